@@ -0,0 +1,63 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Compiles the BPF programs of the bpf feature (`CLANG` picks a different clang).
+fn compile_bpf() {
+    let source = "src/bpf/auxiliary.bpf.c";
+    println!("cargo:rerun-if-changed={}", source);
+    println!("cargo:rerun-if-env-changed=CLANG");
+
+    let mut object = PathBuf::from(env::var("OUT_DIR").unwrap());
+    object.push("auxiliary.bpf.o");
+    let clang = env::var("CLANG").unwrap_or(String::from("clang"));
+    // <linux/types.h> needs <asm/types.h>, which Debian & co. keep in a multiarch directory:
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or(String::from("x86_64"));
+    let status = Command::new(&clang)
+        .args(["-g", "-O2", "-target", "bpf", "-c", source])
+        .arg(format!("-I/usr/include/{}-linux-gnu", arch))
+        .arg("-o")
+        .arg(&object)
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => panic!("{} can't compile {}: {}", clang, source, status),
+        Err(e) => panic!("The bpf feature needs clang ({}): {}", clang, e),
+    }
+}
+
+/// Rebuilds when a commit, checkout or staged change alters what `git describe` prints.
+fn watch_git() {
+    let git = Path::new(".git");
+    if !git.is_dir() {
+        return;
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    // HEAD only names the branch, committing moves the ref it points to:
+    if let Ok(head) = fs::read_to_string(git.join("HEAD")) {
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            if git.join(reference).exists() {
+                println!("cargo:rerun-if-changed=.git/{}", reference);
+            }
+        }
+    }
+}
+
+fn main() {
+    // Let machine.json tell which version of autoperf produced the results:
+    let version = Command::new("git")
+        .args(["describe", "--always", "--dirty"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from(String::from_utf8_lossy(&out.stdout).trim()))
+        .unwrap_or_default();
+    println!("cargo:rustc-env=AUTOPERF_GIT_VERSION={}", version);
+    watch_git();
+
+    if env::var_os("CARGO_FEATURE_BPF").is_some() {
+        compile_bpf();
+    }
+}
@@ -0,0 +1,137 @@
+/* The C API of autoperf (src/capi.rs), generated with cbindgen, don't edit. */
+
+#ifndef AUTOPERF_H
+#define AUTOPERF_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * What CPUs can share.
+ */
+typedef enum autoperf_level {
+  AUTOPERF_LEVEL_CPU,
+  AUTOPERF_LEVEL_CORE,
+  AUTOPERF_LEVEL_L1,
+  AUTOPERF_LEVEL_L2,
+  AUTOPERF_LEVEL_L3,
+  AUTOPERF_LEVEL_SOCKET,
+  AUTOPERF_LEVEL_NODE,
+  AUTOPERF_LEVEL_MACHINE,
+} autoperf_level;
+
+/**
+ * The topology of a machine (opaque).
+ */
+typedef struct autoperf_topology autoperf_topology;
+
+/**
+ * A CPU and where it is on the machine.
+ */
+typedef struct autoperf_cpu {
+  uint64_t cpu;
+  uint64_t core;
+  uint64_t socket;
+  uint64_t node;
+  uint64_t l1;
+  uint64_t l2;
+  uint64_t l3;
+} autoperf_cpu;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * The topology of this machine (from lscpu and numactl, or sysctl on FreeBSD),
+ * NULL if we can't tell. Free it with `autoperf_topology_free`.
+ */
+struct autoperf_topology *autoperf_topology_new(void);
+
+/**
+ * The topology saved in a run directory (its `lscpu.csv` and `numactl.dat`),
+ * NULL if the files can't be read.
+ *
+ * # Safety
+ * `lscpu` and `numactl` have to be NUL-terminated paths.
+ */
+struct autoperf_topology *autoperf_topology_from_files(const char *lscpu, const char *numactl);
+
+/**
+ * # Safety
+ * `topology` has to come from `autoperf_topology_new` or
+ * `autoperf_topology_from_files` (or be NULL) and can't be used afterwards.
+ */
+void autoperf_topology_free(struct autoperf_topology *topology);
+
+/**
+ * All CPUs of the machine (by number).
+ *
+ * # Safety
+ * `out` has to have room for `len` CPUs (or be NULL).
+ */
+size_t autoperf_topology_cpus(const struct autoperf_topology *topology,
+                              struct autoperf_cpu *out,
+                              size_t len);
+
+/**
+ * The CPUs that share `shared` with `cpu` but not `not_shared`, e.g. L3 and
+ * Core for the CPUs on the same L3 but another core, Core and Cpu for the SMT
+ * siblings of `cpu`. With Cpu as `not_shared` only `cpu` itself is left out.
+ *
+ * # Safety
+ * `out` has to have room for `len` CPUs (or be NULL).
+ */
+size_t autoperf_cpus_sharing(const struct autoperf_topology *topology,
+                             uint64_t cpu,
+                             enum autoperf_level shared,
+                             enum autoperf_level not_shared,
+                             struct autoperf_cpu *out,
+                             size_t len);
+
+/**
+ * The bytes of the L2 a core has on average, 0 if the cache sizes are unknown.
+ * The sizes are those the run saved for `autoperf_topology_from_files` (next
+ * to `lscpu`), otherwise those of this machine.
+ *
+ * # Safety
+ * `topology` has to be a topology that wasn't freed (or NULL).
+ */
+uint64_t autoperf_l2_share_per_core(const struct autoperf_topology *topology);
+
+/**
+ * The bytes of its L3 a core of `socket` has on average, 0 if the cache sizes
+ * are unknown (or there is no such socket).
+ *
+ * # Safety
+ * `topology` has to be a topology that wasn't freed (or NULL).
+ */
+uint64_t autoperf_l3_share_per_core(const struct autoperf_topology *topology, uint64_t socket);
+
+/**
+ * The CPUs `pair` gives to A and B with deployment `name` (e.g. "L3-SMT", see
+ * the manual), only on `socket` if it's not negative. Writes up to `len` CPUs
+ * to `a` and `b`, their numbers to `a_count` and `b_count` and returns 0, -1 if
+ * there is no such deployment (or no CPUs for it).
+ *
+ * # Safety
+ * `name` has to be NUL-terminated, `a` and `b` have to have room for `len`
+ * CPUs (or be NULL), `a_count` and `b_count` have to be valid (or NULL).
+ */
+int autoperf_deployment(const struct autoperf_topology *topology,
+                        const char *name,
+                        int64_t socket,
+                        struct autoperf_cpu *a,
+                        struct autoperf_cpu *b,
+                        size_t len,
+                        size_t *a_count,
+                        size_t *b_count);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* AUTOPERF_H */
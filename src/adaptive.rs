@@ -0,0 +1,240 @@
+//! Adaptive `pair` campaigns: the runs of the manifest are the coarse pass, then
+//! we look at what they measured and spend more machine time only where it
+//! helps. `adaptive` in the experiment flags
+//!
+//! * noisy runs, whose IPC varies from interval to interval by more than `cv`
+//!   (the coefficient of variation, 0.1 by default), and
+//! * surprising runs, where A slows down next to B by more than `surprise` (0.25
+//!   by default) more or less than the median slowdown of the configuration.
+//!
+//! A flagged run is repeated `repetitions` times (2 by default) into
+//! `<run>_rep<k>`; a surprising pair is also measured in the `configurations`
+//! of `adaptive` (finer placements the coarse pass left out). Every run we add
+//! is saved with why in `refinement.csv` of the campaign:
+//!
+//! ```text
+//! RUN_ID,PATH,REASON,VALUE,ADDED_RUN_ID,ADDED_PATH
+//! 3f2a9c01d4e5b6a7,L3-SMT/gcc_vs_stream,surprise,0.61,3f2a9c01d4e5b6a7-r1,L3-SMT/gcc_vs_stream_rep1
+//! ```
+//!
+//! The coarse pass decides what is added, so restarting a campaign adds the same
+//! runs (and skips the ones that completed).
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::diff::{self, mean, variance};
+use crate::interference::{first_of, ipc, with_run};
+use crate::normalize::{CYCLES, INSTRUCTIONS};
+
+/// The runs the refinement added to a campaign directory.
+pub const REFINEMENT_FILE: &str = "refinement.csv";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adaptive {
+    /// How often a flagged run is repeated.
+    pub repetitions: u64,
+    /// Runs whose interval IPC varies more than this are noisy.
+    pub max_cv: f64,
+    /// Slowdowns further than this from the median are surprising.
+    pub surprise: f64,
+    /// Where surprising pairs are measured as well.
+    pub configurations: Vec<String>,
+}
+
+/// Why a run is refined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    /// The coefficient of variation of its interval IPC.
+    Variance(f64),
+    /// How much its slowdown differs from the median slowdown.
+    Surprise(f64),
+}
+
+impl Reason {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Reason::Variance(_) => "variance",
+            Reason::Surprise(_) => "surprise",
+        }
+    }
+
+    pub fn value(&self) -> f64 {
+        match *self {
+            Reason::Variance(v) | Reason::Surprise(v) => v,
+        }
+    }
+}
+
+/// What the coarse pass measured in a run.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Measured {
+    /// Over all intervals.
+    pub ipc: Option<f64>,
+    /// Of the IPC of the intervals.
+    pub cv: Option<f64>,
+}
+
+/// A completed run of the coarse pass.
+pub struct Cell {
+    pub configuration: String,
+    /// Of A alone (if A runs next to B).
+    pub solo: Option<Measured>,
+    pub measured: Measured,
+}
+
+impl Adaptive {
+    /// The `adaptive` of the manifest.
+    pub fn parse(value: &toml::Value) -> Result<Adaptive, String> {
+        let t = value.as_table().ok_or(String::from(
+            "'adaptive' should be a table, e.g. { repetitions = 2, cv = 0.1 }",
+        ))?;
+        let number = |key: &str, default: f64| -> Result<f64, String> {
+            match t.get(key) {
+                Some(&toml::Value::Float(f)) if f > 0.0 => Ok(f),
+                Some(&toml::Value::Integer(i)) if i > 0 => Ok(i as f64),
+                Some(_) => Err(format!(
+                    "'{}' of 'adaptive' should be a positive number",
+                    key
+                )),
+                None => Ok(default),
+            }
+        };
+        let repetitions = match t.get("repetitions") {
+            Some(&toml::Value::Integer(i)) if i >= 0 => i as u64,
+            Some(_) => {
+                return Err(String::from(
+                    "'repetitions' of 'adaptive' should be a positive integer",
+                ))
+            }
+            None => 2,
+        };
+        let configurations = match t.get("configurations") {
+            Some(toml::Value::Array(cs)) => cs
+                .iter()
+                .map(|c| c.as_str().map(String::from))
+                .collect::<Option<Vec<String>>>()
+                .ok_or(String::from(
+                    "'configurations' of 'adaptive' should be a list of strings",
+                ))?,
+            Some(_) => {
+                return Err(String::from(
+                    "'configurations' of 'adaptive' should be a list of strings",
+                ))
+            }
+            None => Vec::new(),
+        };
+        let keys = ["repetitions", "cv", "surprise", "configurations"];
+        if let Some(key) = t.keys().find(|k| !keys.contains(&k.as_str())) {
+            return Err(format!("'adaptive' has an unknown key '{}'", key));
+        }
+        Ok(Adaptive {
+            repetitions,
+            max_cv: number("cv", 0.1)?,
+            surprise: number("surprise", 0.25)?,
+            configurations,
+        })
+    }
+
+    /// Why (if at all) every one of `cells` should be refined. Noise comes first,
+    /// a noisy slowdown isn't surprising.
+    pub fn judge(&self, cells: &[Cell]) -> Vec<Option<Reason>> {
+        let slowdown = |cell: &Cell| match (cell.solo.and_then(|s| s.ipc), cell.measured.ipc) {
+            (Some(solo), Some(ipc)) if ipc > 0.0 => Some(solo / ipc),
+            _ => None,
+        };
+        let mut slowdowns: BTreeMap<&str, Vec<f64>> = BTreeMap::new();
+        for cell in cells {
+            if let Some(s) = slowdown(cell) {
+                slowdowns
+                    .entry(cell.configuration.as_str())
+                    .or_default()
+                    .push(s);
+            }
+        }
+        let medians: BTreeMap<&str, f64> = slowdowns
+            .into_iter()
+            .map(|(configuration, mut s)| {
+                s.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+                (configuration, s[s.len() / 2])
+            })
+            .collect();
+        cells
+            .iter()
+            .map(|cell| {
+                if let Some(cv) = cell.measured.cv.filter(|cv| *cv > self.max_cv) {
+                    return Some(Reason::Variance(cv));
+                }
+                let s = slowdown(cell)?;
+                let off = s - medians[cell.configuration.as_str()];
+                if off.abs() > self.surprise {
+                    Some(Reason::Surprise(off))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// What the run in `results` (a directory or an archive) measured.
+pub fn measure(results: &Path, tmp: &Path) -> Measured {
+    let totals = with_run(results, tmp, diff::interval_totals);
+    let cv = match (first_of(&totals, &INSTRUCTIONS), first_of(&totals, &CYCLES)) {
+        (Some(instructions), Some(cycles)) => {
+            let ipcs: Vec<f64> = instructions
+                .iter()
+                .zip(cycles.iter())
+                .filter(|&(_, c)| *c > 0.0)
+                .map(|(i, c)| i / c)
+                .collect();
+            let m = mean(&ipcs);
+            if ipcs.len() > 1 && m > 0.0 {
+                Some(variance(&ipcs, m).sqrt() / m)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+    Measured {
+        ipc: ipc(&totals),
+        cv,
+    }
+}
+
+/// A run the refinement added (paths relative to the campaign directory).
+pub struct Added {
+    pub run_id: String,
+    pub path: PathBuf,
+    pub reason: Reason,
+    pub added_run_id: String,
+    pub added_path: PathBuf,
+}
+
+/// Saves the runs the refinement added in `refinement.csv` of `campaign`.
+pub fn save(campaign: &Path, added: &[Added]) -> io::Result<()> {
+    let io_error = |e: csv::Error| io::Error::other(e);
+    let mut wtr = csv::Writer::from_file(campaign.join(REFINEMENT_FILE)).map_err(io_error)?;
+    wtr.encode((
+        "RUN_ID",
+        "PATH",
+        "REASON",
+        "VALUE",
+        "ADDED_RUN_ID",
+        "ADDED_PATH",
+    ))
+    .map_err(io_error)?;
+    for a in added {
+        wtr.encode((
+            &a.run_id,
+            a.path.to_string_lossy(),
+            a.reason.as_str(),
+            a.reason.value(),
+            &a.added_run_id,
+            a.added_path.to_string_lossy(),
+        ))
+        .map_err(io_error)?;
+    }
+    wtr.flush().map_err(io_error)
+}
@@ -1,5 +1,8 @@
 use csv;
-use log::*;
+use rayon::prelude::*;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs;
@@ -10,9 +13,29 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::process;
 use std::str::FromStr;
+use std::sync::Mutex;
 use toml;
+use tracing::{debug, error, info, warn, Level};
 
+use crate::archive;
+use crate::bootstrap::{IntervalWriter, INTERVAL_COLUMNS};
+use crate::columnar::{
+    event_pattern, result_writer, BufferWriter, ResultWriter, RunWriter, SelectWriter, WideWriter,
+    RESULT_COLUMNS,
+};
+use crate::coretypes::{read_core_types, CoreTypeWriter, CORE_TYPE_COLUMNS};
+use crate::efficiency::{EfficiencyWriter, EFFICIENCY_COLUMNS};
+use crate::index;
+use crate::normalize::{Normalization, NormalizeWriter};
+use crate::output::read_merits;
+use crate::perfstat::{self, Count, Value};
+use crate::phases::{phase_at, read_phases, Marker};
+use crate::profile::{Scope, SOFTWARE_EVENTS};
+use crate::rules::{self, Rule};
+use crate::symbols::{aggregate_symbols, SYMBOL_COLUMNS};
+use crate::threads::{parse_perf_thread, read_threads, ThreadInfo};
 use crate::util::*;
+use crate::validate::{self, Issue};
 
 use perfcnt::linux::perf_file::PerfFile;
 use perfcnt::linux::perf_format::{EventData, EventDesc, EventType};
@@ -38,26 +61,98 @@ fn verify_events_in_order(events: &Vec<EventDesc>, values: &Vec<(u64, Option<u64
     return true;
 }
 
+/// A row of a perf stat file: event name, time, socket, core, cpu, node, unit, value.
+type StatRow = (String, f64, Socket, Core, Cpu, Node, String, u64);
+
+/// Why we couldn't use a row of a perf stat file.
+enum BadRow {
+    UnknownCpu(String, String),
+    CpuNotANumber(String, String),
+    NotCounted(String),
+    NotSupported(String),
+    Multiplexed(String),
+}
+
+/// The counts of a stat file (see `perfstat.rs`).
+fn stat_counts(path: &Path, scope: Scope) -> impl Iterator<Item = Count> {
+    perfstat::counts(path, scope).expect("Can't read perf stat file")
+}
+
+fn decode_stat_row(mt: &MachineTopology, count: Count) -> Result<StatRow, BadRow> {
+    let Count {
+        time,
+        location: cpu,
+        value,
+        event,
+        percent,
+        ..
+    } = count;
+
+    // Perf will just report first CPU on the socket for uncore events,
+    // so we temporarily encode the location in the event name and
+    // extract it here again:
+    let (unit, event_name) = if !event.starts_with("uncore_") {
+        // Normal case, we just take the regular event and cpu fields from perf stat
+        (String::from("cpu"), String::from(event.trim()))
+    } else {
+        // Uncore events, use first part of the event name as the location
+        let (unit, name) = event.split_at(event.find(".").unwrap());
+        (
+            String::from(unit),
+            String::from(name.trim_start_matches(".").trim()),
+        )
+    };
+
+    if !cpu.starts_with("CPU") {
+        return Err(BadRow::UnknownCpu(event_name, cpu));
+    }
+    let cpu_nr = match u64::from_str(cpu[3..].trim()) {
+        Ok(v) => v,
+        Err(_e) => return Err(BadRow::CpuNotANumber(event_name, cpu)),
+    };
+    let cpuinfo: &CpuInfo = mt
+        .cpu(cpu_nr)
+        .expect("Invalid CPU number (check run.toml or lspcu.csv)");
+
+    let value = match value {
+        Value::Counted(value) => value.round() as u64,
+        Value::NotCounted => return Err(BadRow::NotCounted(event_name)),
+        Value::NotSupported => return Err(BadRow::NotSupported(event_name)),
+    };
+    if percent < 91.0 {
+        return Err(BadRow::Multiplexed(event_name));
+    }
+
+    Ok((
+        event_name,
+        time,
+        cpuinfo.socket,
+        cpuinfo.core,
+        cpu_nr,
+        cpuinfo.node.node,
+        unit,
+        value,
+    ))
+}
+
 /// Extracts the perf stat file and writes it to a CSV file that looks like this:
-/// "EVENT_NAME", "TIME", "SOCKET", "CORE", "CPU", "NODE", "UNIT", "SAMPLE_VALUE"
+/// "EVENT_NAME", "TIME", "SOCKET", "CORE", "CPU", "NODE", "UNIT", "SAMPLE_VALUE", "PHASE",
+/// "TID", "THREAD", "SCOPE"
+///
+/// We go over the file twice, first to find the breakpoints and the events that
+/// we can't use (these may only show up late in the file) and then to write the
+/// rows, so we never hold more than one row of it in memory.
 fn parse_perf_csv_file(
     mt: &MachineTopology,
     cpus: &Vec<&CpuInfo>,
     cpu_filter: Filter,
     sockets: &Vec<Socket>,
     breakpoints: &Vec<String>,
+    phases: &[Marker],
     path: &Path,
-    writer: &mut csv::Writer<File>,
+    writer: &mut dyn ResultWriter,
 ) -> io::Result<()> {
-    // Check if it's a file:
-    let meta: Metadata = fs::metadata(path)?;
-    if !meta.file_type().is_file() {
-        error!("Not a file {:?}", path);
-    }
-
     let mut erronous_events: HashMap<String, bool> = HashMap::new();
-    type OutputRow = (String, String, Socket, Core, Cpu, Node, String, u64);
-    let mut parsed_rows: Vec<OutputRow> = Vec::with_capacity(5000);
 
     // All the sockets this program is running on:
     let mut all_sockets: Vec<Socket> = cpus.iter().map(|c| c.socket).collect();
@@ -68,144 +163,111 @@ fn parse_perf_csv_file(
     let mut start: Option<f64> = None;
     let mut end: Option<f64> = None;
 
-    let mut rdr = csv::Reader::from_file(path)
-        .unwrap()
-        .has_headers(false)
-        .delimiter(b';')
-        .flexible(true);
-    for record in rdr.decode() {
-        if record.is_ok() {
-            type SourceRow = (f64, String, String, String, String, String, f64);
-            let (time, cpu, value_string, _, event, _, percent): SourceRow =
-                record.expect("Should not happen (in is_ok() branch)!");
-
-            // Perf will just report first CPU on the socket for uncore events,
-            // so we temporarily encode the location in the event name and
-            // extract it here again:
-            let (unit, event_name) = if !event.starts_with("uncore_") {
-                // Normal case, we just take the regular event and cpu fields from perf stat
-                (String::from("cpu"), String::from(event.trim()))
-            } else {
-                // Uncore events, use first part of the event name as the location
-                let (unit, name) = event.split_at(event.find(".").unwrap());
-                (
-                    String::from(unit),
-                    String::from(name.trim_start_matches(".").trim()),
-                )
-            };
-
-            if erronous_events.contains_key(&event_name) {
-                // Skip already reported, bad events
-                continue;
-            }
+    // The CPU we take the uncore events of a unit on a socket from (perf may
+    // report them on several CPUs of the socket, with the same value):
+    let mut uncore_cpus: HashMap<(String, Socket), Cpu> = HashMap::new();
 
-            if !cpu.starts_with("CPU") {
-                error!(
-                    "{:?}: Unkown CPU value {}, skipping this row.",
-                    path.as_os_str(),
-                    cpu
-                );
+    for count in stat_counts(path, Scope::Cpu) {
+        let (event_name, time, socket, _, cpu_nr, _, unit, value) = match decode_stat_row(mt, count)
+        {
+            Ok(row) => row,
+            Err(BadRow::UnknownCpu(event_name, cpu)) => {
+                if !erronous_events.contains_key(&event_name) {
+                    error!(
+                        "{:?}: Unkown CPU value {}, skipping this row.",
+                        path.as_os_str(),
+                        cpu
+                    );
+                }
                 continue;
             }
-
-            let cpu_nr = match u64::from_str(&cpu[3..].trim()) {
-                Ok(v) => v,
-                Err(_e) => {
+            Err(BadRow::CpuNotANumber(event_name, cpu)) => {
+                if !erronous_events.contains_key(&event_name) {
                     error!(
                         "{:?}: CPU value is not a number '{}', skipping this row.",
                         path.as_os_str(),
                         cpu
                     );
-                    continue;
                 }
-            };
-            let cpuinfo: &CpuInfo = mt
-                .cpu(cpu_nr)
-                .expect("Invalid CPU number (check run.toml or lspcu.csv)");
-
-            if value_string.trim() == "<not counted>" {
-                warn!(
-                    "{:?}: Event '{}' was not counted. This is a bug, please report it!",
-                    path.as_os_str(),
-                    event_name
-                );
-                erronous_events.insert(event_name.clone(), true);
                 continue;
             }
-            if value_string.trim() == "<not supported>" {
-                warn!(
-                    "{:?}: Event '{}' was not measured correctly with perf. This is a bug, please report it!",
-                    path.as_os_str(),
-                    event_name
-                );
-                erronous_events.insert(event_name.clone(), true);
+            Err(BadRow::NotCounted(event_name)) => {
+                erronous_events
+                    .entry(event_name.clone())
+                    .or_insert_with(|| {
+                        warn!(
+                            "{:?}: Event '{}' was not counted. This is a bug, please report it!",
+                            path.as_os_str(),
+                            event_name
+                        );
+                        true
+                    });
                 continue;
             }
-            if percent < 91.0 {
-                error!(
-                    "{:?}: has multiplexed event '{}'. This is a bug, please report it!",
-                    path.as_os_str(),
-                    event_name
-                );
-                erronous_events.insert(event_name.clone(), true);
+            Err(BadRow::NotSupported(event_name)) => {
+                erronous_events.entry(event_name.clone()).or_insert_with(|| {
+                    warn!(
+                        "{:?}: Event '{}' was not measured correctly with perf. This is a bug, please report it!",
+                        path.as_os_str(),
+                        event_name
+                    );
+                    true
+                });
                 continue;
             }
+            Err(BadRow::Multiplexed(event_name)) => {
+                erronous_events
+                    .entry(event_name.clone())
+                    .or_insert_with(|| {
+                        error!(
+                            "{:?}: has multiplexed event '{}'. This is a bug, please report it!",
+                            path.as_os_str(),
+                            event_name
+                        );
+                        true
+                    });
+                continue;
+            }
+        };
 
-            let value = u64::from_str(value_string.trim()).expect(
-                format!("Parsed string '{}' should be a value by now!", value_string).as_str(),
-            );
+        if erronous_events.contains_key(&event_name) {
+            // Skip already reported, bad events
+            continue;
+        }
 
-            if breakpoints.len() >= 1
-                && value == 1
-                && event_name.ends_with(breakpoints[0].as_str())
-                && cpus.iter().any(|c| c.cpu == cpu_nr)
-            {
-                if start.is_some() {
-                    error!(
-                        "{:?}: Start breakpoint ({:?}) triggered multiple times.",
-                        path.as_os_str(),
-                        breakpoints[0]
-                    );
-                }
-                start = Some(time)
+        if unit.starts_with("uncore") {
+            let cpu = uncore_cpus.entry((unit, socket)).or_insert(cpu_nr);
+            *cpu = (*cpu).min(cpu_nr);
+        }
+
+        if !breakpoints.is_empty()
+            && value == 1
+            && event_name.ends_with(breakpoints[0].as_str())
+            && cpus.iter().any(|c| c.cpu == cpu_nr)
+        {
+            if start.is_some() {
+                error!(
+                    "{:?}: Start breakpoint ({:?}) triggered multiple times.",
+                    path.as_os_str(),
+                    breakpoints[0]
+                );
             }
-            if breakpoints.len() >= 2
-                && value == 1
-                && event_name.ends_with(breakpoints[1].as_str())
-                && cpus.iter().any(|c| c.cpu == cpu_nr)
-            {
-                if end.is_some() {
-                    warn!(
-                        "{:?}: End breakpoint ({:?}) triggered multiple times. Update end \
-                         breakpoint.",
-                        path.as_os_str(),
-                        breakpoints[1]
-                    );
-                }
-                end = Some(time)
+            start = Some(time)
+        }
+        if breakpoints.len() >= 2
+            && value == 1
+            && event_name.ends_with(breakpoints[1].as_str())
+            && cpus.iter().any(|c| c.cpu == cpu_nr)
+        {
+            if end.is_some() {
+                warn!(
+                    "{:?}: End breakpoint ({:?}) triggered multiple times. Update end \
+                     breakpoint.",
+                    path.as_os_str(),
+                    breakpoints[1]
+                );
             }
-
-            parsed_rows.push((
-                event_name,
-                time.to_string(),
-                cpuinfo.socket,
-                cpuinfo.core,
-                cpu_nr,
-                cpuinfo.node.node,
-                unit,
-                value,
-            ));
-        } else {
-            // Ignore lines that start with # (comments) but fail in case another
-            // line can not be parsed:
-            match record.unwrap_err() {
-                csv::Error::Decode(s) => {
-                    if !s.starts_with("Failed converting '#") {
-                        panic!("Can't decode line {}.", s)
-                    }
-                }
-                e => panic!("Unrecoverable error {} while decoding.", e),
-            };
+            end = Some(time)
         }
     }
     if breakpoints.len() >= 1 && start.is_none() {
@@ -240,18 +302,29 @@ fn parse_perf_csv_file(
     let mut current_index = 0;
     let mut time_to_index: HashMap<String, usize> = HashMap::new();
     let mut is_recording: bool = start.is_none();
-    let start = start.map(|s| s.to_string());
-    let end = end.map(|s| s.to_string());
-    for r in parsed_rows {
-        let (event_name, time, socket, core, cpu, node, unit, value): OutputRow = r;
+    for count in stat_counts(path, Scope::Cpu) {
+        // We already reported the bad rows above
+        let (event_name, time, socket, core, cpu, node, unit, value): StatRow =
+            match decode_stat_row(mt, count) {
+                Ok(row) => row,
+                Err(_) => continue,
+            };
+
+        if unit.starts_with("uncore")
+            && uncore_cpus
+                .get(&(unit.clone(), socket))
+                .is_some_and(|c| *c != cpu)
+        {
+            continue;
+        }
 
         // Skip all events before we have the breakpoint
         is_recording = match start {
-            Some(ref start_time) => is_recording || time == start_time.as_str(),
+            Some(start_time) => is_recording || time == start_time,
             None => true,
         };
         is_recording = match end {
-            Some(ref end_time) => is_recording && time != end_time.as_str(),
+            Some(end_time) => is_recording && time != end_time,
             None => true,
         };
         if !is_recording {
@@ -259,9 +332,8 @@ fn parse_perf_csv_file(
         }
 
         if erronous_events.contains_key(&event_name) {
-            // We do two passes here because we may get an erronous event only
-            // at a later point in time in the CSV file
-            // (when we already parsed this event a few times)
+            // We may get an erronous event only at a later point in time in the
+            // CSV file (when we already parsed this event a few times)
             continue;
         }
 
@@ -293,24 +365,399 @@ fn parse_perf_csv_file(
             continue;
         }
 
-        if !time_to_index.contains_key(&time) {
-            time_to_index.insert(time.clone(), current_index);
+        let time_str = time.to_string();
+        if !time_to_index.contains_key(&time_str) {
+            time_to_index.insert(time_str.clone(), current_index);
+            current_index += 1;
+        }
+
+        // Uncore events count for the socket, not the CPU perf counted them on:
+        let (core, cpu) = if unit.starts_with("uncore") {
+            (String::new(), String::new())
+        } else {
+            (core.to_string(), cpu.to_string())
+        };
+        writer.write_row(&[
+            event_name.as_str(),
+            format!("{}", *time_to_index.get(&time_str).unwrap()).as_str(),
+            time_str.as_str(),
+            socket.to_string().as_str(),
+            core.as_str(),
+            cpu.as_str(),
+            node.to_string().as_str(),
+            unit.as_str(),
+            value.to_string().as_str(),
+            phase_at(phases, time),
+            "",
+            "",
+            "cpu",
+        ]);
+    }
+
+    Ok(())
+}
+
+/// Extracts a `perf stat --per-thread` file. The rows look the same as the ones
+/// from `parse_perf_csv_file` but instead of a location (SOCKET, CORE, CPU, NODE)
+/// they have the thread id and name.
+fn parse_perf_thread_csv_file(
+    threads: &BTreeMap<u64, ThreadInfo>,
+    phases: &[Marker],
+    path: &Path,
+    writer: &mut dyn ResultWriter,
+) -> io::Result<()> {
+    let mut current_index = 0;
+    let mut time_to_index: HashMap<String, usize> = HashMap::new();
+
+    for count in stat_counts(path, Scope::Thread) {
+        let (time, thread, event, percent) =
+            (count.time, &count.location, &count.event, count.percent);
+        let (comm, tid) = match parse_perf_thread(thread.as_str()) {
+            Some(t) => t,
+            None => {
+                error!(
+                    "{:?}: Unkown thread value {}, skipping this row.",
+                    path.as_os_str(),
+                    thread
+                );
+                continue;
+            }
+        };
+        // Ignore all the threads on the system that didn't belong to the program
+        // (unless we didn't manage to see any of the program's threads):
+        let name = match threads.get(&tid) {
+            Some((_, _, name)) => name.clone(),
+            None if threads.is_empty() => comm,
+            None => continue,
+        };
+
+        // Threads that didn't run during an interval are not counted:
+        let value = match count.count() {
+            Some(value) => value,
+            None => continue,
+        };
+        if percent < 91.0 {
+            error!(
+                "{:?}: has multiplexed event '{}'. This is a bug, please report it!",
+                path.as_os_str(),
+                event
+            );
+            continue;
+        }
+
+        let time_str = time.to_string();
+        if !time_to_index.contains_key(&time_str) {
+            time_to_index.insert(time_str.clone(), current_index);
+            current_index += 1;
+        }
+
+        writer.write_row(&[
+            event.trim(),
+            format!("{}", *time_to_index.get(&time_str).unwrap()).as_str(),
+            time_str.as_str(),
+            "",
+            "",
+            "",
+            "",
+            "cpu",
+            value.to_string().as_str(),
+            phase_at(phases, time),
+            tid.to_string().as_str(),
+            name.as_str(),
+            "thread",
+        ]);
+    }
+
+    Ok(())
+}
+
+/// Where a value measured with `--per-core`, `--per-socket` or `--per-node` (or
+/// for the program as a whole) came from.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+enum Location {
+    /// Socket and the core id reported by perf (e.g., S0-C3 or S0-D0-C3)
+    Core(Socket, u64),
+    /// e.g., S1
+    Socket(Socket),
+    /// e.g., N0
+    Node(Node),
+    /// Nowhere in particular (`Scope::Program`)
+    Program,
+}
+
+impl Location {
+    fn parse(location: &str) -> Option<Location> {
+        let location = location.trim();
+        let parts: Vec<&str> = location.split('-').collect();
+        let id = |part: &str, prefix: char| -> Option<u64> {
+            if part.starts_with(prefix) {
+                u64::from_str(&part[1..]).ok()
+            } else {
+                None
+            }
+        };
+
+        if location.is_empty() {
+            Some(Location::Program)
+        } else if location.starts_with('N') {
+            id(location, 'N').map(Location::Node)
+        } else if parts.len() == 1 {
+            id(parts[0], 'S').map(Location::Socket)
+        } else {
+            match (id(parts[0], 'S'), id(parts[parts.len() - 1], 'C')) {
+                (Some(s), Some(c)) => Some(Location::Core(s, c)),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Time, location, event and value of a count in a `--per-core`, `--per-socket`
+/// or `--per-node` perf stat file (Err if we can't use it).
+fn decode_scope_row(
+    scope: Scope,
+    count: Count,
+) -> Result<(f64, Location, String, u64), (Level, String)> {
+    let location = match Location::parse(count.location.as_str()) {
+        Some(l) => l,
+        None => {
+            return Err((
+                Level::ERROR,
+                format!(
+                    "Unkown {} value {}, skipping this row.",
+                    scope, count.location
+                ),
+            ))
+        }
+    };
+    let value = match count.count() {
+        Some(value) => value,
+        None => {
+            return Err((
+                Level::WARN,
+                format!(
+                    "Event '{}' was not measured correctly with perf.",
+                    count.event
+                ),
+            ))
+        }
+    };
+    if count.percent < 91.0 {
+        return Err((
+            Level::ERROR,
+            format!(
+                "has multiplexed event '{}'. This is a bug, please report it!",
+                count.event
+            ),
+        ));
+    }
+    Ok((count.time, location, count.event, value))
+}
+
+/// Extracts a perf stat file that was measured with `--per-core`, `--per-socket`,
+/// `--per-node` or for the program as a whole. The rows look the same as the ones from `parse_perf_csv_file`
+/// but only the location columns that apply to the scope are filled in.
+///
+/// # Note
+/// perf reports the physical core ids whereas lscpu (and therefore MachineTopology)
+/// uses logical core ids, we match them by their order on the socket.
+fn parse_perf_scope_csv_file(
+    mt: &MachineTopology,
+    scope: Scope,
+    phases: &[Marker],
+    path: &Path,
+    writer: &mut dyn ResultWriter,
+) -> io::Result<()> {
+    // perf core id -> logical core id (see note above), we need to see all core
+    // ids before we can write the first row:
+    let mut physical: HashMap<Socket, BTreeSet<u64>> = HashMap::new();
+    for count in stat_counts(path, scope) {
+        match decode_scope_row(scope, count) {
+            Ok((_, Location::Core(s, c), _, _)) => {
+                physical.entry(s).or_default().insert(c);
+            }
+            Ok(_) => {}
+            Err((level, problem)) => {
+                if level == Level::ERROR {
+                    error!("{:?}: {}", path.as_os_str(), problem)
+                } else {
+                    warn!("{:?}: {}", path.as_os_str(), problem)
+                }
+            }
+        }
+    }
+    let mut core_ids: HashMap<Location, Core> = HashMap::new();
+    for socket in mt.sockets() {
+        let physical: BTreeSet<u64> = physical.remove(&socket).unwrap_or_default();
+        for (physical, logical) in physical.into_iter().zip(mt.cores_on_socket(socket)) {
+            core_ids.insert(Location::Core(socket, physical), logical);
+        }
+    }
+
+    // Only report the node (or socket) if the location doesn't span more than one:
+    let unique = |values: Vec<u64>| -> String {
+        let mut values = values;
+        values.sort();
+        values.dedup();
+        if values.len() == 1 {
+            values[0].to_string()
+        } else {
+            String::new()
+        }
+    };
+
+    let mut current_index = 0;
+    let mut time_to_index: HashMap<String, usize> = HashMap::new();
+    for count in stat_counts(path, scope) {
+        // We already reported the bad rows above
+        let (time, location, event, value) = match decode_scope_row(scope, count) {
+            Ok(row) => row,
+            Err(_) => continue,
+        };
+        let (unit, event_name) = if !event.starts_with("uncore_") {
+            (String::from("cpu"), event)
+        } else {
+            let (unit, name) = event.split_at(event.find(".").unwrap());
+            (
+                String::from(unit),
+                String::from(name.trim_start_matches(".")),
+            )
+        };
+
+        let (socket, core, node) = match location {
+            Location::Core(s, _) => {
+                let core = core_ids.get(&location).copied();
+                let nodes: Vec<u64> = mt
+                    .cpus_on_socket(s)
+                    .iter()
+                    .filter(|c| Some(c.core) == core)
+                    .map(|c| c.node.node)
+                    .collect();
+                (
+                    s.to_string(),
+                    core.map_or(String::new(), |c| c.to_string()),
+                    unique(nodes),
+                )
+            }
+            Location::Socket(s) => {
+                let nodes = mt.cpus_on_socket(s).iter().map(|c| c.node.node).collect();
+                (s.to_string(), String::new(), unique(nodes))
+            }
+            Location::Node(n) => {
+                let sockets: Vec<u64> = mt
+                    .nodes()
+                    .into_iter()
+                    .filter(|ni| ni.node == n)
+                    .flat_map(|ni| mt.cpus_on_node(ni).into_iter().map(|c| c.socket))
+                    .collect();
+                (unique(sockets), String::new(), n.to_string())
+            }
+            Location::Program => (String::new(), String::new(), String::new()),
+        };
+
+        let time_str = time.to_string();
+        if !time_to_index.contains_key(&time_str) {
+            time_to_index.insert(time_str.clone(), current_index);
+            current_index += 1;
+        }
+
+        writer.write_row(&[
+            event_name.as_str(),
+            format!("{}", *time_to_index.get(&time_str).unwrap()).as_str(),
+            time_str.as_str(),
+            socket.as_str(),
+            core.as_str(),
+            "",
+            node.as_str(),
+            unit.as_str(),
+            value.to_string().as_str(),
+            phase_at(phases, time),
+            "",
+            "",
+            scope.to_string().as_str(),
+        ]);
+    }
+
+    Ok(())
+}
+
+/// The name of a metric of a metric group, perf writes it as its unit (e.g.,
+/// `%  tma_retiring`).
+fn metric_name(unit: &str) -> String {
+    unit.trim_start_matches('%')
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Extracts the metrics of a perf stat file that measured metric groups (`-M`).
+/// The rows look the same as the ones of the other files, with the metric as the
+/// event, `metric` as the UNIT and the value perf derived (not necessarily an
+/// integer) as the SAMPLE_VALUE. The location columns are filled in as far as perf
+/// tells us the location at `scope` (only the socket for cores).
+fn parse_perf_metric_file(
+    mt: &MachineTopology,
+    scope: Scope,
+    phases: &[Marker],
+    path: &Path,
+    writer: &mut dyn ResultWriter,
+) -> io::Result<()> {
+    let mut current_index = 0;
+    let mut time_to_index: HashMap<String, usize> = HashMap::new();
+    for metric in perfstat::metrics(path, scope)? {
+        // socket, core, cpu, node, tid, thread
+        let mut location: [String; 6] = Default::default();
+        match scope {
+            Scope::Cpu => {
+                let cpu = metric
+                    .location
+                    .trim_start_matches("CPU")
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|cpu| mt.cpu(cpu));
+                if let Some(cpu) = cpu {
+                    location[0] = cpu.socket.to_string();
+                    location[1] = cpu.core.to_string();
+                    location[2] = cpu.cpu.to_string();
+                    location[3] = cpu.node.node.to_string();
+                }
+            }
+            Scope::Thread => {
+                if let Some((comm, tid)) = parse_perf_thread(&metric.location) {
+                    location[4] = tid.to_string();
+                    location[5] = comm;
+                }
+            }
+            _ => match Location::parse(&metric.location) {
+                Some(Location::Core(s, _)) | Some(Location::Socket(s)) => {
+                    location[0] = s.to_string()
+                }
+                Some(Location::Node(n)) => location[3] = n.to_string(),
+                Some(Location::Program) | None => {}
+            },
+        }
+
+        let time_str = metric.time.to_string();
+        if !time_to_index.contains_key(&time_str) {
+            time_to_index.insert(time_str.clone(), current_index);
             current_index += 1;
         }
 
-        writer
-            .encode(&[
-                event_name.as_str(),
-                format!("{}", *time_to_index.get(&time).unwrap()).as_str(),
-                time.as_str(),
-                socket.to_string().as_str(),
-                core.to_string().as_str(),
-                cpu.to_string().as_str(),
-                node.to_string().as_str(),
-                unit.as_str(),
-                value.to_string().as_str(),
-            ])
-            .unwrap();
+        writer.write_row(&[
+            metric_name(&metric.unit).as_str(),
+            format!("{}", *time_to_index.get(&time_str).unwrap()).as_str(),
+            time_str.as_str(),
+            location[0].as_str(),
+            location[1].as_str(),
+            location[2].as_str(),
+            location[3].as_str(),
+            "metric",
+            metric.value.to_string().as_str(),
+            phase_at(phases, metric.time),
+            location[4].as_str(),
+            location[5].as_str(),
+            scope.to_string().as_str(),
+        ]);
     }
 
     Ok(())
@@ -321,7 +768,7 @@ fn parse_perf_csv_file(
 fn parse_perf_file(
     path: &Path,
     event_names: Vec<&str>,
-    writer: &mut csv::Writer<File>,
+    writer: &mut dyn ResultWriter,
 ) -> io::Result<()> {
     // Check if it's a file:
     let meta: Metadata = fs::metadata(path)?;
@@ -366,9 +813,7 @@ fn parse_perf_file(
                     let &(_, name) = event_info.iter().find(|ev| ev.0.ids.contains(&id)).unwrap();
                     let sample_value = format!("{}", event_count);
 
-                    writer
-                        .encode(&[name, time.as_str(), cpu.as_str(), sample_value.as_str()])
-                        .unwrap();
+                    writer.write_row(&[name, time.as_str(), cpu.as_str(), sample_value.as_str()]);
                 }
             }
             _ => unreachable!("Should not happen"),
@@ -398,64 +843,699 @@ impl Filter {
     }
 }
 
-pub fn aggregate(path: &Path, cpu_filter: &str, uncore_filter: &str, save_to: &Path) {
-    if !path.exists() {
-        error!("Input directory does not exist {:?}", path);
+/// (command, event_names, perf_events, breakpoints, datafile, perf_command)
+pub type PerfLogRow = (String, String, String, String, String, String);
+
+/// Reads the perf.csv log of a run and returns its schema version and rows.
+///
+/// The columns are looked up by name so we can still read the logs of older
+/// autoperf versions (columns they don't have are empty).
+pub fn read_perf_log(path: &Path) -> (u64, Vec<PerfLogRow>) {
+    let mut rdr = csv::Reader::from_file(path).unwrap().flexible(true);
+    let headers = rdr.headers().expect("perf.csv has no header");
+    let column = |record: &Vec<String>, name: &str| -> String {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .and_then(|idx| record.get(idx))
+            .map_or(String::new(), |v| v.clone())
+    };
+
+    let mut version = 1;
+    let mut rows: Vec<PerfLogRow> = Vec::new();
+    for record in rdr.records() {
+        let record = record.expect("Can't read perf.csv");
+        version = u64::from_str(column(&record, "schema_version").as_str()).unwrap_or(1);
+        rows.push((
+            column(&record, "command"),
+            column(&record, "event_names"),
+            column(&record, "perf_events"),
+            column(&record, "breakpoints"),
+            column(&record, "datafile"),
+            column(&record, "perf_command"),
+        ));
+    }
+
+    if version > SCHEMA_VERSION {
+        warn!(
+            "{:?} was written by a newer autoperf (schema version {} > {}), \
+             results may be incomplete.",
+            path, version, SCHEMA_VERSION
+        );
+    }
+    (version, rows)
+}
+
+/// Columns of software_events.csv.
+pub const SOFTWARE_COLUMNS: [&str; 5] = ["FILE", "EVENT_NAME", "TOTAL", "SECONDS", "PER_SECOND"];
+
+/// Columns of perf_metrics.csv.
+pub const PERF_METRICS_COLUMNS: [&str; 6] = [
+    "FILE",
+    "TIME",
+    "LOCATION",
+    "EVENT_NAME",
+    "METRIC_VALUE",
+    "METRIC_UNIT",
+];
+
+/// Sums up the software events (see `SOFTWARE_EVENTS`) of a perf stat file over all
+/// locations and returns (event, total, seconds measured, events per second).
+///
+/// # Note
+/// perf measures these system-wide (-a), so they include activity of other programs too.
+fn software_event_rates(scope: Scope, path: &Path) -> Vec<(String, u64, f64, f64)> {
+    let mut totals: BTreeMap<String, u64> = BTreeMap::new();
+    let mut seconds: f64 = 0.0;
+    for count in stat_counts(path, scope) {
+        seconds = seconds.max(count.time);
+        if SOFTWARE_EVENTS.contains(&count.event.as_str()) {
+            if let Some(value) = count.count() {
+                *totals.entry(count.event).or_insert(0) += value;
+            }
+        }
+    }
+
+    totals
+        .into_iter()
+        .map(|(event, total)| {
+            let per_second = if seconds > 0.0 {
+                total as f64 / seconds
+            } else {
+                0.0
+            };
+            (event, total, seconds, per_second)
+        })
+        .collect()
+}
+
+/// How many items per thread `parallel_ordered` works on at a time.
+const ITEMS_PER_THREAD: usize = 2;
+
+/// Calls `work` for all `items` in parallel and writes the rows it produced in the
+/// order of `items`. We only work on a few items at a time, and the rows of an
+/// item are buffered until it's their turn, so at most all rows of that many
+/// items are in memory: the items should be small (the files of a run, not
+/// runs). `done` is called (in order) for every item that `work` was successful
+/// for, once its rows are written.
+fn parallel_ordered<T, F, D>(
+    items: &[T],
+    wrtr: &mut dyn ResultWriter,
+    software_wrtr: &mut dyn ResultWriter,
+    metrics_wrtr: &mut dyn ResultWriter,
+    symbols_wrtr: &mut dyn ResultWriter,
+    work: F,
+    mut done: D,
+) where
+    T: Sync,
+    F: Fn(
+            &T,
+            &mut dyn ResultWriter,
+            &mut dyn ResultWriter,
+            &mut dyn ResultWriter,
+            &mut dyn ResultWriter,
+        ) -> bool
+        + Sync,
+    D: FnMut(&T),
+{
+    let chunk_size = cmp::max(1, rayon::current_num_threads() * ITEMS_PER_THREAD);
+    for chunk in items.chunks(chunk_size) {
+        let buffers: Vec<(bool, BufferWriter, BufferWriter, BufferWriter, BufferWriter)> = chunk
+            .par_iter()
+            .map(|item| {
+                let mut rows = BufferWriter::new();
+                let mut software_rows = BufferWriter::new();
+                let mut metric_rows = BufferWriter::new();
+                let mut symbol_rows = BufferWriter::new();
+                let ok = work(
+                    item,
+                    &mut rows,
+                    &mut software_rows,
+                    &mut metric_rows,
+                    &mut symbol_rows,
+                );
+                (ok, rows, software_rows, metric_rows, symbol_rows)
+            })
+            .collect();
+        for (item, (ok, rows, software_rows, metric_rows, symbol_rows)) in chunk.iter().zip(buffers)
+        {
+            rows.replay(wrtr);
+            software_rows.replay(software_wrtr);
+            metric_rows.replay(metrics_wrtr);
+            symbol_rows.replay(symbols_wrtr);
+            if ok {
+                done(item);
+            }
+        }
+    }
+}
+
+/// Finds all runs (directories with a perf.csv or archives of them) below `path`
+/// in a stable order. Campaigns with an index (see `index.rs`) aren't searched.
+pub fn campaign_runs(path: &Path, runs: &mut Vec<PathBuf>) {
+    if let Some(completed) = index::completed_runs(path) {
+        runs.extend(completed);
+        return;
+    }
+    let mut entries: Vec<PathBuf> = match fs::read_dir(path) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => return,
+    };
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            let mut perf_csv = entry.clone();
+            perf_csv.push("perf.csv");
+            if perf_csv.exists() {
+                runs.push(entry);
+            } else {
+                campaign_runs(&entry, runs);
+            }
+        } else if archive::is_archive(&entry) {
+            runs.push(entry);
+        }
+    }
+}
+
+/// The writer for the results in the format and layout of `settings`, with only
+/// the events and columns selected in `settings`. Adds to `save_to` if `append`
+/// is set.
+fn layout_writer(
+    settings: &AggregateSettings,
+    save_to: &Path,
+    columns: &[&str],
+    append: bool,
+) -> Box<dyn ResultWriter> {
+    let input_columns = columns;
+    let normalized: Vec<String> = match settings.normalize {
+        Some(normalization) => normalization.columns(columns),
+        None => columns.iter().map(|c| String::from(*c)).collect(),
+    };
+    let columns: Vec<&str> = normalized.iter().map(|c| c.as_str()).collect();
+    let value_column: &str = columns
+        .iter()
+        .find(|c| c.starts_with("SAMPLE_VALUE"))
+        .map_or("SAMPLE_VALUE", |c| *c);
+
+    let mut selected: Vec<&str> = match settings.columns {
+        Some(selection) => selection
+            .split(',')
+            .map(|c| c.trim())
+            .map(|c| if c == "SAMPLE_VALUE" { value_column } else { c })
+            .collect(),
+        None => columns.clone(),
+    };
+    if let Some(unknown) = selected.iter().find(|c| !columns.contains(c)) {
+        error!(
+            "Unknown column '{}' in --columns (we have {}).",
+            unknown,
+            columns.join(", ")
+        );
+        process::exit(1);
+    }
+    if settings.layout == "wide" {
+        // We need these to know which column a value goes in:
+        for column in ["EVENT_NAME", value_column].iter() {
+            if !selected.contains(column) {
+                selected.push(column);
+            }
+        }
+    }
+    let events = settings.events.map(|events| match event_pattern(events) {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            error!("Invalid --events pattern '{}': {}", events, e);
+            process::exit(1);
+        }
+    });
+
+    let inner: Box<dyn ResultWriter> = if append {
+        append_writer(save_to, &selected)
+    } else {
+        match settings.layout {
+            "wide" => Box::new(WideWriter::new(settings.format, save_to, &selected)),
+            _ => result_writer(settings.format, save_to, &selected),
+        }
+    };
+    let wrtr: Box<dyn ResultWriter> = if events.is_none() && selected == columns {
+        inner
+    } else {
+        Box::new(SelectWriter::new(inner, &columns, &selected, events))
+    };
+    match settings.normalize {
+        Some(normalization) => Box::new(NormalizeWriter::new(wrtr, normalization, input_columns)),
+        None => wrtr,
+    }
+}
+
+/// Opens an existing CSV file to add more rows to it, if its header matches `columns`.
+fn append_writer(path: &Path, columns: &[&str]) -> Box<dyn ResultWriter> {
+    let header = fs::read_to_string(path)
+        .ok()
+        .and_then(|content| content.lines().next().map(String::from))
+        .unwrap_or_default();
+    if header != columns.join(",") {
+        error!(
+            "{:?} has different columns than what we would write, can't add to it.",
+            path
+        );
         process::exit(1);
     }
+    let file = fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .expect("Can't open file for appending");
+    Box::new(csv::Writer::from_writer(file))
+}
+
+/// The runs of a campaign we already aggregated into `manifest`.
+fn read_aggregated_runs(manifest: &Path) -> HashSet<String> {
+    let mut runs = HashSet::new();
+    if let Ok(mut rdr) = csv::Reader::from_file(manifest) {
+        for record in rdr.decode() {
+            let (run,): (String,) = record.expect("Can't read list of aggregated runs");
+            runs.insert(run);
+        }
+    }
+    runs
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AggregateSettings<'a> {
+    /// Which core events to include (all, exclusive, shared or none).
+    pub cpu_filter: &'a str,
+    /// Which uncore events to include (all, exclusive, shared or none).
+    pub uncore_filter: &'a str,
+    /// csv or parquet
+    pub format: &'a str,
+    /// long (one row per value) or wide (one column per event)
+    pub layout: &'a str,
+    /// Only include events that match (see `event_pattern`).
+    pub events: Option<&'a str>,
+    /// Only include these columns (comma-separated).
+    pub columns: Option<&'a str>,
+    /// Normalize the counter values.
+    pub normalize: Option<Normalization>,
+    /// Only add the runs of a campaign that aren't in the results yet.
+    pub incremental: bool,
+    /// Invariants the counter values of every run are checked against.
+    pub rules: &'a [Rule],
+    /// How often we resample for the confidence intervals (0 leaves them empty).
+    pub bootstrap: usize,
+}
+
+/// Values of a likwid run can't be normalized per second: its samples are the
+/// whole run (or one marker region each), `NormalizeWriter` takes the first one
+/// to be one perf interval long at most.
+fn normalizable(run: &Path, settings: &AggregateSettings) -> bool {
+    if settings.normalize != Some(Normalization::Seconds) {
+        return true;
+    }
+    let (_, rows) = read_perf_log(&run.join("perf.csv"));
+    !rows.iter().any(|row| row.5.starts_with("likwid-perfctr"))
+}
+
+/// The confidence intervals, efficiency and counts per core type of a run.
+type Derived = [Vec<Vec<String>>; 3];
+
+/// Merges the measurements of a profile, or of all the runs of a `pair` campaign
+/// if `path` doesn't have a perf.csv. Runs are aggregated one after the other and
+/// written out right away, so the size of a campaign doesn't matter.
+pub fn aggregate(path: &Path, settings: &AggregateSettings, save_to: &Path) {
+    let mut perf_csv: PathBuf = path.to_path_buf();
+    perf_csv.push("perf.csv");
+    let mut runs: Vec<PathBuf> = Vec::new();
+    if !perf_csv.exists() {
+        campaign_runs(path, &mut runs);
+    }
+
+    let software_to = save_to.with_file_name("software_events.csv");
+    let metrics_to = save_to.with_file_name("perf_metrics.csv");
+    let symbols_to = save_to.with_file_name(match settings.format {
+        "parquet" => "symbols.parquet",
+        _ => "symbols.csv",
+    });
+    let validation_to = &save_to.with_file_name("validation");
+    let intervals_to = save_to.with_file_name("confidence_intervals.csv");
+    let efficiency_to = save_to.with_file_name("efficiency.csv");
+    let core_types_to = save_to.with_file_name("per_core_type.csv");
+    // layout_writer complains about an invalid pattern:
+    let events = settings.events.and_then(|e| event_pattern(e).ok());
+    if runs.is_empty() {
+        if settings.incremental {
+            warn!("{:?} is not a campaign, --incremental is ignored.", path);
+        }
+        if !normalizable(path, settings) {
+            error!(
+                "{:?} was measured with likwid, normalize it per 1000 instructions or cycles instead of per second.",
+                path
+            );
+            process::exit(1);
+        }
+        let mut wrtr = layout_writer(settings, save_to, &RESULT_COLUMNS, false);
+        let mut software_wrtr = result_writer("csv", &software_to, &SOFTWARE_COLUMNS);
+        let mut metrics_wrtr = result_writer("csv", &metrics_to, &PERF_METRICS_COLUMNS);
+        let mut symbols_wrtr = result_writer(settings.format, &symbols_to, &SYMBOL_COLUMNS);
+        let mut intervals_wrtr = result_writer("csv", &intervals_to, &INTERVAL_COLUMNS);
+        let mut efficiency_wrtr = result_writer("csv", &efficiency_to, &EFFICIENCY_COLUMNS);
+        let mut core_types_wrtr = result_writer("csv", &core_types_to, &CORE_TYPE_COLUMNS);
+        let mut with_core_types =
+            CoreTypeWriter::new(wrtr.as_mut(), read_core_types(&path.join(CORE_TYPE_FILE)));
+        let mut with_efficiency = EfficiencyWriter::new(&mut with_core_types);
+        let mut with_intervals = IntervalWriter::new(&mut with_efficiency);
+        let aggregated = aggregate_into(
+            path,
+            settings.cpu_filter,
+            settings.uncore_filter,
+            &mut with_intervals,
+            software_wrtr.as_mut(),
+            metrics_wrtr.as_mut(),
+        );
+        if let Err(e) = aggregated {
+            error!("Can't aggregate {:?}: {}", path, e);
+            process::exit(1);
+        }
+        for row in with_intervals.intervals(events.as_ref(), settings.bootstrap) {
+            let row: Vec<&str> = row.iter().map(|c| c.as_str()).collect();
+            intervals_wrtr.write_row(&row);
+        }
+        for row in with_efficiency.efficiency() {
+            let row: Vec<&str> = row.iter().map(|c| c.as_str()).collect();
+            efficiency_wrtr.write_row(&row);
+        }
+        for row in with_core_types.per_core_type() {
+            let row: Vec<&str> = row.iter().map(|c| c.as_str()).collect();
+            core_types_wrtr.write_row(&row);
+        }
+        aggregate_symbols(path, symbols_wrtr.as_mut());
+        wrtr.finish();
+        software_wrtr.finish();
+        metrics_wrtr.finish();
+        symbols_wrtr.finish();
+        intervals_wrtr.finish();
+        efficiency_wrtr.finish();
+        core_types_wrtr.finish();
+        let mut issues = validate::check_run("", path);
+        issues.extend(rules::check_run("", path, settings.rules));
+        save_validation(validation_to, 1, issues);
+        info!("Merging completed");
+        return;
+    }
+
+    let with_run = |columns: &[&'static str]| -> Vec<&'static str> {
+        let mut with_run = vec!["RUN"];
+        with_run.extend_from_slice(columns);
+        with_run
+    };
+    let run_name = |run: &Path| -> String {
+        run.strip_prefix(path)
+            .unwrap_or(run)
+            .to_string_lossy()
+            .trim_end_matches(archive::ARCHIVE_EXTENSION)
+            .to_string()
+    };
+
+    // The runs that are in the results, so `--incremental` knows what to skip:
+    let manifest = save_to.with_file_name("aggregated_runs.csv");
+    let append = settings.incremental && save_to.exists() && manifest.exists();
+    let (
+        mut wrtr,
+        mut software_wrtr,
+        mut metrics_wrtr,
+        mut symbols_wrtr,
+        mut intervals_wrtr,
+        mut efficiency_wrtr,
+        mut core_types_wrtr,
+        mut manifest_wrtr,
+    ) = if append {
+        if settings.format == "parquet" || settings.layout == "wide" {
+            error!("Can only add runs to results in the long CSV layout.");
+            process::exit(1);
+        }
+        let aggregated = read_aggregated_runs(&manifest);
+        runs.retain(|run| !aggregated.contains(&run_name(run)));
+        info!(
+            "{} runs already aggregated, adding {} new ones.",
+            aggregated.len(),
+            runs.len()
+        );
+        (
+            layout_writer(settings, save_to, &with_run(&RESULT_COLUMNS), true),
+            append_writer(&software_to, &with_run(&SOFTWARE_COLUMNS)),
+            append_writer(&metrics_to, &with_run(&PERF_METRICS_COLUMNS)),
+            append_writer(&symbols_to, &with_run(&SYMBOL_COLUMNS)),
+            append_writer(&intervals_to, &with_run(&INTERVAL_COLUMNS)),
+            append_writer(&efficiency_to, &with_run(&EFFICIENCY_COLUMNS)),
+            append_writer(&core_types_to, &with_run(&CORE_TYPE_COLUMNS)),
+            append_writer(&manifest, &["RUN"]),
+        )
+    } else {
+        info!("Merging {} runs of the campaign in {:?}", runs.len(), path);
+        (
+            layout_writer(settings, save_to, &with_run(&RESULT_COLUMNS), false),
+            result_writer("csv", &software_to, &with_run(&SOFTWARE_COLUMNS)),
+            result_writer("csv", &metrics_to, &with_run(&PERF_METRICS_COLUMNS)),
+            result_writer(settings.format, &symbols_to, &with_run(&SYMBOL_COLUMNS)),
+            result_writer("csv", &intervals_to, &with_run(&INTERVAL_COLUMNS)),
+            result_writer("csv", &efficiency_to, &with_run(&EFFICIENCY_COLUMNS)),
+            result_writer("csv", &core_types_to, &with_run(&CORE_TYPE_COLUMNS)),
+            result_writer("csv", &manifest, &["RUN"]),
+        )
+    };
+
+    let issues: Mutex<Vec<Issue>> = Mutex::new(Vec::new());
+    // run -> its confidence intervals, efficiency and counts per core type, written
+    // in the order of the runs at the end:
+    let derived: Mutex<BTreeMap<usize, (String, Derived)>> = Mutex::new(BTreeMap::new());
+    let merge_run = |idx: usize,
+                     run: &PathBuf,
+                     wrtr: &mut dyn ResultWriter,
+                     software_wrtr: &mut dyn ResultWriter,
+                     metrics_wrtr: &mut dyn ResultWriter,
+                     symbols_wrtr: &mut dyn ResultWriter|
+     -> bool {
+        let name = run_name(run);
+        debug!("Merging run {}", name);
+
+        // Every run gets its own temporary directory, left-overs of one never end up in another:
+        let mut tmp = std::env::temp_dir();
+        tmp.push(format!("autoperf-{}-{}", process::id(), idx));
+        let run_directory = if archive::is_archive(run) {
+            match archive::extract(run, &tmp) {
+                Ok(dir) => dir,
+                Err(e) => {
+                    error!("Can't extract {:?}, skipping run: {}", run, e);
+                    return false;
+                }
+            }
+        } else {
+            run.to_path_buf()
+        };
+        if !normalizable(&run_directory, settings) {
+            error!(
+                "{:?} was measured with likwid, can't normalize it per second, skipping run.",
+                run
+            );
+            if archive::is_archive(run) {
+                let _ = fs::remove_dir_all(&tmp);
+            }
+            return false;
+        }
+
+        let mut run_wrtr = RunWriter::new(wrtr, &name);
+        let mut with_core_types = CoreTypeWriter::new(
+            &mut run_wrtr,
+            read_core_types(&run_directory.join(CORE_TYPE_FILE)),
+        );
+        let mut with_efficiency = EfficiencyWriter::new(&mut with_core_types);
+        let mut with_intervals = IntervalWriter::new(&mut with_efficiency);
+        let aggregated = aggregate_into(
+            &run_directory,
+            settings.cpu_filter,
+            settings.uncore_filter,
+            &mut with_intervals,
+            &mut RunWriter::new(software_wrtr, &name),
+            &mut RunWriter::new(metrics_wrtr, &name),
+        );
+        if let Err(e) = aggregated {
+            // The rows of its other files are written, we go on with the other runs:
+            error!("Can't aggregate all files of {:?}: {}", run, e);
+        }
+        let run_intervals = with_intervals.intervals(events.as_ref(), settings.bootstrap);
+        let run_efficiency = with_efficiency.efficiency();
+        let run_core_types = with_core_types.per_core_type();
+        derived.lock().unwrap().insert(
+            idx,
+            (
+                name.clone(),
+                [run_intervals, run_efficiency, run_core_types],
+            ),
+        );
+        aggregate_symbols(&run_directory, &mut RunWriter::new(symbols_wrtr, &name));
+        let mut run_issues = validate::check_run(&name, &run_directory);
+        run_issues.extend(rules::check_run(&name, &run_directory, settings.rules));
+        issues.lock().unwrap().extend(run_issues);
+
+        if archive::is_archive(run) {
+            let _ = fs::remove_dir_all(&tmp);
+        }
+        true
+    };
+    // The runs are merged one after the other (the files of a run in parallel, see
+    // `aggregate_into`): buffering the rows of several runs at a time would hold
+    // whole runs in memory.
+    for (idx, run) in runs.iter().enumerate() {
+        if merge_run(
+            idx,
+            run,
+            wrtr.as_mut(),
+            software_wrtr.as_mut(),
+            metrics_wrtr.as_mut(),
+            symbols_wrtr.as_mut(),
+        ) {
+            manifest_wrtr.write_row(&[run_name(run).as_str()]);
+        }
+    }
+
+    wrtr.finish();
+    software_wrtr.finish();
+    metrics_wrtr.finish();
+    symbols_wrtr.finish();
+    manifest_wrtr.finish();
+    for (_, (name, [intervals, efficiency, core_types])) in derived.into_inner().unwrap() {
+        for (rows, wrtr) in [
+            (intervals, &mut intervals_wrtr),
+            (efficiency, &mut efficiency_wrtr),
+            (core_types, &mut core_types_wrtr),
+        ] {
+            for row in rows {
+                let mut with_run: Vec<&str> = vec![name.as_str()];
+                with_run.extend(row.iter().map(|c| c.as_str()));
+                wrtr.write_row(&with_run);
+            }
+        }
+    }
+    intervals_wrtr.finish();
+    efficiency_wrtr.finish();
+    core_types_wrtr.finish();
+
+    let mut issues = issues.into_inner().unwrap();
+    issues.sort_by(|a, b| a.run.cmp(&b.run).then(a.file.cmp(&b.file)));
+    let mut missing: Vec<Issue> = validate::missing_runs(path)
+        .into_iter()
+        .map(|run| Issue {
+            run: run_name(&run),
+            file: String::new(),
+            kind: String::from("missing"),
+            event: String::new(),
+            detail: String::from("run has no measurements"),
+        })
+        .collect();
+    missing.extend(issues);
+    save_validation(validation_to, runs.len(), missing);
+    info!("Merging completed");
+}
+
+fn save_validation(save_to: &Path, runs: usize, issues: Vec<Issue>) {
+    if let Err(e) = validate::save_report(save_to, runs, issues) {
+        error!("Can't save validation report {:?}: {}", save_to, e);
+    }
+}
+
+/// An error about the files of a run.
+fn invalid_run(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+/// The CPUs of A and the breakpoints in the `run.toml` of a run.
+fn read_run_config(run_config: &Path) -> io::Result<(Vec<u64>, Vec<String>)> {
+    let run_string = fs::read_to_string(run_config)?;
+    let mut parser = toml::Parser::new(run_string.as_str());
+    let doc = match parser.parse() {
+        Some(doc) => doc,
+        None => {
+            return Err(invalid_run(format!(
+                "Can't parse {:?}: {:?}",
+                run_config, parser.errors
+            )))
+        }
+    };
+    let invalid = |what: &str| invalid_run(format!("{:?}: {}", run_config, what));
+
+    let a: &toml::Table = doc
+        .get("a")
+        .and_then(|a| a.as_table())
+        .ok_or_else(|| invalid("'a' should be a table"))?;
+    let deployment: &toml::Table = doc
+        .get("deployment")
+        .and_then(|d| d.as_table())
+        .ok_or_else(|| invalid("'deployment' should be a table"))?;
+    let cpus = deployment
+        .get("a")
+        .and_then(|a| a.as_slice())
+        .ok_or_else(|| invalid("'deployment.a' should be an array"))?
+        .iter()
+        .map(|c| {
+            c.as_table()
+                .and_then(|c| c.get("cpu"))
+                .and_then(|cpu| cpu.as_integer())
+                .map(|cpu| cpu as u64)
+                .ok_or_else(|| invalid("'deployment.a' should be tables with a 'cpu'"))
+        })
+        .collect::<io::Result<Vec<u64>>>()?;
+    let breakpoints = a
+        .get("breakpoints")
+        .and_then(|b| b.as_slice())
+        .ok_or_else(|| invalid("'a.breakpoints' should be an array"))?
+        .iter()
+        .map(|b| {
+            b.as_str()
+                .map(String::from)
+                .ok_or_else(|| invalid("'a.breakpoints' should be strings"))
+        })
+        .collect::<io::Result<Vec<String>>>()?;
+
+    Ok((cpus, breakpoints))
+}
+
+/// Merges the measurements in `path` and writes them to `wrtr`, the software event
+/// rates go to `software_wrtr` (see `SOFTWARE_COLUMNS`) and the metrics perf
+/// derived to `metrics_wrtr` (see `PERF_METRICS_COLUMNS`). Fails if the run is
+/// missing files or one of them is broken, the rows of its other files are written.
+pub fn aggregate_into(
+    path: &Path,
+    cpu_filter: &str,
+    uncore_filter: &str,
+    wrtr: &mut dyn ResultWriter,
+    software_wrtr: &mut dyn ResultWriter,
+    metrics_wrtr: &mut dyn ResultWriter,
+) -> io::Result<()> {
+    if !path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Input directory does not exist {:?}", path),
+        ));
+    }
 
     let mut lscpu_file: PathBuf = path.to_path_buf();
     lscpu_file.push("lscpu.csv");
     let mut numactl_file: PathBuf = path.to_path_buf();
     numactl_file.push("numactl.dat");
+    for file in [&lscpu_file, &numactl_file].iter() {
+        if !file.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("File not found: {:?}", file),
+            ));
+        }
+    }
     let mt = MachineTopology::from_files(&lscpu_file, &numactl_file);
 
     let mut run_config: PathBuf = path.to_path_buf();
     run_config.push("run.toml");
 
     let (cpus, breakpoints) = if run_config.as_path().exists() {
-        let mut file = File::open(run_config.as_path()).expect("run.toml file does not exist?");
-        let mut run_string = String::new();
-        let _ = file.read_to_string(&mut run_string).unwrap();
-        let mut parser = toml::Parser::new(run_string.as_str());
-        let doc = match parser.parse() {
-            Some(doc) => doc,
-            None => {
-                error!("Can't parse the run.toml file:\n{:?}", parser.errors);
-                process::exit(3);
-            }
-        };
-
-        let a: &toml::Table = doc["a"]
-            .as_table()
-            .expect("run.toml: 'a' should be a table.");
-        let deployment: &toml::Table = doc
-            .get("deployment")
-            .expect("deployment?")
-            .as_table()
-            .expect("run.toml: 'a.deployment' should be a table.");
-        let cpus: Vec<u64> = deployment
-            .get("a")
-            .expect("deployment.a")
-            .as_slice()
-            .expect("run.tom: 'a.deployment.a' should be an array")
-            .iter()
-            .map(|c| {
-                c.as_table().expect("table")["cpu"]
-                    .as_integer()
-                    .expect("int") as u64
-            })
-            .collect();
-        let breakpoints: Vec<String> = a
-            .get("breakpoints")
-            .expect("no breakpoints?")
-            .as_slice()
-            .expect("breakpoints not an array?")
-            .iter()
-            .map(|s| s.as_str().expect("breakpoint not a string?").to_string())
-            .collect();
-
-        (cpus, breakpoints)
+        read_run_config(&run_config)?
     } else {
         debug!("Couldn't find a run.toml, we include counter values from all CPUs and sockets");
         let cpus: Vec<u64> = mt.cores();
@@ -468,10 +1548,14 @@ pub fn aggregate(path: &Path, cpu_filter: &str, uncore_filter: &str, save_to: &P
     let all_cpus: Vec<&CpuInfo> = cpus
         .into_iter()
         .map(|c| {
-            mt.cpu(c)
-                .expect("Invalid CPU in run.toml or wrong lscpu.csv?")
+            mt.cpu(c).ok_or_else(|| {
+                invalid_run(format!(
+                    "{:?}: CPU {} isn't in lscpu.csv (wrong lscpu.csv?)",
+                    run_config, c
+                ))
+            })
         })
-        .collect();
+        .collect::<io::Result<Vec<&CpuInfo>>>()?;
 
     // All the sockets this program is running on:
     let mut all_sockets: Vec<Socket> = all_cpus.iter().map(|c| c.socket).collect();
@@ -516,60 +1600,140 @@ pub fn aggregate(path: &Path, cpu_filter: &str, uncore_filter: &str, save_to: &P
     csv_data.push("perf.csv");
     let csv_data_path = csv_data.as_path();
     if !csv_data_path.exists() {
-        error!("File not found: {:?}", csv_data_path);
-        return;
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("File not found: {:?}", csv_data_path),
+        ));
     }
-    type Row = (String, String, String, String, String, String);
-    let mut rdr = csv::Reader::from_file(csv_data_path).unwrap();
-    let rows = rdr.decode().collect::<csv::Result<Vec<Row>>>().unwrap();
-
-    // Create result.csv file:
-    let csv_result: PathBuf = save_to.to_owned();
-    let mut wrtr = csv::Writer::from_file(csv_result.as_path()).unwrap();
-    wrtr.encode(&[
-        "EVENT_NAME",
-        "INDEX",
-        "TIME",
-        "SOCKET",
-        "CORE",
-        "CPU",
-        "NODE",
-        "UNIT",
-        "SAMPLE_VALUE",
-    ])
-    .unwrap();
-
-    // Write content in result.csv
-    for row in rows {
-        let (_, event_names, _, _, file, _) = row;
-        let _string_names: Vec<&str> = event_names.split(",").collect();
+    let (version, rows) = read_perf_log(csv_data_path);
+    debug!("{:?} has schema version {}", csv_data_path, version);
+    let merits = read_merits(path);
+
+    // Files are parsed in parallel, their rows are written in the order of perf.csv:
+    let aggregate_file = |row: &PerfLogRow,
+                          wrtr: &mut dyn ResultWriter,
+                          software_wrtr: &mut dyn ResultWriter,
+                          metrics_wrtr: &mut dyn ResultWriter|
+     -> io::Result<()> {
+        let (_, event_names, _, _, file, perf_command) = row;
+        // What the program reported about the measurement (see `output.rs`):
+        if let Some(&(ref name, merit)) = merits.get(file) {
+            wrtr.write_row(&[
+                name.as_str(),
+                "0",
+                "",
+                "",
+                "",
+                "",
+                "",
+                "workload",
+                merit.to_string().as_str(),
+                "",
+                "",
+                "",
+                "",
+            ]);
+        }
 
         let mut perf_data = path.to_owned();
-        perf_data.push(&file);
-
-        let file_ext = perf_data
-            .extension()
-            .expect("File does not have an extension");
-        match file_ext.to_str().unwrap() {
-            "data" => parse_perf_file(
-                perf_data.as_path(),
-                event_names.split(",").collect(),
-                &mut wrtr,
-            )
-            .unwrap(),
-            "csv" => parse_perf_csv_file(
-                &mt,
-                &all_cpus,
-                cpu_filter,
-                &considered_sockets,
-                &breakpoints,
-                perf_data.as_path(),
-                &mut wrtr,
-            )
-            .unwrap(),
-            _ => panic!("Unknown file extension, I can't parse this."),
+        perf_data.push(file);
+
+        let file_ext = perf_data.extension().and_then(|e| e.to_str()).unwrap_or("");
+        match file_ext {
+            "data" => parse_perf_file(perf_data.as_path(), event_names.split(",").collect(), wrtr)?,
+            "csv" | "json" => {
+                // Phase markers (if any) are saved as <nr>_phases.csv next to <nr>_stat.csv
+                let mut phases_file = path.to_owned();
+                phases_file.push(perfstat::next_to(file, "_phases.csv"));
+                let phases = read_phases(phases_file.as_path());
+
+                let scope = Scope::from_perf_command(perf_command.as_str());
+                for (event, total, seconds, per_second) in
+                    software_event_rates(scope, perf_data.as_path())
+                {
+                    software_wrtr.write_row(&[
+                        file.as_str(),
+                        event.as_str(),
+                        total.to_string().as_str(),
+                        seconds.to_string().as_str(),
+                        per_second.to_string().as_str(),
+                    ]);
+                }
+                if let Ok(metrics) = perfstat::metrics(perf_data.as_path(), scope) {
+                    for metric in metrics {
+                        metrics_wrtr.write_row(&[
+                            file.as_str(),
+                            metric.time.to_string().as_str(),
+                            metric.location.as_str(),
+                            metric.event.as_str(),
+                            metric.value.to_string().as_str(),
+                            metric.unit.as_str(),
+                        ]);
+                    }
+                }
+
+                match scope {
+                    Scope::Thread => {
+                        // The program's threads are saved as <nr>_threads.csv
+                        let mut threads_file = path.to_owned();
+                        threads_file.push(perfstat::next_to(file, "_threads.csv"));
+                        parse_perf_thread_csv_file(
+                            &read_threads(threads_file.as_path()).unwrap_or_default(),
+                            &phases,
+                            perf_data.as_path(),
+                            wrtr,
+                        )?
+                    }
+                    Scope::Cpu => parse_perf_csv_file(
+                        &mt,
+                        &all_cpus,
+                        cpu_filter,
+                        &considered_sockets,
+                        &breakpoints,
+                        &phases,
+                        perf_data.as_path(),
+                        wrtr,
+                    )?,
+                    scope => {
+                        parse_perf_scope_csv_file(&mt, scope, &phases, perf_data.as_path(), wrtr)?
+                    }
+                }
+                if perfstat::measures_metric_groups(perf_command) {
+                    parse_perf_metric_file(&mt, scope, &phases, perf_data.as_path(), wrtr)?
+                }
+            }
+            _ => {
+                return Err(invalid_run(format!(
+                    "{:?} has an unknown extension, I can't parse it.",
+                    perf_data
+                )))
+            }
         };
+        Ok(())
+    };
+    let failed: Mutex<Option<io::Error>> = Mutex::new(None);
+    parallel_ordered(
+        &rows,
+        wrtr,
+        software_wrtr,
+        metrics_wrtr,
+        &mut BufferWriter::new(),
+        |row, wrtr, software_wrtr, metrics_wrtr, _| match aggregate_file(
+            row,
+            wrtr,
+            software_wrtr,
+            metrics_wrtr,
+        ) {
+            Ok(()) => true,
+            Err(e) => {
+                failed.lock().unwrap().get_or_insert(e);
+                false
+            }
+        },
+        |_| {},
+    );
+    match failed.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
     }
-
-    info!("Merging completed");
 }
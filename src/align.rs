@@ -0,0 +1,383 @@
+//! Puts the time series of two co-located programs on one time grid, so they
+//! can be correlated. Their samples don't line up: every measurement of a run is
+//! a perf of its own (that reports the time since it started), resctrl samples A
+//! and B on its own clock, and runs side by side start whenever they start. We
+//! take the time every measurement started (`started` in perf.csv, `TIMESTAMP`
+//! in resctrl.csv), resample every series on a common grid (`--step`), linearly
+//! interpolated or with the last value, and write a row per point of the grid
+//! with the series of A and B as columns:
+//!
+//! ```text
+//! TIME,A.cycles,A.instructions,A.llc_occupancy,B.cycles,B.llc_occupancy
+//! 0,3100000000,4200000000,10485760,2900000000,8388608
+//! ```
+//!
+//! Counters become rates (per second of their interval), so intervals of
+//! different length compare. In a `pair` run, A and B are perf's counts on the
+//! CPUs of their deployment and their resctrl groups; with two directories (runs
+//! side by side, two profiles at the same time), A and B are what was measured
+//! for the A of each. A series is only resampled in the time it covers: the
+//! measurements of a run are one after the other, their events are empty at the
+//! time of the others.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use regex::Regex;
+use tracing::{debug, warn};
+
+use crate::perfstat::{self, Value};
+use crate::profile::Scope;
+
+/// How we get the value of a series at a point of the grid.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Method {
+    /// Between the samples before and after it.
+    Linear,
+    /// The sample before it.
+    Last,
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(method: &str) -> Result<Method, String> {
+        match method {
+            "linear" => Ok(Method::Linear),
+            "last" => Ok(Method::Last),
+            _ => Err(format!("Unknown method '{}' (linear or last)", method)),
+        }
+    }
+}
+
+pub struct AlignSettings {
+    /// Seconds between the points of the grid.
+    pub step: f64,
+    pub method: Method,
+    /// Only these series.
+    pub events: Option<Regex>,
+}
+
+/// (seconds since the epoch, value), in order.
+pub type Series = Vec<(f64, f64)>;
+
+/// The series of a program, by name.
+pub type Program = BTreeMap<String, Series>;
+
+fn to_io_error(e: csv::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Reads a CSV file with a header into rows of (column -> value).
+fn read_rows(path: &Path) -> io::Result<Vec<BTreeMap<String, String>>> {
+    let mut rdr = csv::Reader::from_file(path)
+        .map_err(to_io_error)?
+        .flexible(true);
+    let headers = rdr.headers().map_err(to_io_error)?;
+    let mut rows = Vec::new();
+    for record in rdr.records() {
+        let record = record.map_err(to_io_error)?;
+        rows.push(headers.iter().cloned().zip(record).collect());
+    }
+    Ok(rows)
+}
+
+/// The CPUs of A and B in the deployment of a `pair` run.
+fn deployment_cpus(run: &Path) -> Option<(Vec<u64>, Vec<u64>)> {
+    let content = fs::read_to_string(run.join("run.toml")).ok()?;
+    let doc = toml::Parser::new(&content).parse()?;
+    let cpus = |program: &str| -> Vec<u64> {
+        doc.get("deployment")
+            .and_then(|d| d.lookup(program))
+            .and_then(|p| p.as_slice())
+            .map_or(Vec::new(), |cpus| {
+                cpus.iter()
+                    .filter_map(|c| c.lookup("cpu").and_then(|c| c.as_integer()))
+                    .map(|c| c as u64)
+                    .collect()
+            })
+    };
+    Some((cpus("a"), cpus("b")))
+}
+
+/// Whose count it is (0 for A, 1 for B), `None` if neither runs there.
+fn program_of(location: &str, scope: Scope, cpus: Option<&(Vec<u64>, Vec<u64>)>) -> Option<usize> {
+    let (a, b) = match (scope, cpus) {
+        (Scope::Cpu, Some(cpus)) => cpus,
+        // Without CPUs we can't tell, it's A's measurement:
+        _ => return Some(0),
+    };
+    let cpu = u64::from_str(location.trim_start_matches("CPU")).ok()?;
+    if a.contains(&cpu) {
+        Some(0)
+    } else if b.contains(&cpu) {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+/// Adds the counters of a stat file (started at `started`) as rates.
+fn add_stat_file(
+    path: &Path,
+    scope: Scope,
+    started: f64,
+    cpus: Option<&(Vec<u64>, Vec<u64>)>,
+    settings: &AlignSettings,
+    programs: &mut [Program; 2],
+) -> io::Result<()> {
+    // (program, event) -> (end of the interval, sum of the counts)
+    let mut totals: BTreeMap<(usize, String), Vec<(f64, f64)>> = BTreeMap::new();
+    for count in perfstat::counts(path, scope)? {
+        let value = match count.value {
+            Value::Counted(value) => value,
+            _ => continue,
+        };
+        if let Some(ref events) = settings.events {
+            if !events.is_match(&count.event) {
+                continue;
+            }
+        }
+        let program = match program_of(&count.location, scope, cpus) {
+            Some(program) => program,
+            None => continue,
+        };
+        let samples = totals.entry((program, count.event)).or_default();
+        match samples.last_mut() {
+            Some(last) if last.0 == count.time => last.1 += value,
+            _ => samples.push((count.time, value)),
+        }
+    }
+    for ((program, event), samples) in totals {
+        let mut since = 0.0;
+        let series = programs[program].entry(event).or_default();
+        for (time, value) in samples {
+            if time > since {
+                series.push((started + time, value / (time - since)));
+            }
+            since = time;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the resctrl samples of A and B (summed over the L3 domains).
+fn add_resctrl(
+    path: &Path,
+    settings: &AlignSettings,
+    programs: &mut [Program; 2],
+) -> io::Result<()> {
+    let rows = read_rows(path)?;
+    if !rows.is_empty() && !rows[0].contains_key("TIMESTAMP") {
+        warn!(
+            "{:?} was written before autoperf saved when resctrl samples are taken, it's not aligned.",
+            path
+        );
+        return Ok(());
+    }
+    let columns = [
+        ("llc_occupancy", "LLC_OCCUPANCY"),
+        ("mbm_total_bps", "MBM_TOTAL_BPS"),
+        ("mbm_local_bps", "MBM_LOCAL_BPS"),
+    ];
+    for row in rows.iter() {
+        let program = match row.get("PROGRAM").map(|p| p.as_str()) {
+            Some("A") => 0,
+            Some("B") => 1,
+            _ => continue,
+        };
+        let time = match row.get("TIMESTAMP").and_then(|t| f64::from_str(t).ok()) {
+            Some(time) => time,
+            None => continue,
+        };
+        for &(name, column) in columns.iter() {
+            if let Some(ref events) = settings.events {
+                if !events.is_match(name) {
+                    continue;
+                }
+            }
+            let value = match row.get(column).and_then(|v| f64::from_str(v).ok()) {
+                Some(value) => value,
+                None => continue,
+            };
+            let series = programs[program].entry(String::from(name)).or_default();
+            match series.last_mut() {
+                Some(last) if last.0 == time => last.1 += value,
+                _ => series.push((time, value)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The series of A and B in a run (or profile) directory.
+pub fn read_programs(dir: &Path, settings: &AlignSettings) -> io::Result<[Program; 2]> {
+    let mut programs = [Program::new(), Program::new()];
+    let cpus = deployment_cpus(dir);
+    for measurement in read_rows(&dir.join("perf.csv"))? {
+        let empty = String::new();
+        let column = |name: &str| measurement.get(name).unwrap_or(&empty);
+        let datafile = column("datafile");
+        if !perfstat::is_stat_file(datafile) {
+            continue;
+        }
+        let started = match f64::from_str(column("started")) {
+            Ok(started) => started,
+            Err(_) => {
+                warn!(
+                    "{:?} was measured before autoperf saved when measurements start, it's not aligned.",
+                    dir.join(datafile)
+                );
+                continue;
+            }
+        };
+        let scope = Scope::from_perf_command(column("perf_command"));
+        debug!("Reading {:?} (started at {})", dir.join(datafile), started);
+        add_stat_file(
+            &dir.join(datafile),
+            scope,
+            started,
+            cpus.as_ref(),
+            settings,
+            &mut programs,
+        )?;
+    }
+    let resctrl_csv = dir.join("resctrl.csv");
+    if resctrl_csv.exists() {
+        add_resctrl(&resctrl_csv, settings, &mut programs)?;
+    }
+    for program in programs.iter_mut() {
+        program.retain(|_, series| !series.is_empty());
+        for series in program.values_mut() {
+            series.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        }
+    }
+    Ok(programs)
+}
+
+/// The value of `series` at `time`, if it covers it.
+fn value_at(series: &Series, time: f64, method: Method) -> Option<f64> {
+    let (first, last) = (series.first()?, series.last()?);
+    if time < first.0 || time > last.0 {
+        return None;
+    }
+    let after = series.partition_point(|s| s.0 <= time);
+    let before = series[after - 1];
+    match (method, series.get(after)) {
+        (Method::Linear, Some(next)) => {
+            let share = (time - before.0) / (next.0 - before.0);
+            Some(before.1 + share * (next.1 - before.1))
+        }
+        _ => Some(before.1),
+    }
+}
+
+/// Series resampled on a common time grid.
+pub struct Grid {
+    /// `A.<series>` and `B.<series>`, in order.
+    pub names: Vec<String>,
+    pub step: f64,
+    /// The values of every series (in the order of `names`) at every point,
+    /// `None` outside of the time it covers.
+    pub values: Vec<Vec<Option<f64>>>,
+}
+
+impl Grid {
+    pub fn points(&self) -> usize {
+        self.values.first().map_or(0, |v| v.len())
+    }
+
+    /// The values of the series `name`.
+    pub fn series(&self, name: &str) -> Option<&[Option<f64>]> {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .map(|idx| &self.values[idx][..])
+    }
+}
+
+/// Resamples the series of A and B on a grid from their first to their last
+/// sample, `step` seconds apart.
+pub fn resample(programs: &[Program; 2], step: f64, method: Method) -> io::Result<Grid> {
+    let series: Vec<(String, &Series)> = ["A", "B"]
+        .iter()
+        .zip(programs.iter())
+        .flat_map(|(name, program)| {
+            program
+                .iter()
+                .map(move |(series, samples)| (format!("{}.{}", name, series), samples))
+        })
+        .collect();
+    if series.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no time series to align",
+        ));
+    }
+
+    let start = series
+        .iter()
+        .map(|&(_, s)| s[0].0)
+        .fold(f64::INFINITY, f64::min);
+    let end = series
+        .iter()
+        .map(|&(_, s)| s[s.len() - 1].0)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let points = ((end - start) / step).floor() as usize + 1;
+    debug!(
+        "Aligning {} series on {} points, {}s apart",
+        series.len(),
+        points,
+        step
+    );
+    Ok(Grid {
+        names: series.iter().map(|(name, _)| name.clone()).collect(),
+        step,
+        values: series
+            .iter()
+            .map(|&(_, s)| {
+                (0..points)
+                    .map(|point| value_at(s, start + point as f64 * step, method))
+                    .collect()
+            })
+            .collect(),
+    })
+}
+
+/// Resamples the series of A and B of `a` (a `pair` run), or those of A in `a`
+/// and A in `b` (two runs at the same time), on a common grid and writes them
+/// to `out` (see the module doc). Returns how many rows there are.
+pub fn align<W: io::Write>(
+    a: &Path,
+    b: Option<&Path>,
+    settings: &AlignSettings,
+    out: W,
+) -> io::Result<usize> {
+    let programs = match b {
+        None => read_programs(a, settings)?,
+        Some(b) => {
+            let [of_a, _] = read_programs(a, settings)?;
+            let [of_b, _] = read_programs(b, settings)?;
+            [of_a, of_b]
+        }
+    };
+    let grid = resample(&programs, settings.step, settings.method)?;
+
+    let mut wtr = csv::Writer::from_writer(out);
+    let mut header = vec![String::from("TIME")];
+    header.extend(grid.names.iter().cloned());
+    wtr.encode(header).map_err(to_io_error)?;
+    for point in 0..grid.points() {
+        let mut row = vec![format!("{:.6}", point as f64 * grid.step)];
+        row.extend(
+            grid.values
+                .iter()
+                .map(|v| v[point].map_or(String::new(), |v| v.to_string())),
+        );
+        wtr.encode(row).map_err(to_io_error)?;
+    }
+    wtr.flush().map_err(to_io_error)?;
+    Ok(grid.points())
+}
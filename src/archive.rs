@@ -0,0 +1,63 @@
+//! Packs the output directory of a run into a single zstd-compressed tar archive
+//! (`<run>.tar.zst`). Campaigns produce thousands of small files otherwise.
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::debug;
+
+/// File extension of our archives.
+pub const ARCHIVE_EXTENSION: &str = ".tar.zst";
+
+/// zstd compression level (the CSV files compress well even at low levels).
+const COMPRESSION_LEVEL: i32 = 3;
+
+pub fn is_archive(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(ARCHIVE_EXTENSION)
+}
+
+/// Where `compress_dir` puts the archive of `dir`.
+pub fn archive_path(dir: &Path) -> PathBuf {
+    let name = dir
+        .file_name()
+        .map_or(String::from("out"), |n| n.to_string_lossy().into_owned());
+    dir.with_file_name(format!("{}{}", name, ARCHIVE_EXTENSION))
+}
+
+/// Compresses `dir` into `<dir>.tar.zst` and removes `dir`.
+pub fn compress_dir(dir: &Path) -> io::Result<PathBuf> {
+    let archive = archive_path(dir);
+    let name = dir
+        .file_name()
+        .map_or(String::from("out"), |n| n.to_string_lossy().into_owned());
+
+    {
+        let file = File::create(&archive)?;
+        let encoder = zstd::Encoder::new(file, COMPRESSION_LEVEL)?;
+        let mut builder = tar::Builder::new(encoder);
+        builder.append_dir_all(&name, dir)?;
+        builder.into_inner()?.finish()?;
+    }
+    fs::remove_dir_all(dir)?;
+
+    debug!("Compressed {:?} into {:?}", dir, archive);
+    Ok(archive)
+}
+
+/// Extracts an archive created by `compress_dir` into `into` and returns the
+/// path of the extracted run directory.
+pub fn extract(archive: &Path, into: &Path) -> io::Result<PathBuf> {
+    let file = File::open(archive)?;
+    let decoder = zstd::Decoder::new(file)?;
+    tar::Archive::new(decoder).unpack(into)?;
+
+    let name = archive.file_name().map_or(String::new(), |n| {
+        n.to_string_lossy()
+            .trim_end_matches(ARCHIVE_EXTENSION)
+            .to_string()
+    });
+    let mut dir = into.to_path_buf();
+    dir.push(name);
+    Ok(dir)
+}
@@ -0,0 +1,312 @@
+//! Measures the benchmarks of a Rust project (`autoperf bench`), one at a time:
+//! `cargo bench --no-run` builds them and tells us the bench executables, every
+//! executable lists its benchmarks (`--list`), and every benchmark runs pinned
+//! to one CPU (perf only counts on that CPU) with its own `CRITERION_HOME`. The
+//! counters are only enabled after Criterion's warm-up.
+//!
+//! `bench.csv` joins the counters with what Criterion measured: the total of
+//! every event, the iterations Criterion sampled, the event per iteration and
+//! Criterion's mean, median and standard deviation. Benchmarks of the built-in
+//! harness (`#[bench]`) have no timing and no iterations.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use regex::Regex;
+use rustc_serialize::json::Json;
+use tracing::{debug, error, info, warn};
+
+use super::columnar::result_writer;
+use super::diff;
+use super::profile::{self, ProfileSettings, SOFTWARE_EVENTS};
+use super::summary::{self, Failure};
+use super::util::{mkdir, Cpu};
+
+pub const BENCH_COLUMNS: [&str; 8] = [
+    "BENCHMARK",
+    "EVENT_NAME",
+    "TOTAL",
+    "ITERATIONS",
+    "PER_ITERATION",
+    "MEAN_NS",
+    "MEDIAN_NS",
+    "STD_DEV_NS",
+];
+
+/// A benchmark of a bench target.
+#[derive(Debug, Clone)]
+pub struct Benchmark {
+    /// The bench target (`[[bench]]` name).
+    pub target: String,
+    pub executable: PathBuf,
+    /// e.g. `fib/20`
+    pub name: String,
+    /// Does Criterion run it (or the built-in harness)?
+    pub criterion: bool,
+}
+
+/// What Criterion measured for a benchmark.
+#[derive(Debug, Clone, Default)]
+struct Timing {
+    mean_ns: f64,
+    median_ns: f64,
+    std_dev_ns: f64,
+    iterations: f64,
+}
+
+/// The bench executables `cargo bench --no-run` builds in `project`: (target,
+/// executable).
+fn bench_executables(project: &Path) -> Result<Vec<(String, PathBuf)>, String> {
+    let out = Command::new("cargo")
+        .args(["bench", "--no-run", "--message-format=json"])
+        .current_dir(project)
+        .output()
+        .map_err(|e| format!("Can't run cargo: {}", e))?;
+    if !out.status.success() {
+        return Err(format!(
+            "cargo bench --no-run failed with {}:\n{}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr)
+        ));
+    }
+
+    let mut executables = Vec::new();
+    // One JSON message per line, the artifacts of bench targets have an executable:
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+        let message = match Json::from_str(line) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        let is_bench = message
+            .find_path(&["target", "kind"])
+            .and_then(|k| k.as_array())
+            .is_some_and(|kinds| kinds.iter().any(|k| k.as_string() == Some("bench")));
+        let name = message
+            .find_path(&["target", "name"])
+            .and_then(|n| n.as_string());
+        let executable = message.find("executable").and_then(|e| e.as_string());
+        if let (true, Some(name), Some(executable)) = (is_bench, name, executable) {
+            executables.push((String::from(name), PathBuf::from(executable)));
+        }
+    }
+    Ok(executables)
+}
+
+/// The benchmarks `executable --list` prints (`fib/20: benchmark` for
+/// Criterion, `bench_fib: bench` for the built-in harness).
+fn list(target: &str, executable: &Path, project: &Path) -> Result<Vec<Benchmark>, String> {
+    let out = Command::new(executable)
+        .args(["--bench", "--list"])
+        .current_dir(project)
+        .output()
+        .map_err(|e| format!("Can't run {:?}: {}", executable, e))?;
+    if !out.status.success() {
+        return Err(format!(
+            "{:?} --list failed with {}",
+            executable, out.status
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (name, kind) = line.rsplit_once(": ")?;
+            if kind != "benchmark" && kind != "bench" {
+                return None;
+            }
+            Some(Benchmark {
+                target: String::from(target),
+                executable: executable.to_path_buf(),
+                name: String::from(name),
+                criterion: kind == "benchmark",
+            })
+        })
+        .collect())
+}
+
+/// The benchmarks of `project` that match `filter` (by `<target>/<name>`).
+pub fn benchmarks(project: &Path, filter: Option<&Regex>) -> Result<Vec<Benchmark>, String> {
+    let mut benchmarks = Vec::new();
+    for (target, executable) in bench_executables(project)? {
+        for benchmark in list(&target, &executable, project)? {
+            let id = format!("{}/{}", benchmark.target, benchmark.name);
+            if filter.is_none_or(|f| f.is_match(&id) || f.is_match(&benchmark.name)) {
+                benchmarks.push(benchmark);
+            }
+        }
+    }
+    Ok(benchmarks)
+}
+
+/// `fib/20 (small)` as a directory name.
+fn directory_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// The `new/estimates.json` (and `new/sample.json`) Criterion saved somewhere
+/// below `criterion_home`.
+fn find_estimates(dir: &Path) -> Option<PathBuf> {
+    let estimates = dir.join("new").join("estimates.json");
+    if estimates.exists() {
+        return Some(estimates);
+    }
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+        .find_map(|p| find_estimates(&p))
+}
+
+fn timing(criterion_home: &Path) -> Option<Timing> {
+    let estimates_file = find_estimates(criterion_home)?;
+    let estimates = Json::from_str(&fs::read_to_string(&estimates_file).ok()?).ok()?;
+    let point = |statistic: &str| {
+        estimates
+            .find_path(&[statistic, "point_estimate"])
+            .and_then(|v| v.as_f64())
+            .unwrap_or(0.0)
+    };
+    let iterations = fs::read_to_string(estimates_file.with_file_name("sample.json"))
+        .ok()
+        .and_then(|s| Json::from_str(&s).ok())
+        .and_then(|s| {
+            s.find("iters")
+                .and_then(|i| i.as_array())
+                .map(|iters| iters.iter().filter_map(|i| i.as_f64()).sum())
+        })
+        .unwrap_or(0.0);
+    Some(Timing {
+        mean_ns: point("mean"),
+        median_ns: point("median"),
+        std_dev_ns: point("std_dev"),
+        iterations,
+    })
+}
+
+/// The command that runs `benchmark` on `cpu`.
+fn command(benchmark: &Benchmark, cpu: Cpu, warm_up: u64) -> Vec<String> {
+    let mut cmd = vec![
+        String::from("taskset"),
+        String::from("-c"),
+        cpu.to_string(),
+        benchmark.executable.to_string_lossy().into_owned(),
+        String::from("--bench"),
+        String::from("--exact"),
+        benchmark.name.clone(),
+    ];
+    if benchmark.criterion {
+        cmd.push(String::from("--warm-up-time"));
+        cmd.push(warm_up.to_string());
+    }
+    cmd
+}
+
+/// Measures the benchmarks of `project` that match `filter` on `cpu` and saves
+/// their counters and timing in `output`.
+pub fn bench(
+    project: &Path,
+    output: &Path,
+    filter: Option<&Regex>,
+    cpu: Cpu,
+    warm_up: u64,
+    settings: &ProfileSettings,
+    dryrun: bool,
+) {
+    let benchmarks = match benchmarks(project, filter) {
+        Ok(benchmarks) => benchmarks,
+        Err(e) => {
+            error!("{}", e);
+            summary::fail(Failure::Workload);
+        }
+    };
+    if benchmarks.is_empty() {
+        error!("Found no benchmarks in {:?}.", project);
+        summary::fail(Failure::Error);
+    }
+    info!("Measuring {} benchmarks on CPU {}", benchmarks.len(), cpu);
+    summary::planned(benchmarks.len());
+
+    let settings = ProfileSettings {
+        // Criterion warms up first, its measurement follows:
+        measure_after: Some(Duration::from_secs(warm_up)),
+        cpus: Some(vec![cpu]),
+        ..settings.clone()
+    };
+    let project_dir = project.to_string_lossy();
+    let mut measured: Vec<(&Benchmark, PathBuf)> = Vec::new();
+    for benchmark in benchmarks.iter() {
+        let mut run_path = output.to_path_buf();
+        run_path.push(&benchmark.target);
+        mkdir(&run_path);
+        run_path.push(directory_name(&benchmark.name));
+        let criterion_home = run_path.join("criterion");
+        debug!(
+            "Measuring {}/{} in {:?}",
+            benchmark.target, benchmark.name, run_path
+        );
+
+        profile::profile(
+            &run_path,
+            &project_dir,
+            command(benchmark, cpu, warm_up),
+            vec![(
+                String::from("CRITERION_HOME"),
+                criterion_home.to_string_lossy().into_owned(),
+            )],
+            Vec::new(),
+            false,
+            None,
+            &settings,
+            dryrun,
+        );
+        summary::run_ended(false);
+        measured.push((benchmark, run_path));
+    }
+    if dryrun {
+        return;
+    }
+
+    let mut bench_csv = output.to_path_buf();
+    bench_csv.push("bench.csv");
+    let mut wrtr = result_writer("csv", &bench_csv, &BENCH_COLUMNS);
+    for (benchmark, run_path) in measured {
+        let id = format!("{}/{}", benchmark.target, benchmark.name);
+        let timing = timing(&run_path.join("criterion"));
+        if benchmark.criterion && timing.is_none() {
+            warn!("Criterion saved no estimates for {}.", id);
+        }
+        let timing = timing.unwrap_or_default();
+        let totals: BTreeMap<String, f64> = diff::interval_totals(&run_path)
+            .into_iter()
+            // Software events are counted in every measurement, see software_events.csv:
+            .filter(|(event, _)| !SOFTWARE_EVENTS.contains(&event.as_str()))
+            .map(|(event, values)| (event, values.iter().sum()))
+            .collect();
+        for (event, total) in totals.iter() {
+            let per_iteration = if timing.iterations > 0.0 {
+                (total / timing.iterations).to_string()
+            } else {
+                String::new()
+            };
+            wrtr.write_row(&[
+                id.as_str(),
+                event.as_str(),
+                total.to_string().as_str(),
+                timing.iterations.to_string().as_str(),
+                per_iteration.as_str(),
+                timing.mean_ns.to_string().as_str(),
+                timing.median_ns.to_string().as_str(),
+                timing.std_dev_ns.to_string().as_str(),
+            ]);
+        }
+    }
+    wrtr.finish();
+    info!("Saved {:?}", bench_csv);
+}
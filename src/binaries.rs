@@ -0,0 +1,113 @@
+//! Records which binaries (the program and all shared libraries it loads) we
+//! measured, with their SHA-256 and ELF build-id, so results can be tied to the
+//! exact builds that produced them.
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use tracing::debug;
+
+use super::threads::descendants;
+use super::util::sha256sum;
+
+/// How often we look for newly mapped binaries in /proc.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// A binary: (path, sha256, build-id).
+pub type Binary = (String, String, String);
+
+pub struct BinaryTracker {
+    stop: mpsc::Sender<()>,
+    tracker: thread::JoinHandle<BTreeSet<String>>,
+}
+
+/// Adds the executable and all executable file mappings of `pid`.
+fn scan(pid: u64, binaries: &mut BTreeSet<String>) {
+    if let Ok(exe) = fs::read_link(format!("/proc/{}/exe", pid)) {
+        binaries.insert(exe.to_string_lossy().into_owned());
+    }
+
+    let maps = match fs::read_to_string(format!("/proc/{}/maps", pid)) {
+        Ok(maps) => maps,
+        Err(_) => return,
+    };
+    // address perms offset dev inode path
+    for line in maps.lines() {
+        let fields: Vec<&str> = line.splitn(6, ' ').collect();
+        if fields.len() < 6 || !fields[1].contains('x') {
+            continue;
+        }
+        let path = fields[5].trim();
+        if path.starts_with('/') {
+            binaries.insert(String::from(path.trim_end_matches(" (deleted)")));
+        }
+    }
+}
+
+fn build_id(path: &str) -> String {
+    Command::new("readelf")
+        .arg("-n")
+        .arg(path)
+        .output()
+        .ok()
+        .and_then(|out| {
+            String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .find(|l| l.trim().starts_with("Build ID:"))
+                .map(|l| String::from(l.trim()["Build ID:".len()..].trim()))
+        })
+        .unwrap_or_default()
+}
+
+impl BinaryTracker {
+    /// Starts tracking the binaries of all processes spawned by `parent` (i.e., perf).
+    pub fn start(parent: u32) -> BinaryTracker {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let tracker = thread::spawn(move || {
+            let mut binaries = BTreeSet::new();
+            loop {
+                for pid in descendants(parent as u64) {
+                    scan(pid, &mut binaries);
+                }
+                match stopped.recv_timeout(Duration::from_millis(POLL_INTERVAL_MS)) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            }
+            binaries
+        });
+
+        BinaryTracker { stop, tracker }
+    }
+
+    /// Stops tracking, hashes all binaries we've seen and saves them to `save_to`.
+    pub fn finish(self, save_to: &Path) -> io::Result<Vec<Binary>> {
+        let _ = self.stop.send(());
+        let binaries: Vec<Binary> = self
+            .tracker
+            .join()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|path| {
+                let hash = sha256sum(Path::new(&path)).unwrap_or_default();
+                let id = build_id(&path);
+                (path, hash, id)
+            })
+            .collect();
+        debug!("Program used {} binaries.", binaries.len());
+
+        let mut wtr = csv::Writer::from_file(save_to).unwrap();
+        wtr.encode(("path", "sha256", "build_id")).unwrap();
+        for b in binaries.iter() {
+            wtr.encode(b).unwrap();
+        }
+        wtr.flush().unwrap();
+
+        Ok(binaries)
+    }
+}
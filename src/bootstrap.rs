@@ -0,0 +1,129 @@
+//! Bootstrap confidence intervals, so a mean comes with how sure we are of it.
+//! We resample the values (the interval totals of an event in a run) with
+//! replacement `iterations` times and take the 2.5th and 97.5th percentile of
+//! the means of the resamples: the percentile bootstrap, which doesn't assume
+//! the values are normally distributed (counter values rarely are).
+//!
+//! The resampling is seeded, so the same results give the same intervals.
+//! `aggregate` saves the intervals of every event of every run in
+//! `confidence_intervals.csv`, `diff` has the interval of every difference and
+//! `report` shows them next to the means.
+use std::collections::BTreeMap;
+
+use regex::Regex;
+
+use crate::columnar::ResultWriter;
+use crate::diff::{mean, IntervalTotals};
+
+/// The share of the resampled means between the bounds of an interval.
+pub const CONFIDENCE: f64 = 0.95;
+
+/// The columns of `confidence_intervals.csv` (after `RUN` for a campaign).
+pub const INTERVAL_COLUMNS: [&str; 5] = ["EVENT_NAME", "N", "MEAN", "CI_LOWER", "CI_UPPER"];
+
+const SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// splitmix64, good enough to pick values and fast.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// The mean of a resample (with replacement) of `values`.
+    fn resampled_mean(&mut self, values: &[f64]) -> f64 {
+        let n = values.len() as u64;
+        let sum: f64 = (0..n).map(|_| values[(self.next() % n) as usize]).sum();
+        sum / n as f64
+    }
+}
+
+/// The bounds that leave out (1 - `CONFIDENCE`) / 2 of `estimates` on each side.
+fn percentiles(mut estimates: Vec<f64>) -> (f64, f64) {
+    estimates.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let tail = (1.0 - CONFIDENCE) / 2.0;
+    let at = |p: f64| {
+        let idx = (p * (estimates.len() - 1) as f64).round() as usize;
+        estimates[idx.min(estimates.len() - 1)]
+    };
+    (at(tail), at(1.0 - tail))
+}
+
+/// The confidence interval of the mean of `values`, `None` with fewer than two
+/// values (or no iterations).
+pub fn mean_interval(values: &[f64], iterations: usize) -> Option<(f64, f64)> {
+    if values.len() < 2 || iterations == 0 {
+        return None;
+    }
+    let mut rng = Rng(SEED);
+    let means = (0..iterations)
+        .map(|_| rng.resampled_mean(values))
+        .collect();
+    Some(percentiles(means))
+}
+
+/// The confidence interval of the difference of the means of `b` and `a`
+/// (both are resampled on their own).
+pub fn difference_interval(a: &[f64], b: &[f64], iterations: usize) -> Option<(f64, f64)> {
+    if a.len() < 2 || b.len() < 2 || iterations == 0 {
+        return None;
+    }
+    let mut rng = Rng(SEED);
+    let differences = (0..iterations)
+        .map(|_| rng.resampled_mean(b) - rng.resampled_mean(a))
+        .collect();
+    Some(percentiles(differences))
+}
+
+/// Passes the results of a run on and keeps the interval totals of every event
+/// for its confidence intervals.
+pub struct IntervalWriter<'a> {
+    inner: &'a mut dyn ResultWriter,
+    totals: IntervalTotals,
+}
+
+impl<'a> IntervalWriter<'a> {
+    pub fn new(inner: &'a mut dyn ResultWriter) -> IntervalWriter<'a> {
+        IntervalWriter {
+            inner,
+            totals: IntervalTotals::new(),
+        }
+    }
+
+    /// The rows (see `INTERVAL_COLUMNS`) of the events that match `events`.
+    pub fn intervals(self, events: Option<&Regex>, iterations: usize) -> Vec<Vec<String>> {
+        let totals: BTreeMap<String, Vec<f64>> = self.totals.into_totals();
+        totals
+            .into_iter()
+            .filter(|(event, _)| events.is_none_or(|p| p.is_match(event)))
+            .map(|(event, values)| {
+                let (lower, upper) = match mean_interval(&values, iterations) {
+                    Some((lower, upper)) => (lower.to_string(), upper.to_string()),
+                    None => (String::new(), String::new()),
+                };
+                vec![
+                    event,
+                    values.len().to_string(),
+                    mean(&values).to_string(),
+                    lower,
+                    upper,
+                ]
+            })
+            .collect()
+    }
+}
+
+impl<'a> ResultWriter for IntervalWriter<'a> {
+    fn write_row(&mut self, row: &[&str]) {
+        self.totals.write_row(row);
+        self.inner.write_row(row);
+    }
+
+    /// The inner writer is finished by whoever created it.
+    fn finish(&mut self) {}
+}
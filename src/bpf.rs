@@ -0,0 +1,150 @@
+//! Records OS-level interference next to the hardware counters with eBPF (`--bpf`,
+//! needs `cargo build --features bpf`, libbpf and clang): how long the program
+//! was off the CPU, how long its threads waited in the run queue and which
+//! system calls it made (and how long they took).
+//!
+//! The BPF programs (`bpf/auxiliary.bpf.c`, compiled by build.rs) follow the
+//! threads and processes that perf starts. The totals of a measurement are saved
+//! as `<nr>_bpf.csv` with the columns `metric`, `syscall` (the system call number,
+//! empty for the totals) and `value`.
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use csv;
+use libbpf_rs::{Link, MapFlags, Object, ObjectBuilder};
+use tracing::debug;
+
+use super::threads::descendants;
+
+const OBJECT: &'static [u8] = include_bytes!(concat!(env!("OUT_DIR"), "/auxiliary.bpf.o"));
+
+/// The entries of the `totals` map (in that order) and how we report them.
+const TOTALS: [(&'static str, f64); 4] = [
+    ("off_cpu_seconds", 1e-9),
+    ("run_queue_seconds", 1e-9),
+    ("run_queue_waits", 1.0),
+    ("context_switches", 1.0),
+];
+
+/// Has to match MAX_SYSCALLS of the BPF programs.
+const MAX_SYSCALLS: u32 = 1024;
+
+/// (metric, system call number or "", value)
+pub type BpfValue = (String, String, f64);
+
+pub struct BpfCollector {
+    object: Object,
+    _links: Vec<Link>,
+}
+
+fn to_io_error<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    bytes
+        .get(offset..offset + 8)
+        .and_then(|b| b.try_into().ok())
+        .map_or(0, u64::from_ne_bytes)
+}
+
+impl BpfCollector {
+    /// Starts following everything `launcher` (perf) starts.
+    pub fn start(launcher: u32) -> io::Result<BpfCollector> {
+        let open = ObjectBuilder::default()
+            .open_memory(OBJECT)
+            .map_err(to_io_error)?;
+        let mut object = open.load().map_err(to_io_error)?;
+        object
+            .map("launcher")
+            .ok_or(to_io_error("no launcher map"))?
+            .update(&0u32.to_ne_bytes(), &launcher.to_ne_bytes(), MapFlags::ANY)
+            .map_err(to_io_error)?;
+
+        let mut links = Vec::new();
+        for program in object.progs_iter_mut() {
+            links.push(program.attach().map_err(to_io_error)?);
+        }
+
+        // perf may have started the program before we were attached:
+        let tracked = object.map("tracked").ok_or(to_io_error("no tracked map"))?;
+        for pid in descendants(launcher as u64) {
+            let tasks = match fs::read_dir(format!("/proc/{}/task", pid)) {
+                Ok(tasks) => tasks,
+                Err(_) => continue,
+            };
+            for task in tasks.filter_map(|t| t.ok()) {
+                if let Ok(tid) = u32::from_str(&task.file_name().to_string_lossy()) {
+                    let _ = tracked.update(&tid.to_ne_bytes(), &[1u8], MapFlags::ANY);
+                }
+            }
+        }
+
+        Ok(BpfCollector {
+            object: object,
+            _links: links,
+        })
+    }
+
+    fn read(&self) -> io::Result<Vec<BpfValue>> {
+        let mut values = Vec::new();
+        let totals = self
+            .object
+            .map("totals")
+            .ok_or(to_io_error("no totals map"))?;
+        for (idx, &(metric, scale)) in TOTALS.iter().enumerate() {
+            let value = totals
+                .lookup(&(idx as u32).to_ne_bytes(), MapFlags::ANY)
+                .map_err(to_io_error)?
+                .map_or(0, |v| u64_at(&v, 0));
+            values.push((String::from(metric), String::new(), value as f64 * scale));
+        }
+
+        let syscalls = self
+            .object
+            .map("syscalls")
+            .ok_or(to_io_error("no syscalls map"))?;
+        let (mut count, mut seconds) = (0, 0.0);
+        for nr in 0..MAX_SYSCALLS {
+            let stats = match syscalls
+                .lookup(&nr.to_ne_bytes(), MapFlags::ANY)
+                .map_err(to_io_error)?
+            {
+                Some(stats) => stats,
+                None => continue,
+            };
+            let (calls, ns) = (u64_at(&stats, 0), u64_at(&stats, 8));
+            if calls == 0 {
+                continue;
+            }
+            count += calls;
+            seconds += ns as f64 * 1e-9;
+            values.push((String::from("syscalls"), nr.to_string(), calls as f64));
+            values.push((
+                String::from("syscall_seconds"),
+                nr.to_string(),
+                ns as f64 * 1e-9,
+            ));
+        }
+        values.push((String::from("syscalls"), String::new(), count as f64));
+        values.push((String::from("syscall_seconds"), String::new(), seconds));
+        Ok(values)
+    }
+
+    /// Detaches and saves what we recorded to `save_to`.
+    pub fn finish(self, save_to: &Path) -> io::Result<Vec<BpfValue>> {
+        let values = self.read()?;
+        let mut wtr = csv::Writer::from_file(save_to).map_err(to_io_error)?;
+        wtr.encode(("metric", "syscall", "value"))
+            .map_err(to_io_error)?;
+        for value in values.iter() {
+            wtr.encode(value).map_err(to_io_error)?;
+        }
+        wtr.flush().map_err(to_io_error)?;
+        debug!("Saved {} BPF values to {:?}", values.len(), save_to);
+        Ok(values)
+    }
+}
@@ -0,0 +1,259 @@
+// Off-CPU time, run-queue latency and system calls of the measured program (see
+// bpf.rs). We track the program's tasks by thread id: user space adds the ones
+// that exist when we attach, the program's new threads and processes are added
+// when they are forked (by the launcher, i.e., perf, or a task we track).
+//
+// We only use tracepoints with their stable argument layout, so this doesn't
+// need BTF or a vmlinux.h.
+#include <linux/bpf.h>
+#include <linux/types.h>
+#include <bpf/bpf_helpers.h>
+
+#define MAX_TASKS 65536
+#define MAX_SYSCALLS 1024
+#define TASK_RUNNING 0
+
+// Keys of `totals` (see bpf.rs):
+#define OFF_CPU_NS 0
+#define RUN_QUEUE_NS 1
+#define RUN_QUEUE_WAITS 2
+#define CONTEXT_SWITCHES 3
+#define TOTALS 4
+
+// Every tracepoint starts with 8 bytes of common fields.
+struct sched_switch_args {
+    __u64 common;
+    char prev_comm[16];
+    __s32 prev_pid;
+    __s32 prev_prio;
+    long prev_state;
+    char next_comm[16];
+    __s32 next_pid;
+    __s32 next_prio;
+};
+
+struct sched_wakeup_args {
+    __u64 common;
+    char comm[16];
+    __s32 pid;
+    __s32 prio;
+};
+
+struct sched_process_fork_args {
+    __u64 common;
+    char parent_comm[16];
+    __s32 parent_pid;
+    char child_comm[16];
+    __s32 child_pid;
+};
+
+struct sched_process_exit_args {
+    __u64 common;
+    char comm[16];
+    __s32 pid;
+    __s32 prio;
+};
+
+struct sys_enter_args {
+    __u64 common;
+    long id;
+    unsigned long args[6];
+};
+
+struct sys_exit_args {
+    __u64 common;
+    long id;
+    long ret;
+};
+
+struct syscall_stats {
+    __u64 count;
+    __u64 ns;
+};
+
+// The process that starts the program (perf).
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, __u32);
+    __type(value, __u32);
+} launcher SEC(".maps");
+
+// Thread ids of the program.
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, MAX_TASKS);
+    __type(key, __u32);
+    __type(value, __u8);
+} tracked SEC(".maps");
+
+// When a task went off the CPU.
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, MAX_TASKS);
+    __type(key, __u32);
+    __type(value, __u64);
+} off_since SEC(".maps");
+
+// When a task became runnable (woken up or preempted).
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, MAX_TASKS);
+    __type(key, __u32);
+    __type(value, __u64);
+} queued_since SEC(".maps");
+
+// When a task entered the system call it's in.
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, MAX_TASKS);
+    __type(key, __u32);
+    __type(value, __u64);
+} syscall_since SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, TOTALS);
+    __type(key, __u32);
+    __type(value, __u64);
+} totals SEC(".maps");
+
+// By system call number.
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, MAX_SYSCALLS);
+    __type(key, __u32);
+    __type(value, struct syscall_stats);
+} syscalls SEC(".maps");
+
+static __always_inline int is_tracked(__u32 tid)
+{
+    return bpf_map_lookup_elem(&tracked, &tid) != NULL;
+}
+
+static __always_inline void add(__u32 key, __u64 value)
+{
+    __u64 *total = bpf_map_lookup_elem(&totals, &key);
+    if (total)
+        __sync_fetch_and_add(total, value);
+}
+
+SEC("tracepoint/sched/sched_switch")
+int on_switch(struct sched_switch_args *ctx)
+{
+    __u64 now = bpf_ktime_get_ns();
+    __u32 prev = ctx->prev_pid;
+    __u32 next = ctx->next_pid;
+    __u64 *since;
+
+    if (is_tracked(prev)) {
+        add(CONTEXT_SWITCHES, 1);
+        bpf_map_update_elem(&off_since, &prev, &now, BPF_ANY);
+        // Preempted, so it waits in the run queue right away:
+        if (ctx->prev_state == TASK_RUNNING)
+            bpf_map_update_elem(&queued_since, &prev, &now, BPF_ANY);
+    }
+    if (is_tracked(next)) {
+        since = bpf_map_lookup_elem(&off_since, &next);
+        if (since) {
+            add(OFF_CPU_NS, now - *since);
+            bpf_map_delete_elem(&off_since, &next);
+        }
+        since = bpf_map_lookup_elem(&queued_since, &next);
+        if (since) {
+            add(RUN_QUEUE_NS, now - *since);
+            add(RUN_QUEUE_WAITS, 1);
+            bpf_map_delete_elem(&queued_since, &next);
+        }
+    }
+    return 0;
+}
+
+static __always_inline int on_wakeup(__u32 tid)
+{
+    __u64 now = bpf_ktime_get_ns();
+    if (is_tracked(tid))
+        bpf_map_update_elem(&queued_since, &tid, &now, BPF_ANY);
+    return 0;
+}
+
+SEC("tracepoint/sched/sched_wakeup")
+int on_sched_wakeup(struct sched_wakeup_args *ctx)
+{
+    return on_wakeup(ctx->pid);
+}
+
+SEC("tracepoint/sched/sched_wakeup_new")
+int on_sched_wakeup_new(struct sched_wakeup_args *ctx)
+{
+    return on_wakeup(ctx->pid);
+}
+
+SEC("tracepoint/sched/sched_process_fork")
+int on_fork(struct sched_process_fork_args *ctx)
+{
+    __u64 pid_tgid = bpf_get_current_pid_tgid();
+    __u32 tid = (__u32)pid_tgid;
+    __u32 tgid = pid_tgid >> 32;
+    __u32 key = 0;
+    __u32 child = ctx->child_pid;
+    __u8 yes = 1;
+    __u32 *from = bpf_map_lookup_elem(&launcher, &key);
+
+    if (is_tracked(tid) || (from && *from == tgid))
+        bpf_map_update_elem(&tracked, &child, &yes, BPF_ANY);
+    return 0;
+}
+
+SEC("tracepoint/sched/sched_process_exit")
+int on_process_exit(struct sched_process_exit_args *ctx)
+{
+    __u32 tid = ctx->pid;
+    bpf_map_delete_elem(&tracked, &tid);
+    bpf_map_delete_elem(&off_since, &tid);
+    bpf_map_delete_elem(&queued_since, &tid);
+    bpf_map_delete_elem(&syscall_since, &tid);
+    return 0;
+}
+
+SEC("tracepoint/raw_syscalls/sys_enter")
+int on_sys_enter(struct sys_enter_args *ctx)
+{
+    __u32 tid = (__u32)bpf_get_current_pid_tgid();
+    __u64 now = bpf_ktime_get_ns();
+    struct syscall_stats *stats;
+    __u32 nr;
+
+    if (!is_tracked(tid) || ctx->id < 0 || ctx->id >= MAX_SYSCALLS)
+        return 0;
+    nr = ctx->id;
+    stats = bpf_map_lookup_elem(&syscalls, &nr);
+    if (stats)
+        __sync_fetch_and_add(&stats->count, 1);
+    bpf_map_update_elem(&syscall_since, &tid, &now, BPF_ANY);
+    return 0;
+}
+
+SEC("tracepoint/raw_syscalls/sys_exit")
+int on_sys_exit(struct sys_exit_args *ctx)
+{
+    __u32 tid = (__u32)bpf_get_current_pid_tgid();
+    __u64 now = bpf_ktime_get_ns();
+    struct syscall_stats *stats;
+    __u64 *since;
+    __u32 nr;
+
+    since = bpf_map_lookup_elem(&syscall_since, &tid);
+    if (!since)
+        return 0;
+    if (ctx->id >= 0 && ctx->id < MAX_SYSCALLS) {
+        nr = ctx->id;
+        stats = bpf_map_lookup_elem(&syscalls, &nr);
+        if (stats)
+            __sync_fetch_and_add(&stats->ns, now - *since);
+    }
+    bpf_map_delete_elem(&syscall_since, &tid);
+    return 0;
+}
+
+char LICENSE[] SEC("license") = "GPL";
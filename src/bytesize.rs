@@ -0,0 +1,81 @@
+//! Sizes of memory and caches. The tools we read don't agree on what a `MB`
+//! is: numactl prints `MB` and `GB` but means MiB and GiB (it shifts the bytes
+//! by 20 and 30), depending on the version in either of them. So whoever
+//! parses a size says what the prefixes of the tool mean (`Prefixes`), `KiB`,
+//! `MiB`, ... are always binary. We print sizes in binary units (`31.4 GiB`)
+//! everywhere.
+use std::fmt;
+use std::str::FromStr;
+
+pub const KIB: u64 = 1024;
+pub const MIB: u64 = 1024 * KIB;
+pub const GIB: u64 = 1024 * MIB;
+pub const TIB: u64 = 1024 * GIB;
+
+/// What `k`, `M`, `G` and `T` (without an `i`) mean to a tool.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Prefixes {
+    /// Powers of 1000 (sizes the user gives us).
+    Decimal,
+    /// Powers of 1024 (numactl, /proc/meminfo).
+    Binary,
+}
+
+/// A size in bytes.
+#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+
+    /// The bytes of one `unit` (`MB`, `MiB`, `kB`, `B`, ...), None if we don't
+    /// know it.
+    fn unit(unit: &str, prefixes: Prefixes) -> Option<u64> {
+        let (binary, it) = match unit.strip_suffix("iB") {
+            Some(it) => (true, it),
+            None => (false, unit.strip_suffix('B')?),
+        };
+        let power = match it {
+            "" if !binary => 0,
+            "k" | "K" => 1,
+            "M" => 2,
+            "G" => 3,
+            "T" => 4,
+            _ => return None,
+        };
+        let base: u64 = if binary || prefixes == Prefixes::Binary {
+            1024
+        } else {
+            1000
+        };
+        Some(base.pow(power))
+    }
+
+    /// `32161 MB`, `1.5 GiB` or `512KiB`, in `default` units if there is no
+    /// unit.
+    pub fn parse(text: &str, prefixes: Prefixes, default: &str) -> Option<ByteSize> {
+        let text = text.trim();
+        let split = text
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(text.len());
+        let number = f64::from_str(&text[..split]).ok()?;
+        let unit = match text[split..].trim() {
+            "" => default,
+            unit => unit,
+        };
+        let bytes = ByteSize::unit(unit, prefixes)?;
+        Some(ByteSize((number * bytes as f64).round() as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = [(TIB, "TiB"), (GIB, "GiB"), (MIB, "MiB"), (KIB, "KiB")];
+        match units.iter().find(|&&(size, _)| self.0 >= size) {
+            Some(&(size, unit)) => write!(f, "{:.1} {}", self.0 as f64 / size as f64, unit),
+            None => write!(f, "{} B", self.0),
+        }
+    }
+}
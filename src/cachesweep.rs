@@ -0,0 +1,52 @@
+//! Working set sizes pegged to the caches of the machine, so a sweep over the
+//! input size of a program crosses the cache boundaries of the machine it runs
+//! on instead of the one its manifest was written for:
+//!
+//! * `half-l1`: half of the L1 data cache, fits in L1,
+//! * `2x-l2`: twice the L2, spills into the L3,
+//! * `half-l3-core`: half of the share of the L3 of a core (the L3 over the
+//!   cores that share it), fits in the L3 next to the other cores,
+//! * `4x-l3`: four times the L3, comes from memory.
+//!
+//! `datasets = "cache"` of a program in a `pair` manifest sweeps these sizes
+//! (in bytes) as `$DATASET`, `autoperf topology --cache-sweep` prints them as
+//! the `datasets` table of a manifest.
+use super::util::MachineTopology;
+
+/// The sizes are multiples of a cache line.
+const LINE: u64 = 64;
+
+/// (label, bytes) of the sweep for caches of `l1`, `l2` and `l3` bytes, of
+/// which every core has `l3_per_core` bytes of the L3.
+pub fn sweep(l1: u64, l2: u64, l3: u64, l3_per_core: u64) -> Vec<(String, u64)> {
+    let line = |bytes: u64| (bytes / LINE).max(1) * LINE;
+    vec![
+        (String::from("half-l1"), line(l1 / 2)),
+        (String::from("2x-l2"), line(2 * l2)),
+        (String::from("half-l3-core"), line(l3_per_core / 2)),
+        (String::from("4x-l3"), line(4 * l3)),
+    ]
+}
+
+/// The sweep for the caches of this machine.
+pub fn machine_sweep(mt: &MachineTopology) -> Result<Vec<(String, u64)>, String> {
+    let l3_per_core = mt
+        .sockets()
+        .first()
+        .and_then(|socket| mt.l3_share_per_core(*socket));
+    match (mt.l1_size(), mt.l2_size(), mt.l3_size(), l3_per_core) {
+        (Some(l1), Some(l2), Some(l3), Some(l3_per_core)) => Ok(sweep(l1, l2, l3, l3_per_core)),
+        _ => Err(String::from(
+            "the cache sizes of this machine are unknown (cpuid has no cache parameters)",
+        )),
+    }
+}
+
+/// `datasets = { half-l1 = "24576", ... }` for a program of a manifest.
+pub fn to_toml(sweep: &[(String, u64)]) -> String {
+    let datasets: Vec<String> = sweep
+        .iter()
+        .map(|&(ref label, bytes)| format!("{} = \"{}\"", label, bytes))
+        .collect();
+    format!("datasets = {{ {} }}", datasets.join(", "))
+}
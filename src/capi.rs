@@ -0,0 +1,278 @@
+//! A C API for topology queries (`cargo build --release --features capi`, the
+//! header is `include/autoperf.h`), so C and C++ benchmark harnesses can ask
+//! for e.g. the CPUs that share an L3 with a CPU but not its core, or the CPUs
+//! a `pair` deployment gives to A and B, instead of parsing lscpu themselves.
+//!
+//! Functions that return CPUs write up to `len` of them to `out` and return how
+//! many there are, so a call with `len` 0 tells how much space is needed.
+//! Nothing here panics across the boundary: constructors return NULL and the
+//! other functions 0 (or -1) if something goes wrong.
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use super::deployment::deployment;
+use super::util::{CpuInfo, MachineTopology};
+
+/// The topology of a machine (opaque).
+pub struct AutoperfTopology {
+    topology: MachineTopology,
+}
+
+/// A CPU and where it is on the machine.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AutoperfCpu {
+    pub cpu: u64,
+    pub core: u64,
+    pub socket: u64,
+    pub node: u64,
+    pub l1: u64,
+    pub l2: u64,
+    pub l3: u64,
+}
+
+/// What CPUs can share.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AutoperfLevel {
+    Cpu,
+    Core,
+    L1,
+    L2,
+    L3,
+    Socket,
+    Node,
+    Machine,
+}
+
+impl<'a> From<&'a CpuInfo> for AutoperfCpu {
+    fn from(info: &'a CpuInfo) -> AutoperfCpu {
+        AutoperfCpu {
+            cpu: info.cpu,
+            core: info.core,
+            socket: info.socket,
+            node: info.node.node,
+            l1: info.l1,
+            l2: info.l2,
+            l3: info.l3,
+        }
+    }
+}
+
+impl AutoperfLevel {
+    fn shared(&self, a: &CpuInfo, b: &CpuInfo) -> bool {
+        match *self {
+            AutoperfLevel::Cpu => a.cpu == b.cpu,
+            AutoperfLevel::Core => a.core == b.core,
+            AutoperfLevel::L1 => a.l1 == b.l1,
+            AutoperfLevel::L2 => a.l2 == b.l2,
+            AutoperfLevel::L3 => a.l3 == b.l3,
+            AutoperfLevel::Socket => a.socket == b.socket,
+            AutoperfLevel::Node => a.node.node == b.node.node,
+            AutoperfLevel::Machine => true,
+        }
+    }
+}
+
+fn boxed(topology: Option<MachineTopology>) -> *mut AutoperfTopology {
+    match topology {
+        Some(topology) => Box::into_raw(Box::new(AutoperfTopology { topology })),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        CStr::from_ptr(s).to_str().ok()
+    }
+}
+
+/// Copies `cpus` to `out` (as much as fits) and returns how many there are.
+unsafe fn fill(cpus: &[&CpuInfo], out: *mut AutoperfCpu, len: usize) -> usize {
+    if !out.is_null() {
+        let out = slice::from_raw_parts_mut(out, len);
+        for (slot, cpu) in out.iter_mut().zip(cpus.iter()) {
+            *slot = AutoperfCpu::from(*cpu);
+        }
+    }
+    cpus.len()
+}
+
+/// The topology of this machine (from lscpu and numactl, or sysctl on FreeBSD),
+/// NULL if we can't tell. Free it with `autoperf_topology_free`.
+#[no_mangle]
+pub extern "C" fn autoperf_topology_new() -> *mut AutoperfTopology {
+    boxed(catch_unwind(MachineTopology::new).ok())
+}
+
+/// The topology saved in a run directory (its `lscpu.csv` and `numactl.dat`),
+/// NULL if the files can't be read.
+///
+/// # Safety
+/// `lscpu` and `numactl` have to be NUL-terminated paths.
+#[no_mangle]
+pub unsafe extern "C" fn autoperf_topology_from_files(
+    lscpu: *const c_char,
+    numactl: *const c_char,
+) -> *mut AutoperfTopology {
+    let (lscpu, numactl) = match (to_str(lscpu), to_str(numactl)) {
+        (Some(lscpu), Some(numactl)) => (Path::new(lscpu), Path::new(numactl)),
+        _ => return ptr::null_mut(),
+    };
+    if !lscpu.exists() || !numactl.exists() {
+        return ptr::null_mut();
+    }
+    boxed(catch_unwind(|| MachineTopology::from_files(lscpu, numactl)).ok())
+}
+
+/// # Safety
+/// `topology` has to come from `autoperf_topology_new` or
+/// `autoperf_topology_from_files` (or be NULL) and can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn autoperf_topology_free(topology: *mut AutoperfTopology) {
+    if !topology.is_null() {
+        drop(Box::from_raw(topology));
+    }
+}
+
+/// All CPUs of the machine (by number).
+///
+/// # Safety
+/// `out` has to have room for `len` CPUs (or be NULL).
+#[no_mangle]
+pub unsafe extern "C" fn autoperf_topology_cpus(
+    topology: *const AutoperfTopology,
+    out: *mut AutoperfCpu,
+    len: usize,
+) -> usize {
+    let topology = match topology.as_ref() {
+        Some(t) => &t.topology,
+        None => return 0,
+    };
+    let cpus: Vec<&CpuInfo> = topology
+        .cpus()
+        .into_iter()
+        .filter_map(|c| topology.cpu(c))
+        .collect();
+    fill(&cpus, out, len)
+}
+
+/// The CPUs that share `shared` with `cpu` but not `not_shared`, e.g. L3 and
+/// Core for the CPUs on the same L3 but another core, Core and Cpu for the SMT
+/// siblings of `cpu`. With Cpu as `not_shared` only `cpu` itself is left out.
+///
+/// # Safety
+/// `out` has to have room for `len` CPUs (or be NULL).
+#[no_mangle]
+pub unsafe extern "C" fn autoperf_cpus_sharing(
+    topology: *const AutoperfTopology,
+    cpu: u64,
+    shared: AutoperfLevel,
+    not_shared: AutoperfLevel,
+    out: *mut AutoperfCpu,
+    len: usize,
+) -> usize {
+    let topology = match topology.as_ref() {
+        Some(t) => &t.topology,
+        None => return 0,
+    };
+    let this = match topology.cpu(cpu) {
+        Some(this) => this,
+        None => return 0,
+    };
+    let cpus: Vec<&CpuInfo> = topology
+        .cpus()
+        .into_iter()
+        .filter_map(|c| topology.cpu(c))
+        .filter(|c| shared.shared(this, c) && !not_shared.shared(this, c))
+        .collect();
+    fill(&cpus, out, len)
+}
+
+/// The bytes of the L2 a core has on average, 0 if the cache sizes are unknown.
+/// The sizes are those the run saved for `autoperf_topology_from_files` (next
+/// to `lscpu`), otherwise those of this machine.
+///
+/// # Safety
+/// `topology` has to be a topology that wasn't freed (or NULL).
+#[no_mangle]
+pub unsafe extern "C" fn autoperf_l2_share_per_core(topology: *const AutoperfTopology) -> u64 {
+    match topology.as_ref() {
+        Some(t) => catch_unwind(AssertUnwindSafe(|| t.topology.l2_share_per_core()))
+            .ok()
+            .and_then(|s| s)
+            .unwrap_or(0),
+        None => 0,
+    }
+}
+
+/// The bytes of its L3 a core of `socket` has on average, 0 if the cache sizes
+/// are unknown (or there is no such socket).
+///
+/// # Safety
+/// `topology` has to be a topology that wasn't freed (or NULL).
+#[no_mangle]
+pub unsafe extern "C" fn autoperf_l3_share_per_core(
+    topology: *const AutoperfTopology,
+    socket: u64,
+) -> u64 {
+    match topology.as_ref() {
+        Some(t) => catch_unwind(AssertUnwindSafe(|| t.topology.l3_share_per_core(socket)))
+            .ok()
+            .and_then(|s| s)
+            .unwrap_or(0),
+        None => 0,
+    }
+}
+
+/// The CPUs `pair` gives to A and B with deployment `name` (e.g. "L3-SMT", see
+/// the manual), only on `socket` if it's not negative. Writes up to `len` CPUs
+/// to `a` and `b`, their numbers to `a_count` and `b_count` and returns 0, -1 if
+/// there is no such deployment (or no CPUs for it).
+///
+/// # Safety
+/// `name` has to be NUL-terminated, `a` and `b` have to have room for `len`
+/// CPUs (or be NULL), `a_count` and `b_count` have to be valid (or NULL).
+#[no_mangle]
+pub unsafe extern "C" fn autoperf_deployment(
+    topology: *const AutoperfTopology,
+    name: *const c_char,
+    socket: i64,
+    a: *mut AutoperfCpu,
+    b: *mut AutoperfCpu,
+    len: usize,
+    a_count: *mut usize,
+    b_count: *mut usize,
+) -> c_int {
+    let (topology, name) = match (topology.as_ref(), to_str(name)) {
+        (Some(t), Some(name)) => (&t.topology, name),
+        _ => return -1,
+    };
+    let socket = if socket >= 0 {
+        Some(socket as u64)
+    } else {
+        None
+    };
+    let planned = catch_unwind(AssertUnwindSafe(|| {
+        deployment(name, topology, socket).map(|d| (d.a, d.b))
+    }));
+    match planned {
+        Ok(Some((cpus_a, cpus_b))) => {
+            let (na, nb) = (fill(&cpus_a, a, len), fill(&cpus_b, b, len));
+            if let Some(count) = a_count.as_mut() {
+                *count = na;
+            }
+            if let Some(count) = b_count.as_mut() {
+                *count = nb;
+            }
+            0
+        }
+        _ => -1,
+    }
+}
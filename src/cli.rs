@@ -0,0 +1,701 @@
+//! The command line of autoperf: every sub-command and its arguments.
+//!
+//! Options that take event names (`--events`, `--metric`) complete them from
+//! the event catalog (see `completions.rs`).
+use clap::{Args, Parser, Subcommand};
+use clap_complete::ArgValueCompleter;
+
+use super::completions::{complete_events, Shell};
+use super::logging::LogFormat;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "autoperf",
+    version,
+    author = "Gerd Zellweger <mail@gerdzellweger.com>",
+    about = "Use performance counters with little domain knowledge."
+)]
+pub struct Cli {
+    /// Do more verbose logging (-v = info, -vv = debug, -vvv = trace, default is warn).
+    #[arg(short, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Log as text or JSON (with the campaign, configuration and run of every message).
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    pub log_format: LogFormat,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// Watching and streaming a measurement while it runs.
+#[derive(Debug, Args)]
+pub struct LiveArgs {
+    /// Show a dashboard with IPC, event rates and progress in the terminal while measuring.
+    #[arg(short, long, help_heading = "Live monitoring")]
+    pub watch: bool,
+    /// Serve live counter rates and progress as Prometheus metrics on this address (e.g. 0.0.0.0:9100).
+    #[arg(long, value_name = "ADDRESS", help_heading = "Live monitoring")]
+    pub prometheus: Option<String>,
+    /// Stream the counter values of every interval as InfluxDB line protocol to this file or http:// URL.
+    #[arg(long, value_name = "FILE_OR_URL", help_heading = "Live monitoring")]
+    pub influx: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ProfileArgs {
+    /// Don't run anything, just print what we would do.
+    #[arg(short, long)]
+    pub dryrun: bool,
+    /// Set output directory (defaults to `output` of the configuration, or out).
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output: Option<String>,
+    /// Only start counting after the program ran for this many seconds.
+    #[arg(long, value_name = "SECONDS")]
+    pub measure_after: Option<String>,
+    /// Stop measuring (and the program) after counting for this many seconds.
+    #[arg(long, value_name = "SECONDS")]
+    pub measure_for: Option<String>,
+    /// Only start counting once this metric is steady: ipc, or the rate the program writes to $AUTOPERF_RATE (see the manual).
+    #[arg(long, value_name = "METRIC", value_parser = ["ipc", "rate"], conflicts_with = "measure_after")]
+    pub steady_state: Option<String>,
+    /// How far (in percent of the mean) the metric may vary and still be steady.
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 5.0,
+        requires = "steady_state"
+    )]
+    pub steady_tolerance: f64,
+    /// How long the metric has to stay within the tolerance.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "10",
+        requires = "steady_state"
+    )]
+    pub steady_for: String,
+    /// Start counting after this many seconds even if the metric didn't settle.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "300",
+        requires = "steady_state"
+    )]
+    pub steady_timeout: String,
+    /// Sample the events with perf record (<nr>_perf.data) instead of counting them with perf stat.
+    #[arg(long)]
+    pub record: bool,
+    /// With --record, set the period of every event so it's sampled about this often per second (from a short counting run first).
+    #[arg(long, value_name = "SAMPLES", requires = "record")]
+    pub samples_per_second: Option<u64>,
+    /// Run perf and the program with this scheduling class: nice:<n>, fifo:<p>, rr:<p> or idle.
+    #[arg(long, value_name = "PRIORITY")]
+    pub priority: Option<String>,
+    /// Save at most this much of the program's stdout and stderr per measurement (e.g. 10MiB).
+    #[arg(long, value_name = "SIZE")]
+    pub max_output: Option<String>,
+    /// Start every line of the saved stdout and stderr with the seconds since the program started.
+    #[arg(long)]
+    pub timestamp_output: bool,
+    /// Take the figure of merit from the first group of this regex in the program's output (e.g. 'Throughput: ([0-9.]+)').
+    #[arg(long, value_name = "REGEX")]
+    pub figure_of_merit: Option<String>,
+    /// Take the figure of merit from this JSON pointer in the JSON lines of the program's output (e.g. '/results/ops_per_sec').
+    #[arg(long, value_name = "POINTER", conflicts_with = "figure_of_merit")]
+    pub figure_of_merit_json: Option<String>,
+    /// Read the figure of merit from this file the program writes (relative to its working directory) instead of its output.
+    #[arg(long, value_name = "FILE")]
+    pub figure_of_merit_file: Option<String>,
+    /// The event name of the figure of merit in the results.
+    #[arg(long, value_name = "NAME", default_value = "figure_of_merit")]
+    pub figure_of_merit_name: String,
+    /// Label measurements with the phase names the program writes to $AUTOPERF_PHASE_MARKER.
+    #[arg(long)]
+    pub phase_markers: bool,
+    /// Report counter values per thread of the program instead of per CPU (same as --scope thread).
+    #[arg(long, conflicts_with = "scope")]
+    pub per_thread: bool,
+    /// Granularity at which counter values are reported.
+    #[arg(long, default_value = "cpu", value_parser = ["cpu", "core", "socket", "node", "thread"])]
+    pub scope: String,
+    /// Record CPU frequencies, C-state residencies and temperatures with turbostat while measuring.
+    #[arg(long)]
+    pub turbostat: bool,
+    /// Record memory bandwidth, UPI utilization and C-state residencies of every socket with Intel PCM while measuring.
+    #[arg(long)]
+    pub pcm: bool,
+    /// Record off-CPU time, run-queue latency and system calls of the program with eBPF (needs the bpf feature).
+    #[arg(long)]
+    pub bpf: bool,
+    /// Pack the output directory into a zstd-compressed archive (<output>.tar.zst) when done.
+    #[arg(long)]
+    pub compress: bool,
+    /// Compress the counter values of every interval into chunks (<nr>_stat.csv.zst) while perf writes them.
+    #[arg(long)]
+    pub compress_intervals: bool,
+    /// Measure with perf (default on Linux), likwid-perfctr, PAPI (needs the papi feature), pmcstat (default on FreeBSD) or VTune.
+    #[arg(
+        long,
+        help_heading = "Backends",
+        value_parser = ["perf", "likwid", "papi", "pmcstat", "vtune"]
+    )]
+    pub backend: Option<String>,
+    /// The likwid performance groups to measure, comma separated (default MEM,L3,FLOPS_DP).
+    #[arg(long, value_name = "GROUPS", help_heading = "Backends")]
+    pub groups: Option<String>,
+    /// Only measure the regions the program marks with likwid's marker API.
+    #[arg(long, help_heading = "Backends")]
+    pub likwid_markers: bool,
+    /// The analysis VTune collects (default uarch-exploration).
+    #[arg(long, value_name = "ANALYSIS", help_heading = "Backends")]
+    pub vtune_analysis: Option<String>,
+    /// Keep autoperf's own work on this CPU (see the manual).
+    #[arg(long, value_name = "CPU")]
+    pub housekeeping_cpu: Option<u64>,
+    /// What to do if the output directory isn't empty: stop (error), continue its results (resume) or write to <directory>-<timestamp> (suffix).
+    #[arg(long, value_name = "POLICY", value_parser = ["error", "resume", "suffix"])]
+    pub on_existing: Option<String>,
+    /// Measure the events that pass the pre-flight check instead of stopping if one doesn't.
+    #[arg(long)]
+    pub skip_invalid: bool,
+    /// Measure the events that errata of this CPU make useless as well (see the manual).
+    #[arg(long)]
+    pub allow_errata: bool,
+    /// Only count the LLC lookups these CBo/CHA filters let through, e.g. state=MES,tid=0x3,opc=DRD (see the manual).
+    #[arg(long, value_name = "FILTERS")]
+    pub llc_filter: Option<String>,
+    /// Capture the machine state with these collectors, comma separated (e.g., lstopo,mitigations, see the manual).
+    #[arg(long, value_name = "COLLECTORS")]
+    pub collectors: Option<String>,
+    /// Also measure perf's metric groups, comma separated (e.g., Pipeline,Memory_BW, see `perf list metricgroups`).
+    #[arg(long, value_name = "GROUPS")]
+    pub metric_groups: Option<String>,
+    /// Only measure the events of a preset (see the manual).
+    #[arg(long, value_name = "PRESET", value_parser = ["avx-license", "tsx", "memory", "frontend", "branch", "io"])]
+    pub preset: Option<String>,
+    /// Bind the memory of the program to the NUMA nodes of a memory tier.
+    #[arg(long, value_name = "TIER", value_parser = ["dram", "pmem", "cxl"])]
+    pub memory_tier: Option<String>,
+    #[command(flatten)]
+    pub live: LiveArgs,
+    /// Command to execute.
+    #[arg(
+        value_name = "COMMAND",
+        required = true,
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct PairArgs {
+    /// Don't run anything, just print what we would do.
+    #[arg(short, long)]
+    pub dryrun: bool,
+    /// Skip the first x configuration (used to evaluate the same manifest simultaneously on multiple identical machines).
+    #[arg(short = 'b', long = "base", default_value_t = 0)]
+    pub start: usize,
+    /// Only evaluate every x-th configuration (used to evaluate the same manifest simultaneously on multiple identical machines).
+    #[arg(short, long, default_value_t = 1)]
+    pub step: usize,
+    /// Measure with perf (default on Linux), PAPI (needs the papi feature) or pmcstat (default on FreeBSD).
+    #[arg(long, value_parser = ["perf", "papi", "pmcstat"])]
+    pub backend: Option<String>,
+    /// Keep autoperf's own work on this CPU (see the manual).
+    #[arg(long, value_name = "CPU")]
+    pub housekeeping_cpu: Option<u64>,
+    /// What to do if the output directory isn't empty: stop (error), continue its results (resume) or write to <directory>-<timestamp> (suffix).
+    #[arg(long, value_name = "POLICY", value_parser = ["error", "resume", "suffix"])]
+    pub on_existing: Option<String>,
+    /// Measure the events that pass the pre-flight check instead of stopping if one doesn't.
+    #[arg(long)]
+    pub skip_invalid: bool,
+    /// Measure the events that errata of this CPU make useless as well (see the manual).
+    #[arg(long)]
+    pub allow_errata: bool,
+    /// Only count the LLC lookups these CBo/CHA filters let through, e.g. state=MES,tid=0x3,opc=DRD (see the manual).
+    #[arg(long, value_name = "FILTERS")]
+    pub llc_filter: Option<String>,
+    /// Write the campaign to <DIRECTORY>/<experiment name>/<hostname> instead of next to the manifest.
+    #[arg(long, value_name = "DIRECTORY")]
+    pub output_root: Option<String>,
+    /// Plan the campaign for another machine: a directory with its lscpu.csv and numactl.dat, or synthetic:<sockets>x<cores>x<smt>[x<nodes>].
+    #[arg(long, value_name = "DIRECTORY_OR_SHAPE", requires = "dryrun")]
+    pub topology: Option<String>,
+    #[command(flatten)]
+    pub live: LiveArgs,
+    /// Path of manifest directory.
+    pub directory: String,
+}
+
+#[derive(Debug, Args)]
+pub struct AggregateArgs {
+    /// Which core events to include (exclusive = only cores where program was running on, none = drop core events).
+    #[arg(short, long, default_value = "all", value_parser = ["all", "shared", "exclusive", "none"])]
+    pub core: String,
+    /// Which uncore events to include (exclusive = only uncore units that are exclusively used by program, none = drop uncore events).
+    #[arg(short, long, default_value = "all", value_parser = ["all", "shared", "exclusive", "none"])]
+    pub uncore: String,
+    /// Output format of the results.
+    #[arg(short, long, default_value = "csv", value_parser = ["csv", "parquet"])]
+    pub format: String,
+    /// One row per counter value (long) or one column per event (wide).
+    #[arg(short, long, default_value = "long", value_parser = ["long", "wide"])]
+    pub layout: String,
+    /// Only include these events: comma-separated globs (e.g., 'UNC_M_*,cycles') or a regex prefixed with 're:'.
+    #[arg(short, long, value_name = "PATTERN", add = ArgValueCompleter::new(complete_events))]
+    pub events: Option<String>,
+    /// Only include these columns (comma-separated, e.g., 'RUN,EVENT_NAME,TIME,SAMPLE_VALUE').
+    #[arg(long, value_name = "COLUMNS")]
+    pub columns: Option<String>,
+    /// Normalize values per second, per 1000 instructions (pki) or per 1000 cycles (pkc).
+    #[arg(short, long, value_parser = ["per-second", "pki", "pkc"])]
+    pub normalize: Option<String>,
+    /// How many files to parse in parallel (defaults to the number of CPUs).
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<usize>,
+    /// Only add the runs of a campaign that are not in the output file yet.
+    #[arg(short, long)]
+    pub incremental: bool,
+    /// Check the counter values of every run against the rules in this file (see the manual).
+    #[arg(short, long, value_name = "FILE")]
+    pub rules: Option<String>,
+    /// How often to resample for the confidence intervals of the means (0 = no intervals).
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    pub bootstrap: usize,
+    /// Set output file (defaults to <input dir>/results.csv or results.parquet).
+    #[arg(short, long, value_name = "FILENAME")]
+    pub output: Option<String>,
+    /// Set input directory (or a .tar.zst archive of it).
+    pub directory: String,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// Significance level for the Mann-Whitney U test.
+    #[arg(long, value_name = "P", default_value_t = 0.05)]
+    pub alpha: f64,
+    /// Also print the differences that are not significant.
+    #[arg(long)]
+    pub all: bool,
+    /// How often to resample for the confidence intervals of the differences (0 = no intervals).
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    pub bootstrap: usize,
+    /// Save the table in a file instead of printing it.
+    #[arg(short, long, value_name = "FILENAME")]
+    pub output: Option<String>,
+    /// The first result directory.
+    pub a: String,
+    /// The second result directory.
+    pub b: String,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffConfigArgs {
+    /// Save the table in a file instead of printing it.
+    #[arg(short, long, value_name = "FILENAME")]
+    pub output: Option<String>,
+    /// The first campaign (or profile) directory.
+    pub a: String,
+    /// The second campaign (or profile) directory.
+    pub b: String,
+}
+
+#[derive(Debug, Args)]
+pub struct AlignArgs {
+    /// Seconds between the points of the common time grid.
+    #[arg(long, value_name = "SECONDS", default_value_t = 0.25)]
+    pub step: f64,
+    /// How values between two samples are resampled.
+    #[arg(long, default_value = "linear", value_parser = ["linear", "last"])]
+    pub method: String,
+    /// Only align the series (events, resctrl values) matching this pattern.
+    #[arg(short, long, value_name = "PATTERN")]
+    pub events: Option<String>,
+    /// Save the series in a file instead of printing them.
+    #[arg(short, long, value_name = "FILENAME")]
+    pub output: Option<String>,
+    /// A `pair` run directory (A and B), or the first of two runs measured at the same time.
+    pub a: String,
+    /// The second run measured at the same time.
+    pub b: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct InterferenceArgs {
+    /// Seconds between the points the slowdown and the signals are resampled on.
+    #[arg(long, value_name = "SECONDS", default_value_t = 1.0)]
+    pub step: f64,
+    /// How far the signals may lead or lag the slowdown (in seconds).
+    #[arg(long, value_name = "SECONDS", default_value_t = 5.0)]
+    pub max_lag: f64,
+    /// The series of B to correlate with (defaults to its resctrl bandwidth and LLC occupancy).
+    #[arg(short, long, value_name = "PATTERN")]
+    pub signals: Option<String>,
+    /// Where to save the tables (defaults to the campaign directory).
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output: Option<String>,
+    /// The `pair` campaign directory.
+    pub campaign: String,
+}
+
+#[derive(Debug, Args)]
+pub struct SensitivityArgs {
+    /// Save the scores in a file instead of printing them.
+    #[arg(short, long, value_name = "FILENAME")]
+    pub output: Option<String>,
+    /// The `pair` campaign directory.
+    pub campaign: String,
+}
+
+#[derive(Debug, Args)]
+pub struct RecommendArgs {
+    /// How many cores the workloads may use.
+    #[arg(long, value_name = "N")]
+    pub cores: usize,
+    /// The workloads to place (comma-separated, defaults to all programs measured alone).
+    #[arg(short, long, value_name = "NAMES")]
+    pub workloads: Option<String>,
+    /// Try all pairings (up to 12 workloads) or pair the cheapest first.
+    #[arg(long, default_value = "exact", value_parser = ["exact", "greedy"])]
+    pub method: String,
+    /// Only plan for this placement (a configuration of the experiment).
+    #[arg(long, value_name = "NAME")]
+    pub placement: Option<String>,
+    /// Save the plans in a file instead of printing them.
+    #[arg(short, long, value_name = "FILENAME")]
+    pub output: Option<String>,
+    /// The `pair` campaign directory.
+    pub campaign: String,
+}
+
+#[derive(Debug, Args)]
+pub struct FeaturesArgs {
+    /// Share of the configurations (runs) in the test set.
+    #[arg(short, long, value_name = "FRACTION", default_value_t = 0.2)]
+    pub test: f64,
+    /// Pick different configurations for the test set.
+    #[arg(short, long, value_name = "N", default_value_t = 0)]
+    pub seed: u64,
+    /// Where to save the matrices (defaults to the directory of the results).
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output: Option<String>,
+    /// The aggregated results (long CSV layout).
+    pub results: String,
+}
+
+#[derive(Debug, Args)]
+pub struct VtuneArgs {
+    /// Where to save the files (defaults to <directory of the results>/vtune).
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output: Option<String>,
+    /// The host the results are from (defaults to this machine).
+    #[arg(long)]
+    pub hostname: Option<String>,
+    /// The aggregated results (long CSV layout).
+    pub results: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ReportArgs {
+    /// Only show these events (comma-separated globs, or a regex prefixed with 're:').
+    #[arg(short, long, value_name = "PATTERN", add = ArgValueCompleter::new(complete_events))]
+    pub events: Option<String>,
+    /// How often to resample for the confidence intervals of the means (0 = no intervals).
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    pub bootstrap: usize,
+    /// Set output file (defaults to <input dir>/report.html).
+    #[arg(short, long, value_name = "FILENAME")]
+    pub output: Option<String>,
+    /// The profile or campaign (pair output) directory.
+    pub directory: String,
+}
+
+#[derive(Debug, Args)]
+pub struct PlotArgs {
+    /// ipc, cpi, an event or an expression of events such as 'L1-dcache-load-misses / instructions'.
+    #[arg(
+        short,
+        long,
+        value_name = "METRIC",
+        default_value = "ipc",
+        add = ArgValueCompleter::new(complete_events)
+    )]
+    pub metric: String,
+    /// Comma-separated list of plots (timeseries, bar, box).
+    #[arg(
+        short,
+        long,
+        value_name = "PLOTS",
+        default_value = "timeseries,bar,box"
+    )]
+    pub plots: String,
+    /// Image format.
+    #[arg(short, long, default_value = "svg", value_parser = ["svg", "png"])]
+    pub format: String,
+    /// Only plot these runs (comma-separated globs, or a regex prefixed with 're:').
+    #[arg(short, long, value_name = "PATTERN")]
+    pub runs: Option<String>,
+    /// Where to save the plots (defaults to the directory of the results).
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output: Option<String>,
+    /// The aggregated results (long CSV layout).
+    pub results: String,
+}
+
+#[derive(Debug, Args)]
+pub struct QueryArgs {
+    /// Only this event (SQL LIKE pattern, e.g. 'UNC_%').
+    #[arg(short, long)]
+    pub event: Option<String>,
+    /// Only runs where this program is A.
+    #[arg(short, long)]
+    pub a: Option<String>,
+    /// Only runs where this program is B.
+    #[arg(short, long)]
+    pub b: Option<String>,
+    /// Only runs with this deployment (e.g. L3-SMT).
+    #[arg(short, long)]
+    pub deployment: Option<String>,
+    /// Only runs with this memory policy (e.g. interleave:0,1).
+    #[arg(long)]
+    pub memory_policy: Option<String>,
+    /// Only runs with all CPUs fixed to this frequency (in MHz).
+    #[arg(long, value_name = "MHZ")]
+    pub frequency: Option<u64>,
+    /// Only runs where A has this priority (e.g. fifo:20).
+    #[arg(long)]
+    pub priority_a: Option<String>,
+    /// Only runs where B has this priority (e.g. idle).
+    #[arg(long)]
+    pub priority_b: Option<String>,
+    /// Only samples measured during this phase.
+    #[arg(short, long)]
+    pub phase: Option<String>,
+    /// Sum up the values of every event per run.
+    #[arg(short, long)]
+    pub total: bool,
+    /// The result store (results.sqlite) to query.
+    pub database: String,
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// Don't run anything, just print what we would do.
+    #[arg(short, long)]
+    pub dryrun: bool,
+    /// Set output directory (defaults to `output` of the configuration, or out).
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output: Option<String>,
+    /// Only these benchmarks (comma-separated globs, or a regex prefixed with 're:', matched against <target>/<name> and <name>).
+    #[arg(short, long, value_name = "PATTERN")]
+    pub benchmarks: Option<String>,
+    /// Run the benchmarks on this CPU (defaults to the last CPU that isn't the housekeeping CPU).
+    #[arg(long, value_name = "CPU")]
+    pub cpu: Option<u64>,
+    /// How long Criterion warms up (in seconds), the counters start after it.
+    #[arg(long, value_name = "SECONDS", default_value = "3")]
+    pub warm_up_time: u64,
+    /// Keep autoperf's own work on this CPU (see the manual).
+    #[arg(long, value_name = "CPU")]
+    pub housekeeping_cpu: Option<u64>,
+    /// What to do if the output directory isn't empty: stop (error), continue its results (resume) or write to <directory>-<timestamp> (suffix).
+    #[arg(long, value_name = "POLICY", value_parser = ["error", "resume", "suffix"])]
+    pub on_existing: Option<String>,
+    /// The Rust project (the directory with its Cargo.toml).
+    #[arg(default_value = ".")]
+    pub project: String,
+}
+
+#[derive(Debug, Args)]
+pub struct SpecArgs {
+    /// Only these benchmarks (comma-separated globs, or a regex prefixed with 're:', e.g. '5*_r').
+    #[arg(short, long, value_name = "PATTERN")]
+    pub benchmarks: Option<String>,
+    /// The tuning SPEC set up the run directories for (runcpu --tune).
+    #[arg(long, default_value = "base", value_parser = ["base", "peak"])]
+    pub tune: String,
+    /// The workload size SPEC set up the run directories for (runcpu --size).
+    #[arg(long, default_value = "refrate")]
+    pub size: String,
+    /// Comma-separated list of deployments for the campaign.
+    #[arg(short, long, value_name = "DEPLOYMENTS", default_value = "L3-SMT")]
+    pub configurations: String,
+    /// Where to save manifest.toml (defaults to the current directory).
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output: Option<String>,
+    /// The SPEC CPU 2017 installation (the directory $SPEC points to).
+    pub spec: String,
+}
+
+#[derive(Debug, Args)]
+pub struct SchemaArgs {
+    /// Save <artifact>.schema.json for every artifact in this directory.
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output: Option<String>,
+    /// machine (machine.json), clock (clock.toml), workload (workload.toml), perf-log (perf.csv), results (aggregated results) or validation (validation.json).
+    #[arg(
+        required_unless_present = "output",
+        value_parser = ["machine", "clock", "workload", "perf-log", "results", "validation"]
+    )]
+    pub artifact: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// Also list the files that were added after the hashes were saved.
+    #[arg(long)]
+    pub unlisted: bool,
+    /// The output directory (or its archive) to check.
+    pub directory: String,
+}
+
+#[derive(Debug, Args)]
+pub struct CompletionsArgs {
+    /// The shell to complete autoperf in.
+    pub shell: Shell,
+}
+
+#[derive(Debug, Args)]
+pub struct SearchArgs {
+    /// Print the events whose name, description or unit matches this regex (case-insensitive), e.g. 'l3.*miss'. Without it, look for undocumented events.
+    pub pattern: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct TopologyArgs {
+    #[command(subcommand)]
+    pub action: Option<TopologyAction>,
+    /// Draw it as text (ascii), as a Graphviz graph (dot) or capture it for `topology diff` (json).
+    #[arg(long, default_value = "ascii", value_parser = ["ascii", "dot", "json"])]
+    pub render: String,
+    /// Draw another machine: a directory with its lscpu.csv and numactl.dat, or synthetic:<sockets>x<cores>x<smt>[x<nodes>].
+    #[arg(long, value_name = "DIRECTORY_OR_SHAPE")]
+    pub topology: Option<String>,
+    /// Print working set sizes pegged to the caches of this machine as the `datasets` of a `pair` manifest instead.
+    #[arg(long, conflicts_with = "topology")]
+    pub cache_sweep: bool,
+    /// Print the CBo/CHA PMUs next to every CPU of this machine instead.
+    #[arg(long, conflicts_with_all = ["topology", "cache_sweep"])]
+    pub uncore_boxes: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TopologyAction {
+    /// Print what differs between two topologies (--render json captures, or result directories).
+    Diff {
+        /// The topology before.
+        a: String,
+        /// The topology after.
+        b: String,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub action: ConfigAction,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigAction {
+    /// Print the configuration (from the configuration file, AUTOPERF_* variables and defaults).
+    Show,
+}
+
+#[derive(Debug, Args)]
+pub struct ScaleArgs {
+    /// Don't run anything, just print what we would do.
+    #[arg(short, long)]
+    pub dryrun: bool,
+    /// Set output directory (defaults to `output` of the configuration, or out).
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output: Option<String>,
+    /// The thread counts to run, comma separated (defaults to 1, the cores of an L3, of a socket and all cores).
+    #[arg(short, long, value_name = "COUNTS")]
+    pub threads: Option<String>,
+    /// The environment variable that tells the program how many threads to use ($NUM_THREADS in the command works too).
+    #[arg(long, value_name = "NAME", default_value = "OMP_NUM_THREADS")]
+    pub threads_env: String,
+    /// Keep autoperf's own work on this CPU (see the manual).
+    #[arg(long, value_name = "CPU")]
+    pub housekeeping_cpu: Option<u64>,
+    /// What to do if the output directory isn't empty: stop (error), continue its results (resume) or write to <directory>-<timestamp> (suffix).
+    #[arg(long, value_name = "POLICY", value_parser = ["error", "resume", "suffix"])]
+    pub on_existing: Option<String>,
+    /// Command to execute.
+    #[arg(
+        value_name = "COMMAND",
+        required = true,
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    pub command: Vec<String>,
+}
+
+// We don't support mkgroup at the moment, it's hidden.
+
+#[derive(Debug, Args)]
+pub struct MkgroupArgs {
+    /// Input file (weka ranking).
+    #[arg(short = 'i', long = "input", value_name = "FILE")]
+    pub file: String,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Measure all available H/W events for a command using `perf stat`.
+    Profile(Box<ProfileArgs>),
+    /// Run a set of programs pairwise together on the machine (while measuring all counters).
+    Pair(PairArgs),
+    /// Run a program with an increasing number of threads (pinned to the topology) and measure its scaling efficiency.
+    Scale(ScaleArgs),
+    /// Measure the benchmarks of a Rust project (cargo bench, Criterion) and join the counters with their timing.
+    Bench(BenchArgs),
+    /// Merge counter measurements generated with the `profile` or `pair` command in a single CSV file.
+    Aggregate(AggregateArgs),
+    /// Dump statistics about performance events on Intel architectures.
+    Stats {
+        /// Where to store the generated output (CSV) files.
+        directory: String,
+    },
+    /// Compare the results of two profiles or campaigns and print the significant differences as CSV.
+    Diff(DiffArgs),
+    /// Compare the manifests, machines and run setups (placements, events) of two campaigns and print what differs as CSV.
+    DiffConfig(DiffConfigArgs),
+    /// Resample the time series of co-located programs (counters, resctrl) on a common time grid and print them as CSV.
+    Align(AlignArgs),
+    /// Compare the co-located runs of a `pair` campaign with the runs alone and rank which signals of B explain the slowdown of A.
+    Interference(InterferenceArgs),
+    /// Score how sensitive every program of a `pair` campaign is to its neighbours and how contentious it is, per sharing domain.
+    Sensitivity(SensitivityArgs),
+    /// Recommend which workloads should share cores to fit a core budget, from the slowdowns a `pair` campaign measured.
+    Recommend(RecommendArgs),
+    /// Turn aggregated results into z-scored feature matrices (one row per interval, one column per event) for machine learning.
+    Features(FeaturesArgs),
+    /// Export aggregated results as VTune external data (one CSV file per run) to import them into VTune.
+    Vtune(VtuneArgs),
+    /// Write a self-contained HTML report of a profile or a pair campaign.
+    Report(ReportArgs),
+    /// Plot a metric of aggregated results (over time, per run and its distribution per run).
+    Plot(PlotArgs),
+    /// Select results from the SQLite result store of a `pair` campaign and print them as CSV.
+    Query(QueryArgs),
+    /// Search the events of this CPU (name, description and unit), or find events not covered in Intel's manuals.
+    Search(SearchArgs),
+    /// Write a `pair` manifest with every benchmark (and input) of a SPEC CPU 2017 installation.
+    Spec(SpecArgs),
+    /// Print the JSON Schema of a file autoperf writes, or save the schemas of all of them.
+    Schema(SchemaArgs),
+    /// Check the files of an output directory against the SHA-256 hashes saved when it was complete.
+    Verify(VerifyArgs),
+    /// Draw the sockets, L3s, cores, SMT threads and NUMA nodes of the machine.
+    Topology(TopologyArgs),
+    /// Show the defaults autoperf uses for options that aren't given.
+    Config(ConfigArgs),
+    /// Print the completion script of a shell (source it, e.g. `source <(autoperf completions bash)`).
+    Completions(CompletionsArgs),
+    /// Given a machine, form the largest possible group of events from a given ranking input file.
+    #[command(hide = true)]
+    Mkgroup(MkgroupArgs),
+}
@@ -0,0 +1,462 @@
+//! The captures of the machine state we save next to the results of a run (see
+//! `profile::save_machine_information`). A `Collector` says what it needs (the
+//! programs it runs), how it captures (`run`), where that goes in the run
+//! directory and what the capture tells us (`parse`): the facts of every capture
+//! end up in `machine_facts.csv` (`collector,fact,value`), and a capture the
+//! parser can't make sense of counts as failed.
+//!
+//! `lscpu` and `numactl` are required, we can't make sense of the results
+//! without them. The others are optional: `lstopo`, `cpuid` and `likwid` run
+//! unless a campaign selects its collectors (`--collectors` of `profile`,
+//! `collectors` in the `[experiment]` of a `pair` manifest), `dmidecode`,
+//! `mitigations`, `irqbalance` and `turbostat` only if it selects them. An
+//! optional collector whose programs aren't installed, or that fails, leaves a
+//! warning in `summary.json` and the run goes on without it.
+//!
+//! A new capture is a `Collector` in `COLLECTORS`; one that runs a program and
+//! saves what it prints is a `Tool`.
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+
+use tracing::{debug, error};
+
+use super::summary::{self, Failure};
+use super::sysctl;
+
+/// The facts the parsers found.
+pub const FACT_FILE: &str = "machine_facts.csv";
+
+/// Facts of a capture: (fact, value).
+pub type Facts = Vec<(String, String)>;
+
+/// Reads the facts of a capture (see `Collector::parse`).
+type Parser = fn(&str) -> Result<Facts, String>;
+
+/// A capture of the machine state.
+pub trait Collector: Sync {
+    /// What a campaign selects it by.
+    fn name(&self) -> &'static str;
+    /// The programs it runs (they have to be in the PATH).
+    fn binaries(&self) -> &'static [&'static str];
+    /// Where the capture goes in the run directory.
+    fn file(&self) -> &'static str;
+    /// Captures the state of the machine.
+    fn run(&self) -> io::Result<String>;
+    /// The facts of a capture, or why it doesn't make sense.
+    fn parse(&self, _capture: &str) -> Result<Facts, String> {
+        Ok(Vec::new())
+    }
+    /// Do we stop if it fails?
+    fn required(&self) -> bool {
+        false
+    }
+    /// Does it run if a campaign doesn't select its collectors?
+    fn by_default(&self) -> bool {
+        true
+    }
+}
+
+/// A collector that saves what a program prints.
+pub struct Tool {
+    name: &'static str,
+    binaries: &'static [&'static str],
+    args: &'static [&'static str],
+    file: &'static str,
+    required: bool,
+    by_default: bool,
+    /// What we capture on FreeBSD instead (from sysctl).
+    freebsd: Option<fn() -> String>,
+    parser: Option<Parser>,
+}
+
+impl Tool {
+    fn freebsd(&self) -> Option<fn() -> String> {
+        self.freebsd.filter(|_| cfg!(target_os = "freebsd"))
+    }
+}
+
+impl Collector for Tool {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn binaries(&self) -> &'static [&'static str] {
+        match self.freebsd() {
+            Some(_) => &[],
+            None => self.binaries,
+        }
+    }
+
+    fn file(&self) -> &'static str {
+        self.file
+    }
+
+    fn run(&self) -> io::Result<String> {
+        if let Some(capture) = self.freebsd() {
+            return Ok(capture());
+        }
+        let out = Command::new(self.binaries[0]).args(self.args).output()?;
+        if out.status.success() {
+            Ok(String::from_utf8(out.stdout).unwrap_or_default())
+        } else {
+            debug!(
+                "stderr:\n{}",
+                String::from_utf8(out.stderr).unwrap_or("Can't parse output".to_string())
+            );
+            Err(io::Error::other(format!(
+                "{} exited with {}",
+                self.binaries[0], out.status
+            )))
+        }
+    }
+
+    fn parse(&self, capture: &str) -> Result<Facts, String> {
+        self.parser.map_or(Ok(Vec::new()), |parser| parser(capture))
+    }
+
+    fn required(&self) -> bool {
+        self.required
+    }
+
+    fn by_default(&self) -> bool {
+        self.by_default
+    }
+}
+
+/// The number of CPUs in `lscpu --parse` (the lines that aren't comments).
+fn parse_lscpu(capture: &str) -> Result<Facts, String> {
+    let cpus = capture
+        .lines()
+        .filter(|l| !l.starts_with('#') && l.split(',').count() >= 4)
+        .count();
+    if cpus == 0 {
+        return Err(String::from("lscpu doesn't list any CPUs"));
+    }
+    Ok(vec![(String::from("cpus"), cpus.to_string())])
+}
+
+/// `available: 2 nodes (0-1)` of `numactl --hardware`.
+fn parse_numactl(capture: &str) -> Result<Facts, String> {
+    capture
+        .lines()
+        .find(|l| l.starts_with("available:"))
+        .and_then(|l| l["available:".len()..].split_whitespace().next())
+        .map(|nodes| vec![(String::from("nodes"), String::from(nodes))])
+        .ok_or(String::from("numactl doesn't say how many nodes there are"))
+}
+
+/// The BIOS and the system of `dmidecode` (`BIOS Information/Version`, ...).
+fn parse_dmidecode(capture: &str) -> Result<Facts, String> {
+    let keys = [
+        "Vendor",
+        "Version",
+        "Release Date",
+        "Manufacturer",
+        "Product Name",
+    ];
+    let mut facts = Vec::new();
+    let mut section = "";
+    for line in capture.lines() {
+        if !line.starts_with('\t') {
+            section = line.trim();
+            continue;
+        }
+        if section != "BIOS Information" && section != "System Information" {
+            continue;
+        }
+        let mut parts = line.trim().splitn(2, ": ");
+        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            if keys.contains(&key) {
+                facts.push((format!("{}/{}", section, key), String::from(value)));
+            }
+        }
+    }
+    Ok(facts)
+}
+
+/// `<vulnerability>: <status>` lines.
+fn parse_lines(capture: &str) -> Result<Facts, String> {
+    Ok(capture
+        .lines()
+        .filter_map(|l| {
+            let mut parts = l.splitn(2, ": ");
+            Some((String::from(parts.next()?), String::from(parts.next()?)))
+        })
+        .collect())
+}
+
+/// How the kernel mitigates the CPU vulnerabilities (they cost performance).
+struct Mitigations;
+
+impl Collector for Mitigations {
+    fn name(&self) -> &'static str {
+        "mitigations"
+    }
+
+    fn binaries(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn file(&self) -> &'static str {
+        "mitigations.txt"
+    }
+
+    fn run(&self) -> io::Result<String> {
+        let mut vulnerabilities: Vec<(String, String)> =
+            fs::read_dir("/sys/devices/system/cpu/vulnerabilities")?
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let status = fs::read_to_string(e.path()).ok()?;
+                    Some((
+                        e.file_name().to_string_lossy().into_owned(),
+                        String::from(status.trim()),
+                    ))
+                })
+                .collect();
+        vulnerabilities.sort();
+        Ok(vulnerabilities
+            .iter()
+            .map(|(name, status)| format!("{}: {}\n", name, status))
+            .collect())
+    }
+
+    fn parse(&self, capture: &str) -> Result<Facts, String> {
+        parse_lines(capture)
+    }
+
+    fn by_default(&self) -> bool {
+        false
+    }
+}
+
+/// Whether irqbalance moves the interrupts around (the CPUs they go to change
+/// while we measure) and where the interrupts go.
+struct Irqbalance;
+
+impl Collector for Irqbalance {
+    fn name(&self) -> &'static str {
+        "irqbalance"
+    }
+
+    fn binaries(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn file(&self) -> &'static str {
+        "irqbalance.txt"
+    }
+
+    fn run(&self) -> io::Result<String> {
+        let running = fs::read_dir("/proc")?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let pid = e.file_name().to_string_lossy().into_owned();
+                let comm = fs::read_to_string(e.path().join("comm")).ok()?;
+                (comm.trim() == "irqbalance").then_some(pid)
+            })
+            .next();
+        let mut capture = match running {
+            Some(pid) => format!("irqbalance: running (pid {})\n", pid),
+            None => String::from("irqbalance: not running\n"),
+        };
+        let mut irqs: Vec<(u64, String)> = fs::read_dir("/proc/irq")?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let irq = e.file_name().to_string_lossy().parse().ok()?;
+                let cpus = fs::read_to_string(e.path().join("smp_affinity_list")).ok()?;
+                Some((irq, String::from(cpus.trim())))
+            })
+            .collect();
+        irqs.sort();
+        for (irq, cpus) in irqs {
+            capture.push_str(&format!("irq {}: {}\n", irq, cpus));
+        }
+        Ok(capture)
+    }
+
+    fn parse(&self, capture: &str) -> Result<Facts, String> {
+        Ok(parse_lines(capture)?
+            .into_iter()
+            .filter(|(fact, _)| fact == "irqbalance")
+            .collect())
+    }
+
+    fn by_default(&self) -> bool {
+        false
+    }
+}
+
+/// Every capture we know.
+pub const COLLECTORS: [&'static dyn Collector; 9] = [
+    &Tool {
+        name: "lscpu",
+        binaries: &["lscpu"],
+        args: &["--parse=NODE,SOCKET,CORE,CPU,CACHE"],
+        file: "lscpu.csv",
+        required: true,
+        by_default: true,
+        freebsd: Some(sysctl::lscpu_parse),
+        parser: Some(parse_lscpu),
+    },
+    &Tool {
+        name: "numactl",
+        binaries: &["numactl"],
+        args: &["--hardware"],
+        file: "numactl.dat",
+        required: true,
+        by_default: true,
+        freebsd: Some(sysctl::numactl_hardware),
+        parser: Some(parse_numactl),
+    },
+    &Tool {
+        name: "lstopo",
+        binaries: &["lstopo"],
+        args: &["--of", "console", "--taskset"],
+        file: "lstopo.txt",
+        required: false,
+        by_default: true,
+        freebsd: None,
+        parser: None,
+    },
+    &Tool {
+        name: "cpuid",
+        binaries: &["cpuid"],
+        args: &[],
+        file: "cpuid.txt",
+        required: false,
+        by_default: true,
+        freebsd: None,
+        parser: None,
+    },
+    &Tool {
+        name: "likwid",
+        binaries: &["likwid-topology"],
+        args: &["-g", "-c"],
+        file: "likwid_topology.txt",
+        required: false,
+        by_default: cfg!(not(target_os = "freebsd")),
+        freebsd: None,
+        parser: None,
+    },
+    &Tool {
+        name: "dmidecode",
+        binaries: &["dmidecode"],
+        args: &["-t", "bios", "-t", "system", "-t", "memory"],
+        file: "dmidecode.txt",
+        required: false,
+        by_default: false,
+        freebsd: None,
+        parser: Some(parse_dmidecode),
+    },
+    &Mitigations,
+    &Irqbalance,
+    &Tool {
+        name: "turbostat",
+        binaries: &["turbostat"],
+        args: &["--quiet", "--num_iterations", "1", "--interval", "1"],
+        file: "turbostat_idle.txt",
+        required: false,
+        by_default: false,
+        freebsd: None,
+        parser: None,
+    },
+];
+
+/// The collectors a campaign selected (None: the default ones).
+static SELECTED: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+/// The collector `name`.
+pub fn collector(name: &str) -> Option<&'static dyn Collector> {
+    COLLECTORS.iter().find(|c| c.name() == name).cloned()
+}
+
+/// The files the collectors save in a run directory (whether they ran or not).
+pub fn files() -> Vec<&'static str> {
+    let mut files: Vec<&'static str> = COLLECTORS.iter().map(|c| c.file()).collect();
+    files.push(FACT_FILE);
+    files
+}
+
+/// Runs the optional collectors in `names` (and the required ones) from now on.
+pub fn select(names: &[String]) -> Result<(), String> {
+    if let Some(name) = names.iter().find(|n| collector(n).is_none()) {
+        let known: Vec<&str> = COLLECTORS.iter().map(|c| c.name()).collect();
+        return Err(format!(
+            "unknown collector '{}' (should be one of {})",
+            name,
+            known.join(", ")
+        ));
+    }
+    *SELECTED.lock().unwrap() = Some(names.to_vec());
+    Ok(())
+}
+
+/// The collectors we run.
+fn selected() -> Vec<&'static dyn Collector> {
+    let selected = SELECTED.lock().unwrap().clone();
+    COLLECTORS
+        .iter()
+        .filter(|c| {
+            c.required()
+                || selected
+                    .as_ref()
+                    .map_or(c.by_default(), |names| names.iter().any(|n| n == c.name()))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Is `binary` in the PATH?
+fn installed(binary: &str) -> bool {
+    env::var_os("PATH").is_some_and(|path| {
+        env::split_paths(&path).any(|directory| directory.join(binary).is_file())
+    })
+}
+
+/// The capture and facts of `collector`.
+fn capture(collector: &dyn Collector) -> Result<(String, Facts), String> {
+    if let Some(missing) = collector.binaries().iter().find(|b| !installed(b)) {
+        return Err(format!("{} isn't installed", missing));
+    }
+    let capture = collector.run().map_err(|e| e.to_string())?;
+    let facts = collector.parse(&capture)?;
+    Ok((capture, facts))
+}
+
+fn to_io_error(e: csv::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Runs the selected collectors and saves their captures and facts in
+/// `output_path`. Stops us if a required one fails.
+pub fn collect(output_path: &Path) -> io::Result<()> {
+    let mut wtr = csv::Writer::from_file(output_path.join(FACT_FILE)).map_err(to_io_error)?;
+    wtr.encode(("collector", "fact", "value"))
+        .map_err(to_io_error)?;
+    for collector in selected() {
+        match capture(collector) {
+            Ok((capture, facts)) => {
+                fs::write(output_path.join(collector.file()), capture)?;
+                for (fact, value) in facts {
+                    wtr.encode((collector.name(), fact, value))
+                        .map_err(to_io_error)?;
+                }
+            }
+            Err(e) if collector.required() => {
+                error!("Can't capture {}: {}", collector.file(), e);
+                summary::fail(Failure::Environment);
+            }
+            Err(e) => summary::warn(format!(
+                "Can't capture {} ({}), going on without it: {}",
+                collector.file(),
+                collector.name(),
+                e
+            )),
+        }
+    }
+    wtr.flush().map_err(to_io_error)
+}
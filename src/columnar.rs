@@ -0,0 +1,477 @@
+//! Writers for the aggregated results: CSV (the default) or Parquet.
+//!
+//! Parquet files are typed (so tools don't have to guess if `CPU` is a number)
+//! and much smaller, since event names, units etc. are dictionary-encoded.
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use regex::{self, Regex};
+use tracing::{error, warn};
+
+use super::normalize::is_normalized;
+
+/// Columns of the aggregated results (results.csv).
+pub const RESULT_COLUMNS: [&str; 13] = [
+    "EVENT_NAME",
+    "INDEX",
+    "TIME",
+    "SOCKET",
+    "CORE",
+    "CPU",
+    "NODE",
+    "UNIT",
+    "SAMPLE_VALUE",
+    "PHASE",
+    "TID",
+    "THREAD",
+    "SCOPE",
+];
+
+/// How many rows we buffer before writing a Parquet row group (this bounds the
+/// memory we need for the results, no matter how big the campaign is).
+const ROW_GROUP_SIZE: usize = 100_000;
+
+/// Something we can write rows of aggregated results to.
+pub trait ResultWriter {
+    fn write_row(&mut self, row: &[&str]);
+    fn finish(&mut self);
+}
+
+impl ResultWriter for csv::Writer<File> {
+    fn write_row(&mut self, row: &[&str]) {
+        self.encode(row).unwrap();
+    }
+
+    fn finish(&mut self) {
+        self.flush().unwrap();
+    }
+}
+
+/// Keeps rows in memory, so they can be written out later on in the right order.
+pub struct BufferWriter {
+    rows: Vec<Vec<String>>,
+}
+
+impl BufferWriter {
+    pub fn new() -> BufferWriter {
+        BufferWriter { rows: Vec::new() }
+    }
+
+    pub fn replay(self, into: &mut dyn ResultWriter) {
+        for row in self.rows {
+            let row: Vec<&str> = row.iter().map(|v| v.as_str()).collect();
+            into.write_row(&row);
+        }
+    }
+}
+
+impl ResultWriter for BufferWriter {
+    fn write_row(&mut self, row: &[&str]) {
+        self.rows
+            .push(row.iter().map(|v| String::from(*v)).collect());
+    }
+
+    fn finish(&mut self) {}
+}
+
+/// Puts the name of the run in front of every row (for the results of a
+/// whole campaign).
+pub struct RunWriter<'a> {
+    inner: &'a mut dyn ResultWriter,
+    run: String,
+}
+
+impl<'a> RunWriter<'a> {
+    pub fn new(inner: &'a mut dyn ResultWriter, run: &str) -> RunWriter<'a> {
+        RunWriter {
+            inner,
+            run: String::from(run),
+        }
+    }
+}
+
+impl<'a> ResultWriter for RunWriter<'a> {
+    fn write_row(&mut self, row: &[&str]) {
+        let mut with_run: Vec<&str> = Vec::with_capacity(row.len() + 1);
+        with_run.push(self.run.as_str());
+        with_run.extend_from_slice(row);
+        self.inner.write_row(&with_run);
+    }
+
+    /// The inner writer is finished by whoever created it.
+    fn finish(&mut self) {}
+}
+
+/// Creates `save_to` with `columns` in `format` (csv or parquet).
+pub fn result_writer(format: &str, save_to: &Path, columns: &[&str]) -> Box<dyn ResultWriter> {
+    match format {
+        "parquet" => Box::new(ParquetWriter::new(save_to, columns)),
+        _ => {
+            let mut wrtr = csv::Writer::from_file(save_to).unwrap();
+            wrtr.encode(columns).unwrap();
+            Box::new(wrtr)
+        }
+    }
+}
+
+/// Turns the (long) result rows into one row per sample with a column for every
+/// event. All the other columns identify a sample.
+///
+/// # Note
+/// We only know all the event columns once we've seen every row, so this keeps the
+/// results in memory until `finish` is called.
+pub struct WideWriter {
+    format: String,
+    save_to: PathBuf,
+    columns: Vec<String>,
+    event_column: usize,
+    value_column: usize,
+    samples: Vec<Vec<String>>,
+    sample_index: HashMap<Vec<String>, usize>,
+    values: Vec<HashMap<String, String>>,
+    events: BTreeSet<String>,
+}
+
+impl WideWriter {
+    pub fn new(format: &str, save_to: &Path, columns: &[&str]) -> WideWriter {
+        let position = |name: &str| {
+            columns
+                .iter()
+                .position(|c| *c == name)
+                .expect("Results don't have the column")
+        };
+        WideWriter {
+            format: String::from(format),
+            save_to: save_to.to_path_buf(),
+            columns: columns.iter().map(|c| String::from(*c)).collect(),
+            event_column: position("EVENT_NAME"),
+            value_column: columns
+                .iter()
+                .position(|c| c.starts_with("SAMPLE_VALUE"))
+                .expect("Results don't have a value column"),
+            samples: Vec::new(),
+            sample_index: HashMap::new(),
+            values: Vec::new(),
+            events: BTreeSet::new(),
+        }
+    }
+}
+
+impl ResultWriter for WideWriter {
+    fn write_row(&mut self, row: &[&str]) {
+        if row.len() != self.columns.len() {
+            // Sampled (perf record) results don't have all the columns:
+            error!("Sampled (perf record) results have no wide layout, aggregate them with --layout long.");
+            process::exit(1);
+        }
+        let event = String::from(row[self.event_column]);
+        let sample: Vec<String> = row
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != self.event_column && i != self.value_column)
+            .map(|(_, v)| String::from(*v))
+            .collect();
+
+        let idx = match self.sample_index.get(&sample) {
+            Some(idx) => *idx,
+            None => {
+                self.samples.push(sample.clone());
+                self.values.push(HashMap::new());
+                self.sample_index.insert(sample, self.samples.len() - 1);
+                self.samples.len() - 1
+            }
+        };
+        self.values[idx].insert(event.clone(), String::from(row[self.value_column]));
+        self.events.insert(event);
+    }
+
+    fn finish(&mut self) {
+        let mut columns: Vec<&str> = self
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != self.event_column && i != self.value_column)
+            .map(|(_, c)| c.as_str())
+            .collect();
+        // Normalized values keep their suffix (e.g., SAMPLE_VALUE_PKI -> cycles_PKI):
+        let suffix = &self.columns[self.value_column]["SAMPLE_VALUE".len()..];
+        let event_columns: Vec<String> = self
+            .events
+            .iter()
+            .map(|e| format!("{}{}", e, suffix))
+            .collect();
+        columns.extend(event_columns.iter().map(|e| e.as_str()));
+
+        let mut wrtr = result_writer(&self.format, &self.save_to, &columns);
+        for (sample, values) in self.samples.iter().zip(self.values.iter()) {
+            let mut row: Vec<&str> = sample.iter().map(|v| v.as_str()).collect();
+            row.extend(
+                self.events
+                    .iter()
+                    .map(|e| values.get(e).map_or("", |v| v.as_str())),
+            );
+            wrtr.write_row(&row);
+        }
+        wrtr.finish();
+    }
+}
+
+/// Turns `--events` into a regular expression: either comma-separated globs
+/// (`UNC_M_*,cycles`) or a regex if it starts with `re:`.
+pub fn event_pattern(events: &str) -> Result<Regex, regex::Error> {
+    if let Some(regex) = events.strip_prefix("re:") {
+        return Regex::new(regex);
+    }
+    let globs: Vec<String> = events
+        .split(',')
+        .map(|glob| {
+            glob.trim()
+                .chars()
+                .map(|c| match c {
+                    '*' => String::from(".*"),
+                    '?' => String::from("."),
+                    c => regex::escape(&c.to_string()),
+                })
+                .collect()
+        })
+        .collect();
+    Regex::new(&format!("^(?:{})$", globs.join("|")))
+}
+
+/// Only passes on the rows of events that match a pattern, and only the
+/// selected columns of them.
+pub struct SelectWriter {
+    inner: Box<dyn ResultWriter>,
+    columns: usize,
+    event_column: Option<usize>,
+    events: Option<Regex>,
+    keep: Vec<usize>,
+}
+
+impl SelectWriter {
+    /// `selected` have to be a subset of `columns`.
+    pub fn new(
+        inner: Box<dyn ResultWriter>,
+        columns: &[&str],
+        selected: &[&str],
+        events: Option<Regex>,
+    ) -> SelectWriter {
+        SelectWriter {
+            inner,
+            columns: columns.len(),
+            event_column: columns.iter().position(|c| *c == "EVENT_NAME"),
+            events,
+            keep: selected
+                .iter()
+                .map(|s| columns.iter().position(|c| c == s).expect("Unknown column"))
+                .collect(),
+        }
+    }
+}
+
+impl ResultWriter for SelectWriter {
+    fn write_row(&mut self, row: &[&str]) {
+        if row.len() != self.columns {
+            // Sampled (perf record) results don't have all the columns:
+            error!("Can't select events or columns of sampled (perf record) results, aggregate them without --events and --columns.");
+            process::exit(1);
+        }
+        if let (Some(events), Some(idx)) = (self.events.as_ref(), self.event_column) {
+            if !events.is_match(row[idx]) {
+                return;
+            }
+        }
+        let selected: Vec<&str> = self.keep.iter().map(|idx| row[*idx]).collect();
+        self.inner.write_row(&selected);
+    }
+
+    fn finish(&mut self) {
+        self.inner.finish();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    Integer,
+    Float,
+}
+
+/// Everything that isn't text, a time or a normalized value is a counter value,
+/// index or location (in the wide layout the events are columns too).
+fn kind_of(column: &str) -> Kind {
+    match column {
+        "TIME" => Kind::Float,
+        "RUN" | "FILE" | "EVENT_NAME" | "UNIT" | "PHASE" | "THREAD" | "SCOPE" | "DSO"
+        | "SYMBOL" => Kind::Text,
+        _ if is_normalized(column) => Kind::Float,
+        _ => Kind::Integer,
+    }
+}
+
+/// Definition levels for an optional column (0 = null, 1 = value present).
+fn definition_levels<T>(values: &[Option<T>]) -> Vec<i16> {
+    values
+        .iter()
+        .map(|v| if v.is_some() { 1 } else { 0 })
+        .collect()
+}
+
+/// `value` of a numeric column, None if it's empty (null) or isn't a `T` (which
+/// we count in `invalid`).
+fn parse_value<T: FromStr>(value: &str, invalid: &mut usize) -> Option<T> {
+    if value.is_empty() {
+        return None;
+    }
+    let parsed = value.parse::<T>().ok();
+    if parsed.is_none() {
+        *invalid += 1;
+    }
+    parsed
+}
+
+/// Writes the results to a Parquet file, all columns are optional and empty
+/// values are stored as null.
+pub struct ParquetWriter {
+    writer: Option<SerializedFileWriter<File>>,
+    kinds: Vec<Kind>,
+    columns: Vec<Vec<String>>,
+    dropped: usize,
+    /// Values that aren't numbers or don't fit the column, stored as null.
+    invalid: usize,
+}
+
+impl ParquetWriter {
+    pub fn new(save_to: &Path, names: &[&str]) -> ParquetWriter {
+        let kinds: Vec<Kind> = names.iter().map(|n| kind_of(n)).collect();
+        let fields: Vec<String> = names
+            .iter()
+            .zip(kinds.iter())
+            .map(|(name, kind)| match *kind {
+                Kind::Text => format!("OPTIONAL BYTE_ARRAY {} (UTF8);", name),
+                Kind::Integer => format!("OPTIONAL INT64 {};", name),
+                Kind::Float => format!("OPTIONAL DOUBLE {};", name),
+            })
+            .collect();
+        let schema = parse_message_type(&format!("message results {{ {} }}", fields.join(" ")))
+            .expect("Invalid Parquet schema for results?");
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .set_dictionary_enabled(true)
+            .build();
+        let file = File::create(save_to).expect("Can't create result file");
+        let writer = SerializedFileWriter::new(file, schema.into(), properties.into())
+            .expect("Can't create Parquet writer");
+
+        ParquetWriter {
+            writer: Some(writer),
+            kinds,
+            columns: names.iter().map(|_| Vec::new()).collect(),
+            dropped: 0,
+            invalid: 0,
+        }
+    }
+
+    fn write_row_group(&mut self) {
+        if self.columns[0].is_empty() {
+            return;
+        }
+        let writer = self.writer.as_mut().expect("Writer already closed");
+        let mut row_group = writer.next_row_group().expect("Can't start row group");
+
+        let mut idx = 0;
+        let mut invalid = 0;
+        while let Some(mut column) = row_group.next_column().expect("Can't get next column") {
+            let values: Vec<String> = self.columns[idx].drain(..).collect();
+            let present = values.iter().map(|v| !v.is_empty());
+            let result = match (self.kinds[idx], column.untyped()) {
+                (Kind::Text, ColumnWriter::ByteArrayColumnWriter(ref mut w)) => {
+                    let values: Vec<Option<ByteArray>> = values
+                        .iter()
+                        .zip(present)
+                        .map(|(v, p)| {
+                            if p {
+                                Some(ByteArray::from(v.as_str()))
+                            } else {
+                                None
+                            }
+                        })
+                        .collect();
+                    let data: Vec<ByteArray> = values.iter().filter_map(|v| v.clone()).collect();
+                    w.write_batch(&data, Some(&definition_levels(&values)), None)
+                }
+                (Kind::Integer, ColumnWriter::Int64ColumnWriter(ref mut w)) => {
+                    // SAMPLE_VALUE is a u64 but there's only signed 64-bit integers in
+                    // Parquet, counts from 2^63 on don't fit:
+                    let values: Vec<Option<i64>> = values
+                        .iter()
+                        .map(|v| parse_value::<i64>(v, &mut invalid))
+                        .collect();
+                    let data: Vec<i64> = values.iter().filter_map(|v| *v).collect();
+                    w.write_batch(&data, Some(&definition_levels(&values)), None)
+                }
+                (Kind::Float, ColumnWriter::DoubleColumnWriter(ref mut w)) => {
+                    let values: Vec<Option<f64>> = values
+                        .iter()
+                        .map(|v| parse_value::<f64>(v, &mut invalid))
+                        .collect();
+                    let data: Vec<f64> = values.iter().filter_map(|v| *v).collect();
+                    w.write_batch(&data, Some(&definition_levels(&values)), None)
+                }
+                _ => unreachable!("Column type does not match schema"),
+            };
+            result.expect("Can't write Parquet column");
+            column.close().expect("Can't close Parquet column");
+            idx += 1;
+        }
+
+        row_group.close().expect("Can't close row group");
+        self.invalid += invalid;
+    }
+}
+
+impl ResultWriter for ParquetWriter {
+    fn write_row(&mut self, row: &[&str]) {
+        if row.len() != self.columns.len() {
+            // Sampled (perf record) results don't have all the columns
+            self.dropped += 1;
+            return;
+        }
+        for (column, value) in self.columns.iter_mut().zip(row.iter()) {
+            column.push(String::from(*value));
+        }
+        if self.columns[0].len() >= ROW_GROUP_SIZE {
+            self.write_row_group();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.write_row_group();
+        if let Some(writer) = self.writer.take() {
+            writer.close().expect("Can't finish Parquet file");
+        }
+        if self.dropped > 0 {
+            warn!(
+                "Dropped {} rows that don't have all result columns, use --format csv for them.",
+                self.dropped
+            );
+        }
+        if self.invalid > 0 {
+            warn!(
+                "Stored {} values that aren't numbers or don't fit in a 64-bit integer as null, \
+                 use --format csv for them.",
+                self.invalid
+            );
+        }
+    }
+}
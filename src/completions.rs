@@ -0,0 +1,200 @@
+//! Shell completion (`autoperf completions <shell>`).
+//!
+//! The scripts call back into autoperf (with `COMPLETE=<shell>` set, see
+//! `main`) to complete a word, so completions always match the command line of
+//! the installed binary. Options that take event names complete them from the
+//! event catalog: the events of this machine (name, unit, counters and
+//! description, tab-separated), which we keep in
+//! `$XDG_CACHE_HOME/autoperf/events` (`~/.cache/autoperf/events`, see
+//! `event_catalog` in `config.rs`) so completing
+//! doesn't have to look up the event tables on every key press. `autoperf
+//! completions` rewrites it, e.g. after a new autoperf knows more events.
+//! `autoperf search <regex>` searches it.
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use clap_complete::env::{Bash, EnvCompleter, Fish, Zsh};
+use clap_complete::CompletionCandidate;
+use tracing::warn;
+use x86::perfcnt::intel::{events, Counter, EventDescription};
+
+use super::config::Config;
+use super::profile::PerfEvent;
+
+/// The environment variable that asks autoperf to complete instead of run.
+pub const COMPLETE_ENV: &str = "COMPLETE";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    fn completer(&self) -> &'static dyn EnvCompleter {
+        match *self {
+            Shell::Bash => &Bash,
+            Shell::Zsh => &Zsh,
+            Shell::Fish => &Fish,
+        }
+    }
+}
+
+/// An event of the catalog.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CatalogEvent {
+    pub name: String,
+    /// `cpu` or the uncore unit (e.g., `CBO`, `iMC`).
+    pub unit: String,
+    /// Which counters it can use, e.g. `counters 0-3, alone`.
+    pub counters: String,
+    pub description: String,
+}
+
+impl CatalogEvent {
+    fn new(event: &EventDescription) -> CatalogEvent {
+        CatalogEvent {
+            name: String::from(event.event_name),
+            unit: String::from(event.unit.unwrap_or("cpu")),
+            counters: counters(event),
+            description: event
+                .brief_description
+                .split_whitespace()
+                .collect::<Vec<&str>>()
+                .join(" "),
+        }
+    }
+
+    /// A line of the catalog, None for lines of catalogs that only have names
+    /// (older versions wrote those).
+    fn parse(line: &str) -> Option<CatalogEvent> {
+        let fields: Vec<&str> = line.splitn(4, '\t').collect();
+        if fields.len() < 4 {
+            return None;
+        }
+        Some(CatalogEvent {
+            name: String::from(fields[0]),
+            unit: String::from(fields[1]),
+            counters: String::from(fields[2]),
+            description: String::from(fields[3]),
+        })
+    }
+
+    fn line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}",
+            self.name, self.unit, self.counters, self.description
+        )
+    }
+}
+
+/// The counters of a mask, e.g. `0-3` or `0,2`.
+fn counter_list(mask: u8) -> String {
+    let counters: Vec<u8> = (0..8).filter(|c| mask & (1 << c) != 0).collect();
+    let contiguous = counters.windows(2).all(|w| w[1] == w[0] + 1);
+    match counters.len() {
+        0 => String::from("none"),
+        1 => counters[0].to_string(),
+        n if contiguous => format!("{}-{}", counters[0], counters[n - 1]),
+        _ => counters
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<String>>()
+            .join(","),
+    }
+}
+
+/// What constrains where we can count `event` (on this machine, SMT on or off).
+fn counters(event: &EventDescription) -> String {
+    let perf_event = PerfEvent(event);
+    let mut constraints = vec![match perf_event.counter() {
+        Counter::Fixed(mask) => format!("fixed counter {}", counter_list(mask)),
+        Counter::Programmable(mask) => format!("counters {}", counter_list(mask)),
+    }];
+    if event.offcore {
+        constraints.push(String::from("offcore"));
+    }
+    if event.taken_alone {
+        constraints.push(String::from("alone"));
+    }
+    if event.errata.is_some() {
+        constraints.push(String::from("errata"));
+    }
+    constraints.join(", ")
+}
+
+/// Where the catalog is (`event_catalog` in the configuration).
+fn catalog_file() -> Option<PathBuf> {
+    Config::load().unwrap_or_default().event_catalog.value
+}
+
+/// Writes the events of this machine to the catalog.
+fn save_event_catalog() -> io::Result<Vec<CatalogEvent>> {
+    let mut catalog: Vec<CatalogEvent> = events().map_or(Vec::new(), |events| {
+        events.values().map(CatalogEvent::new).collect()
+    });
+    catalog.sort_by(|a, b| a.name.cmp(&b.name));
+    // We don't know the events of this CPU, maybe a newer autoperf does:
+    if catalog.is_empty() {
+        return Ok(catalog);
+    }
+    if let Some(file) = catalog_file() {
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let lines: Vec<String> = catalog.iter().map(|e| e.line()).collect();
+        fs::write(&file, lines.join("\n") + "\n")?;
+    }
+    Ok(catalog)
+}
+
+/// The events of the catalog (created if there is none yet, or rewritten if an
+/// older autoperf wrote it).
+pub fn event_catalog() -> Vec<CatalogEvent> {
+    let read = catalog_file()
+        .map(fs::read_to_string)
+        .and_then(|catalog| catalog.ok())
+        .and_then(|catalog| catalog.lines().map(CatalogEvent::parse).collect());
+    match read {
+        Some(catalog) => catalog,
+        None => save_event_catalog().unwrap_or_default(),
+    }
+}
+
+/// Completes the last event of a comma-separated list of events.
+pub fn complete_events(current: &OsStr) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+    let (done, prefix) = match current.rfind(',') {
+        Some(idx) => current.split_at(idx + 1),
+        None => ("", current.as_ref()),
+    };
+    event_catalog()
+        .into_iter()
+        .filter(|e| e.name.starts_with(prefix))
+        .map(|e| CompletionCandidate::new(format!("{}{}", done, e.name)))
+        .collect()
+}
+
+/// Prints the script that sets up completions for autoperf in `shell`.
+pub fn print_completions(shell: Shell) -> io::Result<()> {
+    if let Err(e) = save_event_catalog() {
+        warn!("Can't save the event catalog {:?}: {}", catalog_file(), e);
+    }
+    let completer = env::current_exe()
+        .map(|exe| exe.to_string_lossy().into_owned())
+        .unwrap_or(String::from("autoperf"));
+    let mut script = Vec::new();
+    shell.completer().write_registration(
+        COMPLETE_ENV,
+        "autoperf",
+        "autoperf",
+        &completer,
+        &mut script,
+    )?;
+    io::stdout().write_all(&script)
+}
@@ -0,0 +1,275 @@
+//! Defaults for the command line: `~/.config/autoperf/config.toml` (or the file
+//! in `$AUTOPERF_CONFIG`), overridden by `AUTOPERF_*` environment variables,
+//! which the options of a sub-command override in turn:
+//!
+//! ```toml
+//! output = "/scratch/autoperf"    # AUTOPERF_OUTPUT, profile --output
+//! backend = "likwid"              # AUTOPERF_BACKEND, --backend
+//! housekeeping_cpu = 0            # AUTOPERF_HOUSEKEEPING_CPU, --housekeeping-cpu
+//! event_catalog = "~/events.txt"  # AUTOPERF_EVENT_CATALOG
+//! on_existing = "suffix"          # AUTOPERF_ON_EXISTING, --on-existing
+//! ```
+//!
+//! `autoperf config show` prints what we end up with (and where it came from).
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use super::profile::Backend;
+use super::util::{Collision, Cpu, COLLISIONS};
+
+/// Where to find the configuration file (instead of `~/.config/autoperf/config.toml`).
+pub const CONFIG_ENV: &str = "AUTOPERF_CONFIG";
+
+/// The backends `backend` can be (see `profile --backend`).
+const BACKENDS: [&str; 5] = ["perf", "likwid", "papi", "pmcstat", "vtune"];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Source {
+    Default,
+    File,
+    Environment,
+    CommandLine,
+}
+
+#[derive(Debug, Clone)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+impl<T> Setting<T> {
+    fn new(value: T) -> Setting<T> {
+        Setting {
+            value,
+            source: Source::Default,
+        }
+    }
+
+    fn set(&mut self, value: T, source: Source) {
+        self.value = value;
+        self.source = source;
+    }
+
+    /// The value of the command line option if it was given, ours otherwise.
+    pub fn or_flag(&mut self, flag: Option<T>) -> &T {
+        if let Some(value) = flag {
+            self.set(value, Source::CommandLine);
+        }
+        &self.value
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The configuration file (if there is one).
+    pub file: Option<PathBuf>,
+    /// Where `profile` saves its results.
+    pub output: Setting<String>,
+    /// What measures in `profile` and `pair`.
+    pub backend: Setting<String>,
+    /// The CPU autoperf keeps its own work on (see `housekeeping.rs`).
+    pub housekeeping_cpu: Setting<Option<Cpu>>,
+    /// The names of the events of this machine (see `completions.rs`).
+    pub event_catalog: Setting<Option<PathBuf>>,
+    /// What happens if the output directory isn't empty (see `Collision`).
+    pub on_existing: Setting<String>,
+}
+
+fn config_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    match env::var_os(var) {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(fallback)),
+    }
+}
+
+/// `~` at the start of a path is the home directory.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.starts_with("~/"), env::var_os("HOME")) {
+        (true, Some(home)) => PathBuf::from(home).join(&path[2..]),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn check_backend(backend: &str, from: &str) -> Result<String, String> {
+    if BACKENDS.contains(&backend) {
+        Ok(String::from(backend))
+    } else {
+        Err(format!(
+            "Unknown backend '{}' in {} (should be one of {})",
+            backend,
+            from,
+            BACKENDS.join(", ")
+        ))
+    }
+}
+
+fn check_collision(policy: &str, from: &str) -> Result<String, String> {
+    match Collision::from_str(policy) {
+        Ok(_) => Ok(String::from(policy)),
+        Err(_) => Err(format!(
+            "Unknown on_existing '{}' in {} (should be one of {})",
+            policy,
+            from,
+            COLLISIONS.join(", ")
+        )),
+    }
+}
+
+fn parse_cpu(cpu: &str, from: &str) -> Result<Cpu, String> {
+    u64::from_str(cpu.trim())
+        .map_err(|_| format!("{} should be a CPU number (got '{}')", from, cpu))
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            file: None,
+            output: Setting::new(String::from("out")),
+            backend: Setting::new(Backend::default().to_string()),
+            housekeeping_cpu: Setting::new(None),
+            event_catalog: Setting::new(
+                config_dir("XDG_CACHE_HOME", ".cache").map(|d| d.join("autoperf").join("events")),
+            ),
+            on_existing: Setting::new(String::from("resume")),
+        }
+    }
+}
+
+impl Config {
+    /// The defaults, the configuration file and the environment (in that order).
+    pub fn load() -> Result<Config, String> {
+        let mut config: Config = Default::default();
+        let file = match env::var_os(CONFIG_ENV) {
+            Some(file) => Some(PathBuf::from(file)),
+            None => config_dir("XDG_CONFIG_HOME", ".config")
+                .map(|d| d.join("autoperf").join("config.toml"))
+                .filter(|f| f.exists()),
+        };
+        if let Some(file) = file {
+            config.read_file(file)?;
+        }
+        config.read_env()?;
+        Ok(config)
+    }
+
+    fn read_file(&mut self, file: PathBuf) -> Result<(), String> {
+        let content =
+            fs::read_to_string(&file).map_err(|e| format!("Can't read {:?}: {}", file, e))?;
+        let mut parser = toml::Parser::new(content.as_str());
+        let doc = match parser.parse() {
+            Some(doc) => doc,
+            None => return Err(format!("Can't parse {:?}:\n{:?}", file, parser.errors)),
+        };
+        let from = file.to_string_lossy().into_owned();
+        for (key, value) in doc.iter() {
+            match (key.as_str(), value) {
+                ("output", toml::Value::String(output)) => {
+                    self.output.set(String::from(output.as_str()), Source::File)
+                }
+                ("backend", toml::Value::String(backend)) => self
+                    .backend
+                    .set(check_backend(backend, &from)?, Source::File),
+                ("housekeeping_cpu", &toml::Value::Integer(cpu)) if cpu >= 0 => {
+                    self.housekeeping_cpu.set(Some(cpu as Cpu), Source::File)
+                }
+                ("event_catalog", toml::Value::String(catalog)) => self
+                    .event_catalog
+                    .set(Some(expand_home(catalog)), Source::File),
+                ("on_existing", toml::Value::String(policy)) => self
+                    .on_existing
+                    .set(check_collision(policy, &from)?, Source::File),
+                ("output", _) | ("backend", _) | ("event_catalog", _) | ("on_existing", _) => {
+                    return Err(format!("'{}' in {} should be a string", key, from))
+                }
+                ("housekeeping_cpu", _) => {
+                    return Err(format!(
+                        "'housekeeping_cpu' in {} should be a CPU number",
+                        from
+                    ))
+                }
+                _ => return Err(format!("Unknown setting '{}' in {}", key, from)),
+            }
+        }
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn read_env(&mut self) -> Result<(), String> {
+        if let Ok(output) = env::var("AUTOPERF_OUTPUT") {
+            self.output.set(output, Source::Environment);
+        }
+        if let Ok(backend) = env::var("AUTOPERF_BACKEND") {
+            let backend = check_backend(&backend, "AUTOPERF_BACKEND")?;
+            self.backend.set(backend, Source::Environment);
+        }
+        if let Ok(cpu) = env::var("AUTOPERF_HOUSEKEEPING_CPU") {
+            let cpu = parse_cpu(&cpu, "AUTOPERF_HOUSEKEEPING_CPU")?;
+            self.housekeeping_cpu.set(Some(cpu), Source::Environment);
+        }
+        if let Ok(catalog) = env::var("AUTOPERF_EVENT_CATALOG") {
+            self.event_catalog
+                .set(Some(expand_home(&catalog)), Source::Environment);
+        }
+        if let Ok(policy) = env::var("AUTOPERF_ON_EXISTING") {
+            let policy = check_collision(&policy, "AUTOPERF_ON_EXISTING")?;
+            self.on_existing.set(policy, Source::Environment);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Default => write!(f, "default"),
+            Source::File => write!(f, "configuration file"),
+            Source::Environment => write!(f, "environment"),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// The configuration as a configuration file, with where every value came from.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.file {
+            Some(ref file) => writeln!(f, "# Read from {}", file.to_string_lossy())?,
+            None => writeln!(f, "# No configuration file")?,
+        }
+        writeln!(
+            f,
+            "output = {:?}  # {}",
+            self.output.value, self.output.source
+        )?;
+        writeln!(
+            f,
+            "backend = {:?}  # {}",
+            self.backend.value, self.backend.source
+        )?;
+        match self.housekeeping_cpu.value {
+            Some(cpu) => writeln!(
+                f,
+                "housekeeping_cpu = {}  # {}",
+                cpu, self.housekeeping_cpu.source
+            )?,
+            None => writeln!(f, "# housekeeping_cpu is not set")?,
+        }
+        match self.event_catalog.value {
+            Some(ref catalog) => writeln!(
+                f,
+                "event_catalog = {:?}  # {}",
+                catalog.to_string_lossy(),
+                self.event_catalog.source
+            )?,
+            None => writeln!(f, "# event_catalog is not set")?,
+        }
+        writeln!(
+            f,
+            "on_existing = {:?}  # {}",
+            self.on_existing.value, self.on_existing.source
+        )
+    }
+}
@@ -0,0 +1,180 @@
+//! Cooling down between the runs of a `pair` campaign (`cooldown` in the
+//! manifest): back-to-back heavy runs heat the machine up, later runs then
+//! start out throttled (or with less turbo headroom) and their configurations
+//! look worse than they are. Before every run we either wait for a fixed delay
+//! (`cooldown = 30`, in seconds), or until every package is cooler than a
+//! threshold (`cooldown = { below = 45 }`, in °C, with a `timeout` in seconds,
+//! 600 by default). The package temperatures come from the coretemp hwmon
+//! driver.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info, warn};
+
+const HWMON: &str = "/sys/class/hwmon";
+
+/// How often we read the temperatures while cooling down.
+const POLL_MS: u64 = 1000;
+
+#[derive(Debug, Clone)]
+pub enum CoolDown {
+    /// Wait this long before every run (except the first).
+    Delay(Duration),
+    /// Wait until all packages are below `celsius`, at most for `timeout`.
+    Temperature { celsius: f64, timeout: Duration },
+}
+
+impl CoolDown {
+    /// The `cooldown` of the manifest.
+    pub fn parse(value: &toml::Value) -> Result<CoolDown, String> {
+        match *value {
+            toml::Value::Integer(secs) if secs >= 0 => {
+                Ok(CoolDown::Delay(Duration::from_secs(secs as u64)))
+            }
+            toml::Value::Table(ref t) => {
+                let celsius = match t.get("below") {
+                    Some(&toml::Value::Integer(c)) => c as f64,
+                    Some(&toml::Value::Float(c)) => c,
+                    _ => {
+                        return Err(String::from(
+                            "'cooldown' needs the temperature (°C) to wait for ('below')",
+                        ))
+                    }
+                };
+                let timeout = match t.get("timeout") {
+                    Some(&toml::Value::Integer(secs)) if secs > 0 => secs as u64,
+                    Some(_) => {
+                        return Err(String::from("'timeout' of 'cooldown' should be seconds"))
+                    }
+                    None => 600,
+                };
+                if let Some(key) = t.keys().find(|k| *k != "below" && *k != "timeout") {
+                    return Err(format!("'cooldown' has an unknown key '{}'", key));
+                }
+                Ok(CoolDown::Temperature {
+                    celsius,
+                    timeout: Duration::from_secs(timeout),
+                })
+            }
+            _ => Err(String::from(
+                "'cooldown' should be seconds or a temperature ({ below = 45 })",
+            )),
+        }
+    }
+}
+
+/// The `temp<n>_input` files of the packages (`Package id <n>`) coretemp knows.
+fn package_sensors() -> Vec<PathBuf> {
+    let mut sensors = Vec::new();
+    let hwmons = match fs::read_dir(HWMON) {
+        Ok(hwmons) => hwmons,
+        Err(_) => return sensors,
+    };
+    for hwmon in hwmons.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let name = fs::read_to_string(hwmon.join("name")).unwrap_or_default();
+        if name.trim() != "coretemp" {
+            continue;
+        }
+        let files = match fs::read_dir(&hwmon) {
+            Ok(files) => files,
+            Err(_) => continue,
+        };
+        for label in files.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let is_label = label
+                .file_name()
+                .is_some_and(|n| n.to_string_lossy().ends_with("_label"));
+            if !is_label {
+                continue;
+            }
+            let text = fs::read_to_string(&label).unwrap_or_default();
+            if text.starts_with("Package id") {
+                let input = label.to_string_lossy().replace("_label", "_input");
+                sensors.push(PathBuf::from(input));
+            }
+        }
+    }
+    sensors.sort();
+    sensors
+}
+
+/// In °C (coretemp reports millidegrees).
+fn temperature(sensor: &Path) -> Option<f64> {
+    let content = fs::read_to_string(sensor).ok()?;
+    f64::from_str(content.trim()).ok().map(|t| t / 1000.0)
+}
+
+/// The temperature of the hottest package.
+fn hottest(sensors: &[PathBuf]) -> Option<f64> {
+    sensors
+        .iter()
+        .filter_map(|s| temperature(s))
+        .fold(None, |max: Option<f64>, t| {
+            Some(max.map_or(t, |m| m.max(t)))
+        })
+}
+
+/// Waits before the runs of a campaign.
+pub struct Cooler {
+    policy: CoolDown,
+    sensors: Vec<PathBuf>,
+    first: bool,
+}
+
+impl Cooler {
+    pub fn new(policy: CoolDown) -> Cooler {
+        let sensors = match policy {
+            CoolDown::Temperature { .. } => package_sensors(),
+            CoolDown::Delay(_) => Vec::new(),
+        };
+        if let CoolDown::Temperature { .. } = policy {
+            if sensors.is_empty() {
+                warn!("Found no package temperatures (is coretemp loaded?), not cooling down.");
+            }
+        }
+        Cooler {
+            policy,
+            sensors,
+            first: true,
+        }
+    }
+
+    /// Cools down before the next run.
+    pub fn wait(&mut self) {
+        let first = self.first;
+        self.first = false;
+        match self.policy {
+            CoolDown::Delay(delay) => {
+                if !first && delay > Duration::from_secs(0) {
+                    debug!("Cooling down for {:?}", delay);
+                    thread::sleep(delay);
+                }
+            }
+            CoolDown::Temperature { celsius, timeout } => {
+                let start = Instant::now();
+                let mut hottest_package = hottest(&self.sensors);
+                while hottest_package.is_some_and(|t| t >= celsius) {
+                    if start.elapsed() >= timeout {
+                        warn!(
+                            "The packages are still at {:.0} °C after {:?}, starting the next run anyway.",
+                            hottest_package.unwrap_or(0.0),
+                            timeout
+                        );
+                        return;
+                    }
+                    thread::sleep(Duration::from_millis(POLL_MS));
+                    hottest_package = hottest(&self.sensors);
+                }
+                if start.elapsed() >= Duration::from_millis(POLL_MS) {
+                    info!(
+                        "Cooled down to {:.0} °C in {:.0} s",
+                        hottest_package.unwrap_or(0.0),
+                        start.elapsed().as_secs_f64()
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! The core types of a hybrid CPU (Alder Lake and later): the P-cores and the
+//! E-cores have a core PMU each (`cpu_core` and `cpu_atom`) and its `cpus` are
+//! the CPUs of that type. `profile` saves them in `core_types.csv`, CPUs of
+//! other machines don't have a type and the file has no rows.
+//!
+//! As a mean over asymmetric cores hides what either core type does,
+//! `aggregate` sums up the counts of every core type on its own and saves them
+//! in `per_core_type.csv` (for every run of a campaign):
+//!
+//! * `CPUS` how many CPUs of the type counted, `IPC` the instructions per cycle
+//!   of all of them,
+//! * `TOTAL` the count of an event on the core type, `PER_CPU` the count per
+//!   CPU and `PKI` per 1000 instructions of the core type.
+//!
+//! perf names the events of a core PMU `cpu_core/cycles/`, those are summed up
+//! as `cycles` so the events of both types have the same name.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::columnar::{ResultWriter, RESULT_COLUMNS};
+use crate::normalize::{CYCLES, INSTRUCTIONS};
+use crate::pci::parse_cpulist;
+use crate::pmu::DEVICES;
+use crate::util::{Cpu, CORE_TYPE_FILE};
+
+/// The columns of `per_core_type.csv` (after `RUN` for a campaign).
+pub const CORE_TYPE_COLUMNS: [&str; 7] = [
+    "CORE_TYPE",
+    "CPUS",
+    "IPC",
+    "EVENT_NAME",
+    "TOTAL",
+    "PER_CPU",
+    "PKI",
+];
+
+/// What we call the core type of PMU `pmu` (`P` for `cpu_core`, `E` for
+/// `cpu_atom`).
+fn core_type(pmu: &str) -> String {
+    match pmu {
+        "cpu_core" => String::from("P"),
+        "cpu_atom" => String::from("E"),
+        _ => String::from(pmu.trim_start_matches("cpu_")),
+    }
+}
+
+/// The CPUs of every core type of this machine (none unless it's hybrid).
+pub fn core_types() -> Vec<(Cpu, String)> {
+    let mut types = Vec::new();
+    let entries = match fs::read_dir(DEVICES) {
+        Ok(entries) => entries,
+        Err(_) => return types,
+    };
+    for device in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let pmu = device.file_name().unwrap().to_string_lossy().into_owned();
+        if !pmu.starts_with("cpu_") {
+            continue;
+        }
+        if let Ok(cpus) = fs::read_to_string(device.join("cpus")) {
+            for cpu in parse_cpulist(cpus.trim()) {
+                types.push((cpu, core_type(&pmu)));
+            }
+        }
+    }
+    types.sort();
+    types
+}
+
+fn to_io_error(e: csv::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Saves the core type of every CPU in `CORE_TYPE_FILE` in `output_path`.
+pub fn save_core_types(output_path: &Path) -> io::Result<Vec<(Cpu, String)>> {
+    let types = core_types();
+    let mut type_file = output_path.to_path_buf();
+    type_file.push(CORE_TYPE_FILE);
+    let mut wtr = csv::Writer::from_file(type_file).map_err(to_io_error)?;
+    wtr.encode(("cpu", "core_type")).map_err(to_io_error)?;
+    for &(cpu, ref core_type) in types.iter() {
+        wtr.encode((cpu, core_type)).map_err(to_io_error)?;
+    }
+    wtr.flush().map_err(to_io_error)?;
+    Ok(types)
+}
+
+/// The core types in `CORE_TYPE_FILE` at `path` (none if there is no such file,
+/// the CPU isn't hybrid then).
+pub fn read_core_types(path: &Path) -> BTreeMap<Cpu, String> {
+    let mut rdr = match csv::Reader::from_file(path) {
+        Ok(rdr) => rdr.has_headers(true),
+        Err(_) => return BTreeMap::new(),
+    };
+    rdr.decode()
+        .filter_map(|row: csv::Result<(Cpu, String)>| row.ok())
+        .collect()
+}
+
+/// `cpu_core/cycles/` is `cycles`, events with terms
+/// (`cpu_core/event=0x3c,umask=0x1/`) and of other PMUs keep their name.
+fn event_name(event: &str) -> String {
+    let mut parts = event.splitn(3, '/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(pmu), Some(name), Some(modifiers))
+            if pmu.starts_with("cpu_") && !name.contains('=') && !name.contains(',') =>
+        {
+            if modifiers.is_empty() {
+                String::from(name)
+            } else {
+                format!("{}:{}", name, modifiers)
+            }
+        }
+        _ => String::from(event),
+    }
+}
+
+/// What the CPUs of a core type counted.
+#[derive(Default)]
+struct Counted {
+    cpus: BTreeSet<Cpu>,
+    /// event -> its count
+    totals: BTreeMap<String, f64>,
+}
+
+impl Counted {
+    fn total(&self, names: &[&str]) -> Option<f64> {
+        names
+            .iter()
+            .filter_map(|n| self.totals.get(*n))
+            .next()
+            .cloned()
+    }
+}
+
+/// Passes the results of a run on and sums up the counts of every core type.
+pub struct CoreTypeWriter<'a> {
+    inner: &'a mut dyn ResultWriter,
+    /// CPU -> its core type
+    core_types: BTreeMap<Cpu, String>,
+    counted: BTreeMap<String, Counted>,
+}
+
+impl<'a> CoreTypeWriter<'a> {
+    pub fn new(
+        inner: &'a mut dyn ResultWriter,
+        core_types: BTreeMap<Cpu, String>,
+    ) -> CoreTypeWriter<'a> {
+        CoreTypeWriter {
+            inner,
+            core_types,
+            counted: BTreeMap::new(),
+        }
+    }
+
+    /// The rows (see `CORE_TYPE_COLUMNS`) of every event of every core type.
+    pub fn per_core_type(self) -> Vec<Vec<String>> {
+        let mut rows = Vec::new();
+        for (core_type, counted) in self.counted {
+            let instructions = counted.total(&INSTRUCTIONS).filter(|i| *i > 0.0);
+            let ipc = match (instructions, counted.total(&CYCLES)) {
+                (Some(instructions), Some(cycles)) if cycles > 0.0 => {
+                    (instructions / cycles).to_string()
+                }
+                _ => String::new(),
+            };
+            let cpus = counted.cpus.len();
+            for (event, total) in counted.totals.iter() {
+                rows.push(vec![
+                    core_type.clone(),
+                    cpus.to_string(),
+                    ipc.clone(),
+                    event.clone(),
+                    total.to_string(),
+                    (total / cpus as f64).to_string(),
+                    instructions.map_or(String::new(), |i| (total * 1000.0 / i).to_string()),
+                ]);
+            }
+        }
+        rows
+    }
+}
+
+impl<'a> ResultWriter for CoreTypeWriter<'a> {
+    fn write_row(&mut self, row: &[&str]) {
+        self.inner.write_row(row);
+        if self.core_types.is_empty() || row.len() < RESULT_COLUMNS.len() || row[7] != "cpu" {
+            return;
+        }
+        let (cpu, value) = match (Cpu::from_str(row[5]), f64::from_str(row[8])) {
+            (Ok(cpu), Ok(value)) => (cpu, value),
+            _ => return,
+        };
+        let core_type = match self.core_types.get(&cpu) {
+            Some(core_type) => core_type,
+            None => return,
+        };
+        let counted = self.counted.entry(core_type.clone()).or_default();
+        counted.cpus.insert(cpu);
+        *counted.totals.entry(event_name(row[0])).or_insert(0.0) += value;
+    }
+
+    /// The inner writer is finished by whoever created it.
+    fn finish(&mut self) {}
+}
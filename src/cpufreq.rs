@@ -0,0 +1,167 @@
+//! The frequency setpoints a `pair` campaign sweeps (`frequencies` in the
+//! manifest): every run is repeated with all CPUs fixed to each setpoint, with
+//! the same programs and deployment, which gives the energy/performance
+//! trade-off of A. We fix a frequency through cpufreq, by setting the
+//! `scaling_min_freq` and `scaling_max_freq` of every CPU to it (this works
+//! with every governor), and restore the limits the CPUs had once the run is
+//! done.
+//!
+//! The setpoints are in MHz, a list (`[1200, 2000, 2800]`) or a range with a
+//! step (`"1200-3000:200"`, both ends included).
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use tracing::{debug, error};
+
+use super::util::Cpu;
+
+const CPU_DEVICES: &str = "/sys/devices/system/cpu";
+
+fn parse_mhz(mhz: &str, spec: &str) -> Result<u64, String> {
+    u64::from_str(mhz.trim())
+        .ok()
+        .filter(|mhz| *mhz > 0)
+        .ok_or(format!(
+            "'{}' in frequencies '{}' isn't a frequency (MHz)",
+            mhz, spec
+        ))
+}
+
+/// `"1200-3000:200"` as 1200, 1400, ..., 3000.
+fn parse_range(spec: &str) -> Result<Vec<u64>, String> {
+    let mut range = spec.splitn(2, ':');
+    let (bounds, step) = (range.next().unwrap_or(""), range.next().unwrap_or("100"));
+    let mut bounds = bounds.splitn(2, '-');
+    let from = parse_mhz(bounds.next().unwrap_or(""), spec)?;
+    let to = match bounds.next() {
+        Some(to) => parse_mhz(to, spec)?,
+        None => from,
+    };
+    let step = parse_mhz(step, spec)?;
+    if to < from {
+        return Err(format!("frequencies '{}' go down", spec));
+    }
+    Ok((from..=to).step_by(step as usize).collect())
+}
+
+/// The setpoints (in MHz) of `frequencies` in the manifest.
+pub fn parse_frequencies(value: &toml::Value) -> Result<Vec<u64>, String> {
+    match *value {
+        toml::Value::String(ref spec) => parse_range(spec),
+        toml::Value::Array(ref setpoints) => setpoints
+            .iter()
+            .map(|s| match s.as_integer() {
+                Some(mhz) if mhz > 0 => Ok(mhz as u64),
+                _ => Err(format!(
+                    "'frequencies' elements should be frequencies (MHz), not {}",
+                    s
+                )),
+            })
+            .collect(),
+        _ => Err(String::from(
+            "'frequencies' should be a list of frequencies (MHz) or a range (\"1200-3000:200\")",
+        )),
+    }
+}
+
+fn cpufreq_file(cpu: Cpu, file: &str) -> PathBuf {
+    PathBuf::from(format!("{}/cpu{}/cpufreq/{}", CPU_DEVICES, cpu, file))
+}
+
+/// A cpufreq file, in kHz.
+fn read_khz(cpu: Cpu, file: &str) -> io::Result<u64> {
+    let content = fs::read_to_string(cpufreq_file(cpu, file))?;
+    u64::from_str(content.trim()).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("cpu{}/cpufreq/{}: {}", cpu, file, e),
+        )
+    })
+}
+
+fn write_khz(cpu: Cpu, file: &str, khz: u64) -> io::Result<()> {
+    fs::write(cpufreq_file(cpu, file), khz.to_string()).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("can't set cpu{}/cpufreq/{}: {}", cpu, file, e),
+        )
+    })
+}
+
+/// The CPUs cpufreq manages.
+pub fn cpus() -> Vec<Cpu> {
+    let mut cpus: Vec<Cpu> = fs::read_dir(CPU_DEVICES)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let name = e.file_name().to_string_lossy().into_owned();
+                    name.strip_prefix("cpu").and_then(|n| Cpu::from_str(n).ok())
+                })
+                .filter(|cpu| cpufreq_file(*cpu, "scaling_max_freq").exists())
+                .collect()
+        })
+        .unwrap_or_default();
+    cpus.sort();
+    cpus
+}
+
+/// The lowest and highest frequency (in MHz) every CPU can run at.
+pub fn limits() -> io::Result<(u64, u64)> {
+    let cpus = cpus();
+    if cpus.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no CPU in {} has cpufreq", CPU_DEVICES),
+        ));
+    }
+    let mut limits = (0, u64::MAX);
+    for cpu in cpus {
+        limits.0 = limits.0.max(read_khz(cpu, "cpuinfo_min_freq")? / 1000);
+        limits.1 = limits.1.min(read_khz(cpu, "cpuinfo_max_freq")? / 1000);
+    }
+    Ok(limits)
+}
+
+/// All CPUs fixed to a frequency, until it's dropped.
+pub struct FrequencyLock {
+    /// (cpu, lowest frequency, scaling_min_freq, scaling_max_freq) in kHz, as
+    /// they were before.
+    saved: Vec<(Cpu, u64, u64, u64)>,
+}
+
+impl FrequencyLock {
+    /// Fixes all CPUs to `mhz`.
+    pub fn fix(mhz: u64) -> io::Result<FrequencyLock> {
+        let khz = mhz * 1000;
+        let mut lock = FrequencyLock { saved: Vec::new() };
+        for cpu in cpus() {
+            let lowest = read_khz(cpu, "cpuinfo_min_freq")?;
+            let min = read_khz(cpu, "scaling_min_freq")?;
+            let max = read_khz(cpu, "scaling_max_freq")?;
+            // Restored on drop, even if we fail half-way:
+            lock.saved.push((cpu, lowest, min, max));
+            // The minimum can't be above the maximum (and vice versa) at any time:
+            write_khz(cpu, "scaling_min_freq", lowest)?;
+            write_khz(cpu, "scaling_max_freq", khz)?;
+            write_khz(cpu, "scaling_min_freq", khz)?;
+        }
+        debug!("Fixed {} CPUs to {} MHz", lock.saved.len(), mhz);
+        Ok(lock)
+    }
+}
+
+impl Drop for FrequencyLock {
+    fn drop(&mut self) {
+        for &(cpu, lowest, min, max) in self.saved.iter() {
+            let r = write_khz(cpu, "scaling_min_freq", lowest)
+                .and_then(|_| write_khz(cpu, "scaling_max_freq", max))
+                .and_then(|_| write_khz(cpu, "scaling_min_freq", min));
+            if let Err(e) = r {
+                error!("Can't restore the frequency of CPU {}: {}", cpu, e);
+            }
+        }
+    }
+}
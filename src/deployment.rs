@@ -0,0 +1,207 @@
+//! Deployments: how a campaign places program A and B on the machine (on the
+//! same SMT threads, the same L3, different L3s, next to the NIC or away from
+//! it...) and which memory they use.
+use std::fmt;
+
+use super::bytesize::ByteSize;
+use super::util::*;
+
+/// The deployments (placements of A and B) we know.
+pub const DEPLOYMENTS: [&str; 10] = [
+    "L1-SMT",
+    "L3-SMT",
+    "L3-SMT-cores",
+    "L3-cores",
+    "Full-L3",
+    "Full-SMT-L3",
+    "Full-cores",
+    "Full-SMT-cores",
+    "NIC-local",
+    "NIC-remote",
+];
+
+#[derive(Debug, RustcEncodable)]
+pub struct Deployment<'a> {
+    pub description: &'static str,
+    pub a: Vec<&'a CpuInfo>,
+    pub b: Vec<&'a CpuInfo>,
+    pub mem: Vec<NodeInfo>,
+}
+
+impl<'a> Deployment<'a> {
+    pub fn new(
+        desc: &'static str,
+        half_a: Vec<&'a CpuInfo>,
+        half_b: Vec<&'a CpuInfo>,
+        mem: Vec<NodeInfo>,
+    ) -> Deployment<'a> {
+        Deployment {
+            description: desc,
+            a: half_a,
+            b: half_b,
+            mem,
+        }
+    }
+
+    /// Split by just simply interleaving everything
+    /// TODO: this only works because we make assumption on how CpuInfo is ordered..
+    pub fn split_interleaved(
+        desc: &'static str,
+        possible_groupings: Vec<Vec<&'a CpuInfo>>,
+        size: u64,
+    ) -> Deployment<'a> {
+        let mut cpus = possible_groupings.into_iter().last().unwrap();
+
+        let cpus_len = cpus.len();
+        assert!(cpus_len.is_multiple_of(2));
+
+        let upper_half = cpus.split_off(cpus_len / 2);
+        let lower_half = cpus;
+
+        let mut node: NodeInfo = lower_half[0].node;
+        node.memory = Some(size);
+
+        Deployment::new(desc, lower_half, upper_half, vec![node])
+    }
+
+    /// Split but makes sure a group shares the SMT threads
+    pub fn split_smt_aware(
+        desc: &'static str,
+        possible_groupings: Vec<Vec<&'a CpuInfo>>,
+        size: u64,
+    ) -> Deployment<'a> {
+        let cpus = possible_groupings.into_iter().last().unwrap();
+        let cpus_len = cpus.len();
+        assert!(cpus_len.is_multiple_of(2));
+
+        let mut cores: Vec<Core> = cpus.iter().map(|c| c.core).collect();
+        assert!(cores.len().is_multiple_of(2));
+        cores.sort();
+        cores.dedup();
+
+        let mut upper_half: Vec<&CpuInfo> = Vec::with_capacity(cpus_len / 2);
+        let mut lower_half: Vec<&CpuInfo> = Vec::with_capacity(cpus_len / 2);
+
+        for (i, core) in cores.into_iter().enumerate() {
+            let cpus_on_core: Vec<&&CpuInfo> = cpus.iter().filter(|c| c.core == core).collect();
+            if i % 2 == 0 {
+                lower_half.extend(cpus_on_core);
+            } else {
+                upper_half.extend(cpus_on_core);
+            }
+        }
+
+        let mut node: NodeInfo = lower_half[0].node;
+        node.memory = Some(size);
+
+        Deployment::new(desc, lower_half, upper_half, vec![node])
+    }
+
+    /// Split but makes sure a group shares the SMT threads
+    pub fn split_l3_aware(
+        desc: &'static str,
+        possible_groupings: Vec<Vec<&'a CpuInfo>>,
+        size: u64,
+    ) -> Deployment<'a> {
+        let cpus = possible_groupings.into_iter().last().unwrap();
+        let cpus_len = cpus.len();
+        assert!(cpus_len.is_multiple_of(2));
+
+        let mut l3s: Vec<L3> = cpus.iter().map(|c| c.l3).collect();
+        assert!(l3s.len().is_multiple_of(2));
+        l3s.sort();
+        l3s.dedup();
+
+        let mut upper_half: Vec<&CpuInfo> = Vec::with_capacity(cpus_len / 2);
+        let mut lower_half: Vec<&CpuInfo> = Vec::with_capacity(cpus_len / 2);
+
+        for (i, l3) in l3s.into_iter().enumerate() {
+            let cpus_on_l3: Vec<&&CpuInfo> = cpus.iter().filter(|c| c.l3 == l3).collect();
+            if i % 2 == 0 {
+                upper_half.extend(cpus_on_l3);
+            } else {
+                lower_half.extend(cpus_on_l3);
+            }
+        }
+
+        let mut node: NodeInfo = lower_half[0].node;
+        node.memory = Some(size);
+
+        Deployment::new(desc, lower_half, upper_half, vec![node])
+    }
+}
+
+/// The NIC the `NIC-*` deployments place A and B by: the first one (by name) the
+/// machine places on a node.
+fn nic(mt: &MachineTopology) -> Option<&str> {
+    mt.nics()
+        .iter()
+        .find(|n| n.node.is_some())
+        .map(|n| n.name.as_str())
+}
+
+/// Deployment `config` with the CPUs of the last group it applies to, if `socket`
+/// is set only of the groups that are entirely on that socket.
+pub fn deployment<'a>(
+    config: &str,
+    mt: &'a MachineTopology,
+    socket: Option<Socket>,
+) -> Option<Deployment<'a>> {
+    let desc: &'static str = DEPLOYMENTS.iter().find(|d| **d == config)?;
+    let (groupings, size) = match desc {
+        "L1-SMT" => (mt.same_l1(), mt.l1_size()),
+        "L3-SMT" | "L3-SMT-cores" => (mt.same_l3(), mt.l3_size()),
+        "L3-cores" => (mt.same_l3_cores(), mt.l3_size()),
+        "Full-L3" | "Full-cores" => (mt.whole_machine_cores(), mt.l3_size()),
+        // The node of the NIC, or another one:
+        "NIC-local" | "NIC-remote" => match nic(mt).and_then(|nic| mt.device_node(nic)) {
+            Some(node) => (
+                mt.same_node()
+                    .into_iter()
+                    .filter(|g| (g[0].node.node == node) == (desc == "NIC-local"))
+                    .collect(),
+                mt.l3_size(),
+            ),
+            None => (Vec::new(), None),
+        },
+        _ => (mt.whole_machine(), mt.l3_size()),
+    };
+    let groupings: Vec<Vec<&CpuInfo>> = match socket {
+        Some(socket) => groupings
+            .into_iter()
+            .filter(|g| g.iter().all(|c| c.socket == socket))
+            .collect(),
+        None => groupings,
+    };
+    if groupings.is_empty() {
+        return None;
+    }
+    let size = size.unwrap_or(0);
+
+    Some(match desc {
+        "L1-SMT" | "L3-SMT" | "Full-cores" => Deployment::split_interleaved(desc, groupings, size),
+        "L3-SMT-cores" | "L3-cores" | "Full-SMT-cores" | "NIC-local" | "NIC-remote" => {
+            Deployment::split_smt_aware(desc, groupings, size)
+        }
+        _ => Deployment::split_l3_aware(desc, groupings, size),
+    })
+}
+
+impl<'a> fmt::Display for Deployment<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let a: Vec<Cpu> = self.a.iter().map(|c| c.cpu).collect();
+        let b: Vec<Cpu> = self.b.iter().map(|c| c.cpu).collect();
+
+        writeln!(f, "Deployment Plan for {}:", self.description)?;
+        writeln!(f, "-- Program A cores: {:?}", a)?;
+        writeln!(f, "-- Program B cores: {:?}", b)?;
+        writeln!(f, "-- Use memory:")?;
+        for n in self.mem.iter() {
+            match n.memory {
+                Some(bytes) => writeln!(f, " - On node {}: {}", n.node, ByteSize(bytes))?,
+                None => writeln!(f, " - On node {}: no memory", n.node)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,535 @@
+//! Compares the results of two profiles or `pair` campaigns.
+//!
+//! Configurations are matched by their path in the result directories, the
+//! totals an event had in the repetitions of a configuration are the samples we
+//! compare: Welch's t-test and the Mann-Whitney U test tell whether the
+//! difference is significant, Cohen's d and Cliff's delta how large it is. The
+//! intervals of a run aren't independent samples (they are autocorrelated, the
+//! tests would find nearly everything significant), so a configuration needs at
+//! least 2 repetitions in both directories to get p-values.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use tracing::{debug, error, info, warn};
+
+use super::aggregate::{aggregate_into, campaign_runs};
+use super::archive;
+use super::bootstrap::difference_interval;
+use super::columnar::{BufferWriter, ResultWriter, RESULT_COLUMNS};
+use super::index;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiffSettings {
+    /// Differences with a (Mann-Whitney) p-value below this are significant.
+    pub alpha: f64,
+    /// Also report the differences that aren't significant.
+    pub all: bool,
+    /// How often we resample for the confidence interval of a difference.
+    pub bootstrap: usize,
+}
+
+/// The comparison of one event in a configuration.
+#[derive(Debug)]
+struct Difference {
+    configuration: String,
+    event: String,
+    n_a: usize,
+    n_b: usize,
+    mean_a: f64,
+    mean_b: f64,
+    /// The p-values and Cohen's d (None with fewer than 2 repetitions).
+    t_test: Option<f64>,
+    mann_whitney: Option<f64>,
+    cohens_d: Option<f64>,
+    cliffs_delta: f64,
+    /// Confidence interval of `mean_b - mean_a`.
+    interval: Option<(f64, f64)>,
+}
+
+/// Sums up the values of every event per interval (over all locations).
+pub struct IntervalTotals {
+    /// event -> interval index -> value
+    values: BTreeMap<String, BTreeMap<u64, f64>>,
+}
+
+impl IntervalTotals {
+    pub fn new() -> IntervalTotals {
+        IntervalTotals {
+            values: BTreeMap::new(),
+        }
+    }
+
+    /// The totals of every interval of every event, in order.
+    pub fn into_totals(self) -> BTreeMap<String, Vec<f64>> {
+        self.values
+            .into_iter()
+            .map(|(event, values)| (event, values.into_values().collect()))
+            .collect()
+    }
+}
+
+impl ResultWriter for IntervalTotals {
+    fn write_row(&mut self, row: &[&str]) {
+        if row.len() < RESULT_COLUMNS.len() {
+            // Sampled (perf record) results don't have all the columns
+            return;
+        }
+        let (index, value) = match (u64::from_str(row[1]), f64::from_str(row[8])) {
+            (Ok(index), Ok(value)) => (index, value),
+            _ => return,
+        };
+        *self
+            .values
+            .entry(String::from(row[0]))
+            .or_default()
+            .entry(index)
+            .or_insert(0.0) += value;
+    }
+
+    fn finish(&mut self) {}
+}
+
+/// The configurations in a result directory (name -> run directory or archive).
+pub fn configurations(path: &Path) -> BTreeMap<String, PathBuf> {
+    let mut perf_csv = path.to_path_buf();
+    perf_csv.push("perf.csv");
+    let mut configurations = BTreeMap::new();
+    if perf_csv.exists() {
+        configurations.insert(String::new(), path.to_path_buf());
+        return configurations;
+    }
+
+    let mut runs = Vec::new();
+    campaign_runs(path, &mut runs);
+    for run in runs {
+        let name = run
+            .strip_prefix(path)
+            .unwrap_or(&run)
+            .to_string_lossy()
+            .trim_end_matches(archive::ARCHIVE_EXTENSION)
+            .to_string();
+        configurations.insert(name, run);
+    }
+    configurations
+}
+
+/// The configuration of a repeated run (`<id><separator><k>`, see
+/// `pair::Run::refined`) and the repetition.
+fn split_repetition<'a>(id: &'a str, separator: &str) -> (&'a str, Option<u64>) {
+    match id.rfind(separator) {
+        Some(idx) => match u64::from_str(&id[idx + separator.len()..]) {
+            Ok(k) => (&id[..idx], Some(k)),
+            Err(_) => (id, None),
+        },
+        None => (id, None),
+    }
+}
+
+/// The runs of every configuration in a result directory (name -> the run and
+/// its repetitions). A repetition has the run ID of its run with `-r<k>` (and
+/// the directory with `_rep<k>`), we go by the IDs of the campaign's index and
+/// by the directories without one.
+fn repetitions(path: &Path) -> BTreeMap<String, Vec<PathBuf>> {
+    let run_ids: BTreeMap<PathBuf, String> = index::read(path)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| (path.join(entry.path), entry.run_id))
+        .collect();
+    // ID of the configuration -> (name, runs)
+    let mut groups: BTreeMap<String, (String, Vec<PathBuf>)> = BTreeMap::new();
+    for (name, run) in configurations(path) {
+        let name = String::from(split_repetition(&name, "_rep").0);
+        let id = match run_ids.get(&run) {
+            Some(run_id) => String::from(split_repetition(run_id, "-r").0),
+            None => name.clone(),
+        };
+        groups.entry(id).or_insert((name, Vec::new())).1.push(run);
+    }
+    groups.into_values().collect()
+}
+
+/// Extracts `run` into `tmp` if it's an archive, returns the run directory.
+pub fn run_directory(run: &Path, tmp: &Path) -> PathBuf {
+    if archive::is_archive(run) {
+        match archive::extract(run, tmp) {
+            Ok(dir) => dir,
+            Err(e) => {
+                error!("Can't extract {:?}: {}", run, e);
+                process::exit(1);
+            }
+        }
+    } else {
+        run.to_path_buf()
+    }
+}
+
+/// The interval totals of every event in a run directory (of the files we
+/// could aggregate).
+pub fn interval_totals(run_directory: &Path) -> BTreeMap<String, Vec<f64>> {
+    let mut totals = IntervalTotals::new();
+    let aggregated = aggregate_into(
+        run_directory,
+        "all",
+        "all",
+        &mut totals,
+        &mut BufferWriter::new(),
+        &mut BufferWriter::new(),
+    );
+    if let Err(e) = aggregated {
+        error!("Can't aggregate {:?}: {}", run_directory, e);
+    }
+    totals.into_totals()
+}
+
+/// The total of every event in a run (directory or archive).
+fn run_totals(run: &Path, tmp: &Path) -> BTreeMap<String, f64> {
+    let totals = interval_totals(&run_directory(run, tmp));
+    if archive::is_archive(run) {
+        let _ = fs::remove_dir_all(tmp);
+    }
+    totals
+        .into_iter()
+        .map(|(event, values)| (event, values.iter().sum()))
+        .collect()
+}
+
+/// The totals of every event in `runs` (the repetitions of a configuration).
+fn repetition_totals(runs: &[PathBuf], tmp: &Path) -> BTreeMap<String, Vec<f64>> {
+    let mut totals: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for run in runs {
+        for (event, total) in run_totals(run, tmp) {
+            totals.entry(event).or_default().push(total);
+        }
+    }
+    totals
+}
+
+pub fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Sample variance.
+pub fn variance(values: &[f64], mean: f64) -> f64 {
+    values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64
+}
+
+/// ln(Γ(x)) (Lanczos approximation).
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let mut y = x;
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut series = 1.000000000190015;
+    for c in COEFFICIENTS.iter() {
+        y += 1.0;
+        series += c / y;
+    }
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+/// Continued fraction for the incomplete beta function.
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64 {
+    const MAX_ITERATIONS: usize = 200;
+    const EPSILON: f64 = 3.0e-12;
+    const FPMIN: f64 = 1.0e-300;
+
+    let (qab, qap, qam) = (a + b, a + 1.0, a - 1.0);
+    let mut c = 1.0;
+    let mut d = 1.0 - qab * x / qap;
+    if d.abs() < FPMIN {
+        d = FPMIN;
+    }
+    d = 1.0 / d;
+    let mut h = d;
+    for m in 1..=MAX_ITERATIONS {
+        let m = m as f64;
+        let m2 = 2.0 * m;
+        let aa = m * (b - m) * x / ((qam + m2) * (a + m2));
+        d = 1.0 + aa * d;
+        if d.abs() < FPMIN {
+            d = FPMIN;
+        }
+        c = 1.0 + aa / c;
+        if c.abs() < FPMIN {
+            c = FPMIN;
+        }
+        d = 1.0 / d;
+        h *= d * c;
+        let aa = -(a + m) * (qab + m) * x / ((a + m2) * (qap + m2));
+        d = 1.0 + aa * d;
+        if d.abs() < FPMIN {
+            d = FPMIN;
+        }
+        c = 1.0 + aa / c;
+        if c.abs() < FPMIN {
+            c = FPMIN;
+        }
+        d = 1.0 / d;
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.0).abs() < EPSILON {
+            break;
+        }
+    }
+    h
+}
+
+/// The regularized incomplete beta function I_x(a, b).
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let front =
+        (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_fraction(a, b, x) / a
+    } else {
+        1.0 - front * beta_fraction(b, a, 1.0 - x) / b
+    }
+}
+
+/// Complementary error function (Chebyshev approximation, relative error < 1.2e-7).
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
+/// Two-sided p-value of Welch's t-test.
+fn welch_t_test(a: &[f64], b: &[f64]) -> f64 {
+    let (mean_a, mean_b) = (mean(a), mean(b));
+    let se_a = variance(a, mean_a) / a.len() as f64;
+    let se_b = variance(b, mean_b) / b.len() as f64;
+    if se_a + se_b == 0.0 {
+        return if mean_a == mean_b { 1.0 } else { 0.0 };
+    }
+    let t = (mean_a - mean_b) / (se_a + se_b).sqrt();
+    let df = (se_a + se_b).powi(2)
+        / (se_a.powi(2) / (a.len() - 1) as f64 + se_b.powi(2) / (b.len() - 1) as f64);
+    incomplete_beta(df / 2.0, 0.5, df / (df + t * t))
+}
+
+/// Two-sided p-value of the Mann-Whitney U test (normal approximation with tie
+/// and continuity correction) and Cliff's delta (how much more likely a value of
+/// `b` is larger than one of `a` than the other way around).
+pub fn mann_whitney(a: &[f64], b: &[f64]) -> (f64, f64) {
+    let mut values: Vec<(f64, bool)> = a.iter().map(|v| (*v, true)).collect();
+    values.extend(b.iter().map(|v| (*v, false)));
+    values.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    let n = values.len() as f64;
+    let (n_a, n_b) = (a.len() as f64, b.len() as f64);
+    let mut rank_sum_a = 0.0;
+    let mut ties = 0.0;
+    let mut i = 0;
+    while i < values.len() {
+        let mut j = i;
+        while j + 1 < values.len() && values[j + 1].0 == values[i].0 {
+            j += 1;
+        }
+        // Tied values share the average of their ranks:
+        let rank = (i + j) as f64 / 2.0 + 1.0;
+        let count = (j - i + 1) as f64;
+        ties += count.powi(3) - count;
+        rank_sum_a += rank * values[i..=j].iter().filter(|v| v.1).count() as f64;
+        i = j + 1;
+    }
+
+    let u_a = rank_sum_a - n_a * (n_a + 1.0) / 2.0;
+    let u_b = n_a * n_b - u_a;
+    let cliffs_delta = (u_b - u_a) / (n_a * n_b);
+
+    let sigma = (n_a * n_b / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)))).sqrt();
+    if sigma == 0.0 {
+        return (1.0, cliffs_delta);
+    }
+    let z = ((u_a - n_a * n_b / 2.0).abs() - 0.5).max(0.0) / sigma;
+    (erfc(z / 2f64.sqrt()).min(1.0), cliffs_delta)
+}
+
+fn compare(
+    configuration: &str,
+    event: &str,
+    a: &[f64],
+    b: &[f64],
+    iterations: usize,
+) -> Difference {
+    let (mean_a, mean_b) = (mean(a), mean(b));
+    let (mann_whitney, cliffs_delta) = mann_whitney(a, b);
+    let (t_test, mann_whitney, cohens_d) = if a.len() > 1 && b.len() > 1 {
+        let pooled = (((a.len() - 1) as f64 * variance(a, mean_a)
+            + (b.len() - 1) as f64 * variance(b, mean_b))
+            / (a.len() + b.len() - 2) as f64)
+            .sqrt();
+        let cohens_d = if pooled > 0.0 {
+            (mean_b - mean_a) / pooled
+        } else {
+            0.0
+        };
+        (Some(welch_t_test(a, b)), Some(mann_whitney), Some(cohens_d))
+    } else {
+        (None, None, None)
+    };
+    Difference {
+        configuration: String::from(configuration),
+        event: String::from(event),
+        n_a: a.len(),
+        n_b: b.len(),
+        mean_a,
+        mean_b,
+        t_test,
+        mann_whitney,
+        cohens_d,
+        cliffs_delta,
+        interval: difference_interval(a, b, iterations),
+    }
+}
+
+/// Compares the results in `a` and `b` and prints the (significant) differences,
+/// most significant first, as CSV to `out`. Returns how many rows were printed.
+pub fn diff<W: io::Write>(
+    a: &Path,
+    b: &Path,
+    settings: &DiffSettings,
+    out: W,
+) -> io::Result<usize> {
+    let configurations_a = repetitions(a);
+    let configurations_b = repetitions(b);
+    let matched: Vec<&String> = configurations_a
+        .keys()
+        .filter(|c| configurations_b.contains_key(*c))
+        .collect();
+    let unmatched = configurations_a.len() + configurations_b.len() - 2 * matched.len();
+    if unmatched > 0 {
+        warn!(
+            "{} configurations are only in one of the result directories, ignoring them.",
+            unmatched
+        );
+    }
+    info!("Comparing {} configurations.", matched.len());
+    let unrepeated = matched
+        .iter()
+        .filter(|c| configurations_a[**c].len() < 2 || configurations_b[**c].len() < 2)
+        .count();
+    if unrepeated > 0 {
+        warn!(
+            "{} configurations don't have 2 repetitions in both result directories, \
+             they have no p-values.",
+            unrepeated
+        );
+    }
+
+    let mut tmp = std::env::temp_dir();
+    tmp.push(format!("autoperf-{}", process::id()));
+    let mut differences = Vec::new();
+    for configuration in matched {
+        debug!("Comparing {}", configuration);
+        let totals_a = repetition_totals(&configurations_a[configuration], &tmp);
+        let totals_b = repetition_totals(&configurations_b[configuration], &tmp);
+        for (event, values_a) in totals_a.iter() {
+            if let Some(values_b) = totals_b.get(event) {
+                differences.push(compare(
+                    configuration,
+                    event,
+                    values_a,
+                    values_b,
+                    settings.bootstrap,
+                ));
+            }
+        }
+    }
+
+    differences.retain(|d| settings.all || d.mann_whitney.is_some_and(|p| p < settings.alpha));
+    // Without p-values last:
+    let p_value = |d: &Difference| d.mann_whitney.unwrap_or(f64::INFINITY);
+    differences.sort_by(|x, y| {
+        p_value(x)
+            .partial_cmp(&p_value(y))
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(
+                y.cliffs_delta
+                    .abs()
+                    .partial_cmp(&x.cliffs_delta.abs())
+                    .unwrap_or(std::cmp::Ordering::Equal),
+            )
+    });
+
+    let mut wtr = csv::Writer::from_writer(out);
+    wtr.encode([
+        "CONFIGURATION",
+        "EVENT_NAME",
+        "N_A",
+        "N_B",
+        "MEAN_A",
+        "MEAN_B",
+        "DELTA_PERCENT",
+        "DELTA_LOWER",
+        "DELTA_UPPER",
+        "T_TEST_P",
+        "MANN_WHITNEY_P",
+        "COHENS_D",
+        "CLIFFS_DELTA",
+    ])
+    .unwrap();
+    for d in differences.iter() {
+        let percent = |difference: f64| {
+            if d.mean_a != 0.0 {
+                (difference / d.mean_a * 100.0).to_string()
+            } else {
+                String::new()
+            }
+        };
+        let (lower, upper) = match d.interval {
+            Some((lower, upper)) => (percent(lower), percent(upper)),
+            None => (String::new(), String::new()),
+        };
+        // More columns than rustc_serialize encodes tuples of:
+        wtr.encode(vec![
+            d.configuration.clone(),
+            d.event.clone(),
+            d.n_a.to_string(),
+            d.n_b.to_string(),
+            d.mean_a.to_string(),
+            d.mean_b.to_string(),
+            percent(d.mean_b - d.mean_a),
+            lower,
+            upper,
+            d.t_test.map_or(String::new(), |p| p.to_string()),
+            d.mann_whitney.map_or(String::new(), |p| p.to_string()),
+            d.cohens_d.map_or(String::new(), |d| d.to_string()),
+            d.cliffs_delta.to_string(),
+        ])
+        .unwrap();
+    }
+    wtr.flush().unwrap();
+
+    Ok(differences.len())
+}
@@ -0,0 +1,303 @@
+//! Compares what two campaigns (or profiles) were run with, instead of what they
+//! measured: the manifest, the machine (`machine.json`) and how every run was
+//! set up (`run.toml` and the events in its `perf.csv`). When two campaigns
+//! don't agree, a different kernel, governor or placement usually explains it
+//! before any statistics do.
+//!
+//! Everything is flattened into keys (`experiment.configurations`,
+//! `program[gcc].binary`, `kernel_release`, `deployment.a`, ...) and we print
+//! the keys that have different values, as CSV. Runs are matched by their path
+//! (like `diff` does), values that are paths in one of the campaigns start with
+//! `$CAMPAIGN` or `$MANIFEST_DIR`, so two copies of a campaign don't differ
+//! everywhere.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use rustc_serialize::json::Json;
+use tracing::{debug, warn};
+
+use super::aggregate::read_perf_log;
+use super::archive;
+use super::diff::{configurations, run_directory};
+
+/// Columns of what `diff_config` prints.
+pub const DIFF_CONFIG_COLUMNS: [&str; 4] = ["WHERE", "KEY", "A", "B"];
+
+/// Flattened settings (key -> value).
+type Settings = BTreeMap<String, String>;
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        String::from(key)
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+fn toml_scalar(value: &toml::Value) -> String {
+    match *value {
+        toml::Value::String(ref s) => s.clone(),
+        toml::Value::Integer(i) => i.to_string(),
+        toml::Value::Float(f) => f.to_string(),
+        toml::Value::Boolean(b) => b.to_string(),
+        toml::Value::Datetime(ref d) => d.clone(),
+        toml::Value::Array(ref items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(toml_scalar)
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        toml::Value::Table(_) => String::from("{...}"),
+    }
+}
+
+/// Tables are flattened into their keys, arrays of tables (`[[program]]`) by
+/// their `name` (or their index, if they don't have one).
+fn flatten_toml(prefix: &str, value: &toml::Value, into: &mut Settings) {
+    match *value {
+        toml::Value::Table(ref table) => {
+            for (key, value) in table.iter() {
+                flatten_toml(&join_key(prefix, key), value, into);
+            }
+        }
+        toml::Value::Array(ref items)
+            if !items.is_empty() && items.iter().all(|i| i.as_table().is_some()) =>
+        {
+            for (idx, item) in items.iter().enumerate() {
+                let name = item
+                    .lookup("name")
+                    .and_then(|n| n.as_str())
+                    .map_or(idx.to_string(), String::from);
+                flatten_toml(&format!("{}[{}]", prefix, name), item, into);
+            }
+        }
+        _ => {
+            into.insert(String::from(prefix), toml_scalar(value));
+        }
+    }
+}
+
+/// Objects are flattened into their keys, arrays are values.
+fn flatten_json(prefix: &str, value: &Json, into: &mut Settings) {
+    match *value {
+        Json::Object(ref members) => {
+            for (key, value) in members.iter() {
+                flatten_json(&join_key(prefix, key), value, into);
+            }
+        }
+        Json::String(ref s) => {
+            into.insert(String::from(prefix), s.clone());
+        }
+        Json::Null => {
+            into.insert(String::from(prefix), String::new());
+        }
+        _ => {
+            into.insert(String::from(prefix), value.to_string());
+        }
+    }
+}
+
+/// Where a campaign is, to make the paths in it comparable.
+struct Campaign {
+    directory: String,
+    manifest_dir: String,
+}
+
+impl Campaign {
+    fn new(path: &Path) -> Campaign {
+        let directory = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        let manifest_dir = directory
+            .parent()
+            .map_or(PathBuf::new(), |p| p.to_path_buf());
+        Campaign {
+            directory: directory.to_string_lossy().into_owned(),
+            manifest_dir: manifest_dir.to_string_lossy().into_owned(),
+        }
+    }
+
+    fn portable(&self, settings: Settings) -> Settings {
+        settings
+            .into_iter()
+            .map(|(key, value)| {
+                let mut value = value.replace(self.directory.as_str(), "$CAMPAIGN");
+                if !self.manifest_dir.is_empty() && self.manifest_dir != "/" {
+                    value = value.replace(self.manifest_dir.as_str(), "$MANIFEST_DIR");
+                }
+                (key, value)
+            })
+            .collect()
+    }
+}
+
+fn read_toml(path: &Path) -> Option<Settings> {
+    let content = fs::read_to_string(path).ok()?;
+    let table = toml::Parser::new(&content).parse()?;
+    let mut settings = Settings::new();
+    flatten_toml("", &toml::Value::Table(table), &mut settings);
+    Some(settings)
+}
+
+/// The manifest of a campaign: the copy `pair` saves in it, or the one next to it.
+fn manifest(path: &Path) -> Option<Settings> {
+    let copy = path.join("manifest.toml");
+    if copy.exists() {
+        return read_toml(&copy);
+    }
+    path.parent()
+        .and_then(|p| read_toml(&p.join("manifest.toml")))
+}
+
+/// The machine a run was measured on, the governors of all CPUs condensed to how
+/// many CPUs have which.
+fn machine(run_directory: &Path) -> Option<Settings> {
+    let content = fs::read_to_string(run_directory.join("machine.json")).ok()?;
+    let json = Json::from_str(&content).ok()?;
+    let mut flat = Settings::new();
+    flatten_json("", &json, &mut flat);
+
+    let mut governors: BTreeMap<String, usize> = BTreeMap::new();
+    flat.retain(|key, value| {
+        if key.starts_with("governors.") {
+            *governors.entry(value.clone()).or_insert(0) += 1;
+            false
+        } else {
+            true
+        }
+    });
+    if !governors.is_empty() {
+        let counted: Vec<String> = governors
+            .iter()
+            .map(|(governor, cpus)| format!("{} ({} CPUs)", governor, cpus))
+            .collect();
+        flat.insert(String::from("governors"), counted.join(", "));
+    }
+    Some(flat)
+}
+
+/// How a run was set up: its `run.toml` and the events it measured.
+fn run_settings(run_directory: &Path) -> Settings {
+    let mut settings = read_toml(&run_directory.join("run.toml")).unwrap_or_default();
+    let perf_csv = run_directory.join("perf.csv");
+    if perf_csv.exists() {
+        let (_, rows) = read_perf_log(&perf_csv);
+        settings.insert(String::from("measurements"), rows.len().to_string());
+        for (_, event_names, _, _, _, _) in rows {
+            for event in event_names.split(',').filter(|e| !e.is_empty()) {
+                settings.insert(format!("events.{}", event), String::from("measured"));
+            }
+        }
+    }
+    settings
+}
+
+/// The keys that differ between `a` and `b`, as rows.
+fn compare(location: &str, a: &Settings, b: &Settings, rows: &mut Vec<[String; 4]>) {
+    let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+    for key in keys {
+        let (value_a, value_b) = (a.get(key), b.get(key));
+        if value_a != value_b {
+            rows.push([
+                String::from(location),
+                key.clone(),
+                value_a.cloned().unwrap_or(String::new()),
+                value_b.cloned().unwrap_or(String::new()),
+            ]);
+        }
+    }
+}
+
+/// The settings of a run (directory or archive) and the machine it ran on.
+fn read_run(run: &Path, tmp: &Path) -> (Settings, Option<Settings>) {
+    let directory = run_directory(run, tmp);
+    let settings = (run_settings(&directory), machine(&directory));
+    if archive::is_archive(run) {
+        let _ = fs::remove_dir_all(tmp);
+    }
+    settings
+}
+
+/// Compares the setup of the campaigns (or profiles) `a` and `b` and prints what
+/// differs as CSV to `out` (see `DIFF_CONFIG_COLUMNS`). Returns how many rows
+/// were printed.
+pub fn diff_config<W: io::Write>(a: &Path, b: &Path, out: W) -> io::Result<usize> {
+    let (campaign_a, campaign_b) = (Campaign::new(a), Campaign::new(b));
+    let mut rows: Vec<[String; 4]> = Vec::new();
+
+    match (manifest(a), manifest(b)) {
+        (Some(manifest_a), Some(manifest_b)) => compare(
+            "manifest",
+            &campaign_a.portable(manifest_a),
+            &campaign_b.portable(manifest_b),
+            &mut rows,
+        ),
+        (None, None) => debug!("Neither {:?} nor {:?} has a manifest", a, b),
+        _ => warn!("Only one of {:?} and {:?} has a manifest.", a, b),
+    }
+
+    let mut tmp = std::env::temp_dir();
+    tmp.push(format!("autoperf-{}", process::id()));
+    let runs_a = configurations(a);
+    let runs_b = configurations(b);
+    // The machine is the same for all runs of a campaign, we compare the first:
+    let mut machines: (Option<Settings>, Option<Settings>) = (None, None);
+    let names: BTreeSet<&String> = runs_a.keys().chain(runs_b.keys()).collect();
+    for name in names {
+        let location = if name.is_empty() {
+            "run"
+        } else {
+            name.as_str()
+        };
+        match (runs_a.get(name), runs_b.get(name)) {
+            (Some(run_a), Some(run_b)) => {
+                debug!("Comparing the setup of {}", location);
+                let (settings_a, machine_a) = read_run(run_a, &tmp);
+                let (settings_b, machine_b) = read_run(run_b, &tmp);
+                compare(
+                    location,
+                    &campaign_a.portable(settings_a),
+                    &campaign_b.portable(settings_b),
+                    &mut rows,
+                );
+                if machines.0.is_none() {
+                    machines.0 = machine_a;
+                }
+                if machines.1.is_none() {
+                    machines.1 = machine_b;
+                }
+            }
+            (Some(_), None) => rows.push([
+                String::from(location),
+                String::from("run"),
+                String::from("present"),
+                String::new(),
+            ]),
+            (None, Some(_)) => rows.push([
+                String::from(location),
+                String::from("run"),
+                String::new(),
+                String::from("present"),
+            ]),
+            (None, None) => {}
+        }
+    }
+    if let (Some(machine_a), Some(machine_b)) = machines {
+        // Before the runs, it explains the most:
+        let mut machine_rows = Vec::new();
+        compare("machine", &machine_a, &machine_b, &mut machine_rows);
+        let at = rows.iter().take_while(|r| r[0] == "manifest").count();
+        rows.splice(at..at, machine_rows);
+    }
+
+    let mut wtr = csv::Writer::from_writer(out);
+    wtr.encode(DIFF_CONFIG_COLUMNS).unwrap();
+    for row in rows.iter() {
+        wtr.encode(row).unwrap();
+    }
+    wtr.flush().unwrap();
+    Ok(rows.len())
+}
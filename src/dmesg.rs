@@ -0,0 +1,97 @@
+//! Captures what the kernel logged while we were measuring (OOM kills, machine
+//! check exceptions, perf NMI warnings etc.), so it can be attached to the
+//! measurement it affected.
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process::Command;
+
+use tracing::{debug, warn};
+
+/// Messages that are a bad sign for a measurement.
+const SUSPICIOUS: [&str; 6] = [
+    "Out of memory",
+    "oom-kill",
+    "Machine check",
+    "mce:",
+    "perf: interrupt took too long",
+    "NMI",
+];
+
+fn read_dmesg() -> Option<Vec<String>> {
+    let out = Command::new("dmesg").output().ok()?;
+    if !out.status.success() {
+        // Probably kernel.dmesg_restrict is set
+        debug!(
+            "Can't read the kernel log: {}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        return None;
+    }
+    Some(
+        String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .map(String::from)
+            .collect(),
+    )
+}
+
+/// Remembers the last line of the kernel log at some point in time.
+pub struct KernelLog {
+    last_line: Option<String>,
+    readable: bool,
+}
+
+impl KernelLog {
+    pub fn snapshot() -> KernelLog {
+        match read_dmesg() {
+            Some(lines) => KernelLog {
+                last_line: lines.last().cloned(),
+                readable: true,
+            },
+            None => KernelLog {
+                last_line: None,
+                readable: false,
+            },
+        }
+    }
+
+    /// Everything the kernel logged since the snapshot.
+    pub fn since(&self) -> Vec<String> {
+        if !self.readable {
+            return Vec::new();
+        }
+        let lines = read_dmesg().unwrap_or_default();
+
+        // If our last line is gone (ring buffer wrapped around) we keep all of it:
+        let start = match self.last_line {
+            Some(ref last) => lines
+                .iter()
+                .rposition(|l| l == last)
+                .map_or(0, |idx| idx + 1),
+            None => 0,
+        };
+        lines.into_iter().skip(start).collect()
+    }
+}
+
+/// Saves the kernel log lines of a measurement to `save_to` (if there are any) and
+/// warns about suspicious ones.
+pub fn save_delta(lines: &[String], what: &str, save_to: &Path) -> io::Result<()> {
+    if lines.is_empty() {
+        return Ok(());
+    }
+
+    for line in lines.iter() {
+        if SUSPICIOUS.iter().any(|s| line.contains(s)) {
+            warn!(
+                "{}: kernel reported '{}' during the measurement.",
+                what, line
+            );
+        }
+    }
+
+    let mut f = File::create(save_to)?;
+    f.write_all(format!("{}\n", lines.join("\n")).as_bytes())
+}
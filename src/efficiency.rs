@@ -0,0 +1,136 @@
+//! Energy efficiency of a run, from the RAPL energy perf counted (add
+//! `power/energy-pkg/`, `power/energy-ram/`, ... to the `events` of the
+//! manifest) and the figure of merit the program reported (see `output.rs`).
+//! `aggregate` saves a row for every energy domain of every run in
+//! `efficiency.csv`:
+//!
+//! * `ENERGY_J`: what the domain used while we measured, `SECONDS` how long that
+//!   was and `POWER_W` its mean power,
+//! * `EDP` the energy-delay product (J·s) and `ED2P` the energy-delay² product
+//!   (J·s²), which weigh performance more than energy does (lower is better),
+//! * `MERIT` the mean figure of merit of the measurements and `MERIT_PER_JOULE`
+//!   what it is per joule: for a rate (ops/s) that's the merit over the mean
+//!   power, operations per joule. Both are empty without a figure of merit.
+//!
+//! Runs without energy events don't have rows.
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::columnar::{ResultWriter, RESULT_COLUMNS};
+
+/// The columns of `efficiency.csv` (after `RUN` for a campaign).
+pub const EFFICIENCY_COLUMNS: [&str; 8] = [
+    "DOMAIN",
+    "ENERGY_J",
+    "SECONDS",
+    "POWER_W",
+    "EDP",
+    "ED2P",
+    "MERIT",
+    "MERIT_PER_JOULE",
+];
+
+/// Is `event` one of the RAPL domains (`power/energy-pkg/`, `energy-cores`, ...)?
+pub fn is_energy(event: &str) -> bool {
+    event.starts_with("power/energy-") || event.starts_with("energy-")
+}
+
+/// What a domain used.
+#[derive(Default)]
+struct Domain {
+    joules: f64,
+    /// The end of every interval.
+    times: Vec<f64>,
+}
+
+impl Domain {
+    /// How long we measured: from the start of the first interval (which is
+    /// as long as the second) to the end of the last.
+    fn seconds(&self) -> f64 {
+        let mut times = self.times.clone();
+        times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        times.dedup();
+        match times.len() {
+            0 => 0.0,
+            1 => times[0],
+            n => times[n - 1] - times[0] + (times[1] - times[0]),
+        }
+    }
+}
+
+/// Passes the results of a run on and keeps its energy and figure of merit.
+pub struct EfficiencyWriter<'a> {
+    inner: &'a mut dyn ResultWriter,
+    domains: BTreeMap<String, Domain>,
+    merits: Vec<f64>,
+}
+
+impl<'a> EfficiencyWriter<'a> {
+    pub fn new(inner: &'a mut dyn ResultWriter) -> EfficiencyWriter<'a> {
+        EfficiencyWriter {
+            inner,
+            domains: BTreeMap::new(),
+            merits: Vec::new(),
+        }
+    }
+
+    /// The rows (see `EFFICIENCY_COLUMNS`) of every energy domain.
+    pub fn efficiency(self) -> Vec<Vec<String>> {
+        let merit = if self.merits.is_empty() {
+            None
+        } else {
+            Some(self.merits.iter().sum::<f64>() / self.merits.len() as f64)
+        };
+        self.domains
+            .into_iter()
+            .filter_map(|(domain, used)| {
+                let seconds = used.seconds();
+                if seconds <= 0.0 {
+                    return None;
+                }
+                let power = used.joules / seconds;
+                let (merit, per_joule) = match merit {
+                    Some(merit) if power > 0.0 => (merit.to_string(), (merit / power).to_string()),
+                    Some(merit) => (merit.to_string(), String::new()),
+                    None => (String::new(), String::new()),
+                };
+                Some(vec![
+                    domain,
+                    used.joules.to_string(),
+                    seconds.to_string(),
+                    power.to_string(),
+                    (used.joules * seconds).to_string(),
+                    (used.joules * seconds * seconds).to_string(),
+                    merit,
+                    per_joule,
+                ])
+            })
+            .collect()
+    }
+}
+
+impl<'a> ResultWriter for EfficiencyWriter<'a> {
+    fn write_row(&mut self, row: &[&str]) {
+        self.inner.write_row(row);
+        if row.len() < RESULT_COLUMNS.len() {
+            // Sampled (perf record) results don't have all the columns
+            return;
+        }
+        let value = match f64::from_str(row[8]) {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        if row[7] == "workload" {
+            self.merits.push(value);
+        } else if is_energy(row[0]) {
+            let domain = self.domains.entry(String::from(row[0])).or_default();
+            domain.joules += value;
+            if let Ok(time) = f64::from_str(row[2]) {
+                domain.times.push(time);
+            }
+        }
+    }
+
+    /// The inner writer is finished by whoever created it.
+    fn finish(&mut self) {}
+}
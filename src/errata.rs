@@ -0,0 +1,200 @@
+//! Events that errata of the CPU we run on make broken or misleading. The
+//! perfmon data names the errata of an event (`EventDescription::errata`), and
+//! `errata.toml` lists what it doesn't know about, by microarchitecture:
+//!
+//! * `exclude`d events aren't measured (unless `--allow-errata`), they're
+//!   listed in `skip_events` like those that fail the pre-flight check.
+//! * Every other event an erratum applies to is measured but annotated: it ends
+//!   up in `errata.csv` of the run, and `aggregate` reports it in
+//!   `validation.txt` (see `validate.rs`).
+use std::io;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use x86::cpuid;
+use x86::perfcnt::intel::EventDescription;
+
+use super::profile::{get_known_events, ProfileSettings};
+use tracing::{error, info, trace};
+
+/// The annotations of a run (in its directory).
+pub const ERRATA_FILE: &str = "errata.csv";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Action {
+    Exclude,
+    Annotate,
+}
+
+impl Action {
+    fn new(action: &str) -> Action {
+        match action {
+            "exclude" => Action::Exclude,
+            "annotate" => Action::Annotate,
+            _ => panic!("Unknown errata action '{}' in errata.toml", action),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Action::Exclude => "exclude",
+            Action::Annotate => "annotate",
+        }
+    }
+}
+
+/// An entry of `errata.toml`.
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Event names, `PREFIX.*` for all events that start with `PREFIX.`.
+    events: Vec<String>,
+    erratum: String,
+    action: Action,
+    description: String,
+}
+
+impl Entry {
+    fn applies_to(&self, event: &str) -> bool {
+        self.events.iter().any(|e| {
+            if e.ends_with(".*") {
+                event.starts_with(&e[..e.len() - 1])
+            } else {
+                e == event
+            }
+        })
+    }
+}
+
+/// What an erratum means for an event.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Erratum {
+    pub event: String,
+    /// The errata (as named in the specification update), comma-separated.
+    pub errata: String,
+    pub action: Action,
+    pub description: String,
+}
+
+lazy_static! {
+    /// The entries of `errata.toml` for this CPU.
+    static ref ENTRIES: Vec<Entry> = {
+        let cpuid = cpuid::CpuId::new();
+        let (family, model) = cpuid.get_feature_info().map_or((0,0), |fi| (fi.family_id(), (fi.extended_model_id() << 4) | fi.model_id()));
+
+        let errata_config = include_str!("errata.toml");
+        let mut parser = toml::Parser::new(errata_config);
+        let doc = match parser.parse() {
+            Some(doc) => doc,
+            None => {
+                error!("Can't parse the errata file:\n{:?}", parser.errors);
+                std::process::exit(9);
+            }
+        };
+
+        let mut entries = Vec::new();
+        for (name, architecture) in doc {
+            let architecture = architecture.as_table().expect("errata.toml architectures must be a table");
+            let cfamily = architecture["family"].as_integer().expect("Family must be int.") as u8;
+            let models = architecture["models"].as_slice().expect("errata.toml models must be a list.");
+            if family != cfamily || !models.iter().any(|m| m.as_integer() == Some(model as i64)) {
+                continue;
+            }
+            trace!("Running on {}, reading errata from config", name);
+            let events = architecture.get("events").and_then(|e| e.as_slice()).unwrap_or(&[]);
+            for entry in events {
+                let entry = entry.as_table().expect("errata.toml events must be tables");
+                let text = |key: &str| -> String {
+                    String::from(entry[key].as_str().expect("errata.toml values must be strings"))
+                };
+                entries.push(Entry {
+                    events: entry["events"]
+                        .as_slice()
+                        .expect("errata.toml events must be a list.")
+                        .iter()
+                        .map(|e| String::from(e.as_str().expect("Event must be a string.")))
+                        .collect(),
+                    erratum: text("erratum"),
+                    action: Action::new(&text("action")),
+                    description: text("description"),
+                });
+            }
+        }
+        entries
+    };
+}
+
+/// The erratum of `event` (named `name`) on this CPU, if there is one.
+pub fn erratum(name: &str, event: Option<&EventDescription>) -> Option<Erratum> {
+    if let Some(entry) = ENTRIES.iter().find(|e| e.applies_to(name)) {
+        return Some(Erratum {
+            event: String::from(name),
+            errata: entry.erratum.clone(),
+            action: entry.action,
+            description: entry.description.clone(),
+        });
+    }
+    // The perfmon data says "0" for no errata at times:
+    match event.and_then(|e| e.errata) {
+        Some(errata) if errata != "0" && !errata.is_empty() => Some(Erratum {
+            event: String::from(name),
+            errata: String::from(errata),
+            action: Action::Annotate,
+            description: String::from("see the specification update of the CPU"),
+        }),
+        _ => None,
+    }
+}
+
+/// Adds the events of our tables that errata exclude to the `skip_events` of
+/// `settings`, unless we `allow_errata`.
+pub fn exclude(settings: &mut ProfileSettings, allow_errata: bool) {
+    for event in get_known_events() {
+        let erratum = match erratum(event.event_name, Some(event)) {
+            Some(ref e) if e.action == Action::Exclude => e.clone(),
+            _ => continue,
+        };
+        if allow_errata {
+            info!(
+                "Measuring {} despite erratum {}: {}",
+                erratum.event, erratum.errata, erratum.description
+            );
+        } else if !settings.skip_events.contains(&erratum.event) {
+            info!(
+                "Not measuring {} because of erratum {}: {} (--allow-errata measures it)",
+                erratum.event, erratum.errata, erratum.description
+            );
+            settings.skip_events.push(erratum.event);
+        }
+    }
+}
+
+fn to_io_error(e: csv::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Saves the errata of the events we measure (`names` and the events of our
+/// tables, if they are one) in `ERRATA_FILE` in `output_path`, returns how many
+/// there are.
+pub fn save_errata(
+    output_path: &Path,
+    names: &[(String, Option<&EventDescription>)],
+) -> io::Result<usize> {
+    let errata: Vec<Erratum> = names
+        .iter()
+        .filter_map(|&(ref name, event)| erratum(name, event))
+        .collect();
+    if errata.is_empty() {
+        return Ok(0);
+    }
+
+    let mut errata_file = output_path.to_path_buf();
+    errata_file.push(ERRATA_FILE);
+    let mut wtr = csv::Writer::from_file(errata_file).map_err(to_io_error)?;
+    wtr.encode(("event", "errata", "action", "description"))
+        .map_err(to_io_error)?;
+    for e in errata.iter() {
+        wtr.encode((&e.event, &e.errata, e.action.as_str(), &e.description))
+            .map_err(to_io_error)?;
+    }
+    Ok(errata.len())
+}
@@ -0,0 +1,212 @@
+//! Turns aggregated results (the long CSV layout) into feature matrices for
+//! machine learning: one row per interval and run, one column per event, with
+//! every event z-scored.
+//!
+//! Runs (configurations) are either in the training or in the test set as a
+//! whole, so a model never sees intervals of the configuration it's tested on.
+//! The mean and standard deviation of every event come from the training set
+//! only and are saved in `scaling.csv`.
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use csv;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FeatureSettings {
+    /// Share of the configurations that go into the test set.
+    pub test_fraction: f64,
+    /// Changes which configurations end up in the test set.
+    pub seed: u64,
+}
+
+/// The values of all events in one interval of a run.
+pub struct Interval {
+    pub time: f64,
+    /// By event id (`None` if the event wasn't measured in the interval).
+    pub values: Vec<Option<f64>>,
+}
+
+/// The intervals of a result file by run and index.
+pub type Intervals = BTreeMap<(String, u64), Interval>;
+
+fn to_io_error(e: csv::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn column(header: &[String], name: &str) -> io::Result<usize> {
+    header.iter().position(|c| c == name).ok_or(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "results don't have a {} column (only the long layout works)",
+            name
+        ),
+    ))
+}
+
+/// FNV-1a, we want the same split on every machine and Rust version.
+pub fn fnv1a(seed: u64, name: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in seed.to_le_bytes().iter().chain(name.as_bytes().iter()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// The configurations that go into the test set.
+fn test_configurations(configurations: &[&str], settings: &FeatureSettings) -> Vec<String> {
+    let mut shuffled: Vec<&str> = configurations.to_vec();
+    shuffled.sort_by_key(|c| fnv1a(settings.seed, c));
+    let mut test = (configurations.len() as f64 * settings.test_fraction).round() as usize;
+    if configurations.len() > 1 && settings.test_fraction > 0.0 {
+        test = test.max(1).min(configurations.len() - 1);
+    }
+    shuffled.into_iter().take(test).map(String::from).collect()
+}
+
+/// Mean and (population) standard deviation of every event in `intervals`.
+fn scaling(intervals: &[&Interval], events: usize) -> Vec<(f64, f64)> {
+    (0..events)
+        .map(|e| {
+            let values: Vec<f64> = intervals
+                .iter()
+                .filter_map(|i| i.values.get(e).cloned().unwrap_or(None))
+                .collect();
+            if values.is_empty() {
+                return (0.0, 0.0);
+            }
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            let variance =
+                values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+            (mean, variance.sqrt())
+        })
+        .collect()
+}
+
+/// Writes the z-scored intervals, events that weren't measured in an interval get
+/// the mean (0).
+fn save_matrix(
+    save_to: &Path,
+    event_names: &[&str],
+    order: &[usize],
+    intervals: &[(&(String, u64), &Interval)],
+    scale: &[(f64, f64)],
+) -> io::Result<()> {
+    let mut wtr = csv::Writer::from_file(save_to).map_err(to_io_error)?;
+    let mut header = vec!["RUN", "INDEX", "TIME"];
+    header.extend(order.iter().map(|e| event_names[*e]));
+    wtr.encode(&header).map_err(to_io_error)?;
+
+    for &(&(ref run, index), interval) in intervals.iter() {
+        let mut row = vec![run.clone(), index.to_string(), interval.time.to_string()];
+        for e in order.iter() {
+            let (mean, std) = scale[*e];
+            let z = match interval.values.get(*e).cloned().unwrap_or(None) {
+                Some(v) if std > 0.0 => (v - mean) / std,
+                _ => 0.0,
+            };
+            row.push(z.to_string());
+        }
+        wtr.encode(row).map_err(to_io_error)?;
+    }
+    wtr.flush().map_err(to_io_error)
+}
+
+/// Reads aggregated `results` (long layout) into intervals: they are identified
+/// by their run and index, the values of an event are summed up over all
+/// locations. Returns the event names (by id) and the intervals.
+pub fn read_intervals(results: &Path) -> io::Result<(Vec<String>, Intervals)> {
+    let mut rdr = csv::Reader::from_file(results).map_err(to_io_error)?;
+    let header = rdr.headers().map_err(to_io_error)?;
+    let run_idx = header.iter().position(|c| c == "RUN");
+    let index_idx = column(&header, "INDEX")?;
+    let time_idx = column(&header, "TIME")?;
+    let event_idx = column(&header, "EVENT_NAME")?;
+    let value_idx = header
+        .iter()
+        .position(|c| c.starts_with("SAMPLE_VALUE"))
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "results don't have a SAMPLE_VALUE column",
+        ))?;
+
+    let mut event_ids: HashMap<String, usize> = HashMap::new();
+    let mut event_names: Vec<String> = Vec::new();
+    let mut intervals: Intervals = BTreeMap::new();
+    for record in rdr.records() {
+        let record = record.map_err(to_io_error)?;
+        if record.len() != header.len() {
+            continue;
+        }
+        let value = match f64::from_str(&record[value_idx]) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let index = u64::from_str(&record[index_idx]).unwrap_or(0);
+        let time = f64::from_str(&record[time_idx]).unwrap_or(0.0);
+        let run = run_idx.map_or(String::new(), |idx| record[idx].clone());
+
+        let event = match event_ids.get(&record[event_idx]) {
+            Some(id) => *id,
+            None => {
+                event_names.push(record[event_idx].clone());
+                event_ids.insert(record[event_idx].clone(), event_names.len() - 1);
+                event_names.len() - 1
+            }
+        };
+        let interval = intervals.entry((run, index)).or_insert(Interval {
+            time,
+            values: Vec::new(),
+        });
+        interval.time = interval.time.min(time);
+        if interval.values.len() <= event {
+            interval.values.resize(event + 1, None);
+        }
+        *interval.values[event].get_or_insert(0.0) += value;
+    }
+
+    Ok((event_names, intervals))
+}
+
+/// Reads the aggregated `results` and writes `features_train.csv`,
+/// `features_test.csv` and `scaling.csv` to `output_path`.
+pub fn features(results: &Path, settings: &FeatureSettings, output_path: &Path) -> io::Result<()> {
+    let (event_names, intervals) = read_intervals(results)?;
+    let mut configurations: Vec<&str> = intervals.keys().map(|k| k.0.as_str()).collect();
+    configurations.dedup();
+    let test = test_configurations(&configurations, settings);
+    info!(
+        "{} intervals of {} configurations, {} configurations for testing.",
+        intervals.len(),
+        configurations.len(),
+        test.len()
+    );
+
+    let (test_intervals, train_intervals): (Vec<_>, Vec<_>) =
+        intervals.iter().partition(|&(k, _)| test.contains(&k.0));
+    let train: Vec<&Interval> = train_intervals.iter().map(|&(_, i)| i).collect();
+    let scale = scaling(&train, event_names.len());
+
+    let names: Vec<&str> = event_names.iter().map(|e| e.as_str()).collect();
+    let mut order: Vec<usize> = (0..names.len()).collect();
+    order.sort_by_key(|e| names[*e]);
+
+    let mut save_to = output_path.to_path_buf();
+    save_to.push("features_train.csv");
+    save_matrix(&save_to, &names, &order, &train_intervals, &scale)?;
+    save_to.set_file_name("features_test.csv");
+    save_matrix(&save_to, &names, &order, &test_intervals, &scale)?;
+
+    save_to.set_file_name("scaling.csv");
+    let mut wtr = csv::Writer::from_file(&save_to).map_err(to_io_error)?;
+    wtr.encode(("EVENT_NAME", "MEAN", "STD"))
+        .map_err(to_io_error)?;
+    for e in order.iter() {
+        wtr.encode((names[*e], scale[*e].0, scale[*e].1))
+            .map_err(to_io_error)?;
+    }
+    wtr.flush().map_err(to_io_error)
+}
@@ -0,0 +1,287 @@
+//! The GPUs of the machine and the NUMA node and CPUs each is close to, so the
+//! CPU side of a program that feeds a GPU can be profiled (and placed) next to
+//! it. The PCI devices in sysfs tell where every display and 3D controller is
+//! attached (see `pci.rs`); `nvidia-smi topo -m` and `rocm-smi
+//! --showtoponuma` know better for their GPUs if they're installed (the
+//! firmware doesn't always fill in the PCI locality), and they give the index
+//! the GPU has for CUDA and ROCm.
+//!
+//! `profile` saves what we found in `gpus.json` (and what the tools printed in
+//! `nvidia_topo.txt` and `rocm_topo.txt`), `MachineTopology::gpus_on_node`
+//! reads it.
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use rustc_serialize::json::{self, Json};
+use schemars::JsonSchema;
+use tracing::debug;
+
+use super::pci;
+use super::util::{Cpu, Node};
+
+/// The file in a run directory.
+pub const GPU_FILE: &str = "gpus.json";
+
+/// PCI vendor ids and how we call the vendor.
+const VENDORS: [(&str, &str); 3] = [("0x10de", "nvidia"), ("0x1002", "amd"), ("0x8086", "intel")];
+
+#[derive(Debug, Clone, Eq, PartialEq, RustcEncodable, JsonSchema)]
+pub struct GpuInfo {
+    /// e.g. 0000:3b:00.0
+    pub pci_address: String,
+    /// nvidia, amd, intel (the PCI vendor id for others)
+    pub vendor: String,
+    /// The kernel driver bound to the device (empty if there is none).
+    pub driver: String,
+    /// The index nvidia-smi or rocm-smi (and CUDA or ROCm) give the GPU.
+    pub index: Option<u64>,
+    /// The NUMA node the GPU is attached to (none if the machine doesn't say).
+    pub node: Option<Node>,
+    /// The CPUs close to the GPU.
+    pub cpus: Vec<Cpu>,
+    /// The bus of the PCIe root complex the GPU is below.
+    pub pcie_root: String,
+    /// Where `node` and `cpus` come from: sysfs, nvidia-smi or rocm-smi.
+    pub source: String,
+}
+
+impl GpuInfo {
+    fn from_json(gpu: &Json) -> Option<GpuInfo> {
+        let text = |key: &str| gpu.find(key).and_then(|v| v.as_string()).map(String::from);
+        Some(GpuInfo {
+            pci_address: text("pci_address")?,
+            vendor: text("vendor").unwrap_or(String::new()),
+            driver: text("driver").unwrap_or(String::new()),
+            index: gpu.find("index").and_then(|v| v.as_u64()),
+            node: gpu.find("node").and_then(|v| v.as_u64()),
+            cpus: gpu
+                .find("cpus")
+                .and_then(|v| v.as_array())
+                .map_or(Vec::new(), |cpus| {
+                    cpus.iter().filter_map(|c| c.as_u64()).collect()
+                }),
+            pcie_root: text("pcie_root").unwrap_or(String::new()),
+            source: text("source").unwrap_or(String::new()),
+        })
+    }
+}
+
+/// The display and 3D controllers among the PCI devices.
+fn pci_gpus() -> Vec<GpuInfo> {
+    let mut gpus = Vec::new();
+    // Class 0x03xxxx are display controllers (VGA, XGA, 3D and others):
+    for device in pci::devices("0x03") {
+        let locality = match pci::locality(&device) {
+            Some(locality) => locality,
+            None => continue,
+        };
+        let vendor_id = pci::read_trimmed(&device.join("vendor"));
+        let vendor = VENDORS
+            .iter()
+            .find(|v| v.0 == vendor_id)
+            .map_or(vendor_id.clone(), |v| String::from(v.1));
+        gpus.push(GpuInfo {
+            pci_address: locality.address,
+            vendor,
+            driver: locality.driver,
+            index: None,
+            node: locality.node,
+            cpus: locality.cpus,
+            pcie_root: locality.root,
+            source: String::from("sysfs"),
+        });
+    }
+    gpus
+}
+
+/// What `program` prints with `args`, if it's installed and succeeds.
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    match Command::new(program).args(args).output() {
+        Ok(out) if out.status.success() => String::from_utf8(out.stdout).ok(),
+        Ok(out) => {
+            debug!(
+                "{} {} failed with {}: {}",
+                program,
+                args.join(" "),
+                out.status,
+                String::from_utf8_lossy(&out.stderr)
+            );
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// `line` without the escape sequences nvidia-smi underlines the header with.
+fn strip_escapes(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // ESC [ 4 m
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// The CPU and NUMA affinity of every GPU in the matrix of `nvidia-smi topo -m`
+/// (by the index of the GPU).
+fn parse_nvidia_topology(topology: &str) -> Vec<(u64, Vec<Cpu>, Option<Node>)> {
+    let mut lines = topology.lines().filter(|l| !l.trim().is_empty());
+    let header = match lines.next() {
+        Some(header) => strip_escapes(header),
+        None => return Vec::new(),
+    };
+    let header: Vec<&str> = header.split('\t').map(|c| c.trim()).collect();
+    let column = |name: &str| header.iter().position(|c| *c == name);
+    let (cpu_column, node_column) = match (column("CPU Affinity"), column("NUMA Affinity")) {
+        (Some(cpu), node) => (cpu, node),
+        _ => return Vec::new(),
+    };
+
+    let mut affinities = Vec::new();
+    for line in lines.map(strip_escapes) {
+        let row: Vec<&str> = line.split('\t').map(|c| c.trim()).collect();
+        let index = match row.first() {
+            Some(label) if label.starts_with("GPU") => match label[3..].parse::<u64>() {
+                Ok(index) => index,
+                Err(_) => continue,
+            },
+            // The NICs and the legend:
+            _ => continue,
+        };
+        let cpus = row
+            .get(cpu_column)
+            .map_or(Vec::new(), |c| pci::parse_cpulist(c));
+        let node = node_column
+            .and_then(|c| row.get(c))
+            .and_then(|n| n.parse::<Node>().ok());
+        affinities.push((index, cpus, node));
+    }
+    affinities
+}
+
+/// Rows like `GPU[0]  : (Topology) Numa Node: 0` of rocm-smi: the index of
+/// the GPU and what follows `key`.
+fn rocm_values<'a>(output: &'a str, key: &str) -> Vec<(u64, &'a str)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let index = line
+                .trim()
+                .strip_prefix("GPU[")?
+                .split(']')
+                .next()?
+                .parse::<u64>()
+                .ok()?;
+            let value = line.split_once(key)?.1;
+            Some((index, value.trim()))
+        })
+        .collect()
+}
+
+/// Sets what nvidia-smi knows about the NVIDIA GPUs in `gpus`, returns the
+/// output of `nvidia-smi topo -m`.
+fn nvidia_smi(gpus: &mut [GpuInfo]) -> Option<String> {
+    let topology = output("nvidia-smi", &["topo", "-m"])?;
+    let addresses = output(
+        "nvidia-smi",
+        &["--query-gpu=index,pci.bus_id", "--format=csv,noheader"],
+    )
+    .unwrap_or_default();
+    let affinities = parse_nvidia_topology(&topology);
+
+    for line in addresses.lines() {
+        let mut columns = line.splitn(2, ',');
+        let index = match columns.next().and_then(|i| i.trim().parse::<u64>().ok()) {
+            Some(index) => index,
+            None => continue,
+        };
+        let address = pci::normalize_address(columns.next().unwrap_or(""));
+        let gpu = match gpus.iter_mut().find(|g| g.pci_address == address) {
+            Some(gpu) => gpu,
+            None => continue,
+        };
+        gpu.index = Some(index);
+        if let Some(&(_, ref cpus, node)) = affinities.iter().find(|a| a.0 == index) {
+            if !cpus.is_empty() {
+                gpu.cpus = cpus.clone();
+                gpu.node = node.or(gpu.node);
+                gpu.source = String::from("nvidia-smi");
+            }
+        }
+    }
+    Some(topology)
+}
+
+/// Sets what rocm-smi knows about the AMD GPUs in `gpus`, returns its output.
+fn rocm_smi(gpus: &mut [GpuInfo]) -> Option<String> {
+    let topology = output("rocm-smi", &["--showbus", "--showtoponuma"])?;
+    let nodes = rocm_values(&topology, "Numa Node:");
+    for (index, address) in rocm_values(&topology, "PCI Bus:") {
+        let address = pci::normalize_address(address);
+        let gpu = match gpus.iter_mut().find(|g| g.pci_address == address) {
+            Some(gpu) => gpu,
+            None => continue,
+        };
+        gpu.index = Some(index);
+        // -1 if the GPU isn't local to a node:
+        if let Some(node) = nodes
+            .iter()
+            .find(|n| n.0 == index)
+            .and_then(|n| n.1.parse::<Node>().ok())
+        {
+            gpu.node = Some(node);
+            gpu.cpus = pci::node_cpus(node);
+            gpu.source = String::from("rocm-smi");
+        }
+    }
+    Some(topology)
+}
+
+/// The GPUs of this machine, and what `nvidia-smi topo -m` and `rocm-smi`
+/// printed (if they know any of them).
+fn detect() -> (Vec<GpuInfo>, Option<String>, Option<String>) {
+    let mut gpus = pci_gpus();
+    let mut nvidia = None;
+    if gpus.iter().any(|g| g.vendor == "nvidia") {
+        nvidia = nvidia_smi(&mut gpus);
+    }
+    let mut rocm = None;
+    if gpus.iter().any(|g| g.vendor == "amd") {
+        rocm = rocm_smi(&mut gpus);
+    }
+    (gpus, nvidia, rocm)
+}
+
+/// The GPUs of this machine.
+pub fn gpus() -> Vec<GpuInfo> {
+    detect().0
+}
+
+/// Saves the GPUs of this machine in `GPU_FILE` (and what nvidia-smi and
+/// rocm-smi print, if they're installed) in `output_path`.
+pub fn save_gpu_topology(output_path: &Path) -> io::Result<Vec<GpuInfo>> {
+    let (gpus, nvidia, rocm) = detect();
+    if let Some(topology) = nvidia {
+        pci::save_content(output_path, "nvidia_topo.txt", &topology)?;
+    }
+    if let Some(topology) = rocm {
+        pci::save_content(output_path, "rocm_topo.txt", &topology)?;
+    }
+    pci::save_content(
+        output_path,
+        GPU_FILE,
+        &format!("{}\n", json::as_pretty_json(&gpus)),
+    )?;
+    Ok(gpus)
+}
+
+/// The GPUs in `GPU_FILE` at `path` (none if there is no such file).
+pub fn read_gpus(path: &Path) -> Vec<GpuInfo> {
+    pci::read_list(path, GpuInfo::from_json)
+}
@@ -0,0 +1,83 @@
+//! Keeps autoperf's own work off the CPUs it measures (`--housekeeping-cpu`, or
+//! `housekeeping_cpu` in the configuration).
+//!
+//! We pin ourselves to the housekeeping CPU before measuring, so the dashboard,
+//! the samplers and the tools they start (turbostat, PCM, ...) run there. The
+//! commands that run the program (perf, likwid-perfctr, ...) get the CPUs we
+//! had before back, so the program runs where it would without a housekeeping
+//! CPU. Only Linux lets us do this, elsewhere the setting is ignored.
+use std::io;
+use std::process::Command;
+
+use super::util::Cpu;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::mem;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    use std::sync::Mutex;
+
+    use super::Cpu;
+
+    /// Where we ran before `pin` (None if we didn't pin ourselves).
+    static BEFORE: Mutex<Option<libc::cpu_set_t>> = Mutex::new(None);
+
+    fn affinity() -> io::Result<libc::cpu_set_t> {
+        unsafe {
+            let mut set: libc::cpu_set_t = mem::zeroed();
+            if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(set)
+        }
+    }
+
+    fn set_affinity(set: &libc::cpu_set_t) -> io::Result<()> {
+        if unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), set) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn pin(cpu: Cpu) -> io::Result<()> {
+        let before = affinity()?;
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        unsafe { libc::CPU_SET(cpu as usize, &mut set) };
+        set_affinity(&set)?;
+        let mut saved = BEFORE.lock().unwrap();
+        if saved.is_none() {
+            *saved = Some(before);
+        }
+        Ok(())
+    }
+
+    pub fn release(command: &mut Command) {
+        // We can't take the lock after the fork, the closure gets a copy:
+        if let Some(before) = *BEFORE.lock().unwrap() {
+            unsafe {
+                command.pre_exec(move || set_affinity(&before));
+            }
+        }
+    }
+}
+
+/// Moves autoperf (and everything it starts from now on) to `cpu`.
+pub fn pin(cpu: Cpu) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return linux::pin(cpu);
+    #[cfg(not(target_os = "linux"))]
+    return Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("can't pin autoperf to CPU {} on this OS", cpu),
+    ));
+}
+
+/// Lets `command` run on the CPUs autoperf had before it was pinned.
+pub fn release(command: &mut Command) {
+    #[cfg(target_os = "linux")]
+    linux::release(command);
+    #[cfg(not(target_os = "linux"))]
+    let _ = command;
+}
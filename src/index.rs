@@ -0,0 +1,249 @@
+//! `index.json` of a `pair` campaign: every run we started, with its ID, where
+//! its results are (relative to the campaign directory, the archive if it was
+//! compressed) and what was measured. `pair` updates it before and after every
+//! run, and `aggregate`, `diff`, `diff-config`, `validate` and `report` read it
+//! instead of searching the campaign directory for runs, which takes a while on
+//! a network file system with 100k files.
+//!
+//! ```text
+//! {"schema_version": 1, "runs": [{"run_id": "3f2a9c01d4e5b6a7",
+//!   "path": "L3-SMT/gcc_vs_cat", "status": "completed",
+//!   "metadata": {"configuration": "L3-SMT", "a": "gcc", "b": "cat", ...},
+//!   "updated": 1718000000}]}
+//! ```
+//!
+//! Campaigns without an index (or with one we can't read) are searched as before.
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustc_serialize::json::{self, Json};
+use tracing::{debug, error, warn};
+
+/// The index of a campaign directory.
+pub const INDEX_FILE: &str = "index.json";
+
+/// The layout of `INDEX_FILE`, it has a version of its own (the files of a run
+/// have `util::SCHEMA_VERSION`).
+const INDEX_SCHEMA_VERSION: u64 = 1;
+
+/// Where a run is.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Status {
+    /// It's running, or the invocation that ran it was killed.
+    Started,
+    Completed,
+    Failed,
+}
+
+impl Status {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Status::Started => "started",
+            Status::Completed => "completed",
+            Status::Failed => "failed",
+        }
+    }
+
+    fn from_str(status: &str) -> Option<Status> {
+        match status {
+            "started" => Some(Status::Started),
+            "completed" => Some(Status::Completed),
+            "failed" => Some(Status::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A run in the index.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub run_id: String,
+    /// Relative to the campaign directory.
+    pub path: PathBuf,
+    pub status: Status,
+    /// Key metadata (deployment, programs, settings), as in the tags of streamed samples.
+    pub metadata: Vec<(String, String)>,
+    /// Unix timestamp of the last change.
+    pub updated: u64,
+}
+
+/// How an entry is saved.
+#[derive(RustcEncodable)]
+struct SavedEntry {
+    run_id: String,
+    path: String,
+    status: String,
+    metadata: BTreeMap<String, String>,
+    updated: u64,
+}
+
+#[derive(RustcEncodable)]
+struct SavedIndex {
+    schema_version: u64,
+    runs: Vec<SavedEntry>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn parse_entry(run: &Json) -> Option<Entry> {
+    let string = |key: &str| run.find(key).and_then(|v| v.as_string());
+    let metadata = run
+        .find("metadata")
+        .and_then(|m| m.as_object())
+        .map_or(Vec::new(), |members| {
+            members
+                .iter()
+                .filter_map(|(k, v)| v.as_string().map(|v| (k.clone(), String::from(v))))
+                .collect()
+        });
+    Some(Entry {
+        run_id: String::from(string("run_id")?),
+        path: PathBuf::from(string("path")?),
+        status: Status::from_str(string("status")?)?,
+        metadata,
+        updated: run.find("updated").and_then(|u| u.as_u64()).unwrap_or(0),
+    })
+}
+
+/// The runs in the index of `campaign`, `None` if it has no index (or we can't
+/// read it, then we'd rather search the directory).
+pub fn read(campaign: &Path) -> Option<Vec<Entry>> {
+    let path = campaign.join(INDEX_FILE);
+    let content = fs::read_to_string(&path).ok()?;
+    let doc = match Json::from_str(&content) {
+        Ok(doc) => doc,
+        Err(e) => {
+            warn!(
+                "Can't parse {:?} ({}), searching for runs instead.",
+                path, e
+            );
+            return None;
+        }
+    };
+    match doc.find("schema_version").and_then(|v| v.as_u64()) {
+        Some(INDEX_SCHEMA_VERSION) => {}
+        version => {
+            warn!(
+                "{:?} has schema version {:?}, we know {}, searching for runs instead.",
+                path, version, INDEX_SCHEMA_VERSION
+            );
+            return None;
+        }
+    }
+    let runs = doc.find("runs").and_then(|r| r.as_array())?;
+    let mut entries = Vec::with_capacity(runs.len());
+    for run in runs {
+        match parse_entry(run) {
+            Some(entry) => entries.push(entry),
+            None => {
+                warn!(
+                    "{:?} has an invalid run ({}), searching for runs instead.",
+                    path, run
+                );
+                return None;
+            }
+        }
+    }
+    debug!("{:?} lists {} runs", path, entries.len());
+    Some(entries)
+}
+
+/// The completed runs of `campaign` (directories or archives), `None` if it has
+/// no index.
+pub fn completed_runs(campaign: &Path) -> Option<Vec<PathBuf>> {
+    read(campaign).map(|entries| {
+        entries
+            .into_iter()
+            .filter(|e| e.status == Status::Completed)
+            .map(|e| campaign.join(e.path))
+            .collect()
+    })
+}
+
+/// The runs of `campaign` that were started but didn't complete, `None` if it
+/// has no index.
+pub fn unfinished_runs(campaign: &Path) -> Option<Vec<PathBuf>> {
+    read(campaign).map(|entries| {
+        entries
+            .into_iter()
+            .filter(|e| e.status != Status::Completed)
+            .map(|e| campaign.join(e.path))
+            .collect()
+    })
+}
+
+/// The index of a campaign while it runs.
+pub struct CampaignIndex {
+    save_to: PathBuf,
+    /// By run ID (runs with the same ID are measured once).
+    runs: BTreeMap<String, Entry>,
+}
+
+impl CampaignIndex {
+    /// The index in `campaign`, with the runs of earlier invocations.
+    pub fn open(campaign: &Path) -> CampaignIndex {
+        let runs = read(campaign)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|e| (e.run_id.clone(), e))
+            .collect();
+        CampaignIndex {
+            save_to: campaign.join(INDEX_FILE),
+            runs,
+        }
+    }
+
+    /// Is the run `run_id` in the index?
+    pub fn contains(&self, run_id: &str) -> bool {
+        self.runs.contains_key(run_id)
+    }
+
+    /// Adds (or updates) `entry`, without saving the index.
+    pub fn insert(&mut self, mut entry: Entry) {
+        entry.updated = now();
+        self.runs.insert(entry.run_id.clone(), entry);
+    }
+
+    /// Adds (or updates) `entry` and saves the index.
+    pub fn record(&mut self, entry: Entry) {
+        self.insert(entry);
+        self.save_or_log();
+    }
+
+    pub fn save_or_log(&self) {
+        if let Err(e) = self.save() {
+            error!("Can't save {:?}: {}", self.save_to, e);
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        // In the order we search a campaign in:
+        let mut entries: Vec<&Entry> = self.runs.values().collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let index = SavedIndex {
+            schema_version: INDEX_SCHEMA_VERSION,
+            runs: entries
+                .into_iter()
+                .map(|e| SavedEntry {
+                    run_id: e.run_id.clone(),
+                    path: e.path.to_string_lossy().into_owned(),
+                    status: String::from(e.status.as_str()),
+                    metadata: e.metadata.iter().cloned().collect(),
+                    updated: e.updated,
+                })
+                .collect(),
+        };
+        // Readers should never see a half-written file:
+        let tmp = self.save_to.with_extension("json.tmp");
+        let mut f = File::create(&tmp)?;
+        f.write_all(format!("{}\n", json::as_pretty_json(&index)).as_bytes())?;
+        fs::rename(&tmp, &self.save_to)
+    }
+}
@@ -0,0 +1,266 @@
+//! Streams the counter values of every perf interval as InfluxDB line protocol
+//! (`--influx <file or url>`) while we measure, so Grafana can show experiments
+//! as they run.
+//!
+//! We follow the stat file that perf is writing (see `watch::stat_file`) and send
+//! what's new about once a second, either appended to a file or with a POST to
+//! an `http://` URL (e.g. `http://localhost:8086/api/v2/write?org=lab&bucket=perf`,
+//! `AUTOPERF_INFLUX_TOKEN` is sent as the API token). Every sample is tagged with
+//! the run, the location and the event, and for `pair` with the placement of the
+//! programs.
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use tracing::error;
+
+use super::perfstat::{self, Value};
+use super::watch::{self, StatFile};
+
+const MEASUREMENT: &str = "autoperf";
+const TOKEN_ENV: &str = "AUTOPERF_INFLUX_TOKEN";
+const FLUSH_EVERY: Duration = Duration::from_secs(1);
+
+enum Sink {
+    File(File),
+    Http {
+        /// host:port
+        host: String,
+        path: String,
+        token: Option<String>,
+    },
+}
+
+impl Sink {
+    fn new(target: &str) -> io::Result<Sink> {
+        if target.starts_with("https://") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only http:// URLs are supported",
+            ));
+        }
+        if let Some(rest) = target.strip_prefix("http://") {
+            let (host, path) = match rest.find('/') {
+                Some(idx) => (&rest[..idx], &rest[idx..]),
+                None => (rest, "/"),
+            };
+            let host = if host.contains(':') {
+                String::from(host)
+            } else {
+                format!("{}:80", host)
+            };
+            Ok(Sink::Http {
+                host,
+                path: String::from(path),
+                token: env::var(TOKEN_ENV).ok(),
+            })
+        } else {
+            Ok(Sink::File(
+                OpenOptions::new().create(true).append(true).open(target)?,
+            ))
+        }
+    }
+
+    fn send(&mut self, lines: &str) -> io::Result<()> {
+        match *self {
+            Sink::File(ref mut f) => {
+                f.write_all(lines.as_bytes())?;
+                f.flush()
+            }
+            Sink::Http {
+                ref host,
+                ref path,
+                ref token,
+            } => {
+                let mut stream = TcpStream::connect(host.as_str())?;
+                stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+                let mut request = format!(
+                    "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    path,
+                    host,
+                    lines.len()
+                );
+                if let Some(ref token) = *token {
+                    request.push_str(&format!("Authorization: Token {}\r\n", token));
+                }
+                request.push_str("\r\n");
+                stream.write_all(request.as_bytes())?;
+                stream.write_all(lines.as_bytes())?;
+                stream.flush()?;
+
+                let mut status = String::new();
+                BufReader::new(stream).read_line(&mut status)?;
+                match status.split_whitespace().nth(1) {
+                    Some(code) if code.starts_with("2") => Ok(()),
+                    _ => Err(io::Error::other(format!(
+                        "InfluxDB answered '{}'",
+                        status.trim()
+                    ))),
+                }
+            }
+        }
+    }
+}
+
+/// Tag keys and values can't have unescaped commas, equal signs or spaces.
+fn escape(tag: &str) -> String {
+    tag.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn tags(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .filter(|&&(_, v)| !v.is_empty())
+        .map(|&(k, v)| format!(",{}={}", escape(k), escape(v)))
+        .collect()
+}
+
+/// How far we are in a stat file.
+struct Tail {
+    stat_file: StatFile,
+    offset: u64,
+    /// The tags of the run (not the sample).
+    tags: String,
+}
+
+impl Tail {
+    fn new(stat_file: StatFile) -> Tail {
+        let mut pairs: Vec<(&str, &str)> = vec![("run", stat_file.configuration.as_str())];
+        pairs.extend(
+            stat_file
+                .placement
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str())),
+        );
+        let tags = tags(&pairs);
+        Tail {
+            stat_file,
+            offset: 0,
+            tags,
+        }
+    }
+
+    fn same_file(&self, other: &StatFile) -> bool {
+        self.stat_file.path == other.path && self.stat_file.started == other.started
+    }
+
+    /// The samples perf wrote since we last looked (complete lines only).
+    fn read(&mut self) -> io::Result<String> {
+        let mut file = File::open(&self.stat_file.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let complete = match buf.iter().rposition(|b| *b == b'\n') {
+            Some(idx) => idx + 1,
+            None => return Ok(String::new()),
+        };
+        self.offset += complete as u64;
+
+        let started = self
+            .stat_file
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0));
+        let mut lines = String::new();
+        for line in String::from_utf8_lossy(&buf[..complete]).lines() {
+            let count = match perfstat::parse_line(line, self.stat_file.scope) {
+                Some(count) => count,
+                None => continue,
+            };
+            let (time, value) = match count.value {
+                Value::Counted(value) => (count.time, value),
+                _ => continue,
+            };
+            let timestamp = started + Duration::from_secs_f64(time.max(0.0));
+            let _ = writeln!(
+                lines,
+                "{}{}{} value={} {}",
+                MEASUREMENT,
+                self.tags,
+                tags(&[("location", &count.location), ("event", &count.event)]),
+                value,
+                timestamp.as_nanos()
+            );
+        }
+        Ok(lines)
+    }
+}
+
+pub struct Streamer {
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+fn stream(sink: &mut Sink, tail: &mut Option<Tail>, last_error: &mut Option<String>) {
+    // Finish the old stat file before we go on with a new one:
+    if let Some(current) = watch::stat_file() {
+        let changed = tail.as_ref().is_none_or(|t| !t.same_file(&current));
+        if changed {
+            if let Some(ref mut old) = *tail {
+                send(sink, old, last_error);
+            }
+            *tail = Some(Tail::new(current));
+        }
+    }
+    if let Some(ref mut tail) = *tail {
+        send(sink, tail, last_error);
+    }
+}
+
+fn send(sink: &mut Sink, tail: &mut Tail, last_error: &mut Option<String>) {
+    let r = tail.read().and_then(|lines| {
+        if !lines.is_empty() {
+            sink.send(&lines)
+        } else {
+            Ok(())
+        }
+    });
+    match r {
+        Ok(()) => *last_error = None,
+        Err(e) => {
+            // Don't repeat the same error every second:
+            let e = e.to_string();
+            if last_error.as_ref() != Some(&e) {
+                error!("Can't stream samples to InfluxDB: {}", e);
+            }
+            *last_error = Some(e);
+        }
+    }
+}
+
+impl Streamer {
+    /// Streams to `target` (a file or an http:// URL) until `stop` is called.
+    pub fn start(target: &str) -> io::Result<Streamer> {
+        let mut sink = Sink::new(target)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut tail: Option<Tail> = None;
+            let mut last_error: Option<String> = None;
+            loop {
+                let done = stopped.load(Ordering::SeqCst);
+                stream(&mut sink, &mut tail, &mut last_error);
+                if done {
+                    break;
+                }
+                thread::sleep(FLUSH_EVERY);
+            }
+        });
+        Ok(Streamer { stop, thread })
+    }
+
+    /// Sends what's left and stops.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.thread.join();
+    }
+}
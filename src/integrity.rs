@@ -0,0 +1,160 @@
+//! Hashes of the files in an output directory, so a truncated copy or a file
+//! that was edited by hand is caught before anyone bases a paper on it. When
+//! `profile`, `bench`, `scale` or `pair` are done they write the SHA-256 of every
+//! file in the output directory (subdirectories and run archives included) to
+//! `SHA256SUMS`, in the format of `sha256sum` (`sha256sum -c SHA256SUMS` checks
+//! them too). `autoperf verify <dir>` checks the files against it:
+//!
+//! ```text
+//! 5b0c2a...e41f  perf.csv
+//! 9d3f7e...07aa  run-0/1_stat.csv
+//! ```
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, error, info};
+
+use crate::lock::LOCK_FILE;
+use crate::sha256::Sha256;
+
+/// The hashes of an output directory.
+pub const HASHES_FILE: &str = "SHA256SUMS";
+
+/// The SHA-256 of the file at `path` (as hex digits).
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hash = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hash.update(&buffer[..n]);
+    }
+    Ok(hash.finish())
+}
+
+/// All files below `dir` (relative to `root`) in a stable order, without the
+/// hashes themselves and the lock file (it changes with every invocation).
+fn files(root: &Path, dir: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            files(root, &entry, found)?;
+        } else if entry.is_file() {
+            let relative = entry.strip_prefix(root).unwrap_or(&entry).to_path_buf();
+            if relative != Path::new(HASHES_FILE) && relative != Path::new(LOCK_FILE) {
+                found.push(relative);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the hashes of all files in `dir` to `SHA256SUMS` and returns how many
+/// there are.
+pub fn save_hashes(dir: &Path) -> io::Result<usize> {
+    let mut found = Vec::new();
+    files(dir, dir, &mut found)?;
+    let mut sums = File::create(dir.join(HASHES_FILE))?;
+    for file in found.iter() {
+        writeln!(sums, "{}  {}", hash_file(&dir.join(file))?, file.display())?;
+    }
+    sums.flush()?;
+    debug!("Saved the hashes of {} files in {:?}", found.len(), dir);
+    Ok(found.len())
+}
+
+/// Reads `SHA256SUMS` of `dir` as (file, hash).
+fn read_hashes(dir: &Path) -> io::Result<Vec<(PathBuf, String)>> {
+    let sums = File::open(dir.join(HASHES_FILE))?;
+    let mut hashes = Vec::new();
+    for line in BufReader::new(sums).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // `sha256sum -b` marks the file with '*' instead of a space:
+        match line.find([' ', '*']) {
+            Some(idx) if idx == 64 && line.len() > 66 => {
+                hashes.push((PathBuf::from(&line[66..]), line[..64].to_lowercase()))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("not a line of {}: '{}'", HASHES_FILE, line),
+                ))
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// What `verify` found.
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// Files that are as they were.
+    pub intact: usize,
+    /// Files that aren't (truncated, edited), or that we can't read.
+    pub changed: Vec<PathBuf>,
+    pub missing: Vec<PathBuf>,
+    /// Files that were added later (they can't be checked).
+    pub unlisted: Vec<PathBuf>,
+}
+
+impl Verification {
+    /// Is every file that has a hash as it was?
+    pub fn ok(&self) -> bool {
+        self.changed.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Checks the files in `dir` against its `SHA256SUMS`.
+pub fn verify(dir: &Path) -> io::Result<Verification> {
+    let hashes = read_hashes(dir)?;
+    let mut verification = Verification::default();
+    for (file, hash) in hashes.iter() {
+        let path = dir.join(file);
+        if !path.exists() {
+            verification.missing.push(file.clone());
+            continue;
+        }
+        match hash_file(&path) {
+            Ok(ref actual) if actual == hash => verification.intact += 1,
+            Ok(_) => verification.changed.push(file.clone()),
+            Err(e) => {
+                error!("Can't read {:?}: {}", path, e);
+                verification.changed.push(file.clone());
+            }
+        }
+    }
+
+    let mut found = Vec::new();
+    files(dir, dir, &mut found)?;
+    verification.unlisted = found
+        .into_iter()
+        .filter(|f| !hashes.iter().any(|(listed, _)| listed == f))
+        .collect();
+    Ok(verification)
+}
+
+/// Saves the hashes of `dir` once we're done writing to it (logs what went wrong).
+pub fn save_or_log(dir: &Path) {
+    if !dir.is_dir() {
+        return;
+    }
+    match save_hashes(dir) {
+        Ok(files) => info!(
+            "Saved the hashes of {} files to {:?}",
+            files,
+            dir.join(HASHES_FILE)
+        ),
+        Err(e) => error!("Can't save the hashes of {:?}: {}", dir, e),
+    }
+}
@@ -0,0 +1,549 @@
+//! How much a `pair` campaign's antagonists slow their victims down, and
+//! through what. For every co-located run (`<A>_vs_<B>`) we look up the run of
+//! A alone in the same placement (`experiment.alone`) and write two tables:
+//!
+//! * `interference_deltas.csv`: every event of A alone and next to B (the means
+//!   of its interval totals, like `diff`), by how many percent it changed and
+//!   the Mann-Whitney p-value of the change.
+//! * `interference_attribution.csv`: A's slowdown (its IPC alone over its IPC
+//!   in every point of time of the run) cross-correlated with the signals of B
+//!   (its memory bandwidth and LLC occupancy from resctrl, its counters if perf
+//!   counted B's CPUs), resampled on a common grid like `align` does. For every
+//!   signal we keep the lag (within `--max-lag`) with the strongest correlation,
+//!   and the signals of a placement are ranked by it:
+//!
+//! ```text
+//! PLACEMENT,RANK,A,B,SLOWDOWN,SIGNAL,CORRELATION,R_SQUARED,LAG_SECONDS,SAMPLES
+//! L3-SMT,1,gcc,stream,1.42,B.mbm_total_bps,0.91,0.83,0.5,240
+//! L3-SMT,2,gcc,stream,1.42,B.llc_occupancy,0.64,0.41,0,240
+//! ```
+//!
+//! A positive lag means the slowdown follows the signal. Correlation doesn't make
+//! B's bandwidth the cause, but a signal that explains most of the slowdown is
+//! where to look first.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use regex::Regex;
+use tracing::{debug, info, warn};
+
+use crate::align::{self, AlignSettings, Grid, Method};
+use crate::archive;
+use crate::diff::{self, mann_whitney, mean};
+use crate::normalize::{CYCLES, INSTRUCTIONS};
+
+/// The signals of B we correlate with, if not given (a `--signals` pattern).
+pub const DEFAULT_SIGNALS: &str = "llc_occupancy,mbm_total_bps,mbm_local_bps";
+
+/// Correlations of fewer points (at a lag) aren't reported.
+const MIN_SAMPLES: usize = 10;
+
+pub struct InterferenceSettings {
+    /// Seconds between the points of the grid A's slowdown and B's signals are
+    /// resampled on.
+    pub step: f64,
+    /// How far (in seconds) the signals may lead or lag the slowdown.
+    pub max_lag: f64,
+    /// Which series of B are signals.
+    pub signals: Regex,
+}
+
+/// A run of the campaign, after the programs in its run.toml.
+pub struct CampaignRun {
+    /// The name of the configuration the run is in (e.g. `L3-SMT`).
+    pub placement: String,
+    /// The name of the run directory (without the archive extension).
+    pub name: String,
+    pub a: String,
+    pub b: Option<String>,
+    /// How many cores A (and B) ran on.
+    pub cores: usize,
+    /// Directory or archive.
+    pub path: PathBuf,
+}
+
+impl CampaignRun {
+    /// The (placement, name) of the run of A alone a co-located run is compared
+    /// with: `<A>_vs_<B>_cat4` ran next to `<A>_cat4`.
+    pub fn solo(&self) -> Option<(String, String)> {
+        let b = self.b.as_ref()?;
+        Some((
+            self.placement.clone(),
+            self.name.replacen(&format!("_vs_{}", b), "", 1),
+        ))
+    }
+}
+
+/// What we need of a run of A alone.
+struct Solo {
+    totals: BTreeMap<String, Vec<f64>>,
+    ipc: Option<f64>,
+}
+
+/// A row of `interference_deltas.csv`.
+struct Delta {
+    placement: String,
+    a: String,
+    b: String,
+    event: String,
+    n_solo: usize,
+    n_paired: usize,
+    mean_solo: f64,
+    mean_paired: f64,
+    p_value: f64,
+}
+
+impl Delta {
+    fn percent(&self) -> Option<f64> {
+        if self.mean_solo != 0.0 {
+            Some((self.mean_paired - self.mean_solo) / self.mean_solo * 100.0)
+        } else {
+            None
+        }
+    }
+}
+
+/// A row of `interference_attribution.csv`.
+struct Attribution {
+    placement: String,
+    a: String,
+    b: String,
+    slowdown: Option<f64>,
+    signal: String,
+    correlation: f64,
+    lag: f64,
+    samples: usize,
+}
+
+/// The name of a program in a run.toml.
+fn program_name(doc: &toml::Table, program: &str) -> Option<String> {
+    doc.get(program)
+        .and_then(|p| p.lookup("name"))
+        .and_then(|n| n.as_str())
+        .map(String::from)
+}
+
+/// The (socket, core) of every CPU of `program` in the deployment of a run.toml.
+fn deployment_cores(doc: &toml::Table, program: &str) -> BTreeSet<(i64, i64)> {
+    doc.get("deployment")
+        .and_then(|d| d.lookup(program))
+        .and_then(|p| p.as_slice())
+        .map_or(BTreeSet::new(), |cpus| {
+            cpus.iter()
+                .filter_map(|c| {
+                    let field = |f: &str| c.lookup(f).and_then(|v| v.as_integer());
+                    Some((field("socket")?, field("core")?))
+                })
+                .collect()
+        })
+}
+
+/// Reads which programs ran in `run_directory`.
+fn read_run(configuration: &str, path: &Path, run_directory: &Path) -> Option<CampaignRun> {
+    let content = fs::read_to_string(run_directory.join("run.toml")).ok()?;
+    let doc = toml::Parser::new(&content).parse()?;
+    let configuration = Path::new(configuration);
+    let b = program_name(&doc, "b");
+    let mut cores = deployment_cores(&doc, "a");
+    if b.is_some() {
+        cores.extend(deployment_cores(&doc, "b"));
+    }
+    Some(CampaignRun {
+        placement: configuration
+            .parent()
+            .map_or(String::new(), |p| p.to_string_lossy().into_owned()),
+        name: configuration
+            .file_name()
+            .map_or(String::new(), |n| n.to_string_lossy().into_owned()),
+        a: program_name(&doc, "a")?,
+        b,
+        cores: cores.len(),
+        path: path.to_path_buf(),
+    })
+}
+
+/// The first of `names` that's in `totals`.
+pub fn first_of<'a>(
+    totals: &'a BTreeMap<String, Vec<f64>>,
+    names: &[&str],
+) -> Option<&'a Vec<f64>> {
+    names.iter().filter_map(|n| totals.get(*n)).next()
+}
+
+/// Instructions per cycle over all intervals.
+pub fn ipc(totals: &BTreeMap<String, Vec<f64>>) -> Option<f64> {
+    let instructions: f64 = first_of(totals, &INSTRUCTIONS)?.iter().sum();
+    let cycles: f64 = first_of(totals, &CYCLES)?.iter().sum();
+    if cycles > 0.0 {
+        Some(instructions / cycles)
+    } else {
+        None
+    }
+}
+
+/// A's slowdown at every point of the grid (IPC alone over IPC at that time).
+fn slowdown(grid: &Grid, solo_ipc: f64) -> Option<Vec<Option<f64>>> {
+    let series = |names: &[&str]| {
+        names
+            .iter()
+            .filter_map(|n| grid.series(&format!("A.{}", n)))
+            .next()
+    };
+    let (instructions, cycles) = (series(&INSTRUCTIONS)?, series(&CYCLES)?);
+    Some(
+        instructions
+            .iter()
+            .zip(cycles.iter())
+            .map(|(i, c)| match (i, c) {
+                (Some(i), Some(c)) if *i > 0.0 && *c > 0.0 => Some(solo_ipc / (i / c)),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+/// Pearson's correlation of the points where both have a value.
+fn pearson(pairs: &[(f64, f64)]) -> Option<f64> {
+    let xs: Vec<f64> = pairs.iter().map(|p| p.0).collect();
+    let ys: Vec<f64> = pairs.iter().map(|p| p.1).collect();
+    let (mean_x, mean_y) = (mean(&xs), mean(&ys));
+    let (mut cov, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for &(x, y) in pairs {
+        cov += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x).powi(2);
+        var_y += (y - mean_y).powi(2);
+    }
+    if var_x > 0.0 && var_y > 0.0 {
+        Some(cov / (var_x * var_y).sqrt())
+    } else {
+        None
+    }
+}
+
+/// The strongest correlation of `signal` (shifted by up to `max_lag` points)
+/// with `slowdown`: (correlation, lag in points, samples).
+fn cross_correlate(
+    slowdown: &[Option<f64>],
+    signal: &[Option<f64>],
+    max_lag: usize,
+) -> Option<(f64, i64, usize)> {
+    let mut best: Option<(f64, i64, usize)> = None;
+    for lag in -(max_lag as i64)..=(max_lag as i64) {
+        // The slowdown at `i` against the signal `lag` points earlier:
+        let pairs: Vec<(f64, f64)> = (0..slowdown.len() as i64)
+            .filter(|i| i - lag >= 0 && i - lag < signal.len() as i64)
+            .filter_map(
+                |i| match (slowdown[i as usize], signal[(i - lag) as usize]) {
+                    (Some(s), Some(v)) => Some((s, v)),
+                    _ => None,
+                },
+            )
+            .collect();
+        if pairs.len() < MIN_SAMPLES {
+            continue;
+        }
+        if let Some(r) = pearson(&pairs) {
+            // Ties go to the smaller lag:
+            let better = best.is_none_or(|(b, l, _)| {
+                r.abs() > b.abs() || (r.abs() == b.abs() && lag.abs() < l.abs())
+            });
+            if better {
+                best = Some((r, lag, pairs.len()));
+            }
+        }
+    }
+    best
+}
+
+/// The series `align` has to read: A's instructions and cycles, and the signals.
+fn align_settings(settings: &InterferenceSettings) -> AlignSettings {
+    let ipc_events: Vec<String> = INSTRUCTIONS
+        .iter()
+        .chain(CYCLES.iter())
+        .map(|e| regex::escape(e))
+        .collect();
+    let pattern = format!(
+        "^(?:{})$|(?:{})",
+        ipc_events.join("|"),
+        settings.signals.as_str()
+    );
+    AlignSettings {
+        step: settings.step,
+        method: Method::Linear,
+        events: Some(Regex::new(&pattern).expect("The signals are a valid pattern")),
+    }
+}
+
+/// Cross-correlates A's slowdown with the signals of B in a co-located run.
+fn attribute(
+    run: &CampaignRun,
+    run_directory: &Path,
+    solo_ipc: f64,
+    slowdown_overall: Option<f64>,
+    settings: &InterferenceSettings,
+) -> io::Result<Vec<Attribution>> {
+    let programs = align::read_programs(run_directory, &align_settings(settings))?;
+    let grid = align::resample(&programs, settings.step, Method::Linear)?;
+    let slowdown = match slowdown(&grid, solo_ipc) {
+        Some(slowdown) => slowdown,
+        None => {
+            warn!(
+                "{}/{} has no instructions and cycles of A over time, can't attribute its slowdown.",
+                run.placement, run.name
+            );
+            return Ok(Vec::new());
+        }
+    };
+    let max_lag = (settings.max_lag / settings.step).round() as usize;
+    let mut attributions = Vec::new();
+    for (name, values) in grid.names.iter().zip(grid.values.iter()) {
+        let signal = match name.strip_prefix("B.") {
+            Some(signal) if settings.signals.is_match(signal) => signal,
+            _ => continue,
+        };
+        match cross_correlate(&slowdown, values, max_lag) {
+            Some((correlation, lag, samples)) => attributions.push(Attribution {
+                placement: run.placement.clone(),
+                a: run.a.clone(),
+                b: run.b.clone().unwrap_or_default(),
+                slowdown: slowdown_overall,
+                signal: name.clone(),
+                correlation,
+                lag: lag as f64 * settings.step,
+                samples,
+            }),
+            None => debug!(
+                "{} and the slowdown of A in {}/{} don't overlap (or are constant)",
+                signal, run.placement, run.name
+            ),
+        }
+    }
+    Ok(attributions)
+}
+
+/// Reads a run (extracting it if it's an archive) and cleans up after `f`.
+pub fn with_run<T, F: FnOnce(&Path) -> T>(run: &Path, tmp: &Path, f: F) -> T {
+    let directory = diff::run_directory(run, tmp);
+    let result = f(&directory);
+    if archive::is_archive(run) {
+        let _ = fs::remove_dir_all(tmp);
+    }
+    result
+}
+
+fn write_deltas(deltas: &[Delta], path: &Path) -> io::Result<()> {
+    let mut wtr = csv::Writer::from_file(path).map_err(io::Error::other)?;
+    wtr.encode([
+        "PLACEMENT",
+        "A",
+        "B",
+        "EVENT_NAME",
+        "N_SOLO",
+        "N_PAIRED",
+        "MEAN_SOLO",
+        "MEAN_PAIRED",
+        "DELTA_PERCENT",
+        "MANN_WHITNEY_P",
+    ])
+    .unwrap();
+    for d in deltas {
+        wtr.encode((
+            &d.placement,
+            &d.a,
+            &d.b,
+            &d.event,
+            d.n_solo,
+            d.n_paired,
+            d.mean_solo,
+            d.mean_paired,
+            d.percent().map_or(String::new(), |p| p.to_string()),
+            d.p_value,
+        ))
+        .unwrap();
+    }
+    Ok(())
+}
+
+fn write_attributions(attributions: &[Attribution], path: &Path) -> io::Result<()> {
+    let mut wtr = csv::Writer::from_file(path).map_err(io::Error::other)?;
+    wtr.encode([
+        "PLACEMENT",
+        "RANK",
+        "A",
+        "B",
+        "SLOWDOWN",
+        "SIGNAL",
+        "CORRELATION",
+        "R_SQUARED",
+        "LAG_SECONDS",
+        "SAMPLES",
+    ])
+    .unwrap();
+    let mut rank = 0;
+    for (idx, a) in attributions.iter().enumerate() {
+        rank = if idx > 0 && attributions[idx - 1].placement == a.placement {
+            rank + 1
+        } else {
+            1
+        };
+        wtr.encode((
+            &a.placement,
+            rank,
+            &a.a,
+            &a.b,
+            a.slowdown.map_or(String::new(), |s| s.to_string()),
+            &a.signal,
+            a.correlation,
+            a.correlation * a.correlation,
+            a.lag,
+            a.samples,
+        ))
+        .unwrap();
+    }
+    Ok(())
+}
+
+/// Where runs in archives are extracted to.
+pub fn tmp_directory() -> PathBuf {
+    let mut tmp = std::env::temp_dir();
+    tmp.push(format!("autoperf-{}", process::id()));
+    tmp
+}
+
+/// The runs of the campaign in `campaign` (extracting archives into `tmp`).
+pub fn campaign_runs(campaign: &Path, tmp: &Path) -> Vec<CampaignRun> {
+    let mut runs = Vec::new();
+    for (configuration, path) in diff::configurations(campaign) {
+        match with_run(&path, tmp, |dir| read_run(&configuration, &path, dir)) {
+            Some(run) => runs.push(run),
+            None => warn!("Can't read the run.toml of {:?}, ignoring it.", path),
+        }
+    }
+    runs
+}
+
+/// Compares the co-located runs of the campaign in `campaign` with the runs of
+/// A alone and writes `interference_deltas.csv` and
+/// `interference_attribution.csv` to `output_path` (see the module doc).
+/// Returns how many co-located runs were compared.
+pub fn interference(
+    campaign: &Path,
+    settings: &InterferenceSettings,
+    output_path: &Path,
+) -> io::Result<usize> {
+    let tmp = tmp_directory();
+    let runs = campaign_runs(campaign, &tmp);
+
+    // (placement, run name) -> A alone
+    let mut solos: BTreeMap<(String, String), Solo> = BTreeMap::new();
+    for run in runs.iter().filter(|r| r.b.is_none()) {
+        debug!("Reading {}/{} (alone)", run.placement, run.name);
+        let totals = with_run(&run.path, &tmp, diff::interval_totals);
+        let ipc = ipc(&totals);
+        solos.insert(
+            (run.placement.clone(), run.name.clone()),
+            Solo { totals, ipc },
+        );
+    }
+
+    let mut deltas = Vec::new();
+    let mut attributions = Vec::new();
+    let mut compared = 0;
+    for run in runs.iter() {
+        let b = match run.b {
+            Some(ref b) => b,
+            None => continue,
+        };
+        let solo = match run.solo().and_then(|solo| solos.get(&solo)) {
+            Some(solo) => solo,
+            None => {
+                warn!(
+                    "{}/{} has no run of {} alone to compare with (experiment.alone), ignoring it.",
+                    run.placement, run.name, run.a
+                );
+                continue;
+            }
+        };
+        debug!(
+            "Comparing {}/{} with {} alone",
+            run.placement, run.name, run.a
+        );
+        compared += 1;
+        let result = with_run(&run.path, &tmp, |dir| {
+            let totals = diff::interval_totals(dir);
+            let slowdown = match (solo.ipc, ipc(&totals)) {
+                (Some(solo), Some(paired)) if paired > 0.0 => Some(solo / paired),
+                _ => None,
+            };
+            let attributed = match solo.ipc {
+                Some(solo_ipc) => attribute(run, dir, solo_ipc, slowdown, settings),
+                None => Ok(Vec::new()),
+            };
+            (totals, attributed)
+        });
+        let (totals, attributed) = result;
+        for (event, paired) in totals.iter() {
+            let alone = match solo.totals.get(event) {
+                Some(alone) if !alone.is_empty() && !paired.is_empty() => alone,
+                _ => continue,
+            };
+            deltas.push(Delta {
+                placement: run.placement.clone(),
+                a: run.a.clone(),
+                b: b.clone(),
+                event: event.clone(),
+                n_solo: alone.len(),
+                n_paired: paired.len(),
+                mean_solo: mean(alone),
+                mean_paired: mean(paired),
+                p_value: mann_whitney(alone, paired).0,
+            });
+        }
+        match attributed {
+            Ok(attributed) => attributions.extend(attributed),
+            Err(e) => warn!(
+                "Can't align the series of {}/{} ({}), no attribution for it.",
+                run.placement, run.name, e
+            ),
+        }
+    }
+    if solos.is_empty() {
+        warn!("The campaign has no runs of programs alone, set experiment.alone to compare with them.");
+    }
+
+    // The largest changes (and strongest signals) of a placement first:
+    deltas.sort_by(|x, y| {
+        (&x.placement, &x.a, &x.b)
+            .cmp(&(&y.placement, &y.a, &y.b))
+            .then(
+                y.percent()
+                    .map_or(0.0, f64::abs)
+                    .partial_cmp(&x.percent().map_or(0.0, f64::abs))
+                    .unwrap_or(std::cmp::Ordering::Equal),
+            )
+    });
+    attributions.sort_by(|x, y| {
+        x.placement.cmp(&y.placement).then(
+            y.correlation
+                .abs()
+                .partial_cmp(&x.correlation.abs())
+                .unwrap_or(std::cmp::Ordering::Equal),
+        )
+    });
+
+    let deltas_csv = output_path.join("interference_deltas.csv");
+    write_deltas(&deltas, &deltas_csv)?;
+    let attribution_csv = output_path.join("interference_attribution.csv");
+    write_attributions(&attributions, &attribution_csv)?;
+    info!(
+        "Saved {} deltas in {:?} and {} signals in {:?}",
+        deltas.len(),
+        deltas_csv,
+        attributions.len(),
+        attribution_csv
+    );
+    Ok(compared)
+}
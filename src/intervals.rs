@@ -0,0 +1,223 @@
+//! Keeps the interval samples of perf stat small: at 10 ms and hundreds of
+//! events a `<nr>_stat.csv` grows by megabytes every second. With
+//! `--compress-intervals` we follow the stat file while perf writes it and
+//! append every chunk of complete lines (`CHUNK_SIZE`) as a zstd frame of its
+//! own to `<nr>_stat.csv.zst`. What's compressed is punched out of the stat file
+//! (but the last `LIVE_TAIL`, the dashboard and the streamers read the latest
+//! intervals there), so it never takes more than a few megabytes on disk, and
+//! once perf is done it's removed.
+//!
+//! The frames are independent: a crash loses at most the chunk we were working
+//! on, and `zstdcat 1_stat.csv.zst` gives back the file perf wrote. perf.csv
+//! still names `<nr>_stat.csv`, `perfstat::counts` and `perfstat::metrics` read
+//! the compressed file if the stat file isn't there.
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, error};
+
+/// The extension of compressed stat files (after the one of the stat file).
+pub const EXTENSION: &str = "zst";
+
+/// How many bytes of lines go in a chunk (before compression).
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// How much of the end of the stat file stays, once it's compressed.
+const LIVE_TAIL: u64 = 2 * 1024 * 1024;
+
+/// zstd compression level (the intervals repeat a lot, level 3 gets most of it fast).
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// How often we look for new lines.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Where the compressed intervals of `stat_file` are.
+pub fn compressed_path(stat_file: &Path) -> PathBuf {
+    let mut name = stat_file.as_os_str().to_os_string();
+    name.push(".");
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Opens a stat file, or its compressed intervals if there is no stat file.
+pub fn open(stat_file: &Path) -> io::Result<Box<dyn Read>> {
+    match File::open(stat_file) {
+        Ok(file) => Ok(Box::new(file)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let compressed = File::open(compressed_path(stat_file)).map_err(|_| e)?;
+            Ok(Box::new(zstd::Decoder::new(compressed)?))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Follows a stat file and writes its lines to the compressed file in chunks.
+struct Follower {
+    stat_file: PathBuf,
+    /// Opened once perf created it.
+    file: Option<File>,
+    compressed: File,
+    /// Read, but not compressed yet.
+    pending: Vec<u8>,
+    /// How much of the stat file went into chunks.
+    done: u64,
+    /// How much of the stat file we've punched out (if the file system can).
+    punched: Option<u64>,
+    chunks: usize,
+}
+
+impl Follower {
+    fn new(stat_file: &Path) -> io::Result<Follower> {
+        Ok(Follower {
+            stat_file: stat_file.to_path_buf(),
+            file: None,
+            compressed: File::create(compressed_path(stat_file))?,
+            pending: Vec::new(),
+            done: 0,
+            punched: Some(0),
+            chunks: 0,
+        })
+    }
+
+    /// Compresses the complete chunks perf has written (all of it, if `last`).
+    fn poll(&mut self, last: bool) -> io::Result<()> {
+        if self.file.is_none() {
+            self.file = match File::open(&self.stat_file) {
+                Ok(file) => Some(file),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e),
+            };
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.read_to_end(&mut self.pending)?;
+        }
+        while self.pending.len() >= CHUNK_SIZE {
+            // Chunks end with a line, perf may still be writing the one after it:
+            let end = match self.pending[..CHUNK_SIZE].iter().rposition(|b| *b == b'\n') {
+                Some(idx) => idx + 1,
+                None => CHUNK_SIZE,
+            };
+            let chunk: Vec<u8> = self.pending.drain(..end).collect();
+            self.write_chunk(&chunk)?;
+        }
+        if last && !self.pending.is_empty() {
+            let chunk = std::mem::take(&mut self.pending);
+            self.write_chunk(&chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let frame = zstd::encode_all(chunk, COMPRESSION_LEVEL)?;
+        // A frame at a time, readers never see half of one (unless we crash):
+        self.compressed.write_all(&frame)?;
+        self.compressed.flush()?;
+        self.done += chunk.len() as u64;
+        self.chunks += 1;
+        self.punch();
+        Ok(())
+    }
+
+    /// Frees the parts of the stat file that are compressed, the file keeps its
+    /// size (perf keeps writing at its end).
+    #[cfg(target_os = "linux")]
+    fn punch(&mut self) {
+        use std::os::unix::io::AsRawFd;
+
+        let (file, punched) = match (self.file.as_ref(), self.punched) {
+            (Some(file), Some(punched)) => (file, punched),
+            _ => return,
+        };
+        // In whole pages, file systems only free those:
+        let until = self.done.saturating_sub(LIVE_TAIL) & !4095;
+        if until <= punched {
+            return;
+        }
+        let r = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                punched as libc::off_t,
+                (until - punched) as libc::off_t,
+            )
+        };
+        if r == 0 {
+            self.punched = Some(until);
+        } else {
+            debug!(
+                "Can't punch holes into {:?} ({}), it stays until perf is done.",
+                self.stat_file,
+                io::Error::last_os_error()
+            );
+            self.punched = None;
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn punch(&mut self) {}
+}
+
+/// Compresses the intervals of a stat file while perf writes it.
+pub struct IntervalCompressor {
+    stat_file: PathBuf,
+    stop: mpsc::Sender<()>,
+    /// How many chunks there are (`None` if perf didn't write a stat file).
+    worker: thread::JoinHandle<io::Result<Option<usize>>>,
+}
+
+impl IntervalCompressor {
+    /// Starts following `stat_file` (perf doesn't have to have created it yet).
+    pub fn start(stat_file: &Path) -> io::Result<IntervalCompressor> {
+        let mut follower = Follower::new(stat_file)?;
+        let (stop, stopped) = mpsc::channel::<()>();
+        let worker = thread::spawn(move || {
+            loop {
+                follower.poll(false)?;
+                match stopped.recv_timeout(POLL_INTERVAL) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            }
+            follower.poll(true)?;
+            Ok(follower.file.as_ref().map(|_| follower.chunks))
+        });
+        Ok(IntervalCompressor {
+            stat_file: stat_file.to_path_buf(),
+            stop,
+            worker,
+        })
+    }
+
+    /// Compresses the rest once perf exited and removes the stat file. If
+    /// something went wrong, both files stay.
+    pub fn finish(self) {
+        let _ = self.stop.send(());
+        let compressed = compressed_path(&self.stat_file);
+        match self.worker.join() {
+            Ok(Ok(None)) => {
+                let _ = fs::remove_file(&compressed);
+            }
+            Ok(Ok(Some(chunks))) => {
+                debug!("Compressed {:?} in {} chunks", self.stat_file, chunks);
+                if let Err(e) = fs::remove_file(&self.stat_file) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        error!("Can't remove {:?}: {}", self.stat_file, e);
+                    }
+                }
+            }
+            // Parts of the stat file may be punched out already:
+            Ok(Err(e)) => error!(
+                "Can't compress {:?} ({}), the first intervals are in {:?}.",
+                self.stat_file, e, compressed
+            ),
+            Err(_) => error!(
+                "Compressing {:?} failed, the first intervals are in {:?}.",
+                self.stat_file, compressed
+            ),
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! The disk and file-system I/O of a measurement, so a program that waits for
+//! its storage isn't mistaken for one that suffers from interference in the
+//! core or the uncore:
+//!
+//! * What every block device did while perf was running, the difference of
+//!   `/proc/diskstats` before and after the measurement (`io.csv`).
+//! * What every process of the program read and wrote, from `/proc/<pid>/io`
+//!   (`<nr>_pid_io.csv`). We poll the processes while they run, the last
+//!   moments of a process that exits are lost.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use super::threads;
+
+/// How often we read /proc/<pid>/io of the program's processes.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// /proc/diskstats counts in sectors of 512 bytes (whatever the device uses).
+const SECTOR_SIZE: u64 = 512;
+
+/// The columns of `io.csv`.
+pub const DEVICE_COLUMNS: [&str; 9] = [
+    "datafile",
+    "device",
+    "read_bytes",
+    "write_bytes",
+    "reads",
+    "writes",
+    "seconds",
+    "read_iops",
+    "write_iops",
+];
+
+/// What a block device did (since boot, or between two points in time).
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct DeviceIo {
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    /// Completed requests (merged requests count once).
+    pub reads: u64,
+    pub writes: u64,
+}
+
+/// The I/O of every block device at some point in time.
+pub struct DiskStats {
+    taken: Instant,
+    devices: BTreeMap<String, DeviceIo>,
+}
+
+/// `   8       0 sda 5309 1245 413716 2211 3040 2724 134632 5672 0 4132 7883 ...`
+fn parse_diskstats(content: &str) -> BTreeMap<String, DeviceIo> {
+    let mut devices = BTreeMap::new();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 {
+            continue;
+        }
+        let field = |idx: usize| u64::from_str(fields[idx]).unwrap_or(0);
+        devices.insert(
+            String::from(fields[2]),
+            DeviceIo {
+                read_bytes: field(5) * SECTOR_SIZE,
+                write_bytes: field(9) * SECTOR_SIZE,
+                reads: field(3),
+                writes: field(7),
+            },
+        );
+    }
+    devices
+}
+
+/// `count` per second of `seconds`.
+fn per_second(count: u64, seconds: f64) -> f64 {
+    if seconds > 0.0 {
+        count as f64 / seconds
+    } else {
+        0.0
+    }
+}
+
+impl DiskStats {
+    pub fn now() -> DiskStats {
+        DiskStats {
+            taken: Instant::now(),
+            devices: fs::read_to_string("/proc/diskstats")
+                .map(|content| parse_diskstats(&content))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The devices that did I/O between `before` and now, and how many seconds
+    /// passed.
+    pub fn since(before: &DiskStats) -> (Vec<(String, DeviceIo)>, f64) {
+        let now = DiskStats::now();
+        let seconds = now.taken.duration_since(before.taken).as_secs_f64();
+        let devices = now
+            .devices
+            .into_iter()
+            .filter_map(|(device, after)| {
+                // Devices that appeared in between did all of it in between:
+                let b = before.devices.get(&device).cloned().unwrap_or_default();
+                let delta = DeviceIo {
+                    read_bytes: after.read_bytes.saturating_sub(b.read_bytes),
+                    write_bytes: after.write_bytes.saturating_sub(b.write_bytes),
+                    reads: after.reads.saturating_sub(b.reads),
+                    writes: after.writes.saturating_sub(b.writes),
+                };
+                if delta == DeviceIo::default() {
+                    None
+                } else {
+                    Some((device, delta))
+                }
+            })
+            .collect();
+        (devices, seconds)
+    }
+}
+
+/// Writes what the block devices did during the measurement `datafile` (see
+/// `DiskStats::since`) to `wtr`.
+pub fn encode_devices<W: io::Write>(
+    wtr: &mut csv::Writer<W>,
+    datafile: &str,
+    devices: &[(String, DeviceIo)],
+    seconds: f64,
+) -> csv::Result<()> {
+    for (device, delta) in devices.iter() {
+        wtr.encode((
+            datafile,
+            device,
+            delta.read_bytes,
+            delta.write_bytes,
+            delta.reads,
+            delta.writes,
+            seconds,
+            per_second(delta.reads, seconds),
+            per_second(delta.writes, seconds),
+        ))?;
+    }
+    Ok(())
+}
+
+/// What a process read and wrote (see `proc(5)`): (pid, name, rchar, wchar,
+/// read_bytes, write_bytes, syscr, syscw).
+pub type ProcessIo = (u64, String, u64, u64, u64, u64, u64, u64);
+
+/// `rchar: 323934931\nwchar: 323929600\nsyscr: 632687\n...`
+fn parse_pid_io(pid: u64, name: String, content: &str) -> ProcessIo {
+    let mut io = (pid, name, 0, 0, 0, 0, 0, 0);
+    for line in content.lines() {
+        let mut parts = line.splitn(2, ':');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim(), u64::from_str(value.trim()).unwrap_or(0)),
+            _ => continue,
+        };
+        match key {
+            "rchar" => io.2 = value,
+            "wchar" => io.3 = value,
+            "read_bytes" => io.4 = value,
+            "write_bytes" => io.5 = value,
+            "syscr" => io.6 = value,
+            "syscw" => io.7 = value,
+            _ => {}
+        }
+    }
+    io
+}
+
+/// Reads the I/O of all processes spawned by `parent` (the counters only grow,
+/// the latest values are all of it).
+fn scan(parent: u64, processes: &mut BTreeMap<u64, ProcessIo>) {
+    for pid in threads::descendants(parent) {
+        let name = fs::read_to_string(format!("/proc/{}/comm", pid))
+            .map(|n| String::from(n.trim()))
+            .unwrap_or(String::new());
+        // Needs the same permissions as ptrace:
+        if let Ok(content) = fs::read_to_string(format!("/proc/{}/io", pid)) {
+            processes.insert(pid, parse_pid_io(pid, name, &content));
+        }
+    }
+}
+
+pub struct IoTracker {
+    started: Instant,
+    stop: mpsc::Sender<()>,
+    tracker: thread::JoinHandle<BTreeMap<u64, ProcessIo>>,
+}
+
+impl IoTracker {
+    /// Starts reading the I/O of all processes spawned by `parent` (i.e., perf).
+    pub fn start(parent: u32) -> IoTracker {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let tracker = thread::spawn(move || {
+            let mut processes = BTreeMap::new();
+            loop {
+                scan(parent as u64, &mut processes);
+                match stopped.recv_timeout(Duration::from_millis(POLL_INTERVAL_MS)) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            }
+            processes
+        });
+
+        IoTracker {
+            started: Instant::now(),
+            stop,
+            tracker,
+        }
+    }
+
+    /// Stops reading and saves the I/O of every process we've seen to `save_to`
+    /// (with the IOPS over the whole measurement).
+    pub fn finish(self, save_to: &Path) -> io::Result<Vec<ProcessIo>> {
+        let _ = self.stop.send(());
+        let seconds = self.started.elapsed().as_secs_f64();
+        let processes: Vec<ProcessIo> = self
+            .tracker
+            .join()
+            .unwrap_or_default()
+            .into_values()
+            .collect();
+        debug!("Read the I/O of {} processes.", processes.len());
+
+        let to_io_error = |e: csv::Error| io::Error::other(e);
+        let mut wtr = csv::Writer::from_file(save_to).map_err(to_io_error)?;
+        wtr.encode((
+            "pid",
+            "name",
+            "rchar",
+            "wchar",
+            "read_bytes",
+            "write_bytes",
+            "syscr",
+            "syscw",
+            "seconds",
+            "read_iops",
+            "write_iops",
+        ))
+        .map_err(to_io_error)?;
+        for p in processes.iter() {
+            wtr.encode((
+                p.0,
+                &p.1,
+                p.2,
+                p.3,
+                p.4,
+                p.5,
+                p.6,
+                p.7,
+                seconds,
+                per_second(p.6, seconds),
+                per_second(p.7, seconds),
+            ))
+            .map_err(to_io_error)?;
+        }
+        wtr.flush().map_err(to_io_error)?;
+
+        Ok(processes)
+    }
+}
@@ -0,0 +1,106 @@
+//! Saves what it takes to resolve the kernel samples of `perf record` somewhere
+//! else: a copy of /proc/kallsyms (`kallsyms`) and the build-ids of the kernel
+//! and its modules (`kernel_build_ids.csv`). perf looks binaries up by build-id,
+//! so together with the matching vmlinux and modules the samples can be resolved
+//! on another machine or after a kernel upgrade (`perf report --kallsyms`, which
+//! `aggregate` uses when it finds the copy).
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use tracing::{debug, warn};
+
+/// The name of the copy of /proc/kallsyms in a run.
+pub const KALLSYMS_FILE: &str = "kallsyms";
+
+/// The type of the ELF note with the build-id.
+const NT_GNU_BUILD_ID: u32 = 3;
+
+fn word(notes: &[u8], at: usize) -> Option<u32> {
+    let bytes = notes.get(at..at + 4)?;
+    Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Finds the GNU build-id in the ELF notes of /sys/kernel/notes or
+/// /sys/module/<module>/notes/.note.gnu.build-id (namesz, descsz, type, name and
+/// desc padded to 4 bytes).
+fn build_id(notes: &[u8]) -> Option<String> {
+    let padded = |n: usize| (n + 3) & !3;
+    let mut at = 0;
+    while at + 12 <= notes.len() {
+        let name_size = word(notes, at)? as usize;
+        let desc_size = word(notes, at + 4)? as usize;
+        let kind = word(notes, at + 8)?;
+        let name = notes.get(at + 12..at + 12 + name_size)?;
+        let desc_at = at + 12 + padded(name_size);
+        if kind == NT_GNU_BUILD_ID && name.starts_with(b"GNU") {
+            let desc = notes.get(desc_at..desc_at + desc_size)?;
+            return Some(desc.iter().map(|b| format!("{:02x}", b)).collect());
+        }
+        at = desc_at + padded(desc_size);
+    }
+    None
+}
+
+/// The build-ids of the running kernel (as `[kernel.kallsyms]`, the way perf
+/// calls it) and all loaded modules that have one.
+fn build_ids() -> Vec<(String, String)> {
+    let mut ids = Vec::new();
+    if let Some(id) = fs::read("/sys/kernel/notes")
+        .ok()
+        .and_then(|n| build_id(&n))
+    {
+        ids.push((String::from("[kernel.kallsyms]"), id));
+    }
+
+    let mut modules: Vec<String> = match fs::read_dir("/sys/module") {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    modules.sort();
+    for module in modules {
+        let notes = format!("/sys/module/{}/notes/.note.gnu.build-id", module);
+        if let Some(id) = fs::read(&notes).ok().and_then(|n| build_id(&n)) {
+            ids.push((module, id));
+        }
+    }
+    ids
+}
+
+/// Saves the kernel symbols and build-ids to `output_path`.
+///
+/// # Note
+/// Unless we're root (or kernel.kptr_restrict is 0) the kernel hides the
+/// addresses in /proc/kallsyms, the copy is useless then.
+pub fn save(output_path: &Path) -> io::Result<()> {
+    let kallsyms = fs::read_to_string("/proc/kallsyms")?;
+    let hidden = kallsyms.lines().all(|l| {
+        l.split_whitespace()
+            .next()
+            .is_none_or(|a| a.trim_start_matches('0').is_empty())
+    });
+    if hidden {
+        warn!(
+            "The kernel hides the addresses in /proc/kallsyms (see kernel.kptr_restrict), \
+             the copy in {:?} can't resolve kernel samples.",
+            output_path
+        );
+    }
+    fs::write(output_path.join(KALLSYMS_FILE), kallsyms)?;
+
+    let ids = build_ids();
+    debug!(
+        "Saving the build-ids of the kernel and {} modules.",
+        ids.len().saturating_sub(1)
+    );
+    let mut wtr = csv::Writer::from_file(output_path.join("kernel_build_ids.csv")).unwrap();
+    wtr.encode(("module", "build_id")).unwrap();
+    for id in ids {
+        wtr.encode(id).unwrap();
+    }
+    wtr.flush().unwrap();
+    Ok(())
+}
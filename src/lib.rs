@@ -0,0 +1,17 @@
+//! The parts of autoperf other programs can use: the topology and deployment
+//! code the binary is built on, the Python module (the python feature, see
+//! `python.rs`) and the C API (the capi feature, see `capi.rs`).
+pub mod bytesize;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod deployment;
+pub mod features;
+pub mod gpu;
+pub mod memtier;
+pub mod nic;
+pub mod numactl;
+pub mod pci;
+#[cfg(feature = "python")]
+mod python;
+pub mod sysctl;
+pub mod util;
@@ -0,0 +1,436 @@
+//! Measures with `likwid-perfctr` instead of perf (`--backend likwid`), for
+//! clusters where perf isn't allowed but likwid's access daemon is available.
+//!
+//! We run the program once per likwid performance group (e.g. MEM, L3, FLOPS_DP,
+//! see `likwid-perfctr -a`) and convert the raw counter values of every hardware
+//! thread into the stat file layout perf writes (`<nr>_stat.csv`), so `aggregate`
+//! and everything after it works as usual. likwid's own output is kept as
+//! `<nr>_likwid.csv`, the metrics it derives for the groups are collected in
+//! `likwid_metrics.csv`.
+//!
+//! With `--likwid-markers`, likwid only reports the regions the program marked
+//! with its marker API (`LIKWID_MARKER_START("solve")` etc.). Every region becomes
+//! an interval of the stat file and a phase (`<nr>_phases.csv`) with the region's
+//! name, so the regions end up in the PHASE column of the results.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{Instant, SystemTime};
+
+use tracing::{error, warn};
+
+use super::housekeeping;
+use super::profile::{create_out_directory, perf_log_writer, save_machine_information};
+use super::summary::{self, Failure};
+use super::util::*;
+use super::workload::Workload;
+
+/// The groups we measure if there is no `--groups`.
+const DEFAULT_GROUPS: [&str; 3] = ["MEM", "L3", "FLOPS_DP"];
+
+#[derive(Debug, Default, Clone)]
+pub struct LikwidSettings {
+    /// The performance groups to measure (one run of the program each).
+    pub groups: Vec<String>,
+    /// Only count the regions marked with likwid's marker API.
+    pub markers: bool,
+}
+
+impl LikwidSettings {
+    fn groups(&self) -> Vec<String> {
+        if !self.groups.is_empty() {
+            return self.groups.clone();
+        }
+        let available = available_groups();
+        let groups: Vec<String> = DEFAULT_GROUPS
+            .iter()
+            .filter(|g| available.is_empty() || available.iter().any(|a| a == *g))
+            .map(|g| String::from(*g))
+            .collect();
+        if groups.len() < DEFAULT_GROUPS.len() {
+            warn!(
+                "likwid doesn't have all of {:?} on this machine, measuring {:?}.",
+                DEFAULT_GROUPS, groups
+            );
+        }
+        groups
+    }
+}
+
+/// The performance groups likwid has for this machine.
+pub fn available_groups() -> Vec<String> {
+    let out = match Command::new("likwid-perfctr").arg("-a").output() {
+        Ok(out) => out,
+        Err(_) => return Vec::new(),
+    };
+    // A header, a line of dashes and then one group per line (name description):
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .skip_while(|l| !l.starts_with("---"))
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next().map(String::from))
+        .collect()
+}
+
+pub fn check_for_likwid() {
+    if Command::new("likwid-perfctr").arg("-v").output().is_err() {
+        error!(
+            "'likwid-perfctr' does not seem to be executable? You may need to install likwid \
+             (or load its module)."
+        );
+        summary::fail(Failure::Environment);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Kind {
+    Raw,
+    Metric,
+}
+
+/// A table of likwid's CSV output (`TABLE,[Region <name>,]Group 1 Raw,MEM,...`).
+#[derive(Debug)]
+struct Table {
+    region: Option<String>,
+    kind: Kind,
+    /// How long the region ran, `RDTSC Runtime [s]` of its info rows (the
+    /// longest of all hardware threads).
+    runtime: Option<f64>,
+    /// The row that names the columns (`Event,Counter,HWThread 0,...`).
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// (column, hardware thread) of the value columns.
+    fn threads(&self) -> Vec<(usize, u64)> {
+        self.header
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, c)| {
+                let c = c.trim();
+                let nr = c
+                    .strip_prefix("HWThread ")
+                    .or_else(|| c.strip_prefix("Core "))?;
+                u64::from_str(nr).ok().map(|nr| (idx, nr))
+            })
+            .collect()
+    }
+}
+
+fn read_tables(path: &Path) -> io::Result<Vec<Table>> {
+    let mut rdr = csv::Reader::from_file(path)
+        .map_err(io::Error::other)?
+        .has_headers(false)
+        .flexible(true);
+
+    let mut tables: Vec<Table> = Vec::new();
+    // Are we in a table we care about?
+    let mut in_table = false;
+    for record in rdr.records().filter_map(|r| r.ok()) {
+        let first = record.first().map_or("", |c| c.trim());
+        match first {
+            "TABLE" => {
+                let title: Vec<&str> = record[1..].iter().map(|c| c.trim()).collect();
+                let region = title
+                    .iter()
+                    .find(|c| c.starts_with("Region "))
+                    .map(|c| String::from(&c["Region ".len()..]));
+                let kind = if title.iter().any(|c| c.ends_with("Stat")) {
+                    None
+                } else if title.iter().any(|c| c.ends_with("Raw")) {
+                    Some(Kind::Raw)
+                } else if title.iter().any(|c| c.ends_with("Metric")) {
+                    Some(Kind::Metric)
+                } else {
+                    None
+                };
+                in_table = kind.is_some();
+                if let Some(kind) = kind {
+                    tables.push(Table {
+                        region,
+                        kind,
+                        runtime: None,
+                        header: Vec::new(),
+                        rows: Vec::new(),
+                    });
+                }
+            }
+            "STRUCT" => in_table = false,
+            _ if in_table => {
+                let table = tables.last_mut().unwrap();
+                if first == "Event" || first == "Metric" {
+                    table.header = record;
+                    table.rows.clear();
+                } else if first.starts_with("RDTSC Runtime") {
+                    // Regions start with some info rows, we only need their runtime:
+                    table.runtime = record[1..]
+                        .iter()
+                        .filter_map(|c| f64::from_str(c.trim()).ok())
+                        .fold(None, |max: Option<f64>, v| {
+                            Some(max.map_or(v, |m| m.max(v)))
+                        });
+                } else if !table.header.is_empty() {
+                    table.rows.push(record);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(tables)
+}
+
+/// likwid names the counter a value comes from, we keep the name of the event
+/// unless it's from an uncore unit (so the channels of e.g. a memory controller
+/// stay apart).
+fn event_name(event: &str, counter: &str) -> String {
+    let core = ["FIXC", "PMC", "TMP"];
+    if core.iter().any(|c| counter.starts_with(c)) {
+        String::from(event)
+    } else {
+        format!("{}:{}", event, counter)
+    }
+}
+
+/// (region, metric, hardware thread, value)
+type MetricValue = (String, String, u64, f64);
+
+fn value(cell: Option<&String>) -> Option<f64> {
+    cell.and_then(|c| f64::from_str(c.trim()).ok())
+        .filter(|v| v.is_finite())
+}
+
+/// Converts likwid's output into a stat file like perf writes with `-A -x ;`
+/// (and a phase file if there are regions). Returns the names of the events and
+/// the metrics (region, metric, hardware thread, value).
+///
+/// The regions follow one another in the stat file, like perf's intervals: the
+/// time of a region is the sum of its runtime and the ones before it.
+fn convert(
+    likwid_csv: &Path,
+    runtime: f64,
+    stat_file: &Path,
+    phases_file: &Path,
+) -> io::Result<(Vec<String>, Vec<MetricValue>)> {
+    let tables = read_tables(likwid_csv)?;
+    let mut stat = File::create(stat_file)?;
+    let mut events: Vec<String> = Vec::new();
+    let mut metrics = Vec::new();
+    // Every region is an interval (by first appearance), region -> (start, end):
+    let mut regions: BTreeMap<String, (f64, f64)> = BTreeMap::new();
+    let mut end = 0.0;
+
+    for table in tables.iter() {
+        let time = match table.region {
+            Some(ref region) => match regions.get(region) {
+                Some(&(_, end)) => end,
+                None => {
+                    let runtime = match table.runtime {
+                        Some(runtime) => runtime,
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("No RDTSC runtime for region {}", region),
+                            ))
+                        }
+                    };
+                    regions.insert(region.clone(), (end, end + runtime));
+                    end += runtime;
+                    end
+                }
+            },
+            None => runtime,
+        };
+        let threads = table.threads();
+        for row in table.rows.iter() {
+            let name = match row.first() {
+                Some(name) if !name.trim().is_empty() => name.trim(),
+                _ => continue,
+            };
+            match table.kind {
+                Kind::Raw => {
+                    let event = event_name(name, row.get(1).map_or("", |c| c.trim()));
+                    for &(idx, thread) in threads.iter() {
+                        if let Some(v) = value(row.get(idx)) {
+                            writeln!(
+                                stat,
+                                "{:.6};CPU{};{};;{};0;100.00",
+                                time,
+                                thread,
+                                v.max(0.0).round() as u64,
+                                event
+                            )?;
+                        }
+                    }
+                    if !events.contains(&event) {
+                        events.push(event);
+                    }
+                }
+                Kind::Metric => {
+                    for &(idx, thread) in threads.iter() {
+                        if let Some(v) = value(row.get(idx)) {
+                            let region = table.region.clone().unwrap_or(String::new());
+                            metrics.push((region, String::from(name), thread, v));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if !regions.is_empty() {
+        let mut wtr = csv::Writer::from_file(phases_file).map_err(io::Error::other)?;
+        let r = wtr.encode(("time", "phase"));
+        assert!(r.is_ok());
+        let mut by_time: Vec<(&String, &(f64, f64))> = regions.iter().collect();
+        by_time.sort_by(|a, b| (a.1).0.partial_cmp(&(b.1).0).unwrap());
+        for (region, &(start, end)) in by_time {
+            // The marker goes inside the region, the interval of the one before ends at its start:
+            let r = wtr.encode(((start + end) / 2.0, region));
+            assert!(r.is_ok());
+        }
+    }
+    Ok((events, metrics))
+}
+
+pub fn profile(
+    output_path: &Path,
+    cmd_working_dir: &str,
+    cmd: Vec<String>,
+    settings: &LikwidSettings,
+    capture_store: Option<&PathBuf>,
+    dryrun: bool,
+) {
+    assert!(!cmd.is_empty());
+    let mut completed_file: PathBuf = output_path.to_path_buf();
+    completed_file.push("completed");
+    if completed_file.exists() {
+        warn!(
+            "Run {} already completed, skipping.",
+            output_path.to_string_lossy()
+        );
+        return;
+    }
+
+    create_out_directory(output_path);
+    if !dryrun {
+        check_for_likwid();
+        save_machine_information(output_path, capture_store);
+        let mut workload_file = output_path.to_path_buf();
+        workload_file.push("workload.toml");
+        let r = Workload::new(&cmd, cmd_working_dir, &Vec::new()).save(&workload_file);
+        if let Err(e) = r {
+            error!(
+                "Can't save workload information to {:?}: {}",
+                workload_file, e
+            );
+        }
+    }
+
+    let cpus: Vec<String> = MachineTopology::new()
+        .cpus()
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+    let mut wtr = perf_log_writer(output_path);
+    let mut metrics_path = output_path.to_path_buf();
+    metrics_path.push("likwid_metrics.csv");
+    let mut metrics_wtr = csv::Writer::from_file(&metrics_path).unwrap();
+    let r = metrics_wtr.encode(("datafile", "group", "region", "metric", "cpu", "value"));
+    assert!(r.is_ok());
+
+    for (idx, group) in settings.groups().iter().enumerate() {
+        let filename = format!("{}_stat.csv", idx + 1);
+        let mut likwid_csv = output_path.to_path_buf();
+        likwid_csv.push(format!("{}_likwid.csv", idx + 1));
+
+        // -c only measures the CPUs, unlike -C it doesn't pin the program:
+        let mut likwid = Command::new("likwid-perfctr");
+        likwid.current_dir(cmd_working_dir);
+        housekeeping::release(&mut likwid);
+        likwid.arg("-c").arg(cpus.join(","));
+        likwid.arg("-g").arg(group);
+        if settings.markers {
+            likwid.arg("-m");
+        }
+        likwid.arg("-o").arg(likwid_csv.as_os_str());
+        likwid.args(cmd.as_slice());
+        let likwid_cmd: String = format!("{:?}", likwid).replace("\"", "");
+        if dryrun {
+            warn!("Dry run mode -- would execute: {}", likwid_cmd);
+            continue;
+        }
+
+        let started_at = SystemTime::now();
+        let started = Instant::now();
+        let (stdout, stderr) = match likwid.output() {
+            Ok(out) => {
+                if !out.status.success() {
+                    error!(
+                        "likwid command: {} got unknown exit status was: {}",
+                        likwid_cmd, out.status
+                    );
+                    summary::record(Failure::Workload);
+                }
+                (
+                    String::from_utf8_lossy(&out.stdout).into_owned(),
+                    String::from_utf8_lossy(&out.stderr).into_owned(),
+                )
+            }
+            Err(e) => {
+                error!("Executing {} failed : {}", likwid_cmd, e);
+                continue;
+            }
+        };
+        let runtime = started.elapsed().as_secs_f64();
+
+        let mut stat_file = output_path.to_path_buf();
+        stat_file.push(&filename);
+        let mut phases_file = output_path.to_path_buf();
+        phases_file.push(format!("{}_phases.csv", idx + 1));
+        let events = match convert(&likwid_csv, runtime, &stat_file, &phases_file) {
+            Ok((events, metrics)) => {
+                for (region, metric, cpu, value) in metrics {
+                    let r = metrics_wtr.encode((&filename, group, region, metric, cpu, value));
+                    assert!(r.is_ok());
+                }
+                events
+            }
+            Err(e) => {
+                error!("Can't read likwid output {:?}: {}", likwid_csv, e);
+                Vec::new()
+            }
+        };
+        if events.is_empty() {
+            warn!(
+                "likwid didn't report any counter values for group {}.",
+                group
+            );
+        }
+
+        let r = wtr.encode(vec![
+            cmd.join(" "),
+            events.join(","),
+            group.clone(),
+            String::new(),
+            filename,
+            likwid_cmd,
+            stdout,
+            stderr,
+            SCHEMA_VERSION.to_string(),
+            unix_time(started_at),
+        ]);
+        assert!(r.is_ok());
+        let r = wtr.flush();
+        assert!(r.is_ok());
+        let r = metrics_wtr.flush();
+        assert!(r.is_ok());
+    }
+
+    if !dryrun {
+        let _ = File::create(completed_file.as_path()).unwrap();
+    }
+}
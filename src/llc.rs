@@ -0,0 +1,297 @@
+//! The filters of the CBo/CHA (the uncore unit of an LLC slice) that make the
+//! LLC events count only some of the lookups: lines in some states (`filter_state`),
+//! requests of one hardware thread (`filter_tid`) or with one opcode
+//! (`filter_opc`). `--llc-filter state=MES,tid=0x3,opc=DRD` sets them for every
+//! event with such a filter.
+//!
+//! The bits of the states differ between microarchitectures, the perfmon data
+//! describes them for the LLC_LOOKUP and LLC_VICTIMS events of each
+//! ("CHAFilter0[24:21,17] bits correspond to [FMESI] state"), so we encode the
+//! states with that rather than a table of our own. Opcodes are numbers, or a
+//! name the perfmon data gives the opcode ("DRD (0x182)").
+//!
+//! Without `--llc-filter` the events get the filters they always got (see
+//! `PerfEvent::perf_args`).
+use std::path::Path;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use x86::perfcnt::intel::EventDescription;
+
+use super::pmu;
+use super::profile::get_known_events;
+
+/// The filters of the events with an opcode filter.
+const OPC_FILTERS: [&str; 2] = ["CBoFilter1[28:20]", "CBoFilter[31:23]"];
+
+lazy_static! {
+    static ref STATE_BITS: Regex =
+        Regex::new(r"\w+\[([0-9:,]+)\] bits correspond to \[([A-Z']+)\] state").unwrap();
+    static ref REGISTER: Regex = Regex::new(r"^\w+\[(\d+):(\d+)\]$").unwrap();
+    static ref OPCODE: Regex = Regex::new(r"\b([A-Za-z][A-Za-z0-9_]*) \((0x[0-9a-fA-F]+)\)").unwrap();
+    /// Where the CBo/CHA PMU of the kernel puts a filter in the configuration
+    /// (the first bit and how many there are), if we have one.
+    static ref FORMAT: Vec<(String, (u32, u32))> = {
+        let pmus = pmu::read_pmus(Path::new(pmu::DEVICES)).unwrap_or_default();
+        pmus.iter()
+            .find(|p| p.name == "uncore_cbox_0" || p.name == "uncore_cha_0")
+            .map_or(Vec::new(), |p| {
+                p.format
+                    .iter()
+                    .filter_map(|(term, bits)| format_bits(bits).map(|b| (term.clone(), b)))
+                    .collect()
+            })
+    };
+}
+
+/// The filter we set (if there is `--llc-filter`).
+static FILTER: Mutex<Option<LlcFilter>> = Mutex::new(None);
+
+/// `config1:17-23` is (17, 7).
+fn format_bits(bits: &str) -> Option<(u32, u32)> {
+    let range = bits.split_once(':')?.1;
+    let mut ends = range.splitn(2, '-');
+    let first: u32 = ends.next()?.parse().ok()?;
+    let last: u32 = ends.next().map_or(Some(first), |l| l.parse().ok())?;
+    Some((first, last + 1 - first))
+}
+
+fn format_field(term: &str) -> Option<(u32, u32)> {
+    FORMAT.iter().find(|f| f.0 == term).map(|f| f.1)
+}
+
+/// The registers filters of `event` set: the first bit and the last one.
+fn registers(event: &EventDescription) -> Vec<(u32, u32)> {
+    event.filter.map_or(Vec::new(), |filter| {
+        filter
+            .split(',')
+            .filter_map(|f| REGISTER.captures(f.trim()))
+            .filter_map(|c| Some((c[2].parse().ok()?, c[1].parse().ok()?)))
+            .collect()
+    })
+}
+
+/// The states the state filter of `event` knows and their bits (in the filter),
+/// if it has one.
+fn state_bits(event: &EventDescription) -> Option<Vec<(String, u32)>> {
+    let description = event.public_description.unwrap_or(event.brief_description);
+    let captures = STATE_BITS.captures(description)?;
+
+    // [24:21,17] are the bits of the states from the first to the last
+    let mut positions = Vec::new();
+    for part in captures[1].split(',') {
+        let mut ends = part.splitn(2, ':');
+        let high: u32 = ends.next()?.parse().ok()?;
+        let low: u32 = ends.next().map_or(Some(high), |l| l.parse().ok())?;
+        positions.extend((low..high + 1).rev());
+    }
+    let mut states: Vec<String> = Vec::new();
+    for c in captures[2].chars() {
+        match (c, states.last_mut()) {
+            ('\'', Some(state)) => state.push(c),
+            ('\'', None) => return None,
+            (c, _) => states.push(c.to_string()),
+        }
+    }
+    if states.len() != positions.len() {
+        return None;
+    }
+
+    // perf's filter_state starts at the first bit of the filter register:
+    let first = match format_field("filter_state") {
+        Some((first, _)) => first,
+        None => registers(event)
+            .iter()
+            .filter(|&&(low, high)| positions.iter().all(|p| *p >= low && *p <= high))
+            .map(|r| r.0)
+            .min()?,
+    };
+    Some(
+        states
+            .into_iter()
+            .zip(positions)
+            .filter(|&(_, p)| p >= first)
+            .map(|(s, p)| (s, p - first))
+            .collect(),
+    )
+}
+
+/// Does `event` have an opcode filter?
+fn has_opc_filter(event: &EventDescription) -> bool {
+    OPC_FILTERS
+        .iter()
+        .any(|f| event.filter.is_some_and(|filter| filter.contains(f)))
+}
+
+/// The opcodes the perfmon data of this CPU names.
+fn opcodes() -> Vec<(String, u64)> {
+    let mut opcodes: Vec<(String, u64)> = Vec::new();
+    for event in get_known_events() {
+        let description = event.public_description.unwrap_or(event.brief_description);
+        for c in OPCODE.captures_iter(description) {
+            if let Ok(value) = u64::from_str_radix(&c[2][2..], 16) {
+                if !opcodes.iter().any(|o| o.0 == c[1]) {
+                    opcodes.push((String::from(&c[1]), value));
+                }
+            }
+        }
+    }
+    opcodes
+}
+
+fn parse_number(value: &str) -> Option<u64> {
+    if let Some(hex) = value.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+/// The filters of `--llc-filter`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LlcFilter {
+    /// Only count lines in these states (`M`, `E`, ...).
+    pub states: Vec<String>,
+    /// Only count the requests of this hardware thread.
+    pub tid: Option<u64>,
+    /// Only count requests with this opcode.
+    pub opc: Option<u64>,
+}
+
+impl LlcFilter {
+    /// Parses `state=MES,tid=0x3,opc=DRD` for the events of this CPU.
+    pub fn new(spec: &str) -> Result<LlcFilter, String> {
+        let events = get_known_events();
+        let known_states: Vec<String> = events
+            .iter()
+            .filter_map(|e| state_bits(e))
+            .flat_map(|bits| bits.into_iter().map(|b| b.0))
+            .fold(Vec::new(), |mut states, s| {
+                if !states.contains(&s) {
+                    states.push(s);
+                }
+                states
+            });
+        let opc_filter = events.iter().any(|e| has_opc_filter(e));
+
+        let mut filter: LlcFilter = Default::default();
+        for option in spec.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+            let mut kv = option.splitn(2, '=');
+            let (key, value) = (kv.next().unwrap(), kv.next().unwrap_or("").trim());
+            match key.trim() {
+                "state" => {
+                    if known_states.is_empty() {
+                        return Err(String::from(
+                            "the LLC events of this CPU have no state filter we know",
+                        ));
+                    }
+                    let mut rest = value;
+                    while !rest.is_empty() {
+                        // The longest state first (M' before M):
+                        let state = known_states
+                            .iter()
+                            .filter(|s| rest.starts_with(s.as_str()))
+                            .max_by_key(|s| s.len())
+                            .ok_or(format!(
+                                "unknown state '{}' (the states are {})",
+                                rest,
+                                known_states.join(", ")
+                            ))?;
+                        filter.states.push(state.clone());
+                        rest = &rest[state.len()..];
+                    }
+                    if filter.states.is_empty() {
+                        return Err(String::from("state needs at least one state"));
+                    }
+                }
+                "tid" => {
+                    let tid =
+                        parse_number(value).ok_or(format!("tid '{}' isn't a number", value))?;
+                    if let Some((_, width)) = format_field("filter_tid") {
+                        if width < 64 && tid >> width != 0 {
+                            return Err(format!(
+                                "tid {} doesn't fit in the {} bits of filter_tid",
+                                tid, width
+                            ));
+                        }
+                    }
+                    filter.tid = Some(tid);
+                }
+                "opc" => {
+                    if !opc_filter {
+                        return Err(String::from(
+                            "the events of this CPU have no opcode filter we know",
+                        ));
+                    }
+                    let opcodes = opcodes();
+                    let opc = parse_number(value)
+                        .or(opcodes.iter().find(|o| o.0 == value).map(|o| o.1))
+                        .ok_or(format!(
+                            "unknown opcode '{}' (give its number, or one of {})",
+                            value,
+                            opcodes
+                                .iter()
+                                .map(|o| o.0.as_str())
+                                .collect::<Vec<&str>>()
+                                .join(", ")
+                        ))?;
+                    if opc >> 9 != 0 {
+                        return Err(format!("opcode 0x{:x} has more than 9 bits", opc));
+                    }
+                    filter.opc = Some(opc);
+                }
+                _ => {
+                    return Err(format!(
+                        "unknown filter '{}' (there are state, tid and opc)",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    /// `filter_state` for `event`, if it has a state filter and we filter states.
+    pub fn state_arg(&self, event: &EventDescription) -> Option<String> {
+        if self.states.is_empty() {
+            return None;
+        }
+        let bits = state_bits(event)?;
+        let value = self
+            .states
+            .iter()
+            .filter_map(|s| bits.iter().find(|b| &b.0 == s))
+            .fold(0u64, |value, b| value | 1 << b.1);
+        Some(format!("filter_state=0x{:x}", value))
+    }
+
+    /// `filter_opc` for `event`, if it has an opcode filter and we filter opcodes.
+    pub fn opc_arg(&self, event: &EventDescription) -> Option<String> {
+        match self.opc {
+            Some(opc) if has_opc_filter(event) => Some(format!("filter_opc=0x{:x}", opc)),
+            _ => None,
+        }
+    }
+
+    /// `tid_en` and `filter_tid` for `event`, if it is one of the LLC events
+    /// (or has an opcode filter) and we filter threads.
+    pub fn tid_args(&self, event: &EventDescription) -> Vec<String> {
+        match self.tid {
+            Some(tid) if state_bits(event).is_some() || has_opc_filter(event) => {
+                vec![String::from("tid_en=1"), format!("filter_tid=0x{:x}", tid)]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Use `filter` for the events we measure from now on.
+pub fn configure(filter: LlcFilter) {
+    *FILTER.lock().unwrap() = Some(filter);
+}
+
+/// The filter of `--llc-filter`, if there is one.
+pub fn configured() -> Option<LlcFilter> {
+    FILTER.lock().unwrap().clone()
+}
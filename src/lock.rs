@@ -0,0 +1,118 @@
+//! Keeps two autoperf invocations from writing into the same output directory
+//! (a `profile` directory, or a `pair` campaign): whoever measures holds an
+//! advisory lock (flock) on `.autoperf.lock` in it, and writes its PID into the
+//! file so the other one can say who's in the way. The kernel drops the lock when
+//! the process exits, even if it crashed, so there's never a stale lock to clean
+//! up (the file stays, an unlocked file means nobody is writing).
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use tracing::{debug, error, info};
+
+use crate::summary::{self, Failure};
+use crate::util::{self, Collision};
+
+/// The lock file in an output directory.
+pub const LOCK_FILE: &str = ".autoperf.lock";
+
+/// Holds the lock on a directory until it's dropped.
+#[derive(Debug)]
+pub struct DirectoryLock {
+    /// Closing it releases the lock.
+    _file: File,
+    path: PathBuf,
+}
+
+/// Why we couldn't lock a directory.
+#[derive(Debug)]
+pub enum LockError {
+    /// Another process holds the lock (the PID it wrote, if we could read it).
+    Held(Option<u32>),
+    Io(io::Error),
+}
+
+impl DirectoryLock {
+    /// Locks `directory` (which has to exist) without waiting for it.
+    pub fn acquire(directory: &Path) -> Result<DirectoryLock, LockError> {
+        let path = directory.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(LockError::Io)?;
+        let r = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if r != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                return Err(LockError::Held(holder.trim().parse::<u32>().ok()));
+            }
+            return Err(LockError::Io(e));
+        }
+
+        // It's ours, whatever PID is in there is from an earlier invocation:
+        file.set_len(0).map_err(LockError::Io)?;
+        file.seek(SeekFrom::Start(0)).map_err(LockError::Io)?;
+        writeln!(file, "{}", process::id()).map_err(LockError::Io)?;
+        file.flush().map_err(LockError::Io)?;
+        debug!("Locked {:?}", path);
+        Ok(DirectoryLock { _file: file, path })
+    }
+}
+
+impl Drop for DirectoryLock {
+    fn drop(&mut self) {
+        debug!("Unlocking {:?}", self.path);
+    }
+}
+
+/// Locks `directory` for the rest of the invocation, or fails if another
+/// autoperf writes to it.
+pub fn lock_or_fail(directory: &Path) -> DirectoryLock {
+    match DirectoryLock::acquire(directory) {
+        Ok(lock) => lock,
+        Err(LockError::Held(Some(pid))) => {
+            error!(
+                "{:?} is in use by another autoperf (PID {}), wait for it to finish or use another output directory.",
+                directory, pid
+            );
+            summary::fail(Failure::Environment);
+        }
+        Err(LockError::Held(None)) => {
+            error!(
+                "{:?} is in use by another autoperf, wait for it to finish or use another output directory.",
+                directory
+            );
+            summary::fail(Failure::Environment);
+        }
+        Err(LockError::Io(e)) => {
+            error!("Can't lock {:?}: {}", directory.join(LOCK_FILE), e);
+            summary::fail(Failure::Environment);
+        }
+    }
+}
+
+/// Creates the output directory `output` (or another one, see `Collision`),
+/// locks it and has the summary saved in it.
+pub fn claim(output: &Path, policy: Collision) -> (PathBuf, DirectoryLock) {
+    let output_path = match util::output_directory(output, policy) {
+        Ok(path) => path,
+        Err(e) => {
+            error!("{}", e);
+            summary::fail(Failure::Error);
+        }
+    };
+    if output_path != output {
+        info!("Writing to {:?}", output_path);
+    }
+    // Before the summary goes there, ours would overwrite the other one's:
+    let lock = lock_or_fail(&output_path);
+    summary::save_in(&output_path);
+    (output_path, lock)
+}
@@ -0,0 +1,212 @@
+//! Logging with tracing: messages go to the terminal (or the dashboard, see
+//! `watch.rs`) as text or, with `--log-format json`, as JSON with the spans they
+//! were logged in.
+//!
+//! `pair` logs in a span for the campaign, one for every configuration and one
+//! for every run, `profile` in a run span. A run span names the directory of the
+//! run (`run_span`), and everything logged in it (down to debug messages, no
+//! matter how verbose the terminal is) is written to `log.jsonl` in that
+//! directory, one JSON object per message:
+//!
+//! ```json
+//! {"timestamp":1718000000.25,"level":"WARN","target":"autoperf::profile",
+//!  "fields":{"message":"..."},"spans":[{"name":"campaign",...},...]}
+//! ```
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde_json::{Map, Number, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{info_span, Event, Span, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt as format, Layer};
+
+use super::watch;
+
+/// The log of a run (in its directory).
+pub const RUN_LOG: &str = "log.jsonl";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Logs `level` and above (unless `RUST_LOG` says otherwise) in `log_format`,
+/// into the dashboard if we `watch` the measurement.
+pub fn init(level: &str, log_format: LogFormat, watch: bool) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    let console = format::layer()
+        .with_writer(|| watch::LogWriter)
+        .with_ansi(!watch);
+    let console = match log_format {
+        LogFormat::Text => console.boxed(),
+        LogFormat::Json => console.json().with_span_list(true).boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(console.with_filter(filter))
+        .with(RunLogs.with_filter(LevelFilter::DEBUG))
+        .init();
+}
+
+/// The span of a run that writes to `directory` (see `RunLogs`).
+pub fn run_span(directory: &Path) -> Span {
+    info_span!("run", directory = %directory.display())
+}
+
+/// The fields of a span or event.
+#[derive(Debug, Default)]
+struct Fields(Map<String, Value>);
+
+impl Visit for Fields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        let value = Number::from_f64(value).map_or(Value::Null, Value::Number);
+        self.0.insert(String::from(field.name()), value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0
+            .insert(String::from(field.name()), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0
+            .insert(String::from(field.name()), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0
+            .insert(String::from(field.name()), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0
+            .insert(String::from(field.name()), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(
+            String::from(field.name()),
+            Value::from(format!("{:?}", value)),
+        );
+    }
+}
+
+/// The log file of a run span, opened once there is something to log and the
+/// directory exists.
+struct RunLog {
+    directory: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl RunLog {
+    fn write(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() && self.directory.is_dir() {
+            *file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.directory.join(RUN_LOG))
+                .ok();
+        }
+        if let Some(ref mut file) = *file {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
+
+/// Writes the messages logged in a run span to the log of the run.
+struct RunLogs;
+
+impl<S> Layer<S> for RunLogs
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let mut extensions = span.extensions_mut();
+        if span.name() == "run" {
+            if let Some(Value::String(directory)) = fields.0.get("directory") {
+                extensions.insert(RunLog {
+                    directory: PathBuf::from(directory),
+                    file: Mutex::new(None),
+                });
+            }
+        }
+        extensions.insert(fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event, ctx: Context<S>) {
+        let spans: Vec<_> = match ctx.event_scope(event) {
+            Some(scope) => scope.from_root().collect(),
+            None => return,
+        };
+        let run = match spans
+            .iter()
+            .rev()
+            .find(|s| s.extensions().get::<RunLog>().is_some())
+        {
+            Some(run) => run,
+            None => return,
+        };
+
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |t| t.as_secs_f64());
+        let mut entry = Map::new();
+        entry.insert(
+            String::from("timestamp"),
+            Number::from_f64(timestamp).map_or(Value::Null, Value::Number),
+        );
+        entry.insert(
+            String::from("level"),
+            Value::from(event.metadata().level().to_string()),
+        );
+        entry.insert(
+            String::from("target"),
+            Value::from(event.metadata().target()),
+        );
+        entry.insert(String::from("fields"), Value::Object(fields.0));
+        let spans: Vec<Value> = spans
+            .iter()
+            .map(|span| {
+                let mut fields = span
+                    .extensions()
+                    .get::<Fields>()
+                    .map_or(Map::new(), |f| f.0.clone());
+                fields.insert(String::from("name"), Value::from(span.name()));
+                Value::Object(fields)
+            })
+            .collect();
+        entry.insert(String::from("spans"), Value::Array(spans));
+
+        let extensions = run.extensions();
+        if let Some(log) = extensions.get::<RunLog>() {
+            log.write(&Value::Object(entry).to_string());
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Writes `machine.json`, a machine-readable record of the environment a profile
+//! was taken in (kernel, microcode, mitigations, tool versions etc.) so results
+//! from different machines or campaigns can be compared later on.
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use rustc_serialize::json;
+use schemars::JsonSchema;
+
+use super::stats;
+use super::sysctl::sysctl;
+use super::util::{ClockInfo, SCHEMA_VERSION};
+
+/// Tools we depend on and the arguments that make them print their version.
+const TOOLS: [(&str, &str); 7] = [
+    ("perf", "--version"),
+    ("lscpu", "--version"),
+    ("numactl", "--version"),
+    ("lstopo", "--version"),
+    ("cpuid", "--version"),
+    ("likwid-topology", "-v"),
+    ("turbostat", "--version"),
+];
+
+#[derive(Debug, RustcEncodable, JsonSchema)]
+pub struct MachineManifest {
+    pub schema_version: u64,
+    pub hostname: String,
+    pub kernel_release: String,
+    pub kernel_version: String,
+    pub kernel_cmdline: String,
+    pub cpu_model: String,
+    /// e.g. SkylakeX (the cpuid key if we don't know the name, empty if we can't tell)
+    pub microarchitecture: String,
+    pub microcode: String,
+    /// vulnerability -> mitigation status
+    pub mitigations: BTreeMap<String, String>,
+    /// cpu -> cpufreq governor
+    pub governors: BTreeMap<String, String>,
+    pub transparent_hugepages: String,
+    pub transparent_hugepages_defrag: String,
+    /// tool -> version (empty if not installed)
+    pub tools: BTreeMap<String, String>,
+    pub autoperf_version: String,
+    pub autoperf_git_version: String,
+    pub clock: ClockInfo,
+}
+
+fn read_trimmed(path: &str) -> String {
+    fs::read_to_string(path)
+        .map(|s| String::from(s.trim()))
+        .unwrap_or_default()
+}
+
+/// `value` unless it's empty (we're not on Linux), then the sysctl `name`.
+fn or_sysctl(value: String, name: &str) -> String {
+    if !value.is_empty() {
+        value
+    } else {
+        sysctl(name).unwrap_or_default()
+    }
+}
+
+/// The active setting in sysfs files like `always [madvise] never`.
+fn selected(path: &str) -> String {
+    let content = read_trimmed(path);
+    match (content.find('['), content.find(']')) {
+        (Some(start), Some(end)) if start < end => String::from(&content[start + 1..end]),
+        _ => content,
+    }
+}
+
+/// The first value of `key` in /proc/cpuinfo.
+fn cpuinfo(key: &str) -> String {
+    read_trimmed("/proc/cpuinfo")
+        .lines()
+        .filter(|l| l.starts_with(key))
+        .filter_map(|l| l.split_once(':').map(|x| x.1))
+        .map(|v| String::from(v.trim()))
+        .next()
+        .unwrap_or(String::new())
+}
+
+/// Name of the microarchitecture we run on (looked up by vendor, family and model).
+fn microarchitecture() -> String {
+    let vendor = cpuinfo("vendor_id");
+    let family = cpuinfo("cpu family");
+    let model = cpuinfo("model\t").parse::<u64>();
+    match model {
+        Ok(model) if !vendor.is_empty() && !family.is_empty() => {
+            let key = format!("{}-{}-{:X}", vendor, family, model);
+            stats::architectures()
+                .get(key.as_str())
+                .map_or(key.clone(), |&(name, _, _)| String::from(name))
+        }
+        _ => String::new(),
+    }
+}
+
+/// All files in `dir` (name -> content).
+fn read_dir_files(dir: &str) -> BTreeMap<String, String> {
+    let mut files = BTreeMap::new();
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            files.insert(
+                entry.file_name().to_string_lossy().into_owned(),
+                read_trimmed(&entry.path().to_string_lossy()),
+            );
+        }
+    }
+    files
+}
+
+fn governors() -> BTreeMap<String, String> {
+    let mut governors = BTreeMap::new();
+    if let Ok(entries) = fs::read_dir("/sys/devices/system/cpu") {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let governor = read_trimmed(&format!(
+                "{}/cpufreq/scaling_governor",
+                entry.path().to_string_lossy()
+            ));
+            if name.starts_with("cpu") && !governor.is_empty() {
+                governors.insert(name, governor);
+            }
+        }
+    }
+    governors
+}
+
+/// First line a tool prints for its version (some use stderr for that).
+fn tool_version(tool: &str, arg: &str) -> String {
+    match Command::new(tool).arg(arg).output() {
+        Ok(out) => {
+            let stdout = String::from_utf8_lossy(&out.stdout).into_owned();
+            let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+            let version = if !stdout.trim().is_empty() {
+                stdout
+            } else {
+                stderr
+            };
+            String::from(version.lines().next().unwrap_or("").trim())
+        }
+        Err(_) => String::new(),
+    }
+}
+
+impl MachineManifest {
+    pub fn new(clock: ClockInfo) -> MachineManifest {
+        MachineManifest {
+            schema_version: SCHEMA_VERSION,
+            hostname: or_sysctl(read_trimmed("/proc/sys/kernel/hostname"), "kern.hostname"),
+            kernel_release: or_sysctl(read_trimmed("/proc/sys/kernel/osrelease"), "kern.osrelease"),
+            kernel_version: or_sysctl(read_trimmed("/proc/sys/kernel/version"), "kern.version"),
+            kernel_cmdline: read_trimmed("/proc/cmdline"),
+            cpu_model: or_sysctl(cpuinfo("model name"), "hw.model"),
+            microarchitecture: microarchitecture(),
+            microcode: cpuinfo("microcode"),
+            mitigations: read_dir_files("/sys/devices/system/cpu/vulnerabilities"),
+            governors: governors(),
+            transparent_hugepages: selected("/sys/kernel/mm/transparent_hugepage/enabled"),
+            transparent_hugepages_defrag: selected("/sys/kernel/mm/transparent_hugepage/defrag"),
+            tools: TOOLS
+                .iter()
+                .map(|&(tool, arg)| (String::from(tool), tool_version(tool, arg)))
+                .collect(),
+            autoperf_version: String::from(env!("CARGO_PKG_VERSION")),
+            autoperf_git_version: String::from(env!("AUTOPERF_GIT_VERSION")),
+            clock,
+        }
+    }
+}
+
+pub fn save_machine_manifest(output_path: &Path, clock: ClockInfo) -> io::Result<()> {
+    let manifest = MachineManifest::new(clock);
+
+    let mut out_file: PathBuf = output_path.to_path_buf();
+    out_file.push("machine.json");
+    let mut f = File::create(out_file.as_path())?;
+    f.write_all(format!("{}\n", json::as_pretty_json(&manifest)).as_bytes())
+}
@@ -1,109 +1,1232 @@
-use clap::{load_yaml, App};
+use clap::{CommandFactory, Parser};
+use clap_complete::CompleteEnv;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
+mod adaptive;
 mod aggregate;
+mod align;
+mod archive;
+mod bench;
+mod binaries;
+mod bootstrap;
+#[cfg(feature = "bpf")]
+mod bpf;
+mod cachesweep;
+mod cli;
+mod collectors;
+mod columnar;
+mod completions;
+mod config;
+mod cooldown;
+mod coretypes;
+mod cpufreq;
+mod diff;
+mod diffconfig;
+mod dmesg;
+mod efficiency;
+mod errata;
+mod housekeeping;
+mod index;
+mod influx;
+mod integrity;
+mod interference;
+mod intervals;
+mod iostat;
+mod kallsyms;
+mod likwid;
+mod llc;
+mod lock;
+mod logging;
+mod machine;
+mod mempolicy;
 mod mkgroup;
+mod normalize;
+mod output;
 mod pair;
+#[cfg(feature = "papi")]
+mod papi;
+mod pcm;
+mod perfstat;
+mod phases;
+mod plot;
+mod pmcstat;
+mod pmu;
+mod preflight;
+mod presets;
+mod pressure;
+mod priority;
+mod privilege;
 mod profile;
+mod progress;
+mod prometheus;
+mod recommend;
+mod report;
+mod resctrl;
+mod reset;
+mod rules;
+mod sampling;
 mod scale;
+mod sched;
+mod schema;
 mod search;
+mod sensitivity;
+mod sha256;
+mod spec;
 mod stats;
-mod util;
-use log::*;
+mod steady;
+mod store;
+mod suites;
+mod summary;
+mod symbols;
+mod threads;
+mod throttle;
+mod topology;
+mod turbostat;
+mod uncore;
+mod validate;
+mod vtune;
+mod watch;
+mod workload;
 
-use aggregate::aggregate;
-use pair::pair;
+use autoperf::{bytesize, deployment, features, gpu, memtier, nic, pci, sysctl, util};
+use tracing::{debug, error, info, warn};
+
+use aggregate::{aggregate, AggregateSettings};
+use bytesize::{ByteSize, Prefixes};
+use cli::{Cli, Command, ConfigAction, LiveArgs, TopologyAction};
+use config::Config;
+use output::{FigureOfMerit, OutputSettings};
+use pair::{pair, PairSettings};
+use priority::Priority;
 use profile::profile;
 use stats::stats;
+use summary::Failure;
 
 use mkgroup::mkgroup;
-use search::print_unknown_events;
+use profile::{Backend, ProfileSettings, Scope};
+use search::{print_catalog_events, print_unknown_events};
+
+/// Starts the metrics exporter if the sub-command was started with `--prometheus`.
+fn start_exporter(live: &LiveArgs) {
+    if let Some(ref addr) = live.prometheus {
+        if let Err(e) = prometheus::serve(addr) {
+            error!("Can't serve Prometheus metrics on {}: {}", addr, e);
+            summary::fail(Failure::Error);
+        }
+    }
+}
+
+/// Starts streaming samples if the sub-command was started with `--influx`.
+fn start_streamer(live: &LiveArgs, dryrun: bool) -> Option<influx::Streamer> {
+    let target = live.influx.as_ref()?;
+    if dryrun {
+        return None;
+    }
+    match influx::Streamer::start(target) {
+        Ok(streamer) => Some(streamer),
+        Err(e) => {
+            error!("Can't stream samples to {}: {}", target, e);
+            summary::fail(Failure::Error);
+        }
+    }
+}
+
+/// What counts the events of the sub-command (`--backend`, likwid and VTune have
+/// their own `profile`).
+fn backend(backend: &str) -> Backend {
+    let backend = match backend {
+        "likwid" | "vtune" => Default::default(),
+        backend => Backend::new(backend),
+    };
+    if backend == Backend::Papi && !cfg!(feature = "papi") {
+        error!("autoperf was built without PAPI support, rebuild it with `--features papi`.");
+        summary::fail(Failure::Environment);
+    }
+    backend
+}
+
+/// Moves autoperf to the housekeeping CPU (`--housekeeping-cpu` or the configuration).
+fn pin_housekeeping(config: &mut Config, flag: Option<u64>) {
+    if let Some(cpu) = *config.housekeeping_cpu.or_flag(flag.map(Some)) {
+        if let Err(e) = housekeeping::pin(cpu) {
+            error!("Can't move autoperf to housekeeping CPU {}: {}", cpu, e);
+            summary::fail(Failure::Environment);
+        }
+        info!("autoperf runs on housekeeping CPU {}", cpu);
+    }
+}
 
-fn setup_logging(lvl: &str) {
-    use env_logger::Env;
-    env_logger::from_env(Env::default().default_filter_or(lvl)).init();
+/// The collision policy (`--on-existing` or the configuration).
+fn on_existing(config: &mut Config, flag: Option<String>) -> util::Collision {
+    util::Collision::from_str(config.on_existing.or_flag(flag))
+        .expect("checked with the configuration")
+}
+
+/// Was the sub-command started with `--bpf` (and do we have eBPF support)?
+fn bpf_collection(bpf: bool) -> bool {
+    if bpf && !cfg!(feature = "bpf") {
+        error!("autoperf was built without eBPF support, rebuild it with `--features bpf`.");
+        summary::fail(Failure::Environment);
+    }
+    bpf
+}
+
+/// Brings up the dashboard if the sub-command was started with `--watch`.
+fn start_dashboard(live: &LiveArgs, dryrun: bool) -> Option<watch::Dashboard> {
+    if !live.watch || dryrun {
+        return None;
+    }
+    match watch::Dashboard::start() {
+        Ok(dashboard) => Some(dashboard),
+        Err(e) => {
+            error!("Can't start the dashboard: {}", e);
+            None
+        }
+    }
+}
+
+fn parse_seconds(arg: &str, value: &str) -> Duration {
+    match f64::from_str(value) {
+        Ok(secs) if secs >= 0.0 => Duration::from_millis((secs * 1000.0) as u64),
+        _ => {
+            error!(
+                "--{} expects a positive amount of seconds (got '{}')",
+                arg, value
+            );
+            summary::fail(Failure::Error);
+        }
+    }
+}
+
+/// Selects the collectors of `--collectors` (see `collectors.rs`).
+fn select_collectors(names: Option<&String>) {
+    if let Some(names) = names {
+        let names: Vec<String> = names.split(",").map(|n| String::from(n.trim())).collect();
+        if let Err(e) = collectors::select(&names) {
+            error!("--collectors: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Sets the filters of `--llc-filter` (see `llc.rs`).
+fn configure_llc_filter(spec: Option<&String>) {
+    if let Some(spec) = spec {
+        match llc::LlcFilter::new(spec) {
+            Ok(filter) => llc::configure(filter),
+            Err(e) => {
+                error!("--llc-filter '{}': {}", spec, e);
+                summary::fail(Failure::Error);
+            }
+        }
+    }
+}
+
+/// `cmd` with its memory bound to the nodes of `--memory-tier` (see `memtier.rs`).
+fn bind_memory(tier: Option<&String>, cmd: Vec<String>) -> Vec<String> {
+    let tier = match tier {
+        Some(tier) => memtier::MemoryTier::new(tier).expect("clap checks the tier"),
+        None => return cmd,
+    };
+    let nodes: Vec<u64> = util::MachineTopology::new()
+        .nodes_of_tier(tier)
+        .iter()
+        .map(|n| n.node)
+        .collect();
+    if nodes.is_empty() {
+        error!(
+            "This machine has no {} memory (see memory_tiers.csv).",
+            tier
+        );
+        summary::fail(Failure::Environment);
+    }
+    let bind = mempolicy::MemoryPolicy::Bind(nodes);
+    mempolicy::numactl(bind.numactl_args(0, &[]).unwrap(), cmd)
 }
 
 fn main() {
-    let yaml = load_yaml!("cmd.yml");
-    let matches = App::from_yaml(yaml).get_matches();
+    // The completion scripts run us with $COMPLETE set to complete a word:
+    CompleteEnv::with_factory(Cli::command)
+        .var(completions::COMPLETE_ENV)
+        .complete();
+    let cli = Cli::parse();
 
-    let level = match matches.occurrences_of("verbose") {
+    let level = match cli.verbose {
         0 => "warn",
         1 => "info",
         2 => "debug",
         3 => "trace",
         _ => "trace",
     };
-    setup_logging(level);
-
-    if let Some(matches) = matches.subcommand_matches("profile") {
-        let output_path = Path::new(matches.value_of("output").unwrap_or("out"));
-        let cmd: Vec<String> = matches
-            .values_of("COMMAND")
-            .unwrap()
-            .map(|s| s.to_string())
-            .collect();
-
-        let dryrun: bool = matches.is_present("dryrun");
-        profile(
-            output_path,
-            ".",
-            cmd,
-            Default::default(),
-            Default::default(),
-            false,
-            None,
-            dryrun,
-        );
-    }
-    if let Some(matches) = matches.subcommand_matches("aggregate") {
-        let input_directory = Path::new(matches.value_of("directory").unwrap_or("out"));
-        let output_path: PathBuf = match matches.value_of("output") {
-            Some(v) => PathBuf::from(v),
-            None => {
-                let mut pb = input_directory.to_path_buf();
-                pb.push("results.csv");
-                pb
-            }
-        };
-        let uncore_filter: &str = matches.value_of("uncore").unwrap_or("all");
-        let core_filter: &str = matches.value_of("core").unwrap_or("all");
-
-        aggregate(
-            input_directory,
-            core_filter,
-            uncore_filter,
-            &output_path.as_path(),
-        );
-    }
-    if let Some(matches) = matches.subcommand_matches("pair") {
-        let output_path = Path::new(matches.value_of("directory").unwrap_or("out"));
-        let start: usize = usize::from_str(matches.value_of("start").unwrap_or("0")).unwrap_or(0);
-        let stepping: usize = usize::from_str(matches.value_of("step").unwrap_or("1")).unwrap_or(1);
-        if stepping == 0 {
-            error!("skip amount must be > 0");
+    let watch = match cli.command {
+        Command::Profile(ref args) => args.live.watch && !args.dryrun,
+        Command::Pair(ref args) => args.live.watch && !args.dryrun,
+        _ => false,
+    };
+    logging::init(level, cli.log_format, watch);
+    let mut config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
             std::process::exit(1);
         }
+    };
 
-        let dryrun: bool = matches.is_present("dryrun");
-        pair(output_path, dryrun, start, stepping);
-    }
-    if let Some(matches) = matches.subcommand_matches("scale") {
-        let _output_path = Path::new(matches.value_of("directory").unwrap_or("out"));
-        let _dryrun: bool = matches.is_present("dryrun");
-        // scale(output_path, dryrun);
-    }
-    if let Some(matches) = matches.subcommand_matches("stats") {
-        let output_path = Path::new(matches.value_of("directory").unwrap_or("out"));
-        stats(output_path);
-    }
-    if let Some(_matches) = matches.subcommand_matches("search") {
-        print_unknown_events();
-    }
-    if let Some(matches) = matches.subcommand_matches("mkgroup") {
-        let ranking_file = Path::new(matches.value_of("file").unwrap_or("notfound"));
-        mkgroup(ranking_file);
+    match cli.command {
+        Command::Profile(args) => {
+            let output = config.output.or_flag(args.output.clone()).clone();
+            summary::start("profile");
+            let policy = on_existing(&mut config, args.on_existing.clone());
+            let (output_path, _lock) = lock::claim(Path::new(&output), policy);
+            let output_path = output_path.as_path();
+            let run = logging::run_span(output_path).entered();
+            let backend_name = config.backend.or_flag(args.backend.clone()).clone();
+            pin_housekeeping(&mut config, args.housekeeping_cpu);
+
+            let mut settings = ProfileSettings {
+                measure_after: args
+                    .measure_after
+                    .as_ref()
+                    .map(|v| parse_seconds("measure-after", v)),
+                measure_for: args
+                    .measure_for
+                    .as_ref()
+                    .map(|v| parse_seconds("measure-for", v)),
+                phase_markers: args.phase_markers,
+                compress_intervals: args.compress_intervals,
+                scope: if args.per_thread {
+                    Scope::Thread
+                } else {
+                    Scope::new(&args.scope)
+                },
+                turbostat: args.turbostat,
+                pcm: args.pcm,
+                bpf: bpf_collection(args.bpf),
+                per_process: false,
+                capture_store: None,
+                cpus: None,
+                backend: backend(&backend_name),
+                sysfs_events: Vec::new(),
+                skip_events: Vec::new(),
+                samples_per_second: args.samples_per_second,
+                metric_groups: args.metric_groups.as_ref().map_or(Vec::new(), |g| {
+                    g.split(",").map(|g| String::from(g.trim())).collect()
+                }),
+                steady_state: args
+                    .steady_state
+                    .as_ref()
+                    .map(|metric| steady::SteadyState {
+                        metric: steady::Metric::new(metric),
+                        tolerance: args.steady_tolerance / 100.0,
+                        window: parse_seconds("steady-for", &args.steady_for),
+                        timeout: parse_seconds("steady-timeout", &args.steady_timeout),
+                    }),
+                priority: args
+                    .priority
+                    .as_ref()
+                    .map(|spec| match Priority::new(spec) {
+                        Ok(priority) => {
+                            if let Err(e) = priority.check() {
+                                error!("Can't use --priority: {}", e);
+                                summary::fail(Failure::Environment);
+                            }
+                            priority
+                        }
+                        Err(e) => {
+                            error!("Invalid --priority: {}", e);
+                            std::process::exit(1);
+                        }
+                    }),
+                output: OutputSettings {
+                    max_bytes: args.max_output.as_ref().map(|size| {
+                        match ByteSize::parse(size, Prefixes::Decimal, "B") {
+                            Some(size) => size.bytes(),
+                            None => {
+                                error!("Invalid --max-output '{}' (e.g. 10MiB).", size);
+                                std::process::exit(1);
+                            }
+                        }
+                    }),
+                    timestamps: args.timestamp_output,
+                    figure_of_merit: if args.figure_of_merit.is_some()
+                        || args.figure_of_merit_json.is_some()
+                        || args.figure_of_merit_file.is_some()
+                    {
+                        match FigureOfMerit::new(
+                            Some(args.figure_of_merit_name.as_str()),
+                            args.figure_of_merit.as_deref(),
+                            args.figure_of_merit_json.as_deref(),
+                            args.figure_of_merit_file.as_deref(),
+                        ) {
+                            Ok(fom) => Some(fom),
+                            Err(e) => {
+                                error!("Invalid figure of merit: {}.", e);
+                                std::process::exit(1);
+                            }
+                        }
+                    } else {
+                        None
+                    },
+                },
+                resctrl_tasks: None,
+            };
+
+            configure_llc_filter(args.llc_filter.as_ref());
+            select_collectors(args.collectors.as_ref());
+            let preset = args
+                .preset
+                .as_ref()
+                .map(|p| presets::preset(p).expect("clap checks the preset"));
+            if let Some(preset) = preset {
+                presets::add_sysfs_events(preset, &mut settings);
+            }
+            let dryrun: bool = args.dryrun;
+            if !dryrun {
+                privilege::degrade(&mut settings);
+            }
+            if backend_name == "perf" {
+                errata::exclude(&mut settings, args.allow_errata);
+                if !dryrun {
+                    preflight::validate(&mut settings, args.skip_invalid);
+                }
+                pmu::prefer_free_running(&mut settings);
+            }
+            start_exporter(&args.live);
+            let streamer = start_streamer(&args.live, dryrun);
+            let dashboard = start_dashboard(&args.live, dryrun);
+            let cmd = bind_memory(args.memory_tier.as_ref(), args.command);
+            if args.preset.is_some() && (backend_name == "likwid" || backend_name == "vtune") {
+                warn!("--preset is ignored by the {} backend.", backend_name);
+            }
+            if settings.steady_state.is_some() && backend_name != "perf" {
+                warn!("--steady-state is ignored by the {} backend.", backend_name);
+            }
+            if !settings.metric_groups.is_empty() && backend_name != "perf" {
+                warn!(
+                    "--metric-groups is ignored by the {} backend.",
+                    backend_name
+                );
+            }
+            if backend_name == "likwid" {
+                let likwid_settings = likwid::LikwidSettings {
+                    groups: args.groups.as_ref().map_or(Vec::new(), |g| {
+                        g.split(",").map(|g| String::from(g.trim())).collect()
+                    }),
+                    markers: args.likwid_markers,
+                };
+                likwid::profile(output_path, ".", cmd, &likwid_settings, None, dryrun);
+            } else if backend_name == "vtune" {
+                let analysis = args
+                    .vtune_analysis
+                    .as_ref()
+                    .map_or(vtune::DEFAULT_ANALYSIS, |a| a.as_str());
+                vtune::profile(output_path, ".", cmd, analysis, dryrun);
+            } else {
+                let preset_events = preset.map(|p| presets::events(p, &settings.skip_events));
+                profile(
+                    output_path,
+                    ".",
+                    cmd,
+                    Default::default(),
+                    Default::default(),
+                    args.record,
+                    preset_events,
+                    &settings,
+                    dryrun,
+                );
+            }
+            summary::run_ended(false);
+            if let Some(streamer) = streamer {
+                streamer.stop();
+            }
+            if let Some(dashboard) = dashboard {
+                dashboard.stop();
+            }
+
+            // Messages from now on don't go to log.jsonl, it's part of the hashes:
+            drop(run);
+            // The summary goes into the archive:
+            let exit_code = summary::conclude();
+            if args.compress && !dryrun {
+                if let Err(e) = archive::compress_dir(output_path) {
+                    error!("Can't compress {:?}: {}", output_path, e);
+                    summary::fail(Failure::Error);
+                }
+            }
+            std::process::exit(exit_code);
+        }
+        Command::Aggregate(args) => {
+            let input = Path::new(&args.directory);
+
+            // Archives are extracted into a temporary directory first:
+            let mut extracted_to: Option<PathBuf> = None;
+            let input_directory: PathBuf = if archive::is_archive(input) {
+                let mut tmp = std::env::temp_dir();
+                tmp.push(format!("autoperf-{}", std::process::id()));
+                match archive::extract(input, &tmp) {
+                    Ok(dir) => {
+                        extracted_to = Some(tmp);
+                        dir
+                    }
+                    Err(e) => {
+                        error!("Can't extract {:?}: {}", input, e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                input.to_path_buf()
+            };
+
+            if let Some(jobs) = args.jobs {
+                if jobs == 0 {
+                    error!("--jobs expects a positive number of threads");
+                    std::process::exit(1);
+                }
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(jobs)
+                    .build_global()
+                    .expect("Can't create thread pool");
+            }
+            let format: &str = &args.format;
+            let output_path: PathBuf = match args.output {
+                Some(ref v) => PathBuf::from(v),
+                None if extracted_to.is_some() => {
+                    let name = input.file_name().unwrap().to_string_lossy();
+                    input.with_file_name(format!(
+                        "{}_results.{}",
+                        name.trim_end_matches(archive::ARCHIVE_EXTENSION),
+                        format
+                    ))
+                }
+                None => {
+                    let mut pb = input_directory.to_path_buf();
+                    pb.push(format!("results.{}", format));
+                    pb
+                }
+            };
+            let rules = match args.rules.as_ref().map(|r| rules::load(Path::new(r))) {
+                Some(Ok(rules)) => rules,
+                Some(Err(e)) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+                None => Vec::new(),
+            };
+
+            let settings = AggregateSettings {
+                cpu_filter: &args.core,
+                uncore_filter: &args.uncore,
+                format,
+                layout: &args.layout,
+                events: args.events.as_deref(),
+                columns: args.columns.as_deref(),
+                normalize: args
+                    .normalize
+                    .as_ref()
+                    .map(|n| normalize::Normalization::from_str(n).unwrap()),
+                incremental: args.incremental,
+                rules: &rules,
+                bootstrap: args.bootstrap,
+            };
+            aggregate(input_directory.as_path(), &settings, output_path.as_path());
+
+            if let Some(tmp) = extracted_to {
+                let _ = std::fs::remove_dir_all(tmp);
+            }
+        }
+        Command::Bench(args) => {
+            let output = config.output.or_flag(args.output.clone()).clone();
+            summary::start("bench");
+            let policy = on_existing(&mut config, args.on_existing.clone());
+            let (output_path, _lock) = lock::claim(Path::new(&output), policy);
+            let output_path = output_path.as_path();
+            pin_housekeeping(&mut config, args.housekeeping_cpu);
+            let benchmarks = args
+                .benchmarks
+                .as_ref()
+                .map(|b| match columnar::event_pattern(b) {
+                    Ok(pattern) => pattern,
+                    Err(e) => {
+                        error!("Invalid --benchmarks pattern: {}", e);
+                        std::process::exit(1);
+                    }
+                });
+            let housekeeping_cpu = *config.housekeeping_cpu.or_flag(None);
+            let cpu = match args.cpu.or_else(|| {
+                util::MachineTopology::new()
+                    .cpus()
+                    .into_iter()
+                    .filter(|c| Some(*c) != housekeeping_cpu)
+                    .max()
+            }) {
+                Some(cpu) => cpu,
+                None => {
+                    error!(
+                        "There is no CPU to run the benchmarks on besides the housekeeping CPU."
+                    );
+                    summary::fail(Failure::Error);
+                }
+            };
+
+            let dryrun = args.dryrun;
+            let mut settings: ProfileSettings = Default::default();
+            errata::exclude(&mut settings, false);
+            if !dryrun {
+                preflight::validate(&mut settings, false);
+            }
+            pmu::prefer_free_running(&mut settings);
+            bench::bench(
+                Path::new(&args.project),
+                output_path,
+                benchmarks.as_ref(),
+                cpu,
+                args.warm_up_time,
+                &settings,
+                dryrun,
+            );
+            std::process::exit(summary::conclude());
+        }
+        Command::Pair(args) => {
+            let output_path = Path::new(&args.directory);
+            if args.step == 0 {
+                error!("skip amount must be > 0");
+                std::process::exit(1);
+            }
+            summary::start("pair");
+
+            let backend_name = config.backend.or_flag(args.backend.clone()).clone();
+            pin_housekeeping(&mut config, args.housekeeping_cpu);
+            configure_llc_filter(args.llc_filter.as_ref());
+
+            let dryrun: bool = args.dryrun;
+            let topology =
+                args.topology
+                    .as_ref()
+                    .map(|spec| match util::MachineTopology::load(spec) {
+                        Ok(mt) => mt,
+                        Err(e) => {
+                            error!("--topology: {}", e);
+                            summary::fail(Failure::Error);
+                        }
+                    });
+            start_exporter(&args.live);
+            let streamer = start_streamer(&args.live, dryrun);
+            let dashboard = start_dashboard(&args.live, dryrun);
+            let policy = on_existing(&mut config, args.on_existing.clone());
+            pair(
+                output_path,
+                PairSettings {
+                    output_root: args.output_root.as_ref().map(|r| Path::new(r.as_str())),
+                    on_existing: policy,
+                    dryrun,
+                    start: args.start,
+                    stepping: args.step,
+                    backend: backend(&backend_name),
+                    skip_invalid: args.skip_invalid,
+                    allow_errata: args.allow_errata,
+                    topology,
+                },
+            );
+            if let Some(streamer) = streamer {
+                streamer.stop();
+            }
+            if let Some(dashboard) = dashboard {
+                dashboard.stop();
+            }
+            std::process::exit(summary::conclude());
+        }
+        Command::Scale(args) => {
+            let output = config.output.or_flag(args.output.clone()).clone();
+            summary::start("scale");
+            let policy = on_existing(&mut config, args.on_existing.clone());
+            let (output_path, _lock) = lock::claim(Path::new(&output), policy);
+            let output_path = output_path.as_path();
+            pin_housekeeping(&mut config, args.housekeeping_cpu);
+            let steps: Vec<usize> = match args.threads.as_ref() {
+                Some(counts) => match counts
+                    .split(',')
+                    .map(|c| usize::from_str(c.trim()))
+                    .collect::<Result<Vec<usize>, _>>()
+                {
+                    Ok(counts) => counts,
+                    Err(e) => {
+                        error!("Invalid --threads '{}': {}", counts, e);
+                        std::process::exit(1);
+                    }
+                },
+                None => scale::topology_steps(&util::MachineTopology::new()),
+            };
+
+            let dryrun = args.dryrun;
+            let mut settings: ProfileSettings = Default::default();
+            errata::exclude(&mut settings, false);
+            if !dryrun {
+                preflight::validate(&mut settings, false);
+            }
+            pmu::prefer_free_running(&mut settings);
+            scale::scale(
+                output_path,
+                &args.command,
+                &steps,
+                &args.threads_env,
+                *config.housekeeping_cpu.or_flag(None),
+                &settings,
+                dryrun,
+            );
+            std::process::exit(summary::conclude());
+        }
+        Command::Stats { directory } => {
+            let output_path = Path::new(&directory);
+            stats(output_path);
+        }
+        Command::Diff(args) => {
+            let a = Path::new(&args.a);
+            let b = Path::new(&args.b);
+            for dir in [a, b].iter() {
+                if !dir.exists() {
+                    error!("Result directory {:?} does not exist", dir);
+                    std::process::exit(1);
+                }
+            }
+            let settings = diff::DiffSettings {
+                alpha: args.alpha,
+                all: args.all,
+                bootstrap: args.bootstrap,
+            };
+            let result = match args.output {
+                Some(ref file) => {
+                    std::fs::File::create(file).and_then(|f| diff::diff(a, b, &settings, f))
+                }
+                None => diff::diff(a, b, &settings, std::io::stdout()),
+            };
+            match result {
+                Ok(rows) => info!("{} differences found", rows),
+                Err(e) => {
+                    error!("Can't compare {:?} and {:?}: {}", a, b, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::DiffConfig(args) => {
+            let a = Path::new(&args.a);
+            let b = Path::new(&args.b);
+            for dir in [a, b].iter() {
+                if !dir.exists() {
+                    error!("Result directory {:?} does not exist", dir);
+                    std::process::exit(1);
+                }
+            }
+            let result = match args.output {
+                Some(ref file) => {
+                    std::fs::File::create(file).and_then(|f| diffconfig::diff_config(a, b, f))
+                }
+                None => diffconfig::diff_config(a, b, std::io::stdout()),
+            };
+            match result {
+                Ok(0) => info!("{:?} and {:?} were set up the same way", a, b),
+                Ok(rows) => info!("{} differences found", rows),
+                Err(e) => {
+                    error!("Can't compare {:?} and {:?}: {}", a, b, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Align(args) => {
+            let a = Path::new(&args.a);
+            let b = args.b.as_ref().map(Path::new);
+            for dir in [Some(a), b].iter().flatten() {
+                if !dir.exists() {
+                    error!("Result directory {:?} does not exist", dir);
+                    std::process::exit(1);
+                }
+            }
+            if args.step <= 0.0 || args.step.is_nan() {
+                error!("--step has to be greater than 0");
+                std::process::exit(1);
+            }
+            let events = args
+                .events
+                .as_ref()
+                .map(|e| match columnar::event_pattern(e) {
+                    Ok(pattern) => pattern,
+                    Err(e) => {
+                        error!("Invalid --events pattern: {}", e);
+                        std::process::exit(1);
+                    }
+                });
+            let settings = align::AlignSettings {
+                step: args.step,
+                method: align::Method::from_str(&args.method).unwrap(),
+                events,
+            };
+            let result = match args.output {
+                Some(ref file) => {
+                    std::fs::File::create(file).and_then(|f| align::align(a, b, &settings, f))
+                }
+                None => align::align(a, b, &settings, std::io::stdout()),
+            };
+            match result {
+                Ok(rows) => info!("Aligned {} points in time", rows),
+                Err(e) => {
+                    error!("Can't align {:?}: {}", a, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Interference(args) => {
+            let campaign = Path::new(&args.campaign);
+            if !campaign.exists() {
+                error!("Campaign directory {:?} does not exist", campaign);
+                std::process::exit(1);
+            }
+            if args.step <= 0.0 || args.step.is_nan() || args.max_lag < 0.0 {
+                error!("--step has to be greater than 0 and --max-lag at least 0");
+                std::process::exit(1);
+            }
+            let signals = args
+                .signals
+                .as_ref()
+                .map_or(interference::DEFAULT_SIGNALS, |s| s.as_str());
+            let signals = match columnar::event_pattern(signals) {
+                Ok(pattern) => pattern,
+                Err(e) => {
+                    error!("Invalid --signals pattern: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let output_path: PathBuf = match args.output {
+                Some(ref v) => PathBuf::from(v),
+                None => campaign.to_path_buf(),
+            };
+            let settings = interference::InterferenceSettings {
+                step: args.step,
+                max_lag: args.max_lag,
+                signals,
+            };
+            util::mkdir(&output_path);
+            match interference::interference(campaign, &settings, &output_path) {
+                Ok(0) => warn!("{:?} has no co-located runs to compare", campaign),
+                Ok(runs) => info!("Compared {} co-located runs", runs),
+                Err(e) => {
+                    error!("Can't compare the runs of {:?}: {}", campaign, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Sensitivity(args) => {
+            let campaign = Path::new(&args.campaign);
+            if !campaign.exists() {
+                error!("Campaign directory {:?} does not exist", campaign);
+                std::process::exit(1);
+            }
+            let result = match args.output {
+                Some(ref file) => {
+                    std::fs::File::create(file).and_then(|f| sensitivity::sensitivity(campaign, f))
+                }
+                None => sensitivity::sensitivity(campaign, std::io::stdout()),
+            };
+            match result {
+                Ok(rows) => info!("Scored {} workloads (per domain)", rows),
+                Err(e) => {
+                    error!("Can't score the workloads of {:?}: {}", campaign, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Recommend(args) => {
+            let campaign = Path::new(&args.campaign);
+            if !campaign.exists() {
+                error!("Campaign directory {:?} does not exist", campaign);
+                std::process::exit(1);
+            }
+            let settings = recommend::RecommendSettings {
+                workloads: args.workloads.as_ref().map_or(Vec::new(), |w| {
+                    w.split(',').map(|w| String::from(w.trim())).collect()
+                }),
+                cores: args.cores,
+                method: recommend::Method::from_str(&args.method).unwrap(),
+                placement: args.placement.clone(),
+            };
+            let result = match args.output {
+                Some(ref file) => std::fs::File::create(file)
+                    .and_then(|f| recommend::recommend(campaign, &settings, f)),
+                None => recommend::recommend(campaign, &settings, std::io::stdout()),
+            };
+            match result {
+                Ok(plans) => info!("{} placements fit on {} cores", plans, args.cores),
+                Err(e) => {
+                    error!("Can't recommend a placement from {:?}: {}", campaign, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Features(args) => {
+            let results = Path::new(&args.results);
+            let output_path: PathBuf = match args.output {
+                Some(ref v) => PathBuf::from(v),
+                None => match results.parent() {
+                    Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+                    _ => PathBuf::from("."),
+                },
+            };
+            let settings = features::FeatureSettings {
+                test_fraction: args.test,
+                seed: args.seed,
+            };
+            util::mkdir(&output_path);
+            if let Err(e) = features::features(results, &settings, &output_path) {
+                error!("Can't create feature matrices from {:?}: {}", results, e);
+                std::process::exit(1);
+            }
+        }
+        Command::Vtune(args) => {
+            let results = Path::new(&args.results);
+            let output_path: PathBuf = match args.output {
+                Some(ref v) => PathBuf::from(v),
+                None => {
+                    let mut p = match results.parent() {
+                        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+                        _ => PathBuf::from("."),
+                    };
+                    p.push("vtune");
+                    p
+                }
+            };
+            let hostname = args
+                .hostname
+                .clone()
+                .unwrap_or_else(|| pair::get_hostname().unwrap_or(String::from("unknown")));
+            util::mkdir(&output_path);
+            match vtune::export(results, &hostname, &output_path) {
+                Ok(runs) => info!("Exported {} runs to {:?}", runs, output_path),
+                Err(e) => {
+                    error!("Can't export {:?} for VTune: {}", results, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Report(args) => {
+            let directory = Path::new(&args.directory);
+            if !directory.exists() {
+                error!("Result directory {:?} does not exist", directory);
+                std::process::exit(1);
+            }
+            let output_path: PathBuf = match args.output {
+                Some(ref v) => PathBuf::from(v),
+                None => {
+                    let mut pb = directory.to_path_buf();
+                    pb.push("report.html");
+                    pb
+                }
+            };
+            let events = args
+                .events
+                .as_ref()
+                .map(|e| match columnar::event_pattern(e) {
+                    Ok(pattern) => pattern,
+                    Err(e) => {
+                        error!("Invalid --events pattern: {}", e);
+                        std::process::exit(1);
+                    }
+                });
+            match report::report(directory, events.as_ref(), args.bootstrap, &output_path) {
+                Ok(()) => info!("Report saved in {:?}", output_path),
+                Err(e) => {
+                    error!("Can't write report {:?}: {}", output_path, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Plot(args) => {
+            let results = Path::new(&args.results);
+            let output_path: PathBuf = match args.output {
+                Some(ref v) => PathBuf::from(v),
+                None => match results.parent() {
+                    Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+                    _ => PathBuf::from("."),
+                },
+            };
+            let plots: Vec<plot::Plot> = args
+                .plots
+                .split(',')
+                .map(|p| match plot::Plot::from_str(p.trim()) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                })
+                .collect();
+            let runs = args
+                .runs
+                .as_ref()
+                .map(|r| match columnar::event_pattern(r) {
+                    Ok(pattern) => pattern,
+                    Err(e) => {
+                        error!("Invalid --runs pattern: {}", e);
+                        std::process::exit(1);
+                    }
+                });
+            util::mkdir(&output_path);
+            match plot::plot(
+                results,
+                &args.metric,
+                &plots,
+                &args.format,
+                runs.as_ref(),
+                &output_path,
+            ) {
+                Ok(files) => info!("Saved {:?}", files),
+                Err(e) => {
+                    error!("Can't plot {:?}: {}", results, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Query(args) => {
+            let database = Path::new(&args.database);
+            if !database.exists() {
+                error!("Result store {:?} does not exist", database);
+                std::process::exit(1);
+            }
+            let query = store::Query {
+                event: args.event.as_deref(),
+                a: args.a.as_deref(),
+                b: args.b.as_deref(),
+                deployment: args.deployment.as_deref(),
+                memory_policy: args.memory_policy.as_deref(),
+                frequency_mhz: args.frequency,
+                priority_a: args.priority_a.as_deref(),
+                priority_b: args.priority_b.as_deref(),
+                phase: args.phase.as_deref(),
+                total: args.total,
+            };
+            match store::Store::open(database).and_then(|s| s.query(&query, std::io::stdout())) {
+                Ok(rows) => debug!("Query returned {} rows", rows),
+                Err(e) => {
+                    error!("Query failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Search(args) => match args.pattern {
+            Some(pattern) => {
+                let regex = regex::RegexBuilder::new(&pattern)
+                    .case_insensitive(true)
+                    .build();
+                let pattern = match regex {
+                    Ok(pattern) => pattern,
+                    Err(e) => {
+                        error!("Invalid pattern '{}': {}", pattern, e);
+                        std::process::exit(1);
+                    }
+                };
+                print_catalog_events(&pattern);
+            }
+            None => print_unknown_events(),
+        },
+        Command::Spec(args) => {
+            let benchmarks = args
+                .benchmarks
+                .as_ref()
+                .map(|b| match columnar::event_pattern(b) {
+                    Ok(pattern) => pattern,
+                    Err(e) => {
+                        error!("Invalid --benchmarks pattern: {}", e);
+                        std::process::exit(1);
+                    }
+                });
+            let configurations: Vec<String> = args
+                .configurations
+                .split(',')
+                .map(|c| String::from(c.trim()))
+                .collect();
+            if let Some(c) = configurations
+                .iter()
+                .find(|c| !deployment::DEPLOYMENTS.contains(&c.as_str()))
+            {
+                error!(
+                    "Unknown deployment '{}' (there are {}).",
+                    c,
+                    deployment::DEPLOYMENTS.join(", ")
+                );
+                std::process::exit(1);
+            }
+            let output_path = PathBuf::from(args.output.as_ref().map_or(".", |o| o.as_str()));
+            util::mkdir(&output_path);
+            spec::generate(
+                Path::new(&args.spec),
+                &args.tune,
+                &args.size,
+                benchmarks.as_ref(),
+                &configurations,
+                &output_path,
+            );
+        }
+        Command::Schema(args) => {
+            if let Some(ref output) = args.output {
+                let output_path = Path::new(output);
+                util::mkdir(output_path);
+                match schema::save_schemas(output_path) {
+                    Ok(schemas) => info!("Saved {} schemas in {:?}", schemas, output_path),
+                    Err(e) => {
+                        error!("Can't save schemas in {:?}: {}", output_path, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if let Some(ref artifact) = args.artifact {
+                match schema::schema(artifact) {
+                    Some(schema) => println!("{}", schema),
+                    None => {
+                        error!(
+                            "Unknown artifact {}, known are {}",
+                            artifact,
+                            schema::artifacts().join(", ")
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        Command::Verify(args) => {
+            let input = Path::new(&args.directory);
+            // The hashes of a compressed run are in its archive:
+            let mut extracted_to: Option<PathBuf> = None;
+            let directory: PathBuf = if archive::is_archive(input) {
+                let mut tmp = std::env::temp_dir();
+                tmp.push(format!("autoperf-{}", std::process::id()));
+                match archive::extract(input, &tmp) {
+                    Ok(dir) => {
+                        extracted_to = Some(tmp);
+                        dir
+                    }
+                    Err(e) => {
+                        error!("Can't extract {:?}: {}", input, e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                input.to_path_buf()
+            };
+            let verification = integrity::verify(&directory);
+            if let Some(tmp) = extracted_to {
+                let _ = std::fs::remove_dir_all(tmp);
+            }
+            match verification {
+                Ok(verification) => {
+                    for file in verification.changed.iter() {
+                        println!("CHANGED  {}", file.display());
+                    }
+                    for file in verification.missing.iter() {
+                        println!("MISSING  {}", file.display());
+                    }
+                    if args.unlisted {
+                        for file in verification.unlisted.iter() {
+                            println!("UNLISTED {}", file.display());
+                        }
+                    }
+                    if verification.ok() {
+                        info!(
+                            "All {} files of {:?} are intact ({} added later).",
+                            verification.intact,
+                            input,
+                            verification.unlisted.len()
+                        );
+                    } else {
+                        error!(
+                            "{:?}: {} files changed, {} missing, {} intact.",
+                            input,
+                            verification.changed.len(),
+                            verification.missing.len(),
+                            verification.intact
+                        );
+                        std::process::exit(1);
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "Can't check {:?} (is there a {}?): {}",
+                        input,
+                        integrity::HASHES_FILE,
+                        e
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Topology(args) => {
+            if let Some(TopologyAction::Diff { a, b }) = args.action {
+                let read = |spec: &str| match topology::CapturedTopology::read(spec) {
+                    Ok(topology) => topology,
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                };
+                let differences = topology::diff(&read(&a), &read(&b));
+                if differences.is_empty() {
+                    println!("The topologies are the same.");
+                }
+                for difference in differences {
+                    println!("{}", difference);
+                }
+                return;
+            }
+            if args.cache_sweep {
+                match cachesweep::machine_sweep(&util::MachineTopology::new()) {
+                    Ok(sweep) => println!("{}", cachesweep::to_toml(&sweep)),
+                    Err(e) => {
+                        error!("Can't sweep the caches: {}", e);
+                        summary::fail(Failure::Environment);
+                    }
+                }
+                return;
+            }
+            if args.uncore_boxes {
+                let mt = util::MachineTopology::new();
+                for cpu in mt.cpus() {
+                    match uncore::uncore_boxes_for(&mt, cpu) {
+                        Ok(boxes) => println!("CPU {}: {}", cpu, boxes.join(" ")),
+                        Err(e) => {
+                            error!("Can't find the uncore boxes: {}", e);
+                            summary::fail(Failure::Environment);
+                        }
+                    }
+                }
+                return;
+            }
+            let live = args.topology.is_none();
+            let mt = match args.topology.as_ref() {
+                Some(spec) => match util::MachineTopology::load(spec) {
+                    Ok(mt) => mt,
+                    Err(e) => {
+                        error!("--topology: {}", e);
+                        std::process::exit(1);
+                    }
+                },
+                None => util::MachineTopology::new(),
+            };
+            match args.render.as_str() {
+                "dot" => print!("{}", topology::dot(&mt)),
+                "json" => print!("{}", topology::CapturedTopology::new(&mt, live).to_json()),
+                _ => print!("{}", topology::ascii(&mt)),
+            }
+        }
+        Command::Config(args) => match args.action {
+            ConfigAction::Show => print!("{}", config),
+        },
+        Command::Completions(args) => {
+            if let Err(e) = completions::print_completions(args.shell) {
+                error!("Can't print the completions: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Mkgroup(args) => {
+            let ranking_file = Path::new(&args.file);
+            mkgroup(ranking_file);
+        }
     }
 }
@@ -0,0 +1,141 @@
+//! The memory policies a `pair` campaign sweeps (`memory_policies` in the
+//! manifest): every run is repeated with the memory of A and B allocated by
+//! each policy, with the same programs and deployment, which gives the NUMA
+//! sensitivity of A. numactl applies the policy:
+//!
+//! * `local`: on the node of the CPU that allocates (`--localalloc`).
+//! * `interleave`, `interleave:0,1`: interleaved across all nodes, or the given
+//!   ones (`--interleave`).
+//! * `preferred:1`: on node 1 while it has memory (`--preferred`).
+//! * `preferred-remote`: on the DRAM node closest to the node of A (by the
+//!   distances of the kernel) that isn't its node.
+//! * `bind:2,3`: only on the given nodes (`--membind`).
+use std::fmt;
+use std::fs;
+
+use super::util::Node;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MemoryPolicy {
+    Local,
+    /// Across these nodes (all if there are none).
+    Interleave(Vec<Node>),
+    Preferred(Node),
+    PreferredRemote,
+    Bind(Vec<Node>),
+}
+
+fn parse_nodes(nodes: &str, spec: &str) -> Result<Vec<Node>, String> {
+    nodes
+        .split(',')
+        .map(|n| {
+            n.trim()
+                .parse::<Node>()
+                .map_err(|_| format!("'{}' in memory policy '{}' isn't a node", n, spec))
+        })
+        .collect()
+}
+
+fn node_list(nodes: &[Node]) -> String {
+    nodes
+        .iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// The distances of `node` to every node, from sysfs (none if it doesn't say).
+fn distances(node: Node) -> Vec<u64> {
+    fs::read_to_string(format!("/sys/devices/system/node/node{}/distance", node))
+        .map(|d| {
+            d.split_whitespace()
+                .filter_map(|d| d.parse::<u64>().ok())
+                .collect()
+        })
+        .unwrap_or(Vec::new())
+}
+
+impl MemoryPolicy {
+    pub fn new(spec: &str) -> Result<MemoryPolicy, String> {
+        let mut parts = spec.trim().splitn(2, ':');
+        let (policy, nodes) = (parts.next().unwrap(), parts.next());
+        match (policy, nodes) {
+            ("local", None) => Ok(MemoryPolicy::Local),
+            ("interleave", None) => Ok(MemoryPolicy::Interleave(Vec::new())),
+            ("interleave", Some(nodes)) => Ok(MemoryPolicy::Interleave(parse_nodes(nodes, spec)?)),
+            ("preferred", Some(node)) => match parse_nodes(node, spec)?.as_slice() {
+                [node] => Ok(MemoryPolicy::Preferred(*node)),
+                _ => Err(format!("memory policy '{}' takes one node", spec)),
+            },
+            ("preferred-remote", None) => Ok(MemoryPolicy::PreferredRemote),
+            ("bind", Some(nodes)) => Ok(MemoryPolicy::Bind(parse_nodes(nodes, spec)?)),
+            _ => Err(format!(
+                "unknown memory policy '{}' (there are local, interleave[:<nodes>], \
+                 preferred:<node>, preferred-remote and bind:<nodes>)",
+                spec
+            )),
+        }
+    }
+
+    /// The nodes the policy names (`interleave:0,1` names 0 and 1).
+    pub fn nodes(&self) -> Vec<Node> {
+        match *self {
+            MemoryPolicy::Interleave(ref on) | MemoryPolicy::Bind(ref on) => on.clone(),
+            MemoryPolicy::Preferred(node) => vec![node],
+            MemoryPolicy::Local | MemoryPolicy::PreferredRemote => Vec::new(),
+        }
+    }
+
+    /// The policy in a directory name, e.g. `interleave-0-1`.
+    pub fn dir_name(&self) -> String {
+        self.to_string().replace([':', ','], "-")
+    }
+
+    /// The numactl options for programs that run on node `local` of a machine with
+    /// the DRAM nodes `nodes`.
+    pub fn numactl_args(&self, local: Node, nodes: &[Node]) -> Result<Vec<String>, String> {
+        Ok(match *self {
+            MemoryPolicy::Local => vec![String::from("--localalloc")],
+            MemoryPolicy::Interleave(ref on) if on.is_empty() => {
+                vec![String::from("--interleave=all")]
+            }
+            MemoryPolicy::Interleave(ref on) => vec![format!("--interleave={}", node_list(on))],
+            MemoryPolicy::Preferred(node) => vec![format!("--preferred={}", node)],
+            MemoryPolicy::PreferredRemote => {
+                let distances = distances(local);
+                let remote = nodes
+                    .iter()
+                    .filter(|n| **n != local)
+                    .min_by_key(|n| distances.get(**n as usize).cloned().unwrap_or(u64::MAX))
+                    .ok_or(format!(
+                        "preferred-remote needs a DRAM node other than node {}",
+                        local
+                    ))?;
+                vec![format!("--preferred={}", remote)]
+            }
+            MemoryPolicy::Bind(ref on) => vec![format!("--membind={}", node_list(on))],
+        })
+    }
+}
+
+impl fmt::Display for MemoryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryPolicy::Local => write!(f, "local"),
+            MemoryPolicy::Interleave(ref on) if on.is_empty() => write!(f, "interleave"),
+            MemoryPolicy::Interleave(ref on) => write!(f, "interleave:{}", node_list(on)),
+            MemoryPolicy::Preferred(node) => write!(f, "preferred:{}", node),
+            MemoryPolicy::PreferredRemote => write!(f, "preferred-remote"),
+            MemoryPolicy::Bind(ref on) => write!(f, "bind:{}", node_list(on)),
+        }
+    }
+}
+
+/// The command that runs `cmd` with the memory policy of `args`.
+pub fn numactl(args: Vec<String>, cmd: Vec<String>) -> Vec<String> {
+    let mut with_policy = vec![String::from("numactl")];
+    with_policy.extend(args);
+    with_policy.push(String::from("--"));
+    with_policy.extend(cmd);
+    with_policy
+}
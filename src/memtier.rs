@@ -0,0 +1,129 @@
+//! What memory a NUMA node has: DRAM, persistent memory or CXL memory. The
+//! kernel onlines PMem namespaces and CXL regions as nodes of their own (usually
+//! without CPUs) through a dax device (`daxctl reconfigure-device --mode
+//! system-ram`), the dax device's `target_node` is that node and where it sits
+//! in sysfs tells what it is (below an NVDIMM bus or a CXL decoder). Every other
+//! node is DRAM.
+//!
+//! `profile` saves the tiers in `memory_tiers.csv`, `--memory-tier` (and
+//! `memory_tier` in a pair manifest) binds the memory of the programs to the
+//! nodes of a tier.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::util::Node;
+
+/// The dax devices (of PMem namespaces and CXL regions).
+const DAX_DEVICES: &str = "/sys/bus/dax/devices";
+
+/// The file in a run directory.
+pub const TIER_FILE: &str = "memory_tiers.csv";
+
+/// The tiers we know (what `--memory-tier` takes).
+pub const TIERS: [&str; 3] = ["dram", "pmem", "cxl"];
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, RustcEncodable, Default)]
+pub enum MemoryTier {
+    #[default]
+    Dram,
+    Pmem,
+    Cxl,
+}
+
+impl MemoryTier {
+    pub fn new(tier: &str) -> Option<MemoryTier> {
+        match tier {
+            "dram" => Some(MemoryTier::Dram),
+            "pmem" => Some(MemoryTier::Pmem),
+            "cxl" => Some(MemoryTier::Cxl),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            MemoryTier::Dram => "dram",
+            MemoryTier::Pmem => "pmem",
+            MemoryTier::Cxl => "cxl",
+        }
+    }
+}
+
+impl fmt::Display for MemoryTier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The tier of the memory of dax device `device`, from where it is in sysfs:
+/// `.../ACPI0012:00/ndbus0/region0/dax0.0/dax0.0` is PMem,
+/// `.../ACPI0017:00/root0/decoder0.0/region0/dax_region0/dax0.0` CXL.
+fn dax_tier(device: &Path) -> Option<MemoryTier> {
+    let path = fs::canonicalize(device).ok()?;
+    let is = |prefix: &str| {
+        path.components()
+            .any(|c| c.as_os_str().to_string_lossy().starts_with(prefix))
+    };
+    if is("ndbus") {
+        Some(MemoryTier::Pmem)
+    } else if is("decoder") || is("ACPI0017") {
+        Some(MemoryTier::Cxl)
+    } else {
+        // e.g. soft-reserved memory (hmem) we can't tell apart from DRAM
+        None
+    }
+}
+
+/// The nodes that aren't DRAM on this machine and their tier.
+pub fn tiers() -> Vec<(Node, MemoryTier)> {
+    let mut tiers = Vec::new();
+    let entries = match fs::read_dir(DAX_DEVICES) {
+        Ok(entries) => entries,
+        Err(_) => return tiers,
+    };
+    for device in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        // target_node is -1 for a device that has none:
+        let node = fs::read_to_string(device.join("target_node"))
+            .ok()
+            .and_then(|n| n.trim().parse::<Node>().ok());
+        if let (Some(node), Some(tier)) = (node, dax_tier(&device)) {
+            if !tiers.contains(&(node, tier)) {
+                tiers.push((node, tier));
+            }
+        }
+    }
+    tiers.sort();
+    tiers
+}
+
+fn to_io_error(e: csv::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Saves the nodes that aren't DRAM in `TIER_FILE` in `output_path`.
+pub fn save_memory_tiers(output_path: &Path) -> io::Result<Vec<(Node, MemoryTier)>> {
+    let tiers = tiers();
+    let mut tier_file = output_path.to_path_buf();
+    tier_file.push(TIER_FILE);
+    let mut wtr = csv::Writer::from_file(tier_file).map_err(to_io_error)?;
+    wtr.encode(("node", "tier")).map_err(to_io_error)?;
+    for &(node, tier) in tiers.iter() {
+        wtr.encode((node, tier.as_str())).map_err(to_io_error)?;
+    }
+    Ok(tiers)
+}
+
+/// The tiers in `TIER_FILE` at `path` (none if there is no such file, all nodes
+/// are DRAM then).
+pub fn read_memory_tiers(path: &Path) -> Vec<(Node, MemoryTier)> {
+    let mut rdr = match csv::Reader::from_file(path) {
+        Ok(rdr) => rdr.has_headers(true),
+        Err(_) => return Vec::new(),
+    };
+    rdr.decode()
+        .filter_map(|row: csv::Result<(Node, String)>| row.ok())
+        .filter_map(|(node, tier)| MemoryTier::new(&tier).map(|t| (node, t)))
+        .collect()
+}
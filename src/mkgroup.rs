@@ -6,7 +6,7 @@ use csv;
 use phf::Map;
 
 use super::profile::{MonitoringUnit, PerfEvent, PerfEventGroup};
-use log::*;
+use tracing::info;
 use x86::perfcnt::intel::{events, EventDescription};
 
 pub fn mkgroup(ranking_file: &Path) {
@@ -25,6 +25,8 @@ pub fn mkgroup(ranking_file: &Path) {
     res.insert(MonitoringUnit::R2PCIe, 4);
     res.insert(MonitoringUnit::R3QPI, 2); // According to the manual this is 3 but then it multiplexes...
     res.insert(MonitoringUnit::QPI, 4); // Not in the manual?
+    let mut fixed = HashMap::with_capacity(1);
+    fixed.insert(MonitoringUnit::CPU, 3);
 
     // Accuracy,Config,Error,Event,F1 score,Precision/Recall,Samples,Samples detail,Test App
     // Accuracy,Error,Event,F1 score,Precision,Recall,Samples Test 0,Samples Test 1,Samples Test Total,Samples Training 0,Samples Training 1,Samples Training Total,Tested Application,Training Configs
@@ -45,7 +47,7 @@ pub fn mkgroup(ranking_file: &Path) {
         .has_headers(true);
     let mut events_added = HashMap::with_capacity(25);
 
-    let mut group = PerfEventGroup::new(&res);
+    let mut group = PerfEventGroup::new(&res, &fixed);
 
     for row in rdr.decode() {
         let (_, _, feature_name, _, _, _, _, _, _, _): OutputRow = row.unwrap();
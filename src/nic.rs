@@ -0,0 +1,107 @@
+//! The network interfaces of the machine that are (or are on) a PCI device and
+//! where they're attached (see `pci.rs`), so network-heavy programs can be
+//! placed close to their NIC or far from it (the `NIC-local` and `NIC-remote`
+//! deployments). Virtual interfaces (loopback, bridges, veths) have no device
+//! and aren't NICs here.
+//!
+//! `profile` saves them in `nics.json`, `MachineTopology::nics_on_node` reads it.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use rustc_serialize::json::{self, Json};
+use schemars::JsonSchema;
+
+use super::pci;
+use super::util::{Cpu, Node};
+
+/// Where the kernel lists the network interfaces.
+const NET_DEVICES: &str = "/sys/class/net";
+
+/// The file in a run directory.
+pub const NIC_FILE: &str = "nics.json";
+
+#[derive(Debug, Clone, Eq, PartialEq, RustcEncodable, JsonSchema)]
+pub struct NicInfo {
+    /// The interface, e.g. eth0
+    pub name: String,
+    /// e.g. 0000:3b:00.0
+    pub pci_address: String,
+    /// The kernel driver of the PCI device.
+    pub driver: String,
+    /// The NUMA node the NIC is attached to (none if the machine doesn't say).
+    pub node: Option<Node>,
+    /// The CPUs close to the NIC.
+    pub cpus: Vec<Cpu>,
+    /// The bus of the PCIe root complex the NIC is below.
+    pub pcie_root: String,
+    /// Link speed in Mbit/s (none if the link is down).
+    pub speed: Option<u64>,
+}
+
+impl NicInfo {
+    fn from_json(nic: &Json) -> Option<NicInfo> {
+        let text = |key: &str| nic.find(key).and_then(|v| v.as_string()).map(String::from);
+        Some(NicInfo {
+            name: text("name")?,
+            pci_address: text("pci_address").unwrap_or(String::new()),
+            driver: text("driver").unwrap_or(String::new()),
+            node: nic.find("node").and_then(|v| v.as_u64()),
+            cpus: nic
+                .find("cpus")
+                .and_then(|v| v.as_array())
+                .map_or(Vec::new(), |cpus| {
+                    cpus.iter().filter_map(|c| c.as_u64()).collect()
+                }),
+            pcie_root: text("pcie_root").unwrap_or(String::new()),
+            speed: nic.find("speed").and_then(|v| v.as_u64()),
+        })
+    }
+}
+
+/// The NICs of this machine (by name).
+pub fn nics() -> Vec<NicInfo> {
+    let mut nics = Vec::new();
+    let entries = match fs::read_dir(NET_DEVICES) {
+        Ok(entries) => entries,
+        Err(_) => return nics,
+    };
+    for interface in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let locality = match pci::locality(&interface.join("device")) {
+            Some(locality) => locality,
+            None => continue,
+        };
+        nics.push(NicInfo {
+            name: interface
+                .file_name()
+                .map_or(String::new(), |n| n.to_string_lossy().into_owned()),
+            pci_address: locality.address,
+            driver: locality.driver,
+            node: locality.node,
+            cpus: locality.cpus,
+            pcie_root: locality.root,
+            // -1 (or an error reading it) if the link is down:
+            speed: pci::read_trimmed(&interface.join("speed"))
+                .parse::<u64>()
+                .ok(),
+        });
+    }
+    nics.sort_by(|a, b| a.name.cmp(&b.name));
+    nics
+}
+
+/// Saves the NICs of this machine in `NIC_FILE` in `output_path`.
+pub fn save_nic_topology(output_path: &Path) -> io::Result<Vec<NicInfo>> {
+    let nics = nics();
+    pci::save_content(
+        output_path,
+        NIC_FILE,
+        &format!("{}\n", json::as_pretty_json(&nics)),
+    )?;
+    Ok(nics)
+}
+
+/// The NICs in `NIC_FILE` at `path` (none if there is no such file).
+pub fn read_nics(path: &Path) -> Vec<NicInfo> {
+    pci::read_list(path, NicInfo::from_json)
+}
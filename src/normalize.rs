@@ -0,0 +1,228 @@
+//! Normalizes the aggregated counter values: per second (of the measurement
+//! interval), per 1000 instructions (PKI) or per 1000 cycles (PKC).
+//!
+//! The mode ends up in the name of the value column (e.g., `SAMPLE_VALUE_PKI`),
+//! so normalized results can't be mistaken for raw counts.
+use std::collections::HashMap;
+use std::process;
+use std::str::FromStr;
+
+use tracing::error;
+
+use super::columnar::ResultWriter;
+use super::profile::INTERVAL_MS;
+
+/// Names under which perf (or the Intel event lists) know the instructions counter.
+pub const INSTRUCTIONS: [&str; 3] = ["instructions", "INST_RETIRED.ANY", "inst_retired.any"];
+
+/// Names under which perf (or the Intel event lists) know the cycles counter.
+pub const CYCLES: [&str; 5] = [
+    "cycles",
+    "cpu-cycles",
+    "CPU_CLK_UNHALTED.THREAD",
+    "cpu_clk_unhalted.thread",
+    "CPU_CLK_UNHALTED.THREAD_P",
+];
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Normalization {
+    /// Per second of the interval (per-second).
+    Seconds,
+    /// Per 1000 instructions (pki).
+    Instructions,
+    /// Per 1000 cycles (pkc).
+    Cycles,
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Normalization, String> {
+        match s {
+            "per-second" => Ok(Normalization::Seconds),
+            "pki" => Ok(Normalization::Instructions),
+            "pkc" => Ok(Normalization::Cycles),
+            _ => Err(format!("Unknown normalization '{}'", s)),
+        }
+    }
+}
+
+impl Normalization {
+    /// What we append to `SAMPLE_VALUE` (and the event columns in the wide layout).
+    pub fn suffix(&self) -> &'static str {
+        match *self {
+            Normalization::Seconds => "_PER_SECOND",
+            Normalization::Instructions => "_PKI",
+            Normalization::Cycles => "_PKC",
+        }
+    }
+
+    /// The result columns once the values are normalized.
+    pub fn columns(&self, columns: &[&str]) -> Vec<String> {
+        columns
+            .iter()
+            .map(|c| {
+                if *c == "SAMPLE_VALUE" {
+                    format!("{}{}", c, self.suffix())
+                } else {
+                    String::from(*c)
+                }
+            })
+            .collect()
+    }
+
+    fn reference_events(&self) -> &'static [&'static str] {
+        match *self {
+            Normalization::Seconds => &[],
+            Normalization::Instructions => &INSTRUCTIONS,
+            Normalization::Cycles => &CYCLES,
+        }
+    }
+}
+
+/// Is `column` a normalized value (so it holds floats instead of counts)?
+pub fn is_normalized(column: &str) -> bool {
+    [
+        Normalization::Seconds,
+        Normalization::Instructions,
+        Normalization::Cycles,
+    ]
+    .iter()
+    .any(|n| column.ends_with(n.suffix()))
+}
+
+fn position(columns: &[&str], name: &str) -> Option<usize> {
+    columns.iter().position(|c| *c == name)
+}
+
+/// Normalizes the values of the result rows before passing them on.
+///
+/// Rows of one sample (same run, index and time) come in one after the other, we
+/// keep them until the sample is complete since the instructions or cycles of a
+/// sample may come after the events we normalize with them (core events are
+/// normalized with the counts at the same location, uncore events with the sum
+/// of the counts on their socket).
+pub struct NormalizeWriter {
+    inner: Box<dyn ResultWriter>,
+    normalization: Normalization,
+    columns: usize,
+    run: Option<usize>,
+    event: usize,
+    index: usize,
+    time: usize,
+    unit: usize,
+    value: usize,
+    socket: usize,
+    location: Vec<usize>,
+    sample: Vec<Vec<String>>,
+    previous_time: f64,
+}
+
+impl NormalizeWriter {
+    pub fn new(
+        inner: Box<dyn ResultWriter>,
+        normalization: Normalization,
+        columns: &[&str],
+    ) -> NormalizeWriter {
+        let required = |name: &str| position(columns, name).expect("Results don't have column");
+        NormalizeWriter {
+            inner,
+            normalization,
+            columns: columns.len(),
+            run: position(columns, "RUN"),
+            event: required("EVENT_NAME"),
+            index: required("INDEX"),
+            time: required("TIME"),
+            unit: required("UNIT"),
+            value: required("SAMPLE_VALUE"),
+            socket: required("SOCKET"),
+            location: ["SOCKET", "CORE", "CPU", "NODE", "TID"]
+                .iter()
+                .map(|c| required(c))
+                .collect(),
+            sample: Vec::new(),
+            previous_time: 0.0,
+        }
+    }
+
+    fn same_sample(&self, a: &[String], b: &[&str]) -> bool {
+        self.run.is_none_or(|idx| a[idx] == b[idx])
+            && a[self.index] == b[self.index]
+            && a[self.time] == b[self.time]
+    }
+
+    fn location(&self, row: &[String]) -> Vec<String> {
+        self.location.iter().map(|idx| row[*idx].clone()).collect()
+    }
+
+    /// Normalizes and writes the rows of the sample we're holding on to.
+    fn write_sample(&mut self) {
+        if self.sample.is_empty() {
+            return;
+        }
+        let time = f64::from_str(&self.sample[0][self.time]).unwrap_or(0.0);
+        // perf's time is since it started, the first interval we keep is one
+        // `-I` before it (or less, if the program ended first), even if a start
+        // breakpoint or `-D` left out the time before:
+        let interval = if self.sample[0][self.index] == "0" {
+            time.min(INTERVAL_MS as f64 / 1000.0)
+        } else {
+            time - self.previous_time
+        };
+        self.previous_time = time;
+
+        // Instructions (or cycles) per location and per socket:
+        let mut per_location: HashMap<Vec<String>, f64> = HashMap::new();
+        let mut per_socket: HashMap<String, f64> = HashMap::new();
+        let references = self.normalization.reference_events();
+        for row in self.sample.iter() {
+            if row[self.unit] == "cpu" && references.contains(&row[self.event].as_str()) {
+                let value = f64::from_str(&row[self.value]).unwrap_or(0.0);
+                *per_location.entry(self.location(row)).or_insert(0.0) += value;
+                *per_socket.entry(row[self.socket].clone()).or_insert(0.0) += value;
+            }
+        }
+
+        let sample: Vec<Vec<String>> = self.sample.drain(..).collect();
+        for mut row in sample {
+            let value = f64::from_str(&row[self.value]).ok();
+            let reference = match self.normalization {
+                Normalization::Seconds => Some(interval),
+                _ if row[self.unit] == "cpu" => {
+                    per_location.get(&self.location(&row)).map(|r| r / 1000.0)
+                }
+                _ => per_socket.get(&row[self.socket]).map(|r| r / 1000.0),
+            };
+            row[self.value] = match (value, reference) {
+                (Some(v), Some(r)) if r > 0.0 => (v / r).to_string(),
+                _ => String::new(),
+            };
+            let row: Vec<&str> = row.iter().map(|v| v.as_str()).collect();
+            self.inner.write_row(&row);
+        }
+    }
+}
+
+impl ResultWriter for NormalizeWriter {
+    fn write_row(&mut self, row: &[&str]) {
+        if row.len() != self.columns {
+            // Sampled (perf record) results don't have all the columns:
+            error!("Can't normalize sampled (perf record) results, aggregate them without --normalize.");
+            process::exit(1);
+        }
+        let new_sample = self
+            .sample
+            .first()
+            .is_some_and(|first| !self.same_sample(first, row));
+        if new_sample {
+            self.write_sample();
+        }
+        self.sample
+            .push(row.iter().map(|v| String::from(*v)).collect());
+    }
+
+    fn finish(&mut self) {
+        self.write_sample();
+        self.inner.finish();
+    }
+}
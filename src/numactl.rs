@@ -0,0 +1,150 @@
+//! Reads the output of `numactl --hardware` (`numactl.dat`):
+//!
+//! ```text
+//! available: 2 nodes (0-1)
+//! node 0 cpus: 0 1 2 3
+//! node 0 size: 32161 MB
+//! node 0 free: 30679 MB
+//! node 1 cpus:
+//! node 1 size: 0 MB
+//! node 1 free: 0 MB
+//! node distances:
+//! node   0   1
+//!   0:  10  21
+//!   1:  21  10
+//! ```
+//!
+//! We go line by line and only look at `node <n> <field>: <value>`, everything
+//! else (the distances, `No NUMA available on this system`, ...) is skipped.
+//! Versions and distributions differ in the spacing, whether a node without
+//! CPUs or memory has a `cpus:` or `size:` line at all and whether the node ids
+//! are contiguous (`available: 2 nodes (0,2)`), so we assume none of it.
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use super::bytesize::{ByteSize, Prefixes};
+use super::util::{Cpu, Node};
+
+/// What `numactl --hardware` says about a node.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct NumaNode {
+    pub node: Node,
+    /// Empty for nodes without CPUs (or without a `cpus:` line).
+    pub cpus: Vec<Cpu>,
+    /// In bytes, None if there is no `size:` line.
+    pub size: Option<u64>,
+    /// In bytes, None if there is no `free:` line.
+    pub free: Option<u64>,
+}
+
+/// Splits `node 0 size: 32161 MB` into the node, the field and its value.
+fn node_line(line: &str) -> Option<(Node, &str, &str)> {
+    let line = line.trim();
+    if !line.starts_with("node") {
+        return None;
+    }
+    let rest = line["node".len()..].trim_start();
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let node = Node::from_str(&rest[..digits]).ok()?;
+    // `node   0   1` (the distance header) has no field:
+    let colon = rest.find(':')?;
+    let field = rest[digits..colon].trim();
+    if field.is_empty() || field.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((node, field, rest[colon + 1..].trim()))
+}
+
+/// `32161 MB` in bytes (numactl's MB are MiB), MB if there is no unit.
+pub fn size(value: &str) -> Option<u64> {
+    ByteSize::parse(value, Prefixes::Binary, "MB").map(|size| size.bytes())
+}
+
+/// Every node `numactl --hardware` lists, with or without CPUs and memory,
+/// sorted by id. Lines we can't read are skipped.
+pub fn parse(numactl_output: &str) -> Vec<NumaNode> {
+    let mut nodes: BTreeMap<Node, NumaNode> = BTreeMap::new();
+    for (node, field, value) in numactl_output.lines().filter_map(node_line) {
+        let entry = nodes.entry(node).or_insert_with(|| NumaNode {
+            node,
+            ..Default::default()
+        });
+        match field {
+            "cpus" => {
+                entry.cpus = value
+                    .split_whitespace()
+                    .filter_map(|cpu| Cpu::from_str(cpu).ok())
+                    .collect()
+            }
+            "size" => entry.size = size(value),
+            "free" => entry.free = size(value),
+            _ => (),
+        }
+    }
+    nodes.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn node(node: Node, cpus: Vec<Cpu>, size: u64, free: u64) -> NumaNode {
+        NumaNode {
+            node,
+            cpus,
+            size: Some(size * MIB),
+            free: Some(free * MIB),
+        }
+    }
+
+    #[test]
+    fn single_node() {
+        let nodes = parse(include_str!("../tests/topology/debian12-vm/numactl.dat"));
+        assert_eq!(nodes, vec![node(0, vec![0, 1, 2, 3], 7941, 6204)]);
+    }
+
+    #[test]
+    fn two_nodes() {
+        let nodes = parse(include_str!("../tests/topology/ubuntu-2204-2s/numactl.dat"));
+        assert_eq!(
+            nodes,
+            vec![
+                node(0, vec![0, 1, 4, 5], 32102, 29870),
+                node(1, vec![2, 3, 6, 7], 32253, 31544),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_and_memoryless_nodes() {
+        // Node 1 is missing, node 2 has no memory and node 3 no CPUs:
+        let nodes = parse(include_str!(
+            "../tests/topology/rhel8-memoryless/numactl.dat"
+        ));
+        assert_eq!(
+            nodes,
+            vec![
+                node(0, vec![0, 1], 15730, 14021),
+                node(2, vec![2, 3], 0, 0),
+                node(3, vec![], 65536, 65311),
+            ]
+        );
+    }
+
+    #[test]
+    fn nodes_without_size_and_free_lines() {
+        let nodes = parse("available: 2 nodes (0-1)\nnode 0 cpus: 0 1\nnode  1  cpus:  2 3\n");
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[1].cpus, vec![2, 3]);
+        assert_eq!((nodes[1].size, nodes[1].free), (None, None));
+    }
+
+    #[test]
+    fn without_numa() {
+        assert_eq!(parse("No NUMA available on this system\n"), vec![]);
+    }
+}
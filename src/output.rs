@@ -0,0 +1,322 @@
+//! Saves what a program writes to stdout and stderr while it runs
+//! (`<nr>_stdout.txt` and `<nr>_stderr.txt` of every measurement, `B_stdout.txt`
+//! and `B_stderr.txt` of an antagonist). Every line can get the seconds since the
+//! program started (`[    1.250034] ...`), and a chatty program can't fill the
+//! disk: once a file has `max_bytes` we write a marker and drop the rest (but
+//! keep reading, or the program would block on a full pipe).
+//!
+//! Many benchmarks report how well they did themselves (`Throughput: 1234.5
+//! ops/s`, or `{"ops_per_sec": 1234.5}`). A `FigureOfMerit` finds that number with
+//! a regex (its first group, or all of it) or a JSON pointer, either in every
+//! line of stdout (and stderr, the last one found wins) or in a file the program
+//! writes. It's saved per measurement in `figure_of_merit.csv` and `aggregate`
+//! adds it to the results (as an event of its own, next to the counters of the
+//! same measurement), so counters can be regressed against what the program
+//! actually got done.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Instant, SystemTime};
+
+use regex::Regex;
+use rustc_serialize::json::Json;
+use tracing::{error, warn};
+
+/// Lists the figure of merit of every measurement of a run.
+pub const FIGURE_OF_MERIT_FILE: &str = "figure_of_merit.csv";
+
+/// The `EVENT_NAME` of the figure of merit in the results.
+pub const FIGURE_OF_MERIT: &str = "figure_of_merit";
+
+#[derive(Debug, Default, Clone)]
+pub struct OutputSettings {
+    /// Stop saving a stream once it has this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Start every line with the seconds since the program started.
+    pub timestamps: bool,
+    /// How the program reports its figure of merit.
+    pub figure_of_merit: Option<FigureOfMerit>,
+}
+
+/// How we find the number in the output (or file).
+#[derive(Debug, Clone)]
+pub enum Extract {
+    Regex(Regex),
+    /// A JSON pointer (RFC 6901, e.g. `/results/0/throughput`).
+    Json(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct FigureOfMerit {
+    /// Its `EVENT_NAME` in the results.
+    pub name: String,
+    pub extract: Extract,
+    /// Read it from this file (relative to the working directory of the program)
+    /// once the program is done, instead of from its output.
+    pub file: Option<PathBuf>,
+}
+
+/// What we kept of a stream: the first `max_bytes` of it and the last figure of
+/// merit it had.
+struct Stream {
+    text: Vec<u8>,
+    merit: Option<f64>,
+}
+
+impl Stream {
+    fn empty() -> Stream {
+        Stream {
+            text: Vec::new(),
+            merit: None,
+        }
+    }
+}
+
+/// The figure of merit in `line`, if `pattern` matches it.
+pub fn parse_merit(pattern: &Regex, line: &str) -> Option<f64> {
+    let found = pattern.captures(line)?;
+    let text = found.get(1).or(found.get(0))?.as_str();
+    f64::from_str(text.trim()).ok()
+}
+
+/// Follows `pointer` in `json` to a number (or a string with one).
+pub fn json_merit(json: &Json, pointer: &str) -> Option<f64> {
+    let mut value = json;
+    for token in pointer.split('/').skip(1) {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        value = match *value {
+            Json::Object(ref members) => members.get(&token)?,
+            Json::Array(ref items) => items.get(usize::from_str(&token).ok()?)?,
+            _ => return None,
+        };
+    }
+    match *value {
+        Json::F64(v) => Some(v),
+        Json::I64(v) => Some(v as f64),
+        Json::U64(v) => Some(v as f64),
+        Json::String(ref s) => f64::from_str(s.trim()).ok(),
+        _ => None,
+    }
+}
+
+impl FigureOfMerit {
+    /// Needs exactly one of `regex` and `pointer`, `name` is `figure_of_merit` by
+    /// default.
+    pub fn new(
+        name: Option<&str>,
+        regex: Option<&str>,
+        pointer: Option<&str>,
+        file: Option<&str>,
+    ) -> Result<FigureOfMerit, String> {
+        let extract = match (regex, pointer) {
+            (Some(pattern), None) => Extract::Regex(
+                Regex::new(pattern).map_err(|e| format!("invalid regex '{}': {}", pattern, e))?,
+            ),
+            (None, Some(pointer)) if pointer.is_empty() || pointer.starts_with('/') => {
+                Extract::Json(pointer.to_string())
+            }
+            (None, Some(pointer)) => {
+                return Err(format!(
+                    "the JSON pointer '{}' should start with '/'",
+                    pointer
+                ))
+            }
+            (None, None) => return Err(String::from("it needs a regex or a JSON pointer")),
+            (Some(_), Some(_)) => {
+                return Err(String::from(
+                    "it has a regex and a JSON pointer, only one can find it",
+                ))
+            }
+        };
+        let name = name.unwrap_or(FIGURE_OF_MERIT).trim();
+        if name.is_empty() {
+            return Err(String::from("its name is empty"));
+        }
+        Ok(FigureOfMerit {
+            name: name.to_string(),
+            extract,
+            file: file.map(PathBuf::from),
+        })
+    }
+
+    /// The figure of merit in a line of output (for JSON, if the line is a JSON
+    /// document that has it).
+    pub fn parse_line(&self, line: &str) -> Option<f64> {
+        match self.extract {
+            Extract::Regex(ref pattern) => parse_merit(pattern, line),
+            Extract::Json(ref pointer) => {
+                let line = line.trim();
+                if !line.starts_with('{') && !line.starts_with('[') {
+                    return None;
+                }
+                Json::from_str(line)
+                    .ok()
+                    .and_then(|json| json_merit(&json, pointer))
+            }
+        }
+    }
+
+    /// The figure of merit in a file: the last line the regex matches, or the
+    /// pointer in the JSON document.
+    pub fn parse_text(&self, text: &str) -> Option<f64> {
+        match self.extract {
+            Extract::Regex(_) => text.lines().filter_map(|l| self.parse_line(l)).next_back(),
+            Extract::Json(ref pointer) => Json::from_str(text)
+                .ok()
+                .and_then(|json| json_merit(&json, pointer)),
+        }
+    }
+
+    /// Reads the figure of merit from `file`, if the program wrote it after
+    /// `since` (an old file is from an earlier measurement).
+    pub fn read_file(&self, working_dir: &Path, since: SystemTime) -> Option<f64> {
+        let file = working_dir.join(self.file.as_ref()?);
+        let modified = fs::metadata(&file).and_then(|m| m.modified()).ok()?;
+        if modified < since {
+            warn!("{:?} is older than the measurement, ignoring it.", file);
+            return None;
+        }
+        self.parse_text(&fs::read_to_string(&file).ok()?)
+    }
+}
+
+fn copy<R: Read>(
+    from: R,
+    to: &Path,
+    settings: &OutputSettings,
+    start: Instant,
+) -> io::Result<Stream> {
+    let mut file = File::create(to)?;
+    let mut reader = BufReader::new(from);
+    let mut stream = Stream::empty();
+    let mut line: Vec<u8> = Vec::new();
+    let mut truncated = false;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        match settings.figure_of_merit {
+            Some(ref fom) if fom.file.is_none() => {
+                if let Some(merit) = fom.parse_line(&String::from_utf8_lossy(&line)) {
+                    stream.merit = Some(merit);
+                }
+            }
+            _ => {}
+        }
+        if truncated {
+            continue;
+        }
+        if let Some(max) = settings.max_bytes {
+            if (stream.text.len() + line.len()) as u64 > max {
+                truncated = true;
+                writeln!(
+                    file,
+                    "[autoperf: truncated, the output is longer than {} bytes]",
+                    max
+                )?;
+                continue;
+            }
+        }
+        if settings.timestamps {
+            write!(file, "[{:12.6}] ", start.elapsed().as_secs_f64())?;
+        }
+        file.write_all(&line)?;
+        stream.text.extend_from_slice(&line);
+    }
+    Ok(stream)
+}
+
+/// Saves the output of a program that's running.
+pub struct Capture {
+    streams: Vec<thread::JoinHandle<io::Result<Stream>>>,
+}
+
+/// What a program wrote (as much as we kept of it) and its figure of merit.
+pub struct Captured {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub merit: Option<f64>,
+}
+
+impl Capture {
+    /// Saves the stdout and stderr of `child` (they have to be piped) to `stdout_to`
+    /// and `stderr_to`.
+    pub fn start(
+        child: &mut Child,
+        stdout_to: &Path,
+        stderr_to: &Path,
+        settings: &OutputSettings,
+    ) -> Capture {
+        let start = Instant::now();
+        let outputs: Vec<(Option<Box<dyn Read + Send>>, PathBuf)> = vec![
+            (
+                child
+                    .stdout
+                    .take()
+                    .map(|s| Box::new(s) as Box<dyn Read + Send>),
+                stdout_to.to_path_buf(),
+            ),
+            (
+                child
+                    .stderr
+                    .take()
+                    .map(|s| Box::new(s) as Box<dyn Read + Send>),
+                stderr_to.to_path_buf(),
+            ),
+        ];
+        let streams = outputs
+            .into_iter()
+            .map(|(from, to)| {
+                let settings = settings.clone();
+                thread::spawn(move || match from {
+                    Some(from) => copy(from, &to, &settings, start),
+                    None => Ok(Stream::empty()),
+                })
+            })
+            .collect();
+        Capture { streams }
+    }
+
+    /// Waits until the program closed its stdout and stderr (it exited).
+    pub fn finish(self) -> Captured {
+        let mut streams: Vec<Stream> = self
+            .streams
+            .into_iter()
+            .map(|s| match s.join() {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    error!("Can't save the output of the program: {}", e);
+                    Stream::empty()
+                }
+                Err(_) => Stream::empty(),
+            })
+            .collect();
+        let stderr = streams.pop().unwrap();
+        let stdout = streams.pop().unwrap();
+        Captured {
+            merit: stdout.merit.or(stderr.merit),
+            stdout: stdout.text,
+            stderr: stderr.text,
+        }
+    }
+}
+
+/// The figure of merit (name and value) of every measurement (by data file) of
+/// the run in `path`.
+pub fn read_merits(path: &Path) -> HashMap<String, (String, f64)> {
+    let mut merits = HashMap::new();
+    if let Ok(mut rdr) = csv::Reader::from_file(path.join(FIGURE_OF_MERIT_FILE)) {
+        for record in rdr.decode() {
+            let record: csv::Result<(String, String, f64)> = record;
+            if let Ok((file, name, merit)) = record {
+                merits.insert(file, (name, merit));
+            }
+        }
+    }
+    merits
+}
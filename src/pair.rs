@@ -1,27 +1,63 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process;
 use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
 use std::thread;
 
 use std::fmt;
 
 use itertools::{iproduct, Itertools};
+use lazy_static::lazy_static;
 use rustc_serialize::Encodable;
 use std::time::Duration;
 use wait_timeout::ChildExt;
 
-use log::*;
-use toml;
-
-use super::profile;
+use tracing::{debug, error, info, info_span, warn, Span};
+
+use super::adaptive::{self, Adaptive, Reason};
+use super::aggregate::{aggregate_into, SOFTWARE_COLUMNS};
+use super::archive;
+use super::bytesize::{ByteSize, Prefixes};
+use super::collectors;
+use super::columnar::ResultWriter;
+use super::cooldown::{CoolDown, Cooler};
+use super::cpufreq::{self, FrequencyLock};
+use super::deployment::{deployment, Deployment};
+use super::errata;
+use super::index::{self, CampaignIndex};
+use super::interference;
+use super::lock;
+use super::logging;
+use super::mempolicy::{self, MemoryPolicy};
+use super::memtier::{self, MemoryTier};
+use super::output::{Capture, FigureOfMerit, OutputSettings};
+use super::pmu::{self, SysfsEvent};
+use super::preflight;
+use super::presets::{self, Preset};
+use super::priority::Priority;
+use super::privilege;
+use super::profile::{self, Backend};
+use super::progress::{CampaignProgress, Outcome};
+use super::resctrl::{self, MonGroup, ResctrlMonitor};
+use super::reset::Reset;
+use super::rules::{self, Rule};
+use super::sampling::{self, Cell, Sampling};
+use super::sched::{self, SchedRecorder};
+use super::sha256::Sha256;
+use super::store::{self, Configuration, Store};
+use super::suites;
+use super::summary::{self, Failure};
 use super::util::*;
+use super::validate;
+use super::watch;
+use super::workload::Workload;
 
-fn get_hostname() -> Option<String> {
+pub fn get_hostname() -> Option<String> {
     use libc::gethostname;
 
     let mut buf: [i8; 64] = [0; 64];
@@ -42,131 +78,8 @@ fn get_hostname() -> Option<String> {
     Some(String::from_utf8(c_str).unwrap())
 }
 
-#[derive(Debug, RustcEncodable)]
-struct Deployment<'a> {
-    description: &'static str,
-    a: Vec<&'a CpuInfo>,
-    b: Vec<&'a CpuInfo>,
-    mem: Vec<NodeInfo>,
-}
-
-impl<'a> Deployment<'a> {
-    pub fn new(
-        desc: &'static str,
-        half_a: Vec<&'a CpuInfo>,
-        half_b: Vec<&'a CpuInfo>,
-        mem: Vec<NodeInfo>,
-    ) -> Deployment<'a> {
-        Deployment {
-            description: desc,
-            a: half_a,
-            b: half_b,
-            mem: mem,
-        }
-    }
-
-    /// Split by just simply interleaving everything
-    /// TODO: this only works because we make assumption on how CpuInfo is ordered..
-    pub fn split_interleaved(
-        desc: &'static str,
-        possible_groupings: Vec<Vec<&'a CpuInfo>>,
-        size: u64,
-    ) -> Deployment<'a> {
-        let mut cpus = possible_groupings.into_iter().last().unwrap();
-
-        let cpus_len = cpus.len();
-        assert!(cpus_len % 2 == 0);
-
-        let upper_half = cpus.split_off(cpus_len / 2);
-        let lower_half = cpus;
-
-        let mut node: NodeInfo = lower_half[0].node;
-        node.memory = size;
-
-        Deployment::new(desc, lower_half, upper_half, vec![node])
-    }
-
-    /// Split but makes sure a group shares the SMT threads
-    pub fn split_smt_aware(
-        desc: &'static str,
-        possible_groupings: Vec<Vec<&'a CpuInfo>>,
-        size: u64,
-    ) -> Deployment<'a> {
-        let cpus = possible_groupings.into_iter().last().unwrap();
-        let cpus_len = cpus.len();
-        assert!(cpus_len % 2 == 0);
-
-        let mut cores: Vec<Core> = cpus.iter().map(|c| c.core).collect();
-        assert!(cores.len() % 2 == 0);
-        cores.sort();
-        cores.dedup();
-
-        let mut upper_half: Vec<&CpuInfo> = Vec::with_capacity(cpus_len / 2);
-        let mut lower_half: Vec<&CpuInfo> = Vec::with_capacity(cpus_len / 2);
-
-        for (i, core) in cores.into_iter().enumerate() {
-            let cpus_on_core: Vec<&&CpuInfo> = cpus.iter().filter(|c| c.core == core).collect();
-            if i % 2 == 0 {
-                lower_half.extend(cpus_on_core.into_iter());
-            } else {
-                upper_half.extend(cpus_on_core.into_iter());
-            }
-        }
-
-        let mut node: NodeInfo = lower_half[0].node;
-        node.memory = size;
-
-        Deployment::new(desc, lower_half, upper_half, vec![node])
-    }
-
-    /// Split but makes sure a group shares the SMT threads
-    pub fn split_l3_aware(
-        desc: &'static str,
-        possible_groupings: Vec<Vec<&'a CpuInfo>>,
-        size: u64,
-    ) -> Deployment<'a> {
-        let cpus = possible_groupings.into_iter().last().unwrap();
-        let cpus_len = cpus.len();
-        assert!(cpus_len % 2 == 0);
-
-        let mut l3s: Vec<L3> = cpus.iter().map(|c| c.l3).collect();
-        assert!(l3s.len() % 2 == 0);
-        l3s.sort();
-        l3s.dedup();
-
-        let mut upper_half: Vec<&CpuInfo> = Vec::with_capacity(cpus_len / 2);
-        let mut lower_half: Vec<&CpuInfo> = Vec::with_capacity(cpus_len / 2);
-
-        for (i, l3) in l3s.into_iter().enumerate() {
-            let cpus_on_l3: Vec<&&CpuInfo> = cpus.iter().filter(|c| c.l3 == l3).collect();
-            if i % 2 == 0 {
-                upper_half.extend(cpus_on_l3.into_iter());
-            } else {
-                lower_half.extend(cpus_on_l3.into_iter());
-            }
-        }
-
-        let mut node: NodeInfo = lower_half[0].node;
-        node.memory = size;
-
-        Deployment::new(desc, lower_half, upper_half, vec![node])
-    }
-}
-
-impl<'a> fmt::Display for Deployment<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let a: Vec<Cpu> = self.a.iter().map(|c| c.cpu).collect();
-        let b: Vec<Cpu> = self.b.iter().map(|c| c.cpu).collect();
-
-        write!(f, "Deployment Plan for {}:\n", self.description)?;
-        write!(f, "-- Program A cores: {:?}\n", a)?;
-        write!(f, "-- Program B cores: {:?}\n", b)?;
-        write!(f, "-- Use memory:\n")?;
-        for n in self.mem.iter() {
-            write!(f, " - On node {}: {} Bytes\n", n.node, n.memory)?;
-        }
-        Ok(())
-    }
+lazy_static! {
+    static ref STORE_LOCK: Mutex<()> = Mutex::new(());
 }
 
 #[derive(Debug, RustcEncodable)]
@@ -184,6 +97,22 @@ struct Program<'a> {
     is_parsec: bool,
     use_watch_repeat: bool,
     alone: bool,
+    /// The label of the dataset it runs (see `datasets` in `suites.rs`).
+    dataset: Option<String>,
+    /// The scheduling class it runs with (see `priority.rs`).
+    priority: Option<String>,
+    /// Save at most this many bytes of its stdout and stderr.
+    max_output: Option<u64>,
+    /// Timestamp the lines of its output.
+    timestamp_output: bool,
+    /// Finds the figure of merit with this regex (see `output.rs`)...
+    figure_of_merit: Option<String>,
+    /// ...or this JSON pointer...
+    figure_of_merit_json: Option<String>,
+    /// ...in this file it writes (instead of its output).
+    figure_of_merit_file: Option<String>,
+    /// The event name of the figure of merit.
+    figure_of_merit_name: Option<String>,
 }
 
 impl<'a> Program<'a> {
@@ -224,6 +153,77 @@ impl<'a> Program<'a> {
         let alone: bool = config.get("alone").map_or(alone_default, |v| {
             v.as_bool().expect("'program.alone' should be boolean")
         });
+        let dataset: Option<String> = config.get("dataset").map(|v| {
+            v.as_str()
+                .expect("'program.dataset' should be a string")
+                .to_string()
+        });
+        let priority: Option<String> = config.get("priority").map(|v| {
+            let spec = v.as_str().expect("'program.priority' should be a string");
+            let priority = match Priority::new(spec) {
+                Ok(priority) => priority,
+                Err(e) => {
+                    error!("Error in manifest.toml: {}", e);
+                    summary::fail(Failure::Error);
+                }
+            };
+            if let Err(e) = priority.check() {
+                error!("Can't run {} with its priority: {}", name, e);
+                summary::fail(Failure::Environment);
+            }
+            priority.to_string()
+        });
+        let max_output: Option<u64> = config.get("max_output").map(|v| {
+            let size = v.as_str().expect("'program.max_output' should be a string");
+            match ByteSize::parse(size, Prefixes::Decimal, "B") {
+                Some(size) => size.bytes(),
+                None => {
+                    error!(
+                        "Error in manifest.toml: invalid max_output '{}' (e.g. \"10MiB\").",
+                        size
+                    );
+                    summary::fail(Failure::Error);
+                }
+            }
+        });
+        let timestamp_output: bool = config.get("timestamp_output").is_some_and(|v| {
+            v.as_bool()
+                .expect("'program.timestamp_output' should be boolean")
+        });
+        // A regex, or a table with `regex` or `json` (and `file`, `name`):
+        let (figure_of_merit, figure_of_merit_json, figure_of_merit_file, figure_of_merit_name) =
+            match config.get("figure_of_merit") {
+                Some(toml::Value::String(pattern)) => (Some(pattern.clone()), None, None, None),
+                Some(toml::Value::Table(fom)) => {
+                    let get = |key: &str| {
+                        fom.get(key).map(|v| {
+                            v.as_str()
+                                .expect("'program.figure_of_merit' keys should be strings")
+                                .to_string()
+                        })
+                    };
+                    (get("regex"), get("json"), get("file"), get("name"))
+                }
+                Some(_) => {
+                    error!("Error in manifest.toml: program.figure_of_merit should be a string or a table");
+                    summary::fail(Failure::Error);
+                }
+                None => (None, None, None, None),
+            };
+        if figure_of_merit.is_some() || figure_of_merit_json.is_some() {
+            if let Err(e) = FigureOfMerit::new(
+                figure_of_merit_name.as_deref(),
+                figure_of_merit.as_deref(),
+                figure_of_merit_json.as_deref(),
+                figure_of_merit_file.as_deref(),
+            ) {
+                error!("Error in manifest.toml: invalid figure_of_merit: {}", e);
+                summary::fail(Failure::Error);
+            }
+        } else if figure_of_merit_file.is_some() || figure_of_merit_name.is_some() {
+            error!("Error in manifest.toml: figure_of_merit needs a regex or a json pointer");
+            summary::fail(Failure::Error);
+        }
         let args: Vec<String> = config["arguments"]
             .as_slice()
             .expect("program.arguments not an array?")
@@ -288,21 +288,91 @@ impl<'a> Program<'a> {
         });
 
         Program {
-            name: name,
-            manifest_path: manifest_path,
-            binary: binary,
+            name,
+            manifest_path,
+            binary,
             is_openmp: openmp,
             is_parsec: parsec,
-            env: env,
-            alone: alone,
-            working_dir: working_dir,
+            env,
+            alone,
+            working_dir,
             use_watch_repeat: watch_repeat,
-            args: args,
-            antagonist_args: antagonist_args,
-            breakpoints: breakpoints,
+            args,
+            antagonist_args,
+            breakpoints,
             // TODO: this is currently not in use (remove?)
-            checkpoints: checkpoints,
+            checkpoints,
+            dataset,
+            priority,
+            max_output,
+            timestamp_output,
+            figure_of_merit,
+            figure_of_merit_json,
+            figure_of_merit_file,
+            figure_of_merit_name,
+        }
+    }
+
+    /// How we save the output of the program.
+    fn output(&self) -> OutputSettings {
+        OutputSettings {
+            max_bytes: self.max_output,
+            timestamps: self.timestamp_output,
+            figure_of_merit: if self.figure_of_merit.is_some()
+                || self.figure_of_merit_json.is_some()
+            {
+                Some(
+                    FigureOfMerit::new(
+                        self.figure_of_merit_name.as_deref(),
+                        self.figure_of_merit.as_deref(),
+                        self.figure_of_merit_json.as_deref(),
+                        self.figure_of_merit_file.as_deref(),
+                    )
+                    .expect("checked in the manifest"),
+                )
+            } else {
+                None
+            },
+        }
+    }
+
+    /// What makes the program what it is in a run (see `run_id`): paths in the
+    /// manifest directory start with `$MANIFEST_DIR`, so the same manifest
+    /// somewhere else is the same program.
+    fn identity(&self, antagonist: bool) -> String {
+        let manifest_dir = self.manifest_path.to_string_lossy();
+        let portable = |s: &str| s.replace(manifest_dir.as_ref(), "$MANIFEST_DIR");
+        let mut identity = format!(
+            "name={}\nbinary={}\nworking_dir={}\n",
+            self.name,
+            portable(&self.binary),
+            portable(&self.working_dir)
+        );
+        let args = if antagonist {
+            &self.antagonist_args
+        } else {
+            &self.args
+        };
+        for arg in args.iter() {
+            identity.push_str(&format!("arg={}\n", portable(arg)));
+        }
+        for (key, value) in self.env.iter() {
+            identity.push_str(&format!("env={}={}\n", key, portable(value)));
+        }
+        if let Some(ref dataset) = self.dataset {
+            identity.push_str(&format!("dataset={}\n", dataset));
+        }
+        if let Some(ref priority) = self.priority {
+            identity.push_str(&format!("priority={}\n", priority));
         }
+        identity
+    }
+
+    /// The scheduling class of the program (if it has one).
+    fn priority(&self) -> Option<Priority> {
+        self.priority
+            .as_ref()
+            .map(|p| Priority::new(p).expect("checked in the manifest"))
     }
 
     fn get_cmd(&self, antagonist: bool, cores: &Vec<&CpuInfo>) -> Vec<String> {
@@ -359,20 +429,102 @@ impl<'a> Program<'a> {
 
         // keep this one:
         for (k, v) in self.env.clone() {
-            env.push((k, v));
+            env.push((k, v.replace("$NUM_THREADS", &cores.len().to_string())));
         }
 
         env
     }
 }
 
-#[derive(RustcEncodable)]
+/// How many hex digits of the hash are a run ID.
+const RUN_ID_DIGITS: usize = 16;
+
+#[derive(Clone, RustcEncodable)]
 struct Run<'a> {
+    schema_version: u64,
+    /// Identifies the configuration of the run (see `run_id`).
+    run_id: String,
     manifest_path: &'a Path,
     output_path: PathBuf,
     a: &'a Program<'a>,
     b: Option<&'a Program<'a>>,
     deployment: &'a Deployment<'a>,
+    /// For how many seconds we record scheduler activity (0 = don't record).
+    sched: u64,
+    /// Monitor the LLC occupancy of A and B with resctrl.
+    resctrl: bool,
+    /// How many L3 ways A gets with CAT (B gets the rest).
+    cat_ways: Option<u64>,
+    /// Pack the output directory into a .tar.zst archive once the run is done.
+    compress: bool,
+    /// Compress the intervals of perf stat while it writes them.
+    compress_intervals: bool,
+    /// Where we keep the machine information files of all runs.
+    captures: PathBuf,
+    /// SQLite database we add the results of the run to.
+    store: Option<PathBuf>,
+    /// Invariants the counter values have to fulfill.
+    rules: &'a [Rule],
+    /// How often we repeat a run that violates `rules`.
+    reruns: u64,
+    /// Only count on these CPUs, other runs use the rest of the machine.
+    measure_cpus: Option<Vec<Cpu>>,
+    /// What counts the events.
+    backend: Backend,
+    /// Record memory bandwidth, UPI utilization and C-states with Intel PCM.
+    pcm: bool,
+    /// Record off-CPU time, run-queue latency and system calls of A with eBPF.
+    bpf: bool,
+    /// Count A only, we may not count system-wide (see `privilege::degrade`).
+    per_process: bool,
+    /// Events named by the kernel, measured on top of the events of our tables.
+    sysfs_events: &'a [SysfsEvent],
+    /// Events of our tables we don't measure (they failed the pre-flight check).
+    skip_events: &'a [String],
+    /// perf's metric groups measured on top of the events.
+    metric_groups: &'a [String],
+    /// Only measure the events of the preset of this name (see `presets.rs`).
+    preset: Option<&'static str>,
+    /// Bind the memory of A and B to these nodes (all of a memory tier).
+    membind: &'a [Node],
+    /// The memory policy of A and B (see `mempolicy.rs`).
+    memory_policy: Option<String>,
+    /// The DRAM nodes of the machine (for `preferred-remote`).
+    dram_nodes: &'a [Node],
+    /// The frequency (in MHz) all CPUs are fixed to (see `cpufreq.rs`).
+    frequency: Option<u64>,
+    /// What we reset before the run (see `reset.rs`).
+    reset: &'a Reset,
+}
+
+/// The ID of a run: a hash of the programs and everything we vary between runs
+/// (the configuration tuple). It's the same for the same manifest, in every
+/// campaign and on every machine.
+fn run_id(
+    a: &Program,
+    b: Option<&Program>,
+    deployment: &Deployment,
+    cat_ways: Option<u64>,
+    memory_policy: Option<&MemoryPolicy>,
+    frequency: Option<u64>,
+) -> String {
+    let mut hash = Sha256::new();
+    hash.update(format!("deployment={}\n[a]\n", deployment.description).as_bytes());
+    hash.update(a.identity(false).as_bytes());
+    if let Some(b) = b {
+        hash.update(b"[b]\n");
+        hash.update(b.identity(true).as_bytes());
+    }
+    if let Some(ways) = cat_ways {
+        hash.update(format!("cat_ways={}\n", ways).as_bytes());
+    }
+    if let Some(policy) = memory_policy {
+        hash.update(format!("memory_policy={}\n", policy).as_bytes());
+    }
+    if let Some(mhz) = frequency {
+        hash.update(format!("frequency_mhz={}\n", mhz).as_bytes());
+    }
+    hash.finish()[..RUN_ID_DIGITS].to_string()
 }
 
 impl<'a> Run<'a> {
@@ -382,26 +534,260 @@ impl<'a> Run<'a> {
         a: &'a Program<'a>,
         b: Option<&'a Program<'a>>,
         deployment: &'a Deployment,
+        sched: u64,
+        resctrl: bool,
+        cat_ways: Option<u64>,
+        compress: bool,
+        compress_intervals: bool,
+        store: bool,
+        rules: &'a [Rule],
+        reruns: u64,
+        backend: Backend,
+        pcm: bool,
+        bpf: bool,
+        per_process: bool,
+        sysfs_events: &'a [SysfsEvent],
+        skip_events: &'a [String],
+        metric_groups: &'a [String],
+        preset: Option<&'static Preset>,
+        membind: &'a [Node],
+        memory_policy: Option<&MemoryPolicy>,
+        dram_nodes: &'a [Node],
+        frequency: Option<u64>,
+        reset: &'a Reset,
     ) -> Run<'a> {
+        let mut captures = output_path.to_path_buf();
+        captures.push("captures");
+        let store = if store {
+            let mut store = output_path.to_path_buf();
+            store.push(store::STORE_FILE);
+            Some(store)
+        } else {
+            None
+        };
+
         let mut out_dir = output_path.to_path_buf();
         out_dir.push(deployment.description);
         mkdir(&out_dir);
-        match b {
-            Some(p) => out_dir.push(format!("{}_vs_{}", a.name, p.name)),
-            None => out_dir.push(a.name.as_str()),
+        let name = match b {
+            Some(p) => format!("{}_vs_{}", a.name, p.name),
+            None => a.name.clone(),
+        };
+        let name = match cat_ways {
+            Some(ways) => format!("{}_cat{}", name, ways),
+            None => name,
+        };
+        let name = match memory_policy {
+            Some(policy) => format!("{}_mem-{}", name, policy.dir_name()),
+            None => name,
+        };
+        let priority_dir = |p: &Program| {
+            p.priority()
+                .map_or(String::from("default"), |p| p.dir_name())
+        };
+        let name = match b {
+            Some(b) if a.priority.is_some() || b.priority.is_some() => {
+                format!("{}_prio-{}-{}", name, priority_dir(a), priority_dir(b))
+            }
+            None if a.priority.is_some() => format!("{}_prio-{}", name, priority_dir(a)),
+            _ => name,
+        };
+        match frequency {
+            Some(mhz) => out_dir.push(format!("{}_{}MHz", name, mhz)),
+            None => out_dir.push(name),
         }
 
         Run {
-            manifest_path: manifest_path,
+            schema_version: SCHEMA_VERSION,
+            run_id: run_id(a, b, deployment, cat_ways, memory_policy, frequency),
+            manifest_path,
             output_path: out_dir,
-            a: a,
-            b: b,
-            deployment: deployment,
+            a,
+            b,
+            deployment,
+            sched,
+            resctrl,
+            cat_ways,
+            compress,
+            compress_intervals,
+            captures,
+            store,
+            rules,
+            reruns,
+            measure_cpus: None,
+            backend,
+            pcm,
+            bpf,
+            per_process,
+            sysfs_events,
+            skip_events,
+            metric_groups,
+            preset: preset.map(|p| p.name),
+            membind,
+            memory_policy: memory_policy.map(|p| p.to_string()),
+            dram_nodes,
+            frequency,
+            reset,
         }
     }
 
-    fn profile_a(&self) -> io::Result<()> {
-        let cmd = self.a.get_cmd(false, &self.deployment.a);
+    /// `cmd` (of a program on `cpus`) with the memory policy of the run, or its
+    /// memory bound to `membind` (if there is one of them).
+    fn bind_memory(&self, cmd: Vec<String>, cpus: &[&CpuInfo]) -> Vec<String> {
+        let policy = match self.memory_policy {
+            Some(ref policy) => MemoryPolicy::new(policy).expect("checked in the manifest"),
+            None if self.membind.is_empty() => return cmd,
+            None => MemoryPolicy::Bind(self.membind.to_vec()),
+        };
+        let local = cpus.first().map_or(0, |c| c.node.node);
+        let args = policy
+            .numactl_args(local, self.dram_nodes)
+            .expect("checked in the manifest");
+        mempolicy::numactl(args, cmd)
+    }
+
+    /// Does the memory of the run go to nodes we name (see `bind_memory`)? They
+    /// don't move with the run to another socket, `local` and `preferred-remote` do.
+    fn binds_to_nodes(&self) -> bool {
+        let policy = self
+            .memory_policy
+            .as_ref()
+            .map(|p| MemoryPolicy::new(p).expect("checked in the manifest"));
+        match policy {
+            Some(MemoryPolicy::Interleave(nodes)) => !nodes.is_empty(),
+            Some(MemoryPolicy::Preferred(_)) | Some(MemoryPolicy::Bind(_)) => true,
+            Some(MemoryPolicy::Local) | Some(MemoryPolicy::PreferredRemote) => false,
+            None => !self.membind.is_empty(),
+        }
+    }
+
+    /// Can we run this side by side with other runs (on other sockets)? CAT,
+    /// perf sched, PCM and frequency setpoints affect or measure the whole machine,
+    /// and the resctrl groups of A and B are the same for every run. Memory on
+    /// nodes we name would be local to one socket and remote to the others.
+    fn is_parallelizable(&self) -> bool {
+        // Resetting the caches would disturb the runs next to us:
+        self.cat_ways.is_none()
+            && !self.resctrl
+            && self.sched == 0
+            && !self.pcm
+            && self.frequency.is_none()
+            && self.reset.is_empty()
+            && !self.binds_to_nodes()
+    }
+
+    /// The run in `deployment` instead (with the same programs and settings), or
+    /// its `repetition`, for the refinement of an adaptive campaign in `campaign`.
+    fn refined(
+        &self,
+        deployment: &'a Deployment<'a>,
+        repetition: Option<u64>,
+        campaign: &Path,
+    ) -> Run<'a> {
+        let policy = self
+            .memory_policy
+            .as_ref()
+            .map(|p| MemoryPolicy::new(p).expect("checked in the manifest"));
+        let mut run = self.clone();
+        run.run_id = run_id(
+            self.a,
+            self.b,
+            deployment,
+            self.cat_ways,
+            policy.as_ref(),
+            self.frequency,
+        );
+        let mut name = self
+            .output_path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        if let Some(k) = repetition {
+            run.run_id = format!("{}-r{}", run.run_id, k);
+            name = format!("{}_rep{}", name, k);
+        }
+        let mut out_dir = campaign.join(deployment.description);
+        mkdir(&out_dir);
+        out_dir.push(name);
+        run.output_path = out_dir;
+        run.deployment = deployment;
+        run.measure_cpus = None;
+        run
+    }
+
+    /// Moves the run to `deployment` and only measures `cpus`.
+    fn relocate(&mut self, deployment: &'a Deployment<'a>, cpus: Vec<Cpu>) {
+        self.deployment = deployment;
+        self.measure_cpus = Some(cpus);
+    }
+
+    /// Puts process `pid` (and everything it spawns from now on) in its own resctrl
+    /// group: a control group that limits its L3 ways if we do a CAT sweep, otherwise
+    /// a MON group. Without `pid` the group stays empty until someone assigns to it.
+    fn resctrl_group(&self, name: &str, pid: Option<u32>) -> Option<MonGroup> {
+        let group = match self.cat_ways {
+            Some(ways) => resctrl::split_l3_ways(ways).and_then(|(mask_a, mask_b)| {
+                let mask = if name == "A" { mask_a } else { mask_b };
+                MonGroup::with_l3_mask(name, mask)
+            }),
+            None if self.resctrl => MonGroup::create(name),
+            None => return None,
+        };
+
+        let group = match pid {
+            Some(pid) => group.and_then(|g| g.assign(pid).map(|_| g)),
+            None => group,
+        };
+        if let Err(ref e) = group {
+            error!("Can't monitor {} with resctrl: {}", name, e);
+        }
+        group.ok()
+    }
+
+    /// Starts recording the scheduler if we co-locate two programs.
+    fn start_sched(&self) -> Option<SchedRecorder> {
+        if self.sched == 0 || self.b.is_none() {
+            return None;
+        }
+
+        let mut data = self.output_path.clone();
+        data.push("sched.data");
+        match SchedRecorder::start(&data, self.sched) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                error!("Can't start perf sched record: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Stores the runtime shares and wait times of A and B in sched.csv.
+    fn finish_sched(&self, recorder: SchedRecorder) {
+        let data = match recorder.finish() {
+            Ok(data) => data,
+            Err(e) => {
+                error!("perf sched record failed: {}", e);
+                return;
+            }
+        };
+
+        let mut programs = vec![("A", self.a.binary.as_str())];
+        if let Some(b) = self.b {
+            programs.push(("B", b.binary.as_str()));
+        }
+        let mut sched_csv = self.output_path.clone();
+        sched_csv.push("sched.csv");
+        if let Err(e) = sched::summarize(&data, &programs, &sched_csv) {
+            error!("Can't summarize scheduler activity: {}", e);
+        }
+    }
+
+    fn profile_a(&self, resctrl_tasks: Option<PathBuf>) -> io::Result<()> {
+        let cmd = self.bind_memory(
+            self.a.get_cmd(false, &self.deployment.a),
+            &self.deployment.a,
+        );
         let env = self.a.get_env(false, &self.deployment.a);
         let mut bps: Vec<String> = self.a.breakpoints.iter().map(|s| s.to_string()).collect();
         // TODO: this is currently not in use (remove?)
@@ -419,7 +805,25 @@ impl<'a> Run<'a> {
             env,
             bps,
             false,
-            None,
+            self.preset
+                .and_then(presets::preset)
+                .map(|p| presets::events(p, self.skip_events)),
+            &profile::ProfileSettings {
+                capture_store: Some(self.captures.clone()),
+                compress_intervals: self.compress_intervals,
+                cpus: self.measure_cpus.clone(),
+                backend: self.backend,
+                pcm: self.pcm,
+                bpf: self.bpf,
+                per_process: self.per_process,
+                sysfs_events: self.sysfs_events.to_vec(),
+                skip_events: self.skip_events.to_vec(),
+                metric_groups: self.metric_groups.to_vec(),
+                priority: self.a.priority(),
+                output: self.a.output(),
+                resctrl_tasks,
+                ..Default::default()
+            },
             false,
         );
         Ok(())
@@ -434,10 +838,21 @@ impl<'a> Run<'a> {
                 command_args.insert(0, String::from("-n0"));
                 command_args.insert(0, String::from("watch"));
             }
+            let command_args = self.bind_memory(command_args, &self.deployment.b);
 
             debug!("Spawning {:?} with environment {:?}", command_args, env);
             debug!("Working dir for B is: {}", b.working_dir.as_str());
 
+            let mut workload_file = self.output_path.clone();
+            workload_file.push("B_workload.toml");
+            let r = Workload::new(&command_args, b.working_dir.as_str(), &env).save(&workload_file);
+            if let Err(e) = r {
+                error!(
+                    "Can't save workload information to {:?}: {}",
+                    workload_file, e
+                );
+            }
+
             let mut cmd = Command::new(&command_args[0]);
             let cmd = cmd
                 .stdout(Stdio::piped())
@@ -457,15 +872,6 @@ impl<'a> Run<'a> {
         })
     }
 
-    fn save_output<T: io::Read>(&self, filename: &str, what: &mut T) -> io::Result<()> {
-        let mut stdout = String::new();
-        what.read_to_string(&mut stdout)?;
-        let mut stdout_path = self.output_path.clone();
-        stdout_path.push(filename);
-        let mut f = File::create(stdout_path.as_path())?;
-        f.write_all(stdout.as_bytes())
-    }
-
     fn save_run_information(&self) -> io::Result<()> {
         let mut run_toml_path = self.output_path.clone();
         run_toml_path.push("run.toml");
@@ -480,19 +886,137 @@ impl<'a> Run<'a> {
         f.write_all(format!("{}", self).as_bytes())
     }
 
+    /// The run in the campaign's index (`out_dir`), its results are in the
+    /// archive once there is one.
+    fn index_entry(&self, out_dir: &Path, status: index::Status) -> index::Entry {
+        let archive = archive::archive_path(&self.output_path);
+        let results = if archive.exists() {
+            archive
+        } else {
+            self.output_path.clone()
+        };
+        index::Entry {
+            run_id: self.run_id.clone(),
+            path: results
+                .strip_prefix(out_dir)
+                .unwrap_or(&results)
+                .to_path_buf(),
+            status,
+            metadata: self.placement(),
+            updated: 0,
+        }
+    }
+
     fn is_completed(&self) -> bool {
         // Is this run already done (in case we restart):
         let mut completed_file: PathBuf = self.output_path.to_path_buf();
         completed_file.push("completed");
-        if completed_file.exists() {
-            true
-        } else {
-            false
+        completed_file.exists() || archive::archive_path(&self.output_path).exists()
+    }
+
+    /// Aggregates the results of the run into the campaign's database (it keeps
+    /// the earlier results of the run if we can't aggregate them).
+    fn add_to_store(&self, store_path: &Path) -> io::Result<()> {
+        // Runs side by side take turns:
+        let _store_lock = STORE_LOCK.lock().unwrap();
+        let store = Store::open(store_path)?;
+        let mut software_csv = self.output_path.clone();
+        software_csv.push("software_events.csv");
+        let mut software_wrtr = csv::Writer::from_file(&software_csv).unwrap();
+        software_wrtr.encode(SOFTWARE_COLUMNS).unwrap();
+
+        let mut writer = store.add_run(
+            &self.output_path,
+            &Configuration {
+                a: &self.a.name,
+                b: self.b.map(|b| b.name.as_str()),
+                deployment: self.deployment.description,
+                cat_ways: self.cat_ways,
+                memory_policy: self.memory_policy.as_deref(),
+                frequency_mhz: self.frequency,
+                priority_a: self.a.priority.as_deref(),
+                priority_b: self.b.and_then(|b| b.priority.as_deref()),
+            },
+        )?;
+        let mut metrics_wrtr = writer.perf_metrics();
+        let aggregated = aggregate_into(
+            &self.output_path,
+            "all",
+            "all",
+            &mut writer,
+            &mut software_wrtr,
+            &mut metrics_wrtr,
+        );
+        if aggregated.is_err() {
+            writer.rollback();
+            return aggregated;
         }
+        writer.finish();
+        software_wrtr.finish();
+        Ok(())
+    }
+
+    /// Moves the results of a run that violated the rules to `rejected/<attempt>`
+    /// (the symlinks to the captures are recreated by the next attempt).
+    fn reject(&self, attempt: u64) -> io::Result<()> {
+        let mut rejected = self.output_path.clone();
+        rejected.push("rejected");
+        rejected.push(attempt.to_string());
+        fs::create_dir_all(&rejected)?;
+        for entry in fs::read_dir(&self.output_path)? {
+            let entry = entry?;
+            if entry.file_name() == "rejected" {
+                continue;
+            }
+            if entry.file_type()?.is_symlink() {
+                fs::remove_file(entry.path())?;
+            } else {
+                let mut to = rejected.clone();
+                to.push(entry.file_name());
+                fs::rename(entry.path(), to)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the programs are placed (as key, value) for the tags of streamed samples.
+    fn placement(&self) -> Vec<(String, String)> {
+        let mut placement = vec![
+            (
+                String::from("configuration"),
+                String::from(self.deployment.description),
+            ),
+            (String::from("a"), self.a.name.clone()),
+            (String::from("run_id"), self.run_id.clone()),
+        ];
+        if let Some(b) = self.b {
+            placement.push((String::from("b"), b.name.clone()));
+        }
+        if let Some(ref dataset) = self.a.dataset {
+            placement.push((String::from("dataset_a"), dataset.clone()));
+        }
+        if let Some(dataset) = self.b.and_then(|b| b.dataset.as_ref()) {
+            placement.push((String::from("dataset_b"), dataset.clone()));
+        }
+        if let Some(ways) = self.cat_ways {
+            placement.push((String::from("cat_ways"), ways.to_string()));
+        }
+        if let Some(ref policy) = self.memory_policy {
+            placement.push((String::from("memory_policy"), policy.clone()));
+        }
+        if let Some(mhz) = self.frequency {
+            placement.push((String::from("frequency_mhz"), mhz.to_string()));
+        }
+        if let Some(ref priority) = self.a.priority {
+            placement.push((String::from("priority_a"), priority.clone()));
+        }
+        if let Some(priority) = self.b.and_then(|b| b.priority.as_ref()) {
+            placement.push((String::from("priority_b"), priority.clone()));
+        }
+        placement
     }
 
     fn profile(&mut self) -> io::Result<()> {
-        mkdir(&self.output_path);
         if self.is_completed() {
             warn!(
                 "Run {} already completed, skipping.",
@@ -501,79 +1025,210 @@ impl<'a> Run<'a> {
             return Ok(());
         }
 
+        let mut attempt = 0;
+        loop {
+            if !self.measure()? {
+                return Ok(());
+            }
+            if self.rules.is_empty() {
+                break;
+            }
+            let violations = rules::check_run("", &self.output_path, self.rules);
+            if !violations.is_empty() && attempt < self.reruns {
+                attempt += 1;
+                warn!(
+                    "Run {} violates {} rules, repeating it (attempt {} of {}).",
+                    self.output_path.to_string_lossy(),
+                    violations.len(),
+                    attempt,
+                    self.reruns
+                );
+                self.reject(attempt)?;
+                continue;
+            }
+            let mut validation = self.output_path.clone();
+            validation.push("validation");
+            validate::save_report(&validation, 1, violations)?;
+            break;
+        }
+
+        if let Some(ref store_path) = self.store {
+            if let Err(e) = self.add_to_store(store_path) {
+                error!("Can't add run to result store {:?}: {}", store_path, e);
+            }
+        }
+        if self.compress {
+            archive::compress_dir(&self.output_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the measurements, returns false if the run had to be skipped.
+    fn measure(&mut self) -> io::Result<bool> {
+        mkdir(&self.output_path);
+
         self.save_run_information()?;
 
+        // B runs at the setpoint too, the CPUs get their limits back when we return:
+        let _frequency = match self.frequency.map(FrequencyLock::fix) {
+            Some(Ok(lock)) => Some(lock),
+            Some(Err(e)) => {
+                // Without the setpoint these results are meaningless:
+                error!(
+                    "Can't fix the frequency to {} MHz ({}), skipping run.",
+                    self.frequency.unwrap_or(0),
+                    e
+                );
+                return Ok(false);
+            }
+            None => None,
+        };
+
+        if let Err(e) = self.reset.apply() {
+            // The run would start in whatever state the last one left behind:
+            error!("Can't reset the machine ({}), skipping run.", e);
+            return Ok(false);
+        }
+
         // Profile together with B
-        let maybe_app_b: Option<Child> = self.start_b();
+        let mut maybe_app_b: Option<Child> = self.start_b();
+        let b_priority = self.b.and_then(|b| b.priority());
+        if let (Some(app_b), Some(priority)) = (maybe_app_b.as_mut(), b_priority) {
+            if let Err(e) = priority.apply(app_b.id()) {
+                // Without the priority these results are meaningless:
+                error!("Can't give B priority {} ({}), skipping run.", priority, e);
+                app_b.kill()?;
+                app_b.wait()?;
+                return Ok(false);
+            }
+        }
+        // Saved while B runs, a full pipe would stop it:
+        let b_capture = match (maybe_app_b.as_mut(), self.b) {
+            (Some(app_b), Some(b)) => Some(Capture::start(
+                app_b,
+                &self.output_path.join("B_stdout.txt"),
+                &self.output_path.join("B_stderr.txt"),
+                &b.output(),
+            )),
+            _ => None,
+        };
+        let mut mon_groups: Vec<MonGroup> = Vec::with_capacity(2);
+        if let Some(group) = maybe_app_b
+            .as_ref()
+            .and_then(|b| self.resctrl_group("B", Some(b.id())))
+        {
+            mon_groups.push(group);
+        }
         if maybe_app_b.is_some() {
             debug!("Wait for B to warmup before starting to profile A");
             let one_min = Duration::from_millis(60000);
             thread::sleep(one_min);
         }
 
-        self.profile_a()?;
-
-        match maybe_app_b {
-            Some(mut app_b) => {
-                match app_b.wait_timeout(Duration::from_millis(200)).unwrap() {
-                    Some(status) => {
-                        // The Application B has already exited, this means it probably crashed
-                        // while we were profiling (bad). We can't use these results.
-                        app_b
-                            .stdout
-                            .map(|mut c| self.save_output("B_stdout.txt", &mut c));
-                        app_b
-                            .stderr
-                            .map(|mut c| self.save_output("B_stderr.txt", &mut c));
-
-                        let mut completed_path = self.output_path.clone();
-                        completed_path.push("completed");
-                        fs::remove_file(completed_path)?;
-
-                        panic!(
-                            "B has crashed during measurements {:?}. This is bad.",
-                            status.code()
-                        );
-                        // TODO: save error code and continue (?)
-                    }
-                    None => {
-                        app_b.kill()?;
-                        app_b.wait()?;
-                        app_b
-                            .stdout
-                            .map(|mut c| self.save_output("B_stdout.txt", &mut c));
-                        app_b
-                            .stderr
-                            .map(|mut c| self.save_output("B_stderr.txt", &mut c));
-                    }
+        // A doesn't run yet, perf moves it into the group once it forked it:
+        let mut a_tasks = None;
+        if let Some(group) = self.resctrl_group("A", None) {
+            a_tasks = Some(group.tasks());
+            mon_groups.push(group);
+        }
+        if let Some(ways) = self.cat_ways {
+            if mon_groups.len() != 1 + self.b.map_or(0, |_| 1) {
+                // Without the L3 partitioning these results are meaningless:
+                error!("Can't restrict programs to {} L3 ways, skipping run.", ways);
+                if let Some(mut app_b) = maybe_app_b {
+                    app_b.kill()?;
+                    app_b.wait()?;
                 }
+                return Ok(false);
             }
-            None => {}
+        }
+        // Without monitoring we still hold on to the (CAT) groups until A is done:
+        let (monitor, _cat_groups) = if self.resctrl && !mon_groups.is_empty() {
+            (Some(ResctrlMonitor::start(mon_groups)), Vec::new())
+        } else {
+            (None, mon_groups)
         };
 
-        Ok(())
+        let sched_recorder = self.start_sched();
+        self.profile_a(a_tasks)?;
+        if let Some(recorder) = sched_recorder {
+            self.finish_sched(recorder);
+        }
+        if let Some(monitor) = monitor {
+            let mut resctrl_csv = self.output_path.clone();
+            resctrl_csv.push("resctrl.csv");
+            if let Err(e) = monitor.finish(&resctrl_csv) {
+                error!("Can't save resctrl measurements: {}", e);
+            }
+        }
+
+        if let Some(mut app_b) = maybe_app_b {
+            match app_b.wait_timeout(Duration::from_millis(200)).unwrap() {
+                Some(status) => {
+                    // The Application B has already exited, this means it probably crashed
+                    // while we were profiling (bad). We can't use these results.
+                    b_capture.map(|c| c.finish());
+
+                    let mut completed_path = self.output_path.clone();
+                    completed_path.push("completed");
+                    fs::remove_file(completed_path)?;
+
+                    panic!(
+                        "B has crashed during measurements {:?}. This is bad.",
+                        status.code()
+                    );
+                    // TODO: save error code and continue (?)
+                }
+                None => {
+                    app_b.kill()?;
+                    app_b.wait()?;
+                    b_capture.map(|c| c.finish());
+                }
+            }
+        };
+
+        Ok(true)
     }
 }
 
 impl<'a> fmt::Display for Run<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
+        writeln!(
             f,
-            "A: ENV = {:?} CMD = {:?}\n",
+            "A: ENV = {:?} CMD = {:?}",
             self.a.get_env(false, &self.deployment.a),
             self.a.get_cmd(false, &self.deployment.a)
         )?;
-        write!(f, "A Breakpoints: {:?}\n", self.a.breakpoints)?;
-        write!(f, "A Checkpoints: {:?}\n", self.a.checkpoints)?;
+        writeln!(f, "A Breakpoints: {:?}", self.a.breakpoints)?;
+        writeln!(f, "A Checkpoints: {:?}", self.a.checkpoints)?;
+        if let Some(ref dataset) = self.a.dataset {
+            writeln!(f, "A dataset: {}", dataset)?;
+        }
+        if let Some(ways) = self.cat_ways {
+            writeln!(f, "A L3 ways (CAT): {}", ways)?;
+        }
+        if let Some(ref policy) = self.memory_policy {
+            writeln!(f, "Memory policy of A and B: {}", policy)?;
+        }
+        if let Some(mhz) = self.frequency {
+            writeln!(f, "Frequency of all CPUs: {} MHz", mhz)?;
+        }
+        if let Some(ref priority) = self.a.priority {
+            writeln!(f, "A priority: {}", priority)?;
+        }
+        if let Some(priority) = self.b.and_then(|b| b.priority.as_ref()) {
+            writeln!(f, "B priority: {}", priority)?;
+        }
         match self.b {
             Some(b) => {
-                write!(
+                writeln!(
                     f,
-                    "B: {:?} {:?}\n",
+                    "B: {:?} {:?}",
                     b.get_env(true, &self.deployment.b),
                     b.get_cmd(true, &self.deployment.b)
                 )?;
-                write!(f, "{}", &self.deployment)?;
+                write!(f, "{}", self.deployment)?;
             }
             None => {
                 write!(f, "No other program running.")?;
@@ -583,16 +1238,244 @@ impl<'a> fmt::Display for Run<'a> {
     }
 }
 
-pub fn pair(manifest_folder: &Path, dryrun: bool, start: usize, stepping: usize) {
-    let canonical_manifest_path =
-        fs::canonicalize(&manifest_folder).expect("canonicalize manifest path does not work");
+/// Profiles `run` and keeps track of the progress (and of the run in the index).
+fn run_one(
+    run: &mut Run,
+    out_dir: &Path,
+    progress: &Mutex<CampaignProgress>,
+    index: &Mutex<CampaignIndex>,
+) {
+    let name = run
+        .output_path
+        .strip_prefix(out_dir)
+        .unwrap_or(&run.output_path)
+        .to_string_lossy()
+        .into_owned();
+    let _configuration = info_span!(
+        "configuration",
+        run_id = run.run_id.as_str(),
+        deployment = run.deployment.description,
+        a = run.a.name.as_str(),
+        b = run.b.map(|b| b.name.as_str()),
+        cat_ways = run.cat_ways,
+        memory_policy = run.memory_policy.as_deref(),
+        frequency_mhz = run.frequency,
+        priority_a = run.a.priority.as_deref(),
+        priority_b = run.b.and_then(|b| b.priority.as_ref()).map(|p| p.as_str())
+    )
+    .entered();
+    let _run = logging::run_span(&run.output_path).entered();
+    watch::placement(run.placement());
+    progress.lock().unwrap().start_run(&name);
+    index
+        .lock()
+        .unwrap()
+        .record(run.index_entry(out_dir, index::Status::Started));
+    let error = match run.profile() {
+        Ok(()) => false,
+        Err(e) => {
+            error!("Run {} failed: {}", name, e);
+            true
+        }
+    };
+    let outcome = summary::run_ended(error);
+    let status = match outcome {
+        Outcome::Failed => index::Status::Failed,
+        _ => index::Status::Completed,
+    };
+    index
+        .lock()
+        .unwrap()
+        .record(run.index_entry(out_dir, status));
+    progress.lock().unwrap().finish_run(Some(&name), outcome);
+}
 
-    let mut out_dir = canonical_manifest_path.to_path_buf();
-    let hostname = get_hostname().unwrap_or(String::from("unknown"));
-    out_dir.push(hostname);
-    mkdir(&out_dir);
+/// Where the runs of a campaign go and what keeps track of them.
+#[derive(Clone, Copy)]
+struct Books<'b> {
+    out_dir: &'b Path,
+    progress: &'b Mutex<CampaignProgress>,
+    index: &'b Mutex<CampaignIndex>,
+}
+
+/// Profiles the runs in `batch` at the same time, each on its own socket (a
+/// single run stays where it is).
+fn run_batch<'a>(
+    batch: &mut Vec<&mut Run<'a>>,
+    socket_deployments: &'a [(Socket, Vec<Option<Deployment<'a>>>)],
+    mt: &MachineTopology,
+    books: Books,
+    cooler: &mut Option<Cooler>,
+) {
+    let Books {
+        out_dir,
+        progress,
+        index,
+    } = books;
+    if batch.is_empty() {
+        return;
+    }
+    if let Some(cooler) = cooler.as_mut() {
+        cooler.wait();
+    }
+    if batch.len() == 1 {
+        run_one(batch.pop().unwrap(), out_dir, progress, index);
+        return;
+    }
+
+    for (run, &(socket, ref on_socket)) in batch.iter_mut().zip(socket_deployments.iter()) {
+        let deployment = on_socket
+            .iter()
+            .filter_map(|d| d.as_ref())
+            .find(|d| d.description == run.deployment.description)
+            .expect("run doesn't fit on socket");
+        let cpus = mt.cpus_on_socket(socket).iter().map(|c| c.cpu).collect();
+        run.relocate(deployment, cpus);
+    }
+    info!("Running {} runs side by side.", batch.len());
+    // The runs log in the campaign span, even on their own threads:
+    let campaign = Span::current();
+    thread::scope(|s| {
+        for run in batch.drain(..) {
+            let campaign = campaign.clone();
+            s.spawn(move || campaign.in_scope(|| run_one(run, out_dir, progress, index)));
+        }
+    });
+}
+
+/// Resolves the `events` of the manifest against the PMUs of this machine (see
+/// `pmu.rs`), we don't start a campaign with events we can't count.
+fn resolve_events(events: &[toml::Value], backend: Backend) -> Vec<SysfsEvent> {
+    let pmus = match pmu::read_pmus(Path::new(pmu::DEVICES)) {
+        Ok(pmus) => pmus,
+        Err(e) => {
+            error!("Can't read the PMUs in {}: {}", pmu::DEVICES, e);
+            summary::fail(Failure::Environment);
+        }
+    };
+    let mut resolved = Vec::with_capacity(events.len());
+    let mut unknown = 0;
+    for event in events {
+        let event = event.as_str().expect("'events' elements should be strings");
+        match pmu::resolve(event, &pmus) {
+            Ok(event) => {
+                debug!("Event {} is {:?}", event.name, event.perf_events);
+                resolved.push(event);
+            }
+            Err(e) => {
+                error!("{}", e);
+                unknown += 1;
+            }
+        }
+    }
+    if unknown > 0 {
+        error!(
+            "{} of the manifest's events are not available on this machine.",
+            unknown
+        );
+        summary::fail(Failure::Environment);
+    }
+    if backend != Backend::Perf && !resolved.is_empty() {
+        warn!("'events' are only measured with perf, not {}.", backend);
+    }
+    resolved
+}
+
+/// Stops the campaign if an event can't be counted on a core type of a hybrid
+/// CPU that A is deployed on (see `pmu::check_core_types`): perf would count it
+/// on some of A's CPUs and say nothing about the others.
+fn check_core_types<'a, I: Iterator<Item = &'a Deployment<'a>>>(
+    events: &[SysfsEvent],
+    deployments: I,
+) {
+    let pmus = match pmu::read_pmus(Path::new(pmu::DEVICES)) {
+        Ok(pmus) => pmus,
+        Err(e) => {
+            debug!("Can't read the PMUs in {}: {}", pmu::DEVICES, e);
+            return;
+        }
+    };
+    let mut uncountable = 0;
+    for d in deployments {
+        let cpus: Vec<Cpu> = d.a.iter().map(|c| c.cpu).collect();
+        for event in events {
+            if let Err(e) = pmu::check_core_types(event, &pmus, &cpus) {
+                error!("{}: {}", d.description, e);
+                uncountable += 1;
+            }
+        }
+    }
+    if uncountable > 0 {
+        error!("Leave these events out, or place A on the core types that have them.");
+        summary::fail(Failure::Environment);
+    }
+}
+
+/// Deployment `d` with its memory on `nodes` (if there are any, the nodes of the
+/// `memory_tier`).
+fn with_memory<'a>(d: Option<Deployment<'a>>, nodes: &[NodeInfo]) -> Option<Deployment<'a>> {
+    d.map(|mut d| {
+        if !nodes.is_empty() {
+            d.mem = nodes.to_vec();
+        }
+        d
+    })
+}
 
-    let mt = MachineTopology::new();
+/// The directory of a campaign below `--output-root`: the `name` of the
+/// experiment, or the name of the manifest's directory.
+fn campaign_name(manifest_dir: &Path, doc: &toml::Table) -> String {
+    let name = doc
+        .get("experiment")
+        .and_then(|e| e.lookup("name"))
+        .map(|n| {
+            n.as_str()
+                .expect("Error in manifest.toml: 'experiment.name' should be a string.")
+                .to_string()
+        });
+    name.unwrap_or_else(|| {
+        manifest_dir
+            .file_name()
+            .map_or(String::from("campaign"), |n| {
+                n.to_string_lossy().into_owned()
+            })
+    })
+}
+
+/// How `pair` runs the campaign of a manifest.
+pub struct PairSettings<'a> {
+    /// Write the campaign to <output_root>/<experiment name>/<hostname> instead of
+    /// next to the manifest.
+    pub output_root: Option<&'a Path>,
+    /// What we do with the results of an earlier campaign.
+    pub on_existing: Collision,
+    pub dryrun: bool,
+    /// Only run every `stepping`-th configuration, beginning with `start`.
+    pub start: usize,
+    pub stepping: usize,
+    pub backend: Backend,
+    /// Measure the events that pass the pre-flight check instead of stopping.
+    pub skip_invalid: bool,
+    /// Measure the events that errata of this CPU make useless as well.
+    pub allow_errata: bool,
+    /// Another machine's topology, to plan a campaign for it (dry runs only).
+    pub topology: Option<MachineTopology>,
+}
+
+pub fn pair(manifest_folder: &Path, settings: PairSettings) {
+    let PairSettings {
+        output_root,
+        on_existing,
+        dryrun,
+        start,
+        stepping,
+        backend,
+        skip_invalid,
+        allow_errata,
+        topology,
+    } = settings;
+    let canonical_manifest_path =
+        fs::canonicalize(&manifest_folder).expect("canonicalize manifest path does not work");
 
     let mut manifest: PathBuf = canonical_manifest_path.to_path_buf();
     manifest.push("manifest.toml");
@@ -604,9 +1487,29 @@ pub fn pair(manifest_folder: &Path, dryrun: bool, start: usize, stepping: usize)
         Some(doc) => doc,
         None => {
             error!("Can't parse the manifest file:\n{:?}", parser.errors);
-            process::exit(1);
+            summary::fail(Failure::Error);
         }
     };
+
+    // Next to the manifest, or in a directory of its own below --output-root:
+    let mut out_dir = match output_root {
+        Some(root) => root.join(campaign_name(&canonical_manifest_path, &doc)),
+        None => canonical_manifest_path.to_path_buf(),
+    };
+    let hostname = get_hostname().unwrap_or(String::from("unknown"));
+    out_dir.push(hostname);
+    // Held until the campaign is done, runs of another `pair` would interleave with ours:
+    let (out_dir, _lock) = lock::claim(&out_dir, on_existing);
+    let _campaign = info_span!("campaign", directory = %out_dir.display()).entered();
+
+    let mt = topology.unwrap_or_default();
+
+    // What the campaign was run with, for `diff-config` (the manifest may change later):
+    if !dryrun {
+        if let Err(e) = fs::copy(&manifest, out_dir.join("manifest.toml")) {
+            warn!("Can't save a copy of the manifest in {:?}: {}", out_dir, e);
+        }
+    }
     let experiment: &toml::Table = doc["experiment"]
         .as_table()
         .expect("Error in manifest.toml: 'experiment' should be a table.");
@@ -623,7 +1526,113 @@ pub fn pair(manifest_folder: &Path, dryrun: bool, start: usize, stepping: usize)
         .collect();
     let run_alone: bool = experiment
         .get("alone")
-        .map_or(true, |v| v.as_bool().expect("'alone' should be boolean"));
+        .is_none_or(|v| v.as_bool().expect("'alone' should be boolean"));
+    let sched: u64 = experiment.get("sched").map_or(0, |v| {
+        v.as_integer()
+            .expect("'sched' should be an integer (seconds)") as u64
+    });
+    let resctrl: bool = experiment
+        .get("resctrl")
+        .is_some_and(|v| v.as_bool().expect("'resctrl' should be boolean"));
+    let compress: bool = experiment
+        .get("compress")
+        .is_some_and(|v| v.as_bool().expect("'compress' should be boolean"));
+    let compress_intervals: bool = experiment
+        .get("compress_intervals")
+        .is_some_and(|v| v.as_bool().expect("'compress_intervals' should be boolean"));
+    let store: bool = experiment
+        .get("store")
+        .is_some_and(|v| v.as_bool().expect("'store' should be boolean"));
+    let rules: Vec<Rule> = experiment.get("rules").map_or(Vec::new(), |v| {
+        let mut rules_file = canonical_manifest_path.to_path_buf();
+        rules_file.push(v.as_str().expect("'rules' should be a file name"));
+        match rules::load(&rules_file) {
+            Ok(rules) => rules,
+            Err(e) => {
+                error!("{}", e);
+                summary::fail(Failure::Error);
+            }
+        }
+    });
+    let reruns: u64 = experiment.get("reruns").map_or(0, |v| {
+        v.as_integer().expect("'reruns' should be an integer") as u64
+    });
+    let parallel: bool = experiment
+        .get("parallel")
+        .is_some_and(|v| v.as_bool().expect("'parallel' should be boolean"));
+    let pcm: bool = experiment
+        .get("pcm")
+        .is_some_and(|v| v.as_bool().expect("'pcm' should be boolean"));
+    let bpf: bool = experiment
+        .get("bpf")
+        .is_some_and(|v| v.as_bool().expect("'bpf' should be boolean"));
+    if bpf && !cfg!(feature = "bpf") {
+        error!("'bpf' needs eBPF support, rebuild autoperf with `--features bpf`.");
+        summary::fail(Failure::Environment);
+    }
+    if let Some(names) = experiment.get("collectors") {
+        let names: Vec<String> = names
+            .as_slice()
+            .and_then(|names| names.iter().map(|n| n.as_str().map(String::from)).collect())
+            .expect("'collectors' should be a list of strings");
+        if let Err(e) = collectors::select(&names) {
+            error!("Error in manifest.toml: {}", e);
+            summary::fail(Failure::Error);
+        }
+    }
+    let sysfs_events: Vec<SysfsEvent> = experiment.get("events").map_or(Vec::new(), |events| {
+        let events = events
+            .as_slice()
+            .expect("Error in manifest.toml: 'events' should be a list.");
+        resolve_events(events, backend)
+    });
+    let preset: Option<&'static Preset> = experiment.get("preset").map(|v| {
+        let name = v.as_str().expect("'preset' should be a string");
+        match presets::preset(name) {
+            Some(preset) => preset,
+            None => {
+                error!(
+                    "Unknown preset '{}' in manifest.toml (should be one of {}).",
+                    name,
+                    presets::PRESETS
+                        .iter()
+                        .map(|p| p.name)
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                );
+                summary::fail(Failure::Error);
+            }
+        }
+    });
+    let metric_groups: Vec<String> = experiment
+        .get("metric_groups")
+        .map_or(Vec::new(), |groups| {
+            groups
+                .as_slice()
+                .expect("Error in manifest.toml: 'metric_groups' should be a list.")
+                .iter()
+                .map(|g| {
+                    g.as_str()
+                        .expect("metric_groups elements should name perf metric groups (strings)")
+                        .to_string()
+                })
+                .collect()
+        });
+    if !metric_groups.is_empty() && backend != Backend::Perf {
+        warn!("'metric_groups' are only measured with the perf backend.");
+    }
+    let cat_ways: Vec<Option<u64>> = experiment.get("cat_ways").map_or(vec![None], |ways| {
+        ways.as_slice()
+            .expect("Error in manifest.toml: 'cat_ways' should be a list.")
+            .iter()
+            .map(|w| {
+                Some(
+                    w.as_integer()
+                        .expect("cat_ways elements should be integers") as u64,
+                )
+            })
+            .collect()
+    });
     let profile_only: Option<Vec<String>> = experiment.get("profile_only_a").map(|progs| {
         progs
             .as_slice()
@@ -649,68 +1658,270 @@ pub fn pair(manifest_folder: &Path, dryrun: bool, start: usize, stepping: usize)
             .collect()
     });
 
+    // Suites and thread sweeps can make more than one program of a table:
+    let mut program_tables: Vec<toml::Table> = Vec::with_capacity(2);
     let mut programs: Vec<Program> = Vec::with_capacity(2);
     for (key, _value) in &doc {
         if key.starts_with("program") {
             let program_desc: &toml::Table = doc[key]
                 .as_table()
                 .expect("Error in manifest.toml: 'program' should be a table.");
-            programs.push(Program::from_toml(
-                &canonical_manifest_path,
-                program_desc,
-                run_alone,
-            ));
+            match suites::expand(&canonical_manifest_path, program_desc, &mt) {
+                Ok(expanded) => program_tables.extend(expanded),
+                Err(e) => {
+                    error!("Error in manifest.toml ({}): {}", key, e);
+                    summary::fail(Failure::Error);
+                }
+            }
         }
     }
+    for program_desc in program_tables.iter() {
+        programs.push(Program::from_toml(
+            &canonical_manifest_path,
+            program_desc,
+            run_alone,
+        ));
+    }
 
-    let mut deployments: Vec<Deployment> = Vec::with_capacity(4);
-    for config in configs {
-        match config.as_str() {
-            "L1-SMT" => deployments.push(Deployment::split_interleaved(
-                "L1-SMT",
-                mt.same_l1(),
-                mt.l1_size().unwrap_or(0),
-            )),
-            "L3-SMT" => deployments.push(Deployment::split_interleaved(
-                "L3-SMT",
-                mt.same_l3(),
-                mt.l3_size().unwrap_or(0),
-            )),
-            "L3-SMT-cores" => deployments.push(Deployment::split_smt_aware(
-                "L3-SMT-cores",
-                mt.same_l3(),
-                mt.l3_size().unwrap_or(0),
-            )),
-            "L3-cores" => deployments.push(Deployment::split_smt_aware(
-                "L3-cores",
-                mt.same_l3_cores(),
-                mt.l3_size().unwrap_or(0),
-            )),
-            "Full-L3" => deployments.push(Deployment::split_l3_aware(
-                "Full-L3",
-                mt.whole_machine_cores(),
-                mt.l3_size().unwrap_or(0),
-            )),
-            "Full-SMT-L3" => deployments.push(Deployment::split_l3_aware(
-                "Full-SMT-L3",
-                mt.whole_machine(),
-                mt.l3_size().unwrap_or(0),
-            )),
-            "Full-cores" => deployments.push(Deployment::split_interleaved(
-                "Full-cores",
-                mt.whole_machine_cores(),
-                mt.l3_size().unwrap_or(0),
-            )),
-            "Full-SMT-cores" => deployments.push(Deployment::split_smt_aware(
-                "Full-SMT-cores",
-                mt.whole_machine(),
-                mt.l3_size().unwrap_or(0),
-            )),
+    if backend != Backend::Perf && programs.iter().any(|p| p.priority.is_some()) {
+        warn!(
+            "With the {} backend only B runs with its 'priority'.",
+            backend
+        );
+    }
 
-            _ => error!("Ignored unknown deployment config '{}'.", config),
+    // Better now than after hours of measuring:
+    let mut checked = profile::ProfileSettings {
+        sysfs_events,
+        pcm,
+        bpf,
+        ..Default::default()
+    };
+    if !dryrun {
+        privilege::degrade(&mut checked);
+    }
+    let (pcm, bpf) = (checked.pcm, checked.bpf);
+    if let Some(preset) = preset {
+        presets::add_sysfs_events(preset, &mut checked);
+    }
+    if backend == Backend::Perf {
+        errata::exclude(&mut checked, allow_errata);
+        if !dryrun {
+            preflight::validate(&mut checked, skip_invalid);
+        }
+        pmu::prefer_free_running(&mut checked);
+    }
+    if let Some(preset) = preset {
+        // Stops us if the CPU has none of its events:
+        let _ = presets::events(preset, &checked.skip_events);
+    }
+
+    let memory_tier: Option<MemoryTier> = experiment.get("memory_tier").map(|v| {
+        let tier = v
+            .as_str()
+            .expect("'memory_tier' should be a string (dram, pmem or cxl)");
+        match MemoryTier::new(tier) {
+            Some(tier) => tier,
+            None => {
+                error!(
+                    "Unknown memory_tier '{}' in manifest.toml (should be one of {}).",
+                    tier,
+                    memtier::TIERS.join(", ")
+                );
+                summary::fail(Failure::Error);
+            }
+        }
+    });
+    let memory_nodes: Vec<NodeInfo> = memory_tier.map_or(Vec::new(), |tier| {
+        let nodes = mt.nodes_of_tier(tier);
+        if nodes.is_empty() {
+            error!(
+                "This machine has no {} memory (see memory_tiers.csv).",
+                tier
+            );
+            summary::fail(Failure::Environment);
+        }
+        nodes
+    });
+    let membind: Vec<Node> = memory_nodes.iter().map(|n| n.node).collect();
+    let dram_nodes: Vec<Node> = mt
+        .nodes_of_tier(MemoryTier::Dram)
+        .iter()
+        .map(|n| n.node)
+        .collect();
+    let memory_policies: Vec<Option<MemoryPolicy>> =
+        experiment.get("memory_policies").map_or(vec![None], |policies| {
+            policies
+                .as_slice()
+                .expect("Error in manifest.toml: 'memory_policies' should be a list.")
+                .iter()
+                .map(|p| {
+                    let spec = p
+                        .as_str()
+                        .expect("memory_policies elements should be strings");
+                    match MemoryPolicy::new(spec) {
+                        Ok(MemoryPolicy::PreferredRemote) if dram_nodes.len() < 2 => {
+                            error!(
+                                "Memory policy preferred-remote needs a machine with more than one DRAM node."
+                            );
+                            summary::fail(Failure::Environment);
+                        }
+                        Ok(policy) => {
+                            let memoryless = policy.nodes().into_iter().find(|n| {
+                                mt.memory_nodes().iter().all(|m| m.node != *n)
+                            });
+                            if let Some(node) = memoryless {
+                                error!(
+                                    "Memory policy {} needs memory on node {}, which has none.",
+                                    policy, node
+                                );
+                                summary::fail(Failure::Environment);
+                            }
+                            Some(policy)
+                        }
+                        Err(e) => {
+                            error!("Error in manifest.toml: {}", e);
+                            summary::fail(Failure::Error);
+                        }
+                    }
+                })
+                .collect()
+        });
+    if memory_tier.is_some() && memory_policies.iter().any(|p| p.is_some()) {
+        error!("Error in manifest.toml: 'memory_tier' and 'memory_policies' can't be combined (bind:<nodes> binds to nodes).");
+        summary::fail(Failure::Error);
+    }
+
+    let frequencies: Vec<Option<u64>> =
+        experiment
+            .get("frequencies")
+            .map_or(vec![None], |f| match cpufreq::parse_frequencies(f) {
+                Ok(setpoints) => setpoints.into_iter().map(Some).collect(),
+                Err(e) => {
+                    error!("Error in manifest.toml: {}", e);
+                    summary::fail(Failure::Error);
+                }
+            });
+    if frequencies.iter().any(|f| f.is_some()) && !dryrun {
+        let (lowest, highest) = match cpufreq::limits() {
+            Ok(limits) => limits,
+            Err(e) => {
+                error!("Can't sweep frequencies: {}", e);
+                summary::fail(Failure::Environment);
+            }
         };
+        if let Some(mhz) = frequencies
+            .iter()
+            .filter_map(|f| *f)
+            .find(|mhz| *mhz < lowest || *mhz > highest)
+        {
+            error!(
+                "This machine can't run at {} MHz (only at {} to {} MHz).",
+                mhz, lowest, highest
+            );
+            summary::fail(Failure::Environment);
+        }
+    }
+
+    let reset: Reset =
+        experiment
+            .get("reset")
+            .map_or(Default::default(), |r| match Reset::parse(r) {
+                Ok(reset) => reset,
+                Err(e) => {
+                    error!("Error in manifest.toml: {}", e);
+                    summary::fail(Failure::Error);
+                }
+            });
+    if !dryrun {
+        if let Err(e) = reset.check_scratch() {
+            error!("Error in manifest.toml: {}", e);
+            summary::fail(Failure::Error);
+        }
+    }
+    let mut cooler: Option<Cooler> = experiment
+        .get("cooldown")
+        .map(|c| match CoolDown::parse(c) {
+            Ok(policy) => Cooler::new(policy),
+            Err(e) => {
+                error!("Error in manifest.toml: {}", e);
+                summary::fail(Failure::Error);
+            }
+        });
+
+    let sampling: Option<Sampling> = experiment.get("sample").map(|s| match Sampling::parse(s) {
+        Ok(sampling) => sampling,
+        Err(e) => {
+            error!("Error in manifest.toml: {}", e);
+            summary::fail(Failure::Error);
+        }
+    });
+
+    let adaptive: Option<Adaptive> = experiment
+        .get("adaptive")
+        .map(|a| match Adaptive::parse(a) {
+            Ok(adaptive) => adaptive,
+            Err(e) => {
+                error!("Error in manifest.toml: {}", e);
+                summary::fail(Failure::Error);
+            }
+        });
+    let mut refine_deployments: Vec<Deployment> = Vec::new();
+    for config in adaptive.iter().flat_map(|a| a.configurations.iter()) {
+        if configs.contains(config) {
+            error!(
+                "Error in manifest.toml: '{}' of 'adaptive' is already in 'configurations'.",
+                config
+            );
+            summary::fail(Failure::Error);
+        }
+        match with_memory(deployment(config, &mt, None), &memory_nodes) {
+            Some(d) => refine_deployments.push(d),
+            None => error!(
+                "Ignored deployment config '{}' of 'adaptive' (unknown, or this machine has nothing to place it by).",
+                config
+            ),
+        }
+    }
+
+    let mut deployments: Vec<Deployment> = Vec::with_capacity(4);
+    for config in configs.iter() {
+        match with_memory(deployment(config, &mt, None), &memory_nodes) {
+            Some(d) => deployments.push(d),
+            None => error!(
+                "Ignored deployment config '{}' (unknown, or this machine has nothing to place it by).",
+                config
+            ),
+        }
+    }
+
+    // On a hybrid CPU the core types of a deployment may not have all events:
+    if !checked.sysfs_events.is_empty() {
+        check_core_types(
+            &checked.sysfs_events,
+            deployments.iter().chain(refine_deployments.iter()),
+        );
     }
 
+    // The same deployments on every socket (the socket of the serial runs first),
+    // to run experiments side by side:
+    let mut sockets = mt.sockets();
+    sockets.reverse();
+    let socket_deployments: Vec<(Socket, Vec<Option<Deployment>>)> = if parallel {
+        sockets
+            .iter()
+            .map(|s| {
+                let on_socket = deployments
+                    .iter()
+                    .map(|d| with_memory(deployment(d.description, &mt, Some(*s)), &memory_nodes))
+                    .collect();
+                (*s, on_socket)
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
     // Add all possible pairs:
     let mut pairs: Vec<(&Program, Option<&Program>)> = Vec::new();
     for p in programs.iter() {
@@ -720,8 +1931,11 @@ pub fn pair(manifest_folder: &Path, dryrun: bool, start: usize, stepping: usize)
         pairs.push((a, Some(b)));
     }
 
-    // Filter out the pairs we do not want to execute:
+    // Filter out the pairs we do not want to execute (and remember which level
+    // of every factor a run has, for fractional samples):
+    let level = |p: &Program| programs.iter().position(|q| std::ptr::eq(p, q)).unwrap();
     let mut runs: Vec<Run> = Vec::new();
+    let mut levels: Vec<Vec<usize>> = Vec::new();
     for (a, b) in pairs.into_iter() {
         let profile_a = profile_only
             .as_ref()
@@ -734,30 +1948,372 @@ pub fn pair(manifest_folder: &Path, dryrun: bool, start: usize, stepping: usize)
             continue;
         }
 
-        for d in deployments.iter() {
+        for (d_level, d) in deployments.iter().enumerate() {
             if b.is_none() && (!run_alone || !a.alone) {
                 continue;
             }
-            runs.push(Run::new(
-                &canonical_manifest_path,
-                out_dir.as_path(),
-                a,
-                b,
-                d,
-            ));
+            for ((w_level, ways), (p_level, policy), (f_level, frequency)) in iproduct!(
+                cat_ways.iter().enumerate(),
+                memory_policies.iter().enumerate(),
+                frequencies.iter().enumerate()
+            ) {
+                levels.push(vec![
+                    level(a),
+                    b.map_or(0, |b| level(b) + 1),
+                    d_level,
+                    w_level,
+                    p_level,
+                    f_level,
+                ]);
+                runs.push(Run::new(
+                    &canonical_manifest_path,
+                    out_dir.as_path(),
+                    a,
+                    b,
+                    d,
+                    sched,
+                    resctrl,
+                    *ways,
+                    compress,
+                    compress_intervals,
+                    store,
+                    &rules,
+                    reruns,
+                    backend,
+                    pcm,
+                    bpf,
+                    checked.per_process,
+                    &checked.sysfs_events,
+                    &checked.skip_events,
+                    &metric_groups,
+                    preset,
+                    &membind,
+                    policy.as_ref(),
+                    &dram_nodes,
+                    *frequency,
+                    &reset,
+                ));
+            }
         }
     }
 
-    // Finally, profile the runs we are supposed to execute based on the command line args
-    let mut i = 0;
-    for run in runs.iter_mut().skip(start).step(stepping) {
+    // A run the manifest has more than once (a program listed twice etc.) is
+    // measured once:
+    let mut seen: BTreeSet<String> = BTreeSet::new();
+    let (mut runs, levels): (Vec<Run>, Vec<Vec<usize>>) = runs
+        .into_iter()
+        .zip(levels)
+        .filter(|(run, _)| {
+            let first = seen.insert(run.run_id.clone());
+            if !first {
+                warn!(
+                    "The manifest has run {} ({:?}) more than once, it's only measured once.",
+                    run.run_id, run.output_path
+                );
+            }
+            first
+        })
+        .unzip();
+
+    // Runs that would share a directory (programs with the same name, settings
+    // that aren't in the name) get their ID in it, wherever they are in the list:
+    let mut per_dir: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+    for run in runs.iter() {
+        per_dir
+            .entry(run.output_path.clone())
+            .or_default()
+            .push(run.run_id.clone());
+    }
+    for run in runs.iter_mut() {
+        let ids = &per_dir[&run.output_path];
+        if ids.len() < 2 {
+            continue;
+        }
+        let name = format!(
+            "{}_{}",
+            run.output_path.file_name().unwrap().to_string_lossy(),
+            run.run_id
+        );
+        run.output_path.set_file_name(name);
+    }
+
+    // Runs of earlier invocations, or of an autoperf that didn't keep an index:
+    let index = Mutex::new(CampaignIndex::open(&out_dir));
+    if !dryrun {
+        let mut index = index.lock().unwrap();
+        let done: Vec<&Run> = runs
+            .iter()
+            .filter(|r| !index.contains(&r.run_id) && r.is_completed())
+            .collect();
+        if !done.is_empty() {
+            debug!("Adding {} completed runs to the index", done.len());
+            for run in done {
+                index.insert(run.index_entry(&out_dir, index::Status::Completed));
+            }
+            index.save_or_log();
+        }
+    }
+
+    // Only the runs the sample picks (if there is one):
+    if let Some(ref sampling) = sampling {
+        let cells: Vec<Cell> = runs
+            .iter()
+            .zip(levels)
+            .map(|(run, levels)| Cell {
+                run_id: run.run_id.clone(),
+                path: run
+                    .output_path
+                    .strip_prefix(&out_dir)
+                    .unwrap_or(&run.output_path)
+                    .to_path_buf(),
+                stratum: format!(
+                    "{}/{}",
+                    run.deployment.description,
+                    if run.b.is_none() { "alone" } else { "pair" }
+                ),
+                levels,
+            })
+            .collect();
+        let mut picked = sampling::read_picked(&out_dir);
+        let selection = sampling.select(&cells, &picked);
+        let label = sampling.label();
+        for (cell, selected) in cells.iter().zip(selection.iter()) {
+            if *selected {
+                picked.entry(cell.run_id.clone()).or_insert(label.clone());
+            }
+        }
+        info!(
+            "The sample ({}) picks {} of {} runs ({} picked by any sample so far).",
+            label,
+            selection.iter().filter(|s| **s).count(),
+            cells.len(),
+            cells
+                .iter()
+                .filter(|c| picked.contains_key(&c.run_id))
+                .count()
+        );
         if !dryrun {
-            run.profile().ok();
+            if let Err(e) = sampling::save(&out_dir, &cells, &picked) {
+                error!("Can't save the sample in {:?}: {}", out_dir, e);
+            }
+        }
+        let mut selection = selection.into_iter();
+        runs.retain(|_| selection.next().unwrap());
+    }
+
+    // Finally, profile the runs we are supposed to execute based on the command line args
+    let chosen: Vec<usize> = (0..runs.len()).skip(start).step(stepping).collect();
+    let mut planned = chosen.len();
+    summary::planned(planned);
+    let progress = Mutex::new(CampaignProgress::new(&out_dir, planned));
+    let selected: Vec<&mut Run> = runs.iter_mut().skip(start).step(stepping).collect();
+    let books = Books {
+        out_dir: &out_dir,
+        progress: &progress,
+        index: &index,
+    };
+    let mut i = schedule(
+        selected,
+        dryrun,
+        &socket_deployments,
+        &mt,
+        books,
+        &mut cooler,
+    );
+
+    // More of the runs the coarse pass found noisy or surprising:
+    if let Some(ref adaptive) = adaptive {
+        if dryrun {
+            warn!("Dryrun mode: what 'adaptive' adds is decided after the runs.");
         } else {
-            warn!("Dryrun mode: {}", run);
+            let (mut refinements, added) = refine(
+                adaptive,
+                &runs,
+                &chosen,
+                &deployments,
+                &refine_deployments,
+                &out_dir,
+            );
+            if let Err(e) = adaptive::save(&out_dir, &added) {
+                error!("Can't save the refinement in {:?}: {}", out_dir, e);
+            }
+            info!(
+                "The coarse pass has {} noisy or surprising runs, adding {} runs.",
+                added
+                    .iter()
+                    .map(|a| &a.run_id)
+                    .collect::<BTreeSet<_>>()
+                    .len(),
+                refinements.len()
+            );
+            {
+                let mut index = index.lock().unwrap();
+                for run in refinements.iter().filter(|r| r.is_completed()) {
+                    index.insert(run.index_entry(&out_dir, index::Status::Completed));
+                }
+                index.save_or_log();
+            }
+            planned += refinements.len();
+            summary::planned(planned);
+            progress.lock().unwrap().add_runs(refinements.len());
+            i += schedule(
+                refinements.iter_mut().collect(),
+                dryrun,
+                &socket_deployments,
+                &mt,
+                books,
+                &mut cooler,
+            );
         }
-        i += 1;
     }
 
     info!("{} runs completed.", i);
 }
+
+/// The runs an adaptive campaign adds to the `chosen` runs of the coarse pass
+/// (see `adaptive.rs`), and why.
+fn refine<'a>(
+    adaptive: &Adaptive,
+    runs: &[Run<'a>],
+    chosen: &[usize],
+    deployments: &'a [Deployment<'a>],
+    refine_deployments: &'a [Deployment<'a>],
+    out_dir: &Path,
+) -> (Vec<Run<'a>>, Vec<adaptive::Added>) {
+    let tmp = interference::tmp_directory();
+    let results = |run: &Run| {
+        let archive = archive::archive_path(&run.output_path);
+        if archive.exists() {
+            archive
+        } else {
+            run.output_path.clone()
+        }
+    };
+    let relative = |path: &Path| path.strip_prefix(out_dir).unwrap_or(path).to_path_buf();
+    let configuration = |run: &Run| {
+        (
+            run.a.name.clone(),
+            run.deployment.description,
+            run.cat_ways,
+            run.memory_policy.clone(),
+            run.frequency,
+        )
+    };
+    let solos: BTreeMap<_, &Run> = runs
+        .iter()
+        .filter(|r| r.b.is_none() && r.is_completed())
+        .map(|r| (configuration(r), r))
+        .collect();
+
+    let done: Vec<&Run> = chosen
+        .iter()
+        .map(|i| &runs[*i])
+        .filter(|r| r.is_completed())
+        .collect();
+    let mut solo_measured: BTreeMap<String, adaptive::Measured> = BTreeMap::new();
+    let mut cells: Vec<adaptive::Cell> = Vec::with_capacity(done.len());
+    for run in done.iter() {
+        let solo = match solos.get(&configuration(run)) {
+            Some(solo) if run.b.is_some() => Some(
+                *solo_measured
+                    .entry(solo.run_id.clone())
+                    .or_insert_with(|| adaptive::measure(&results(solo), &tmp)),
+            ),
+            _ => None,
+        };
+        cells.push(adaptive::Cell {
+            configuration: String::from(run.deployment.description),
+            solo,
+            measured: adaptive::measure(&results(run), &tmp),
+        });
+    }
+
+    let mut refinements: Vec<Run> = Vec::new();
+    let mut added: Vec<adaptive::Added> = Vec::new();
+    for (run, reason) in done.iter().zip(adaptive.judge(&cells)) {
+        let reason = match reason {
+            Some(reason) => reason,
+            None => continue,
+        };
+        debug!("{} is {} ({})", run, reason.as_str(), reason.value());
+        let deployment = deployments
+            .iter()
+            .find(|d| d.description == run.deployment.description)
+            .expect("run of an unknown deployment");
+        let mut more: Vec<Run> = (1..=adaptive.repetitions)
+            .map(|k| run.refined(deployment, Some(k), out_dir))
+            .collect();
+        if let (Reason::Surprise(_), Some(_)) = (reason, run.b) {
+            more.extend(
+                refine_deployments
+                    .iter()
+                    .map(|d| run.refined(d, None, out_dir)),
+            );
+        }
+        for refinement in more {
+            added.push(adaptive::Added {
+                run_id: run.run_id.clone(),
+                path: relative(&run.output_path),
+                reason,
+                added_run_id: refinement.run_id.clone(),
+                added_path: relative(&refinement.output_path),
+            });
+            refinements.push(refinement);
+        }
+    }
+    (refinements, added)
+}
+
+/// Profiles `selected` (side by side if they fit on every socket), returns how
+/// many runs there were.
+fn schedule<'a>(
+    selected: Vec<&mut Run<'a>>,
+    dryrun: bool,
+    socket_deployments: &'a [(Socket, Vec<Option<Deployment<'a>>>)],
+    mt: &MachineTopology,
+    books: Books,
+    cooler: &mut Option<Cooler>,
+) -> usize {
+    let mut i = 0;
+    let mut batch: Vec<&mut Run> = Vec::new();
+    for run in selected.into_iter() {
+        i += 1;
+        if dryrun {
+            warn!("Dryrun mode: {}", run);
+            continue;
+        }
+        if run.is_completed() {
+            summary::run_skipped();
+            books
+                .progress
+                .lock()
+                .unwrap()
+                .finish_run(None, Outcome::Skipped);
+            continue;
+        }
+        // Runs go side by side if every socket has their deployment:
+        let fits = socket_deployments.len() > 1
+            && run.is_parallelizable()
+            && socket_deployments.iter().all(|(_, on_socket)| {
+                on_socket
+                    .iter()
+                    .filter_map(|d| d.as_ref())
+                    .any(|d| d.description == run.deployment.description)
+            });
+        if !fits {
+            run_batch(&mut batch, socket_deployments, mt, books, cooler);
+            if let Some(cooler) = cooler.as_mut() {
+                cooler.wait();
+            }
+            run_one(run, books.out_dir, books.progress, books.index);
+            continue;
+        }
+        batch.push(run);
+        if batch.len() == socket_deployments.len() {
+            run_batch(&mut batch, socket_deployments, mt, books, cooler);
+        }
+    }
+    run_batch(&mut batch, socket_deployments, mt, books, cooler);
+
+    i
+}
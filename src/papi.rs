@@ -0,0 +1,587 @@
+//! Counts with PAPI instead of perf (`--backend papi`, needs `cargo build --features
+//! papi` and libpapi), for platforms where PAPI is the sanctioned interface to the
+//! counters (POWER, some HPC systems). The manifests of `pair` campaigns stay the
+//! same, only the backend changes.
+//!
+//! autoperf's event names are mapped to PAPI events: the generic perf events to
+//! PAPI presets (`instructions` is `PAPI_TOT_INS`, `cycles` is `PAPI_TOT_CYC`...),
+//! the Intel event names to PAPI's native events (`INST_RETIRED.ANY` is
+//! `INST_RETIRED:ANY`), names PAPI knows as they are (`PAPI_L2_TCM`,
+//! `perf::PAGE-FAULTS`) are passed through. Events PAPI doesn't have on the
+//! machine are left out. We put as many events in an event set as the hardware
+//! counts at once and run the program once per event set.
+//!
+//! PAPI counts the program (and the threads and processes it starts) rather than
+//! CPUs, so we write the values of every interval like `perf stat --per-thread`
+//! writes them (with the program as the only thread) and under autoperf's names of
+//! the events, so `aggregate` and everything after it treats the results like the
+//! ones of perf.
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::os::raw::{c_int, c_longlong, c_ulong, c_void};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use lazy_static::lazy_static;
+use tracing::{debug, error, warn};
+use x86::perfcnt::intel::events;
+
+use super::housekeeping;
+use super::phases::{PhaseListener, PHASE_MARKER_ENV};
+use super::profile::{
+    create_out_directory, perf_log_writer, save_machine_information, ProfileSettings, Scope,
+    SOFTWARE_EVENTS,
+};
+use super::summary::{self, Failure};
+use super::util::*;
+use super::watch::{self, INTERVAL};
+use super::workload::Workload;
+
+#[allow(non_snake_case)]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_longlong, c_ulong, c_void};
+
+    pub const PAPI_OK: c_int = 0;
+    pub const PAPI_EINVAL: c_int = -1;
+    pub const PAPI_NULL: c_int = -1;
+    /// `PAPI_set_opt` option to count the threads and processes of the program too.
+    pub const PAPI_INHERIT: c_int = 28;
+    pub const PAPI_INHERIT_ALL: c_int = 1;
+
+    /// The inherit member of `PAPI_option_t` (the only one we pass).
+    #[repr(C)]
+    pub struct PAPI_inherit_option_t {
+        pub eventset: c_int,
+        pub inherit: c_int,
+    }
+
+    #[link(name = "papi")]
+    extern "C" {
+        pub fn PAPI_library_init(version: c_int) -> c_int;
+        pub fn PAPI_is_initialized() -> c_int;
+        pub fn PAPI_strerror(code: c_int) -> *mut c_char;
+        pub fn PAPI_event_name_to_code(name: *const c_char, code: *mut c_int) -> c_int;
+        pub fn PAPI_create_eventset(event_set: *mut c_int) -> c_int;
+        pub fn PAPI_add_event(event_set: c_int, code: c_int) -> c_int;
+        pub fn PAPI_set_opt(option: c_int, ptr: *mut c_void) -> c_int;
+        pub fn PAPI_attach(event_set: c_int, tid: c_ulong) -> c_int;
+        pub fn PAPI_start(event_set: c_int) -> c_int;
+        pub fn PAPI_read(event_set: c_int, values: *mut c_longlong) -> c_int;
+        pub fn PAPI_stop(event_set: c_int, values: *mut c_longlong) -> c_int;
+        pub fn PAPI_cleanup_eventset(event_set: c_int) -> c_int;
+        pub fn PAPI_destroy_eventset(event_set: *mut c_int) -> c_int;
+    }
+}
+
+/// Generic perf events and the PAPI presets that count the same.
+const PRESETS: [(&'static str, &'static str); 11] = [
+    ("instructions", "PAPI_TOT_INS"),
+    ("cycles", "PAPI_TOT_CYC"),
+    ("ref-cycles", "PAPI_REF_CYC"),
+    ("branches", "PAPI_BR_INS"),
+    ("branch-misses", "PAPI_BR_MSP"),
+    ("cache-references", "PAPI_L3_TCA"),
+    ("cache-misses", "PAPI_L3_TCM"),
+    ("L1-dcache-load-misses", "PAPI_L1_DCM"),
+    ("L1-icache-load-misses", "PAPI_L1_ICM"),
+    ("dTLB-load-misses", "PAPI_TLB_DM"),
+    ("iTLB-load-misses", "PAPI_TLB_IM"),
+];
+
+/// The software events (see `SOFTWARE_EVENTS`) in PAPI's perf_event component.
+const SOFTWARE_PRESETS: [(&'static str, &'static str); 3] = [
+    ("cs", "perf::CONTEXT-SWITCHES"),
+    ("migrations", "perf::CPU-MIGRATIONS"),
+    ("page-faults", "perf::PAGE-FAULTS"),
+];
+
+/// perf's comm is at most 15 characters.
+const COMM_LENGTH: usize = 15;
+
+fn strerror(code: c_int) -> String {
+    let msg = unsafe { ffi::PAPI_strerror(code) };
+    if msg.is_null() {
+        format!("PAPI error {}", code)
+    } else {
+        unsafe { CStr::from_ptr(msg) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+fn check(what: &str, code: c_int) -> Result<(), String> {
+    if code == ffi::PAPI_OK {
+        Ok(())
+    } else {
+        Err(format!("{} failed: {}", what, strerror(code)))
+    }
+}
+
+lazy_static! {
+    // libpapi can only be initialized once per process:
+    static ref INITIALIZED: Result<(), String> = init();
+}
+
+/// Initializes libpapi.
+fn init() -> Result<(), String> {
+    if unsafe { ffi::PAPI_is_initialized() } != 0 {
+        return Ok(());
+    }
+    // PAPI_VER_CURRENT is a macro of papi.h, PAPI_library_init wants the major
+    // and minor version of the library we link. We try the ones that exist:
+    for major in (5..8).rev() {
+        for minor in (0..10).rev() {
+            let version: c_int = (major << 24) | (minor << 16);
+            let r = unsafe { ffi::PAPI_library_init(version) };
+            if r > 0 {
+                debug!("Initialized PAPI {}.{}", major, minor);
+                return Ok(());
+            }
+            if r != ffi::PAPI_EINVAL {
+                return Err(format!("Can't initialize PAPI: {}", strerror(r)));
+            }
+        }
+    }
+    Err(String::from(
+        "Can't initialize PAPI: unsupported version of libpapi",
+    ))
+}
+
+pub fn check_for_papi() {
+    if let Err(ref e) = *INITIALIZED {
+        error!("{} (is libpapi installed and `papi_avail` working?)", e);
+        summary::fail(Failure::Environment);
+    }
+}
+
+/// The PAPI event for an autoperf event name (if PAPI has it on this machine).
+fn papi_event(name: &str) -> Option<c_int> {
+    let preset = PRESETS
+        .iter()
+        .chain(SOFTWARE_PRESETS.iter())
+        .find(|&&(generic, _)| generic == name)
+        .map(|&(_, preset)| preset);
+    let candidates: Vec<String> = match preset {
+        Some(preset) => vec![String::from(preset)],
+        None if name.starts_with("PAPI_") || name.contains("::") => vec![String::from(name)],
+        // Intel's EVENT.UMASK is EVENT:UMASK for libpfm4, which PAPI uses:
+        None => vec![name.replacen('.', ":", 1), String::from(name)],
+    };
+    candidates.iter().find_map(|candidate| {
+        let c_name = CString::new(candidate.as_str()).ok()?;
+        let mut code: c_int = 0;
+        let r = unsafe { ffi::PAPI_event_name_to_code(c_name.as_ptr(), &mut code) };
+        if r == ffi::PAPI_OK {
+            Some(code)
+        } else {
+            None
+        }
+    })
+}
+
+struct EventSet {
+    handle: c_int,
+    /// (autoperf name, PAPI event)
+    events: Vec<(String, c_int)>,
+}
+
+impl EventSet {
+    fn new() -> Result<EventSet, String> {
+        let mut handle = ffi::PAPI_NULL;
+        check("PAPI_create_eventset", unsafe {
+            ffi::PAPI_create_eventset(&mut handle)
+        })?;
+        Ok(EventSet {
+            handle: handle,
+            events: Vec::new(),
+        })
+    }
+
+    /// Adds the event if the hardware can count it together with the others.
+    fn add(&mut self, name: &str, code: c_int) -> bool {
+        let r = unsafe { ffi::PAPI_add_event(self.handle, code) };
+        if r == ffi::PAPI_OK {
+            self.events.push((String::from(name), code));
+        }
+        r == ffi::PAPI_OK
+    }
+
+    /// Counts `pid` (and everything it starts) from now on.
+    fn start(&mut self, pid: u32) -> Result<(), String> {
+        let mut inherit = ffi::PAPI_inherit_option_t {
+            eventset: self.handle,
+            inherit: ffi::PAPI_INHERIT_ALL,
+        };
+        check("PAPI_set_opt(PAPI_INHERIT)", unsafe {
+            ffi::PAPI_set_opt(
+                ffi::PAPI_INHERIT,
+                &mut inherit as *mut ffi::PAPI_inherit_option_t as *mut c_void,
+            )
+        })?;
+        check("PAPI_attach", unsafe {
+            ffi::PAPI_attach(self.handle, pid as c_ulong)
+        })?;
+        check("PAPI_start", unsafe { ffi::PAPI_start(self.handle) })
+    }
+
+    fn read(&mut self, values: &mut Vec<c_longlong>) -> Result<(), String> {
+        values.resize(self.events.len(), 0);
+        check("PAPI_read", unsafe {
+            ffi::PAPI_read(self.handle, values.as_mut_ptr())
+        })
+    }
+
+    fn stop(&mut self, values: &mut Vec<c_longlong>) -> Result<(), String> {
+        values.resize(self.events.len(), 0);
+        check("PAPI_stop", unsafe {
+            ffi::PAPI_stop(self.handle, values.as_mut_ptr())
+        })
+    }
+}
+
+impl Drop for EventSet {
+    fn drop(&mut self) {
+        let mut values = Vec::new();
+        let _ = self.stop(&mut values);
+        unsafe {
+            ffi::PAPI_cleanup_eventset(self.handle);
+            ffi::PAPI_destroy_eventset(&mut self.handle);
+        }
+    }
+}
+
+/// Puts the events into as few event sets as possible. The software events go in
+/// every set (they don't occupy any counters), events PAPI can't count are left
+/// out.
+fn schedule_events(names: &[String]) -> Result<Vec<Vec<(String, c_int)>>, String> {
+    let software: Vec<(&str, c_int)> = SOFTWARE_EVENTS
+        .iter()
+        .filter_map(|name| papi_event(name).map(|code| (*name, code)))
+        .collect();
+    let new_set = || -> Result<EventSet, String> {
+        let mut set = EventSet::new()?;
+        for &(name, code) in software.iter() {
+            set.add(name, code);
+        }
+        Ok(set)
+    };
+
+    let mut groups = Vec::new();
+    let mut unknown: Vec<&str> = Vec::new();
+    let mut current = new_set()?;
+    let mut in_current = 0;
+    for name in names.iter() {
+        let code = match papi_event(name) {
+            Some(code) => code,
+            None => {
+                unknown.push(name);
+                continue;
+            }
+        };
+        if current.add(name, code) {
+            in_current += 1;
+            continue;
+        }
+        if in_current == 0 {
+            // Doesn't even work on its own:
+            unknown.push(name);
+            continue;
+        }
+        groups.push(current.events.clone());
+        current = new_set()?;
+        in_current = 0;
+        if current.add(name, code) {
+            in_current += 1;
+        } else {
+            unknown.push(name);
+        }
+    }
+    if in_current > 0 {
+        groups.push(current.events.clone());
+    }
+
+    if unknown.len() > 0 {
+        warn!(
+            "PAPI can't count {} of {} events on this machine, leaving them out.",
+            unknown.len(),
+            names.len()
+        );
+        debug!("Events PAPI doesn't have: {:?}", unknown);
+    }
+    Ok(groups)
+}
+
+/// The events we count if the caller doesn't ask for specific ones: the generic
+/// events and (on Intel machines) the ones we know for this CPU.
+fn default_events() -> Vec<String> {
+    let mut names: Vec<String> = PRESETS.iter().map(|&(n, _)| String::from(n)).collect();
+    if let Some(known) = events() {
+        names.extend(known.values().map(|e| String::from(e.event_name)));
+    }
+    names
+}
+
+fn read_all<R: Read + Send + 'static>(reader: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut out = String::new();
+        if let Some(mut reader) = reader {
+            let mut buf = Vec::new();
+            let _ = reader.read_to_end(&mut buf);
+            out = String::from_utf8_lossy(&buf).into_owned();
+        }
+        out
+    })
+}
+
+/// Starts the program stopped, so we can attach to it before it does anything.
+fn spawn_stopped(command: &mut Command) -> io::Result<Child> {
+    unsafe {
+        command.pre_exec(|| {
+            libc::raise(libc::SIGSTOP);
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+    let mut status: c_int = 0;
+    let r = unsafe { libc::waitpid(child.id() as libc::pid_t, &mut status, libc::WUNTRACED) };
+    if r < 0 || !libc::WIFSTOPPED(status) {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "the program didn't start",
+        ));
+    }
+    Ok(child)
+}
+
+/// Runs the program once and writes the counter values of every interval to
+/// `stat_file`. Returns stdout and stderr of the program.
+fn measure(
+    command: &mut Command,
+    set: &mut EventSet,
+    comm: &str,
+    stat_file: &Path,
+) -> Result<(String, String), String> {
+    let file = File::create(stat_file).map_err(|e| format!("{:?}: {}", stat_file, e))?;
+    let mut wtr = BufWriter::new(file);
+    let mut child = spawn_stopped(command).map_err(|e| format!("Can't start program: {}", e))?;
+    let pid = child.id();
+    if let Err(e) = set.start(pid) {
+        let _ = child.kill();
+        let _ = unsafe { libc::kill(pid as libc::pid_t, libc::SIGCONT) };
+        let _ = child.wait();
+        return Err(e);
+    }
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+    let started = Instant::now();
+    unsafe { libc::kill(pid as libc::pid_t, libc::SIGCONT) };
+
+    let location = format!("{}-{}", comm, pid);
+    let mut last: Vec<c_longlong> = vec![0; set.events.len()];
+    let mut values: Vec<c_longlong> = Vec::new();
+    let mut intervals = 1;
+    loop {
+        let next = Duration::from_secs_f64(INTERVAL * intervals as f64);
+        thread::sleep(next.checked_sub(started.elapsed()).unwrap_or_default());
+        let done = match child.try_wait() {
+            Ok(Some(status)) => {
+                if !status.success() {
+                    error!("{:?} exited with {}", command, status);
+                    summary::record(Failure::Workload);
+                }
+                true
+            }
+            Ok(None) => false,
+            Err(e) => return Err(format!("Can't wait for program: {}", e)),
+        };
+        if done {
+            set.stop(&mut values)?;
+        } else {
+            set.read(&mut values)?;
+        }
+
+        // Like perf -I, every interval has the counts since the last one:
+        let time = started.elapsed().as_secs_f64();
+        for (idx, &(ref name, _)) in set.events.iter().enumerate() {
+            let delta = values[idx].saturating_sub(last[idx]).max(0);
+            let r = writeln!(wtr, "{:.9};{};{};;{};0;100.00", time, location, delta, name);
+            r.map_err(|e| format!("Can't write {:?}: {}", stat_file, e))?;
+        }
+        let _ = wtr.flush();
+        last.clone_from(&values);
+        intervals += 1;
+        if done {
+            break;
+        }
+    }
+
+    Ok((
+        stdout.join().unwrap_or_default(),
+        stderr.join().unwrap_or_default(),
+    ))
+}
+
+/// Measures `cmd` with PAPI once per event set, writing the same files as
+/// `profile::profile` does for perf (`perf.csv`, `<nr>_stat.csv`...).
+pub fn profile(
+    output_path: &Path,
+    cmd_working_dir: &str,
+    cmd: Vec<String>,
+    env: Vec<(String, String)>,
+    events: Option<Vec<String>>,
+    settings: &ProfileSettings,
+    dryrun: bool,
+) {
+    assert!(cmd.len() >= 1);
+    let mut completed_file: PathBuf = output_path.to_path_buf();
+    completed_file.push("completed");
+    if completed_file.exists() {
+        warn!(
+            "Run {} already completed, skipping.",
+            output_path.to_string_lossy()
+        );
+        return;
+    }
+    if settings.measure_after.is_some()
+        || settings.measure_for.is_some()
+        || settings.turbostat
+        || settings.pcm
+    {
+        warn!("--measure-after, --measure-for, --turbostat and --pcm are not supported with PAPI.");
+    }
+    if settings.scope != Scope::Cpu && settings.scope != Scope::Thread {
+        warn!("PAPI counts the program, not CPUs, ignoring --scope.");
+    }
+
+    check_for_papi();
+    let names = events.unwrap_or_else(default_events);
+    let groups = match schedule_events(&names) {
+        Ok(groups) => groups,
+        Err(e) => {
+            error!("{}", e);
+            summary::fail(Failure::Environment);
+        }
+    };
+
+    create_out_directory(output_path);
+    if !dryrun {
+        save_machine_information(output_path, settings.capture_store.as_ref());
+        let mut workload_file = output_path.to_path_buf();
+        workload_file.push("workload.toml");
+        let r = Workload::new(&cmd, cmd_working_dir, &env).save(&workload_file);
+        if let Err(e) = r {
+            error!(
+                "Can't save workload information to {:?}: {}",
+                workload_file, e
+            );
+        }
+    }
+
+    let comm: String = Path::new(&cmd[0])
+        .file_name()
+        .map_or(cmd[0].clone(), |f| f.to_string_lossy().into_owned())
+        .chars()
+        .take(COMM_LENGTH)
+        .collect();
+    let mut wtr = perf_log_writer(output_path);
+
+    for (idx, group) in groups.iter().enumerate() {
+        let filename = format!("{}_stat.csv", idx + 1);
+        let mut stat_file = output_path.to_path_buf();
+        stat_file.push(&filename);
+        let event_names: Vec<&str> = group.iter().map(|&(ref n, _)| n.as_str()).collect();
+        // `--per-thread` tells aggregate how to read the stat file (see `Scope::from_perf_command`):
+        let papi_cmd = format!(
+            "papi --per-thread -e {} {}",
+            event_names.join(","),
+            cmd.join(" ")
+        );
+        if dryrun {
+            warn!("Dry run mode -- would execute: {}", papi_cmd);
+            continue;
+        }
+        watch::measurement(idx, groups.len(), &stat_file, Scope::Thread);
+
+        let mut set = match EventSet::new() {
+            Ok(set) => set,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+        for &(ref name, code) in group.iter() {
+            if !set.add(name, code) {
+                error!("PAPI can't add {} again, leaving it out.", name);
+            }
+        }
+
+        let mut command = Command::new(&cmd[0]);
+        command.args(&cmd[1..]);
+        command.current_dir(cmd_working_dir);
+        housekeeping::release(&mut command);
+        command.envs(env.iter().map(|&(ref k, ref v)| (k, v)));
+        command.stdin(Stdio::null());
+        command.stdout(Stdio::piped());
+        command.stderr(Stdio::piped());
+
+        let phase_listener = if settings.phase_markers {
+            let mut fifo = output_path.to_path_buf();
+            fifo.push("phases.fifo");
+            match PhaseListener::new(&fifo) {
+                Ok(listener) => {
+                    command.env(PHASE_MARKER_ENV, listener.fifo());
+                    Some(listener)
+                }
+                Err(e) => {
+                    error!("Can't create phase marker FIFO {:?}: {}", fifo, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let started = SystemTime::now();
+        let (stdout, stderr) = match measure(&mut command, &mut set, &comm, &stat_file) {
+            Ok(out) => out,
+            Err(e) => {
+                error!("Measuring {} failed: {}", papi_cmd, e);
+                (String::new(), e)
+            }
+        };
+
+        if let Some(listener) = phase_listener {
+            let mut phases_path = output_path.to_path_buf();
+            phases_path.push(format!("{}_phases.csv", idx + 1));
+            match listener.finish(&phases_path) {
+                Ok(markers) => debug!("Program reported {} phase markers.", markers.len()),
+                Err(e) => error!("Can't save phase markers to {:?}: {}", phases_path, e),
+            }
+        }
+
+        let papi_events: Vec<String> = group.iter().map(|&(_, c)| format!("{:#x}", c)).collect();
+        let r = wtr.encode(vec![
+            cmd.join(" "),
+            event_names.join(","),
+            papi_events.join(","),
+            String::new(),
+            filename,
+            papi_cmd,
+            stdout,
+            stderr,
+            SCHEMA_VERSION.to_string(),
+            unix_time(started),
+        ]);
+        assert!(r.is_ok());
+        let r = wtr.flush();
+        assert!(r.is_ok());
+    }
+
+    if !dryrun {
+        let _ = File::create(completed_file.as_path()).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Where the PCI devices (GPUs, NICs) are attached: the NUMA node and the CPUs
+//! the kernel says are local to a device (`numa_node` and `local_cpulist` of the
+//! device in sysfs) and the PCIe root complex it hangs off (devices below the
+//! same root share the link to the CPUs). `gpu.rs` and `nic.rs` describe the
+//! devices we care about with that.
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use rustc_serialize::json::Json;
+
+use super::util::{Cpu, Node};
+
+/// Where the kernel lists the PCI devices.
+pub const PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+/// The locality of a PCI device.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PciLocality {
+    /// e.g. 0000:3b:00.0
+    pub address: String,
+    /// The bus of the root complex, e.g. 0000:3a for `pci0000:3a`.
+    pub root: String,
+    /// The NUMA node (none if the firmware doesn't say).
+    pub node: Option<Node>,
+    pub cpus: Vec<Cpu>,
+    pub driver: String,
+}
+
+pub fn read_trimmed(path: &Path) -> String {
+    fs::read_to_string(path)
+        .map(|s| String::from(s.trim()))
+        .unwrap_or_default()
+}
+
+/// `0-3,8,10-11` are the CPUs 0, 1, 2, 3, 8, 10 and 11.
+pub fn parse_cpulist(list: &str) -> Vec<Cpu> {
+    let mut cpus = Vec::new();
+    for range in list.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+        let mut ends = range.splitn(2, '-');
+        let first = ends.next().and_then(|f| f.parse::<Cpu>().ok());
+        let last = ends.next().map_or(first, |l| l.parse::<Cpu>().ok());
+        if let (Some(first), Some(last)) = (first, last) {
+            cpus.extend(first..last + 1);
+        }
+    }
+    cpus
+}
+
+/// The CPUs of NUMA node `node`.
+pub fn node_cpus(node: Node) -> Vec<Cpu> {
+    parse_cpulist(&read_trimmed(Path::new(&format!(
+        "/sys/devices/system/node/node{}/cpulist",
+        node
+    ))))
+}
+
+/// `00000000:3B:00.0` (nvidia-smi) is `0000:3b:00.0` (sysfs).
+pub fn normalize_address(address: &str) -> String {
+    let address = address.trim().to_lowercase();
+    match address.find(':') {
+        Some(end) if end > 4 => String::from(&address[end - 4..]),
+        _ => address,
+    }
+}
+
+/// Is `name` a PCI address like `0000:3b:00.0`?
+fn is_address(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() == 12 && bytes[4] == b':' && bytes[7] == b':' && bytes[10] == b'.'
+}
+
+/// The locality of the PCI device that `device` (a directory in sysfs) is or is
+/// below (the `device` of a network interface can be a virtio device on one).
+pub fn locality(device: &Path) -> Option<PciLocality> {
+    let path = fs::canonicalize(device).ok()?;
+    let pci = path.ancestors().find(|p| {
+        p.file_name()
+            .is_some_and(|n| is_address(&n.to_string_lossy()))
+    })?;
+    // /sys/devices/pci0000:3a/0000:3a:00.0/0000:3b:00.0
+    let root = pci
+        .ancestors()
+        .filter_map(|p| p.file_name())
+        .map(|n| n.to_string_lossy().into_owned())
+        .find(|n| n.starts_with("pci"))
+        .map_or(String::new(), |n| String::from(&n[3..]));
+    let driver = fs::read_link(pci.join("driver"))
+        .ok()
+        .and_then(|d| d.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .unwrap_or(String::new());
+    Some(PciLocality {
+        address: pci
+            .file_name()
+            .map_or(String::new(), |n| n.to_string_lossy().into_owned()),
+        root,
+        // numa_node is -1 if the firmware doesn't say:
+        node: read_trimmed(&pci.join("numa_node")).parse::<Node>().ok(),
+        cpus: parse_cpulist(&read_trimmed(&pci.join("local_cpulist"))),
+        driver,
+    })
+}
+
+/// The PCI devices in `PCI_DEVICES` whose class starts with `class` (e.g.
+/// `0x03` for the display controllers), by address.
+pub fn devices(class: &str) -> Vec<PathBuf> {
+    let mut devices: Vec<PathBuf> = match fs::read_dir(PCI_DEVICES) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|d| read_trimmed(&d.join("class")).starts_with(class))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    devices.sort();
+    devices
+}
+
+/// Saves `content` in `file` in `output_path`.
+pub fn save_content(output_path: &Path, file: &str, content: &str) -> io::Result<()> {
+    let mut out_file: PathBuf = output_path.to_path_buf();
+    out_file.push(file);
+    let mut f = File::create(out_file.as_path())?;
+    f.write_all(content.as_bytes())
+}
+
+/// The entries of the JSON list in `path` (none if there is no such file).
+pub fn read_list<T, F: Fn(&Json) -> Option<T>>(path: &Path, entry: F) -> Vec<T> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| Json::from_str(&content).ok())
+        .and_then(|list| {
+            list.as_array()
+                .map(|l| l.iter().filter_map(entry).collect())
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,158 @@
+//! Runs Intel PCM (`pcm` and `pcm-memory`) next to perf for the memory bandwidth,
+//! UPI utilization and C-state residencies of every socket. PCM reads the uncore
+//! counters itself, so this works where perf isn't allowed to access the uncore
+//! PMUs.
+//!
+//! Both tools write a CSV line every second. Their CSV files have two header
+//! rows: the first names the group of the columns (`System`, `SKT0`, `Socket 0`,
+//! `SKT0 Core C-State`...) and only has a value where a group starts, the second
+//! names the metric.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+
+use tracing::debug;
+
+/// The PCM tools we run and the suffix of their output file.
+const TOOLS: [(&str, &str); 2] = [("pcm", "pcm"), ("pcm-memory", "pcm_memory")];
+
+/// An average over the whole measurement: (tool, group, metric, value).
+pub type PcmValue = (String, String, String, f64);
+
+pub struct Pcm {
+    /// (tool, process, output)
+    children: Vec<(&'static str, Child, PathBuf)>,
+}
+
+/// Older PCM releases call their binaries `pcm.x`, `pcm-memory.x` etc.
+fn spawn(tool: &str, output: &Path) -> io::Result<Child> {
+    let mut csv_arg = std::ffi::OsString::from("-csv=");
+    csv_arg.push(output.as_os_str());
+    let spawn = |binary: &str| {
+        let mut command = Command::new(binary);
+        if tool == "pcm" {
+            // We only need the socket and system values:
+            command.arg("-nc");
+        }
+        command
+            .arg(&csv_arg)
+            .arg("1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+    };
+    match spawn(tool) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => spawn(&format!("{}.x", tool)),
+        r => r,
+    }
+}
+
+fn stop(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+    let _ = child.wait();
+}
+
+impl Pcm {
+    /// Starts `pcm` and `pcm-memory`, they write to `<prefix>_pcm.csv` and
+    /// `<prefix>_pcm_memory.csv` in `output_path`.
+    pub fn start(output_path: &Path, prefix: &str) -> io::Result<Pcm> {
+        let mut children = Vec::new();
+        for &(tool, suffix) in TOOLS.iter() {
+            let mut output = output_path.to_path_buf();
+            output.push(format!("{}_{}.csv", prefix, suffix));
+            match spawn(tool, &output) {
+                Ok(child) => children.push((tool, child, output)),
+                Err(e) => {
+                    for &mut (_, ref mut child, _) in children.iter_mut() {
+                        stop(child);
+                    }
+                    return Err(io::Error::new(e.kind(), format!("{}: {}", tool, e)));
+                }
+            }
+        }
+        Ok(Pcm { children })
+    }
+
+    /// Stops PCM and returns the averages of every socket (and the system).
+    pub fn finish(mut self) -> io::Result<Vec<PcmValue>> {
+        let mut values = Vec::new();
+        for &mut (tool, ref mut child, ref output) in self.children.iter_mut() {
+            stop(child);
+            let csv = fs::read_to_string(output)?;
+            values.extend(
+                parse_pcm_csv(csv.as_str())
+                    .into_iter()
+                    .map(|(group, metric, value)| (String::from(tool), group, metric, value)),
+            );
+        }
+        Ok(values)
+    }
+}
+
+/// Memory bandwidth (pcm's READ/WRITE, pcm-memory's Read/Write/Memory but not
+/// the single channels), UPI (or QPI on older machines) and C-state residencies.
+fn is_metric(metric: &str) -> bool {
+    let bandwidth = (metric.contains("Read")
+        || metric.contains("Write")
+        || metric.contains("READ")
+        || metric.contains("WRITE")
+        || metric.starts_with("Memory"))
+        && !metric.starts_with("Ch");
+    bandwidth || metric.starts_with("UPI") || metric.starts_with("QPI") || metric.contains("res%")
+}
+
+/// Averages all intervals in a PCM CSV file per (group, metric).
+fn parse_pcm_csv(output: &str) -> Vec<(String, String, f64)> {
+    let mut lines = output.lines().filter(|l| !l.trim().is_empty());
+    let (groups, metrics) = match (lines.next(), lines.next()) {
+        (Some(groups), Some(metrics)) => (groups, metrics),
+        _ => return Vec::new(),
+    };
+    // Depending on the version PCM separates with ',' or ';':
+    let delimiter = if groups.matches(';').count() > groups.matches(',').count() {
+        ';'
+    } else {
+        ','
+    };
+
+    let mut group = String::new();
+    let columns: Vec<(String, String)> = groups
+        .split(delimiter)
+        .chain(std::iter::repeat(""))
+        .zip(metrics.split(delimiter))
+        .map(|(g, m)| {
+            if !g.trim().is_empty() {
+                group = String::from(g.trim());
+            }
+            (group.clone(), String::from(m.trim()))
+        })
+        .collect();
+
+    // (group, metric) -> (sum, samples)
+    let mut sums: BTreeMap<(String, String), (f64, u64)> = BTreeMap::new();
+    for line in lines {
+        for ((group, metric), value) in columns.iter().zip(line.split(delimiter)) {
+            if !is_metric(metric) {
+                continue;
+            }
+            if let Ok(value) = f64::from_str(value.trim()) {
+                let entry = sums
+                    .entry((group.clone(), metric.clone()))
+                    .or_insert((0.0, 0));
+                entry.0 += value;
+                entry.1 += 1;
+            }
+        }
+    }
+
+    debug!("Parsed {} PCM values.", sums.len());
+    sums.into_iter()
+        .map(|((group, metric), (sum, n))| (group, metric, sum / n as f64))
+        .collect()
+}
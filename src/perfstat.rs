@@ -0,0 +1,294 @@
+//! Reads the counter values perf stat writes, in either of its formats: CSV
+//! (`-x ;`), which every perf has, or JSON (`-j`, one object per line), which
+//! newer versions have and we prefer (the stat file then is `<nr>_stat.json`
+//! instead of `<nr>_stat.csv`). In CSV a value is what it is by its column, and
+//! the columns move (`--per-core` etc. add the number of CPUs after the
+//! location). In JSON every value has a name:
+//!
+//! ```text
+//! {"interval" : 0.250360394, "cpu": "3", "counter-value" : "2013682.000000", "unit" : "",
+//!  "event" : "instructions", "event-runtime" : 250307954, "pcnt-running" : 100.00,
+//!  "metric-value" : "0.76", "metric-unit" : "insn per cycle"}
+//! ```
+//!
+//! Both become a `Count`. The backends that convert their output into the
+//! layout of perf (likwid, PAPI, pmcstat) keep writing CSV.
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use rustc_serialize::json::Json;
+
+use super::intervals;
+use super::profile::Scope;
+
+/// perf couldn't count an event in an interval.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Counted(f64),
+    /// `<not counted>`: the event didn't run (e.g., a thread was asleep).
+    NotCounted,
+    /// `<not supported>`: the PMU doesn't have the event.
+    NotSupported,
+}
+
+impl Value {
+    fn new(text: &str) -> Option<Value> {
+        match text.trim() {
+            "<not counted>" => Some(Value::NotCounted),
+            "<not supported>" => Some(Value::NotSupported),
+            value => f64::from_str(value).ok().map(Value::Counted),
+        }
+    }
+}
+
+/// The value of an event in an interval at a location.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Count {
+    /// Seconds since perf started.
+    pub time: f64,
+    /// Where perf counted, as perf writes it in CSV: `CPU3`, `S0-D0-C1`, `S1`,
+    /// `N0` or `<comm>-<tid>`.
+    pub location: String,
+    pub value: Value,
+    pub unit: String,
+    pub event: String,
+    /// How long the counter ran, in nanoseconds.
+    pub runtime: u64,
+    /// For how much of the time it was enabled the counter ran (less than 100
+    /// if it was multiplexed).
+    pub percent: f64,
+    /// What perf derived from the value (e.g., `insn per cycle`), if anything.
+    pub metric_value: Option<f64>,
+    pub metric_unit: String,
+}
+
+impl Count {
+    /// The number of events (None if perf couldn't count them).
+    pub fn count(&self) -> Option<u64> {
+        match self.value {
+            Value::Counted(value) => Some(value.round() as u64),
+            Value::NotCounted | Value::NotSupported => None,
+        }
+    }
+}
+
+/// What perf derived from the counts of an interval: the `GHz` of `cycles`, the
+/// `insn per cycle` of `instructions`, ... perf prints some metrics on a line of
+/// their own, they have no event.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    pub time: f64,
+    pub location: String,
+    pub event: String,
+    pub value: f64,
+    pub unit: String,
+}
+
+/// The column of the value in a CSV stat file measured at `scope`:
+/// time;[<location>;[#cpus;]]value;unit;event;run time;percentage;metric value;metric unit
+fn value_column(scope: Scope) -> usize {
+    match scope {
+        Scope::Core | Scope::Socket | Scope::Node => 3,
+        Scope::Cpu | Scope::Thread => 2,
+        Scope::Program => 1,
+    }
+}
+
+/// The location of a CSV row measured at `scope` (empty for the program as a whole).
+fn csv_location(row: &[&str], scope: Scope) -> String {
+    match scope {
+        Scope::Program => String::new(),
+        _ => String::from(row[1]),
+    }
+}
+
+fn csv_count(line: &str, scope: Scope) -> Option<Count> {
+    let row: Vec<&str> = line.split(';').map(|c| c.trim()).collect();
+    let value_idx = value_column(scope);
+    if row.len() <= value_idx + 2 || row[0].starts_with("#") {
+        return None;
+    }
+    let column = |idx: usize| row.get(idx).cloned().unwrap_or("");
+    Some(Count {
+        time: f64::from_str(row[0]).ok()?,
+        location: csv_location(&row, scope),
+        value: Value::new(row[value_idx])?,
+        unit: String::from(column(value_idx + 1)),
+        event: String::from(column(value_idx + 2)),
+        runtime: u64::from_str(column(value_idx + 3)).unwrap_or(0),
+        percent: f64::from_str(column(value_idx + 4)).unwrap_or(100.0),
+        metric_value: f64::from_str(column(value_idx + 5)).ok(),
+        metric_unit: String::from(column(value_idx + 6)),
+    })
+}
+
+fn csv_metric(line: &str, scope: Scope) -> Option<Metric> {
+    let row: Vec<&str> = line.split(';').map(|c| c.trim()).collect();
+    let value_idx = value_column(scope);
+    if row.len() <= value_idx + 6 || row[0].starts_with("#") || row[value_idx + 6].is_empty() {
+        return None;
+    }
+    Some(Metric {
+        time: f64::from_str(row[0]).ok()?,
+        location: csv_location(&row, scope),
+        event: String::from(row[value_idx + 2]),
+        value: f64::from_str(row[value_idx + 5]).ok()?,
+        unit: String::from(row[value_idx + 6]),
+    })
+}
+
+/// A field of a JSON line as text, perf writes numbers as numbers or strings
+/// depending on the field and version.
+fn text(object: &Json, key: &str) -> Option<String> {
+    match object.find(key)? {
+        Json::String(s) => Some(s.clone()),
+        &Json::U64(n) => Some(n.to_string()),
+        &Json::I64(n) => Some(n.to_string()),
+        &Json::F64(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn number(object: &Json, key: &str) -> Option<f64> {
+    text(object, key).and_then(|t| f64::from_str(t.trim()).ok())
+}
+
+/// The location keys of `perf stat -j`, by scope.
+const LOCATIONS: [&str; 6] = ["cpu", "core", "die", "socket", "node", "thread"];
+
+/// Where a JSON line was counted, as perf writes it in CSV.
+fn json_location(object: &Json) -> String {
+    LOCATIONS
+        .iter()
+        .find_map(|key| text(object, key).map(|l| (*key, l)))
+        .map_or(String::new(), |(key, location)| match key {
+            "cpu" => format!("CPU{}", location),
+            _ => location,
+        })
+}
+
+fn json_count(line: &str) -> Option<Count> {
+    let object = Json::from_str(line).ok()?;
+    // Lines with just a metric have no event:
+    let event = text(&object, "event")?;
+    Some(Count {
+        time: number(&object, "interval").unwrap_or(0.0),
+        location: json_location(&object),
+        value: Value::new(&text(&object, "counter-value")?)?,
+        unit: text(&object, "unit").unwrap_or_default(),
+        event,
+        runtime: number(&object, "event-runtime").map_or(0, |r| r as u64),
+        percent: number(&object, "pcnt-running").unwrap_or(100.0),
+        metric_value: number(&object, "metric-value"),
+        metric_unit: text(&object, "metric-unit").unwrap_or_default(),
+    })
+}
+
+fn json_metric(line: &str) -> Option<Metric> {
+    let object = Json::from_str(line).ok()?;
+    let unit = text(&object, "metric-unit").filter(|u| !u.is_empty())?;
+    Some(Metric {
+        time: number(&object, "interval").unwrap_or(0.0),
+        location: json_location(&object),
+        event: text(&object, "event").unwrap_or_default(),
+        value: number(&object, "metric-value")?,
+        unit,
+    })
+}
+
+/// A line of a stat file measured at `scope`, in either format (None for
+/// comments and lines that aren't counts).
+pub fn parse_line(line: &str, scope: Scope) -> Option<Count> {
+    if line.trim_start().starts_with('{') {
+        json_count(line)
+    } else {
+        csv_count(line, scope)
+    }
+}
+
+/// The metric on a line of a stat file measured at `scope`, if it has one.
+pub fn parse_metric(line: &str, scope: Scope) -> Option<Metric> {
+    if line.trim_start().starts_with('{') {
+        json_metric(line)
+    } else {
+        csv_metric(line, scope)
+    }
+}
+
+/// The metrics of a stat file measured at `scope` (or of its compressed
+/// intervals, see `intervals.rs`).
+pub fn metrics(path: &Path, scope: Scope) -> io::Result<impl Iterator<Item = Metric>> {
+    let lines = BufReader::new(intervals::open(path)?).lines();
+    Ok(lines
+        .map_while(Result::ok)
+        .filter_map(move |line| parse_metric(&line, scope)))
+}
+
+/// The counts of a stat file measured at `scope` (or of its compressed
+/// intervals).
+pub fn counts(path: &Path, scope: Scope) -> io::Result<impl Iterator<Item = Count>> {
+    let lines = BufReader::new(intervals::open(path)?).lines();
+    Ok(lines
+        .map_while(Result::ok)
+        .filter_map(move |line| parse_line(&line, scope)))
+}
+
+/// The events counted in a stat file measured at `scope`, in the order perf
+/// first reported them.
+pub fn events(path: &Path, scope: Scope) -> Vec<String> {
+    let mut events: Vec<String> = Vec::new();
+    if let Ok(counts) = counts(path, scope) {
+        for count in counts {
+            if !events.contains(&count.event) {
+                events.push(count.event);
+            }
+        }
+    }
+    events
+}
+
+/// Did `perf_command` (of `perf.csv`) measure metric groups (`-M`)?
+pub fn measures_metric_groups(perf_command: &str) -> bool {
+    perf_command.split_whitespace().any(|arg| arg == "-M")
+}
+
+/// The name of the stat file of measurement `nr`.
+pub fn stat_file(nr: usize, json: bool) -> String {
+    match json {
+        true => format!("{}_stat.json", nr),
+        false => format!("{}_stat.csv", nr),
+    }
+}
+
+/// Is `file` (a `datafile` of `perf.csv`) a stat file?
+pub fn is_stat_file(file: &str) -> bool {
+    file.ends_with("_stat.csv") || file.ends_with("_stat.json")
+}
+
+/// The file that belongs to stat file `file`, e.g. `3_phases.csv` of `3_stat.json`.
+pub fn next_to(file: &str, suffix: &str) -> String {
+    let stem = file
+        .trim_end_matches("_stat.csv")
+        .trim_end_matches("_stat.json");
+    format!("{}{}", stem, suffix)
+}
+
+lazy_static! {
+    /// Does our perf have `perf stat -j`?
+    static ref JSON: bool = Command::new("perf")
+        .args(["stat", "-h"])
+        .output()
+        .map(|out| {
+            String::from_utf8_lossy(&out.stdout).contains("--json-output")
+                || String::from_utf8_lossy(&out.stderr).contains("--json-output")
+        })
+        .unwrap_or(false);
+}
+
+/// Should perf stat write JSON? Set `AUTOPERF_PERF_CSV` to get CSV anyway.
+pub fn json_supported() -> bool {
+    *JSON && std::env::var_os("AUTOPERF_PERF_CSV").is_none()
+}
@@ -0,0 +1,130 @@
+//! Programs can tell us about their phases (e.g., setup/solve/teardown) by writing
+//! a phase name per line into the FIFO named by the `AUTOPERF_PHASE_MARKER`
+//! environment variable:
+//!
+//! ```sh
+//! echo solve > $AUTOPERF_PHASE_MARKER
+//! ```
+//!
+//! We timestamp every marker (relative to the start of perf, like perf's interval
+//! timestamps) and store them next to the counter measurements, so `aggregate`
+//! can label every sample with the phase it was measured in.
+use std::ffi::CString;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Instant;
+
+use tracing::{debug, error};
+
+/// The environment variable that tells a program where to write phase markers.
+pub const PHASE_MARKER_ENV: &str = "AUTOPERF_PHASE_MARKER";
+
+/// Written by us into the FIFO to tell the reader thread that the run is over.
+const END_OF_RUN: &str = "__autoperf_end_of_run__";
+
+/// A phase marker: (seconds since start of the measurement, phase name).
+pub type Marker = (f64, String);
+
+pub struct PhaseListener {
+    fifo: PathBuf,
+    reader: thread::JoinHandle<Vec<Marker>>,
+}
+
+impl PhaseListener {
+    /// Creates the marker FIFO and starts listening for phase markers.
+    pub fn new(fifo: &Path) -> io::Result<PhaseListener> {
+        let _ = fs::remove_file(fifo);
+        let c_path = CString::new(fifo.as_os_str().as_bytes()).expect("Path contains a 0 byte?");
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // We open the FIFO for reading and writing so it never reports EOF, even if
+        // the program opens and closes it several times (or never at all).
+        let file = OpenOptions::new().read(true).write(true).open(fifo)?;
+        let start = Instant::now();
+        let reader = thread::spawn(move || {
+            let mut markers: Vec<Marker> = Vec::new();
+            for line in BufReader::new(file).lines() {
+                let elapsed = start.elapsed();
+                let phase = match line {
+                    Ok(l) => String::from(l.trim()),
+                    Err(e) => {
+                        error!("Can't read phase marker: {}", e);
+                        break;
+                    }
+                };
+
+                if phase == END_OF_RUN {
+                    break;
+                }
+                if !phase.is_empty() {
+                    let time = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+                    debug!("Program entered phase '{}' at {} s", phase, time);
+                    markers.push((time, phase));
+                }
+            }
+
+            markers
+        });
+
+        Ok(PhaseListener {
+            fifo: fifo.to_path_buf(),
+            reader,
+        })
+    }
+
+    pub fn fifo(&self) -> &Path {
+        self.fifo.as_path()
+    }
+
+    /// Stops listening and saves all markers we received to `save_to`.
+    pub fn finish(self, save_to: &Path) -> io::Result<Vec<Marker>> {
+        {
+            let mut fifo = OpenOptions::new().write(true).open(&self.fifo)?;
+            fifo.write_all(format!("\n{}\n", END_OF_RUN).as_bytes())?;
+        }
+        let markers = self.reader.join().unwrap_or_default();
+        fs::remove_file(&self.fifo)?;
+
+        let mut wtr = csv::Writer::from_file(save_to).unwrap();
+        wtr.encode(("time", "phase")).unwrap();
+        for marker in markers.iter() {
+            wtr.encode(marker).unwrap();
+        }
+        wtr.flush().unwrap();
+
+        Ok(markers)
+    }
+}
+
+/// Reads the phase markers saved by `PhaseListener::finish`.
+pub fn read_phases(path: &Path) -> Vec<Marker> {
+    if !path.exists() {
+        return Vec::new();
+    }
+
+    let mut rdr = csv::Reader::from_file(path).unwrap().has_headers(true);
+    let mut markers = rdr
+        .decode()
+        .collect::<csv::Result<Vec<Marker>>>()
+        .expect("Can't parse phase markers file");
+    markers.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    markers
+}
+
+/// Returns the name of the phase the program was in at `time`
+/// (empty if it didn't report any phase yet).
+pub fn phase_at(markers: &[Marker], time: f64) -> &str {
+    markers
+        .iter()
+        .take_while(|m| m.0 <= time)
+        .last()
+        .map_or("", |m| m.1.as_str())
+}
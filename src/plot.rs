@@ -0,0 +1,327 @@
+//! Plots a metric straight from aggregated results (long layout): over time for
+//! every run, one bar per run (the metric over the whole run) and a box plot per
+//! run with the distribution over its intervals. Good enough for a quick look,
+//! for anything fancy use the CSV files.
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use plotters::coord::Shift;
+use plotters::prelude::*;
+use regex::Regex;
+use tracing::info;
+
+use super::features::read_intervals;
+use super::normalize::{CYCLES, INSTRUCTIONS};
+use super::presets;
+use super::rules::Metric;
+
+const SIZE: (u32, u32) = (1024, 640);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Plot {
+    TimeSeries,
+    Bars,
+    Box,
+}
+
+impl FromStr for Plot {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Plot, String> {
+        match s {
+            "timeseries" => Ok(Plot::TimeSeries),
+            "bar" => Ok(Plot::Bars),
+            "box" => Ok(Plot::Box),
+            _ => Err(format!("Unknown plot '{}'", s)),
+        }
+    }
+}
+
+impl Plot {
+    fn suffix(&self) -> &'static str {
+        match *self {
+            Plot::TimeSeries => "timeseries",
+            Plot::Bars => "bars",
+            Plot::Box => "box",
+        }
+    }
+}
+
+/// The metric of one run.
+struct RunSeries {
+    name: String,
+    /// (time, value) of every interval.
+    points: Vec<(f64, f64)>,
+    /// The metric computed from the sums of the events over the run.
+    total: f64,
+}
+
+/// A metric name we know (ipc, cpi, the metrics of the presets) as expression of the events in the results,
+/// anything else is an expression already.
+fn metric_expression(metric: &str, events: &[String]) -> String {
+    let first = |names: &[&str]| -> String {
+        names
+            .iter()
+            .find(|n| events.iter().any(|e| e == *n))
+            .map_or(String::from(names[0]), |n| String::from(*n))
+    };
+    match metric {
+        "ipc" => format!("\"{}\" / \"{}\"", first(&INSTRUCTIONS), first(&CYCLES)),
+        "cpi" => format!("\"{}\" / \"{}\"", first(&CYCLES), first(&INSTRUCTIONS)),
+        _ => presets::metric(metric).map_or(String::from(metric), String::from),
+    }
+}
+
+/// Grows a range that would be empty.
+fn range(min: f64, max: f64) -> (f64, f64) {
+    if min < max {
+        (min, max)
+    } else {
+        (min - 1.0, max + 1.0)
+    }
+}
+
+fn label(name: &str) -> String {
+    if !name.is_empty() {
+        String::from(name)
+    } else {
+        String::from("profile")
+    }
+}
+
+fn draw<DB: DrawingBackend>(
+    root: DrawingArea<DB, Shift>,
+    plot: Plot,
+    metric: &str,
+    runs: &[RunSeries],
+) -> Result<(), Box<dyn Error>>
+where
+    DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+    let labels: Vec<String> = runs.iter().map(|r| label(&r.name)).collect();
+    let label_width = labels.iter().map(|l| l.len()).max().unwrap_or(0) as u32 * 7 + 10;
+    let run_label = |v: &SegmentValue<usize>| match *v {
+        SegmentValue::CenterOf(i) => labels.get(i).cloned().unwrap_or(String::new()),
+        _ => String::new(),
+    };
+
+    match plot {
+        Plot::TimeSeries => {
+            let points = runs.iter().flat_map(|r| r.points.iter());
+            let (x_min, x_max) = range(
+                0.0,
+                points
+                    .clone()
+                    .map(|p| p.0)
+                    .fold(f64::NEG_INFINITY, f64::max),
+            );
+            let (y_min, y_max) = range(
+                points
+                    .clone()
+                    .map(|p| p.1)
+                    .fold(f64::INFINITY, f64::min)
+                    .min(0.0),
+                points.map(|p| p.1).fold(f64::NEG_INFINITY, f64::max),
+            );
+            let mut chart = ChartBuilder::on(&root)
+                .caption(metric, ("sans-serif", 20))
+                .margin(10)
+                .x_label_area_size(40)
+                .y_label_area_size(70)
+                .build_cartesian_2d(x_min..x_max, y_min..y_max)?;
+            chart
+                .configure_mesh()
+                .x_desc("Time (s)")
+                .y_desc(metric)
+                .draw()?;
+            for (idx, run) in runs.iter().enumerate() {
+                chart
+                    .draw_series(LineSeries::new(
+                        run.points.iter().cloned(),
+                        Palette99::pick(idx).stroke_width(2),
+                    ))?
+                    .label(label(&run.name))
+                    .legend(move |(x, y)| {
+                        PathElement::new(vec![(x, y), (x + 20, y)], Palette99::pick(idx))
+                    });
+            }
+            chart
+                .configure_series_labels()
+                .background_style(WHITE.mix(0.8))
+                .border_style(BLACK)
+                .draw()?;
+        }
+        Plot::Bars => {
+            let (x_min, x_max) = range(
+                runs.iter().map(|r| r.total).fold(0.0, f64::min),
+                runs.iter().map(|r| r.total).fold(0.0, f64::max),
+            );
+            let mut chart = ChartBuilder::on(&root)
+                .caption(metric, ("sans-serif", 20))
+                .margin(10)
+                .x_label_area_size(40)
+                .y_label_area_size(label_width)
+                .build_cartesian_2d(x_min..x_max, (0..runs.len()).into_segmented())?;
+            chart
+                .configure_mesh()
+                .disable_y_mesh()
+                .y_labels(runs.len())
+                .y_label_formatter(&run_label)
+                .x_desc(metric)
+                .draw()?;
+            chart.draw_series(runs.iter().enumerate().map(|(i, r)| {
+                let mut bar = Rectangle::new(
+                    [
+                        (0.0, SegmentValue::Exact(i)),
+                        (r.total, SegmentValue::Exact(i + 1)),
+                    ],
+                    Palette99::pick(0).filled(),
+                );
+                bar.set_margin(3, 3, 0, 0);
+                bar
+            }))?;
+        }
+        Plot::Box => {
+            let values: Vec<Vec<f64>> = runs
+                .iter()
+                .map(|r| r.points.iter().map(|p| p.1).collect())
+                .collect();
+            let all = values.iter().flat_map(|v| v.iter());
+            let (x_min, x_max) = range(
+                all.clone().cloned().fold(f64::INFINITY, f64::min),
+                all.cloned().fold(f64::NEG_INFINITY, f64::max),
+            );
+            let mut chart = ChartBuilder::on(&root)
+                .caption(metric, ("sans-serif", 20))
+                .margin(10)
+                .x_label_area_size(40)
+                .y_label_area_size(label_width)
+                .build_cartesian_2d(x_min as f32..x_max as f32, (0..runs.len()).into_segmented())?;
+            chart
+                .configure_mesh()
+                .disable_y_mesh()
+                .y_labels(runs.len())
+                .y_label_formatter(&run_label)
+                .x_desc(metric)
+                .draw()?;
+            chart.draw_series(
+                values
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, v)| !v.is_empty())
+                    .map(|(i, v)| {
+                        Boxplot::new_horizontal(SegmentValue::CenterOf(i), &Quartiles::new(v))
+                    }),
+            )?;
+        }
+    }
+
+    root.present()?;
+    Ok(())
+}
+
+/// Plots `metric` for the runs in `results` that match `runs` and returns the
+/// files we created (in `output_path`, one per plot).
+pub fn plot(
+    results: &Path,
+    metric: &str,
+    plots: &[Plot],
+    format: &str,
+    runs: Option<&Regex>,
+    output_path: &Path,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let (event_names, intervals) = read_intervals(results)?;
+    let expression = metric_expression(metric, &event_names);
+    let parsed = Metric::parse(&expression)
+        .map_err(|e| format!("Can't parse metric '{}': {}", expression, e))?;
+    let events: Vec<(String, usize)> = parsed
+        .events()
+        .into_iter()
+        .map(|e| match event_names.iter().position(|n| n == e) {
+            Some(id) => Ok((String::from(e), id)),
+            None => Err(format!("Results don't have event '{}'", e)),
+        })
+        .collect::<Result<Vec<(String, usize)>, String>>()?;
+
+    // Intervals are sorted by run and index:
+    let mut series: Vec<RunSeries> = Vec::new();
+    let mut sums: HashMap<String, f64> = HashMap::new();
+    for ((run, _), interval) in intervals.iter() {
+        if runs.is_some_and(|p| !p.is_match(run)) {
+            continue;
+        }
+        let values: Option<HashMap<String, f64>> = events
+            .iter()
+            .map(|&(ref e, id)| {
+                interval
+                    .values
+                    .get(id)
+                    .cloned()
+                    .unwrap_or(None)
+                    .map(|v| (e.clone(), v))
+            })
+            .collect();
+        let values = match values {
+            Some(values) => values,
+            None => continue,
+        };
+        if series.last().is_none_or(|s| &s.name != run) {
+            if let Some(last) = series.last_mut() {
+                last.total = parsed.eval(&sums);
+            }
+            sums.clear();
+            series.push(RunSeries {
+                name: run.clone(),
+                points: Vec::new(),
+                total: 0.0,
+            });
+        }
+        for (e, v) in values.iter() {
+            *sums.entry(e.clone()).or_insert(0.0) += v;
+        }
+        let value = parsed.eval(&values);
+        if value.is_finite() {
+            series
+                .last_mut()
+                .unwrap()
+                .points
+                .push((interval.time, value));
+        }
+    }
+    if let Some(last) = series.last_mut() {
+        last.total = parsed.eval(&sums);
+    }
+    if series.is_empty() {
+        return Err(From::from("No intervals with all the events of the metric"));
+    }
+    info!("Plotting {} for {} runs", expression, series.len());
+
+    let name: String = metric
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    let mut files = Vec::new();
+    for plot in plots.iter() {
+        let mut save_to = output_path.to_path_buf();
+        save_to.push(format!("{}_{}.{}", name, plot.suffix(), format));
+        match format {
+            "png" => draw(
+                BitMapBackend::new(&save_to, SIZE).into_drawing_area(),
+                *plot,
+                metric,
+                &series,
+            )?,
+            _ => draw(
+                SVGBackend::new(&save_to, SIZE).into_drawing_area(),
+                *plot,
+                metric,
+                &series,
+            )?,
+        }
+        files.push(save_to);
+    }
+    Ok(files)
+}
@@ -0,0 +1,333 @@
+//! Measures with FreeBSD's pmcstat (hwpmc) instead of perf (`--backend pmcstat`,
+//! the default on FreeBSD).
+//!
+//! The events are scheduled into groups like for perf and we run the program
+//! once per group under `pmcstat -w 0.25`, which prints the counts of every
+//! interval. libpmc knows the events by the same names as perf (both use Intel's
+//! event tables), pmcstat just wants them in lower case. It only programs the
+//! core PMUs, so we leave out the uncore events.
+//!
+//! With `--scope cpu` (the default), pmcstat counts on every CPU (`-s`) while
+//! the program runs, with `--scope thread` it counts the program and its
+//! children (`-p -d`) and the values show up as a single thread of the program.
+//! pmcstat's output is kept as `<nr>_pmcstat.txt` and converted into the stat
+//! file layout perf writes (`<nr>_stat.csv`), so `aggregate` works as usual.
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::{Instant, SystemTime};
+
+use tracing::{debug, error, warn};
+use x86::perfcnt::intel::EventDescription;
+
+use super::phases::{PhaseListener, PHASE_MARKER_ENV};
+use super::profile::{
+    create_out_directory, perf_log_writer, save_machine_information, schedule_events, PerfEvent,
+    ProfileSettings, Scope,
+};
+use super::summary::{self, Failure};
+use super::util::*;
+use super::watch::{self, INTERVAL};
+use super::workload::Workload;
+
+pub fn check_for_pmcstat() {
+    let works = Command::new("pmcstat")
+        .arg("-L")
+        .output()
+        .is_ok_and(|out| out.status.success());
+    if !works {
+        error!(
+            "'pmcstat -L' failed, is pmcstat installed and the hwpmc module loaded \
+             (kldload hwpmc)?"
+        );
+        summary::fail(Failure::Environment);
+    }
+}
+
+/// (CPU, event) of a column.
+type Column = (Option<u64>, String);
+
+/// The columns of pmcstat's output: the CPU (for system mode PMCs) and the event
+/// of the header `# s/00/inst_retired.any s/01/inst_retired.any ...` (or
+/// `# p/inst_retired.any ...` for process mode PMCs). We name the events like perf
+/// does, so the results match those of perf.
+fn parse_header(line: &str, events: &[&str]) -> Vec<Column> {
+    let mut columns = Vec::new();
+    let mut tokens = line.trim_start_matches('#').split_whitespace();
+    while let Some(token) = tokens.next() {
+        let (cpu, name) = if token == "p/" {
+            // Process mode names are right-aligned:
+            (None, tokens.next().unwrap_or(""))
+        } else if let Some(name) = token.strip_prefix("p/") {
+            (None, name)
+        } else if let Some(event) = token.strip_prefix("s/") {
+            let mut parts = event.splitn(2, '/');
+            match (parts.next().map(u64::from_str), parts.next()) {
+                (Some(Ok(cpu)), Some(name)) => (Some(cpu), name),
+                _ => continue,
+            }
+        } else {
+            continue;
+        };
+        // pmcstat may cut long names short:
+        let event = events
+            .iter()
+            .find(|e| e.to_lowercase() == name)
+            .or(events.iter().find(|e| e.to_lowercase().starts_with(name)))
+            .map_or(String::from(name), |e| String::from(*e));
+        columns.push((cpu, event));
+    }
+    columns
+}
+
+/// Converts pmcstat's output into a stat file like perf writes with `-A -x ;`
+/// (or `--per-thread`, with `location` as the thread). Returns the number of
+/// intervals.
+fn convert(
+    pmcstat_txt: &Path,
+    events: &[&str],
+    location: &str,
+    runtime: f64,
+    stat_file: &Path,
+) -> io::Result<usize> {
+    let output = fs::read_to_string(pmcstat_txt)?;
+    let mut columns = Vec::new();
+    let mut intervals: Vec<(Vec<Column>, Vec<u64>)> = Vec::new();
+    for line in output.lines().map(|l| l.trim()) {
+        if line.starts_with('#') {
+            columns = parse_header(line, events);
+            continue;
+        }
+        let values: Option<Vec<u64>> = line
+            .split_whitespace()
+            .map(|v| u64::from_str(v).ok())
+            .collect();
+        match values {
+            Some(values) if !values.is_empty() && values.len() == columns.len() => {
+                intervals.push((columns.clone(), values))
+            }
+            _ => debug!("Ignoring pmcstat output '{}'", line),
+        }
+    }
+
+    let mut stat = File::create(stat_file)?;
+    for (idx, (columns, values)) in intervals.iter().enumerate() {
+        // pmcstat doesn't print a time, the last interval ends with the program:
+        let time = if idx + 1 == intervals.len() {
+            runtime.max(INTERVAL * idx as f64)
+        } else {
+            INTERVAL * (idx + 1) as f64
+        };
+        for (&(cpu, ref event), value) in columns.iter().zip(values.iter()) {
+            match cpu {
+                Some(cpu) => {
+                    writeln!(stat, "{:.6};CPU{};{};;{};0;100.00", time, cpu, value, event)?
+                }
+                None => writeln!(
+                    stat,
+                    "{:.6};{};{};;{};0;100.00",
+                    time, location, value, event
+                )?,
+            }
+        }
+    }
+    Ok(intervals.len())
+}
+
+pub fn profile<'a, 'b>(
+    output_path: &Path,
+    cmd_working_dir: &str,
+    cmd: Vec<String>,
+    env: Vec<(String, String)>,
+    events: Vec<&'a EventDescription<'b>>,
+    settings: &ProfileSettings,
+    dryrun: bool,
+) where
+    'b: 'a,
+{
+    assert!(!cmd.is_empty());
+    let mut completed_file: PathBuf = output_path.to_path_buf();
+    completed_file.push("completed");
+    if completed_file.exists() {
+        warn!(
+            "Run {} already completed, skipping.",
+            output_path.to_string_lossy()
+        );
+        return;
+    }
+    if settings.measure_after.is_some()
+        || settings.measure_for.is_some()
+        || settings.turbostat
+        || settings.pcm
+        || settings.bpf
+    {
+        warn!(
+            "--measure-after, --measure-for, --turbostat, --pcm and --bpf are not supported \
+             with pmcstat."
+        );
+    }
+    let per_cpu = settings.scope != Scope::Thread;
+    if settings.scope != Scope::Cpu && settings.scope != Scope::Thread {
+        warn!("pmcstat counts every CPU or the program, using --scope cpu.");
+    }
+
+    let core_events: Vec<&EventDescription> = events
+        .into_iter()
+        .filter(|e| !PerfEvent(e).is_uncore())
+        .collect();
+    let groups = schedule_events(core_events);
+
+    create_out_directory(output_path);
+    if !dryrun {
+        check_for_pmcstat();
+        save_machine_information(output_path, settings.capture_store.as_ref());
+        let mut workload_file = output_path.to_path_buf();
+        workload_file.push("workload.toml");
+        let r = Workload::new(&cmd, cmd_working_dir, &env).save(&workload_file);
+        if let Err(e) = r {
+            error!(
+                "Can't save workload information to {:?}: {}",
+                workload_file, e
+            );
+        }
+    }
+
+    let comm: String = Path::new(&cmd[0])
+        .file_name()
+        .map_or(cmd[0].clone(), |f| f.to_string_lossy().into_owned());
+    let mut wtr = perf_log_writer(output_path);
+
+    for (idx, group) in groups.iter().enumerate() {
+        let filename = format!("{}_stat.csv", idx + 1);
+        let mut stat_file = output_path.to_path_buf();
+        stat_file.push(&filename);
+        let mut pmcstat_txt = output_path.to_path_buf();
+        pmcstat_txt.push(format!("{}_pmcstat.txt", idx + 1));
+        let event_names = group.get_event_names();
+        let pmc_events: Vec<String> = event_names.iter().map(|e| e.to_lowercase()).collect();
+
+        let mut pmcstat = Command::new("pmcstat");
+        pmcstat.current_dir(cmd_working_dir);
+        pmcstat.arg("-w").arg(INTERVAL.to_string());
+        pmcstat.arg("-o").arg(pmcstat_txt.as_os_str());
+        if per_cpu {
+            if let Some(ref cpus) = settings.cpus {
+                let cpus: Vec<String> = cpus.iter().map(|c| c.to_string()).collect();
+                pmcstat.arg("-c").arg(cpus.join(","));
+            }
+        } else {
+            // Count the processes the program starts as well:
+            pmcstat.arg("-d");
+        }
+        for event in pmc_events.iter() {
+            pmcstat.arg(if per_cpu { "-s" } else { "-p" }).arg(event);
+        }
+        pmcstat.args(cmd.as_slice());
+        for (key, value) in env.iter() {
+            pmcstat.env(key, value);
+        }
+        let pmcstat_cmd: String = format!("{:?}", pmcstat).replace("\"", "");
+        if dryrun {
+            warn!("Dry run mode -- would execute: {}", pmcstat_cmd);
+            continue;
+        }
+        let scope = if per_cpu { Scope::Cpu } else { Scope::Thread };
+        watch::measurement(idx, groups.len(), &stat_file, scope);
+
+        let phase_listener = if settings.phase_markers {
+            let mut fifo = output_path.to_path_buf();
+            fifo.push("phases.fifo");
+            match PhaseListener::new(&fifo) {
+                Ok(listener) => {
+                    pmcstat.env(PHASE_MARKER_ENV, listener.fifo());
+                    Some(listener)
+                }
+                Err(e) => {
+                    error!("Can't create phase marker FIFO {:?}: {}", fifo, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let started_at = SystemTime::now();
+        let started = Instant::now();
+        let child = pmcstat
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        // pmcstat counts the whole program, its pid stands in for the thread id:
+        let location = match child {
+            Ok(ref child) => format!("{}-{}", comm, child.id()),
+            Err(_) => comm.clone(),
+        };
+        let (stdout, stderr) = match child.and_then(|c| c.wait_with_output()) {
+            Ok(out) => {
+                if !out.status.success() {
+                    error!(
+                        "pmcstat command: {} got unknown exit status was: {}",
+                        pmcstat_cmd, out.status
+                    );
+                    summary::record(Failure::Workload);
+                }
+                (
+                    String::from_utf8_lossy(&out.stdout).into_owned(),
+                    String::from_utf8_lossy(&out.stderr).into_owned(),
+                )
+            }
+            Err(e) => {
+                error!("Executing {} failed : {}", pmcstat_cmd, e);
+                continue;
+            }
+        };
+        let runtime = started.elapsed().as_secs_f64();
+
+        match convert(&pmcstat_txt, &event_names, &location, runtime, &stat_file) {
+            Ok(0) => warn!(
+                "pmcstat didn't report any counter values for {:?}.",
+                pmc_events
+            ),
+            Ok(intervals) => debug!("pmcstat reported {} intervals.", intervals),
+            Err(e) => error!("Can't read pmcstat output {:?}: {}", pmcstat_txt, e),
+        }
+
+        if let Some(listener) = phase_listener {
+            let mut phases_path = output_path.to_path_buf();
+            phases_path.push(format!("{}_phases.csv", idx + 1));
+            match listener.finish(&phases_path) {
+                Ok(markers) => debug!("Program reported {} phase markers.", markers.len()),
+                Err(e) => error!("Can't save phase markers to {:?}: {}", phases_path, e),
+            }
+        }
+
+        // `--per-thread` tells aggregate how to read the stat file (see `Scope::from_perf_command`):
+        let perf_command = if per_cpu {
+            pmcstat_cmd
+        } else {
+            format!("{} --per-thread", pmcstat_cmd)
+        };
+        let r = wtr.encode(vec![
+            cmd.join(" "),
+            event_names.join(","),
+            pmc_events.join(","),
+            String::new(),
+            filename,
+            perf_command,
+            stdout,
+            stderr,
+            SCHEMA_VERSION.to_string(),
+            unix_time(started_at),
+        ]);
+        assert!(r.is_ok());
+        let r = wtr.flush();
+        assert!(r.is_ok());
+    }
+
+    if !dryrun {
+        let _ = File::create(completed_file.as_path()).unwrap();
+    }
+}
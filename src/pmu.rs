@@ -0,0 +1,620 @@
+//! The events the kernel knows by name. Every PMU in
+//! `/sys/bus/event_source/devices` lists its events in `events/` (`data_read`
+//! is `event=0xff,umask=0x20`) and the terms they are made of in `format/`
+//! (`umask` is `config:8-15`). We resolve the `events` of a manifest against
+//! them before the campaign starts, so a misspelled event or a PMU this machine
+//! doesn't have stops us right away instead of leaving a campaign of
+//! `<not supported>` counters:
+//!
+//! * `data_read` is an event of some PMU, we count it on every PMU that has it.
+//! * `uncore_imc_free_running/data_read/` names the PMU as well. A PMU name
+//!   without its instance number stands for all instances
+//!   (`uncore_imc_free_running_0`, `uncore_imc_free_running_1`, ...).
+//! * `cpu/event=0x3c,umask=0x1/` is made of the terms in the PMU's format.
+//! * `{slots,topdown-retiring,topdown-be-bound}` is a group, perf counts its
+//!   members together. Topdown events (Ice Lake and later) count on the slots
+//!   counter and need a group that `slots` leads: we put `slots` first (and add
+//!   it if it's missing), and put the topdown events that aren't in a group in
+//!   one (see `perf_events`).
+//! * What perf knows without sysfs (`cycles`, `L1-dcache-load-misses`, raw
+//!   events like `r003c` and tracepoints like `sched:sched_switch`) is passed
+//!   on as it is.
+//!
+//! Free-running counters (of `uncore_imc_free_running` and the like) take none
+//! of the counters perf programs, so they're counted next to the first group of
+//! events instead of in a run of their own. `prefer_free_running` counts the
+//! memory bandwidth with them if the machine has them.
+//!
+//! Hybrid CPUs (Alder Lake and later) have a core PMU per core type,
+//! `cpu_core` for the P-cores and `cpu_atom` for the E-cores, and no `cpu`. An
+//! event of `cpu` (`cpu/event=0x3c,umask=0x1/`) is counted on every core type
+//! whose format has its terms, a group of them becomes a group per core type,
+//! and the events by name are counted on the core types that have them. As the
+//! core types don't have the same events, `check_core_types` makes sure an event
+//! is counted on every core type of a placement (perf's error wouldn't say).
+//!
+//! Like the uncore events of our tables (see `PerfEventGroup::get_perf_config`),
+//! events of uncore PMUs are named `<pmu>.<event>` so `aggregate` knows which
+//! unit counted them.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use tracing::{debug, info};
+
+use super::pci::parse_cpulist;
+use super::profile::{get_known_events, ProfileSettings};
+use super::topology::cpu_list;
+use super::util::Cpu;
+
+/// Where the kernel lists its PMUs.
+pub const DEVICES: &str = "/sys/bus/event_source/devices";
+
+/// Terms perf understands for the events of every PMU.
+const PERF_TERMS: [&str; 8] = [
+    "config", "config1", "config2", "name", "period", "freq", "time", "percore",
+];
+
+/// Events of the `perf list` that don't come from sysfs.
+const PERF_EVENTS: [&str; 30] = [
+    "cycles",
+    "cpu-cycles",
+    "instructions",
+    "cache-references",
+    "cache-misses",
+    "branch-instructions",
+    "branches",
+    "branch-misses",
+    "bus-cycles",
+    "stalled-cycles-frontend",
+    "stalled-cycles-backend",
+    "ref-cycles",
+    "cpu-clock",
+    "task-clock",
+    "page-faults",
+    "faults",
+    "minor-faults",
+    "major-faults",
+    "context-switches",
+    "cs",
+    "cpu-migrations",
+    "migrations",
+    "alignment-faults",
+    "emulation-faults",
+    "dummy",
+    "bpf-output",
+    "duration_time",
+    "user_time",
+    "system_time",
+    "cgroup-switches",
+];
+
+lazy_static! {
+    static ref CACHE_EVENT: Regex = Regex::new(
+        r"^(L1-dcache|L1-icache|LLC|dTLB|iTLB|branch|node)-(loads?|stores?|prefetch(es)?)(-misses)?$"
+    )
+    .unwrap();
+    static ref RAW_EVENT: Regex = Regex::new(r"^r[0-9a-fA-F]+$").unwrap();
+    static ref INSTANCE: Regex = Regex::new(r"^_[0-9]+$").unwrap();
+    static ref MODIFIERS: Regex = Regex::new(r"^[ukhIGHpPSDW]*$").unwrap();
+}
+
+/// A PMU of the kernel.
+#[derive(Debug, Clone)]
+pub struct Pmu {
+    pub name: String,
+    /// The names of its events and their terms.
+    pub events: BTreeMap<String, String>,
+    /// The terms it knows and the bits of the configuration they set.
+    pub format: BTreeMap<String, String>,
+    /// The CPUs of a core type of a hybrid CPU (`cpu_core`, `cpu_atom`), empty
+    /// for every other PMU.
+    pub cpus: Vec<Cpu>,
+}
+
+/// An event of the manifest and what perf counts for it.
+#[derive(Debug, Clone, Eq, PartialEq, RustcEncodable)]
+pub struct SysfsEvent {
+    /// The event as the manifest names it.
+    pub name: String,
+    /// The events for `perf -e`, one for every PMU with the event.
+    pub perf_events: Vec<String>,
+    /// Do free-running counters count it (see `prefer_free_running`)?
+    pub free_running: bool,
+}
+
+/// The files of `directory` and what they contain (nothing if there is no such
+/// directory).
+fn read_files(directory: &Path) -> io::Result<BTreeMap<String, String>> {
+    let mut files = BTreeMap::new();
+    if !directory.is_dir() {
+        return Ok(files);
+    }
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        // Not events, perf reads them to print the values of `name`:
+        if name.ends_with(".scale")
+            || name.ends_with(".unit")
+            || name.ends_with(".per-pkg")
+            || name.ends_with(".snapshot")
+        {
+            continue;
+        }
+        files.insert(name, String::from(fs::read_to_string(&path)?.trim()));
+    }
+    Ok(files)
+}
+
+/// The PMUs in `devices` (usually `DEVICES`).
+pub fn read_pmus(devices: &Path) -> io::Result<Vec<Pmu>> {
+    let mut pmus = Vec::new();
+    for entry in fs::read_dir(devices)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let cpus = match fs::read_to_string(path.join("cpus")) {
+            Ok(cpus) if name.starts_with("cpu_") => parse_cpulist(cpus.trim()),
+            _ => Vec::new(),
+        };
+        pmus.push(Pmu {
+            name,
+            events: read_files(&path.join("events"))?,
+            format: read_files(&path.join("format"))?,
+            cpus,
+        });
+    }
+    pmus.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(pmus)
+}
+
+impl Pmu {
+    /// Is this PMU `name`, or one of its instances (`name_0`, `name_1`...)?
+    fn is(&self, name: &str) -> bool {
+        self.name == name
+            || (self.name.starts_with(name) && INSTANCE.is_match(&self.name[name.len()..]))
+    }
+
+    fn is_uncore(&self) -> bool {
+        self.name.starts_with("uncore_")
+    }
+
+    /// Is this the PMU of a core type of a hybrid CPU?
+    fn is_core_type(&self) -> bool {
+        !self.cpus.is_empty()
+    }
+
+    /// `cpu_atom (CPUs 16-23)`
+    fn core_type(&self) -> String {
+        format!("{} (CPUs {})", self.name, cpu_list(&self.cpus))
+    }
+
+    /// Are its counters free-running (they count all the time, perf only reads them)?
+    fn is_free_running(&self) -> bool {
+        self.is_uncore() && self.name.contains("_free_running")
+    }
+
+    /// Does `term` make sense in an event of this PMU?
+    fn knows(&self, term: &str) -> bool {
+        let key = term.split('=').next().unwrap_or("");
+        self.format.contains_key(key)
+            || PERF_TERMS.contains(&key)
+            || (!term.contains('=') && self.events.contains_key(term))
+    }
+
+    /// The event made of `terms` (and `modifiers`) on this PMU.
+    fn perf_event(&self, terms: &[&str], modifiers: &str) -> String {
+        let mut terms: Vec<String> = terms.iter().map(|t| String::from(*t)).collect();
+        if self.is_uncore() {
+            let name = match terms.iter().position(|t| t.starts_with("name=")) {
+                Some(idx) => terms.remove(idx)[5..].to_string(),
+                None => terms.join("_").replace('=', "_"),
+            };
+            terms.push(format!("name={}.{}", self.name, name));
+        }
+        format!("{}/{}/{}", self.name, terms.join(","), modifiers)
+    }
+}
+
+/// The PMUs of the core types of a hybrid CPU (none on other CPUs).
+fn core_types(pmus: &[Pmu]) -> Vec<&Pmu> {
+    pmus.iter().filter(|p| p.is_core_type()).collect()
+}
+
+/// Does perf know `event` without sysfs?
+fn is_perf_event(event: &str) -> bool {
+    let (name, modifiers) = match event.find(':') {
+        Some(idx) => (&event[..idx], &event[idx + 1..]),
+        None => (event, ""),
+    };
+    let known =
+        PERF_EVENTS.contains(&name) || CACHE_EVENT.is_match(name) || RAW_EVENT.is_match(name);
+    // Anything else with a colon is a tracepoint (subsystem:event):
+    (known && MODIFIERS.is_match(modifiers))
+        || (!known && !modifiers.is_empty() && !name.is_empty())
+}
+
+/// The events of `events`, split at the commas that aren't part of the terms of
+/// an event (`cpu/event=0x3c,umask=0x1/,cycles` is two events). A group
+/// (`{slots,topdown-retiring}:u`) is split into its members.
+pub fn members(events: &str) -> Vec<&str> {
+    let events = match (events.find('{'), events.rfind('}')) {
+        (Some(0), Some(end)) => &events[1..end],
+        _ => events,
+    };
+    let mut members = Vec::new();
+    let (mut in_terms, mut start) = (false, 0);
+    for (idx, c) in events.char_indices() {
+        match c {
+            '/' => in_terms = !in_terms,
+            ',' if !in_terms => {
+                members.push(events[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    members.push(events[start..].trim());
+    members.into_iter().filter(|m| !m.is_empty()).collect()
+}
+
+/// The PMU of a perf event (`cpu` for `cpu/topdown-retiring/`).
+fn pmu_of(perf_event: &str) -> &str {
+    perf_event.split('/').next().unwrap_or("")
+}
+
+/// Is `perf_event` one of the topdown events, which count on the slots counter
+/// (Ice Lake and later)?
+fn is_topdown(perf_event: &str) -> bool {
+    perf_event.split('/').nth(1).is_some_and(|terms| {
+        terms
+            .split(',')
+            .any(|t| t == "slots" || t.starts_with("topdown-"))
+    })
+}
+
+fn is_slots(perf_event: &str) -> bool {
+    perf_event.split('/').nth(1) == Some("slots")
+}
+
+/// The group of `members`: perf only counts topdown events in a group that
+/// `slots` leads, so we put it first (and add it if it's missing).
+fn group(mut members: Vec<String>) -> String {
+    if let Some(pmu) = members
+        .iter()
+        .find(|m| is_topdown(m))
+        .map(|m| String::from(pmu_of(m)))
+    {
+        members.retain(|m| !is_slots(m));
+        members.insert(0, format!("{}/slots/", pmu));
+    }
+    format!("{{{}}}", members.join(","))
+}
+
+/// Can we count the topdown events of `perf_events`, does their PMU have `slots`?
+fn check_slots(event: &str, perf_events: &[String], pmus: &[Pmu]) -> Result<(), String> {
+    for perf_event in perf_events.iter().flat_map(|e| members(e)) {
+        if is_topdown(perf_event)
+            && !pmus
+                .iter()
+                .any(|p| p.name == pmu_of(perf_event) && p.events.contains_key("slots"))
+        {
+            return Err(format!(
+                "'{}': {} has topdown events but no slots event to lead them",
+                event,
+                pmu_of(perf_event)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The perf events of `events` for one `perf stat`: their topdown events that
+/// aren't in a group of their own go in one group (per PMU) that `slots` leads,
+/// where the first of them was.
+pub fn perf_events(events: &[&SysfsEvent]) -> Vec<String> {
+    let mut perf_events: Vec<String> = Vec::new();
+    // PMU, where its group goes and its members:
+    let mut topdown: Vec<(String, usize, Vec<String>)> = Vec::new();
+    for perf_event in events.iter().flat_map(|e| e.perf_events.iter()) {
+        if perf_event.starts_with('{') || !is_topdown(perf_event) {
+            perf_events.push(perf_event.clone());
+            continue;
+        }
+        let pmu = pmu_of(perf_event);
+        match topdown.iter_mut().find(|t| t.0 == pmu) {
+            Some(t) => t.2.push(perf_event.clone()),
+            None => {
+                topdown.push((
+                    String::from(pmu),
+                    perf_events.len(),
+                    vec![perf_event.clone()],
+                ));
+                perf_events.push(String::new());
+            }
+        }
+    }
+    for (_, idx, members) in topdown {
+        perf_events[idx] = group(members);
+    }
+    perf_events
+}
+
+/// Resolves `event` (see above) against `pmus`, or the members of a group
+/// (`{slots,topdown-retiring,topdown-be-bound}`), which perf counts together.
+pub fn resolve(event: &str, pmus: &[Pmu]) -> Result<SysfsEvent, String> {
+    let event = event.trim();
+    if !event.starts_with('{') {
+        let resolved = resolve_one(event, pmus)?;
+        check_slots(event, &resolved.perf_events, pmus)?;
+        return Ok(resolved);
+    }
+
+    let modifiers = match event.rfind('}') {
+        Some(end) => &event[end + 1..],
+        None => {
+            return Err(format!(
+                "'{}' is missing the closing '}}' of the group",
+                event
+            ))
+        }
+    };
+    if !modifiers.is_empty() && !(modifiers.starts_with(':') && MODIFIERS.is_match(&modifiers[1..]))
+    {
+        return Err(format!("'{}' has unknown modifiers '{}'", event, modifiers));
+    }
+    let mut variants: Vec<(&str, Vec<String>)> = Vec::new();
+    for member in members(event) {
+        variants.push((member, resolve_one(member, pmus)?.perf_events));
+    }
+    if variants.is_empty() {
+        return Err(format!("'{}' is an empty group", event));
+    }
+    let groups = match variants.iter().find(|&(_, v)| v.len() != 1) {
+        None => vec![variants.into_iter().map(|(_, mut v)| v.remove(0)).collect()],
+        Some(&(member, ref v)) => match per_core_type(&variants, pmus) {
+            Some(groups) => groups,
+            None => {
+                return Err(format!(
+                    "'{}': {} is counted on {} PMUs, the events of a group must be counted on one",
+                    event,
+                    member,
+                    v.len()
+                ))
+            }
+        },
+    };
+    let mut perf_events = Vec::with_capacity(groups.len());
+    for members in groups {
+        check_slots(event, &members, pmus)?;
+        perf_events.push(format!("{}{}", group(members), modifiers));
+    }
+
+    Ok(SysfsEvent {
+        name: String::from(event),
+        perf_events,
+        free_running: false,
+    })
+}
+
+/// The groups (one per core type of a hybrid CPU) of the `variants` of the
+/// members of a group: every core type that has all of them gets one. None if
+/// the members aren't events of the core types or no core type has them all.
+fn per_core_type(variants: &[(&str, Vec<String>)], pmus: &[Pmu]) -> Option<Vec<Vec<String>>> {
+    let types = core_types(pmus);
+    let is_core = |v: &String| types.iter().any(|p| p.name == pmu_of(v));
+    if types.is_empty() || !variants.iter().all(|(_, v)| v.iter().all(is_core)) {
+        return None;
+    }
+    let mut groups = Vec::new();
+    for pmu in types {
+        let members: Option<Vec<String>> = variants
+            .iter()
+            .map(|(_, v)| v.iter().find(|v| pmu_of(v) == pmu.name).cloned())
+            .collect();
+        match members {
+            Some(members) => groups.push(members),
+            None => debug!("{} doesn't have all events of the group", pmu.core_type()),
+        }
+    }
+    if groups.is_empty() {
+        None
+    } else {
+        Some(groups)
+    }
+}
+
+/// Is `event` counted on every core type of a hybrid CPU that `cpus` (of a
+/// placement) has? Events that aren't counted on the cores (uncore events, and
+/// the events perf knows itself, which it counts on every core type) are.
+pub fn check_core_types(event: &SysfsEvent, pmus: &[Pmu], cpus: &[Cpu]) -> Result<(), String> {
+    let types = core_types(pmus);
+    let counted_on: Vec<&Pmu> = types
+        .iter()
+        .filter(|p| {
+            event
+                .perf_events
+                .iter()
+                .flat_map(|e| members(e))
+                .any(|m| pmu_of(m) == p.name)
+        })
+        .cloned()
+        .collect();
+    if counted_on.is_empty() {
+        return Ok(());
+    }
+    match types.iter().find(|p| {
+        p.cpus.iter().any(|c| cpus.contains(c)) && !counted_on.iter().any(|o| o.name == p.name)
+    }) {
+        Some(missing) => Err(format!(
+            "'{}' can't be counted on the {} of the placement, only {} has it",
+            event.name,
+            missing.core_type(),
+            counted_on
+                .iter()
+                .map(|p| p.core_type())
+                .collect::<Vec<String>>()
+                .join(" and ")
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Resolves an `event` that isn't a group.
+fn resolve_one(event: &str, pmus: &[Pmu]) -> Result<SysfsEvent, String> {
+    let mut free_running = false;
+    let perf_events: Vec<String> = if event.contains('/') {
+        let parts: Vec<&str> = event.splitn(3, '/').collect();
+        if parts.len() != 3 {
+            return Err(format!(
+                "'{}' should look like <pmu>/<terms>/ (e.g., cpu/event=0x3c/)",
+                event
+            ));
+        }
+        let (pmu, modifiers) = (parts[0], parts[2]);
+        let terms: Vec<&str> = parts[1]
+            .split(',')
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+            .collect();
+        if !MODIFIERS.is_match(modifiers) {
+            return Err(format!("'{}' has unknown modifiers '{}'", event, modifiers));
+        }
+        let mut instances: Vec<&Pmu> = pmus.iter().filter(|p| p.is(pmu)).collect();
+        // A hybrid CPU has no `cpu`, we count the event on every core type that knows it:
+        if instances.is_empty() && pmu == "cpu" && !core_types(pmus).is_empty() {
+            let (known, unknown): (Vec<&Pmu>, Vec<&Pmu>) = core_types(pmus)
+                .into_iter()
+                .partition(|p| terms.iter().all(|t| p.knows(t)));
+            for p in unknown.iter() {
+                let term = terms.iter().find(|t| !p.knows(t)).unwrap();
+                debug!(
+                    "'{}' isn't counted on {}, it has no event or term '{}'",
+                    event,
+                    p.core_type(),
+                    term
+                );
+            }
+            if known.is_empty() {
+                return Err(format!(
+                    "'{}': no core type of this hybrid CPU knows all of its terms ({} don't)",
+                    event,
+                    unknown
+                        .iter()
+                        .map(|p| p.core_type())
+                        .collect::<Vec<String>>()
+                        .join(" and ")
+                ));
+            }
+            instances = known;
+        }
+        if instances.is_empty() {
+            return Err(format!(
+                "'{}': there is no PMU {} in {}",
+                event, pmu, DEVICES
+            ));
+        }
+        for instance in instances.iter() {
+            if let Some(term) = terms.iter().find(|t| !instance.knows(t)) {
+                return Err(format!(
+                    "'{}': {} has no event or term '{}' (its terms are {})",
+                    event,
+                    instance.name,
+                    term,
+                    instance
+                        .format
+                        .keys()
+                        .map(|k| k.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                ));
+            }
+        }
+        free_running = instances.iter().all(|p| p.is_free_running());
+        instances
+            .iter()
+            .map(|p| p.perf_event(&terms, modifiers))
+            .collect()
+    } else {
+        let (name, modifiers) = match event.find(':') {
+            Some(idx) => (&event[..idx], &event[idx + 1..]),
+            None => (event, ""),
+        };
+        let with_event: Vec<&Pmu> = pmus
+            .iter()
+            .filter(|p| p.events.contains_key(name) && MODIFIERS.is_match(modifiers))
+            .collect();
+        if !with_event.is_empty() {
+            free_running = with_event.iter().all(|p| p.is_free_running());
+            with_event
+                .iter()
+                .map(|p| p.perf_event(&[name], modifiers))
+                .collect()
+        } else if is_perf_event(event) {
+            vec![String::from(event)]
+        } else {
+            return Err(format!(
+                "Unknown event '{}': no PMU in {} has it and perf doesn't know it either",
+                event, DEVICES
+            ));
+        }
+    };
+
+    Ok(SysfsEvent {
+        name: String::from(event),
+        perf_events,
+        free_running,
+    })
+}
+
+/// Events of our tables that free-running counters count as well: the names the
+/// kernel gives the free-running event (they differ between machines) and the
+/// event of our tables.
+const FREE_RUNNING: [(&[&str], &str); 2] = [
+    (&["data_read", "read"], "UNC_M_CAS_COUNT.RD"),
+    (&["data_write", "write"], "UNC_M_CAS_COUNT.WR"),
+];
+
+/// Counts the memory bandwidth with the free-running counters of the IMC
+/// (`uncore_imc_free_running`) if this machine has them. They take no counter,
+/// so the events of our tables they replace leave theirs to the other IMC
+/// events.
+pub fn prefer_free_running(settings: &mut ProfileSettings) {
+    let pmus = match read_pmus(Path::new(DEVICES)) {
+        Ok(pmus) => pmus,
+        Err(e) => {
+            debug!("Can't read the PMUs in {}: {}", DEVICES, e);
+            return;
+        }
+    };
+    let known = get_known_events();
+    for &(names, replaced) in FREE_RUNNING.iter() {
+        if settings.skip_events.iter().any(|s| s == replaced)
+            || !known.iter().any(|e| e.event_name == replaced)
+        {
+            continue;
+        }
+        let event = match names
+            .iter()
+            .filter_map(|n| resolve(&format!("uncore_imc_free_running/{}/", n), &pmus).ok())
+            .next()
+        {
+            Some(event) => event,
+            None => continue,
+        };
+        info!(
+            "Counting {} with the free-running counters ({}) instead.",
+            replaced, event.name
+        );
+        if !settings
+            .sysfs_events
+            .iter()
+            .any(|e| e.perf_events == event.perf_events)
+        {
+            settings.sysfs_events.push(event);
+        }
+        settings.skip_events.push(String::from(replaced));
+    }
+}
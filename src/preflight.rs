@@ -0,0 +1,169 @@
+//! Checks the events before we measure them, so an event this machine can't
+//! count shows up before a campaign of many hours rather than as
+//! `<not supported>` in its results. We count a batch of events at a time
+//! (system-wide, while `true` runs) with `perf stat`; if perf refuses a batch,
+//! we try its events one by one to find the ones it doesn't like.
+//!
+//! Without `--skip-invalid` we stop if an event is invalid, with it we measure
+//! the others.
+use std::process::Command;
+
+use tracing::{debug, error, info, warn};
+use x86::perfcnt::intel::EventDescription;
+
+use super::pmu;
+use super::profile::{check_perf_access, get_known_events, schedule_events, ProfileSettings};
+use super::summary::{self, Failure};
+
+/// How many events we count in one `perf stat` (perf multiplexes them).
+const BATCH: usize = 32;
+
+/// How perf names a row of its output for `perf_event`.
+fn printed_name(perf_event: &str) -> &str {
+    match perf_event.find("name=") {
+        Some(idx) => {
+            let name = &perf_event[idx + 5..];
+            &name[..name.find([',', '/']).unwrap_or(name.len())]
+        }
+        None => perf_event,
+    }
+}
+
+/// Counts `perf_events` once: the value of every row perf printed, or what
+/// perf complained about if it refused them.
+fn perf_stat(perf_events: &[&str]) -> Result<Vec<(String, String)>, String> {
+    let mut perf = Command::new("perf");
+    perf.args(["stat", "-x", ";", "-a"]);
+    for event in perf_events {
+        perf.arg("-e").arg(event);
+    }
+    perf.args(["--", "true"]);
+    let out = perf
+        .output()
+        .map_err(|e| format!("Can't execute perf: {}", e))?;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    if !out.status.success() {
+        let complaint = stderr
+            .lines()
+            .map(|l| l.trim())
+            .find(|l| !l.is_empty())
+            .unwrap_or("perf failed");
+        return Err(String::from(complaint));
+    }
+    Ok(stderr
+        .lines()
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split(';').collect();
+            if fields.len() > 2 {
+                Some((String::from(fields[2]), String::from(fields[0])))
+            } else {
+                None
+            }
+        })
+        .collect())
+}
+
+/// Why perf can't count `event` (None if it can).
+fn why_invalid(perf_events: &[String], rows: &[(String, String)]) -> Option<String> {
+    perf_events
+        .iter()
+        .flat_map(|e| pmu::members(e))
+        .filter_map(|e| rows.iter().find(|r| r.0 == printed_name(e)))
+        .find(|r| r.1 == "<not supported>")
+        .map(|r| format!("{} is not supported", r.0))
+}
+
+/// Checks `events` (a name and the perf events that count it), returns the ones
+/// we can't count and why.
+pub fn check(events: &[(String, Vec<String>)]) -> Vec<(String, String)> {
+    let mut invalid = Vec::new();
+    for batch in events.chunks(BATCH) {
+        let perf_events: Vec<&str> = batch
+            .iter()
+            .flat_map(|e| e.1.iter().map(|p| p.as_str()))
+            .collect();
+        match perf_stat(&perf_events) {
+            Ok(rows) => {
+                for (name, events) in batch {
+                    if let Some(why) = why_invalid(events, &rows) {
+                        invalid.push((name.clone(), why));
+                    }
+                }
+            }
+            Err(e) => {
+                debug!(
+                    "perf refused a batch of events ({}), checking them one by one",
+                    e
+                );
+                for (name, events) in batch {
+                    let perf_events: Vec<&str> = events.iter().map(|p| p.as_str()).collect();
+                    let why = match perf_stat(&perf_events) {
+                        Ok(rows) => why_invalid(events, &rows),
+                        Err(e) => Some(e),
+                    };
+                    if let Some(why) = why {
+                        invalid.push((name.clone(), why));
+                    }
+                }
+            }
+        }
+    }
+    invalid
+}
+
+/// The perf events of an event of our tables.
+fn table_event(event: &EventDescription) -> (String, Vec<String>) {
+    let perf_events = schedule_events(vec![event])
+        .iter()
+        .flat_map(|g| g.get_perf_config_strings())
+        .collect();
+    (String::from(event.event_name), perf_events)
+}
+
+/// Checks the events `settings` measure (the events of our tables and the
+/// events named by the kernel), and drops the invalid ones if we
+/// `skip_invalid`. We stop otherwise.
+pub fn validate(settings: &mut ProfileSettings, skip_invalid: bool) {
+    check_perf_access();
+    let mut events: Vec<(String, Vec<String>)> = get_known_events()
+        .into_iter()
+        .filter(|e| !settings.skip_events.iter().any(|s| s == e.event_name))
+        .map(table_event)
+        .filter(|e| !e.1.is_empty())
+        .collect();
+    let table_events = events.len();
+    events.extend(
+        settings
+            .sysfs_events
+            .iter()
+            .map(|e| (e.name.clone(), pmu::perf_events(&[e]))),
+    );
+    info!("Checking {} events before we measure them.", events.len());
+
+    let invalid = check(&events);
+    for (name, why) in invalid.iter() {
+        if skip_invalid {
+            warn!("Skipping event {}: {}", name, why);
+        } else {
+            error!("Can't measure event {}: {}", name, why);
+        }
+    }
+    if !invalid.is_empty() && !skip_invalid {
+        error!(
+            "{} of {} events can't be measured, fix them or start again with --skip-invalid \
+             to measure the others.",
+            invalid.len(),
+            events.len()
+        );
+        summary::fail(Failure::Environment);
+    }
+
+    let is_invalid = |name: &str| invalid.iter().any(|i| i.0 == name);
+    for (name, _) in events[..table_events].iter() {
+        if is_invalid(name) {
+            settings.skip_events.push(name.clone());
+        }
+    }
+    settings.sysfs_events.retain(|e| !is_invalid(&e.name));
+}
@@ -0,0 +1,472 @@
+//! Presets: the events of our tables that answer one question, and the metrics
+//! (expressions of them, see `rules::Metric`) that answer it. `profile --preset`
+//! measures only the events of a preset (in as few runs as they fit in), `plot
+//! --metric` and `report` know the names of the metrics.
+//!
+//! * `avx-license`: the cycles a core spent in each AVX frequency license
+//!   (`CORE_POWER.LVL*_TURBO_LICENSE`, level 0 is the turbo of non-AVX code,
+//!   level 1 that of AVX2 and light AVX-512, level 2 that of heavy AVX-512),
+//!   the cycles it was throttled waiting for a license (`CORE_POWER.THROTTLE`)
+//!   and how fast it ran relative to the base frequency.
+//! * `tsx`: the transactions of RTM and HLE (lock elision): how many started,
+//!   committed and aborted, the aborts by cause (the causes differ between
+//!   microarchitectures) and the cycles spent in transactions (`cycles-t`) and
+//!   in those that committed (`cycles-ct`). The kernel names the latter two, they
+//!   are counted with perf's `in_tx` and `in_tx_cp` bits (`cycles-ct` only counts
+//!   on the third counter, so they get a run of their own).
+//!
+//! The presets below are for common studies, so they are comparable between
+//! people who ran them. Their events have different names on Haswell and on
+//! Skylake and later, a preset lists both and measures the ones the CPU has
+//! (their metrics use the Skylake names unless there is no such event).
+//!
+//! * `memory`: the memory hierarchy: loads that missed L1, L2 and L3, where L3
+//!   misses were served from (local or remote DRAM), the cycles stalled on
+//!   memory, TLB walks and the traffic of the memory controllers.
+//! * `frontend`: whether the frontend delivers enough µops: the slots and cycles
+//!   it didn't deliver any, where µops came from (DSB, MITE or the microcode
+//!   sequencer), instruction cache and ITLB misses and switches from the DSB to
+//!   the MITE.
+//! * `branch`: branches by kind and how many were mispredicted, resteers and
+//!   machine clears and the cycles spent recovering from them.
+//! * `io`: what PCIe devices read and wrote (IIO, Skylake-SP and later), the
+//!   coherent operations of the IRP and whether the LLC had the lines I/O
+//!   asked for.
+//!
+//! Next to events, metrics can use the share of the L2 and L3 a core of the
+//! machine has (`L2_SHARE_MIB`, `L3_SHARE_MIB`, see `machine_values`), e.g.
+//! `l3-mpki-per-mib` puts the L3 misses in relation to the part of the L3 a
+//! core can expect to have.
+use std::path::Path;
+
+use tracing::{error, info, warn};
+use x86::perfcnt::intel::EventDescription;
+
+use super::pmu;
+use super::profile::{get_known_events, ProfileSettings};
+use super::summary::{self, Failure};
+use super::util::MachineTopology;
+
+pub struct Preset {
+    pub name: &'static str,
+    pub events: &'static [&'static str],
+    /// Events the kernel names (see `pmu.rs`) and what we call them (their
+    /// name in the results).
+    pub sysfs_events: &'static [(&'static str, &'static str)],
+    /// The name of a metric and its expression.
+    pub metrics: &'static [(&'static str, &'static str)],
+}
+
+pub const PRESETS: [Preset; 6] = [
+    Preset {
+        name: "avx-license",
+        events: &[
+            "INST_RETIRED.ANY",
+            "CPU_CLK_UNHALTED.THREAD",
+            "CPU_CLK_UNHALTED.REF_TSC",
+            "CORE_POWER.LVL0_TURBO_LICENSE",
+            "CORE_POWER.LVL1_TURBO_LICENSE",
+            "CORE_POWER.LVL2_TURBO_LICENSE",
+            "CORE_POWER.THROTTLE",
+        ],
+        sysfs_events: &[],
+        metrics: &[
+            (
+                "license0-share",
+                "CORE_POWER.LVL0_TURBO_LICENSE / CPU_CLK_UNHALTED.THREAD",
+            ),
+            (
+                "license1-share",
+                "CORE_POWER.LVL1_TURBO_LICENSE / CPU_CLK_UNHALTED.THREAD",
+            ),
+            (
+                "license2-share",
+                "CORE_POWER.LVL2_TURBO_LICENSE / CPU_CLK_UNHALTED.THREAD",
+            ),
+            (
+                "throttle-share",
+                "CORE_POWER.THROTTLE / CPU_CLK_UNHALTED.THREAD",
+            ),
+            (
+                "frequency-ratio",
+                "CPU_CLK_UNHALTED.THREAD / CPU_CLK_UNHALTED.REF_TSC",
+            ),
+        ],
+    },
+    Preset {
+        name: "tsx",
+        events: &[
+            "INST_RETIRED.ANY",
+            "CPU_CLK_UNHALTED.THREAD",
+            "RTM_RETIRED.START",
+            "RTM_RETIRED.COMMIT",
+            "RTM_RETIRED.ABORTED",
+            "RTM_RETIRED.ABORTED_MEM",
+            "RTM_RETIRED.ABORTED_TIMER",
+            "RTM_RETIRED.ABORTED_UNFRIENDLY",
+            "RTM_RETIRED.ABORTED_MEMTYPE",
+            "RTM_RETIRED.ABORTED_EVENTS",
+            "RTM_RETIRED.ABORTED_MISC1",
+            "RTM_RETIRED.ABORTED_MISC2",
+            "RTM_RETIRED.ABORTED_MISC3",
+            "RTM_RETIRED.ABORTED_MISC4",
+            "RTM_RETIRED.ABORTED_MISC5",
+            "HLE_RETIRED.START",
+            "HLE_RETIRED.COMMIT",
+            "HLE_RETIRED.ABORTED",
+            "HLE_RETIRED.ABORTED_MEM",
+            "HLE_RETIRED.ABORTED_TIMER",
+            "HLE_RETIRED.ABORTED_UNFRIENDLY",
+            "HLE_RETIRED.ABORTED_MEMTYPE",
+            "HLE_RETIRED.ABORTED_EVENTS",
+            "HLE_RETIRED.ABORTED_MISC1",
+            "HLE_RETIRED.ABORTED_MISC2",
+            "HLE_RETIRED.ABORTED_MISC3",
+            "HLE_RETIRED.ABORTED_MISC4",
+            "HLE_RETIRED.ABORTED_MISC5",
+            "TX_MEM.ABORT_CONFLICT",
+            "TX_MEM.ABORT_CAPACITY",
+            "TX_MEM.ABORT_CAPACITY_WRITE",
+        ],
+        sysfs_events: &[
+            ("cpu/cycles-t,name=cycles-t/", "cycles-t"),
+            ("cpu/cycles-ct,name=cycles-ct/", "cycles-ct"),
+        ],
+        metrics: &[
+            ("rtm-abort-rate", "RTM_RETIRED.ABORTED / RTM_RETIRED.START"),
+            ("hle-abort-rate", "HLE_RETIRED.ABORTED / HLE_RETIRED.START"),
+            (
+                "conflict-abort-share",
+                "TX_MEM.ABORT_CONFLICT / (RTM_RETIRED.ABORTED + HLE_RETIRED.ABORTED)",
+            ),
+            (
+                "capacity-abort-share",
+                "TX_MEM.ABORT_CAPACITY / (RTM_RETIRED.ABORTED + HLE_RETIRED.ABORTED)",
+            ),
+            (
+                "capacity-write-abort-share",
+                "TX_MEM.ABORT_CAPACITY_WRITE / (RTM_RETIRED.ABORTED + HLE_RETIRED.ABORTED)",
+            ),
+            ("transactional-cycles", "cycles-t / CPU_CLK_UNHALTED.THREAD"),
+            (
+                "aborted-cycles",
+                "(cycles-t - cycles-ct) / CPU_CLK_UNHALTED.THREAD",
+            ),
+        ],
+    },
+    Preset {
+        name: "memory",
+        events: &[
+            "INST_RETIRED.ANY",
+            "CPU_CLK_UNHALTED.THREAD",
+            "MEM_LOAD_RETIRED.L1_MISS",
+            "MEM_LOAD_RETIRED.L2_MISS",
+            "MEM_LOAD_RETIRED.L3_MISS",
+            "MEM_LOAD_UOPS_RETIRED.L1_MISS",
+            "MEM_LOAD_UOPS_RETIRED.L2_MISS",
+            "MEM_LOAD_UOPS_RETIRED.L3_MISS",
+            "MEM_LOAD_L3_MISS_RETIRED.LOCAL_DRAM",
+            "MEM_LOAD_L3_MISS_RETIRED.REMOTE_DRAM",
+            "MEM_LOAD_UOPS_L3_MISS_RETIRED.LOCAL_DRAM",
+            "MEM_LOAD_UOPS_L3_MISS_RETIRED.REMOTE_DRAM",
+            "CYCLE_ACTIVITY.STALLS_MEM_ANY",
+            "CYCLE_ACTIVITY.STALLS_L3_MISS",
+            "CYCLE_ACTIVITY.STALLS_LDM_PENDING",
+            "L2_LINES_IN.ALL",
+            "DTLB_LOAD_MISSES.WALK_COMPLETED",
+            "DTLB_STORE_MISSES.WALK_COMPLETED",
+            "UNC_M_CAS_COUNT.RD",
+            "UNC_M_CAS_COUNT.WR",
+        ],
+        sysfs_events: &[],
+        metrics: &[
+            (
+                "l1-mpki",
+                "MEM_LOAD_RETIRED.L1_MISS * 1000 / INST_RETIRED.ANY",
+            ),
+            (
+                "l2-mpki",
+                "MEM_LOAD_RETIRED.L2_MISS * 1000 / INST_RETIRED.ANY",
+            ),
+            (
+                "l3-mpki",
+                "MEM_LOAD_RETIRED.L3_MISS * 1000 / INST_RETIRED.ANY",
+            ),
+            (
+                "l2-mpki-per-mib",
+                "MEM_LOAD_RETIRED.L2_MISS * 1000 / INST_RETIRED.ANY / L2_SHARE_MIB",
+            ),
+            (
+                "l3-mpki-per-mib",
+                "MEM_LOAD_RETIRED.L3_MISS * 1000 / INST_RETIRED.ANY / L3_SHARE_MIB",
+            ),
+            (
+                "remote-dram-share",
+                "MEM_LOAD_L3_MISS_RETIRED.REMOTE_DRAM / (MEM_LOAD_L3_MISS_RETIRED.LOCAL_DRAM + MEM_LOAD_L3_MISS_RETIRED.REMOTE_DRAM)",
+            ),
+            (
+                "memory-stall-share",
+                "CYCLE_ACTIVITY.STALLS_MEM_ANY / CPU_CLK_UNHALTED.THREAD",
+            ),
+            (
+                "l3-miss-stall-share",
+                "CYCLE_ACTIVITY.STALLS_L3_MISS / CPU_CLK_UNHALTED.THREAD",
+            ),
+            (
+                "dtlb-walks-pki",
+                "(DTLB_LOAD_MISSES.WALK_COMPLETED + DTLB_STORE_MISSES.WALK_COMPLETED) * 1000 / INST_RETIRED.ANY",
+            ),
+            ("dram-read-bytes", "UNC_M_CAS_COUNT.RD * 64"),
+            ("dram-write-bytes", "UNC_M_CAS_COUNT.WR * 64"),
+        ],
+    },
+    Preset {
+        name: "frontend",
+        events: &[
+            "INST_RETIRED.ANY",
+            "CPU_CLK_UNHALTED.THREAD",
+            "IDQ_UOPS_NOT_DELIVERED.CORE",
+            "IDQ_UOPS_NOT_DELIVERED.CYCLES_0_UOPS_DELIV.CORE",
+            "IDQ.DSB_UOPS",
+            "IDQ.MITE_UOPS",
+            "IDQ.MS_UOPS",
+            "ICACHE_64B.IFTAG_MISS",
+            "ICACHE_16B.IFDATA_STALL",
+            "ICACHE.MISSES",
+            "ITLB_MISSES.WALK_COMPLETED",
+            "ITLB_MISSES.STLB_HIT",
+            "DSB2MITE_SWITCHES.PENALTY_CYCLES",
+            "BACLEARS.ANY",
+        ],
+        sysfs_events: &[],
+        metrics: &[
+            (
+                "frontend-starved-share",
+                "IDQ_UOPS_NOT_DELIVERED.CYCLES_0_UOPS_DELIV.CORE / CPU_CLK_UNHALTED.THREAD",
+            ),
+            (
+                "dsb-coverage",
+                "IDQ.DSB_UOPS / (IDQ.DSB_UOPS + IDQ.MITE_UOPS + IDQ.MS_UOPS)",
+            ),
+            (
+                "microcode-share",
+                "IDQ.MS_UOPS / (IDQ.DSB_UOPS + IDQ.MITE_UOPS + IDQ.MS_UOPS)",
+            ),
+            (
+                "icache-mpki",
+                "ICACHE_64B.IFTAG_MISS * 1000 / INST_RETIRED.ANY",
+            ),
+            (
+                "icache-stall-share",
+                "ICACHE_16B.IFDATA_STALL / CPU_CLK_UNHALTED.THREAD",
+            ),
+            (
+                "itlb-walks-pki",
+                "ITLB_MISSES.WALK_COMPLETED * 1000 / INST_RETIRED.ANY",
+            ),
+            (
+                "dsb-switch-share",
+                "DSB2MITE_SWITCHES.PENALTY_CYCLES / CPU_CLK_UNHALTED.THREAD",
+            ),
+        ],
+    },
+    Preset {
+        name: "branch",
+        events: &[
+            "INST_RETIRED.ANY",
+            "CPU_CLK_UNHALTED.THREAD",
+            "BR_INST_RETIRED.ALL_BRANCHES",
+            "BR_MISP_RETIRED.ALL_BRANCHES",
+            "BR_INST_RETIRED.CONDITIONAL",
+            "BR_MISP_RETIRED.CONDITIONAL",
+            "BR_INST_RETIRED.COND",
+            "BR_MISP_RETIRED.COND",
+            "BR_INST_RETIRED.INDIRECT",
+            "BR_MISP_RETIRED.INDIRECT",
+            "BR_INST_RETIRED.NEAR_CALL",
+            "BR_INST_RETIRED.NEAR_RETURN",
+            "BR_INST_RETIRED.NEAR_TAKEN",
+            "BR_MISP_RETIRED.NEAR_TAKEN",
+            "BACLEARS.ANY",
+            "MACHINE_CLEARS.COUNT",
+            "INT_MISC.CLEAR_RESTEER_CYCLES",
+            "INT_MISC.RECOVERY_CYCLES",
+        ],
+        sysfs_events: &[],
+        metrics: &[
+            (
+                "branch-mispredict-rate",
+                "BR_MISP_RETIRED.ALL_BRANCHES / BR_INST_RETIRED.ALL_BRANCHES",
+            ),
+            (
+                "branch-mpki",
+                "BR_MISP_RETIRED.ALL_BRANCHES * 1000 / INST_RETIRED.ANY",
+            ),
+            (
+                "conditional-mispredict-rate",
+                "BR_MISP_RETIRED.CONDITIONAL / BR_INST_RETIRED.CONDITIONAL",
+            ),
+            (
+                "branch-share",
+                "BR_INST_RETIRED.ALL_BRANCHES / INST_RETIRED.ANY",
+            ),
+            (
+                "machine-clears-pki",
+                "MACHINE_CLEARS.COUNT * 1000 / INST_RETIRED.ANY",
+            ),
+            (
+                "recovery-share",
+                "INT_MISC.RECOVERY_CYCLES / CPU_CLK_UNHALTED.THREAD",
+            ),
+        ],
+    },
+    Preset {
+        name: "io",
+        events: &[
+            "INST_RETIRED.ANY",
+            "CPU_CLK_UNHALTED.THREAD",
+            "UNC_IIO_DATA_REQ_OF_CPU.MEM_READ.PART0",
+            "UNC_IIO_DATA_REQ_OF_CPU.MEM_READ.PART1",
+            "UNC_IIO_DATA_REQ_OF_CPU.MEM_READ.PART2",
+            "UNC_IIO_DATA_REQ_OF_CPU.MEM_READ.PART3",
+            "UNC_IIO_DATA_REQ_OF_CPU.MEM_WRITE.PART0",
+            "UNC_IIO_DATA_REQ_OF_CPU.MEM_WRITE.PART1",
+            "UNC_IIO_DATA_REQ_OF_CPU.MEM_WRITE.PART2",
+            "UNC_IIO_DATA_REQ_OF_CPU.MEM_WRITE.PART3",
+            "UNC_I_COHERENT_OPS.PCITOM",
+            "UNC_I_COHERENT_OPS.RFO",
+            "UNC_I_TRANSACTIONS.READS",
+            "UNC_I_TRANSACTIONS.WRITES",
+            "UNC_I_FAF_INSERTS",
+            "UNC_I_MISC1.LOST_FWD",
+            "UNC_CHA_TOR_INSERTS.IO_HIT",
+            "UNC_CHA_TOR_INSERTS.IO_MISS",
+        ],
+        sysfs_events: &[],
+        metrics: &[
+            // The IIO counts requests for 4 bytes:
+            (
+                "pcie-read-bytes",
+                "(UNC_IIO_DATA_REQ_OF_CPU.MEM_READ.PART0 + UNC_IIO_DATA_REQ_OF_CPU.MEM_READ.PART1 + UNC_IIO_DATA_REQ_OF_CPU.MEM_READ.PART2 + UNC_IIO_DATA_REQ_OF_CPU.MEM_READ.PART3) * 4",
+            ),
+            (
+                "pcie-write-bytes",
+                "(UNC_IIO_DATA_REQ_OF_CPU.MEM_WRITE.PART0 + UNC_IIO_DATA_REQ_OF_CPU.MEM_WRITE.PART1 + UNC_IIO_DATA_REQ_OF_CPU.MEM_WRITE.PART2 + UNC_IIO_DATA_REQ_OF_CPU.MEM_WRITE.PART3) * 4",
+            ),
+            (
+                "io-llc-miss-share",
+                "UNC_CHA_TOR_INSERTS.IO_MISS / (UNC_CHA_TOR_INSERTS.IO_HIT + UNC_CHA_TOR_INSERTS.IO_MISS)",
+            ),
+        ],
+    },
+];
+
+pub fn preset(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|p| p.name == name)
+}
+
+/// The expression of the metric `name` of a preset.
+pub fn metric(name: &str) -> Option<&'static str> {
+    PRESETS
+        .iter()
+        .flat_map(|p| p.metrics.iter())
+        .find(|m| m.0 == name)
+        .map(|m| m.1)
+}
+
+/// The metrics of all presets.
+pub fn metrics() -> Vec<(&'static str, &'static str)> {
+    PRESETS
+        .iter()
+        .flat_map(|p| p.metrics.iter().cloned())
+        .collect()
+}
+
+/// What metrics know of the machine `mt` next to the events: the MiB of the L2
+/// and of the L3 (on the first socket) a core has on average. Without the cache
+/// sizes there are none.
+pub fn machine_values(mt: &MachineTopology) -> Vec<(&'static str, f64)> {
+    let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+    let mut values = Vec::new();
+    if let Some(l2) = mt.l2_share_per_core() {
+        values.push(("L2_SHARE_MIB", mib(l2)));
+    }
+    let socket = mt.sockets().first().cloned();
+    if let Some(l3) = socket.and_then(|s| mt.l3_share_per_core(s)) {
+        values.push(("L3_SHARE_MIB", mib(l3)));
+    }
+    values
+}
+
+/// The events of `preset` this CPU has (and `skip_events` doesn't exclude). We
+/// measure without the others, unless the CPU has none of the events the preset
+/// is about.
+pub fn events(preset: &Preset, skip_events: &[String]) -> Vec<&'static EventDescription<'static>> {
+    let known = get_known_events();
+    let mut events = Vec::with_capacity(preset.events.len());
+    let mut missing = Vec::new();
+    for name in preset.events.iter() {
+        match known
+            .iter()
+            .find(|e| e.event_name == *name && !skip_events.iter().any(|s| s == *name))
+        {
+            Some(event) => events.push(*event),
+            None => missing.push(*name),
+        }
+    }
+    // The clocks and instructions alone don't answer anything:
+    let is_clock =
+        |name: &str| name.starts_with("CPU_CLK_UNHALTED.") || name.starts_with("INST_RETIRED.");
+    if events.iter().all(|e| is_clock(e.event_name)) {
+        error!(
+            "This CPU has none of the events of preset {} we can measure ({}).",
+            preset.name,
+            missing.join(", ")
+        );
+        summary::fail(Failure::Environment);
+    }
+    if !missing.is_empty() {
+        // Presets cover several microarchitectures, some events are always missing:
+        info!(
+            "Preset {} measures without {} (not an event of this CPU or excluded).",
+            preset.name,
+            missing.join(", ")
+        );
+    }
+    events
+}
+
+/// Adds the events of `preset` the kernel names to the `sysfs_events` of
+/// `settings` (those it has).
+pub fn add_sysfs_events(preset: &Preset, settings: &mut ProfileSettings) {
+    if preset.sysfs_events.is_empty() {
+        return;
+    }
+    let pmus = match pmu::read_pmus(Path::new(pmu::DEVICES)) {
+        Ok(pmus) => pmus,
+        Err(e) => {
+            warn!(
+                "Can't read the PMUs in {}, preset {} measures without {}: {}",
+                pmu::DEVICES,
+                preset.name,
+                preset
+                    .sysfs_events
+                    .iter()
+                    .map(|e| e.1)
+                    .collect::<Vec<&str>>()
+                    .join(", "),
+                e
+            );
+            return;
+        }
+    };
+    for &(event, name) in preset.sysfs_events.iter() {
+        match pmu::resolve(event, &pmus) {
+            Ok(mut resolved) => {
+                resolved.name = String::from(name);
+                settings.sysfs_events.push(resolved);
+            }
+            Err(e) => warn!("Preset {} measures without {}: {}", preset.name, name, e),
+        }
+    }
+}
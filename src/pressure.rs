@@ -0,0 +1,187 @@
+//! Samples how much memory the machine has left and how hard the kernel works
+//! to find more while perf measures: the reclaim, swap, compaction and NUMA
+//! counters of `/proc/vmstat`, the free, cached and dirty memory of
+//! `/proc/meminfo` and the pressure stall information (PSI) of
+//! `/proc/pressure/{cpu,memory,io}`. We read them at the interval of perf, so a
+//! drop in IPC can be lined up with the time the program (or its neighbour)
+//! stalled on memory.
+//!
+//! Every measurement gets a `<nr>_pressure.csv` with one row per sample and
+//! metric: `time` (seconds since the measurement started), `source` (`vmstat`,
+//! `meminfo`, `psi_cpu`, `psi_memory` or `psi_io`), `metric` and `value`.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+/// The counters of /proc/vmstat we keep (by prefix), the others are about the
+/// state of the page allocator and too many to sample.
+const VMSTAT_COUNTERS: [&str; 15] = [
+    "pgfault",
+    "pgmajfault",
+    "pswpin",
+    "pswpout",
+    "pgscan_kswapd",
+    "pgscan_direct",
+    "pgsteal_kswapd",
+    "pgsteal_direct",
+    "allocstall_",
+    "compact_stall",
+    "thp_fault_",
+    "numa_hit",
+    "numa_miss",
+    "numa_hint_faults",
+    "workingset_refault_",
+];
+
+/// The lines of /proc/meminfo we keep.
+const MEMINFO_FIELDS: [&str; 10] = [
+    "MemFree",
+    "MemAvailable",
+    "Cached",
+    "Dirty",
+    "Writeback",
+    "AnonPages",
+    "AnonHugePages",
+    "Shmem",
+    "Slab",
+    "SwapFree",
+];
+
+/// The resources the kernel reports pressure for.
+const PSI_RESOURCES: [&str; 3] = ["cpu", "memory", "io"];
+
+/// (time, source, metric, value)
+pub type Sample = (f64, String, String, f64);
+
+/// `pgfault 123` lines of the counters we keep, the counters are totals since boot.
+fn parse_vmstat(content: &str) -> Vec<(String, f64)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let (counter, value) = (parts.next()?, parts.next()?);
+            if !VMSTAT_COUNTERS.iter().any(|c| counter.starts_with(c)) {
+                return None;
+            }
+            f64::from_str(value)
+                .ok()
+                .map(|v| (String::from(counter), v))
+        })
+        .collect()
+}
+
+/// `MemFree:  1234 kB` lines of the fields we keep, in bytes.
+fn parse_meminfo(content: &str) -> Vec<(String, f64)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let field = parts.next()?.trim_end_matches(':');
+            if !MEMINFO_FIELDS.contains(&field) {
+                return None;
+            }
+            let value = f64::from_str(parts.next()?).ok()?;
+            match parts.next() {
+                Some("kB") => Some((String::from(field), value * 1024.0)),
+                _ => Some((String::from(field), value)),
+            }
+        })
+        .collect()
+}
+
+/// `some avg10=0.00 avg60=0.00 avg300=0.00 total=977528` as `some_avg10`,
+/// `some_total` (the microseconds tasks stalled since boot) etc.
+fn parse_psi(content: &str) -> Vec<(String, f64)> {
+    let mut metrics = Vec::new();
+    for line in content.lines() {
+        let mut parts = line.split_whitespace();
+        let kind = match parts.next() {
+            Some(kind) => kind,
+            None => continue,
+        };
+        for value in parts {
+            let mut kv = value.splitn(2, '=');
+            if let (Some(key), Some(Ok(v))) = (kv.next(), kv.next().map(f64::from_str)) {
+                if key == "avg10" || key == "total" {
+                    metrics.push((format!("{}_{}", kind, key), v));
+                }
+            }
+        }
+    }
+    metrics
+}
+
+/// One sample of every metric at `time`.
+fn sample(time: f64, samples: &mut Vec<Sample>) {
+    let mut add = |source: &str, metrics: Vec<(String, f64)>| {
+        samples.extend(
+            metrics
+                .into_iter()
+                .map(|(metric, value)| (time, String::from(source), metric, value)),
+        );
+    };
+    if let Ok(content) = fs::read_to_string("/proc/vmstat") {
+        add("vmstat", parse_vmstat(&content));
+    }
+    if let Ok(content) = fs::read_to_string("/proc/meminfo") {
+        add("meminfo", parse_meminfo(&content));
+    }
+    // There is no /proc/pressure without CONFIG_PSI (or with psi=0):
+    for resource in PSI_RESOURCES.iter() {
+        if let Ok(content) = fs::read_to_string(format!("/proc/pressure/{}", resource)) {
+            add(&format!("psi_{}", resource), parse_psi(&content));
+        }
+    }
+}
+
+pub struct PressureSampler {
+    stop: mpsc::Sender<()>,
+    sampler: thread::JoinHandle<Vec<Sample>>,
+}
+
+impl PressureSampler {
+    /// Samples all metrics every `interval` until `finish` is called.
+    pub fn start(interval: Duration) -> PressureSampler {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let sampler = thread::spawn(move || {
+            let start = Instant::now();
+            let mut samples: Vec<Sample> = Vec::new();
+            loop {
+                sample(start.elapsed().as_secs_f64(), &mut samples);
+                match stopped.recv_timeout(interval) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            }
+            // The state at the end of the measurement:
+            sample(start.elapsed().as_secs_f64(), &mut samples);
+            samples
+        });
+
+        PressureSampler { stop, sampler }
+    }
+
+    /// Stops sampling and saves the time series to `save_to`.
+    pub fn finish(self, save_to: &Path) -> io::Result<Vec<Sample>> {
+        let _ = self.stop.send(());
+        let samples = self.sampler.join().unwrap_or_default();
+        debug!("Took {} memory pressure samples.", samples.len());
+
+        let to_io_error = |e: csv::Error| io::Error::other(e);
+        let mut wtr = csv::Writer::from_file(save_to).map_err(to_io_error)?;
+        wtr.encode(("time", "source", "metric", "value"))
+            .map_err(to_io_error)?;
+        for s in samples.iter() {
+            wtr.encode(s).map_err(to_io_error)?;
+        }
+        wtr.flush().map_err(to_io_error)?;
+
+        Ok(samples)
+    }
+}
@@ -0,0 +1,161 @@
+//! The scheduling class a program of a `pair` campaign runs with (`priority` of
+//! a program in the manifest), for studies of how much priority protects a
+//! program from (or exposes it to) interference:
+//!
+//! * `nice:<n>`: the normal scheduler with nice level n (-20 to 19).
+//! * `fifo:<p>`, `rr:<p>`: real-time priority p with SCHED_FIFO or SCHED_RR. We
+//!   stay below the kernel's threaded interrupts (priority 50) and refuse to run
+//!   without the kernel's real-time throttling, otherwise a program that never
+//!   sleeps keeps everything else off its CPUs for good.
+//! * `idle`: SCHED_IDLE, only runs if nothing else wants the CPU (for antagonists
+//!   that should soak up idle time).
+//!
+//! B gets its class right after it was spawned, A before perf starts it (perf
+//! runs with the class of A too, it's asleep most of the time). Threads inherit
+//! the class. Anything but a higher nice level needs root (or CAP_SYS_NICE).
+use std::fmt;
+use std::fs;
+use std::io;
+
+use super::privilege::has_capability;
+
+/// The highest real-time priority we hand out.
+pub const MAX_RT_PRIORITY: i32 = 49;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Priority {
+    Nice(i32),
+    Fifo(i32),
+    RoundRobin(i32),
+    Idle,
+}
+
+fn parse_level(level: &str, spec: &str, min: i32, max: i32) -> Result<i32, String> {
+    match level.trim().parse::<i32>() {
+        Ok(level) if level >= min && level <= max => Ok(level),
+        _ => Err(format!(
+            "'{}' in priority '{}' should be between {} and {}",
+            level, spec, min, max
+        )),
+    }
+}
+
+/// The capability to raise priorities and change scheduling classes.
+const CAP_SYS_NICE: u32 = 23;
+
+impl Priority {
+    pub fn new(spec: &str) -> Result<Priority, String> {
+        let mut parts = spec.trim().splitn(2, ':');
+        let (class, level) = (parts.next().unwrap(), parts.next());
+        match (class, level) {
+            ("nice", Some(level)) => Ok(Priority::Nice(parse_level(level, spec, -20, 19)?)),
+            ("fifo", Some(level)) => Ok(Priority::Fifo(parse_level(
+                level,
+                spec,
+                1,
+                MAX_RT_PRIORITY,
+            )?)),
+            ("rr", Some(level)) => Ok(Priority::RoundRobin(parse_level(
+                level,
+                spec,
+                1,
+                MAX_RT_PRIORITY,
+            )?)),
+            ("idle", None) => Ok(Priority::Idle),
+            _ => Err(format!(
+                "unknown priority '{}' (there are nice:<n>, fifo:<p>, rr:<p> and idle)",
+                spec
+            )),
+        }
+    }
+
+    /// Can we give a program this class? Everything but a higher nice level needs
+    /// CAP_SYS_NICE, real-time classes also the kernel's real-time throttling
+    /// (kernel.sched_rt_runtime_us isn't -1).
+    pub fn check(&self) -> Result<(), String> {
+        let privileged = match *self {
+            Priority::Nice(level) => level < 0,
+            Priority::Fifo(_) | Priority::RoundRobin(_) | Priority::Idle => true,
+        };
+        if privileged && !has_capability(CAP_SYS_NICE) {
+            return Err(format!("priority '{}' needs root (or CAP_SYS_NICE)", self));
+        }
+        if let Priority::Fifo(_) | Priority::RoundRobin(_) = *self {
+            let runtime =
+                fs::read_to_string("/proc/sys/kernel/sched_rt_runtime_us").unwrap_or_default();
+            if runtime.trim() == "-1" {
+                return Err(format!(
+                    "priority '{}' needs real-time throttling, but kernel.sched_rt_runtime_us is -1",
+                    self
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The priority in a directory name, e.g. `nice10` or `fifo20`.
+    pub fn dir_name(&self) -> String {
+        self.to_string().replace(':', "")
+    }
+
+    /// Gives the task `tid` (0 is the calling thread) this class. Only does a
+    /// system call, so it's fine to use in `pre_exec`.
+    #[cfg(target_os = "linux")]
+    pub fn set(&self, tid: i32) -> io::Result<()> {
+        let r = match *self {
+            Priority::Nice(level) => unsafe {
+                libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, level)
+            },
+            Priority::Fifo(_) | Priority::RoundRobin(_) | Priority::Idle => {
+                let (policy, level) = match *self {
+                    Priority::Fifo(level) => (libc::SCHED_FIFO, level),
+                    Priority::RoundRobin(level) => (libc::SCHED_RR, level),
+                    _ => (libc::SCHED_IDLE, 0),
+                };
+                let param = libc::sched_param {
+                    sched_priority: level,
+                };
+                unsafe { libc::sched_setscheduler(tid, policy, &param) }
+            }
+        };
+        if r != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn set(&self, _tid: i32) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("can't set priority '{}' on this OS", self),
+        ))
+    }
+
+    /// Gives process `pid` and all of its threads this class.
+    pub fn apply(&self, pid: u32) -> io::Result<()> {
+        let tasks: Vec<i32> = fs::read_dir(format!("/proc/{}/task", pid))
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter_map(|e| e.file_name().to_string_lossy().parse::<i32>().ok())
+                    .collect()
+            })
+            .unwrap_or(vec![pid as i32]);
+        for tid in tasks {
+            self.set(tid)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Priority::Nice(level) => write!(f, "nice:{}", level),
+            Priority::Fifo(level) => write!(f, "fifo:{}", level),
+            Priority::RoundRobin(level) => write!(f, "rr:{}", level),
+            Priority::Idle => write!(f, "idle"),
+        }
+    }
+}
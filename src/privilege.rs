@@ -0,0 +1,126 @@
+//! What we may do on this machine. Without root, perf can count system-wide
+//! (`-a`, and the uncore PMUs) if `perf_event_paranoid` is -1 or 0 or we have
+//! CAP_PERFMON, but some of the collectors need more than that:
+//!
+//! * `turbostat` and `pcm` read the MSRs (`/dev/cpu/*/msr`, root and the msr
+//!   module), as do the SMI counts of `throttling.csv`,
+//! * `bpf` loads eBPF programs (root or CAP_BPF),
+//! * `cpuid` and `likwid-topology` may not be allowed to read the CPUID devices.
+//!
+//! We find out before a `profile` or `pair` starts: collectors we can't run are
+//! turned off and the captures of the machine information that fail are left
+//! out, with a warning in `summary.json` either way, instead of stopping the
+//! campaign halfway through. If perf may not count system-wide, it counts the
+//! program only (`ProfileSettings::per_process`). Only if perf can't count at
+//! all we stop (exit code 3, see `profile::check_perf_access`).
+use std::fs::{self, File};
+use std::str::FromStr;
+
+use super::profile::{ProfileSettings, Scope};
+use super::summary;
+
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_PERFMON: u32 = 38;
+const CAP_BPF: u32 = 39;
+
+/// Do we have `capability` (the effective capabilities in /proc/self/status)?
+pub fn has_capability(capability: u32) -> bool {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find(|l| l.starts_with("CapEff:"))
+                .and_then(|l| u64::from_str_radix(l["CapEff:".len()..].trim(), 16).ok())
+        })
+        .is_some_and(|caps| caps & (1 << capability) != 0)
+}
+
+/// What we may do.
+#[derive(Debug, Clone, Copy)]
+pub struct Privileges {
+    pub root: bool,
+    /// CAP_PERFMON (or CAP_SYS_ADMIN, which has it on older kernels).
+    pub perfmon: bool,
+    pub bpf: bool,
+    /// `/proc/sys/kernel/perf_event_paranoid` (None if we can't read it).
+    pub paranoid: Option<i64>,
+    /// Can we read the MSRs?
+    pub msr: bool,
+}
+
+impl Privileges {
+    pub fn detect() -> Privileges {
+        let root = unsafe { libc::geteuid() } == 0;
+        let admin = has_capability(CAP_SYS_ADMIN);
+        Privileges {
+            root,
+            perfmon: admin || has_capability(CAP_PERFMON),
+            bpf: admin || has_capability(CAP_BPF),
+            paranoid: fs::read_to_string("/proc/sys/kernel/perf_event_paranoid")
+                .ok()
+                .and_then(|p| i64::from_str(p.trim()).ok()),
+            msr: File::open("/dev/cpu/0/msr").is_ok(),
+        }
+    }
+
+    /// May perf count every CPU (and the uncore PMUs)?
+    pub fn system_wide(&self) -> bool {
+        self.root || self.perfmon || self.paranoid.is_some_and(|p| p <= 0)
+    }
+
+    /// May perf count the processes we start (up to 2, with 2 only in user space)?
+    pub fn per_process(&self) -> bool {
+        self.system_wide() || self.paranoid.is_none_or(|p| p <= 2)
+    }
+}
+
+/// Turns off the collectors of `settings` we aren't allowed to run, with a
+/// warning for each (see the module).
+pub fn degrade(settings: &mut ProfileSettings) {
+    let privileges = Privileges::detect();
+    if !privileges.msr {
+        summary::warn(String::from(
+            "Can't read /dev/cpu/0/msr (needs root and the msr module), SMIs aren't counted",
+        ));
+    }
+    if privileges.root {
+        return;
+    }
+    if !privileges.system_wide() && privileges.per_process() {
+        summary::warn(format!(
+            "perf_event_paranoid is {} and we don't have CAP_PERFMON, perf counts the program \
+             only (without -a, -C and the uncore events)",
+            privileges
+                .paranoid
+                .map_or(String::from("unknown"), |p| p.to_string())
+        ));
+        settings.per_process = true;
+        // perf only splits the counts up (-A, --per-core, --per-thread, ...) system-wide:
+        if settings.scope != Scope::Program {
+            summary::warn(format!(
+                "perf can't count per {} for the program only, counting the program as a whole",
+                settings.scope
+            ));
+            settings.scope = Scope::Program;
+        }
+    }
+    if settings.turbostat {
+        summary::warn(String::from(
+            "turbostat needs root, frequencies and C-states aren't recorded (turbostat.csv)",
+        ));
+        settings.turbostat = false;
+    }
+    if settings.pcm && !privileges.msr {
+        summary::warn(String::from(
+            "PCM needs root or read access to /dev/cpu/*/msr, pcm.csv isn't recorded",
+        ));
+        settings.pcm = false;
+    }
+    if settings.bpf && !privileges.bpf {
+        summary::warn(String::from(
+            "eBPF needs root or CAP_BPF, OS interference isn't recorded",
+        ));
+        settings.bpf = false;
+    }
+}
@@ -4,21 +4,59 @@ use std::collections::HashMap;
 use csv;
 use lazy_static::lazy_static;
 use pbr::ProgressBar;
+use schemars::JsonSchema;
 use std::error;
 use std::error::Error;
 use std::fmt;
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
+use std::os::unix::process::CommandExt;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::{Command, Output, Stdio};
 use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
 use x86::cpuid;
 use x86::perfcnt::intel::{events, Counter, EventDescription, MSRIndex, PebsType, Tuple};
 
+use super::binaries::BinaryTracker;
+#[cfg(feature = "bpf")]
+use super::bpf::BpfCollector;
+use super::collectors;
+use super::coretypes::save_core_types;
+use super::dmesg::{self, KernelLog};
+use super::errata;
+use super::gpu::save_gpu_topology;
+use super::housekeeping;
+use super::intervals::IntervalCompressor;
+use super::iostat::{self, DiskStats, IoTracker};
+use super::kallsyms;
+use super::llc;
+use super::machine::save_machine_manifest;
+use super::memtier::save_memory_tiers;
+use super::nic::save_nic_topology;
+use super::output::{Capture, OutputSettings, FIGURE_OF_MERIT_FILE};
+use super::pcm::Pcm;
+use super::perfstat;
+use super::phases::{PhaseListener, PHASE_MARKER_ENV};
+use super::pmu::{self, SysfsEvent};
+use super::pressure::PressureSampler;
+use super::priority::Priority;
+use super::privilege::Privileges;
+use super::resctrl;
+use super::steady::{Gate, SteadyState, RATE_ENV};
+use super::summary::{self, Failure};
+use super::threads::ThreadTracker;
+use super::throttle::Throttling;
+use super::turbostat::Turbostat;
 use super::util::*;
-use log::*;
+use super::watch;
+use super::workload::Workload;
+use tracing::{debug, error, info, trace, warn};
 
 lazy_static! {
 
@@ -39,60 +77,43 @@ lazy_static! {
         let cpu_counter = cpuid.get_performance_monitoring_info().map_or(0, |info| info.number_of_counters()) as usize;
         let mut res = HashMap::with_capacity(11);
         res.insert(MonitoringUnit::CPU, cpu_counter);
-        let (family, model) = cpuid.get_feature_info().map_or((0,0), |fi| (fi.family_id(), ((fi.extended_model_id() as u8) << 4) | fi.model_id() as u8));
-
-        let ctr_config = include_str!("counters.toml");
-        let mut parser = toml::Parser::new(ctr_config);
 
-        let doc = match parser.parse() {
-            Some(doc) => doc,
+        match counter_limits("programmable_counters") {
+            Some(limits) => res.extend(limits),
             None => {
-                error!("Can't parse the counter configuration file:\n{:?}", parser.errors);
-                std::process::exit(9);
-            }
-        };
-
-        trace!("Trying to find architecture for family = {:#x} model = {:#x}", family, model);
-        let mut found: bool = false;
-        for (name, architecture) in doc {
-            let architecture = architecture.as_table().expect("counters.toml architectures must be a table");
-            let cfamily = &architecture["family"];
-            for cmodel in architecture["models"].as_slice().expect("counters.toml models must be a list.") {
-                let cfamily = cfamily.as_integer().expect("Family must be int.") as u8;
-                let cmodel = cmodel.as_integer().expect("Model must be int.") as u8;
-                if family == cfamily && model == cmodel {
-                    trace!("Running on {}, reading MonitoringUnit limits from config", name);
-                    found = true;
-
-                    // TODO: We should ideally get both, prgrammable and fixed counters:
-                    for (unit, limit) in architecture["programmable_counters"].as_table().expect("programmable_counters must be a table") {
-                        let unit = MonitoringUnit::new(unit.as_str());
-                        let limit = limit.as_integer().expect("Counter limit should be an integer");
-                        res.insert(unit, limit as usize);
-                    }
-                }
+                let (family, model) = family_model();
+                warn!("Didn't recogize this architecture so we can't infer #counters for MonitoringUnit (Please update counters.toml for family = {:#x} model = {:#x})", family, model);
+                res.insert(MonitoringUnit::UBox, 4);
+                res.insert(MonitoringUnit::HA, 4);
+                res.insert(MonitoringUnit::IRP, 4);
+                res.insert(MonitoringUnit::PCU, 4);
+                res.insert(MonitoringUnit::R2PCIe, 4);
+                res.insert(MonitoringUnit::R3QPI, 4);
+                res.insert(MonitoringUnit::QPI, 4);
+                res.insert(MonitoringUnit::CBox, 2);
+                res.insert(MonitoringUnit::IMC, 4);
+                res.insert(MonitoringUnit::Arb, 2);
+                res.insert(MonitoringUnit::M2M, 4);
+                res.insert(MonitoringUnit::CHA, 4);
+                res.insert(MonitoringUnit::M3UPI, 4);
+                res.insert(MonitoringUnit::IIO, 4);
+                res.insert(MonitoringUnit::UPI_LL, 4);
             }
         }
 
-        if !found {
-            warn!("Didn't recogize this architecture so we can't infer #counters for MonitoringUnit (Please update counters.toml for family = {:#x} model = {:#x})", family, model);
-            res.insert(MonitoringUnit::UBox, 4);
-            res.insert(MonitoringUnit::HA, 4);
-            res.insert(MonitoringUnit::IRP, 4);
-            res.insert(MonitoringUnit::PCU, 4);
-            res.insert(MonitoringUnit::R2PCIe, 4);
-            res.insert(MonitoringUnit::R3QPI, 4);
-            res.insert(MonitoringUnit::QPI, 4);
-            res.insert(MonitoringUnit::CBox, 2);
-            res.insert(MonitoringUnit::IMC, 4);
-            res.insert(MonitoringUnit::Arb, 2);
-            res.insert(MonitoringUnit::M2M, 4);
-            res.insert(MonitoringUnit::CHA, 4);
-            res.insert(MonitoringUnit::M3UPI, 4);
-            res.insert(MonitoringUnit::IIO, 4);
-            res.insert(MonitoringUnit::UPI_LL, 4);
-        }
+        res
+    };
 
+    /// The fixed counters of every MonitoringUnit (instructions, cycles and
+    /// ref-cycles on the core), events that have one don't take a programmable counter.
+    static ref FIXED_COUNTERS: HashMap<MonitoringUnit, usize> = {
+        let cpuid = cpuid::CpuId::new();
+        let cpu_counter = cpuid.get_performance_monitoring_info().map_or(0, |info| info.fixed_function_counters()) as usize;
+        let mut res = HashMap::with_capacity(3);
+        res.insert(MonitoringUnit::CPU, cpu_counter);
+        if let Some(limits) = counter_limits("fixed_counters") {
+            res.extend(limits);
+        }
         res
     };
 
@@ -157,13 +178,360 @@ lazy_static! {
     };
 }
 
+/// At which granularity `perf stat` reports the counter values.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, JsonSchema)]
+#[schemars(rename_all = "lowercase")]
+#[derive(Default)]
+pub enum Scope {
+    /// Every CPU separately (`-A`)
+    #[default]
+    Cpu,
+    /// Summed up per physical core (`--per-core`)
+    Core,
+    /// Summed up per socket (`--per-socket`)
+    Socket,
+    /// Summed up per NUMA node (`--per-node`)
+    Node,
+    /// Every thread of the program separately (`--per-thread`)
+    Thread,
+    /// The program as a whole, perf doesn't aggregate (if it may only count the
+    /// program, see `privilege::degrade`)
+    Program,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Scope::Cpu => write!(f, "cpu"),
+            Scope::Core => write!(f, "core"),
+            Scope::Socket => write!(f, "socket"),
+            Scope::Node => write!(f, "node"),
+            Scope::Thread => write!(f, "thread"),
+            Scope::Program => write!(f, "program"),
+        }
+    }
+}
+
+impl Scope {
+    pub fn new(what: &str) -> Scope {
+        match what {
+            "cpu" => Scope::Cpu,
+            "core" => Scope::Core,
+            "socket" => Scope::Socket,
+            "node" => Scope::Node,
+            "thread" => Scope::Thread,
+            _ => panic!("clap-rs should ensure nothing else is passed..."),
+        }
+    }
+
+    /// The perf stat argument to get values at this granularity.
+    fn perf_arg(&self) -> Option<&'static str> {
+        match *self {
+            Scope::Cpu => Some("-A"),
+            Scope::Core => Some("--per-core"),
+            Scope::Socket => Some("--per-socket"),
+            Scope::Node => Some("--per-node"),
+            Scope::Thread => Some("--per-thread"),
+            Scope::Program => None,
+        }
+    }
+
+    /// Figures out the scope of a measurement from the perf command we used (see `perf.csv`).
+    pub fn from_perf_command(perf_command: &str) -> Scope {
+        let args: Vec<&str> = perf_command.split_whitespace().collect();
+        for scope in [Scope::Core, Scope::Socket, Scope::Node, Scope::Thread].iter() {
+            if scope.perf_arg().is_some_and(|arg| args.contains(&arg)) {
+                return *scope;
+            }
+        }
+        // Only perf stat for the program leaves out -A (the other backends never write it):
+        if args.windows(2).any(|w| w == ["perf", "stat"]) && !args.contains(&"-A") {
+            Scope::Program
+        } else {
+            Scope::Cpu
+        }
+    }
+}
+
+/// What counts the events (`--backend`).
+#[derive(Debug, Eq, PartialEq, Clone, Copy, RustcEncodable)]
+pub enum Backend {
+    /// Linux perf
+    Perf,
+    /// PAPI (see `papi.rs`, needs the papi feature)
+    Papi,
+    /// FreeBSD's pmcstat (see `pmcstat.rs`)
+    Pmcstat,
+}
+
+impl Default for Backend {
+    fn default() -> Backend {
+        if cfg!(target_os = "freebsd") {
+            Backend::Pmcstat
+        } else {
+            Backend::Perf
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Backend::Perf => write!(f, "perf"),
+            Backend::Papi => write!(f, "papi"),
+            Backend::Pmcstat => write!(f, "pmcstat"),
+        }
+    }
+}
+
+impl Backend {
+    pub fn new(what: &str) -> Backend {
+        match what {
+            "perf" => Backend::Perf,
+            "papi" => Backend::Papi,
+            "pmcstat" => Backend::Pmcstat,
+            _ => panic!("clap-rs should ensure nothing else is passed..."),
+        }
+    }
+}
+
+/// Options that control how (rather than what) we measure with perf.
+#[derive(Debug, Default, Clone)]
+pub struct ProfileSettings {
+    /// Only enable the counters this long after the program was started.
+    pub measure_after: Option<Duration>,
+    /// Stop measuring once the counters were enabled for this long.
+    ///
+    /// # Note
+    /// perf terminates the program when we stop it, so this is meant
+    /// for long-running workloads (servers etc.).
+    pub measure_for: Option<Duration>,
+    /// Listen for phase markers written by the program (see `phases.rs`).
+    pub phase_markers: bool,
+    /// Compress the intervals of perf stat while it writes them (see `intervals.rs`).
+    pub compress_intervals: bool,
+    /// The granularity at which perf reports counter values.
+    pub scope: Scope,
+    /// Record frequencies, C-states and temperatures with turbostat.
+    pub turbostat: bool,
+    /// Record memory bandwidth, UPI utilization and C-states with Intel PCM.
+    pub pcm: bool,
+    /// Record off-CPU time, run-queue latency and system calls with eBPF (see `bpf.rs`).
+    pub bpf: bool,
+    /// Count the program only (perf without `-a` and `-C`, no uncore events),
+    /// we may not count system-wide (see `privilege::degrade`).
+    pub per_process: bool,
+    /// Store the machine information files only once in this directory (see `dedup_captures`).
+    pub capture_store: Option<PathBuf>,
+    /// Only count on these CPUs (instead of all of them).
+    pub cpus: Option<Vec<u64>>,
+    /// What counts the events.
+    pub backend: Backend,
+    /// Events named by the kernel (see `pmu.rs`), measured after the events of
+    /// our tables.
+    pub sysfs_events: Vec<SysfsEvent>,
+    /// Events of our tables we don't measure (see `preflight.rs`, `errata.rs`
+    /// and `pmu::prefer_free_running`).
+    pub skip_events: Vec<String>,
+    /// perf's metric groups (`-M`), measured on their own after the events.
+    pub metric_groups: Vec<String>,
+    /// When sampling, the period of every event is set so it's sampled about
+    /// this often per second (see `calibrate_periods`), instead of `-F 4`.
+    pub samples_per_second: Option<u64>,
+    /// Only enable the counters once the program is in its steady state (see
+    /// `steady.rs`), instead of after `measure_after`.
+    pub steady_state: Option<SteadyState>,
+    /// The scheduling class perf and the program run with (see `priority.rs`).
+    pub priority: Option<Priority>,
+    /// How we save what the program writes (see `output.rs`).
+    pub output: OutputSettings,
+    /// Move the program (but not perf or us) into the resctrl group with this
+    /// `tasks` file once perf forked it (see `resctrl::assign_program`).
+    pub resctrl_tasks: Option<PathBuf>,
+}
+
+impl ProfileSettings {
+    /// How long perf is allowed to run before we stop it (if at all).
+    ///
+    /// With a steady state we don't know that in advance, the `Gate` stops perf.
+    fn stop_after(&self) -> Option<Duration> {
+        if self.steady_state.is_some() {
+            return None;
+        }
+        self.measure_for
+            .map(|d| d + self.measure_after.unwrap_or(Duration::from_secs(0)))
+    }
+}
+
+/// Software events we always count next to the hardware events: unexplained variance
+/// in counter values is very often caused by context switches or migrations.
+pub const SOFTWARE_EVENTS: [&str; 3] = ["cs", "migrations", "page-faults"];
+
+/// How often perf stat reports the counter values (`-I`), in milliseconds.
+pub const INTERVAL_MS: u64 = 250;
+
+/// How long we count the events of a measurement to calibrate their sampling
+/// periods.
+const PILOT_DURATION: Duration = Duration::from_secs(2);
+
+/// What we record of the program while perf counts, the files are where it goes
+/// (the default records nothing).
+#[derive(Default)]
+struct Recording<'a> {
+    /// All threads the program spawns.
+    threads_file: Option<&'a Path>,
+    /// The binaries the program executes or loads.
+    binaries_file: Option<&'a Path>,
+    /// What eBPF saw of the program.
+    bpf_file: Option<&'a Path>,
+    /// What the program's processes read and wrote.
+    io_file: Option<&'a Path>,
+    /// Enables the counters once the program is steady, `steady_file` says when.
+    gate: Option<Gate>,
+    steady_file: Option<&'a Path>,
+    /// stdout and stderr of the program, as it writes them (see `output.rs`).
+    output_files: Option<(&'a Path, &'a Path)>,
+}
+
+impl<'a> Recording<'a> {
+    fn is_empty(&self) -> bool {
+        self.threads_file.is_none()
+            && self.binaries_file.is_none()
+            && self.bpf_file.is_none()
+            && self.io_file.is_none()
+            && self.gate.is_none()
+            && self.output_files.is_none()
+    }
+}
+
+/// Runs perf to completion, or interrupts it with SIGINT once the measurement
+/// window of `settings` elapsed (perf will then write out the last interval and
+/// terminate the program).
+///
+/// While it runs, we record what `recording` asks for. If it captures the output,
+/// we also return the figure of merit the program reported. With a resctrl group
+/// in `settings`, the program joins it.
+fn run_perf(
+    perf: &mut Command,
+    recording: Recording,
+    settings: &ProfileSettings,
+) -> std::io::Result<(Output, Option<f64>)> {
+    let stop_after = settings.stop_after();
+    let resctrl_tasks = settings.resctrl_tasks.as_deref();
+    if stop_after.is_none() && resctrl_tasks.is_none() && recording.is_empty() {
+        return perf.output().map(|out| (out, None));
+    }
+
+    let mut child = perf
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let pid = child.id();
+    if let Some(tasks) = resctrl_tasks {
+        if let Err(e) = resctrl::assign_program(tasks, pid) {
+            error!(
+                "Can't move the program into resctrl group {:?}: {}",
+                tasks, e
+            );
+        }
+    }
+    let capture = recording
+        .output_files
+        .map(|(stdout, stderr)| Capture::start(&mut child, stdout, stderr, &settings.output));
+    let tracker = recording.threads_file.map(|_| ThreadTracker::start(pid));
+    let binary_tracker = recording.binaries_file.map(|_| BinaryTracker::start(pid));
+    let io_tracker = recording.io_file.map(|_| IoTracker::start(pid));
+    let waiter = recording.gate.and_then(|gate| match gate.start(pid) {
+        Ok(waiter) => Some(waiter),
+        Err(e) => {
+            error!(
+                "Can't wait for the steady state, the counters stay disabled: {}",
+                e
+            );
+            None
+        }
+    });
+    #[cfg(feature = "bpf")]
+    let bpf_collector = recording
+        .bpf_file
+        .and_then(|_| match BpfCollector::start(pid) {
+            Ok(collector) => Some(collector),
+            Err(e) => {
+                error!("Can't start the BPF programs (are you root?): {}", e);
+                None
+            }
+        });
+    let watchdog = stop_after.map(|window| {
+        let (done, finished) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            if let Err(mpsc::RecvTimeoutError::Timeout) = finished.recv_timeout(window) {
+                debug!("Measurement window of {:?} is over, stopping perf.", window);
+                unsafe {
+                    libc::kill(pid as libc::pid_t, libc::SIGINT);
+                }
+            }
+        });
+        (done, handle)
+    });
+
+    let out = match capture {
+        Some(capture) => child.wait().map(|status| {
+            let captured = capture.finish();
+            let out = Output {
+                status,
+                stdout: captured.stdout,
+                stderr: captured.stderr,
+            };
+            (out, captured.merit)
+        }),
+        None => child.wait_with_output().map(|out| (out, None)),
+    };
+    if let Some((done, handle)) = watchdog {
+        let _ = done.send(());
+        let _ = handle.join();
+    }
+    if let (Some(tracker), Some(path)) = (tracker, recording.threads_file) {
+        if let Err(e) = tracker.finish(path) {
+            error!("Can't save the program's threads to {:?}: {}", path, e);
+        }
+    }
+    if let (Some(tracker), Some(path)) = (binary_tracker, recording.binaries_file) {
+        if let Err(e) = tracker.finish(path) {
+            error!("Can't save the program's binaries to {:?}: {}", path, e);
+        }
+    }
+    if let (Some(tracker), Some(path)) = (io_tracker, recording.io_file) {
+        if let Err(e) = tracker.finish(path) {
+            error!("Can't save the program's I/O to {:?}: {}", path, e);
+        }
+    }
+    if let (Some(waiter), Some(path)) = (waiter, recording.steady_file) {
+        if let Err(e) = waiter.finish(path) {
+            error!("Can't save the steady state to {:?}: {}", path, e);
+        }
+    }
+    #[cfg(feature = "bpf")]
+    {
+        if let (Some(collector), Some(path)) = (bpf_collector, recording.bpf_file) {
+            if let Err(e) = collector.finish(path) {
+                error!("Can't save what BPF recorded to {:?}: {}", path, e);
+            }
+        }
+    }
+
+    out
+}
+
 fn execute_perf(
     perf: &mut Command,
     cmd: &Vec<String>,
     counters: &Vec<String>,
     datafile: &Path,
+    recording: Recording,
+    settings: &ProfileSettings,
     dryrun: bool,
-) -> (String, String, String) {
+) -> (String, String, String, Option<f64>) {
     assert!(cmd.len() >= 1);
     let perf = perf.arg("-o").arg(datafile.as_os_str());
     let events: Vec<String> = counters.iter().map(|c| format!("-e {}", c)).collect();
@@ -172,9 +540,9 @@ fn execute_perf(
     let perf = perf.args(cmd.as_slice());
     let perf_cmd_str: String = format!("{:?}", perf).replace("\"", "");
 
-    let (stdout, stderr) = if !dryrun {
-        match perf.output() {
-            Ok(out) => {
+    let (stdout, stderr, merit) = if !dryrun {
+        match run_perf(perf, recording, settings) {
+            Ok((out, merit)) => {
                 let stdout =
                     String::from_utf8(out.stdout).unwrap_or(String::from("Unable to read stdout!"));
                 let stderr =
@@ -188,6 +556,14 @@ fn execute_perf(
                         "perf command: {} got unknown exit status was: {}",
                         perf_cmd_str, out.status
                     );
+                    // perf refuses to open the events before it starts the program:
+                    if stderr.contains("perf_event_paranoid")
+                        || stderr.contains("Permission denied")
+                    {
+                        summary::record(Failure::CounterAccess);
+                    } else {
+                        summary::record(Failure::Workload);
+                    }
                     debug!("stdout:\n{}", stdout);
                     debug!("stderr:\n{}", stderr);
                 }
@@ -200,32 +576,107 @@ fn execute_perf(
                     );
                 }
 
-                (stdout, stderr)
+                (stdout, stderr, merit)
             }
             Err(err) => {
                 error!("Executing {} failed : {}", perf_cmd_str, err);
-                (String::new(), String::new())
+                (String::new(), String::new(), None)
             }
         }
     } else {
         warn!("Dry run mode -- would execute: {}", perf_cmd_str);
-        (String::new(), String::new())
+        (String::new(), String::new(), None)
     };
 
-    (perf_cmd_str, stdout, stderr)
+    (perf_cmd_str, stdout, stderr, merit)
 }
 
 pub fn create_out_directory(out_dir: &Path) {
     if !out_dir.exists() {
-        std::fs::create_dir(out_dir).expect("Can't create `out` directory");
+        std::fs::create_dir_all(out_dir).expect("Can't create `out` directory");
     }
 }
 
+/// The family and model of the CPU we run on.
+fn family_model() -> (u8, u8) {
+    let cpuid = cpuid::CpuId::new();
+    cpuid.get_feature_info().map_or((0, 0), |fi| {
+        (
+            fi.family_id(),
+            (fi.extended_model_id() << 4) | fi.model_id(),
+        )
+    })
+}
+
+/// The counters (`kind` is `programmable_counters` or `fixed_counters`) of every
+/// MonitoringUnit of the CPU we run on, as counters.toml lists them.
+fn counter_limits(kind: &str) -> Option<HashMap<MonitoringUnit, usize>> {
+    let (family, model) = family_model();
+
+    let ctr_config = include_str!("counters.toml");
+    let mut parser = toml::Parser::new(ctr_config);
+
+    let doc = match parser.parse() {
+        Some(doc) => doc,
+        None => {
+            error!(
+                "Can't parse the counter configuration file:\n{:?}",
+                parser.errors
+            );
+            std::process::exit(9);
+        }
+    };
+
+    trace!(
+        "Trying to find architecture for family = {:#x} model = {:#x}",
+        family,
+        model
+    );
+    for (name, architecture) in doc {
+        let architecture = architecture
+            .as_table()
+            .expect("counters.toml architectures must be a table");
+        let cfamily = &architecture["family"];
+        for cmodel in architecture["models"]
+            .as_slice()
+            .expect("counters.toml models must be a list.")
+        {
+            let cfamily = cfamily.as_integer().expect("Family must be int.") as u8;
+            let cmodel = cmodel.as_integer().expect("Model must be int.") as u8;
+            if family == cfamily && model == cmodel {
+                trace!(
+                    "Running on {}, reading MonitoringUnit {} from config",
+                    name,
+                    kind
+                );
+                let mut res = HashMap::with_capacity(11);
+                if let Some(limits) = architecture.get(kind) {
+                    for (unit, limit) in limits.as_table().expect("counter limits must be a table")
+                    {
+                        let unit = MonitoringUnit::new(unit.as_str());
+                        let limit = limit
+                            .as_integer()
+                            .expect("Counter limit should be an integer");
+                        res.insert(unit, limit as usize);
+                    }
+                }
+                return Some(res);
+            }
+        }
+    }
+
+    None
+}
+
+/// The events of this CPU by name (the table's own order changes with every
+/// version of it, measurements and their files shouldn't).
 pub fn get_known_events<'a>() -> Vec<&'a EventDescription<'static>> {
-    events()
+    let mut known: Vec<&EventDescription> = events()
         .expect("No performance events found?")
         .values()
-        .collect()
+        .collect();
+    known.sort_by_key(|e| e.event_name);
+    known
 }
 
 #[allow(non_camel_case_types)]
@@ -473,6 +924,14 @@ impl<'a, 'b> PerfEvent<'a, 'b> {
     }
 
     /// Get the correct counter mask
+    /// Does this event count on a fixed counter?
+    pub fn is_fixed(&self) -> bool {
+        match self.counter() {
+            Counter::Fixed(_) => true,
+            Counter::Programmable(_) => false,
+        }
+    }
+
     pub fn counter(&self) -> Counter {
         if *HT_AVAILABLE || self.is_uncore() {
             self.0.counter
@@ -605,8 +1064,13 @@ impl<'a, 'b> PerfEvent<'a, 'b> {
             PerfEvent::push_arg(&mut ret, String::from("any=1"));
         }
 
-        if self.match_filter("CBoFilter0[23:17]") {
-            PerfEvent::push_arg(&mut ret, String::from("filter_state=0x1f"));
+        let llc_filter = llc::configured().unwrap_or_default();
+        match llc_filter.state_arg(self.0) {
+            Some(arg) => PerfEvent::push_arg(&mut ret, arg),
+            None if self.match_filter("CBoFilter0[23:17]") => {
+                PerfEvent::push_arg(&mut ret, String::from("filter_state=0x1f"));
+            }
+            None => {}
         }
 
         if self.match_filter("CBoFilter1[15:0]") {
@@ -614,10 +1078,18 @@ impl<'a, 'b> PerfEvent<'a, 'b> {
             PerfEvent::push_arg(&mut ret, String::from("filter_nid=0x3"));
         }
 
-        if self.match_filter("CBoFilter1[28:20]") {
-            // TOR events requires filter_opc
-            // Set to: 0x192 PrefData Prefetch Data into LLC but don’t pass to L2. Includes Hints
-            PerfEvent::push_arg(&mut ret, String::from("filter_opc=0x192"));
+        match llc_filter.opc_arg(self.0) {
+            Some(arg) => PerfEvent::push_arg(&mut ret, arg),
+            None if self.match_filter("CBoFilter1[28:20]") => {
+                // TOR events requires filter_opc
+                // Set to: 0x192 PrefData Prefetch Data into LLC but don’t pass to L2. Includes Hints
+                PerfEvent::push_arg(&mut ret, String::from("filter_opc=0x192"));
+            }
+            None => {}
+        }
+
+        for arg in llc_filter.tid_args(self.0) {
+            PerfEvent::push_arg(&mut ret, arg);
         }
 
         ret
@@ -715,14 +1187,20 @@ where
 {
     events: Vec<PerfEvent<'a, 'b>>,
     limits: &'a HashMap<MonitoringUnit, usize>,
+    fixed_limits: &'a HashMap<MonitoringUnit, usize>,
 }
 
 impl<'a, 'b> PerfEventGroup<'a, 'b> {
-    /// Make a new performance event group.
-    pub fn new(unit_sizes: &'a HashMap<MonitoringUnit, usize>) -> PerfEventGroup {
+    /// Make a new performance event group for units with `unit_sizes`
+    /// programmable and `fixed_sizes` fixed counters.
+    pub fn new(
+        unit_sizes: &'a HashMap<MonitoringUnit, usize>,
+        fixed_sizes: &'a HashMap<MonitoringUnit, usize>,
+    ) -> PerfEventGroup<'a, 'b> {
         PerfEventGroup {
             events: Default::default(),
             limits: unit_sizes,
+            fixed_limits: fixed_sizes,
         }
     }
 
@@ -736,6 +1214,32 @@ impl<'a, 'b> PerfEventGroup<'a, 'b> {
         self.events.iter().filter(|e| e.unit() == unit).collect()
     }
 
+    /// Does `event` take one of the fixed counters of its unit? If we don't
+    /// know of fixed counters for the unit, fixed events take programmable
+    /// counters (as they used to).
+    fn uses_fixed(&self, event: &PerfEvent) -> bool {
+        event.is_fixed() && *self.fixed_limits.get(&event.unit()).unwrap_or(&0) > 0
+    }
+
+    /// Returns the events of a unit that use the same kind of counter (fixed or
+    /// programmable) as `event`.
+    fn events_like(&self, event: &PerfEvent) -> Vec<&PerfEvent<'_, '_>> {
+        self.events_by_unit(event.unit())
+            .into_iter()
+            .filter(|c| self.uses_fixed(c) == self.uses_fixed(event))
+            .collect()
+    }
+
+    /// How many counters of the kind `event` needs its unit has.
+    fn limit_for(&self, event: &PerfEvent) -> usize {
+        let limits = if self.uses_fixed(event) {
+            self.fixed_limits
+        } else {
+            self.limits
+        };
+        *limits.get(&event.unit()).unwrap_or(&0)
+    }
+
     /// Backtracking algorithm to find assigment of events to available counters
     /// while respecting the counter constraints every event has.
     /// The events passed here should all have the same counter type
@@ -802,20 +1306,10 @@ impl<'a, 'b> PerfEventGroup<'a, 'b> {
     /// Check if this event conflicts with the counter requirements
     /// of events already in this group
     fn has_counter_constraint_conflicts(&self, new_event: &PerfEvent) -> bool {
-        let unit = new_event.unit();
-        let unit_limit = *self.limits.get(&unit).unwrap_or(&0);
-        //error!("unit = {:?} unit_limit {:?}", unit, unit_limit);
+        let unit_limit = self.limit_for(new_event);
 
         // Get all the events that share the same counters as new_event:
-        let mut events: Vec<&PerfEvent> = self
-            .events_by_unit(unit)
-            .into_iter()
-            .filter(|c| match (c.counter(), new_event.counter()) {
-                (Counter::Programmable(_), Counter::Programmable(_)) => true,
-                (Counter::Fixed(_), Counter::Fixed(_)) => true,
-                _ => false,
-            })
-            .collect();
+        let mut events: Vec<&PerfEvent> = self.events_like(new_event);
 
         events.push(new_event);
         PerfEventGroup::find_counter_assignment(0, unit_limit, events, Vec::new()).is_none()
@@ -845,6 +1339,8 @@ impl<'a, 'b> PerfEventGroup<'a, 'b> {
     ///
     /// Things we consider correctly right now:
     /// * Fixed amount of counters per monitoring unit (so we don't multiplex).
+    /// * Events with a fixed counter (instructions, cycles, ref-cycles) don't
+    ///   take one of the programmable counters.
     /// * Some events can only use some counters.
     /// * Taken alone attribute of the events.
     ///
@@ -858,11 +1354,9 @@ impl<'a, 'b> PerfEventGroup<'a, 'b> {
         }
 
         // 2. Check we don't measure more events than we have counters
-        // for on the repspective units
-        let unit = event.unit();
-        let unit_limit = *self.limits.get(&unit).unwrap_or(&0);
-        if self.events_by_unit(unit).len() >= unit_limit {
-            return Err(AddEventError::UnitCapacityReached(unit));
+        // for on the repspective units (fixed and programmable counters are separate)
+        if self.events_like(&event).len() >= self.limit_for(&event) {
+            return Err(AddEventError::UnitCapacityReached(event.unit()));
         }
 
         // 3. Now, consider the counter <-> event mapping constraints:
@@ -921,13 +1415,6 @@ impl<'a, 'b> PerfEventGroup<'a, 'b> {
                 continue;
             }
 
-            // TODO: handle fixed counters
-            // fixed_counters = {
-            //    "inst_retired.any": (0xc0, 0, 0),
-            //    "cpu_clk_unhalted.thread": (0x3c, 0, 0),
-            //    "cpu_clk_unhalted.thread_any": (0x3c, 0, 1),
-            // }
-
             // Adding offcore event:
             if event.is_offcore() {
                 assert!(devices.len() == 1);
@@ -997,27 +1484,15 @@ impl<'a, 'b> PerfEventGroup<'a, 'b> {
     }
 }
 
-/// Given a list of events, create a list of event groups that can be measured together.
-pub fn schedule_events<'a, 'b>(events: Vec<&'a EventDescription<'b>>) -> Vec<PerfEventGroup<'a, 'b>>
+/// Puts every event in the first group it fits in (in the order of `events`).
+fn first_fit<'a, 'b>(events: Vec<&'a EventDescription<'b>>) -> Vec<PerfEventGroup<'a, 'b>>
 where
     'b: 'a,
 {
     let mut groups: Vec<PerfEventGroup> = Vec::with_capacity(42);
 
     for event in events {
-        if IGNORE_EVENTS.contains_key(event.event_name) {
-            continue;
-        }
-
-        let perf_event: PerfEvent = PerfEvent(event);
         let mut added: Result<(), AddEventError> = Err(AddEventError::ErrataConflict);
-        match perf_event.unit() {
-            MonitoringUnit::Unknown => {
-                info!("Ignoring event with unknown unit '{}'", event);
-                continue;
-            }
-            _ => (),
-        };
 
         // Try to add the event to an existing group:
         for group in groups.iter_mut() {
@@ -1030,52 +1505,152 @@ where
 
         // Unable to add event to any existing group, make a new group instead:
         if !added.is_ok() {
-            let mut pg = PerfEventGroup::new(&*PMU_COUNTERS);
+            let mut pg = PerfEventGroup::new(&PMU_COUNTERS, &FIXED_COUNTERS);
             let perf_event: PerfEvent = PerfEvent(event);
 
             let added = pg.add_event(perf_event);
-            match added {
-                Err(e) => {
-                    let perf_event: PerfEvent = PerfEvent(event);
-                    panic!(
-                        "Can't add a new event {:?} to an empty group: {:?}",
-                        perf_event, e
-                    );
-                }
-                Ok(_) => (),
+            if let Err(e) = added {
+                let perf_event: PerfEvent = PerfEvent(event);
+                panic!(
+                    "Can't add a new event {:?} to an empty group: {:?}",
+                    perf_event, e
+                );
             };
 
             groups.push(pg);
         }
     }
 
-    // println!("{:?}", groups);
     groups
 }
 
+/// How hard it is to find a group for `event`: events that are measured alone
+/// come first, then offcore events (two fit in a group), then events that can
+/// only use some of the counters (the fewer the harder).
+fn constraints(event: &EventDescription) -> (bool, bool, u32) {
+    let perf_event = PerfEvent(event);
+    let alone =
+        event.taken_alone || event.errata.is_some() || ISOLATE_EVENTS.contains(&event.event_name);
+    let mask = match perf_event.counter() {
+        Counter::Programmable(mask) => mask,
+        Counter::Fixed(mask) => mask,
+    };
+    (!alone, !perf_event.is_offcore(), mask.count_ones())
+}
+
+/// Given a list of events, create a list of event groups that can be measured together.
+///
+/// Every group is a run of the program, so we want as few as possible: putting
+/// the events in the first group they fit in wastes counters if the events that
+/// are hard to place come last. We also place them hardest first (first-fit
+/// decreasing, which gets close to the fewest groups there can be) and take the
+/// better of the two.
+pub fn schedule_events<'a, 'b>(events: Vec<&'a EventDescription<'b>>) -> Vec<PerfEventGroup<'a, 'b>>
+where
+    'b: 'a,
+{
+    let events: Vec<&EventDescription> = events
+        .into_iter()
+        .filter(|event| !IGNORE_EVENTS.contains_key(event.event_name))
+        .filter(|event| match PerfEvent(event).unit() {
+            MonitoringUnit::Unknown => {
+                info!("Ignoring event with unknown unit '{}'", event);
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    if events.len() <= 1 {
+        return first_fit(events);
+    }
+
+    let mut sorted = events.clone();
+    sorted.sort_by_key(|e| constraints(e));
+    let naive = first_fit(events);
+    let packed = first_fit(sorted);
+    if packed.len() < naive.len() {
+        info!(
+            "Measuring the events in {} runs instead of {} (in the order given).",
+            packed.len(),
+            naive.len()
+        );
+        packed
+    } else {
+        debug!("Measuring the events in {} runs.", naive.len());
+        naive
+    }
+}
+
+/// One CPU of every socket, one of `cpus` where it has one: the uncore PMUs count
+/// for the whole socket, so perf counts them once on each socket instead of on
+/// every CPU, whichever CPU the kernel lets it count on.
+fn socket_cpus(mt: &MachineTopology, cpus: Option<&Vec<Cpu>>) -> Vec<Cpu> {
+    mt.sockets()
+        .into_iter()
+        .filter_map(|socket| {
+            let on_socket = mt.cpus_on_socket(socket);
+            on_socket
+                .iter()
+                .find(|c| cpus.is_some_and(|cpus| cpus.contains(&c.cpu)))
+                .or(on_socket.first())
+                .map(|c| c.cpu)
+        })
+        .collect()
+}
+
 pub fn get_perf_command(
     cmd_working_dir: &str,
     _output_path: &Path,
     env: &Vec<(String, String)>,
     breakpoints: &Vec<String>,
     record: bool,
+    json: bool,
+    settings: &ProfileSettings,
 ) -> Command {
     let mut perf = Command::new("perf");
     perf.current_dir(cmd_working_dir);
+    housekeeping::release(&mut perf);
+    if let Some(priority) = settings.priority {
+        // The program inherits it from perf:
+        unsafe {
+            perf.pre_exec(move || priority.set(0));
+        }
+    }
     let _filename: String;
     if !record {
         perf.arg("stat");
-        perf.arg("-aA");
-        perf.arg("-I 250");
-        perf.arg("-x ;");
+        if !settings.per_process {
+            perf.arg("-a");
+            if let Some(ref cpus) = settings.cpus {
+                let cpus: Vec<String> = cpus.iter().map(|c| c.to_string()).collect();
+                perf.arg("-C");
+                perf.arg(cpus.join(","));
+            }
+        }
+        if let Some(arg) = settings.scope.perf_arg() {
+            perf.arg(arg);
+        }
+        perf.arg("-I").arg(INTERVAL_MS.to_string());
+        // JSON if perf has it (see `perfstat.rs`):
+        match json {
+            true => perf.arg("-j"),
+            false => perf.args(["-x", ";"]),
+        };
     } else {
         perf.arg("record");
         perf.arg("--group");
         perf.arg("-F 4");
-        perf.arg("-a");
+        if !settings.per_process {
+            perf.arg("-a");
+        }
         perf.arg("--raw-samples");
     }
 
+    // Don't start counting until the program had some time to warm up:
+    if let Some(delay) = settings.measure_after {
+        perf.arg(format!("-D {}", delay.as_millis()));
+    }
+
     // Ensure we use dots as number separators in csv output (see issue #1):
     perf.env("LC_NUMERIC", "C");
 
@@ -1089,6 +1664,153 @@ pub fn get_perf_command(
     perf
 }
 
+/// The name perf stat reports for `counter` (its `name=` term).
+fn counter_name(counter: &str) -> Option<&str> {
+    let start = counter.find("name=")? + "name=".len();
+    let rest = &counter[start..];
+    let end = rest.find([',', '/']).unwrap_or(rest.len());
+    Some(&rest[..end])
+}
+
+/// `counter` sampled every `period` events (a term overrides `-F`).
+fn with_period(counter: &str, period: u64) -> String {
+    match counter.rfind('/') {
+        Some(idx) if idx > 0 => format!("{},period={}{}", &counter[..idx], period, &counter[idx..]),
+        _ => String::from(counter),
+    }
+}
+
+/// Counts the `counters` of measurement `nr` for a short while (saving
+/// `<nr>_pilot.csv`) and returns them with the sampling period that gets each of
+/// them sampled about `samples_per_second` (of `settings`) times per second. Rare
+/// events are sampled on every occurrence, counters perf didn't count keep `-F`.
+fn calibrate_periods(
+    cmd_working_dir: &str,
+    output_path: &Path,
+    env: &Vec<(String, String)>,
+    cmd: &Vec<String>,
+    counters: &Vec<String>,
+    nr: usize,
+    settings: &ProfileSettings,
+) -> Vec<String> {
+    let samples_per_second = match settings.samples_per_second {
+        Some(rate) => rate,
+        None => return counters.clone(),
+    };
+    let pilot_settings = ProfileSettings {
+        measure_for: Some(PILOT_DURATION),
+        steady_state: None,
+        ..settings.clone()
+    };
+    let mut pilot_path = output_path.to_path_buf();
+    pilot_path.push(format!("{}_pilot.csv", nr));
+    let mut perf = get_perf_command(
+        cmd_working_dir,
+        output_path,
+        env,
+        &Vec::new(),
+        false,
+        false,
+        &pilot_settings,
+    );
+    let _ = execute_perf(
+        &mut perf,
+        cmd,
+        counters,
+        &pilot_path,
+        Recording::default(),
+        &pilot_settings,
+        false,
+    );
+
+    let mut totals: HashMap<String, u64> = HashMap::new();
+    let mut seconds: f64 = 0.0;
+    if let Ok(counts) = perfstat::counts(&pilot_path, settings.scope) {
+        for count in counts {
+            seconds = seconds.max(count.time);
+            if let Some(value) = count.count() {
+                *totals.entry(count.event).or_insert(0) += value;
+            }
+        }
+    }
+    if seconds <= 0.0 {
+        warn!(
+            "Measurement {}: the pilot run counted nothing, sampling with -F instead.",
+            nr
+        );
+        return counters.clone();
+    }
+
+    counters
+        .iter()
+        .map(|counter| {
+            let total = counter_name(counter).and_then(|name| totals.get(name));
+            match total {
+                Some(&total) if total > 0 => {
+                    let rate = total as f64 / seconds;
+                    let period = std::cmp::max(1, (rate / samples_per_second as f64) as u64);
+                    debug!(
+                        "{} happens {:.0} times per second, sampling every {}.",
+                        counter, rate, period
+                    );
+                    with_period(counter, period)
+                }
+                _ => counter.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Saves what we know about the machine (the captures of the collectors, cache sizes, memory tiers, core types, GPUs, NICs, clock,
+/// `machine.json`) in `output_path`, or just once in `capture_store` if given.
+/// Without the topology (`numactl.dat`, `lscpu.csv`, see `collectors.rs`) and the
+/// clock we can't make sense of the results, the other captures are left out with
+/// a warning if they fail (a tool isn't installed, or needs root, see
+/// `privilege.rs`).
+pub fn save_machine_information(output_path: &Path, capture_store: Option<&PathBuf>) {
+    collectors::collect(output_path).expect("Can't save the machine facts");
+    let optional = |what: &str, saved: io::Result<()>| {
+        if let Err(e) = saved {
+            summary::warn(format!("Can't save {}, going on without it: {}", what, e));
+        }
+    };
+    optional("memory tiers", save_memory_tiers(output_path).map(|_| ()));
+    optional("core types", save_core_types(output_path).map(|_| ()));
+    optional("cache sizes", save_cache_sizes(output_path).map(|_| ()));
+    optional("GPU topology", save_gpu_topology(output_path).map(|_| ()));
+    optional("NIC topology", save_nic_topology(output_path).map(|_| ()));
+    let clock = save_clock_info(output_path).expect("Can't save clock information");
+    save_machine_manifest(output_path, clock).expect("Can't save machine.json");
+    if let Some(store) = capture_store {
+        dedup_captures(output_path, store, &collectors::files())
+            .expect("Can't deduplicate machine information");
+    }
+}
+
+/// Creates `perf.csv` in `output_path`, the log of every measurement of a run
+/// that `aggregate` goes through.
+pub fn perf_log_writer(output_path: &Path) -> csv::Writer<File> {
+    let mut perf_log = PathBuf::new();
+    perf_log.push(output_path);
+    perf_log.push("perf.csv");
+
+    let mut wtr = csv::Writer::from_file(perf_log).unwrap();
+    let r = wtr.encode((
+        "command",
+        "event_names",
+        "perf_events",
+        "breakpoints",
+        "datafile",
+        "perf_command",
+        "stdout",
+        "stdin",
+        "schema_version",
+        "started",
+    ));
+    assert!(r.is_ok());
+    wtr
+}
+
 pub fn profile<'a, 'b>(
     output_path: &Path,
     cmd_working_dir: &str,
@@ -1097,13 +1819,122 @@ pub fn profile<'a, 'b>(
     breakpoints: Vec<String>,
     record: bool,
     events: Option<Vec<&'a EventDescription<'b>>>,
+    settings: &ProfileSettings,
     dryrun: bool,
 ) where
     'b: 'a,
 {
-    let event_groups = match events {
-        Some(evts) => schedule_events(evts),
-        None => schedule_events(get_known_events()),
+    #[cfg(feature = "papi")]
+    {
+        if settings.backend == Backend::Papi && !record {
+            let names = events.map(|evts| {
+                evts.iter()
+                    .map(|e| String::from(e.event_name))
+                    .collect::<Vec<String>>()
+            });
+            super::papi::profile(
+                output_path,
+                cmd_working_dir,
+                cmd,
+                env,
+                names,
+                settings,
+                dryrun,
+            );
+            return;
+        }
+    }
+
+    if settings.backend == Backend::Pmcstat && !record {
+        super::pmcstat::profile(
+            output_path,
+            cmd_working_dir,
+            cmd,
+            env,
+            match events {
+                Some(evts) => evts,
+                None => get_known_events(),
+            },
+            settings,
+            dryrun,
+        );
+        return;
+    }
+
+    let events: Vec<&EventDescription> = match events {
+        Some(evts) => evts,
+        None => get_known_events(),
+    };
+    let events: Vec<&EventDescription> = events
+        .into_iter()
+        .filter(|e| !settings.skip_events.iter().any(|s| s == e.event_name))
+        .filter(|e| !settings.per_process || e.unit.is_none())
+        .collect();
+    // The uncore PMUs only count system-wide:
+    let uncore = |e: &SysfsEvent| e.perf_events.iter().any(|p| p.starts_with("uncore_"));
+    let sysfs_events: Vec<SysfsEvent> = settings
+        .sysfs_events
+        .iter()
+        .filter(|e| !settings.per_process || !uncore(e))
+        .cloned()
+        .collect();
+    // What we measure, for the errata that apply to it:
+    let mut measured: Vec<(String, Option<&EventDescription>)> = events
+        .iter()
+        .map(|e| (String::from(e.event_name), Some(*e)))
+        .collect();
+    measured.extend(sysfs_events.iter().map(|e| (e.name.clone(), None)));
+    // Uncore events get measurements of their own, perf counts those on one CPU
+    // of every socket (see `socket_cpus`):
+    let (uncore, core): (Vec<&EventDescription>, Vec<&EventDescription>) =
+        events.into_iter().partition(|e| e.unit.is_some());
+    let mut event_groups = schedule_events(core);
+    event_groups.extend(schedule_events(uncore));
+    // The event names and perf events of every measurement:
+    let mut measurements: Vec<(Vec<String>, Vec<String>)> = event_groups
+        .iter()
+        .map(|group| {
+            (
+                group
+                    .get_event_names()
+                    .iter()
+                    .map(|n| String::from(*n))
+                    .collect(),
+                group.get_perf_config_strings(),
+            )
+        })
+        .collect();
+    // Free-running counters take no counter, they go with the first group:
+    let (free_running, counted): (Vec<&SysfsEvent>, Vec<&SysfsEvent>) =
+        sysfs_events.iter().partition(|e| e.free_running);
+    if !free_running.is_empty() && !measurements.is_empty() {
+        let first = &mut measurements[0];
+        first.0.extend(free_running.iter().map(|e| e.name.clone()));
+        first.1.extend(
+            free_running
+                .iter()
+                .flat_map(|e| e.perf_events.iter().cloned()),
+        );
+    }
+    let counted = if !measurements.is_empty() {
+        counted
+    } else {
+        sysfs_events.iter().collect()
+    };
+    // perf multiplexes them if there are more than it has counters for:
+    if !counted.is_empty() {
+        measurements.push((
+            counted.iter().map(|e| e.name.clone()).collect(),
+            pmu::perf_events(&counted),
+        ));
+    }
+
+    // perf picks the events of the metric groups itself:
+    let metric_measurement = if !settings.metric_groups.is_empty() && !record {
+        measurements.push((settings.metric_groups.clone(), Vec::new()));
+        Some(measurements.len() - 1)
+    } else {
+        None
     };
 
     // Is this run already done (in case we restart):
@@ -1119,67 +1950,451 @@ pub fn profile<'a, 'b>(
 
     create_out_directory(output_path);
     if !dryrun {
-        check_for_perf();
-        let ret = check_for_perf_permissions()
-            || check_for_disabled_nmi_watchdog()
-            || check_for_perf_paranoia();
-        if !ret {
-            std::process::exit(3);
-        }
-
-        let _ = save_numa_topology(&output_path).expect("Can't save NUMA topology");
-        let _ = save_cpu_topology(&output_path).expect("Can't save CPU topology");
-        let _ = save_lstopo(&output_path).expect("Can't save lstopo information");
-        let _ = save_cpuid(&output_path).expect("Can't save CPUID information");
-        let _ = save_likwid_topology(&output_path).expect("Can't save likwid information");
+        check_perf_access();
+        save_machine_information(output_path, settings.capture_store.as_ref());
     }
 
     assert!(cmd.len() >= 1);
-    let mut perf_log = PathBuf::new();
-    perf_log.push(output_path);
-    perf_log.push("perf.csv");
+    if !dryrun {
+        let mut workload_file = output_path.to_path_buf();
+        workload_file.push("workload.toml");
+        let r = Workload::new(&cmd, cmd_working_dir, &env).save(&workload_file);
+        if let Err(e) = r {
+            error!(
+                "Can't save workload information to {:?}: {}",
+                workload_file, e
+            );
+        }
+    }
+    if !dryrun {
+        match errata::save_errata(output_path, &measured) {
+            Ok(0) => {}
+            Ok(n) => info!(
+                "Errata apply to {} of the events we measure, see {}.",
+                n,
+                errata::ERRATA_FILE
+            ),
+            Err(e) => error!("Can't save {}: {}", errata::ERRATA_FILE, e),
+        }
+    }
 
-    let mut wtr = csv::Writer::from_file(perf_log).unwrap();
-    let r = wtr.encode((
-        "command",
-        "event_names",
-        "perf_events",
-        "breakpoints",
-        "datafile",
-        "perf_command",
-        "stdout",
-        "stdin",
-    ));
-    assert!(r.is_ok());
+    let mut wtr = perf_log_writer(output_path);
+
+    // Average frequency, C-state residencies etc. of every measurement:
+    let mut turbostat_wtr = if settings.turbostat && !dryrun {
+        let mut turbostat_log = output_path.to_path_buf();
+        turbostat_log.push("turbostat.csv");
+        let mut wtr = csv::Writer::from_file(turbostat_log).unwrap();
+        let r = wtr.encode(("datafile", "cpu", "metric", "value"));
+        assert!(r.is_ok());
+        Some(wtr)
+    } else {
+        None
+    };
+
+    // Memory bandwidth, UPI utilization and C-states of every socket:
+    let mut pcm_wtr = if settings.pcm && !dryrun {
+        let mut pcm_log = output_path.to_path_buf();
+        pcm_log.push("pcm.csv");
+        let mut wtr = csv::Writer::from_file(pcm_log).unwrap();
+        let r = wtr.encode(("datafile", "tool", "group", "metric", "value"));
+        assert!(r.is_ok());
+        Some(wtr)
+    } else {
+        None
+    };
+
+    // SMIs and thermal throttling that happened during a measurement:
+    let mut throttling_wtr = if !dryrun {
+        let mut throttling_log = output_path.to_path_buf();
+        throttling_log.push("throttling.csv");
+        let mut wtr = csv::Writer::from_file(throttling_log).unwrap();
+        let r = wtr.encode(("datafile", "smis", "core_throttles", "package_throttles"));
+        assert!(r.is_ok());
+        Some(wtr)
+    } else {
+        None
+    };
+
+    // What the program says about how well it did (see `output.rs`):
+    let mut merit_wtr = if !dryrun && settings.output.figure_of_merit.is_some() {
+        let mut wtr = csv::Writer::from_file(output_path.join(FIGURE_OF_MERIT_FILE)).unwrap();
+        let r = wtr.encode(("datafile", "name", "figure_of_merit"));
+        assert!(r.is_ok());
+        Some(wtr)
+    } else {
+        None
+    };
+
+    // What the block devices did during a measurement:
+    let mut io_wtr = if !dryrun {
+        let mut io_log = output_path.to_path_buf();
+        io_log.push("io.csv");
+        let mut wtr = csv::Writer::from_file(io_log).unwrap();
+        let r = wtr.encode(iostat::DEVICE_COLUMNS);
+        assert!(r.is_ok());
+        Some(wtr)
+    } else {
+        None
+    };
+
+    // Kernel samples can only be resolved with the kernel we sampled:
+    if record && !dryrun {
+        if let Err(e) = kallsyms::save(output_path) {
+            warn!("Can't save the kernel symbols: {}", e);
+        }
+    }
 
     // For warm-up do a dummy run of the program with perf
     let record_path = Path::new("/dev/null");
-    let mut perf = get_perf_command(cmd_working_dir, output_path, &env, &breakpoints, record);
+    let mut perf = get_perf_command(
+        cmd_working_dir,
+        output_path,
+        &env,
+        &breakpoints,
+        record,
+        false,
+        settings,
+    );
     perf.arg("-n"); // null run - don’t start any counters
-    let (_, _, _) = execute_perf(&mut perf, &cmd, &Vec::new(), &record_path, dryrun);
+    let (_, _, _, _) = execute_perf(
+        &mut perf,
+        &cmd,
+        &Vec::new(),
+        record_path,
+        Recording::default(),
+        settings,
+        dryrun,
+    );
     debug!("Warmup complete, let's start measuring.");
 
-    let mut pb = ProgressBar::new(event_groups.len() as u64);
+    // The dashboard shows the progress itself:
+    let mut pb = if !watch::active() {
+        Some(ProgressBar::new(measurements.len() as u64))
+    } else {
+        None
+    };
 
-    for (idx, group) in event_groups.iter().enumerate() {
+    let measurement_count = measurements.len();
+    // The CPUs of the uncore measurements (see `socket_cpus`):
+    let mut uncore_cpus: Option<Vec<Cpu>> = None;
+    for (idx, (mut event_names, mut counters)) in measurements.into_iter().enumerate() {
         if !dryrun {
-            pb.inc();
+            if let Some(pb) = pb.as_mut() {
+                pb.inc();
+            }
         }
 
-        let event_names: Vec<&str> = group.get_event_names();
-        let counters: Vec<String> = group.get_perf_config_strings();
+        let per_socket = !record
+            && !settings.per_process
+            && !counters.is_empty()
+            && counters.iter().all(|c| c.starts_with("uncore_"));
+        if !record {
+            // Software events don't occupy any PMU counters:
+            event_names.extend(SOFTWARE_EVENTS.iter().map(|e| String::from(*e)));
+            counters.extend(SOFTWARE_EVENTS.iter().map(|e| String::from(*e)));
+        }
+        if record && !dryrun && settings.samples_per_second.is_some() {
+            counters = calibrate_periods(
+                cmd_working_dir,
+                output_path,
+                &env,
+                &cmd,
+                &counters,
+                idx + 1,
+                settings,
+            );
+        }
 
         let mut record_path = PathBuf::new();
+        let json = !record && perfstat::json_supported();
         let filename = match record {
-            false => format!("{}_stat.csv", idx + 1),
+            false => perfstat::stat_file(idx + 1, json),
             true => format!("{}_perf.data", idx + 1),
         };
         record_path.push(output_path);
         record_path.push(&filename);
+        if !record {
+            watch::measurement(idx, measurement_count, &record_path, settings.scope);
+        }
+
+        let socket_settings;
+        let measurement_settings = if per_socket {
+            let cpus = uncore_cpus
+                .get_or_insert_with(|| socket_cpus(&MachineTopology::new(), settings.cpus.as_ref()))
+                .clone();
+            socket_settings = ProfileSettings {
+                cpus: Some(cpus),
+                ..settings.clone()
+            };
+            &socket_settings
+        } else {
+            settings
+        };
+        let mut perf = get_perf_command(
+            cmd_working_dir,
+            output_path,
+            &env,
+            &breakpoints,
+            record,
+            json,
+            measurement_settings,
+        );
+        if metric_measurement == Some(idx) {
+            perf.arg("-M").arg(settings.metric_groups.join(","));
+        }
+
+        let phase_listener = if settings.phase_markers && !dryrun {
+            let mut fifo = output_path.to_path_buf();
+            fifo.push("phases.fifo");
+            match PhaseListener::new(&fifo) {
+                Ok(listener) => {
+                    perf.env(PHASE_MARKER_ENV, listener.fifo());
+                    Some(listener)
+                }
+                Err(e) => {
+                    error!("Can't create phase marker FIFO {:?}: {}", fifo, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut threads_path = output_path.to_path_buf();
+        threads_path.push(format!("{}_threads.csv", idx + 1));
+        let threads_file = if settings.scope == Scope::Thread && !record {
+            Some(threads_path.as_path())
+        } else {
+            None
+        };
+
+        let turbostat = turbostat_wtr.as_ref().and_then(|_| {
+            let mut turbostat_path = output_path.to_path_buf();
+            turbostat_path.push(format!("{}_turbostat.txt", idx + 1));
+            match Turbostat::start(&turbostat_path) {
+                Ok(t) => Some(t),
+                Err(e) => {
+                    error!("Can't start turbostat: {}", e);
+                    None
+                }
+            }
+        });
+        let pcm = pcm_wtr.as_ref().and_then(|_| {
+            match Pcm::start(output_path, (idx + 1).to_string().as_str()) {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    error!("Can't start PCM: {}", e);
+                    None
+                }
+            }
+        });
+
+        // The binaries are the same for every run, so we only record them once:
+        let mut binaries_path = output_path.to_path_buf();
+        binaries_path.push("binaries.csv");
+        let binaries_file = if idx == 0 {
+            Some(binaries_path.as_path())
+        } else {
+            None
+        };
+
+        let mut bpf_path = output_path.to_path_buf();
+        bpf_path.push(format!("{}_bpf.csv", idx + 1));
+        let bpf_file = if settings.bpf && !record {
+            Some(bpf_path.as_path())
+        } else {
+            None
+        };
+
+        let mut io_path = output_path.to_path_buf();
+        io_path.push(format!("{}_pid_io.csv", idx + 1));
+        let io_file = if !dryrun {
+            Some(io_path.as_path())
+        } else {
+            None
+        };
+
+        // perf starts with disabled counters, we enable them once the program is steady:
+        let mut steady_path = output_path.to_path_buf();
+        steady_path.push(format!("{}_steady_state.csv", idx + 1));
+        let gate = match settings.steady_state {
+            Some(ref steady) if !dryrun => {
+                match Gate::new(
+                    output_path,
+                    steady,
+                    settings.cpus.clone(),
+                    settings.measure_for,
+                ) {
+                    Ok(gate) => {
+                        perf.args(gate.perf_args());
+                        if let Some(fifo) = gate.rate_fifo() {
+                            perf.env(RATE_ENV, fifo);
+                        }
+                        Some(gate)
+                    }
+                    Err(e) => {
+                        error!(
+                            "Can't create the perf control FIFOs in {:?}: {}",
+                            output_path, e
+                        );
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let steady_file = gate.as_ref().map(|_| steady_path.as_path());
+
+        let pressure = if !dryrun {
+            Some(PressureSampler::start(Duration::from_millis(INTERVAL_MS)))
+        } else {
+            None
+        };
+        let throttling_before = Throttling::now();
+        let disk_stats_before = DiskStats::now();
+        let kernel_log = if !dryrun {
+            Some(KernelLog::snapshot())
+        } else {
+            None
+        };
+        let compressor = if settings.compress_intervals && !record && !dryrun {
+            match IntervalCompressor::start(&record_path) {
+                Ok(compressor) => Some(compressor),
+                Err(e) => {
+                    error!("Can't compress the intervals of {:?}: {}", record_path, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let stdout_path = output_path.join(format!("{}_stdout.txt", idx + 1));
+        let stderr_path = output_path.join(format!("{}_stderr.txt", idx + 1));
+        let started = SystemTime::now();
+        let (executed_cmd, stdout, stdin, merit) = execute_perf(
+            &mut perf,
+            &cmd,
+            &counters,
+            record_path.as_path(),
+            Recording {
+                threads_file,
+                binaries_file,
+                bpf_file,
+                io_file,
+                gate,
+                steady_file,
+                output_files: Some((stdout_path.as_path(), stderr_path.as_path())),
+            },
+            settings,
+            dryrun,
+        );
+        if let Some(compressor) = compressor {
+            compressor.finish();
+        }
+        if let (Some(wtr), Some(fom)) =
+            (merit_wtr.as_mut(), settings.output.figure_of_merit.as_ref())
+        {
+            let merit = match fom.file {
+                Some(_) => fom.read_file(Path::new(cmd_working_dir), started),
+                None => merit,
+            };
+            match merit {
+                Some(merit) => {
+                    let r = wtr.encode((&filename, &fom.name, merit));
+                    assert!(r.is_ok());
+                    let r = wtr.flush();
+                    assert!(r.is_ok());
+                }
+                None => warn!(
+                    "The program didn't report its {} in measurement {}.",
+                    fom.name,
+                    idx + 1
+                ),
+            }
+        }
+        if let Some(kernel_log) = kernel_log {
+            let mut dmesg_path = output_path.to_path_buf();
+            dmesg_path.push(format!("{}_dmesg.txt", idx + 1));
+            let r = dmesg::save_delta(&kernel_log.since(), filename.as_str(), &dmesg_path);
+            if let Err(e) = r {
+                error!("Can't save kernel log to {:?}: {}", dmesg_path, e);
+            }
+        }
+        if let Some(pressure) = pressure {
+            let mut pressure_path = output_path.to_path_buf();
+            pressure_path.push(format!("{}_pressure.csv", idx + 1));
+            if let Err(e) = pressure.finish(&pressure_path) {
+                error!("Can't save memory pressure to {:?}: {}", pressure_path, e);
+            }
+        }
+        if let Some(wtr) = io_wtr.as_mut() {
+            let (devices, seconds) = DiskStats::since(&disk_stats_before);
+            let r = iostat::encode_devices(wtr, filename.as_str(), &devices, seconds);
+            assert!(r.is_ok());
+            let r = wtr.flush();
+            assert!(r.is_ok());
+        }
+        if let Some(wtr) = throttling_wtr.as_mut() {
+            let throttling = Throttling::since(&throttling_before);
+            throttling.warn(filename.as_str());
+            let r = wtr.encode((
+                &filename,
+                throttling.smis,
+                throttling.core_throttles,
+                throttling.package_throttles,
+            ));
+            assert!(r.is_ok());
+            let r = wtr.flush();
+            assert!(r.is_ok());
+        }
+
+        if let (Some(turbostat), Some(wtr)) = (turbostat, turbostat_wtr.as_mut()) {
+            match turbostat.finish() {
+                Ok(values) => {
+                    for (cpu, metric, value) in values {
+                        let r = wtr.encode((&filename, cpu, metric, value));
+                        assert!(r.is_ok());
+                    }
+                    let r = wtr.flush();
+                    assert!(r.is_ok());
+                }
+                Err(e) => error!("Can't read turbostat output: {}", e),
+            }
+        }
+
+        if let (Some(pcm), Some(wtr)) = (pcm, pcm_wtr.as_mut()) {
+            match pcm.finish() {
+                Ok(values) => {
+                    for (tool, group, metric, value) in values {
+                        let r = wtr.encode((&filename, tool, group, metric, value));
+                        assert!(r.is_ok());
+                    }
+                    let r = wtr.flush();
+                    assert!(r.is_ok());
+                }
+                Err(e) => error!("Can't read PCM output: {}", e),
+            }
+        }
+
+        if let Some(listener) = phase_listener {
+            let mut phases_path = output_path.to_path_buf();
+            phases_path.push(format!("{}_phases.csv", idx + 1));
+            match listener.finish(&phases_path) {
+                Ok(markers) => debug!("Program reported {} phase markers.", markers.len()),
+                Err(e) => error!("Can't save phase markers to {:?}: {}", phases_path, e),
+            }
+        }
+
+        // The perf events are the ones perf chose for the metric groups:
+        if metric_measurement == Some(idx) && !dryrun {
+            counters = perfstat::events(record_path.as_path(), settings.scope);
+            debug!(
+                "perf measured the metric groups with {}",
+                counters.join(",")
+            );
+        }
 
-        let mut perf = get_perf_command(cmd_working_dir, output_path, &env, &breakpoints, record);
-        let (executed_cmd, stdout, stdin) =
-            execute_perf(&mut perf, &cmd, &counters, record_path.as_path(), dryrun);
         if !dryrun {
             let r = wtr.encode(vec![
                 cmd.join(" "),
@@ -1190,6 +2405,8 @@ pub fn profile<'a, 'b>(
                 executed_cmd,
                 stdout,
                 stdin,
+                SCHEMA_VERSION.to_string(),
+                unix_time(started),
             ]);
             assert!(r.is_ok());
 
@@ -1202,6 +2419,23 @@ pub fn profile<'a, 'b>(
     let _ = File::create(completed_file.as_path()).unwrap();
 }
 
+/// Stops us if we can't use perf, or perf can't use the counters. Root and
+/// CAP_PERFMON count system-wide whatever the settings of the kernel say.
+pub fn check_perf_access() {
+    check_for_perf();
+    let privileges = Privileges::detect();
+    // Without system-wide access `privilege::degrade` has us count the program only:
+    if privileges.system_wide() || privileges.per_process() {
+        return;
+    }
+    let ret = check_for_perf_permissions()
+        || check_for_disabled_nmi_watchdog()
+        || check_for_perf_paranoia();
+    if !ret {
+        summary::fail(Failure::CounterAccess);
+    }
+}
+
 pub fn check_for_perf() {
     match Command::new("perf").output() {
         Ok(out) => {
@@ -1213,7 +2447,7 @@ pub fn check_for_perf() {
                     "You may require a restart after fixing this so \
                      `/sys/bus/event_source/devices` is updated!"
                 );
-                std::process::exit(2);
+                summary::fail(Failure::Environment);
             }
         }
         Err(_) => {
@@ -1225,7 +2459,7 @@ pub fn check_for_perf() {
                 "You may require a restart after fixing this so \
                  `/sys/bus/event_source/devices` is updated!"
             );
-            std::process::exit(2);
+            summary::fail(Failure::Environment);
         }
     }
 }
@@ -1262,7 +2496,7 @@ pub fn check_for_perf_permissions() -> bool {
 
         Err(why) => {
             error!("Couldn't read {}: {}", path.display(), why.description());
-            std::process::exit(3);
+            summary::fail(Failure::Environment);
         }
     }
 
@@ -1301,7 +2535,7 @@ pub fn check_for_disabled_nmi_watchdog() -> bool {
 
         Err(why) => {
             error!("Couldn't read {}: {}", path.display(), why.description());
-            std::process::exit(4);
+            summary::fail(Failure::Environment);
         }
     }
 
@@ -1339,7 +2573,7 @@ pub fn check_for_perf_paranoia() -> bool {
 
         Err(why) => {
             error!("Couldn't read {}: {}", path.display(), why.description());
-            std::process::exit(4);
+            summary::fail(Failure::Environment);
         }
     };
 
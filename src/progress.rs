@@ -0,0 +1,196 @@
+//! Keeps track of how far a `pair` campaign is. Before and after every run we
+//! print where we are (run index, elapsed time, ETA and failures) and save it to
+//! `progress.json` in the output directory, so external monitors can poll it.
+//!
+//! The ETA assumes the remaining runs take as long as the runs we completed on
+//! average (runs that were already done from an earlier invocation don't count),
+//! and that as many of them run side by side as we've seen so far.
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rustc_serialize::json;
+use tracing::error;
+
+use super::watch;
+
+/// The layout of `progress.json`, it has a version of its own.
+const PROGRESS_SCHEMA_VERSION: u64 = 1;
+
+/// How a run of the campaign ended.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Outcome {
+    Completed,
+    Failed,
+    /// It was completed by an earlier invocation.
+    Skipped,
+}
+
+/// What we save in `progress.json`.
+#[derive(RustcEncodable)]
+struct Report {
+    schema_version: u64,
+    /// Runs this invocation is supposed to do (see `--base` and `--step`).
+    total: usize,
+    /// Runs that ended (completed, failed or skipped).
+    done: usize,
+    completed: usize,
+    failed: usize,
+    skipped: usize,
+    /// The run we're working on (empty once we're done).
+    current: String,
+    elapsed_secs: u64,
+    eta_secs: Option<u64>,
+    /// Unix timestamp of this update.
+    updated: u64,
+}
+
+pub struct CampaignProgress {
+    save_to: PathBuf,
+    started: Instant,
+    total: usize,
+    completed: usize,
+    failed: usize,
+    skipped: usize,
+    /// How long the runs took that we did.
+    durations: Vec<Duration>,
+    /// The runs we're working on (more than one if they run side by side).
+    current: Vec<(String, Instant)>,
+    /// The most runs we saw running at the same time.
+    side_by_side: usize,
+}
+
+fn hms(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+impl CampaignProgress {
+    /// Progress of `total` runs, saved in `output_path/progress.json`.
+    pub fn new(output_path: &Path, total: usize) -> CampaignProgress {
+        let mut save_to = output_path.to_path_buf();
+        save_to.push("progress.json");
+        CampaignProgress {
+            save_to,
+            started: Instant::now(),
+            total,
+            completed: 0,
+            failed: 0,
+            skipped: 0,
+            durations: Vec::new(),
+            current: Vec::new(),
+            side_by_side: 1,
+        }
+    }
+
+    fn done(&self) -> usize {
+        self.completed + self.failed + self.skipped
+    }
+
+    fn eta(&self) -> Option<Duration> {
+        if self.durations.is_empty() {
+            return None;
+        }
+        let average = self.durations.iter().sum::<Duration>() / self.durations.len() as u32;
+        let remaining = self.total.saturating_sub(self.done()) as u32;
+        Some(average * remaining / self.side_by_side as u32)
+    }
+
+    /// The campaign has `runs` more runs than we thought (see `adaptive.rs`).
+    pub fn add_runs(&mut self, runs: usize) {
+        self.total += runs;
+    }
+
+    pub fn start_run(&mut self, name: &str) {
+        self.current.push((String::from(name), Instant::now()));
+        self.side_by_side = self.side_by_side.max(self.current.len());
+        self.update(true);
+    }
+
+    /// Run `name` (`None` if it was skipped before it started) ended.
+    pub fn finish_run(&mut self, name: Option<&str>, outcome: Outcome) {
+        let started = match self.current.iter().position(|c| Some(c.0.as_str()) == name) {
+            Some(idx) => Some(self.current.remove(idx).1),
+            None => None,
+        };
+        match outcome {
+            Outcome::Completed => {
+                self.completed += 1;
+                if let Some(started) = started {
+                    self.durations.push(started.elapsed());
+                }
+            }
+            Outcome::Failed => self.failed += 1,
+            Outcome::Skipped => self.skipped += 1,
+        }
+        // Restarting a campaign would print a line for every completed run otherwise:
+        self.update(outcome != Outcome::Skipped);
+    }
+
+    /// Saves `progress.json` (and prints the progress if `print` is set).
+    fn update(&self, print: bool) {
+        let eta = self.eta();
+        let current = self
+            .current
+            .iter()
+            .map(|c| c.0.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+        watch::campaign(
+            watch::Campaign {
+                started: self.started,
+                done: self.done(),
+                total: self.total,
+                failed: self.failed,
+                finish: eta.map(|eta| Instant::now() + eta),
+            },
+            &current,
+        );
+        if print && !watch::active() {
+            let run = if !self.current.is_empty() {
+                format!(
+                    "run {}/{} ({})",
+                    (self.done() + self.current.len()).min(self.total),
+                    self.total,
+                    current
+                )
+            } else {
+                format!("{}/{} runs done", self.done(), self.total)
+            };
+            println!(
+                "Campaign: {}, elapsed {}, ETA {}, {} failed",
+                run,
+                hms(self.started.elapsed()),
+                eta.map_or(String::from("-"), hms),
+                self.failed
+            );
+        }
+
+        if let Err(e) = self.save(current, eta) {
+            error!("Can't save {:?}: {}", self.save_to, e);
+        }
+    }
+
+    fn save(&self, current: String, eta: Option<Duration>) -> io::Result<()> {
+        let report = Report {
+            schema_version: PROGRESS_SCHEMA_VERSION,
+            total: self.total,
+            done: self.done(),
+            completed: self.completed,
+            failed: self.failed,
+            skipped: self.skipped,
+            current,
+            elapsed_secs: self.started.elapsed().as_secs(),
+            eta_secs: eta.map(|d| d.as_secs()),
+            updated: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+        // Monitors should never see a half-written file:
+        let tmp = self.save_to.with_extension("json.tmp");
+        let mut f = File::create(&tmp)?;
+        f.write_all(format!("{}\n", json::as_pretty_json(&report)).as_bytes())?;
+        fs::rename(&tmp, &self.save_to)
+    }
+}
@@ -0,0 +1,223 @@
+//! Serves the live counter rates and the progress of a campaign as Prometheus
+//! metrics (`--prometheus <addr>`), so lab infrastructure can scrape measurement
+//! hosts and alert on campaigns that got stuck.
+//!
+//! This is a tiny HTTP server on a background thread: every request to `/metrics`
+//! gets the current state (see `watch::live`), anything else a 404.
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, info};
+
+use super::watch::{self, Live, INTERVAL};
+
+/// Label values have to escape backslashes, quotes and newlines.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for &(ref labels, value) in samples.iter() {
+        if !labels.is_empty() {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        } else {
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+    }
+}
+
+/// The metrics in the Prometheus text format.
+fn metrics(live: &Live) -> String {
+    let mut out = String::new();
+    let configuration = format!("configuration=\"{}\"", label(&live.configuration));
+    metric(
+        &mut out,
+        "autoperf_info",
+        "gauge",
+        "The configuration that's running.",
+        &[(configuration, 1.0)],
+    );
+
+    if let Some(ref campaign) = live.campaign {
+        let samples = |v: usize| vec![(String::new(), v as f64)];
+        metric(
+            &mut out,
+            "autoperf_campaign_runs",
+            "gauge",
+            "Runs of the campaign (this invocation).",
+            &samples(campaign.total),
+        );
+        metric(
+            &mut out,
+            "autoperf_campaign_runs_done",
+            "gauge",
+            "Runs that ended (completed, failed or skipped).",
+            &samples(campaign.done),
+        );
+        metric(
+            &mut out,
+            "autoperf_campaign_runs_failed",
+            "gauge",
+            "Runs that failed.",
+            &samples(campaign.failed),
+        );
+        metric(
+            &mut out,
+            "autoperf_campaign_elapsed_seconds",
+            "gauge",
+            "Time since the campaign started.",
+            &[(String::new(), campaign.started.elapsed().as_secs_f64())],
+        );
+        if let Some(finish) = campaign.finish {
+            metric(
+                &mut out,
+                "autoperf_campaign_eta_seconds",
+                "gauge",
+                "Estimated time until the campaign is done.",
+                &[(
+                    String::new(),
+                    finish
+                        .saturating_duration_since(Instant::now())
+                        .as_secs_f64(),
+                )],
+            );
+        }
+    }
+
+    if let Some((done, total)) = live.measurement {
+        metric(
+            &mut out,
+            "autoperf_measurements",
+            "gauge",
+            "Measurements (event groups) of the current run.",
+            &[(String::new(), total as f64)],
+        );
+        metric(
+            &mut out,
+            "autoperf_measurements_done",
+            "gauge",
+            "Measurements of the current run that are done.",
+            &[(String::new(), done as f64)],
+        );
+    }
+    if let Some(updated) = live.updated {
+        metric(
+            &mut out,
+            "autoperf_last_sample_timestamp_seconds",
+            "gauge",
+            "When perf last wrote counter values (alert if this gets old).",
+            &[(
+                String::new(),
+                updated
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0.0, |d| d.as_secs_f64()),
+            )],
+        );
+    }
+
+    if let Some(ref interval) = live.interval {
+        let mut rates = Vec::new();
+        let mut ipcs = Vec::new();
+        for (location, events) in interval.values.iter() {
+            for (event, value) in events.iter() {
+                rates.push((
+                    format!(
+                        "location=\"{}\",event=\"{}\"",
+                        label(location),
+                        label(event)
+                    ),
+                    value / INTERVAL,
+                ));
+            }
+            if let Some(ipc) = watch::ipc(events) {
+                ipcs.push((format!("location=\"{}\"", label(location)), ipc));
+            }
+        }
+        metric(
+            &mut out,
+            "autoperf_event_rate",
+            "gauge",
+            "Events per second in the latest perf interval.",
+            &rates,
+        );
+        metric(
+            &mut out,
+            "autoperf_ipc",
+            "gauge",
+            "Instructions per cycle in the latest perf interval.",
+            &ipcs,
+        );
+        metric(
+            &mut out,
+            "autoperf_interval_seconds",
+            "gauge",
+            "Time of the latest perf interval (since perf started).",
+            &[(String::new(), interval.time)],
+        );
+    }
+
+    metric(
+        &mut out,
+        "autoperf_scrape_timestamp_seconds",
+        "gauge",
+        "When these metrics were collected.",
+        &[(
+            String::new(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |d| d.as_secs_f64()),
+        )],
+    );
+    out
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    // A client that never finishes its request shouldn't block everyone else:
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers, we don't need any of them:
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" || path.starts_with("/metrics?") {
+        ("200 OK", metrics(&watch::live()))
+    } else {
+        ("404 Not Found", String::from("Metrics are at /metrics\n"))
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Serves the metrics on `addr` (e.g. `0.0.0.0:9100`) until we exit.
+pub fn serve(addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving Prometheus metrics on http://{}/metrics", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let r = stream.and_then(respond);
+            if let Err(e) = r {
+                debug!("Can't answer metrics request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,300 @@
+//! Python bindings (`maturin develop --features python`, see `pyproject.toml`), so
+//! analysis notebooks get the topology of a machine, the deployments `pair` would
+//! use on it and the intervals of aggregated results without parsing
+//! `lscpu.csv`, `numactl.dat` and result CSVs themselves:
+//!
+//! ```python
+//! import autoperf
+//! topology = autoperf.MachineTopology.from_run("out/hostname/run")
+//! plan = autoperf.deployment(topology, "L3-SMT")
+//! results = autoperf.load_results("results.csv")
+//! ```
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+
+use super::{deployment, features, util};
+
+/// A CPU and where it is on the machine.
+#[pyclass(name = "CpuInfo", get_all, frozen)]
+#[derive(Debug, Clone)]
+struct PyCpuInfo {
+    cpu: u64,
+    core: u64,
+    socket: u64,
+    node: u64,
+    /// Memory of the NUMA node (bytes), None if it has none.
+    memory: Option<u64>,
+    l1: u64,
+    l2: u64,
+    l3: u64,
+}
+
+impl<'a> From<&'a util::CpuInfo> for PyCpuInfo {
+    fn from(info: &'a util::CpuInfo) -> PyCpuInfo {
+        PyCpuInfo {
+            cpu: info.cpu,
+            core: info.core,
+            socket: info.socket,
+            node: info.node.node,
+            memory: info.node.memory,
+            l1: info.l1,
+            l2: info.l2,
+            l3: info.l3,
+        }
+    }
+}
+
+fn cpu_infos(cpus: Vec<&util::CpuInfo>) -> Vec<PyCpuInfo> {
+    cpus.into_iter().map(PyCpuInfo::from).collect()
+}
+
+#[pymethods]
+impl PyCpuInfo {
+    fn __repr__(&self) -> String {
+        format!(
+            "CpuInfo(cpu={}, core={}, socket={}, node={}, l1={}, l2={}, l3={})",
+            self.cpu, self.core, self.socket, self.node, self.l1, self.l2, self.l3
+        )
+    }
+}
+
+#[pyclass(name = "MachineTopology")]
+struct PyMachineTopology {
+    topology: util::MachineTopology,
+}
+
+#[pymethods]
+impl PyMachineTopology {
+    /// The topology of this machine.
+    #[new]
+    fn new() -> PyMachineTopology {
+        PyMachineTopology {
+            topology: util::MachineTopology::new(),
+        }
+    }
+
+    /// The topology of the machine a run was measured on (from the run's
+    /// lscpu.csv and numactl.dat).
+    #[staticmethod]
+    fn from_run(directory: PathBuf) -> PyResult<PyMachineTopology> {
+        let lscpu = directory.join("lscpu.csv");
+        let numactl = directory.join("numactl.dat");
+        for file in [&lscpu, &numactl].iter() {
+            if !file.exists() {
+                return Err(PyIOError::new_err(format!("{:?} does not exist", file)));
+            }
+        }
+        Ok(PyMachineTopology {
+            topology: util::MachineTopology::from_files(&lscpu, &numactl),
+        })
+    }
+
+    fn cpus(&self) -> Vec<u64> {
+        self.topology.cpus()
+    }
+
+    fn cpu(&self, cpu: u64) -> Option<PyCpuInfo> {
+        self.topology.cpu(cpu).map(PyCpuInfo::from)
+    }
+
+    fn cores(&self) -> Vec<u64> {
+        self.topology.cores()
+    }
+
+    fn sockets(&self) -> Vec<u64> {
+        self.topology.sockets()
+    }
+
+    /// (node, memory in bytes or None) of every NUMA node.
+    fn nodes(&self) -> Vec<(u64, Option<u64>)> {
+        self.topology
+            .nodes()
+            .iter()
+            .map(|n| (n.node, n.memory))
+            .collect()
+    }
+
+    fn max_memory(&self) -> u64 {
+        self.topology.max_memory()
+    }
+
+    fn cpus_on_core(&self, core: u64) -> Vec<PyCpuInfo> {
+        cpu_infos(self.topology.cpus_on_core(core))
+    }
+
+    fn cpus_on_socket(&self, socket: u64) -> Vec<PyCpuInfo> {
+        cpu_infos(self.topology.cpus_on_socket(socket))
+    }
+
+    fn cpus_on_node(&self, node: u64) -> Vec<PyCpuInfo> {
+        match self.topology.nodes().into_iter().find(|n| n.node == node) {
+            Some(node) => cpu_infos(self.topology.cpus_on_node(node)),
+            None => Vec::new(),
+        }
+    }
+
+    fn cpus_on_l3(&self, l3: u64) -> Vec<PyCpuInfo> {
+        cpu_infos(self.topology.cpus_on_l3(l3))
+    }
+
+    /// Cache sizes in bytes, of the run's machine for `from_run` (if the run
+    /// saved them), of this machine otherwise.
+    fn l1_size(&self) -> Option<u64> {
+        self.topology.l1_size()
+    }
+
+    fn l2_size(&self) -> Option<u64> {
+        self.topology.l2_size()
+    }
+
+    fn l3_size(&self) -> Option<u64> {
+        self.topology.l3_size()
+    }
+
+    fn cores_per_l3(&self, socket: u64) -> Option<u64> {
+        self.topology.cores_per_l3(socket)
+    }
+
+    /// The bytes of the L2 a core has on average.
+    fn l2_share_per_core(&self) -> Option<u64> {
+        self.topology.l2_share_per_core()
+    }
+
+    /// The bytes of its L3 a core of `socket` has on average.
+    fn l3_share_per_core(&self, socket: u64) -> Option<u64> {
+        self.topology.l3_share_per_core(socket)
+    }
+
+    /// The bytes of its L3 a CPU (SMT thread) of `socket` has on average.
+    fn l3_share_per_cpu(&self, socket: u64) -> Option<u64> {
+        self.topology.l3_share_per_cpu(socket)
+    }
+
+    fn __len__(&self) -> usize {
+        self.topology.cpus().len()
+    }
+}
+
+/// Where `pair` places A and B (and their memory) for a deployment.
+#[pyclass(name = "Deployment", get_all, frozen)]
+struct PyDeployment {
+    description: String,
+    a: Vec<PyCpuInfo>,
+    b: Vec<PyCpuInfo>,
+    /// (node, bytes)
+    memory: Vec<(u64, Option<u64>)>,
+}
+
+#[pymethods]
+impl PyDeployment {
+    fn __repr__(&self) -> String {
+        let a: Vec<u64> = self.a.iter().map(|c| c.cpu).collect();
+        let b: Vec<u64> = self.b.iter().map(|c| c.cpu).collect();
+        format!("Deployment({}, a={:?}, b={:?})", self.description, a, b)
+    }
+}
+
+/// The names of the deployments `pair` knows.
+#[pyfunction]
+fn deployments() -> Vec<&'static str> {
+    deployment::DEPLOYMENTS.to_vec()
+}
+
+/// The deployment `name` on `topology` (only with the CPUs of `socket` if given),
+/// like `pair` plans it.
+#[pyfunction]
+#[pyo3(name = "deployment", signature = (topology, name, socket=None))]
+fn plan_deployment(
+    topology: &PyMachineTopology,
+    name: &str,
+    socket: Option<u64>,
+) -> PyResult<PyDeployment> {
+    match deployment::deployment(name, &topology.topology, socket) {
+        Some(d) => Ok(PyDeployment {
+            description: String::from(d.description),
+            a: cpu_infos(d.a),
+            b: cpu_infos(d.b),
+            memory: d.mem.iter().map(|n| (n.node, n.memory)).collect(),
+        }),
+        None => Err(PyValueError::new_err(format!(
+            "Unknown deployment {} (or no CPUs for it), see deployments()",
+            name
+        ))),
+    }
+}
+
+/// The values of all events in one interval of a run (summed up over all
+/// locations).
+#[pyclass(name = "Interval", get_all, frozen)]
+#[derive(Clone)]
+struct PyInterval {
+    run: String,
+    index: u64,
+    time: f64,
+    /// event -> value (only the events measured in the interval)
+    values: BTreeMap<String, f64>,
+}
+
+/// Aggregated results.
+#[pyclass(name = "Results", get_all, frozen)]
+struct PyResults {
+    events: Vec<String>,
+    /// By run and index.
+    intervals: Vec<PyInterval>,
+}
+
+#[pymethods]
+impl PyResults {
+    /// The runs (configurations) in the results.
+    fn runs(&self) -> Vec<String> {
+        let mut runs: Vec<String> = self.intervals.iter().map(|i| i.run.clone()).collect();
+        runs.dedup();
+        runs
+    }
+
+    /// The intervals of `run`.
+    fn run(&self, run: &str) -> Vec<PyInterval> {
+        self.intervals
+            .iter()
+            .filter(|i| i.run == run)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Loads results `aggregate` wrote (the long layout).
+#[pyfunction]
+fn load_results(results: PathBuf) -> PyResult<PyResults> {
+    let (events, intervals) =
+        features::read_intervals(&results).map_err(|e| PyIOError::new_err(e.to_string()))?;
+    let intervals = intervals
+        .into_iter()
+        .map(|((run, index), interval)| PyInterval {
+            run,
+            index,
+            time: interval.time,
+            values: events
+                .iter()
+                .zip(interval.values.iter())
+                .filter_map(|(e, v)| v.map(|v| (e.clone(), v)))
+                .collect(),
+        })
+        .collect();
+    Ok(PyResults { events, intervals })
+}
+
+#[pymodule]
+fn autoperf(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyCpuInfo>()?;
+    m.add_class::<PyMachineTopology>()?;
+    m.add_class::<PyDeployment>()?;
+    m.add_class::<PyInterval>()?;
+    m.add_class::<PyResults>()?;
+    m.add_function(wrap_pyfunction!(deployments, m)?)?;
+    m.add_function(wrap_pyfunction!(plan_deployment, m)?)?;
+    m.add_function(wrap_pyfunction!(load_results, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,357 @@
+//! Turns the slowdowns measured by a `pair` campaign into a placement: which of
+//! a list of workloads should share cores (in one of the placements of the
+//! experiment) so they fit on `--cores` cores and slow each other down the least.
+//!
+//! A workload alone needs the cores of its run alone; two workloads together
+//! need the cores of their co-located run (in `L3-SMT` that's the same cores, the
+//! SMT siblings are shared; placements on separate cores don't save any). A pair
+//! costs the slowdowns of both programs (see `sensitivity`), so it needs runs in
+//! both directions, `<A>_vs_<B>` and `<B>_vs_<A>`. With `exact` we try every way
+//! of pairing the workloads (up to `EXACT_LIMIT` of them), `greedy` pairs the
+//! ones that cost the least per core saved until they fit:
+//!
+//! ```text
+//! PLACEMENT,A,B,CORES,SLOWDOWN_A,SLOWDOWN_B
+//! L3-SMT,gcc,stream,4,1.08,1.02
+//! L3-SMT,mcf,,4,1,
+//! ```
+//!
+//! Every placement that fits gets a plan, the one that costs the least first.
+//! Only runs without variations (`cat_ways`, memory policies, ...) are used.
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use tracing::{debug, info, warn};
+
+use crate::diff;
+use crate::interference::{campaign_runs, ipc, tmp_directory, with_run};
+
+/// We don't search all pairings of more workloads than this (there are 140k for 12).
+pub const EXACT_LIMIT: usize = 12;
+
+/// How the pairs are picked.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Method {
+    /// The best of all pairings.
+    Exact,
+    /// Cheapest pair (per core saved) first.
+    Greedy,
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(method: &str) -> Result<Method, String> {
+        match method {
+            "exact" => Ok(Method::Exact),
+            "greedy" => Ok(Method::Greedy),
+            _ => Err(format!("Unknown method '{}' (exact or greedy)", method)),
+        }
+    }
+}
+
+pub struct RecommendSettings {
+    /// The workloads to place (all with a run alone if empty).
+    pub workloads: Vec<String>,
+    /// How many cores they may use.
+    pub cores: usize,
+    pub method: Method,
+    /// Only plan for this placement.
+    pub placement: Option<String>,
+}
+
+/// What the campaign measured in a placement.
+#[derive(Default)]
+struct Measured {
+    /// Workload -> cores it needs alone (and its IPC alone).
+    alone: BTreeMap<String, (usize, f64)>,
+    /// (A, B) -> cores they need together and the slowdown of A.
+    together: BTreeMap<(String, String), (usize, f64)>,
+}
+
+impl Measured {
+    /// Cores and slowdowns (of `a`, of `b`) of `a` and `b` together, if both
+    /// directions were measured.
+    fn pair(&self, a: &str, b: &str) -> Option<(usize, f64, f64)> {
+        let key = |x: &str, y: &str| (String::from(x), String::from(y));
+        let &(cores, slowdown_a) = self.together.get(&key(a, b))?;
+        let &(cores_reverse, slowdown_b) = self.together.get(&key(b, a))?;
+        Some((cores.max(cores_reverse), slowdown_a, slowdown_b))
+    }
+}
+
+/// A workload alone, or two together.
+#[derive(Debug, Clone)]
+struct Group {
+    a: String,
+    /// With its slowdown.
+    b: Option<(String, f64)>,
+    cores: usize,
+    slowdown_a: f64,
+}
+
+impl Group {
+    /// What the group costs (0 alone, the slowdowns of both beyond 1 together).
+    fn cost(&self) -> f64 {
+        self.slowdown_a - 1.0 + self.b.as_ref().map_or(0.0, |&(_, s)| s - 1.0)
+    }
+}
+
+fn alone(measured: &Measured, workload: &str) -> Group {
+    Group {
+        a: String::from(workload),
+        b: None,
+        cores: measured.alone[workload].0,
+        slowdown_a: 1.0,
+    }
+}
+
+fn together(measured: &Measured, a: &str, b: &str) -> Option<Group> {
+    let (cores, slowdown_a, slowdown_b) = measured.pair(a, b)?;
+    Some(Group {
+        a: String::from(a),
+        b: Some((String::from(b), slowdown_b)),
+        cores,
+        slowdown_a,
+    })
+}
+
+fn cost(plan: &[Group]) -> (f64, usize) {
+    (
+        plan.iter().map(|g| g.cost()).sum(),
+        plan.iter().map(|g| g.cores).sum(),
+    )
+}
+
+/// Tries every pairing of `workloads`, keeps the cheapest that fits in `cores`.
+fn exact(
+    measured: &Measured,
+    workloads: &[String],
+    cores: usize,
+    plan: &mut Vec<Group>,
+    best: &mut Option<Vec<Group>>,
+) {
+    let used: usize = plan.iter().map(|g| g.cores).sum();
+    if used > cores {
+        return;
+    }
+    let first = match workloads.first() {
+        Some(first) => first,
+        None => {
+            let better = best.as_ref().is_none_or(|b| {
+                let (c, u) = cost(plan);
+                let (best_c, best_u) = cost(b);
+                c < best_c || (c == best_c && u < best_u)
+            });
+            if better {
+                *best = Some(plan.clone());
+            }
+            return;
+        }
+    };
+    plan.push(alone(measured, first));
+    exact(measured, &workloads[1..], cores, plan, best);
+    plan.pop();
+    for idx in 1..workloads.len() {
+        if let Some(group) = together(measured, first, &workloads[idx]) {
+            let rest: Vec<String> = workloads[1..]
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i + 1 != idx)
+                .map(|(_, w)| w.clone())
+                .collect();
+            plan.push(group);
+            exact(measured, &rest, cores, plan, best);
+            plan.pop();
+        }
+    }
+}
+
+/// Pairs the workloads that cost the least per core saved until they fit.
+fn greedy(measured: &Measured, workloads: &[String], cores: usize) -> Option<Vec<Group>> {
+    let mut plan: Vec<Group> = workloads.iter().map(|w| alone(measured, w)).collect();
+    while cost(&plan).1 > cores {
+        let mut cheapest: Option<(f64, usize, usize, Group)> = None;
+        for i in 0..plan.len() {
+            for j in 0..plan.len() {
+                if i == j || plan[i].b.is_some() || plan[j].b.is_some() {
+                    continue;
+                }
+                let group = match together(measured, &plan[i].a, &plan[j].a) {
+                    Some(group) => group,
+                    None => continue,
+                };
+                let saved = plan[i].cores + plan[j].cores;
+                if group.cores >= saved {
+                    continue;
+                }
+                let per_core = group.cost() / (saved - group.cores) as f64;
+                if cheapest.as_ref().is_none_or(|c| per_core < c.0) {
+                    cheapest = Some((per_core, i, j, group));
+                }
+            }
+        }
+        let (_, i, j, group) = cheapest?;
+        plan.remove(i.max(j));
+        plan.remove(i.min(j));
+        plan.push(group);
+    }
+    Some(plan)
+}
+
+/// Reads what the campaign measured in every placement.
+fn read_measurements(campaign: &Path) -> BTreeMap<String, Measured> {
+    let tmp = tmp_directory();
+    let runs = campaign_runs(campaign, &tmp);
+    let ipc_of = |path: &Path| with_run(path, &tmp, |dir| ipc(&diff::interval_totals(dir)));
+
+    let mut placements: BTreeMap<String, Measured> = BTreeMap::new();
+    for run in runs.iter().filter(|r| r.b.is_none() && r.name == r.a) {
+        match ipc_of(&run.path) {
+            Some(ipc) => {
+                placements
+                    .entry(run.placement.clone())
+                    .or_default()
+                    .alone
+                    .insert(run.a.clone(), (run.cores, ipc));
+            }
+            None => warn!(
+                "{}/{} has no instructions and cycles, ignoring it.",
+                run.placement, run.name
+            ),
+        }
+    }
+    for run in runs.iter() {
+        let b = match run.b {
+            Some(ref b) if run.name == format!("{}_vs_{}", run.a, b) => b,
+            _ => continue,
+        };
+        let measured = match placements.get_mut(&run.placement) {
+            Some(measured) => measured,
+            None => continue,
+        };
+        let solo_ipc = match measured.alone.get(&run.a) {
+            Some(&(_, ipc)) => ipc,
+            None => continue,
+        };
+        match ipc_of(&run.path) {
+            Some(ipc) if ipc > 0.0 => {
+                measured
+                    .together
+                    .insert((run.a.clone(), b.clone()), (run.cores, solo_ipc / ipc));
+            }
+            _ => warn!(
+                "{}/{} has no instructions and cycles, ignoring it.",
+                run.placement, run.name
+            ),
+        }
+    }
+    placements
+}
+
+/// Recommends how to place the workloads of `settings` from what the campaign
+/// in `campaign` measured and writes the plans as CSV to `out` (see the module
+/// doc). Returns how many placements have a plan.
+pub fn recommend<W: io::Write>(
+    campaign: &Path,
+    settings: &RecommendSettings,
+    out: W,
+) -> io::Result<usize> {
+    let mut plans: Vec<(String, Vec<Group>)> = Vec::new();
+    for (placement, measured) in read_measurements(campaign) {
+        if settings.placement.as_ref().is_some_and(|p| *p != placement) {
+            continue;
+        }
+        let workloads: Vec<String> = if settings.workloads.is_empty() {
+            measured.alone.keys().cloned().collect()
+        } else {
+            settings.workloads.clone()
+        };
+        if let Some(w) = workloads.iter().find(|w| !measured.alone.contains_key(*w)) {
+            warn!(
+                "{} wasn't measured alone in {}, no plan for it.",
+                w, placement
+            );
+            continue;
+        }
+        let method = if settings.method == Method::Exact && workloads.len() > EXACT_LIMIT {
+            warn!(
+                "There are too many workloads to try all pairings ({} > {}), pairing them greedily.",
+                workloads.len(),
+                EXACT_LIMIT
+            );
+            Method::Greedy
+        } else {
+            settings.method
+        };
+        let plan = match method {
+            Method::Exact => {
+                let mut best = None;
+                exact(
+                    &measured,
+                    &workloads,
+                    settings.cores,
+                    &mut Vec::new(),
+                    &mut best,
+                );
+                best
+            }
+            Method::Greedy => greedy(&measured, &workloads, settings.cores),
+        };
+        match plan {
+            Some(plan) => {
+                debug!("{} costs {:?} (slowdown, cores)", placement, cost(&plan));
+                plans.push((placement, plan));
+            }
+            None => info!(
+                "The workloads don't fit on {} cores in {} (with the pairs measured).",
+                settings.cores, placement
+            ),
+        }
+    }
+    if plans.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "no placement fits the workloads on {} cores",
+                settings.cores
+            ),
+        ));
+    }
+    plans.sort_by(|x, y| {
+        cost(&x.1)
+            .partial_cmp(&cost(&y.1))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let (best_cost, best_cores) = cost(&plans[0].1);
+    info!(
+        "{} is the best placement: {} cores, {:.1}% slowdown in total",
+        plans[0].0,
+        best_cores,
+        best_cost * 100.0
+    );
+
+    let mut wtr = csv::Writer::from_writer(out);
+    wtr.encode(["PLACEMENT", "A", "B", "CORES", "SLOWDOWN_A", "SLOWDOWN_B"])
+        .unwrap();
+    for (placement, plan) in plans.iter() {
+        for group in plan {
+            let (b, slowdown_b) = match group.b {
+                Some((ref b, s)) => (b.clone(), s.to_string()),
+                None => (String::new(), String::new()),
+            };
+            wtr.encode((
+                placement,
+                &group.a,
+                b,
+                group.cores,
+                group.slowdown_a,
+                slowdown_b,
+            ))
+            .unwrap();
+        }
+    }
+    Ok(plans.len())
+}
@@ -0,0 +1,453 @@
+//! Writes a single, self-contained HTML file that summarizes a profile or `pair`
+//! campaign: the machine, the campaign plan (manifest and runs), a table of all
+//! events per configuration with a chart of their values over time, and
+//! warnings about runs whose values vary a lot or that have other problems (see
+//! `validate.rs`). Charts are inline SVG, so the report can be sent around
+//! without anything else.
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use regex::Regex;
+use rustc_serialize::json::Json;
+use tracing::{debug, error, info};
+
+use super::archive;
+use super::bootstrap::{mean_interval, CONFIDENCE};
+use super::bytesize::ByteSize;
+use super::diff::{configurations, interval_totals, mean, run_directory, variance};
+use super::normalize::{CYCLES, INSTRUCTIONS};
+use super::presets;
+use super::rules::Metric;
+use super::util::{MachineTopology, CACHE_FILE};
+use super::validate::{self, Issue};
+
+/// Events whose values vary more than this (coefficient of variation over the
+/// intervals of a run) get a warning.
+pub const MAX_VARIATION: f64 = 0.25;
+
+const SPARKLINE_WIDTH: f64 = 160.0;
+const SPARKLINE_HEIGHT: f64 = 24.0;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 1.5em; }
+th, td { border: 1px solid #ccc; padding: 2px 8px; text-align: right; }
+th:first-child, td:first-child { text-align: left; }
+tr.warning td { background: #fff3cd; }
+pre { background: #f6f6f6; padding: 1em; overflow-x: auto; }
+.bar { fill: #4a78b5; }
+.line { fill: none; stroke: #4a78b5; stroke-width: 1; }
+";
+
+/// What we show of an event in a configuration.
+struct EventSummary {
+    name: String,
+    total: f64,
+    mean: f64,
+    /// Bootstrap confidence interval of the mean.
+    interval: Option<(f64, f64)>,
+    /// Coefficient of variation over the intervals.
+    variation: f64,
+    values: Vec<f64>,
+}
+
+struct Configuration {
+    name: String,
+    events: Vec<EventSummary>,
+    ipc: Option<f64>,
+    /// The metrics of the presets we have all events of (see `presets.rs`).
+    metrics: Vec<(&'static str, f64)>,
+    issues: Vec<Issue>,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The values of an event over time as a small line chart.
+fn sparkline(values: &[f64]) -> String {
+    if values.len() < 2 {
+        return String::new();
+    }
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let range = if max > min { max - min } else { 1.0 };
+    let points: Vec<String> = values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            format!(
+                "{:.1},{:.1}",
+                i as f64 / (values.len() - 1) as f64 * SPARKLINE_WIDTH,
+                SPARKLINE_HEIGHT - (v - min) / range * SPARKLINE_HEIGHT
+            )
+        })
+        .collect();
+    format!(
+        "<svg width=\"{}\" height=\"{}\"><polyline class=\"line\" points=\"{}\"/></svg>",
+        SPARKLINE_WIDTH,
+        SPARKLINE_HEIGHT,
+        points.join(" ")
+    )
+}
+
+/// A horizontal bar chart (label, value).
+fn bar_chart(bars: &[(&str, f64)]) -> String {
+    const ROW: f64 = 18.0;
+    const LABEL: f64 = 320.0;
+    const WIDTH: f64 = 400.0;
+    let max = bars.iter().map(|b| b.1).fold(0.0, f64::max);
+    let mut svg = format!(
+        "<svg width=\"{}\" height=\"{}\">",
+        LABEL + WIDTH + 60.0,
+        bars.len() as f64 * ROW
+    );
+    for (i, &(label, value)) in bars.iter().enumerate() {
+        let y = i as f64 * ROW;
+        let width = if max > 0.0 { value / max * WIDTH } else { 0.0 };
+        let _ = write!(
+            svg,
+            "<text x=\"0\" y=\"{:.1}\" font-size=\"12\">{}</text>\
+             <rect class=\"bar\" x=\"{}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{}\"/>\
+             <text x=\"{:.1}\" y=\"{:.1}\" font-size=\"12\">{:.2}</text>",
+            y + ROW - 5.0,
+            escape(label),
+            LABEL,
+            y + 2.0,
+            width,
+            ROW - 4.0,
+            LABEL + width + 4.0,
+            y + ROW - 5.0,
+            value
+        );
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// `presets::machine_values` of the machine the run was measured on (none if
+/// the run didn't save its cache sizes, cpuid would tell those of ours).
+fn machine_values(run_directory: &Path) -> Vec<(&'static str, f64)> {
+    let lscpu = run_directory.join("lscpu.csv");
+    let numactl = run_directory.join("numactl.dat");
+    if !lscpu.exists() || !numactl.exists() || !run_directory.join(CACHE_FILE).exists() {
+        return Vec::new();
+    }
+    presets::machine_values(&MachineTopology::from_files(&lscpu, &numactl))
+}
+
+fn summarize(
+    name: &str,
+    run_directory: &Path,
+    events: Option<&Regex>,
+    bootstrap: usize,
+) -> Configuration {
+    let totals = interval_totals(run_directory);
+    let sum_of = |names: &[&str]| -> Option<f64> {
+        names
+            .iter()
+            .filter_map(|n| totals.get(*n))
+            .next()
+            .map(|values| values.iter().sum())
+    };
+    let ipc = match (sum_of(&INSTRUCTIONS), sum_of(&CYCLES)) {
+        (Some(i), Some(c)) if c > 0.0 => Some(i / c),
+        _ => None,
+    };
+
+    let mut sums: HashMap<String, f64> = totals
+        .iter()
+        .map(|(event, values)| (event.clone(), values.iter().sum()))
+        .collect();
+    for (name, value) in machine_values(run_directory) {
+        sums.insert(String::from(name), value);
+    }
+    let metrics: Vec<(&'static str, f64)> = presets::metrics()
+        .into_iter()
+        .filter_map(|(name, expression)| {
+            let metric = Metric::parse(expression).expect("presets have valid metrics");
+            if metric.events().iter().all(|e| sums.contains_key(*e)) {
+                Some((name, metric.eval(&sums)))
+            } else {
+                None
+            }
+        })
+        .filter(|m| m.1.is_finite())
+        .collect();
+
+    let mut summaries = Vec::new();
+    for (event, values) in totals.iter() {
+        if events.is_some_and(|p| !p.is_match(event)) {
+            continue;
+        }
+        let m = mean(values);
+        let variation = if values.len() > 1 && m != 0.0 {
+            variance(values, m).sqrt() / m.abs()
+        } else {
+            0.0
+        };
+        summaries.push(EventSummary {
+            name: event.clone(),
+            total: values.iter().sum(),
+            mean: m,
+            interval: mean_interval(values, bootstrap),
+            variation,
+            values: values.clone(),
+        });
+    }
+
+    Configuration {
+        name: String::from(name),
+        events: summaries,
+        ipc,
+        metrics,
+        issues: validate::check_run(name, run_directory),
+    }
+}
+
+/// The machine summary, from the machine information files of a run.
+fn machine_summary(run_directory: &Path) -> String {
+    let mut html = String::from("<h2>Machine</h2>\n<table>\n");
+    let mut row = |key: &str, value: String| {
+        let _ = writeln!(html, "<tr><td>{}</td><td>{}</td></tr>", key, escape(&value));
+    };
+
+    let mut machine_json = run_directory.to_path_buf();
+    machine_json.push("machine.json");
+    if let Some(machine) = fs::read_to_string(&machine_json)
+        .ok()
+        .and_then(|content| Json::from_str(&content).ok())
+    {
+        for (key, name) in [
+            ("hostname", "Host"),
+            ("cpu_model", "CPU"),
+            ("microarchitecture", "Microarchitecture"),
+            ("microcode", "Microcode"),
+            ("kernel_release", "Kernel"),
+            ("autoperf_version", "autoperf"),
+        ]
+        .iter()
+        {
+            if let Some(value) = machine.find(key).and_then(|v| v.as_string()) {
+                row(name, String::from(value));
+            }
+        }
+    }
+
+    let mut lscpu = run_directory.to_path_buf();
+    lscpu.push("lscpu.csv");
+    let mut numactl = run_directory.to_path_buf();
+    numactl.push("numactl.dat");
+    if lscpu.exists() && numactl.exists() {
+        let mt = MachineTopology::from_files(&lscpu, &numactl);
+        row("Sockets", mt.sockets().len().to_string());
+        row("Cores", mt.cores().len().to_string());
+        row("CPUs", mt.cpus().len().to_string());
+        row("NUMA nodes", mt.nodes().len().to_string());
+        row("Memory", ByteSize(mt.max_memory()).to_string());
+        row("L2 caches", mt.l2().len().to_string());
+        row("L3 caches", mt.l3().len().to_string());
+        // Without cache_sizes.csv they would come from cpuid of the machine
+        // we're running on:
+        if run_directory.join(CACHE_FILE).exists() {
+            let socket = mt.sockets().first().cloned();
+            let size =
+                |s: Option<u64>| s.map_or(String::from("unknown"), |s| ByteSize(s).to_string());
+            row("L2 per core", size(mt.l2_share_per_core()));
+            row(
+                "L3 per core",
+                size(socket.and_then(|s| mt.l3_share_per_core(s))),
+            );
+        }
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+/// The manifest of the campaign (`pair` puts the results next to it) and the
+/// runs we found.
+fn campaign_plan(path: &Path, configurations: &[Configuration], missing: &[PathBuf]) -> String {
+    let mut html = String::from("<h2>Campaign</h2>\n");
+    let manifest = path.parent().map(|p| {
+        let mut manifest = p.to_path_buf();
+        manifest.push("manifest.toml");
+        manifest
+    });
+    if let Some(content) = manifest.and_then(|m| fs::read_to_string(m).ok()) {
+        let _ = writeln!(html, "<pre>{}</pre>", escape(&content));
+    }
+    let _ = write!(
+        html,
+        "<p>{} runs with measurements, {} without.</p>\n<table>\n<tr><th>Run</th><th>IPC</th><th>Events</th><th>Problems</th></tr>\n",
+        configurations.len(),
+        missing.len()
+    );
+    for c in configurations.iter() {
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"#{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&c.name),
+            escape(&c.name),
+            c.ipc.map_or(String::new(), |ipc| format!("{:.2}", ipc)),
+            c.events.len(),
+            c.issues.len()
+        );
+    }
+    for m in missing.iter() {
+        let _ = writeln!(
+            html,
+            "<tr class=\"warning\"><td>{}</td><td></td><td>0</td><td>no measurements</td></tr>",
+            escape(&m.strip_prefix(path).unwrap_or(m).to_string_lossy())
+        );
+    }
+    html.push_str("</table>\n");
+
+    let ipcs: Vec<(&str, f64)> = configurations
+        .iter()
+        .filter_map(|c| c.ipc.map(|ipc| (c.name.as_str(), ipc)))
+        .collect();
+    if ipcs.len() > 1 {
+        html.push_str("<h3>IPC</h3>\n");
+        html.push_str(&bar_chart(&ipcs));
+        html.push('\n');
+    }
+    html
+}
+
+fn warnings(configurations: &[Configuration]) -> String {
+    let mut html = String::from("<h2>Warnings</h2>\n<ul>\n");
+    let mut count = 0;
+    for c in configurations.iter() {
+        for i in c.issues.iter() {
+            let _ = writeln!(
+                html,
+                "<li>{}: {} {} {} ({})</li>",
+                escape(&i.kind),
+                escape(&c.name),
+                escape(&i.file),
+                escape(&i.event),
+                escape(&i.detail)
+            );
+            count += 1;
+        }
+        for e in c.events.iter().filter(|e| e.variation > MAX_VARIATION) {
+            let _ = writeln!(
+                html,
+                "<li>variance: {} {} (coefficient of variation {:.2})</li>",
+                escape(&c.name),
+                escape(&e.name),
+                e.variation
+            );
+            count += 1;
+        }
+    }
+    if count == 0 {
+        html.push_str("<li>None.</li>\n");
+    }
+    html.push_str("</ul>\n");
+    html
+}
+
+fn configuration_table(c: &Configuration) -> String {
+    let title = if !c.name.is_empty() {
+        c.name.as_str()
+    } else {
+        "Results"
+    };
+    let mut html = format!(
+        "<h2 id=\"{}\">{}</h2>\n<table>\n<tr><th>Event</th><th>Total</th><th>Mean per interval</th><th>{:.0}% CI</th><th>CV</th><th>Over time</th></tr>\n",
+        escape(&c.name),
+        escape(title),
+        CONFIDENCE * 100.0
+    );
+    for e in c.events.iter() {
+        let _ = writeln!(
+            html,
+            "<tr{}><td>{}</td><td>{:.0}</td><td>{:.1}</td><td>{}</td><td>{:.3}</td><td>{}</td></tr>",
+            if e.variation > MAX_VARIATION {
+                " class=\"warning\""
+            } else {
+                ""
+            },
+            escape(&e.name),
+            e.total,
+            e.mean,
+            e.interval
+                .map_or(String::new(), |(lower, upper)| format!("{:.1} – {:.1}", lower, upper)),
+            e.variation,
+            sparkline(&e.values)
+        );
+    }
+    html.push_str("</table>\n");
+    if !c.metrics.is_empty() {
+        html.push_str("<table>\n<tr><th>Metric</th><th>Value</th></tr>\n");
+        for &(name, value) in c.metrics.iter() {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{:.3}</td></tr>",
+                escape(name),
+                value
+            );
+        }
+        html.push_str("</table>\n");
+    }
+    html
+}
+
+/// Writes the report for the profile or campaign in `path` to `save_to`, only
+/// with the events that match `events` (and the confidence intervals of their
+/// means from `bootstrap` resamples).
+pub fn report(
+    path: &Path,
+    events: Option<&Regex>,
+    bootstrap: usize,
+    save_to: &Path,
+) -> io::Result<()> {
+    let runs = configurations(path);
+    if runs.is_empty() {
+        error!("No measurements found in {:?}", path);
+        process::exit(1);
+    }
+    info!("Summarizing {} runs of {:?}", runs.len(), path);
+
+    let mut tmp = std::env::temp_dir();
+    tmp.push(format!("autoperf-{}", process::id()));
+    let mut machine = String::new();
+    let mut summaries = Vec::with_capacity(runs.len());
+    for (name, run) in runs.iter() {
+        debug!("Summarizing run {}", name);
+        let directory = run_directory(run, &tmp);
+        if machine.is_empty() {
+            machine = machine_summary(&directory);
+        }
+        summaries.push(summarize(name, &directory, events, bootstrap));
+        if archive::is_archive(run) {
+            let _ = fs::remove_dir_all(&tmp);
+        }
+    }
+    let missing = validate::missing_runs(path);
+
+    let mut f = File::create(save_to)?;
+    let title = escape(&path.to_string_lossy());
+    write!(
+        f,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>autoperf report: {}</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        title, STYLE, title
+    )?;
+    f.write_all(machine.as_bytes())?;
+    f.write_all(campaign_plan(path, &summaries, &missing).as_bytes())?;
+    f.write_all(warnings(&summaries).as_bytes())?;
+    for c in summaries.iter() {
+        f.write_all(configuration_table(c).as_bytes())?;
+    }
+    f.write_all(b"</body>\n</html>\n")
+}
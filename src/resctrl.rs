@@ -0,0 +1,327 @@
+//! Monitors the LLC occupancy (CMT) and memory bandwidth (MBM) of co-located
+//! programs with Intel RDT through the resctrl file-system. Every program gets
+//! its own MON group, new threads and child processes inherit the group so we
+//! only have to assign a program once. With CAT, a program's group is a control
+//! group instead that limits the L3 ways it can allocate in.
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, error};
+
+use super::threads::descendants;
+
+/// Where resctrl is usually mounted.
+const RESCTRL_MOUNT: &str = "/sys/fs/resctrl";
+
+/// How often we read the monitoring data.
+const SAMPLE_INTERVAL_MS: u64 = 250;
+
+/// How long we wait for perf to fork the program (see `assign_program`).
+const PROGRAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A resctrl monitoring group, removing it moves all its tasks back to the default group.
+pub struct MonGroup {
+    name: String,
+    path: PathBuf,
+}
+
+impl MonGroup {
+    /// Creates a new MON group (`name` is prefixed with autoperf_ in resctrl).
+    pub fn create(name: &str) -> io::Result<MonGroup> {
+        let mut path = PathBuf::from(RESCTRL_MOUNT);
+        path.push("mon_groups");
+        path.push(format!("autoperf_{}", name));
+        MonGroup::create_at(name, path)
+    }
+
+    /// Creates a control group whose tasks can only allocate in the L3 ways that are
+    /// set in `mask` (CAT). Control groups can be monitored just like MON groups.
+    pub fn with_l3_mask(name: &str, mask: u64) -> io::Result<MonGroup> {
+        let mut path = PathBuf::from(RESCTRL_MOUNT);
+        path.push(format!("autoperf_{}", name));
+        let group = MonGroup::create_at(name, path)?;
+
+        let domains: Vec<String> = l3_domains()?
+            .iter()
+            .map(|d| format!("{}={:x}", d, mask))
+            .collect();
+        let mut schemata = group.path.clone();
+        schemata.push("schemata");
+        let mut f = OpenOptions::new().write(true).open(&schemata)?;
+        f.write_all(format!("L3:{}\n", domains.join(";")).as_bytes())?;
+        debug!("{} is restricted to L3 ways {:#x}", name, mask);
+
+        Ok(group)
+    }
+
+    fn create_at(name: &str, path: PathBuf) -> io::Result<MonGroup> {
+        let mut info = PathBuf::from(RESCTRL_MOUNT);
+        info.push("info");
+        if !info.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "resctrl is not mounted (mount -t resctrl resctrl /sys/fs/resctrl)",
+            ));
+        }
+
+        if path.exists() {
+            // Left-over from a previous (crashed) run:
+            fs::remove_dir(&path)?;
+        }
+        fs::create_dir(&path)?;
+
+        Ok(MonGroup {
+            name: String::from(name),
+            path,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// The file that has (and takes) the tasks of the group.
+    pub fn tasks(&self) -> PathBuf {
+        let mut tasks = self.path.clone();
+        tasks.push("tasks");
+        tasks
+    }
+
+    /// Moves all threads of process `pid` into this group.
+    pub fn assign(&self, pid: u32) -> io::Result<()> {
+        assign_to(&self.tasks(), pid)
+    }
+
+    /// Reads `what` (e.g., llc_occupancy) for every L3 domain (i.e., socket).
+    fn read_mon_data(&self, what: &str) -> Vec<(String, u64)> {
+        let mut mon_data = self.path.clone();
+        mon_data.push("mon_data");
+
+        let mut values: Vec<(String, u64)> = Vec::new();
+        let domains = match fs::read_dir(&mon_data) {
+            Ok(domains) => domains,
+            Err(_) => return values,
+        };
+        for domain in domains.filter_map(|d| d.ok()) {
+            let mut file = domain.path();
+            file.push(what);
+            let value = fs::read_to_string(&file)
+                .ok()
+                .and_then(|v| u64::from_str(v.trim()).ok());
+            if let Some(value) = value {
+                let name = domain.file_name().to_string_lossy().into_owned();
+                values.push((String::from(name.trim_start_matches("mon_")), value));
+            }
+        }
+
+        values.sort();
+        values
+    }
+}
+
+impl Drop for MonGroup {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir(&self.path) {
+            error!("Can't remove resctrl group {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Moves all threads of process `pid` into the group of `tasks`.
+fn assign_to(tasks: &Path, pid: u32) -> io::Result<()> {
+    for task in fs::read_dir(format!("/proc/{}/task", pid))? {
+        let tid = task?.file_name();
+        // The kernel only accepts one tid per write:
+        let mut f = OpenOptions::new().write(true).open(tasks)?;
+        f.write_all(tid.to_string_lossy().as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Moves the program perf (`perf_pid`) runs into the group of `tasks`, but not
+/// perf itself: perf forks the program before it opens the counters, so we
+/// wait a bit for the child to show up. The program didn't exec yet at that
+/// point, everything it spawns later inherits the group.
+pub fn assign_program(tasks: &Path, perf_pid: u32) -> io::Result<()> {
+    let started = Instant::now();
+    loop {
+        let children = descendants(perf_pid as u64);
+        if !children.is_empty() {
+            for child in children {
+                assign_to(tasks, child as u32)?;
+            }
+            return Ok(());
+        }
+        if started.elapsed() > PROGRAM_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("perf ({}) didn't start the program", perf_pid),
+            ));
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// The L3 domain ids (i.e., the cache ids) of the machine.
+fn l3_domains() -> io::Result<Vec<String>> {
+    let schemata = fs::read_to_string(format!("{}/schemata", RESCTRL_MOUNT))?;
+    let l3 = schemata
+        .lines()
+        .map(|l| l.trim())
+        .find(|l| l.starts_with("L3:"))
+        .ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            "CPU does not support L3 cache allocation",
+        ))?;
+
+    Ok(l3[3..]
+        .split(';')
+        .filter_map(|d| d.split('=').next())
+        .map(|d| String::from(d.trim()))
+        .collect())
+}
+
+/// Splits the L3 ways between two programs: A gets the lowest `ways` ways and B all
+/// the other ones. Returns the capacity bitmasks for (A, B).
+pub fn split_l3_ways(ways: u64) -> io::Result<(u64, u64)> {
+    let cbm_mask = fs::read_to_string(format!("{}/info/L3/cbm_mask", RESCTRL_MOUNT))?;
+    let all = u64::from_str_radix(cbm_mask.trim(), 16)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let total = all.count_ones() as u64;
+    if ways == 0 || ways >= total {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can't give {} out of {} L3 ways to a program", ways, total),
+        ));
+    }
+
+    let a = (1 << ways) - 1;
+    Ok((a, all & !a))
+}
+
+/// A sample: (seconds since start, group, L3 domain, LLC occupancy in bytes,
+/// total and local memory bandwidth in bytes per second since the last sample).
+/// The bandwidth is None for the first sample or if the CPU doesn't support MBM.
+pub type Sample = (f64, String, String, u64, Option<f64>, Option<f64>);
+
+/// Bytes per second between two readings of a MBM counter.
+fn bandwidth(last: Option<&(f64, u64)>, time: f64, bytes: Option<u64>) -> Option<f64> {
+    match (last, bytes) {
+        (Some(&(last_time, last_bytes)), Some(bytes)) if time > last_time => {
+            Some(bytes.saturating_sub(last_bytes) as f64 / (time - last_time))
+        }
+        _ => None,
+    }
+}
+
+pub struct ResctrlMonitor {
+    /// When we took the first sample (`TIME` is relative to it).
+    started: SystemTime,
+    stop: mpsc::Sender<()>,
+    sampler: thread::JoinHandle<(Vec<MonGroup>, Vec<Sample>)>,
+}
+
+impl ResctrlMonitor {
+    /// Periodically samples the LLC occupancy and memory bandwidth of all `groups`.
+    pub fn start(groups: Vec<MonGroup>) -> ResctrlMonitor {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let started = SystemTime::now();
+        let sampler = thread::spawn(move || {
+            let start = Instant::now();
+            let mut samples: Vec<Sample> = Vec::new();
+            // (group, domain) -> (time, bytes) of the last MBM reading:
+            let mut last_total: HashMap<(String, String), (f64, u64)> = HashMap::new();
+            let mut last_local: HashMap<(String, String), (f64, u64)> = HashMap::new();
+            loop {
+                let elapsed = start.elapsed();
+                let time = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+                for group in groups.iter() {
+                    let total: HashMap<String, u64> =
+                        group.read_mon_data("mbm_total_bytes").into_iter().collect();
+                    let local: HashMap<String, u64> =
+                        group.read_mon_data("mbm_local_bytes").into_iter().collect();
+
+                    for (domain, occupancy) in group.read_mon_data("llc_occupancy") {
+                        let key = (String::from(group.name()), domain.clone());
+                        let total_bytes = total.get(&domain).copied();
+                        let local_bytes = local.get(&domain).copied();
+                        let total_bw = bandwidth(last_total.get(&key), time, total_bytes);
+                        let local_bw = bandwidth(last_local.get(&key), time, local_bytes);
+                        if let Some(bytes) = total_bytes {
+                            last_total.insert(key.clone(), (time, bytes));
+                        }
+                        if let Some(bytes) = local_bytes {
+                            last_local.insert(key.clone(), (time, bytes));
+                        }
+
+                        samples.push((
+                            time,
+                            String::from(group.name()),
+                            domain,
+                            occupancy,
+                            total_bw,
+                            local_bw,
+                        ));
+                    }
+                }
+
+                match stopped.recv_timeout(Duration::from_millis(SAMPLE_INTERVAL_MS)) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            }
+            (groups, samples)
+        });
+
+        ResctrlMonitor {
+            started,
+            stop,
+            sampler,
+        }
+    }
+
+    /// Stops monitoring, removes the groups and saves the time series to `save_to`.
+    pub fn finish(self, save_to: &Path) -> io::Result<Vec<Sample>> {
+        let _ = self.stop.send(());
+        let samples = match self.sampler.join() {
+            Ok((groups, samples)) => {
+                drop(groups);
+                samples
+            }
+            Err(_) => Vec::new(),
+        };
+
+        let mut wtr = csv::Writer::from_file(save_to).unwrap();
+        wtr.encode((
+            "TIME",
+            "PROGRAM",
+            "DOMAIN",
+            "LLC_OCCUPANCY",
+            "MBM_TOTAL_BPS",
+            "MBM_LOCAL_BPS",
+            "TIMESTAMP",
+        ))
+        .unwrap();
+        let started = self
+            .started
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        for s in samples.iter() {
+            let timestamp = format!("{:.6}", started + s.0);
+            wtr.encode((&s.0, &s.1, &s.2, &s.3, &s.4, &s.5, timestamp))
+                .unwrap();
+        }
+        wtr.flush().unwrap();
+
+        Ok(samples)
+    }
+}
@@ -0,0 +1,184 @@
+//! Puts the machine into a known state before every run of a `pair` campaign
+//! (`reset` in the manifest), so whether a run starts "cold" or "warm" is a
+//! choice rather than an accident of what ran before it:
+//!
+//! * `page_cache`: write back dirty pages and drop the page cache, dentries and
+//!   inodes (`/proc/sys/vm/drop_caches`, needs root),
+//! * `cpu_caches`: evict the CPU caches. `wbinvd` is privileged, so we sweep
+//!   a buffer twice the size of an L2 on every core and twice the size of an
+//!   L3 on every L3, which replaces whatever was cached with our lines,
+//! * `scratch`: empty these directories (they have to be on a tmpfs, we don't
+//!   delete anything that would survive a reboot).
+//!
+//! What was reset is recorded in `run.toml`.
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::thread;
+
+use tracing::debug;
+
+use super::util::{Cpu, Domain, MachineTopology};
+
+const DROP_CACHES: &str = "/proc/sys/vm/drop_caches";
+
+/// `f_type` of a tmpfs (see statfs(2)).
+const TMPFS_MAGIC: i64 = 0x01021994;
+
+const CACHE_LINE: usize = 64;
+
+#[derive(Debug, Default, Clone, RustcEncodable)]
+pub struct Reset {
+    pub page_cache: bool,
+    pub cpu_caches: bool,
+    pub scratch: Vec<String>,
+}
+
+impl Reset {
+    /// The `reset` of the manifest.
+    pub fn parse(value: &toml::Value) -> Result<Reset, String> {
+        let table = value
+            .as_table()
+            .ok_or(String::from("'reset' should be a table"))?;
+        let mut reset: Reset = Default::default();
+        for (key, value) in table.iter() {
+            match key.as_str() {
+                "page_cache" | "cpu_caches" => {
+                    let on = value
+                        .as_bool()
+                        .ok_or(format!("'{}' of 'reset' should be boolean", key))?;
+                    if key == "page_cache" {
+                        reset.page_cache = on;
+                    } else {
+                        reset.cpu_caches = on;
+                    }
+                }
+                "scratch" => {
+                    reset.scratch = value
+                        .as_slice()
+                        .and_then(|dirs| {
+                            dirs.iter().map(|d| d.as_str().map(String::from)).collect()
+                        })
+                        .ok_or(String::from(
+                            "'scratch' of 'reset' should be a list of directories",
+                        ))?;
+                }
+                _ => return Err(format!("'reset' has an unknown key '{}'", key)),
+            }
+        }
+        Ok(reset)
+    }
+
+    /// Are all `scratch` directories on a tmpfs?
+    pub fn check_scratch(&self) -> Result<(), String> {
+        for dir in self.scratch.iter() {
+            match is_tmpfs(Path::new(dir)) {
+                Ok(true) => (),
+                Ok(false) => {
+                    return Err(format!(
+                        "scratch directory {} isn't on a tmpfs, we won't empty it",
+                        dir
+                    ))
+                }
+                Err(e) => return Err(format!("can't check scratch directory {}: {}", dir, e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Does the manifest ask for anything?
+    pub fn is_empty(&self) -> bool {
+        !self.page_cache && !self.cpu_caches && self.scratch.is_empty()
+    }
+
+    /// Resets everything the manifest asks for.
+    pub fn apply(&self) -> io::Result<()> {
+        if self.page_cache {
+            drop_page_cache()?;
+        }
+        for dir in self.scratch.iter() {
+            empty(Path::new(dir))?;
+        }
+        // Last, emptying the directories brings their metadata into the caches:
+        if self.cpu_caches {
+            flush_cpu_caches(&MachineTopology::new());
+        }
+        Ok(())
+    }
+}
+
+fn is_tmpfs(dir: &Path) -> io::Result<bool> {
+    let c_path = CString::new(dir.as_os_str().as_bytes()).expect("Path contains a 0 byte?");
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_type as i64 == TMPFS_MAGIC)
+}
+
+fn drop_page_cache() -> io::Result<()> {
+    // Only clean pages are dropped:
+    unsafe { libc::sync() };
+    fs::write(DROP_CACHES, "3")
+        .map_err(|e| io::Error::new(e.kind(), format!("can't write {}: {}", DROP_CACHES, e)))?;
+    debug!("Dropped the page cache");
+    Ok(())
+}
+
+/// Removes everything in `dir` (but not `dir`).
+fn empty(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && !path.read_link().is_ok() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    debug!("Emptied {:?}", dir);
+    Ok(())
+}
+
+/// Writes every cache line of a `size` byte buffer on `cpu` (reading a fresh
+/// buffer would only read the zero page).
+fn sweep(cpu: Cpu, size: usize) {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu as usize, &mut set);
+        libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set);
+    }
+    let mut buffer = vec![0u8; size];
+    // Volatile, or the compiler drops the writes to a buffer nobody reads:
+    for line in buffer.chunks_mut(CACHE_LINE) {
+        unsafe { ptr::write_volatile(&mut line[0], 1) };
+    }
+}
+
+/// Evicts the private caches of every core and the L3 of every L3 domain.
+fn flush_cpu_caches(mt: &MachineTopology) {
+    let l2 = mt.l2_size().unwrap_or(2 * 1024 * 1024) as usize;
+    let l3 = mt.l3_size().unwrap_or(64 * 1024 * 1024) as usize;
+    let on_core: Vec<Cpu> = mt
+        .physical_cores_in(Domain::Machine)
+        .into_iter()
+        .flatten()
+        .map(|c| c.cpu)
+        .collect();
+    let on_l3: Vec<Cpu> = mt.same_l3().iter().map(|cpus| cpus[0].cpu).collect();
+    let (cores, l3s) = (on_core.len(), on_l3.len());
+
+    for (cpus, size) in [(on_core, 2 * l2), (on_l3, 2 * l3)] {
+        let sweepers: Vec<_> = cpus
+            .into_iter()
+            .map(|cpu| thread::spawn(move || sweep(cpu, size)))
+            .collect();
+        for sweeper in sweepers {
+            let _ = sweeper.join();
+        }
+    }
+    debug!("Flushed the caches of {} cores and {} L3s", cores, l3s);
+}
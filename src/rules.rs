@@ -0,0 +1,434 @@
+//! Invariants the counter values of a run have to fulfill (e.g., `cycles > 0` or
+//! `L1D.REPLACEMENT <= MEM_INST_RETIRED.ALL_LOADS`), so a counter that was
+//! programmed wrong doesn't go unnoticed.
+//!
+//! Rules are read from a TOML file:
+//!
+//! ```toml
+//! [[rule]]
+//! name = "ipc"
+//! check = "instructions / cycles <= 4"
+//! microarchitecture = ["Skylake", "SkylakeX"]
+//! ```
+//!
+//! A check compares two expressions (`<`, `<=`, `>`, `>=`, `==`, `!=`) made of
+//! numbers, event names, `+ - * /` and parentheses. Event names can contain `-`,
+//! so subtraction needs spaces around it; names with other special characters go
+//! in double quotes. Events stand for the sum of their values in a measurement
+//! file, a rule is checked in every file that measured all of its events.
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use rustc_serialize::json::Json;
+
+use super::aggregate::read_perf_log;
+use super::perfstat::{self, Value};
+use super::profile::Scope;
+use super::validate::{issue, Issue};
+
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct Rule {
+    pub name: String,
+    pub check: String,
+    /// Only check on these microarchitectures (all of them if empty).
+    pub microarchitecture: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Comparison {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Event(String),
+    Operator(char),
+    Open,
+    Close,
+    Compare(Comparison),
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(f64),
+    Event(String),
+    Binary(Box<Expr>, char, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    left: Expr,
+    comparison: Comparison,
+    right: Expr,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == ':' || c == '-'
+}
+
+fn tokenize(check: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = check.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).cloned();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            tokens.push(Token::Number(
+                f64::from_str(&number).map_err(|_| format!("bad number '{}'", number))?,
+            ));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && is_name_char(chars[i]) {
+                i += 1;
+            }
+            tokens.push(Token::Event(chars[start..i].iter().collect()));
+        } else if c == '"' {
+            let start = i + 1;
+            i = start;
+            while i < chars.len() && chars[i] != '"' {
+                i += 1;
+            }
+            if i == chars.len() {
+                return Err(String::from("missing closing '\"'"));
+            }
+            tokens.push(Token::Event(chars[start..i].iter().collect()));
+            i += 1;
+        } else {
+            let (token, length) = match (c, next) {
+                ('<', Some('=')) => (Token::Compare(Comparison::LessEqual), 2),
+                ('>', Some('=')) => (Token::Compare(Comparison::GreaterEqual), 2),
+                ('=', Some('=')) => (Token::Compare(Comparison::Equal), 2),
+                ('!', Some('=')) => (Token::Compare(Comparison::NotEqual), 2),
+                ('<', _) => (Token::Compare(Comparison::Less), 1),
+                ('>', _) => (Token::Compare(Comparison::Greater), 1),
+                ('+', _) | ('-', _) | ('*', _) | ('/', _) => (Token::Operator(c), 1),
+                ('(', _) => (Token::Open, 1),
+                (')', _) => (Token::Close, 1),
+                _ => return Err(format!("unexpected '{}'", c)),
+            };
+            tokens.push(token);
+            i += length;
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser for the expressions of a check.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn binary(
+        &mut self,
+        operators: &[char],
+        operand: fn(&mut Parser) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        let mut left = operand(self)?;
+        loop {
+            let operator = match self.peek() {
+                Some(&Token::Operator(op)) if operators.contains(&op) => op,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            let right = operand(self)?;
+            left = Expr::Binary(Box::new(left), operator, Box::new(right));
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.binary(&['+', '-'], Parser::term)
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        self.binary(&['*', '/'], Parser::factor)
+    }
+
+    fn factor(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Event(e)) => Ok(Expr::Event(e)),
+            Some(Token::Open) => {
+                let expr = self.expr()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err(String::from("missing ')'")),
+                }
+            }
+            Some(t) => Err(format!("unexpected {:?}", t)),
+            None => Err(String::from("unexpected end")),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let left = self.expr()?;
+        let comparison = match self.next() {
+            Some(Token::Compare(c)) => c,
+            _ => return Err(String::from("expected a comparison (<, <=, >, >=, ==, !=)")),
+        };
+        let right = self.expr()?;
+        if self.position < self.tokens.len() {
+            return Err(format!("unexpected {:?}", self.tokens[self.position]));
+        }
+        Ok(Condition {
+            left,
+            comparison,
+            right,
+        })
+    }
+}
+
+impl Expr {
+    fn events<'a>(&'a self, events: &mut Vec<&'a str>) {
+        match *self {
+            Expr::Number(_) => {}
+            Expr::Event(ref e) => {
+                if !events.contains(&e.as_str()) {
+                    events.push(e);
+                }
+            }
+            Expr::Binary(ref left, _, ref right) => {
+                left.events(events);
+                right.events(events);
+            }
+        }
+    }
+
+    fn eval(&self, totals: &HashMap<String, f64>) -> f64 {
+        match *self {
+            Expr::Number(n) => n,
+            Expr::Event(ref e) => totals.get(e).cloned().unwrap_or(0.0),
+            Expr::Binary(ref left, op, ref right) => {
+                let (l, r) = (left.eval(totals), right.eval(totals));
+                match op {
+                    '+' => l + r,
+                    '-' => l - r,
+                    '*' => l * r,
+                    _ => l / r,
+                }
+            }
+        }
+    }
+}
+
+impl Condition {
+    fn parse(check: &str) -> Result<Condition, String> {
+        let mut parser = Parser {
+            tokens: tokenize(check)?,
+            position: 0,
+        };
+        parser.condition()
+    }
+
+    fn events(&self) -> Vec<&str> {
+        let mut events = Vec::new();
+        self.left.events(&mut events);
+        self.right.events(&mut events);
+        events
+    }
+
+    /// Does it hold? (comparisons with NaN, e.g. from 0 / 0, don't)
+    fn holds(&self, totals: &HashMap<String, f64>) -> bool {
+        let (l, r) = (self.left.eval(totals), self.right.eval(totals));
+        match self.comparison {
+            Comparison::Less => l < r,
+            Comparison::LessEqual => l <= r,
+            Comparison::Greater => l > r,
+            Comparison::GreaterEqual => l >= r,
+            Comparison::Equal => l == r,
+            Comparison::NotEqual => l != r,
+        }
+    }
+}
+
+/// An expression of events, like one side of a check (e.g.,
+/// `instructions / cycles`).
+#[derive(Debug, Clone)]
+pub struct Metric {
+    expr: Expr,
+}
+
+impl Metric {
+    pub fn parse(metric: &str) -> Result<Metric, String> {
+        let mut parser = Parser {
+            tokens: tokenize(metric)?,
+            position: 0,
+        };
+        let expr = parser.expr()?;
+        if parser.position < parser.tokens.len() {
+            return Err(format!("unexpected {:?}", parser.tokens[parser.position]));
+        }
+        Ok(Metric { expr })
+    }
+
+    /// The events the metric is computed from.
+    pub fn events(&self) -> Vec<&str> {
+        let mut events = Vec::new();
+        self.expr.events(&mut events);
+        events
+    }
+
+    /// Computes the metric (events that aren't in `values` count as 0).
+    pub fn eval(&self, values: &HashMap<String, f64>) -> f64 {
+        self.expr.eval(values)
+    }
+}
+
+/// Reads the rules in `path`.
+pub fn load(path: &Path) -> Result<Vec<Rule>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Can't read {:?}: {}", path, e))?;
+    let mut parser = toml::Parser::new(content.as_str());
+    let doc = match parser.parse() {
+        Some(doc) => doc,
+        None => return Err(format!("Can't parse {:?}:\n{:?}", path, parser.errors)),
+    };
+
+    let mut rules = Vec::new();
+    let definitions: &[toml::Value] = match doc.get("rule") {
+        Some(r) => r
+            .as_slice()
+            .ok_or(String::from("'rule' should be a list of tables ([[rule]])"))?,
+        None => &[],
+    };
+    for (idx, definition) in definitions.iter().enumerate() {
+        let definition = definition
+            .as_table()
+            .ok_or(String::from("'rule' should be a list of tables ([[rule]])"))?;
+        let check = definition
+            .get("check")
+            .and_then(|c| c.as_str())
+            .ok_or(format!("Rule {} needs a 'check' (string)", idx + 1))?;
+        Condition::parse(check).map_err(|e| format!("Can't parse rule '{}': {}", check, e))?;
+        let microarchitecture = match definition.get("microarchitecture") {
+            Some(m) => m
+                .as_slice()
+                .ok_or(String::from("'microarchitecture' should be a list"))?
+                .iter()
+                .map(|m| {
+                    m.as_str().map(String::from).ok_or(String::from(
+                        "'microarchitecture' elements should be strings",
+                    ))
+                })
+                .collect::<Result<Vec<String>, String>>()?,
+            None => Vec::new(),
+        };
+        rules.push(Rule {
+            name: definition
+                .get("name")
+                .and_then(|n| n.as_str())
+                .map_or(format!("rule{}", idx + 1), String::from),
+            check: String::from(check),
+            microarchitecture,
+        });
+    }
+
+    Ok(rules)
+}
+
+/// The microarchitecture a run was measured on (from its `machine.json`).
+fn microarchitecture(path: &Path) -> Option<String> {
+    let mut machine_json = path.to_path_buf();
+    machine_json.push("machine.json");
+    let content = fs::read_to_string(machine_json).ok()?;
+    let manifest = Json::from_str(&content).ok()?;
+    manifest
+        .find("microarchitecture")
+        .and_then(|m| m.as_string())
+        .filter(|m| !m.is_empty())
+        .map(String::from)
+}
+
+/// The sum of the (counted) values of every event in a perf stat file.
+fn event_totals(scope: Scope, path: &Path) -> HashMap<String, f64> {
+    let mut totals = HashMap::new();
+    let counts = match perfstat::counts(path, scope) {
+        Ok(counts) => counts,
+        Err(_) => return totals,
+    };
+    for count in counts {
+        if let Value::Counted(value) = count.value {
+            *totals.entry(count.event).or_insert(0.0) += value;
+        }
+    }
+    totals
+}
+
+/// Checks `rules` against the measurements of a run (in `path`).
+pub fn check_run(run: &str, path: &Path, rules: &[Rule]) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let arch = microarchitecture(path);
+    let conditions: Vec<(&Rule, Condition)> = rules
+        .iter()
+        .filter(|r| {
+            r.microarchitecture.is_empty()
+                || arch
+                    .as_ref()
+                    .is_some_and(|a| r.microarchitecture.contains(a))
+        })
+        .filter_map(|r| Condition::parse(&r.check).ok().map(|c| (r, c)))
+        .collect();
+    if conditions.is_empty() {
+        return issues;
+    }
+
+    let mut perf_csv = path.to_path_buf();
+    perf_csv.push("perf.csv");
+    if !perf_csv.exists() {
+        return issues;
+    }
+    let (_, rows) = read_perf_log(&perf_csv);
+    for (_, _, _, _, file, perf_command) in rows {
+        if !perfstat::is_stat_file(&file) {
+            continue;
+        }
+        let mut stat_file = path.to_path_buf();
+        stat_file.push(&file);
+        let totals = event_totals(Scope::from_perf_command(&perf_command), &stat_file);
+        for &(rule, ref condition) in conditions.iter() {
+            let events = condition.events();
+            if !events.iter().all(|e| totals.contains_key(*e)) || condition.holds(&totals) {
+                continue;
+            }
+            let values: Vec<String> = events
+                .iter()
+                .map(|e| format!("{} = {}", e, totals[*e]))
+                .collect();
+            issues.push(issue(
+                run,
+                &file,
+                "rule",
+                &rule.name,
+                format!("{} does not hold ({})", rule.check, values.join(", ")),
+            ));
+        }
+    }
+
+    issues
+}
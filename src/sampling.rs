@@ -0,0 +1,251 @@
+//! Runs only part of a `pair` campaign whose cross product (programs × programs ×
+//! configurations × `cat_ways` × ...) is too large to measure all of it. The
+//! `sample` table of the manifest picks the runs:
+//!
+//! * `{ method = "random", fraction = 0.1, seed = 1 }` (or `runs = 200` instead
+//!   of `fraction`): a random sample, stratified by configuration and by whether
+//!   A runs alone, so every configuration gets its share.
+//! * `{ method = "fractional", fraction = 4, block = 0 }`: a regular fraction of
+//!   the full factorial, the runs whose level numbers (of A, B, configuration,
+//!   CAT ways, memory policy and frequency) add up to `block` modulo `fraction`.
+//!   The `fraction` blocks together are the whole campaign.
+//!
+//! The choice only depends on the run IDs and the seed, so it's the same on
+//! every invocation. Every run of the cross product is saved in `sample.csv` of
+//! the campaign with the sample that picked it (empty if none did yet); with
+//! `fill = true` a sample only picks from the runs no earlier sample picked, so
+//! follow-up campaigns fill in the rest.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, warn};
+
+use crate::features::fnv1a;
+
+/// The samples of a campaign directory.
+pub const SAMPLE_FILE: &str = "sample.csv";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Design {
+    /// A share of every stratum, or about `runs` in total.
+    Random {
+        fraction: Option<f64>,
+        runs: Option<usize>,
+        seed: u64,
+    },
+    /// One of `parts` blocks of the full factorial.
+    Fractional { parts: u64, block: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sampling {
+    pub design: Design,
+    /// Only pick runs no earlier sample picked.
+    pub fill: bool,
+}
+
+/// A run of the cross product.
+pub struct Cell {
+    pub run_id: String,
+    /// Relative to the campaign directory.
+    pub path: PathBuf,
+    /// Configuration, and whether A runs alone.
+    pub stratum: String,
+    /// Which level of every factor the run has.
+    pub levels: Vec<usize>,
+}
+
+impl Sampling {
+    /// The `sample` of the manifest.
+    pub fn parse(value: &toml::Value) -> Result<Sampling, String> {
+        let t = value.as_table().ok_or(String::from(
+            "'sample' should be a table, e.g. { method = \"random\", fraction = 0.1 }",
+        ))?;
+        let integer = |key: &str| -> Result<Option<u64>, String> {
+            match t.get(key) {
+                Some(&toml::Value::Integer(i)) if i >= 0 => Ok(Some(i as u64)),
+                Some(_) => Err(format!(
+                    "'{}' of 'sample' should be a positive integer",
+                    key
+                )),
+                None => Ok(None),
+            }
+        };
+        let fill = match t.get("fill") {
+            Some(&toml::Value::Boolean(fill)) => fill,
+            Some(_) => return Err(String::from("'fill' of 'sample' should be boolean")),
+            None => false,
+        };
+        let (design, keys): (Design, &[&str]) = match t.get("method").and_then(|m| m.as_str()) {
+            Some("random") => {
+                let fraction = match t.get("fraction") {
+                    Some(&toml::Value::Float(f)) if f > 0.0 && f <= 1.0 => Some(f),
+                    Some(_) => {
+                        return Err(String::from(
+                            "'fraction' of a random 'sample' should be between 0 and 1",
+                        ))
+                    }
+                    None => None,
+                };
+                let runs = integer("runs")?.map(|r| r as usize);
+                if fraction.is_some() == runs.is_some() {
+                    return Err(String::from(
+                        "A random 'sample' needs either a 'fraction' or the number of 'runs'",
+                    ));
+                }
+                (
+                    Design::Random {
+                        fraction,
+                        runs,
+                        seed: integer("seed")?.unwrap_or(0),
+                    },
+                    &["method", "fraction", "runs", "seed", "fill"],
+                )
+            }
+            Some("fractional") => {
+                let parts = match integer("fraction")? {
+                    Some(parts) if parts > 0 => parts,
+                    _ => return Err(String::from("A fractional 'sample' needs a 'fraction'")),
+                };
+                let block = integer("block")?.unwrap_or(0);
+                if block >= parts {
+                    return Err(format!(
+                        "'block' of 'sample' should be below the fraction ({})",
+                        parts
+                    ));
+                }
+                (
+                    Design::Fractional { parts, block },
+                    &["method", "fraction", "block", "fill"],
+                )
+            }
+            _ => {
+                return Err(String::from(
+                    "'method' of 'sample' should be \"random\" or \"fractional\"",
+                ))
+            }
+        };
+        if let Some(key) = t.keys().find(|k| !keys.contains(&k.as_str())) {
+            return Err(format!("'sample' has an unknown key '{}'", key));
+        }
+        Ok(Sampling { design, fill })
+    }
+
+    /// What we call the sample in `sample.csv`.
+    pub fn label(&self) -> String {
+        match self.design {
+            Design::Random {
+                fraction: Some(fraction),
+                seed,
+                ..
+            } => format!("random-{}-seed{}", fraction, seed),
+            Design::Random {
+                runs: Some(runs),
+                seed,
+                ..
+            } => format!("random-{}runs-seed{}", runs, seed),
+            Design::Random { seed, .. } => format!("random-seed{}", seed),
+            Design::Fractional { parts, block } => format!("fractional-{}of{}", block, parts),
+        }
+    }
+
+    /// Which of `cells` the sample picks. `picked` has the runs earlier samples
+    /// picked (by run ID), `fill` leaves them out.
+    pub fn select(&self, cells: &[Cell], picked: &BTreeMap<String, String>) -> Vec<bool> {
+        let candidates: Vec<usize> = (0..cells.len())
+            .filter(|&i| !self.fill || !picked.contains_key(&cells[i].run_id))
+            .collect();
+        let mut selected = vec![false; cells.len()];
+        match self.design {
+            Design::Fractional { parts, block } => {
+                for i in candidates {
+                    let sum: usize = cells[i].levels.iter().sum();
+                    selected[i] = sum as u64 % parts == block;
+                }
+            }
+            Design::Random {
+                fraction,
+                runs,
+                seed,
+            } => {
+                let mut strata: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+                for i in candidates.iter() {
+                    strata
+                        .entry(cells[*i].stratum.as_str())
+                        .or_default()
+                        .push(*i);
+                }
+                let total = candidates.len();
+                for (_, mut members) in strata {
+                    members.sort_by_key(|i| fnv1a(seed, &cells[*i].run_id));
+                    let share = match (fraction, runs) {
+                        (Some(fraction), _) => fraction,
+                        (None, Some(runs)) if total > 0 => (runs as f64 / total as f64).min(1.0),
+                        _ => 0.0,
+                    };
+                    // Every stratum gets at least one run:
+                    let take = ((members.len() as f64 * share).round() as usize).max(1);
+                    for i in members.into_iter().take(take) {
+                        selected[i] = true;
+                    }
+                }
+            }
+        }
+        selected
+    }
+}
+
+/// The runs samples of the campaign in `campaign` picked (run ID -> sample).
+pub fn read_picked(campaign: &Path) -> BTreeMap<String, String> {
+    let mut picked = BTreeMap::new();
+    let path = campaign.join(SAMPLE_FILE);
+    let mut rdr = match csv::Reader::from_file(&path) {
+        Ok(rdr) => rdr,
+        Err(_) => return picked,
+    };
+    for record in rdr.decode() {
+        let record: csv::Result<(String, String, String, String)> = record;
+        match record {
+            Ok((run_id, _path, _stratum, sample)) => {
+                if !sample.is_empty() {
+                    picked.insert(run_id, sample);
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Can't read {:?} ({}), earlier samples are ignored.",
+                    path, e
+                );
+                return BTreeMap::new();
+            }
+        }
+    }
+    picked
+}
+
+/// Saves every run of the cross product with the sample that picked it.
+pub fn save(campaign: &Path, cells: &[Cell], picked: &BTreeMap<String, String>) -> io::Result<()> {
+    // Readers should never see a half-written file:
+    let path = campaign.join(SAMPLE_FILE);
+    let tmp = path.with_extension("csv.tmp");
+    {
+        let mut wtr = csv::Writer::from_file(&tmp).map_err(io::Error::other)?;
+        let io_error = |e: csv::Error| io::Error::other(e);
+        wtr.encode(("RUN_ID", "PATH", "STRATUM", "SAMPLE"))
+            .map_err(io_error)?;
+        for cell in cells {
+            wtr.encode((
+                &cell.run_id,
+                cell.path.to_string_lossy(),
+                &cell.stratum,
+                picked.get(&cell.run_id).map_or("", |s| s.as_str()),
+            ))
+            .map_err(io_error)?;
+        }
+        wtr.flush().map_err(io_error)?;
+    }
+    debug!("Saved the sample of {} runs in {:?}", cells.len(), path);
+    fs::rename(&tmp, &path)
+}
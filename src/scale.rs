@@ -1,163 +1,221 @@
-use std::path::Path;
+//! How a program scales with its number of threads (`autoperf scale`): we run
+//! it with the thread counts where the topology changes (one core, the cores of
+//! an L3, the cores of a socket and all cores) or the ones given, every count
+//! pinned to as many cores (one CPU of every core, the cores of an L3 next to
+//! each other), and measure every run like `profile` does.
+//!
+//! The program learns its number of threads from an environment variable
+//! (`OMP_NUM_THREADS` by default) and from `$NUM_THREADS` in its arguments.
+//! `scale.csv` has the scaling efficiency of every step, relative to the
+//! smallest count: the speedup, the efficiency (speedup per thread) and the
+//! serial fraction (Karp-Flatt metric) of the program.
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-#[allow(unused)]
-pub fn scale(_manifest_folder: &Path, _dryrun: bool, _start: usize, _stepping: usize) {
-    // let canonical_manifest_path = fs::canonicalize(&manifest_folder)
-    // .expect("canonicalize manifest path does not work");
-    //
-    // let mut out_dir = canonical_manifest_path.to_path_buf();
-    // let hostname = get_hostname().unwrap_or(String::from("unknown"));
-    // out_dir.push(hostname);
-    // mkdir(&out_dir);
-    //
-    // let mt = MachineTopology::new();
-    //
-    // let mut manifest: PathBuf = canonical_manifest_path.to_path_buf();
-    // manifest.push("manifest.toml");
-    // let mut file = File::open(manifest.as_path()).expect("manifest.toml file does not exist?");
-    // let mut manifest_string = String::new();
-    // let _ = file.read_to_string(&mut manifest_string).unwrap();
-    // let mut parser = toml::Parser::new(manifest_string.as_str());
-    // let doc = match parser.parse() {
-    // Some(doc) => doc,
-    // None => {
-    // error!("Can't parse the manifest file:\n{:?}", parser.errors);
-    // process::exit(1);
-    // }
-    // };
-    // let experiment: &toml::Table = doc["experiment"]
-    // .as_table()
-    // .expect("Error in manifest.toml: 'experiment' should be a table.");
-    // let configuration: &[toml::Value] = experiment["configurations"]
-    // .as_slice()
-    // .expect("Error in manifest.toml: 'configuration' attribute should be a list.");
-    // let configs: Vec<String> = configuration.iter()
-    // .map(|s| s.as_str().expect("configuration elements should be strings").to_string())
-    // .collect();
-    // let run_alone: bool = experiment.get("alone")
-    // .map_or(true, |v| v.as_bool().expect("'alone' should be boolean"));
-    // let profile_only: Option<Vec<String>> = experiment.get("profile_only_a")
-    // .map(|progs| {
-    // progs.as_slice()
-    // .expect("Error in manifest.toml: 'profile_only_a' should be a list.")
-    // .into_iter()
-    // .map(|p| {
-    // p.as_str()
-    // .expect("profile_only_a elements should name programs (strings)")
-    // .to_string()
-    // })
-    // .collect()
-    // });
-    // let profile_only_b: Option<Vec<String>> = experiment.get("profile_only_b")
-    // .map(|progs| {
-    // progs.as_slice()
-    // .expect("Error in manifest.toml: 'profile_only_b' should be a list.")
-    // .into_iter()
-    // .map(|p| {
-    // p.as_str()
-    // .expect("profile_only_b elements should name programs (strings)")
-    // .to_string()
-    // })
-    // .collect()
-    // });
-    //
-    //
-    // let mut programs: Vec<Program> = Vec::with_capacity(2);
-    // for (key, value) in &doc {
-    // if key.starts_with("program") {
-    // let program_desc: &toml::Table =
-    // doc[key].as_table().expect("Error in manifest.toml: 'program' should be a table.");
-    // programs.push(Program::from_toml(&canonical_manifest_path, program_desc, run_alone));
-    // }
-    // }
-    //
-    // let mut deployments: Vec<Deployment> = Vec::with_capacity(4);
-    // for config in configs {
-    // match config.as_str() {
-    // "L1-SMT" => {
-    // deployments.push(Deployment::split_interleaved("L1-SMT",
-    // mt.same_l1(),
-    // mt.l1_size().unwrap_or(0)))
-    // }
-    // "L3-SMT" => {
-    // deployments.push(Deployment::split_interleaved("L3-SMT",
-    // mt.same_l3(),
-    // mt.l3_size().unwrap_or(0)))
-    // }
-    // "L3-SMT-cores" => {
-    // deployments.push(Deployment::split_smt_aware("L3-SMT-cores",
-    // mt.same_l3(),
-    // mt.l3_size().unwrap_or(0)))
-    // }
-    // "L3-cores" => {
-    // deployments.push(Deployment::split_smt_aware("L3-cores",
-    // mt.same_l3_cores(),
-    // mt.l3_size().unwrap_or(0)))
-    // }
-    // "Full-L3" => {
-    // deployments.push(Deployment::split_l3_aware("Full-L3",
-    // mt.whole_machine_cores(),
-    // mt.l3_size().unwrap_or(0)))
-    // }
-    // "Full-SMT-L3" => {
-    // deployments.push(Deployment::split_l3_aware("Full-SMT-L3",
-    // mt.whole_machine(),
-    // mt.l3_size().unwrap_or(0)))
-    // }
-    // "Full-cores" => {
-    // deployments.push(Deployment::split_interleaved("Full-cores",
-    // mt.whole_machine_cores(),
-    // mt.l3_size().unwrap_or(0)))
-    // }
-    // "Full-SMT-cores" => {
-    // deployments.push(Deployment::split_smt_aware("Full-SMT-cores",
-    // mt.whole_machine(),
-    // mt.l3_size().unwrap_or(0)))
-    // }
-    //
-    // _ => error!("Ignored unknown deployment config '{}'.", config),
-    // };
-    // }
-    //
-    // Add all possible pairs:
-    // let mut pairs: Vec<(&Program, Option<&Program>)> = Vec::new();
-    // for p in programs.iter() {
-    // pairs.push((p, None));
-    // }
-    // for (a, b) in iproduct!(programs.iter(), programs.iter()) {
-    // pairs.push((a, Some(b)));
-    // }
-    //
-    // Filter out the pairs we do not want to execute:
-    // let mut runs: Vec<Run> = Vec::new();
-    // for (a, b) in pairs.into_iter() {
-    // let profile_a = profile_only.as_ref().map_or(true, |ps| ps.contains(&a.name));
-    // let profile_b = !b.is_none() &&
-    // profile_only_b.as_ref()
-    // .map_or(profile_a, |ps| ps.contains(&b.unwrap().name));
-    // if !profile_a && !profile_b {
-    // continue;
-    // }
-    //
-    // for d in deployments.iter() {
-    // if b.is_none() && (!run_alone || !a.alone) {
-    // continue;
-    // }
-    // runs.push(Run::new(&canonical_manifest_path, out_dir.as_path(), a, b, d));
-    // }
-    // }
-    //
-    // Finally, profile the runs we are supposed to execute based on the command line args
-    // let mut i = 0;
-    // for run in runs.iter_mut().skip(start).step(stepping) {
-    // if !dryrun {
-    // run.profile();
-    // } else {
-    // println!("{}", run);
-    // }
-    // i += 1;
-    // }
-    //
-    // println!("{} runs completed.", i);
-    //
+use tracing::{debug, error, info, warn};
+
+use super::columnar::result_writer;
+use super::profile::{self, ProfileSettings};
+use super::summary::{self, Failure};
+use super::util::{Core, Cpu, CpuInfo, MachineTopology};
+
+pub const SCALE_COLUMNS: [&str; 7] = [
+    "THREADS",
+    "CPUS",
+    "SECONDS",
+    "SPEEDUP",
+    "EFFICIENCY",
+    "SERIAL_FRACTION",
+    "DIRECTORY",
+];
+
+/// One CPU of every core in the order we fill them: the cores of an L3 next to
+/// each other and the L3s of a socket before the next socket. The core of
+/// `housekeeping_cpu` comes last.
+pub fn core_order(mt: &MachineTopology, housekeeping_cpu: Option<Cpu>) -> Vec<Cpu> {
+    let housekeeping_core = housekeeping_cpu.and_then(|c| mt.cpu(c)).map(|c| c.core);
+    let mut seen = BTreeSet::new();
+    let mut cpus: Vec<_> = mt.cpus().into_iter().filter_map(|c| mt.cpu(c)).collect();
+    cpus.sort_by_key(|c| {
+        (
+            Some(c.core) == housekeeping_core,
+            c.socket,
+            c.l3,
+            c.core,
+            c.cpu,
+        )
+    });
+    cpus.into_iter()
+        .filter(|c| seen.insert(c.core))
+        .map(|c| c.cpu)
+        .collect()
+}
+
+/// The thread counts where the topology changes: one core, the cores of an L3,
+/// the cores of a socket and all cores.
+pub fn topology_steps(mt: &MachineTopology) -> Vec<usize> {
+    let cpus: Vec<_> = mt.cpus().into_iter().filter_map(|c| mt.cpu(c)).collect();
+    let cores = |on: &dyn Fn(&CpuInfo) -> bool| {
+        cpus.iter()
+            .filter(|c| on(c))
+            .map(|c| c.core)
+            .collect::<BTreeSet<Core>>()
+            .len()
+    };
+    let first = match cpus.iter().min_by_key(|c| (c.socket, c.l3, c.core)) {
+        Some(first) => first,
+        None => return Vec::new(),
+    };
+    let mut steps = vec![
+        1,
+        cores(&|c| c.l3 == first.l3),
+        cores(&|c| c.socket == first.socket),
+        cores(&|_| true),
+    ];
+    steps.sort();
+    steps.dedup();
+    steps
+}
+
+/// `cmd` for `threads` threads, pinned to `cpus`.
+fn command(cmd: &[String], cpus: &[Cpu], threads: usize) -> Vec<String> {
+    let cpu_list: Vec<String> = cpus.iter().map(|c| c.to_string()).collect();
+    let mut pinned = vec![
+        String::from("taskset"),
+        String::from("-c"),
+        cpu_list.join(","),
+    ];
+    pinned.extend(
+        cmd.iter()
+            .map(|a| a.replace("$NUM_THREADS", &threads.to_string())),
+    );
+    pinned
+}
+
+/// How long the measurements of a step took on average: the time of the last
+/// sample in every `<nr>_pressure.csv`, it's taken when perf exits.
+fn measurement_seconds(step_dir: &Path) -> Option<f64> {
+    let mut durations = Vec::new();
+    for entry in fs::read_dir(step_dir).ok()?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let is_pressure = path
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().ends_with("_pressure.csv"));
+        if !is_pressure {
+            continue;
+        }
+        let mut rdr = match csv::Reader::from_file(&path) {
+            Ok(rdr) => rdr.has_headers(true),
+            Err(_) => continue,
+        };
+        let last = rdr
+            .decode()
+            .filter_map(|r: csv::Result<(f64, String, String, f64)>| r.ok())
+            .map(|(time, _, _, _)| time)
+            .fold(None, |max: Option<f64>, t| {
+                Some(max.map_or(t, |m| m.max(t)))
+            });
+        durations.extend(last);
+    }
+    if durations.is_empty() {
+        return None;
+    }
+    Some(durations.iter().sum::<f64>() / durations.len() as f64)
+}
+
+/// Runs `cmd` with every thread count of `steps` in `output` (`t<threads>`)
+/// and saves their scaling efficiency in `scale.csv`.
+pub fn scale(
+    output: &Path,
+    cmd: &[String],
+    steps: &[usize],
+    threads_env: &str,
+    housekeeping_cpu: Option<Cpu>,
+    settings: &ProfileSettings,
+    dryrun: bool,
+) {
+    let mt = MachineTopology::new();
+    let cores = core_order(&mt, housekeeping_cpu);
+    if let Some(&too_many) = steps.iter().find(|&&s| s == 0 || s > cores.len()) {
+        error!(
+            "Can't run {} threads, there are {} cores (one thread per core).",
+            too_many,
+            cores.len()
+        );
+        summary::fail(Failure::Error);
+    }
+    if housekeeping_cpu.is_some() && steps.contains(&cores.len()) {
+        warn!("The step with all cores shares a core with the housekeeping CPU.");
+    }
+    info!("Measuring with {:?} threads", steps);
+    summary::planned(steps.len());
+
+    let mut measured: Vec<(usize, Vec<Cpu>, PathBuf)> = Vec::new();
+    for &threads in steps.iter() {
+        let cpus: Vec<Cpu> = cores[..threads].to_vec();
+        let mut step_dir = output.to_path_buf();
+        step_dir.push(format!("t{}", threads));
+        debug!(
+            "Measuring {} threads on {:?} in {:?}",
+            threads, cpus, step_dir
+        );
+
+        let step_settings = ProfileSettings {
+            cpus: Some(cpus.clone()),
+            ..settings.clone()
+        };
+        profile::profile(
+            &step_dir,
+            ".",
+            command(cmd, &cpus, threads),
+            vec![(String::from(threads_env), threads.to_string())],
+            Vec::new(),
+            false,
+            None,
+            &step_settings,
+            dryrun,
+        );
+        summary::run_ended(false);
+        measured.push((threads, cpus, step_dir));
+    }
+    if dryrun {
+        return;
+    }
+
+    let mut scale_csv = output.to_path_buf();
+    scale_csv.push("scale.csv");
+    let mut wrtr = result_writer("csv", &scale_csv, &SCALE_COLUMNS);
+    let mut base: Option<(usize, f64)> = None;
+    for (threads, cpus, step_dir) in measured {
+        let seconds = match measurement_seconds(&step_dir) {
+            Some(seconds) => seconds,
+            None => {
+                warn!("Don't know how long the run with {} threads took.", threads);
+                continue;
+            }
+        };
+        let (base_threads, base_seconds) = *base.get_or_insert((threads, seconds));
+        let speedup = base_seconds / seconds;
+        // The threads relative to the base step, e.g. 4 for 8 threads over 2:
+        let p = threads as f64 / base_threads as f64;
+        let efficiency = speedup / p;
+        let serial_fraction = if p > 1.0 {
+            ((1.0 / speedup - 1.0 / p) / (1.0 - 1.0 / p)).to_string()
+        } else {
+            String::new()
+        };
+        let cpu_list: Vec<String> = cpus.iter().map(|c| c.to_string()).collect();
+        wrtr.write_row(&[
+            threads.to_string().as_str(),
+            cpu_list.join(" ").as_str(),
+            seconds.to_string().as_str(),
+            speedup.to_string().as_str(),
+            efficiency.to_string().as_str(),
+            serial_fraction.as_str(),
+            step_dir.to_string_lossy().as_ref(),
+        ]);
+    }
+    wrtr.finish();
+    info!("Saved {:?}", scale_csv);
 }
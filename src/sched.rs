@@ -0,0 +1,174 @@
+//! Records what the scheduler does (`perf sched record`) while two programs are
+//! co-located. The recording is summarized with `perf sched timehist` into the
+//! runtime share and wait times every program had on a CPU, this way we can tell
+//! interference caused by the scheduler apart from microarchitectural interference.
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+
+use tracing::{debug, error, warn};
+
+/// Linux truncates task names to 15 characters.
+const TASK_COMM_LEN: usize = 15;
+
+pub struct SchedRecorder {
+    child: Child,
+    data: PathBuf,
+}
+
+impl SchedRecorder {
+    /// Records all scheduling events on the machine for `seconds` (in the background).
+    pub fn start(data: &Path, seconds: u64) -> io::Result<SchedRecorder> {
+        let child = Command::new("perf")
+            .arg("sched")
+            .arg("record")
+            .arg("-a")
+            .arg("-o")
+            .arg(data.as_os_str())
+            .arg("--")
+            .arg("sleep")
+            .arg(seconds.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        Ok(SchedRecorder {
+            child,
+            data: data.to_path_buf(),
+        })
+    }
+
+    /// Waits for the recording to finish.
+    pub fn finish(mut self) -> io::Result<PathBuf> {
+        let status = self.child.wait()?;
+        if !status.success() {
+            warn!("perf sched record exited with {}", status);
+        }
+        Ok(self.data)
+    }
+}
+
+/// The name the kernel will use for a task started from `binary`.
+pub fn comm_of(binary: &str) -> String {
+    let name = Path::new(binary)
+        .file_name()
+        .map_or(String::from(binary), |f| f.to_string_lossy().into_owned());
+    name.chars().take(TASK_COMM_LEN).collect()
+}
+
+/// A line of `perf sched timehist`: (time, cpu, task name, wait time, scheduling delay, run time).
+/// All times except the timestamp are in milliseconds.
+type TimehistRow = (f64, u64, String, f64, f64, f64);
+
+fn parse_timehist_line(line: &str) -> Option<TimehistRow> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 6 {
+        return None;
+    }
+
+    let time = f64::from_str(fields[0]).ok()?;
+    let cpu = u64::from_str(fields[1].trim_matches(|c| c == '[' || c == ']')).ok()?;
+    let n = fields.len();
+    let wait = f64::from_str(fields[n - 3]).ok()?;
+    let delay = f64::from_str(fields[n - 2]).ok()?;
+    let run = f64::from_str(fields[n - 1]).ok()?;
+
+    // The task name looks like comm[tid] or comm[tid/pid] (and comm can have spaces):
+    let task = fields[2..n - 3].join(" ");
+    let comm = match task.rfind('[') {
+        Some(idx) => String::from(&task[..idx]),
+        None => task,
+    };
+
+    Some((time, cpu, comm, wait, delay, run))
+}
+
+/// Summarizes the scheduler recording in `data` and saves it to `save_to`.
+/// `programs` are (label, binary) of the co-located programs, every task that
+/// doesn't belong to one of them is accounted as "other" (or "idle").
+pub fn summarize(data: &Path, programs: &[(&str, &str)], save_to: &Path) -> io::Result<()> {
+    let out = Command::new("perf")
+        .arg("sched")
+        .arg("timehist")
+        .arg("-i")
+        .arg(data.as_os_str())
+        .output()?;
+    if !out.status.success() {
+        error!(
+            "perf sched timehist failed: {}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        return Err(io::Error::other("perf sched timehist failed"));
+    }
+
+    let comms: Vec<(&str, String)> = programs
+        .iter()
+        .map(|&(label, binary)| (label, comm_of(binary)))
+        .collect();
+    let program_of = |comm: &str| -> String {
+        if comm == "<idle>" {
+            return String::from("idle");
+        }
+        let labels: Vec<&str> = comms
+            .iter()
+            .filter(|&(_, c)| c == comm)
+            .map(|&(label, _)| label)
+            .collect();
+        if !labels.is_empty() {
+            labels.join("+")
+        } else {
+            String::from("other")
+        }
+    };
+
+    // (cpu, program) -> (run time, wait time, scheduling delay, time slices)
+    let mut stats: BTreeMap<(u64, String), (f64, f64, f64, u64)> = BTreeMap::new();
+    let mut first: Option<f64> = None;
+    let mut last: f64 = 0.0;
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+        let (time, cpu, comm, wait, delay, run) = match parse_timehist_line(line) {
+            Some(row) => row,
+            None => continue,
+        };
+        first = first.or(Some(time));
+        last = time;
+
+        let entry = stats
+            .entry((cpu, program_of(comm.as_str())))
+            .or_insert((0.0, 0.0, 0.0, 0));
+        entry.0 += run;
+        entry.1 += wait;
+        entry.2 += delay;
+        entry.3 += 1;
+    }
+
+    let window_ms = (last - first.unwrap_or(last)) * 1000.0;
+    debug!("Recorded {} ms of scheduler activity.", window_ms);
+
+    let mut wtr = csv::Writer::from_file(save_to).unwrap();
+    wtr.encode((
+        "CPU",
+        "PROGRAM",
+        "RUNTIME_MS",
+        "WAIT_MS",
+        "SCH_DELAY_MS",
+        "SLICES",
+        "SHARE",
+    ))
+    .unwrap();
+    for ((cpu, program), (run, wait, delay, slices)) in stats.into_iter() {
+        let share = if window_ms > 0.0 {
+            run / window_ms
+        } else {
+            0.0
+        };
+        wtr.encode((cpu, program, run, wait, delay, slices, share))
+            .unwrap();
+    }
+    wtr.flush().unwrap();
+
+    Ok(())
+}
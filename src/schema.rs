@@ -0,0 +1,195 @@
+//! JSON Schemas of the files autoperf writes (`autoperf schema`), so pipelines
+//! that read them can validate them, and a change of a format shows up as a
+//! change of its schema.
+//!
+//! The schemas of `machine.json`, `gpus.json`, `nics.json`, `clock.toml`, `workload.toml`,
+//! `validation.json` and `summary.json` come from the structs we save. The rows of `perf.csv` and of
+//! the aggregated results are written column by column, the structs here only
+//! describe them (a CSV row is an object with a property per column). Every
+//! schema carries `SCHEMA_VERSION` in its `$id`.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
+
+use super::gpu::GpuInfo;
+use super::machine::MachineManifest;
+use super::nic::NicInfo;
+use super::profile::Scope;
+use super::summary::Summary;
+use super::util::{ClockInfo, SCHEMA_VERSION};
+use super::validate::Report;
+use super::workload::Workload;
+
+/// A row of `perf.csv`, one for every measurement of a run.
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+struct PerfLogRow {
+    /// The program and its arguments.
+    command: String,
+    /// The events of the measurement (comma-separated).
+    event_names: String,
+    /// The events as the backend was asked to count them (comma-separated).
+    perf_events: String,
+    /// The breakpoints `perf record` used (comma-separated, empty for stat).
+    breakpoints: String,
+    /// The file with the counter values, e.g. `1_stat.csv`.
+    datafile: String,
+    /// How the values were measured (with `--per-thread` etc. it tells how to read
+    /// `datafile`).
+    perf_command: String,
+    stdout: String,
+    /// What the program printed on stderr.
+    stdin: String,
+    schema_version: u64,
+    /// When the measurement started (seconds since the epoch, to the microsecond).
+    started: Option<f64>,
+}
+
+/// A row of the aggregated results (`results.csv` or `results.parquet`). The
+/// location columns the scope doesn't have are empty.
+#[derive(JsonSchema)]
+#[schemars(rename_all = "SCREAMING_SNAKE_CASE")]
+#[allow(dead_code)]
+struct ResultRow {
+    /// The run (only in the results of a `pair` campaign).
+    run: Option<String>,
+    event_name: String,
+    /// The interval of the sample.
+    index: u64,
+    /// End of the interval (seconds since the program started).
+    time: f64,
+    socket: Option<u64>,
+    core: Option<u64>,
+    cpu: Option<u64>,
+    node: Option<u64>,
+    /// The PMU that counted the event (cpu or an uncore unit).
+    unit: String,
+    sample_value: f64,
+    /// The phase the program reported (empty without phase markers).
+    phase: String,
+    tid: Option<u64>,
+    /// The name of the thread.
+    thread: String,
+    scope: Scope,
+}
+
+/// A row of `confidence_intervals.csv` (see `bootstrap.rs`).
+#[derive(JsonSchema)]
+#[schemars(rename_all = "SCREAMING_SNAKE_CASE")]
+#[allow(dead_code)]
+struct ConfidenceIntervalRow {
+    /// The run (only for a `pair` campaign).
+    run: Option<String>,
+    event_name: String,
+    /// How many intervals the event has.
+    n: u64,
+    /// The mean of its interval totals.
+    mean: f64,
+    /// Bounds of the 95% bootstrap confidence interval of the mean (empty with
+    /// fewer than two intervals or `--bootstrap 0`).
+    ci_lower: Option<f64>,
+    ci_upper: Option<f64>,
+}
+
+/// A row of `efficiency.csv` (see `efficiency.rs`).
+#[derive(JsonSchema)]
+#[schemars(rename_all = "SCREAMING_SNAKE_CASE")]
+#[allow(dead_code)]
+struct EfficiencyRow {
+    /// The run (only for a `pair` campaign).
+    run: Option<String>,
+    /// The RAPL energy event, e.g. `power/energy-pkg/`.
+    domain: String,
+    energy_j: f64,
+    /// How long the energy was measured.
+    seconds: f64,
+    power_w: f64,
+    /// Energy-delay product (J·s).
+    edp: f64,
+    /// Energy-delay² product (J·s²).
+    ed2p: f64,
+    /// The mean figure of merit (empty without one).
+    merit: Option<f64>,
+    /// The figure of merit over the mean power.
+    merit_per_joule: Option<f64>,
+}
+
+/// A row of `per_core_type.csv` (see `coretypes.rs`).
+#[derive(JsonSchema)]
+#[schemars(rename_all = "SCREAMING_SNAKE_CASE")]
+#[allow(dead_code)]
+struct CoreTypeRow {
+    /// The run (only for a `pair` campaign).
+    run: Option<String>,
+    /// `P` or `E`.
+    core_type: String,
+    /// How many CPUs of the core type counted.
+    cpus: u64,
+    /// Instructions per cycle of the core type (empty without both).
+    ipc: Option<f64>,
+    /// Without the PMU (`cycles` for `cpu_core/cycles/`).
+    event_name: String,
+    total: f64,
+    per_cpu: f64,
+    /// Per 1000 instructions of the core type (empty without instructions).
+    pki: Option<f64>,
+}
+
+/// Name, file and schema of the artifacts we describe.
+fn schemas() -> Vec<(&'static str, &'static str, RootSchema)> {
+    vec![
+        ("machine", "machine.json", schema_for!(MachineManifest)),
+        ("gpus", "gpus.json", schema_for!(Vec<GpuInfo>)),
+        ("nics", "nics.json", schema_for!(Vec<NicInfo>)),
+        ("clock", "clock.toml", schema_for!(ClockInfo)),
+        ("workload", "workload.toml", schema_for!(Workload)),
+        ("perf-log", "perf.csv", schema_for!(PerfLogRow)),
+        ("results", "results.csv", schema_for!(ResultRow)),
+        (
+            "confidence-intervals",
+            "confidence_intervals.csv",
+            schema_for!(ConfidenceIntervalRow),
+        ),
+        ("efficiency", "efficiency.csv", schema_for!(EfficiencyRow)),
+        ("core-types", "per_core_type.csv", schema_for!(CoreTypeRow)),
+        ("validation", "validation.json", schema_for!(Report)),
+        ("summary", "summary.json", schema_for!(Summary)),
+    ]
+}
+
+pub fn artifacts() -> Vec<&'static str> {
+    schemas().iter().map(|&(name, _, _)| name).collect()
+}
+
+fn to_json(name: &str, file: &str, mut schema: RootSchema) -> String {
+    let metadata = schema.schema.metadata();
+    metadata.id = Some(format!(
+        "https://github.com/gz/autoperf/schema/v{}/{}.schema.json",
+        SCHEMA_VERSION, name
+    ));
+    metadata.title = Some(format!("{} (schema version {})", file, SCHEMA_VERSION));
+    serde_json::to_string_pretty(&schema).unwrap()
+}
+
+/// The schema of `artifact` (see `artifacts`), None if we don't know it.
+pub fn schema(artifact: &str) -> Option<String> {
+    schemas()
+        .into_iter()
+        .find(|&(name, _, _)| name == artifact)
+        .map(|(name, file, schema)| to_json(name, file, schema))
+}
+
+/// Writes `<artifact>.schema.json` for all artifacts to `directory`.
+pub fn save_schemas(directory: &Path) -> io::Result<usize> {
+    let schemas = schemas();
+    let count = schemas.len();
+    for (name, file, schema) in schemas {
+        let mut path = directory.to_path_buf();
+        path.push(format!("{}.schema.json", name));
+        fs::write(&path, to_json(name, file, schema) + "\n")?;
+    }
+    Ok(count)
+}
@@ -7,10 +7,12 @@ use std::path::PathBuf;
 use std::process::Command;
 
 use csv;
+use regex::Regex;
 
+use super::completions;
 use super::profile;
 use super::profile::{MonitoringUnit, PerfEvent};
-use log::*;
+use tracing::{debug, error, warn};
 use x86::perfcnt::intel::{Counter, EventDescription, MSRIndex, PebsType, Tuple};
 
 pub fn event_is_documented(
@@ -139,8 +141,15 @@ where
     for group in event_groups {
         let mut _event_names: Vec<&str> = group.get_event_names();
         let counters: Vec<String> = group.get_perf_config_strings();
-        let mut perf =
-            profile::get_perf_command(cmd_working_dir, output_path, &env, &breakpoints, record);
+        let mut perf = profile::get_perf_command(
+            cmd_working_dir,
+            output_path,
+            &env,
+            &breakpoints,
+            record,
+            false,
+            &Default::default(),
+        );
         let mut found_events = execute_perf(&mut perf, &cmd, &counters);
         all_events.append(&mut found_events);
     }
@@ -148,6 +157,27 @@ where
     all_events
 }
 
+/// Prints the events of the catalog (see `completions.rs`) whose name,
+/// description or unit matches `pattern`, with their counter constraints.
+pub fn print_catalog_events(pattern: &Regex) {
+    let matching: Vec<completions::CatalogEvent> = completions::event_catalog()
+        .into_iter()
+        .filter(|e| {
+            pattern.is_match(&e.name)
+                || pattern.is_match(&e.description)
+                || pattern.is_match(&e.unit)
+        })
+        .collect();
+    if matching.is_empty() {
+        warn!("No event of this CPU matches '{}'.", pattern);
+        return;
+    }
+    for event in matching {
+        println!("{} ({}; {})", event.name, event.unit, event.counters);
+        println!("    {}", event.description);
+    }
+}
+
 pub fn print_unknown_events() {
     let events = profile::get_known_events();
     let pevents: Vec<PerfEvent> = events.into_iter().map(|e| PerfEvent(e)).collect();
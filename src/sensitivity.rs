@@ -0,0 +1,138 @@
+//! Bubble-up style characterization of the workloads of a `pair` campaign: how
+//! sensitive every program is to its neighbours (how much it slows down next to
+//! the others), and how contentious it is (how much the others slow down next
+//! to it), for every sharing domain (the placements of the experiment, e.g.
+//! `L3-SMT` shares a core, `L3` only the cache).
+//!
+//! A slowdown is the IPC of A alone over its IPC next to B, so 1.3 means 30%
+//! fewer instructions per cycle. The sensitivity of a program is its mean
+//! slowdown as A, its contentiousness the mean slowdown of the programs it ran
+//! next to as B, both minus 1 (0 is no interference at all):
+//!
+//! ```text
+//! WORKLOAD,DOMAIN,SENSITIVITY,CONTENTIOUSNESS,AS_VICTIM,AS_ANTAGONIST
+//! gcc,L3-SMT,0.31,0.12,5,5
+//! stream,L3-SMT,0.08,0.45,5,5
+//! ```
+//!
+//! Programs only measured as B (or without runs alone) have no sensitivity.
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use tracing::{debug, warn};
+
+use crate::diff::{self, mean};
+use crate::interference::{campaign_runs, ipc, tmp_directory, with_run};
+
+/// The slowdowns of a workload in a domain.
+#[derive(Default)]
+struct Slowdowns {
+    /// Of itself, next to others.
+    as_victim: Vec<f64>,
+    /// Of the others, next to it.
+    as_antagonist: Vec<f64>,
+}
+
+/// Mean slowdown minus 1, empty without slowdowns.
+fn score(slowdowns: &[f64]) -> String {
+    if slowdowns.is_empty() {
+        String::new()
+    } else {
+        (mean(slowdowns) - 1.0).to_string()
+    }
+}
+
+/// Scores the workloads of the campaign in `campaign` and writes them as CSV to
+/// `out` (see the module doc). Returns how many rows there are.
+pub fn sensitivity<W: io::Write>(campaign: &Path, out: W) -> io::Result<usize> {
+    let tmp = tmp_directory();
+    let runs = campaign_runs(campaign, &tmp);
+    let ipc_of = |path: &Path| with_run(path, &tmp, |dir| ipc(&diff::interval_totals(dir)));
+
+    // (placement, run name) -> IPC of A alone
+    let mut solos: BTreeMap<(String, String), f64> = BTreeMap::new();
+    for run in runs.iter().filter(|r| r.b.is_none()) {
+        match ipc_of(&run.path) {
+            Some(ipc) => {
+                solos.insert((run.placement.clone(), run.name.clone()), ipc);
+            }
+            None => warn!(
+                "{}/{} has no instructions and cycles, {} has no baseline there.",
+                run.placement, run.name, run.a
+            ),
+        }
+    }
+
+    // (workload, domain) -> its slowdowns
+    let mut workloads: BTreeMap<(String, String), Slowdowns> = BTreeMap::new();
+    for run in runs.iter() {
+        let b = match run.b {
+            Some(ref b) => b,
+            None => continue,
+        };
+        let solo_ipc = match run.solo().and_then(|solo| solos.get(&solo)) {
+            Some(ipc) => *ipc,
+            None => {
+                warn!(
+                    "{}/{} has no run of {} alone to compare with (experiment.alone), ignoring it.",
+                    run.placement, run.name, run.a
+                );
+                continue;
+            }
+        };
+        let slowdown = match ipc_of(&run.path) {
+            Some(ipc) if ipc > 0.0 => solo_ipc / ipc,
+            _ => {
+                warn!(
+                    "{}/{} has no instructions and cycles, ignoring it.",
+                    run.placement, run.name
+                );
+                continue;
+            }
+        };
+        debug!(
+            "{} is {} times slower next to {} in {}",
+            run.a, slowdown, b, run.placement
+        );
+        workloads
+            .entry((run.a.clone(), run.placement.clone()))
+            .or_default()
+            .as_victim
+            .push(slowdown);
+        workloads
+            .entry((b.clone(), run.placement.clone()))
+            .or_default()
+            .as_antagonist
+            .push(slowdown);
+    }
+    if workloads.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no co-located runs with a run alone to compare with",
+        ));
+    }
+
+    let mut wtr = csv::Writer::from_writer(out);
+    wtr.encode([
+        "WORKLOAD",
+        "DOMAIN",
+        "SENSITIVITY",
+        "CONTENTIOUSNESS",
+        "AS_VICTIM",
+        "AS_ANTAGONIST",
+    ])
+    .unwrap();
+    for ((workload, domain), slowdowns) in workloads.iter() {
+        wtr.encode((
+            workload,
+            domain,
+            score(&slowdowns.as_victim),
+            score(&slowdowns.as_antagonist),
+            slowdowns.as_victim.len(),
+            slowdowns.as_antagonist.len(),
+        ))
+        .unwrap();
+    }
+    Ok(workloads.len())
+}
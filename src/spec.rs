@@ -0,0 +1,348 @@
+//! Turns a SPEC CPU 2017 installation into a `pair` campaign: every benchmark
+//! (and every input of it, perlbench has three) becomes a program of the
+//! manifest, so we don't need a wrapper script per benchmark.
+//!
+//! SPEC has to set up the run directories first (`runcpu --action setup --size
+//! refrate --tune base <benchmarks>`). Every run directory has the commands of
+//! the benchmark in `speccmds.cmd`, `specinvoke -n` prints them the way they'd
+//! be run (for every copy, we take the first). The environment (`-E`) and the
+//! working directory (`-C`) come from `speccmds.cmd` itself.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use regex::Regex;
+use tracing::{debug, error, info, warn};
+
+use super::summary::{self, Failure};
+
+/// The file with the invocations in a run directory.
+const SPECCMDS: &str = "speccmds.cmd";
+
+/// How a benchmark runs one of its inputs.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Invocation {
+    /// e.g. 500.perlbench_r
+    pub benchmark: String,
+    /// The benchmark, with the number of the input if it has more than one
+    /// (500.perlbench_r-2).
+    pub name: String,
+    pub working_dir: PathBuf,
+    pub binary: String,
+    pub arguments: Vec<String>,
+    pub env: Vec<(String, String)>,
+}
+
+/// The latest run directory SPEC set up for `benchmark_dir` with `tune` and
+/// `size`, e.g. `run/run_base_refrate_mytest-m64.0001`.
+fn run_directory(benchmark_dir: &Path, tune: &str, size: &str) -> Option<PathBuf> {
+    let prefix = format!("run_{}_{}_", tune, size);
+    fs::read_dir(benchmark_dir.join("run"))
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|d| {
+            d.file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with(&prefix))
+                && d.join(SPECCMDS).exists()
+        })
+        .max()
+}
+
+/// The environment (`-E NAME VALUE`) and working directory (`-C DIR`) in
+/// `speccmds.cmd`.
+fn parse_speccmds(speccmds: &str) -> (Vec<(String, String)>, Option<PathBuf>) {
+    let mut env = Vec::new();
+    let mut working_dir = None;
+    for line in speccmds.lines() {
+        let mut parts = line.trim().splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("-E"), Some(name), value) => {
+                env.push((String::from(name), String::from(value.unwrap_or(""))))
+            }
+            (Some("-C"), Some(dir), None) => working_dir = Some(PathBuf::from(dir)),
+            _ => {}
+        }
+    }
+    (env, working_dir)
+}
+
+/// The commands of the first copy in what `specinvoke -n` printed, e.g.
+/// `../run_base_refrate_none.0000/mcf_r_base.none inp.in  > inp.out 2>> inp.err`.
+fn parse_specinvoke(output: &str) -> Vec<Vec<String>> {
+    let mut commands = Vec::new();
+    let mut copies = 0;
+    for line in output.lines().map(|l| l.trim()) {
+        if line.starts_with("# Starting run for copy") {
+            copies += 1;
+            continue;
+        }
+        if copies > 1 {
+            break;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        commands.push(line.split_whitespace().map(String::from).collect());
+    }
+    commands
+}
+
+/// `word` quoted for sh.
+fn quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/// The binary and arguments of `command` (relative to `working_dir`). Output
+/// redirections are dropped (autoperf keeps what the program prints), a
+/// benchmark that reads its input from stdin is started by sh.
+pub fn binary_and_arguments(
+    command: &[String],
+    working_dir: &Path,
+) -> Option<(String, Vec<String>)> {
+    let mut words: Vec<&str> = Vec::new();
+    let mut stdin: Option<&str> = None;
+    let mut tokens = command.iter().map(|t| t.as_str());
+    while let Some(token) = tokens.next() {
+        let redirect = ["2>>", "2>", ">>", ">", "<"]
+            .iter()
+            .find(|r| token.starts_with(*r));
+        match redirect {
+            Some(r) => {
+                // `> out` or `>out`:
+                let file = if token.len() > r.len() {
+                    Some(&token[r.len()..])
+                } else {
+                    tokens.next()
+                };
+                if *r == "<" {
+                    stdin = file;
+                }
+            }
+            None => words.push(token),
+        }
+    }
+
+    let (binary, arguments) = words.split_first()?;
+    let binary = if binary.contains('/') && Path::new(binary).is_relative() {
+        let binary = working_dir.join(binary);
+        fs::canonicalize(&binary)
+            .unwrap_or(binary)
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        String::from(*binary)
+    };
+    let arguments: Vec<String> = arguments.iter().map(|a| String::from(*a)).collect();
+    match stdin {
+        Some(stdin) => {
+            let mut command: Vec<String> = vec![quote(&binary)];
+            command.extend(arguments.iter().map(|a| quote(a)));
+            Some((
+                String::from("sh"),
+                vec![
+                    String::from("-c"),
+                    format!("exec {} < {}", command.join(" "), quote(stdin)),
+                ],
+            ))
+        }
+        None => Some((binary, arguments)),
+    }
+}
+
+/// What `specinvoke -n` prints for the run directory `run_dir`.
+fn specinvoke(spec: &Path, run_dir: &Path) -> Result<String, String> {
+    let specinvoke = spec.join("bin").join("specinvoke");
+    let out = Command::new(&specinvoke)
+        .args(["-n", "-f", SPECCMDS])
+        .current_dir(run_dir)
+        .output()
+        .map_err(|e| format!("Can't run {:?}: {}", specinvoke, e))?;
+    if !out.status.success() {
+        return Err(format!(
+            "{:?} failed in {:?} with {}: {}",
+            specinvoke,
+            run_dir,
+            out.status,
+            String::from_utf8_lossy(&out.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// The invocations of the benchmarks in `spec` that match `benchmarks` (all if
+/// none) from their latest run directories for `tune` and `size`.
+pub fn invocations(
+    spec: &Path,
+    tune: &str,
+    size: &str,
+    benchmarks: Option<&Regex>,
+) -> Result<Vec<Invocation>, String> {
+    let benchspec = spec.join("benchspec").join("CPU");
+    let mut benchmark_dirs: Vec<PathBuf> = fs::read_dir(&benchspec)
+        .map_err(|e| format!("Can't read {:?} (is this SPEC CPU 2017?): {}", benchspec, e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|d| d.is_dir())
+        .collect();
+    benchmark_dirs.sort();
+
+    let mut invocations = Vec::new();
+    let mut not_set_up = Vec::new();
+    for benchmark_dir in benchmark_dirs {
+        let benchmark = benchmark_dir
+            .file_name()
+            .map_or(String::new(), |n| n.to_string_lossy().into_owned());
+        if !benchmarks.is_none_or(|b| b.is_match(&benchmark)) {
+            continue;
+        }
+        let run_dir = match run_directory(&benchmark_dir, tune, size) {
+            Some(run_dir) => run_dir,
+            None => {
+                not_set_up.push(benchmark);
+                continue;
+            }
+        };
+        debug!("Reading the invocations of {} in {:?}", benchmark, run_dir);
+
+        let speccmds = fs::read_to_string(run_dir.join(SPECCMDS))
+            .map_err(|e| format!("Can't read {:?}: {}", run_dir.join(SPECCMDS), e))?;
+        let (env, working_dir) = parse_speccmds(&speccmds);
+        let working_dir = working_dir.unwrap_or(run_dir.clone());
+        let commands: Vec<(String, Vec<String>)> = parse_specinvoke(&specinvoke(spec, &run_dir)?)
+            .iter()
+            .filter_map(|c| binary_and_arguments(c, &working_dir))
+            .collect();
+        for (idx, (binary, arguments)) in commands.iter().enumerate() {
+            invocations.push(Invocation {
+                benchmark: benchmark.clone(),
+                name: if commands.len() > 1 {
+                    format!("{}-{}", benchmark, idx + 1)
+                } else {
+                    benchmark.clone()
+                },
+                working_dir: working_dir.clone(),
+                binary: binary.clone(),
+                arguments: arguments.clone(),
+                env: env.clone(),
+            });
+        }
+    }
+
+    if !not_set_up.is_empty() {
+        warn!(
+            "No {} {} run directory for {} (run `runcpu --action setup --tune {} --size {}` for them).",
+            tune,
+            size,
+            not_set_up.join(", "),
+            tune,
+            size
+        );
+    }
+    Ok(invocations)
+}
+
+/// The `pair` manifest with a program for every invocation, run in the
+/// deployments `configurations`.
+pub fn manifest(invocations: &[Invocation], configurations: &[String]) -> toml::Table {
+    let mut doc = toml::Table::new();
+    let mut experiment = toml::Table::new();
+    experiment.insert(
+        String::from("configurations"),
+        toml::Value::Array(
+            configurations
+                .iter()
+                .map(|c| toml::Value::String(c.clone()))
+                .collect(),
+        ),
+    );
+    experiment.insert(String::from("alone"), toml::Value::Boolean(true));
+    doc.insert(String::from("experiment"), toml::Value::Table(experiment));
+
+    for invocation in invocations.iter() {
+        let string = |s: &str| toml::Value::String(String::from(s));
+        let mut program = toml::Table::new();
+        program.insert(String::from("name"), string(&invocation.name));
+        program.insert(String::from("binary"), string(&invocation.binary));
+        program.insert(
+            String::from("working_dir"),
+            string(&invocation.working_dir.to_string_lossy()),
+        );
+        program.insert(
+            String::from("arguments"),
+            toml::Value::Array(invocation.arguments.iter().map(|a| string(a)).collect()),
+        );
+        if !invocation.env.is_empty() {
+            let env: BTreeMap<String, toml::Value> = invocation
+                .env
+                .iter()
+                .map(|(k, v)| (k.clone(), string(v)))
+                .collect();
+            program.insert(String::from("env"), toml::Value::Table(env));
+        }
+        // Table names are bare keys (no dots):
+        doc.insert(
+            format!("program_{}", invocation.name.replace('.', "_")),
+            toml::Value::Table(program),
+        );
+    }
+    doc
+}
+
+/// Writes `manifest.toml` in `output` for the benchmarks of the SPEC CPU 2017
+/// installation `spec`.
+pub fn generate(
+    spec: &Path,
+    tune: &str,
+    size: &str,
+    benchmarks: Option<&Regex>,
+    configurations: &[String],
+    output: &Path,
+) {
+    if !spec.join("bin").join("specinvoke").exists() {
+        error!(
+            "{:?} has no bin/specinvoke, is it a SPEC CPU 2017 installation?",
+            spec
+        );
+        summary::fail(Failure::Environment);
+    }
+    let invocations = match invocations(spec, tune, size, benchmarks) {
+        Ok(invocations) => invocations,
+        Err(e) => {
+            error!("{}", e);
+            summary::fail(Failure::Error);
+        }
+    };
+    if invocations.is_empty() {
+        error!("Found no benchmarks to run in {:?}.", spec);
+        summary::fail(Failure::Error);
+    }
+
+    let mut manifest_path = output.to_path_buf();
+    manifest_path.push("manifest.toml");
+    let content = format!(
+        "# Generated by `autoperf spec` from {} ({} {})\n{}",
+        spec.to_string_lossy(),
+        tune,
+        size,
+        toml::Value::Table(manifest(&invocations, configurations))
+    );
+    let r = File::create(&manifest_path).and_then(|mut f| f.write_all(content.as_bytes()));
+    if let Err(e) = r {
+        error!("Can't write {:?}: {}", manifest_path, e);
+        summary::fail(Failure::Error);
+    }
+    info!(
+        "Saved {} invocations of {} benchmarks in {:?}",
+        invocations.len(),
+        invocations
+            .iter()
+            .map(|i| i.benchmark.as_str())
+            .collect::<BTreeSet<&str>>()
+            .len(),
+        manifest_path
+    );
+}
@@ -14,7 +14,7 @@ use super::profile::{MonitoringUnit, PerfEvent};
 use super::util::*;
 
 type EventMap = Map<&'static str, EventDescription<'static>>;
-type ArchitectureMap = HashMap<&'static str, (&'static str, &'static str, &'static str)>;
+pub type ArchitectureMap = HashMap<&'static str, (&'static str, &'static str, &'static str)>;
 
 /// Saves the event count for all architectures to a file.
 fn save_event_counts(key_to_name: &ArchitectureMap, csv_result: &Path) {
@@ -305,10 +305,9 @@ fn save_event_descriptions(output_path: &Path) {
     assert!(r.is_ok());
 }
 
-/// Generate all the stats about Intel events and save them to a file.
-pub fn stats(output_path: &Path) {
-    mkdir(output_path);
-
+/// The Intel microarchitectures we know about: cpuid key (vendor-family-model) ->
+/// (name, year, number of programmable counters).
+pub fn architectures() -> ArchitectureMap {
     // TODO: Ideally this should come from x86 crate: x86data/perfmon_data/mapfile.csv
     let mut key_to_name = HashMap::new();
     key_to_name.insert("GenuineIntel-6-1C", ("Bonnell", "2008", "4"));
@@ -332,6 +331,15 @@ pub fn stats(output_path: &Path) {
     key_to_name.insert("GenuineIntel-6-57", ("KnightsLanding", "2016", "4"));
     key_to_name.insert("GenuineIntel-6-55", ("SkylakeX", "2017", "8"));
 
+    key_to_name
+}
+
+/// Generate all the stats about Intel events and save them to a file.
+pub fn stats(output_path: &Path) {
+    mkdir(output_path);
+
+    let key_to_name = architectures();
+
     let mut csv_result_file = output_path.to_path_buf();
     csv_result_file.push("events.csv");
     save_event_counts(&key_to_name, csv_result_file.as_path());
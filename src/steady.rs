@@ -0,0 +1,381 @@
+//! Starts measuring a server-style program only once it reached its steady
+//! state (`profile --steady-state`), instead of after a fixed delay: perf starts
+//! with the counters disabled (`-D -1`) and we enable them through its control
+//! FIFO once a metric stayed within a tolerance band around its mean for a
+//! while. The metric is either
+//!
+//! * `ipc`: the instructions per cycle of the CPUs we measure, counted by a
+//!   second perf every second (it stops before the counters are enabled), or
+//! * `rate`: whatever rate the program reports, one number per line written
+//!   into the FIFO named by `AUTOPERF_RATE` (e.g. its requests per second).
+//!
+//! If the metric doesn't settle before the timeout, we measure anyway (and say
+//! so in `<nr>_steady_state.csv`).
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use super::util::Cpu;
+
+/// The environment variable that tells a program where to write its rate.
+pub const RATE_ENV: &str = "AUTOPERF_RATE";
+
+/// The columns of `<nr>_steady_state.csv`.
+pub const STEADY_STATE_COLUMNS: [&str; 4] = ["metric", "settled", "seconds", "value"];
+
+/// How often the IPC is sampled while we wait.
+const SAMPLE_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Metric {
+    Ipc,
+    Rate,
+}
+
+impl Metric {
+    pub fn new(what: &str) -> Metric {
+        match what {
+            "ipc" => Metric::Ipc,
+            "rate" => Metric::Rate,
+            _ => panic!("clap-rs should ensure nothing else is passed..."),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Metric::Ipc => "ipc",
+            Metric::Rate => "rate",
+        }
+    }
+}
+
+/// When is a program in its steady state?
+#[derive(Debug, Clone)]
+pub struct SteadyState {
+    pub metric: Metric,
+    /// How far (relative to the mean) a sample may be off, e.g. 0.05.
+    pub tolerance: f64,
+    /// How long all samples have to be within the tolerance.
+    pub window: Duration,
+    /// Start measuring after this long, even if the metric didn't settle.
+    pub timeout: Duration,
+}
+
+/// How waiting for the steady state went.
+#[derive(Debug, Clone, Default)]
+struct Settling {
+    /// Did the metric settle (or did we give up, or the program exit)?
+    settled: bool,
+    /// How long it took until we enabled the counters.
+    seconds: f64,
+    /// The mean of the metric over the last window.
+    value: f64,
+}
+
+fn to_io_error(e: csv::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn mkfifo(path: &Path) -> io::Result<()> {
+    let _ = fs::remove_file(path);
+    let c_path = CString::new(path.as_os_str().as_bytes()).expect("Path contains a 0 byte?");
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Is the window of `samples` (oldest first) covered and within `tolerance` of its
+/// mean? Returns the mean.
+fn settled(samples: &VecDeque<(Instant, f64)>, window: Duration, tolerance: f64) -> Option<f64> {
+    let newest = samples.back()?.0;
+    let covered = samples
+        .front()
+        .is_some_and(|s| newest.duration_since(s.0) >= window);
+    if !covered {
+        return None;
+    }
+    let in_window: Vec<f64> = samples
+        .iter()
+        .filter(|s| newest.duration_since(s.0) <= window)
+        .map(|s| s.1)
+        .collect();
+    let mean = in_window.iter().sum::<f64>() / in_window.len() as f64;
+    if mean > 0.0
+        && in_window
+            .iter()
+            .all(|v| (v - mean).abs() <= tolerance * mean)
+    {
+        Some(mean)
+    } else {
+        None
+    }
+}
+
+/// The instructions and cycles of `cpus` (all if None), counted every second.
+fn start_ipc_sampler(
+    cpus: Option<&[Cpu]>,
+    samples: mpsc::Sender<(Instant, f64)>,
+) -> io::Result<Child> {
+    let mut perf = Command::new("perf");
+    perf.args(["stat", "-x", ";", "-e", "instructions,cycles", "-a"]);
+    if let Some(cpus) = cpus {
+        let cpus: Vec<String> = cpus.iter().map(|c| c.to_string()).collect();
+        perf.arg("-C").arg(cpus.join(","));
+    }
+    perf.arg("-I").arg(SAMPLE_MS.to_string());
+    perf.env("LC_NUMERIC", "C");
+    let mut child = perf
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stderr = child.stderr.take().expect("stderr is piped");
+    thread::spawn(move || {
+        // `1.000163687;1234567;;instructions;...` and then the cycles of the interval:
+        let mut instructions: Option<(String, f64)> = None;
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            let fields: Vec<&str> = line.split(';').map(|f| f.trim()).collect();
+            if fields.len() < 4 {
+                continue;
+            }
+            let value = match f64::from_str(fields[1]) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            if fields[3].starts_with("instructions") {
+                instructions = Some((String::from(fields[0]), value));
+            } else if fields[3].starts_with("cycles") {
+                if let Some((time, inst)) = instructions.take() {
+                    if time == fields[0]
+                        && value > 0.0
+                        && samples.send((Instant::now(), inst / value)).is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    Ok(child)
+}
+
+/// The rates the program writes into `fifo`, one per line.
+fn start_rate_reader(fifo: &Path, samples: mpsc::Sender<(Instant, f64)>) -> io::Result<()> {
+    // Open for reading and writing, so we never see EOF (like the phase markers):
+    let file = OpenOptions::new().read(true).write(true).open(fifo)?;
+    thread::spawn(move || {
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            match f64::from_str(line.trim()) {
+                Ok(rate) => {
+                    if samples.send((Instant::now(), rate)).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => debug!("Ignoring rate '{}'", line.trim()),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The FIFOs through which we wait for the steady state of one measurement.
+pub struct Gate {
+    steady: SteadyState,
+    cpus: Option<Vec<Cpu>>,
+    measure_for: Option<Duration>,
+    control: PathBuf,
+    ack: PathBuf,
+    rate: Option<PathBuf>,
+}
+
+impl Gate {
+    /// Creates perf's control FIFOs (and the rate FIFO) in `dir`. Once the
+    /// program is steady we measure for `measure_for` (or until it exits).
+    pub fn new(
+        dir: &Path,
+        steady: &SteadyState,
+        cpus: Option<Vec<Cpu>>,
+        measure_for: Option<Duration>,
+    ) -> io::Result<Gate> {
+        let gate = Gate {
+            steady: steady.clone(),
+            cpus,
+            measure_for,
+            control: dir.join("perf_control.fifo"),
+            ack: dir.join("perf_ack.fifo"),
+            rate: match steady.metric {
+                Metric::Rate => Some(dir.join("rate.fifo")),
+                Metric::Ipc => None,
+            },
+        };
+        mkfifo(&gate.control)?;
+        mkfifo(&gate.ack)?;
+        if let Some(ref rate) = gate.rate {
+            mkfifo(rate)?;
+        }
+        Ok(gate)
+    }
+
+    /// What perf needs to start with disabled counters we can enable.
+    pub fn perf_args(&self) -> Vec<String> {
+        vec![
+            String::from("-D"),
+            String::from("-1"),
+            String::from("--control"),
+            format!(
+                "fifo:{},{}",
+                self.control.to_string_lossy(),
+                self.ack.to_string_lossy()
+            ),
+        ]
+    }
+
+    /// The FIFO the program writes its rate into (for `rate`).
+    pub fn rate_fifo(&self) -> Option<&Path> {
+        self.rate.as_deref()
+    }
+
+    /// Waits for the steady state while perf (`perf_pid`) runs, enables its
+    /// counters and stops it again after `measure_for`.
+    pub fn start(self, perf_pid: u32) -> io::Result<Waiter> {
+        // Read and write, so opening doesn't block until perf opened its end (and
+        // perf doesn't block opening the acknowledgements):
+        let mut control = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.control)?;
+        let ack = OpenOptions::new().read(true).write(true).open(&self.ack)?;
+        let (samples_tx, samples) = mpsc::channel::<(Instant, f64)>();
+        let mut sampler = match self.rate {
+            Some(ref fifo) => {
+                start_rate_reader(fifo, samples_tx)?;
+                None
+            }
+            None => Some(start_ipc_sampler(self.cpus.as_deref(), samples_tx)?),
+        };
+
+        let steady = self.steady.clone();
+        let measure_for = self.measure_for;
+        let (done, exited) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let _ack = ack;
+            let start = Instant::now();
+            let mut window: VecDeque<(Instant, f64)> = VecDeque::new();
+            let mut settling = Settling::default();
+            let perf_exited = loop {
+                if exited.try_recv().is_ok() {
+                    break true;
+                }
+                if start.elapsed() >= steady.timeout {
+                    warn!(
+                        "The {} didn't settle within {:?}, measuring anyway.",
+                        steady.metric.name(),
+                        steady.timeout
+                    );
+                    settling.value = window.back().map_or(0.0, |s| s.1);
+                    break false;
+                }
+                match samples.recv_timeout(Duration::from_millis(100)) {
+                    Ok(sample) => window.push_back(sample),
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    // No more samples, we'll wait for the timeout:
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        thread::sleep(Duration::from_millis(100));
+                        continue;
+                    }
+                }
+                // Keep one sample older than the window:
+                while window.len() > 2 && window[1].0.elapsed() >= steady.window {
+                    window.pop_front();
+                }
+                if let Some(mean) = settled(&window, steady.window, steady.tolerance) {
+                    settling.settled = true;
+                    settling.value = mean;
+                    break false;
+                }
+            };
+            settling.seconds = start.elapsed().as_secs_f64();
+
+            // The second perf would compete with the measurement for counters:
+            if let Some(mut sampler) = sampler.take() {
+                let _ = sampler.kill();
+                let _ = sampler.wait();
+            }
+            if perf_exited {
+                warn!("The program exited before it reached its steady state.");
+                return settling;
+            }
+            debug!(
+                "Steady state after {:.1} s ({} = {:.3}), enabling the counters.",
+                settling.seconds,
+                steady.metric.name(),
+                settling.value
+            );
+            if let Err(e) = control.write_all(b"enable\n") {
+                warn!("Can't enable the counters of perf: {}", e);
+                return settling;
+            }
+            if let Some(window) = measure_for {
+                if let Err(mpsc::RecvTimeoutError::Timeout) = exited.recv_timeout(window) {
+                    debug!("Measurement window of {:?} is over, stopping perf.", window);
+                    unsafe {
+                        libc::kill(perf_pid as libc::pid_t, libc::SIGINT);
+                    }
+                }
+            }
+            settling
+        });
+
+        Ok(Waiter {
+            gate: self,
+            done,
+            handle,
+        })
+    }
+}
+
+pub struct Waiter {
+    gate: Gate,
+    done: mpsc::Sender<()>,
+    handle: thread::JoinHandle<Settling>,
+}
+
+impl Waiter {
+    /// Tells the waiting thread that perf exited, removes the FIFOs and saves
+    /// how long the program took to settle in `path`.
+    pub fn finish(self, path: &Path) -> io::Result<()> {
+        let _ = self.done.send(());
+        let settling = self.handle.join().unwrap_or_default();
+        let _ = fs::remove_file(&self.gate.control);
+        let _ = fs::remove_file(&self.gate.ack);
+        if let Some(ref rate) = self.gate.rate {
+            let _ = fs::remove_file(rate);
+        }
+
+        let mut wtr = csv::Writer::from_file(path).map_err(to_io_error)?;
+        wtr.encode(STEADY_STATE_COLUMNS).map_err(to_io_error)?;
+        wtr.encode((
+            self.gate.steady.metric.name(),
+            settling.settled,
+            settling.seconds,
+            settling.value,
+        ))
+        .map_err(to_io_error)?;
+        wtr.flush().map_err(to_io_error)
+    }
+}
@@ -0,0 +1,517 @@
+//! An SQLite database with the results of a whole `pair` campaign.
+//!
+//! Runs, their configurations, events and the measured values are kept in
+//! normalized tables, so a single event can be looked at across all
+//! placements without going through thousands of CSV files (see `autoperf query`).
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use tracing::{error, warn};
+
+use super::aggregate::PERF_METRICS_COLUMNS;
+use super::columnar::{ResultWriter, RESULT_COLUMNS};
+use super::util::SCHEMA_VERSION;
+
+/// Name of the database file in the campaign directory.
+pub const STORE_FILE: &str = "results.sqlite";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS configurations (
+    id INTEGER PRIMARY KEY,
+    a TEXT NOT NULL,
+    b TEXT,
+    deployment TEXT NOT NULL,
+    cat_ways INTEGER,
+    memory_policy TEXT,
+    frequency_mhz INTEGER,
+    priority_a TEXT,
+    priority_b TEXT
+);
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    configuration_id INTEGER NOT NULL REFERENCES configurations(id),
+    directory TEXT NOT NULL UNIQUE,
+    schema_version INTEGER NOT NULL,
+    added TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+CREATE TABLE IF NOT EXISTS samples (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    event_id INTEGER NOT NULL REFERENCES events(id),
+    idx INTEGER,
+    time REAL,
+    socket INTEGER,
+    core INTEGER,
+    cpu INTEGER,
+    node INTEGER,
+    unit TEXT,
+    value INTEGER,
+    phase TEXT,
+    tid INTEGER,
+    thread TEXT,
+    scope TEXT
+);
+CREATE INDEX IF NOT EXISTS samples_by_event ON samples (event_id, run_id);
+CREATE TABLE IF NOT EXISTS perf_metrics (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    file TEXT,
+    time REAL,
+    location TEXT,
+    event TEXT,
+    value REAL,
+    unit TEXT
+);
+";
+
+/// Selection for `autoperf query` (all of them are optional, `event` may
+/// contain SQL `LIKE` wildcards).
+#[derive(Debug, Default)]
+pub struct Query<'a> {
+    pub event: Option<&'a str>,
+    pub a: Option<&'a str>,
+    pub b: Option<&'a str>,
+    pub deployment: Option<&'a str>,
+    pub memory_policy: Option<&'a str>,
+    pub frequency_mhz: Option<u64>,
+    pub priority_a: Option<&'a str>,
+    pub priority_b: Option<&'a str>,
+    pub phase: Option<&'a str>,
+    /// Sum up the values of every event per run.
+    pub total: bool,
+}
+
+/// What a run measured: the programs, how they were deployed and the settings
+/// of the machine (the `configurations` table).
+#[derive(Debug)]
+pub struct Configuration<'a> {
+    pub a: &'a str,
+    pub b: Option<&'a str>,
+    pub deployment: &'a str,
+    pub cat_ways: Option<u64>,
+    pub memory_policy: Option<&'a str>,
+    pub frequency_mhz: Option<u64>,
+    pub priority_a: Option<&'a str>,
+    pub priority_b: Option<&'a str>,
+}
+
+pub struct Store {
+    conn: Connection,
+}
+
+/// Inserts the aggregated results of one run.
+pub struct StoreWriter<'a> {
+    conn: &'a Connection,
+    run: i64,
+    events: HashMap<String, i64>,
+    /// Counts that don't fit in an INTEGER, stored as REAL.
+    large: usize,
+    /// Sampled (perf record) rows, the store only has counts.
+    sampled: usize,
+}
+
+/// Inserts the perf metrics (see `PERF_METRICS_COLUMNS`) of one run, in the
+/// transaction of its `StoreWriter`.
+pub struct PerfMetricsWriter<'a> {
+    conn: &'a Connection,
+    run: i64,
+}
+
+fn to_sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn integer(value: &str) -> Option<i64> {
+    value.parse::<i64>().ok()
+}
+
+/// Counts are integers, the metrics of metric groups aren't. SQLite only has
+/// signed 64-bit integers, counts from 2^63 on are stored as REAL (and counted
+/// in `large`).
+fn sample_value(value: &str, large: &mut usize) -> Value {
+    if let Some(i) = integer(value) {
+        return Value::Integer(i);
+    }
+    if value.parse::<u64>().is_ok() {
+        *large += 1;
+    }
+    value.parse::<f64>().map_or(Value::Null, Value::Real)
+}
+
+fn text(value: &str) -> Option<&str> {
+    if !value.is_empty() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn value_to_string(value: Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) => f.to_string(),
+        Value::Text(s) => s,
+        Value::Blob(_) => String::from("<blob>"),
+    }
+}
+
+impl Store {
+    pub fn open(path: &Path) -> io::Result<Store> {
+        let conn = Connection::open(path).map_err(to_sql_error)?;
+        conn.execute_batch(SCHEMA).map_err(to_sql_error)?;
+        // Stores of campaigns from before memory policies:
+        if conn
+            .prepare("SELECT memory_policy FROM configurations LIMIT 0")
+            .is_err()
+        {
+            conn.execute_batch("ALTER TABLE configurations ADD COLUMN memory_policy TEXT")
+                .map_err(to_sql_error)?;
+        }
+        // And before frequency setpoints:
+        if conn
+            .prepare("SELECT frequency_mhz FROM configurations LIMIT 0")
+            .is_err()
+        {
+            conn.execute_batch("ALTER TABLE configurations ADD COLUMN frequency_mhz INTEGER")
+                .map_err(to_sql_error)?;
+        }
+        // And before priorities:
+        if conn
+            .prepare("SELECT priority_a FROM configurations LIMIT 0")
+            .is_err()
+        {
+            conn.execute_batch(
+                "ALTER TABLE configurations ADD COLUMN priority_a TEXT;
+                 ALTER TABLE configurations ADD COLUMN priority_b TEXT;",
+            )
+            .map_err(to_sql_error)?;
+        }
+        Ok(Store { conn })
+    }
+
+    fn configuration(&self, c: &Configuration) -> rusqlite::Result<i64> {
+        let cat_ways = c.cat_ways.map(|w| w as i64);
+        let frequency_mhz = c.frequency_mhz.map(|f| f as i64);
+        let existing: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT id FROM configurations WHERE a IS ?1 AND b IS ?2 AND deployment IS ?3 AND cat_ways IS ?4 AND memory_policy IS ?5 AND frequency_mhz IS ?6 AND priority_a IS ?7 AND priority_b IS ?8",
+                params![c.a, c.b, c.deployment, cat_ways, c.memory_policy, frequency_mhz, c.priority_a, c.priority_b],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match existing {
+            Some(id) => Ok(id),
+            None => {
+                self.conn.execute(
+                    "INSERT INTO configurations (a, b, deployment, cat_ways, memory_policy, frequency_mhz, priority_a, priority_b) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![c.a, c.b, c.deployment, cat_ways, c.memory_policy, frequency_mhz, c.priority_a, c.priority_b],
+                )?;
+                Ok(self.conn.last_insert_rowid())
+            }
+        }
+    }
+
+    /// Adds a run (or replaces its results if we've seen it before) and returns a
+    /// writer for its results.
+    pub fn add_run(
+        &self,
+        directory: &Path,
+        configuration: &Configuration,
+    ) -> io::Result<StoreWriter<'_>> {
+        let directory = directory.to_string_lossy();
+        let configuration = self.configuration(configuration).map_err(to_sql_error)?;
+
+        // Replacing the run happens in one transaction: inserting row by row is slow
+        // otherwise, and `StoreWriter::rollback` leaves the store as it was.
+        self.conn.execute_batch("BEGIN").map_err(to_sql_error)?;
+        self.conn
+            .execute(
+                "DELETE FROM samples WHERE run_id IN (SELECT id FROM runs WHERE directory = ?1)",
+                params![directory],
+            )
+            .map_err(to_sql_error)?;
+        self.conn
+            .execute(
+                "DELETE FROM perf_metrics WHERE run_id IN (SELECT id FROM runs WHERE directory = ?1)",
+                params![directory],
+            )
+            .map_err(to_sql_error)?;
+        self.conn
+            .execute("DELETE FROM runs WHERE directory = ?1", params![directory])
+            .map_err(to_sql_error)?;
+        self.conn
+            .execute(
+                "INSERT INTO runs (configuration_id, directory, schema_version) VALUES (?1, ?2, ?3)",
+                params![configuration, directory, SCHEMA_VERSION as i64],
+            )
+            .map_err(to_sql_error)?;
+        let run = self.conn.last_insert_rowid();
+
+        Ok(StoreWriter {
+            conn: &self.conn,
+            run,
+            events: HashMap::new(),
+            large: 0,
+            sampled: 0,
+        })
+    }
+
+    /// Prints the samples (or totals) matching `query` as CSV to `out`.
+    pub fn query<W: io::Write>(&self, query: &Query, out: W) -> io::Result<usize> {
+        let frequency_mhz = query.frequency_mhz.map(|f| f.to_string());
+        let mut conditions: Vec<&str> = Vec::new();
+        let mut arguments: Vec<&str> = Vec::new();
+        if let Some(event) = query.event {
+            conditions.push("e.name LIKE ?");
+            arguments.push(event);
+        }
+        if let Some(a) = query.a {
+            conditions.push("c.a = ?");
+            arguments.push(a);
+        }
+        if let Some(b) = query.b {
+            conditions.push("c.b = ?");
+            arguments.push(b);
+        }
+        if let Some(deployment) = query.deployment {
+            conditions.push("c.deployment = ?");
+            arguments.push(deployment);
+        }
+        if let Some(memory_policy) = query.memory_policy {
+            conditions.push("c.memory_policy = ?");
+            arguments.push(memory_policy);
+        }
+        if let Some(ref frequency_mhz) = frequency_mhz {
+            conditions.push("c.frequency_mhz = ?");
+            arguments.push(frequency_mhz);
+        }
+        if let Some(priority_a) = query.priority_a {
+            conditions.push("c.priority_a = ?");
+            arguments.push(priority_a);
+        }
+        if let Some(priority_b) = query.priority_b {
+            conditions.push("c.priority_b = ?");
+            arguments.push(priority_b);
+        }
+        if let Some(phase) = query.phase {
+            conditions.push("s.phase = ?");
+            arguments.push(phase);
+        }
+        let selection = if !conditions.is_empty() {
+            format!("WHERE {}", conditions.join(" AND "))
+        } else {
+            String::new()
+        };
+
+        let (header, sql): (Vec<&str>, String) = if query.total {
+            (
+                vec![
+                    "RUN",
+                    "A",
+                    "B",
+                    "DEPLOYMENT",
+                    "CAT_WAYS",
+                    "MEMORY_POLICY",
+                    "FREQUENCY_MHZ",
+                    "PRIORITY_A",
+                    "PRIORITY_B",
+                    "EVENT_NAME",
+                    "TOTAL",
+                ],
+                format!(
+                    "SELECT r.directory, c.a, c.b, c.deployment, c.cat_ways, c.memory_policy, c.frequency_mhz, c.priority_a, c.priority_b, e.name, SUM(s.value)
+                     FROM samples s JOIN runs r ON r.id = s.run_id
+                     JOIN configurations c ON c.id = r.configuration_id
+                     JOIN events e ON e.id = s.event_id {}
+                     GROUP BY r.id, e.id ORDER BY r.directory, e.name",
+                    selection
+                ),
+            )
+        } else {
+            (
+                vec![
+                    "RUN",
+                    "A",
+                    "B",
+                    "DEPLOYMENT",
+                    "CAT_WAYS",
+                    "MEMORY_POLICY",
+                    "FREQUENCY_MHZ",
+                    "PRIORITY_A",
+                    "PRIORITY_B",
+                    "EVENT_NAME",
+                    "INDEX",
+                    "TIME",
+                    "SOCKET",
+                    "CORE",
+                    "CPU",
+                    "NODE",
+                    "UNIT",
+                    "SAMPLE_VALUE",
+                    "PHASE",
+                    "TID",
+                    "THREAD",
+                    "SCOPE",
+                ],
+                format!(
+                    "SELECT r.directory, c.a, c.b, c.deployment, c.cat_ways, c.memory_policy, c.frequency_mhz, c.priority_a, c.priority_b, e.name, s.idx,
+                     s.time, s.socket, s.core, s.cpu, s.node, s.unit, s.value, s.phase, s.tid,
+                     s.thread, s.scope
+                     FROM samples s JOIN runs r ON r.id = s.run_id
+                     JOIN configurations c ON c.id = r.configuration_id
+                     JOIN events e ON e.id = s.event_id {}
+                     ORDER BY r.directory, s.rowid",
+                    selection
+                ),
+            )
+        };
+
+        let mut wtr = csv::Writer::from_writer(out);
+        wtr.encode(&header).unwrap();
+
+        let mut statement = self.conn.prepare(&sql).map_err(to_sql_error)?;
+        let mut rows = statement
+            .query(params_from_iter(arguments.iter()))
+            .map_err(to_sql_error)?;
+        let mut count = 0;
+        while let Some(row) = rows.next().map_err(to_sql_error)? {
+            let record: Vec<String> = (0..header.len())
+                .map(|i| value_to_string(row.get(i).unwrap_or(Value::Null)))
+                .collect();
+            wtr.encode(record).unwrap();
+            count += 1;
+        }
+        wtr.flush().unwrap();
+
+        Ok(count)
+    }
+}
+
+impl<'a> StoreWriter<'a> {
+    /// Drops the results of the run instead of saving them (see `finish`).
+    pub fn rollback(&mut self) {
+        if let Err(e) = self.conn.execute_batch("ROLLBACK") {
+            error!("Can't drop the results from the store: {}", e);
+        }
+    }
+
+    /// A writer for the perf metrics of the run.
+    pub fn perf_metrics(&self) -> PerfMetricsWriter<'a> {
+        PerfMetricsWriter {
+            conn: self.conn,
+            run: self.run,
+        }
+    }
+
+    fn event(&mut self, name: &str) -> rusqlite::Result<i64> {
+        if let Some(id) = self.events.get(name) {
+            return Ok(*id);
+        }
+        self.conn.execute(
+            "INSERT OR IGNORE INTO events (name) VALUES (?1)",
+            params![name],
+        )?;
+        let id: i64 = self.conn.query_row(
+            "SELECT id FROM events WHERE name = ?1",
+            params![name],
+            |row| row.get(0),
+        )?;
+        self.events.insert(String::from(name), id);
+        Ok(id)
+    }
+
+    fn insert(&mut self, row: &[&str]) -> rusqlite::Result<usize> {
+        let event = self.event(row[0])?;
+        let value = sample_value(row[8], &mut self.large);
+        let mut statement = self.conn.prepare_cached(
+            "INSERT INTO samples (run_id, event_id, idx, time, socket, core, cpu, node, unit,
+             value, phase, tid, thread, scope)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        )?;
+        statement.execute(params![
+            self.run,
+            event,
+            integer(row[1]),
+            row[2].parse::<f64>().ok(),
+            integer(row[3]),
+            integer(row[4]),
+            integer(row[5]),
+            integer(row[6]),
+            text(row[7]),
+            value,
+            text(row[9]),
+            integer(row[10]),
+            text(row[11]),
+            text(row[12]),
+        ])
+    }
+}
+
+impl<'a> ResultWriter for StoreWriter<'a> {
+    fn write_row(&mut self, row: &[&str]) {
+        if row.len() < RESULT_COLUMNS.len() {
+            // Sampled (perf record) results don't have all the columns
+            self.sampled += 1;
+            return;
+        }
+        if let Err(e) = self.insert(row) {
+            error!("Can't insert result into the store: {}", e);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Err(e) = self.conn.execute_batch("COMMIT") {
+            error!("Can't save results in the store: {}", e);
+        }
+        if self.large > 0 {
+            warn!(
+                "Stored {} counts that don't fit in a 64-bit integer as REAL.",
+                self.large
+            );
+        }
+        if self.sampled > 0 {
+            warn!(
+                "Didn't store {} sampled (perf record) results, the store only has counts.",
+                self.sampled
+            );
+        }
+    }
+}
+
+impl<'a> ResultWriter for PerfMetricsWriter<'a> {
+    fn write_row(&mut self, row: &[&str]) {
+        if row.len() < PERF_METRICS_COLUMNS.len() {
+            return;
+        }
+        let inserted = self
+            .conn
+            .prepare_cached(
+                "INSERT INTO perf_metrics (run_id, file, time, location, event, value, unit)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )
+            .and_then(|mut statement| {
+                statement.execute(params![
+                    self.run,
+                    row[0],
+                    row[1].parse::<f64>().ok(),
+                    text(row[2]),
+                    text(row[3]),
+                    row[4].parse::<f64>().ok(),
+                    text(row[5]),
+                ])
+            });
+        if let Err(e) = inserted {
+            error!("Can't insert perf metric into the store: {}", e);
+        }
+    }
+
+    /// The `StoreWriter` of the run commits.
+    fn finish(&mut self) {}
+}
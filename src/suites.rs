@@ -0,0 +1,367 @@
+//! Programs of well-known benchmark suites in a `pair` manifest: instead of the
+//! binary and its arguments, a program names the `suite`, the `benchmark` and
+//! its `input`, and we fill in how the suite runs it:
+//!
+//! * `parsec`: `parsecmgmt -a run -p <benchmark> -i <input> -n <threads>` of
+//!   the PARSEC installation (inputs `test`, `simdev`, `simsmall`, `simmedium`,
+//!   `simlarge` and `native`, `parsec_config` is the build configuration).
+//! * `npb`: `bin/<benchmark>.<input>.x` of the NAS Parallel Benchmarks (OpenMP),
+//!   the input is the problem class (`S`, `W`, `A` to `F`).
+//! * `llvm-test-suite`: the `RUN:` line of the `.test` file of a benchmark in
+//!   the build directory of the LLVM test-suite, e.g. `MultiSource/Benchmarks/Olden/bh`.
+//!
+//! `suite_dir` is where the suite is installed (built, for the LLVM
+//! test-suite). Every program (not only the ones of a suite) can sweep the
+//! number of threads with `threads = [1, 2, 4]`: it's repeated for every count
+//! as `<name>-t<threads>`, with `$NUM_THREADS` in its arguments and environment
+//! replaced by the count (instead of the number of CPUs of the deployment) and
+//! `OMP_NUM_THREADS` set to it.
+//!
+//! In the same way, `datasets` sweeps the input of a program: it's repeated
+//! for every dataset as `<name>-<label>`, with `$DATASET` replaced by the
+//! dataset (a list of them, labelled by their file name, or a table of label
+//! and dataset). The label is kept as `dataset` of the program. Datasets are
+//! swept before thread counts (`<name>-<label>-t<threads>`). `datasets =
+//! "cache"` sweeps working set sizes pegged to the caches (see `cachesweep.rs`).
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::cachesweep;
+use super::spec;
+use super::util::MachineTopology;
+
+/// The suites we know (what `suite` takes).
+pub const SUITES: [&str; 3] = ["parsec", "npb", "llvm-test-suite"];
+
+/// What a program of a suite runs.
+struct Template {
+    name: String,
+    binary: String,
+    arguments: Vec<String>,
+    working_dir: String,
+    env: Vec<(String, String)>,
+}
+
+fn text<'a>(program: &'a toml::Table, key: &str) -> Result<Option<&'a str>, String> {
+    match program.get(key) {
+        Some(v) => v
+            .as_str()
+            .map(Some)
+            .ok_or(format!("'program.{}' should be a string", key)),
+        None => Ok(None),
+    }
+}
+
+fn exists(path: PathBuf) -> Result<String, String> {
+    if path.exists() {
+        Ok(path.to_string_lossy().into_owned())
+    } else {
+        Err(format!("{:?} does not exist", path))
+    }
+}
+
+fn parsec(
+    dir: &Path,
+    benchmark: &str,
+    input: Option<&str>,
+    config: &str,
+) -> Result<Template, String> {
+    let input = input.unwrap_or("simlarge");
+    Ok(Template {
+        name: format!("{}-{}", benchmark, input),
+        binary: exists(dir.join("bin").join("parsecmgmt"))?,
+        arguments: vec![
+            "-a", "run", "-p", benchmark, "-c", config, "-i", input, "-n",
+        ]
+        .into_iter()
+        .map(String::from)
+        .chain(Some(String::from("$NUM_THREADS")))
+        .collect(),
+        working_dir: dir.to_string_lossy().into_owned(),
+        env: Vec::new(),
+    })
+}
+
+fn npb(dir: &Path, benchmark: &str, input: Option<&str>) -> Result<Template, String> {
+    let class = input.unwrap_or("C").to_uppercase();
+    let benchmark = benchmark.to_lowercase();
+    Ok(Template {
+        name: format!("{}.{}", benchmark, class),
+        binary: exists(dir.join("bin").join(format!("{}.{}.x", benchmark, class)))?,
+        arguments: Vec::new(),
+        working_dir: dir.to_string_lossy().into_owned(),
+        env: vec![(
+            String::from("OMP_NUM_THREADS"),
+            String::from("$NUM_THREADS"),
+        )],
+    })
+}
+
+/// `RUN: cd <dir> ; <binary> <arguments> < <input>` of the `.test` file of
+/// `benchmark` (`%S` is the directory of the file).
+fn llvm_test_suite(dir: &Path, benchmark: &str) -> Result<Template, String> {
+    let benchmark_dir = dir.join(benchmark);
+    let binary_name = benchmark_dir
+        .file_name()
+        .map_or(String::new(), |n| n.to_string_lossy().into_owned());
+    let test_file = benchmark_dir.join(format!("{}.test", binary_name));
+    let test =
+        fs::read_to_string(&test_file).map_err(|e| format!("Can't read {:?}: {}", test_file, e))?;
+    let run = test
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("RUN:"))
+        .ok_or(format!("{:?} has no RUN: line", test_file))?
+        .replace("%S", &benchmark_dir.to_string_lossy());
+
+    let (working_dir, command) = match run.trim().strip_prefix("cd ") {
+        Some(cd) => {
+            let mut parts = cd.splitn(2, ';');
+            (
+                PathBuf::from(parts.next().unwrap_or("").trim()),
+                String::from(parts.next().unwrap_or("")),
+            )
+        }
+        None => (benchmark_dir.clone(), run.clone()),
+    };
+    let tokens: Vec<String> = command.split_whitespace().map(String::from).collect();
+    let (binary, arguments) = spec::binary_and_arguments(&tokens, &working_dir)
+        .ok_or(format!("{:?} has an empty RUN: line", test_file))?;
+    Ok(Template {
+        name: binary_name,
+        binary,
+        arguments,
+        working_dir: working_dir.to_string_lossy().into_owned(),
+        env: Vec::new(),
+    })
+}
+
+fn strings(values: &[String]) -> toml::Value {
+    toml::Value::Array(
+        values
+            .iter()
+            .map(|v| toml::Value::String(v.clone()))
+            .collect(),
+    )
+}
+
+/// `program` with what its suite runs (the keys it has itself win).
+fn from_suite(
+    manifest_path: &Path,
+    program: &toml::Table,
+    suite: &str,
+) -> Result<toml::Table, String> {
+    let benchmark = text(program, "benchmark")?.ok_or(format!(
+        "a program of suite '{}' needs a 'benchmark'",
+        suite
+    ))?;
+    let dir = text(program, "suite_dir")?
+        .ok_or(format!(
+            "a program of suite '{}' needs a 'suite_dir'",
+            suite
+        ))?
+        .replace("$MANIFEST_DIR", &manifest_path.to_string_lossy());
+    let dir = manifest_path.join(dir);
+    let input = text(program, "input")?;
+    let template = match suite {
+        "parsec" => parsec(
+            &dir,
+            benchmark,
+            input,
+            text(program, "parsec_config")?.unwrap_or("gcc"),
+        )?,
+        "npb" => npb(&dir, benchmark, input)?,
+        "llvm-test-suite" => llvm_test_suite(&dir, benchmark)?,
+        _ => {
+            return Err(format!(
+                "unknown suite '{}' (there are {})",
+                suite,
+                SUITES.join(", ")
+            ))
+        }
+    };
+
+    let mut filled = toml::Table::new();
+    filled.insert(String::from("name"), toml::Value::String(template.name));
+    filled.insert(String::from("binary"), toml::Value::String(template.binary));
+    filled.insert(String::from("arguments"), strings(&template.arguments));
+    filled.insert(
+        String::from("working_dir"),
+        toml::Value::String(template.working_dir),
+    );
+    let mut env: toml::Table = template
+        .env
+        .into_iter()
+        .map(|(k, v)| (k, toml::Value::String(v)))
+        .collect();
+    if let Some(own) = program.get("env").and_then(|e| e.as_table()) {
+        env.extend(own.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    for (key, value) in program.iter() {
+        filled.insert(key.clone(), value.clone());
+    }
+    filled.insert(String::from("env"), toml::Value::Table(env));
+    Ok(filled)
+}
+
+/// `value` with `placeholder` replaced (in strings, lists of them and tables).
+fn substitute(value: &toml::Value, placeholder: &str, replacement: &str) -> toml::Value {
+    match *value {
+        toml::Value::String(ref s) => toml::Value::String(s.replace(placeholder, replacement)),
+        toml::Value::Array(ref a) => toml::Value::Array(
+            a.iter()
+                .map(|v| substitute(v, placeholder, replacement))
+                .collect(),
+        ),
+        toml::Value::Table(ref t) => toml::Value::Table(
+            t.iter()
+                .map(|(k, v)| (k.clone(), substitute(v, placeholder, replacement)))
+                .collect(),
+        ),
+        ref v => v.clone(),
+    }
+}
+
+/// `label` as part of a program name (and a directory name).
+fn dataset_label(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// The (label, dataset) of `datasets`: a list of datasets (labelled by their
+/// file name without the extension), a table of label and dataset or `cache`
+/// (the sizes of the caches of `mt`).
+fn datasets(value: &toml::Value, mt: &MachineTopology) -> Result<Vec<(String, String)>, String> {
+    let datasets: Vec<(String, String)> =
+        match *value {
+            toml::Value::String(ref sweep) if sweep == "cache" => cachesweep::machine_sweep(mt)
+                .map_err(|e| format!("'program.datasets' can't be \"cache\": {}", e))?
+                .into_iter()
+                .map(|(label, bytes)| (label, bytes.to_string()))
+                .collect(),
+            toml::Value::Array(ref datasets) => datasets
+                .iter()
+                .map(|d| {
+                    let dataset = d.as_str()?;
+                    let label = Path::new(dataset)
+                        .file_stem()
+                        .map_or(String::from(dataset), |s| s.to_string_lossy().into_owned());
+                    Some((dataset_label(&label), String::from(dataset)))
+                })
+                .collect::<Option<Vec<(String, String)>>>()
+                .ok_or(String::from(
+                    "'program.datasets' should be a list of strings",
+                ))?,
+            toml::Value::Table(ref datasets) => datasets
+                .iter()
+                .map(|(label, d)| Some((dataset_label(label), String::from(d.as_str()?))))
+                .collect::<Option<Vec<(String, String)>>>()
+                .ok_or(String::from(
+                    "'program.datasets' should map labels to strings",
+                ))?,
+            _ => return Err(String::from(
+                "'program.datasets' should be a list of datasets, a table of label and dataset or \"cache\"",
+            )),
+        };
+    let mut labels = BTreeSet::new();
+    if let Some((label, _)) = datasets.iter().find(|&(l, _)| !labels.insert(l.clone())) {
+        return Err(format!(
+            "two of 'program.datasets' are labelled '{}', use a table to label them",
+            label
+        ));
+    }
+    Ok(datasets)
+}
+
+/// `program` for every dataset in its `datasets`.
+fn with_datasets(program: toml::Table, mt: &MachineTopology) -> Result<Vec<toml::Table>, String> {
+    let datasets = match program.get("datasets") {
+        Some(value) => datasets(value, mt)?,
+        None => return Ok(vec![program]),
+    };
+    let name = text(&program, "name")?.unwrap_or("").to_string();
+    Ok(datasets
+        .iter()
+        .map(|(label, dataset)| {
+            let mut swept: toml::Table = program
+                .iter()
+                .filter(|&(k, _)| k != "datasets")
+                .map(|(k, v)| (k.clone(), substitute(v, "$DATASET", dataset)))
+                .collect();
+            swept.insert(
+                String::from("name"),
+                toml::Value::String(format!("{}-{}", name, label)),
+            );
+            swept.insert(String::from("dataset"), toml::Value::String(label.clone()));
+            swept
+        })
+        .collect())
+}
+
+/// `program` for every count in its `threads`.
+fn with_threads(program: toml::Table) -> Result<Vec<toml::Table>, String> {
+    let threads: Vec<i64> = match program.get("threads") {
+        None => return Ok(vec![program]),
+        Some(&toml::Value::Integer(n)) => vec![n],
+        Some(toml::Value::Array(counts)) => counts
+            .iter()
+            .map(|c| c.as_integer().filter(|c| *c > 0))
+            .collect::<Option<Vec<i64>>>()
+            .ok_or(String::from(
+                "'program.threads' should be a list of thread counts",
+            ))?,
+        Some(_) => {
+            return Err(String::from(
+                "'program.threads' should be a thread count or a list of them",
+            ))
+        }
+    };
+
+    let name = text(&program, "name")?.unwrap_or("").to_string();
+    Ok(threads
+        .iter()
+        .map(|&n| {
+            let mut swept: toml::Table = program
+                .iter()
+                .map(|(k, v)| (k.clone(), substitute(v, "$NUM_THREADS", &n.to_string())))
+                .collect();
+            swept.insert(
+                String::from("name"),
+                toml::Value::String(format!("{}-t{}", name, n)),
+            );
+            let mut env = swept
+                .get("env")
+                .and_then(|e| e.as_table())
+                .cloned()
+                .unwrap_or(toml::Table::new());
+            env.entry(String::from("OMP_NUM_THREADS"))
+                .or_insert(toml::Value::String(n.to_string()));
+            swept.insert(String::from("env"), toml::Value::Table(env));
+            swept
+        })
+        .collect())
+}
+
+/// The programs the `program` table of the manifest in `manifest_path` stands
+/// for: itself, what its suite runs, and one for every dataset and thread
+/// count (cache-sized datasets are the ones of `mt`).
+pub fn expand(
+    manifest_path: &Path,
+    program: &toml::Table,
+    mt: &MachineTopology,
+) -> Result<Vec<toml::Table>, String> {
+    let program = match text(program, "suite")? {
+        Some(suite) => from_suite(manifest_path, program, suite)?,
+        None => program.clone(),
+    };
+    let mut expanded = Vec::new();
+    for program in with_datasets(program, mt)? {
+        expanded.extend(with_threads(program)?);
+    }
+    Ok(expanded)
+}
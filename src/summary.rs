@@ -0,0 +1,272 @@
+//! How a `profile` or `pair` ended, for the scripts that run autoperf: the exit
+//! code says what went wrong, and `summary.json` in the output directory has
+//! the details (we write it however we end, see `conclude`, `fail` and the
+//! panic hook of `start`):
+//!
+//! | Exit code | Outcome          | What happened                                             |
+//! |-----------|------------------|-----------------------------------------------------------|
+//! | 0         | `success`        | All runs completed (or were completed before).            |
+//! | 1         | `error`          | Anything else: bad arguments or input, a crash, ...       |
+//! | 2         | `environment`    | A tool or setting we need is missing (perf, likwid, ...). |
+//! | 3         | `counter_access` | We aren't allowed to use the performance counters.        |
+//! | 4         | `workload`       | The measured program failed.                              |
+//! | 5         | `partial`        | Some runs of the campaign failed, the others completed.   |
+//!
+//! The failure of a run is `record`ed on the thread that runs it (runs side by
+//! side run on their own threads), `run_ended` counts it.
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustc_serialize::json;
+use schemars::JsonSchema;
+use tracing::{error, warn};
+
+use super::integrity;
+use super::progress::Outcome;
+use super::util::SCHEMA_VERSION;
+
+/// The summary of an output directory.
+pub const SUMMARY_FILE: &str = "summary.json";
+
+pub const EXIT_PARTIAL: i32 = 5;
+
+/// Why a run (or autoperf) failed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Failure {
+    /// Anything that isn't one of the others.
+    Error,
+    /// A tool or setting we need is missing.
+    Environment,
+    /// The kernel doesn't let us use the performance counters.
+    CounterAccess,
+    /// The measured program exited with an error.
+    Workload,
+}
+
+impl Failure {
+    pub fn category(&self) -> &'static str {
+        match *self {
+            Failure::Error => "error",
+            Failure::Environment => "environment",
+            Failure::CounterAccess => "counter_access",
+            Failure::Workload => "workload",
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Failure::Error => 1,
+            Failure::Environment => 2,
+            Failure::CounterAccess => 3,
+            Failure::Workload => 4,
+        }
+    }
+}
+
+/// What we save in `summary.json`.
+#[derive(Debug, Clone, RustcEncodable, JsonSchema)]
+pub struct Summary {
+    schema_version: u64,
+    /// `profile` or `pair`.
+    command: String,
+    /// `success`, `partial` or the category of the failure (see `exit_code`).
+    outcome: String,
+    exit_code: i32,
+    /// Unix timestamps of the start and the end.
+    started: u64,
+    finished: u64,
+    /// Runs this invocation is supposed to do (1 for `profile`).
+    total: usize,
+    completed: usize,
+    failed: usize,
+    /// Runs that were completed by an earlier invocation.
+    skipped: usize,
+    /// The failed runs (and what stopped us, if something did) by category.
+    failures: BTreeMap<String, usize>,
+    /// What we couldn't measure or capture but went on without (see
+    /// `privilege.rs`).
+    warnings: Vec<String>,
+}
+
+struct State {
+    save_in: Option<PathBuf>,
+    summary: Summary,
+    /// The first failure, it decides the outcome if no run completed.
+    first_failure: Option<Failure>,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+thread_local! {
+    /// The failure of the run on this thread.
+    static RUN_FAILURE: Cell<Option<Failure>> = const { Cell::new(None) };
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs())
+}
+
+// A panic while we hold the lock must not keep us from writing the summary:
+fn state() -> MutexGuard<'static, Option<State>> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Keeps track of how `command` ends, until we `conclude` (or `fail`, or panic).
+pub fn start(command: &str) {
+    *state() = Some(State {
+        save_in: None,
+        summary: Summary {
+            schema_version: SCHEMA_VERSION,
+            command: String::from(command),
+            outcome: String::from("success"),
+            exit_code: 0,
+            started: now(),
+            finished: 0,
+            total: 1,
+            completed: 0,
+            failed: 0,
+            skipped: 0,
+            failures: BTreeMap::new(),
+            warnings: Vec::new(),
+        },
+        first_failure: None,
+    });
+    let report = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        report(info);
+        fail(Failure::Error);
+    }));
+}
+
+/// The summary goes to `directory` (the output directory, once we know it).
+pub fn save_in(directory: &Path) {
+    if let Some(ref mut state) = *state() {
+        state.save_in = Some(directory.to_path_buf());
+    }
+}
+
+/// The campaign has `total` runs.
+pub fn planned(total: usize) {
+    if let Some(ref mut state) = *state() {
+        state.summary.total = total;
+    }
+}
+
+/// The run on this thread failed (the first failure of a run counts).
+pub fn record(failure: Failure) {
+    RUN_FAILURE.with(|f| {
+        if f.get().is_none() {
+            f.set(Some(failure));
+        }
+    });
+}
+
+fn count_failure(state: &mut State, failure: Failure) {
+    *state
+        .summary
+        .failures
+        .entry(String::from(failure.category()))
+        .or_insert(0) += 1;
+    if state.first_failure.is_none() {
+        state.first_failure = Some(failure);
+    }
+}
+
+/// Counts the run on this thread, which failed if it ran into an `error` or a
+/// failure was `record`ed.
+pub fn run_ended(error: bool) -> Outcome {
+    let failure = match RUN_FAILURE.with(|f| f.take()) {
+        Some(failure) => Some(failure),
+        None if error => Some(Failure::Error),
+        None => None,
+    };
+    if let Some(ref mut state) = *state() {
+        match failure {
+            Some(failure) => {
+                state.summary.failed += 1;
+                count_failure(state, failure);
+            }
+            None => state.summary.completed += 1,
+        }
+    }
+    failure.map_or(Outcome::Completed, |_| Outcome::Failed)
+}
+
+/// We go on without something: logs `warning` and adds it to the summary (once).
+pub fn warn(warning: String) {
+    warn!("{}", warning);
+    if let Some(ref mut state) = *state() {
+        if !state.summary.warnings.contains(&warning) {
+            state.summary.warnings.push(warning);
+        }
+    }
+}
+
+/// Counts a run that was completed by an earlier invocation.
+pub fn run_skipped() {
+    if let Some(ref mut state) = *state() {
+        state.summary.skipped += 1;
+    }
+}
+
+fn save(directory: &Path, summary: &Summary) -> io::Result<()> {
+    fs::create_dir_all(directory)?;
+    let mut f = File::create(directory.join(SUMMARY_FILE))?;
+    f.write_all(format!("{}\n", json::as_pretty_json(summary)).as_bytes())
+}
+
+/// Writes the summary with `outcome`.
+fn save_outcome(outcome: &str, exit_code: i32) {
+    if let Some(ref mut state) = *state() {
+        state.summary.outcome = String::from(outcome);
+        state.summary.exit_code = exit_code;
+        state.summary.finished = now();
+        if let Some(ref directory) = state.save_in {
+            if let Err(e) = save(directory, &state.summary) {
+                error!("Can't save {:?}: {}", directory.join(SUMMARY_FILE), e);
+            }
+        }
+    }
+}
+
+/// Stops autoperf because of `failure`.
+pub fn fail(failure: Failure) -> ! {
+    if let Some(ref mut state) = *state() {
+        count_failure(state, failure);
+    }
+    save_outcome(failure.category(), failure.exit_code());
+    process::exit(failure.exit_code())
+}
+
+/// Writes the summary once the runs are done and returns the exit code: 0 if
+/// none failed, `EXIT_PARTIAL` if some completed, that of the first failure
+/// otherwise. The output directory is complete then, so we also save the hashes
+/// of its files (see `integrity.rs`).
+pub fn conclude() -> i32 {
+    let (outcome, exit_code) = match *state() {
+        Some(ref state) if state.summary.failed > 0 => {
+            if state.summary.completed + state.summary.skipped > 0 {
+                ("partial", EXIT_PARTIAL)
+            } else {
+                let failure = state.first_failure.unwrap_or(Failure::Error);
+                (failure.category(), failure.exit_code())
+            }
+        }
+        _ => ("success", 0),
+    };
+    save_outcome(outcome, exit_code);
+    let directory = state().as_ref().and_then(|state| state.save_in.clone());
+    if let Some(directory) = directory {
+        integrity::save_or_log(&directory);
+    }
+    exit_code
+}
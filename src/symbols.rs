@@ -0,0 +1,151 @@
+//! Turns the samples `perf record` saved (`<nr>_perf.data`) into how many samples
+//! every symbol got, so sampling ends up in a table next to the counter results
+//! instead of in a binary only perf can read. We let `perf script` resolve the
+//! symbols and count its lines, which look like this (period, event, ip, symbol
+//! and the binary it belongs to):
+//!
+//! ```text
+//!     250000 cycles:  ffffffff8106a0a4 native_write_msr ([kernel.kallsyms])
+//!     250000 cycles:      55d0c4e2b1a0 std::vector<int>::push_back(int const&) (/usr/bin/app)
+//! ```
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use tracing::{debug, warn};
+
+use crate::aggregate::read_perf_log;
+use crate::columnar::ResultWriter;
+use crate::kallsyms::KALLSYMS_FILE;
+
+/// Columns of symbols.csv (or symbols.parquet).
+pub const SYMBOL_COLUMNS: [&str; 6] = ["FILE", "EVENT_NAME", "DSO", "SYMBOL", "SAMPLES", "PERIOD"];
+
+/// The samples of an event that hit a symbol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolCount {
+    pub event: String,
+    /// The binary or library the symbol is in (`[kernel.kallsyms]` for the kernel).
+    pub dso: String,
+    /// `[unknown]` if perf couldn't resolve the address.
+    pub symbol: String,
+    pub samples: u64,
+    /// The sum of the sample periods, an estimate of how many events the symbol caused.
+    pub period: u64,
+}
+
+/// A line of `perf script` as (event, period, symbol, dso).
+fn parse_sample(line: &str) -> Option<(String, u64, String, String)> {
+    let split = line.find(": ")?;
+    let head: Vec<&str> = line[..split].split_whitespace().collect();
+    let event = String::from(*head.last()?);
+    let period = match head.len() {
+        1 => 1,
+        _ => u64::from_str(head[0]).ok()?,
+    };
+
+    // Skip the instruction pointer, the rest is "symbol (dso)":
+    let rest = line[split + 1..].trim();
+    let rest = rest
+        .split_once(char::is_whitespace)
+        .map(|x| x.1)
+        .unwrap_or("")
+        .trim();
+    let (symbol, dso) = match rest.rfind('(') {
+        Some(idx) if rest.ends_with(')') => (rest[..idx].trim(), &rest[idx + 1..rest.len() - 1]),
+        _ => (rest, "[unknown]"),
+    };
+    let symbol = if symbol.is_empty() {
+        "[unknown]"
+    } else {
+        symbol
+    };
+
+    Some((event, period, String::from(symbol), String::from(dso)))
+}
+
+/// Counts the samples per event and symbol of the perf.data file `data`, the
+/// symbols with the most samples of an event come first.
+pub fn symbol_counts(data: &Path) -> io::Result<Vec<SymbolCount>> {
+    let mut perf = Command::new("perf");
+    perf.arg("script");
+    // The kernel symbols of when we sampled (see `kallsyms.rs`):
+    let kallsyms = data.with_file_name(KALLSYMS_FILE);
+    if kallsyms.exists() {
+        perf.arg("--kallsyms").arg(kallsyms.as_os_str());
+    }
+    let mut child = perf
+        .arg("-i")
+        .arg(data.as_os_str())
+        .arg("-F")
+        .arg("period,event,ip,sym,dso")
+        .arg("-G")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let mut counts: BTreeMap<(String, String, String), (u64, u64)> = BTreeMap::new();
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines() {
+            if let Some((event, period, symbol, dso)) = parse_sample(&line?) {
+                let count = counts.entry((event, dso, symbol)).or_insert((0, 0));
+                count.0 += 1;
+                count.1 += period;
+            }
+        }
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "perf script exited with {}",
+            status
+        )));
+    }
+
+    let mut counts: Vec<SymbolCount> = counts
+        .into_iter()
+        .map(|((event, dso, symbol), (samples, period))| SymbolCount {
+            event,
+            dso,
+            symbol,
+            samples,
+            period,
+        })
+        .collect();
+    counts.sort_by(|a, b| a.event.cmp(&b.event).then(b.samples.cmp(&a.samples)));
+    Ok(counts)
+}
+
+/// Writes the symbol counts of every perf.data file of the run in `path` to
+/// `wrtr` (see `SYMBOL_COLUMNS`).
+pub fn aggregate_symbols(path: &Path, wrtr: &mut dyn ResultWriter) {
+    let perf_csv = path.join("perf.csv");
+    if !perf_csv.exists() {
+        return;
+    }
+    let (_, rows) = read_perf_log(&perf_csv);
+    for (_, _, _, _, file, _) in rows {
+        if !file.ends_with("_perf.data") {
+            continue;
+        }
+        debug!("Resolving the symbols of {}", file);
+        match symbol_counts(&path.join(&file)) {
+            Ok(counts) => {
+                for count in counts {
+                    wrtr.write_row(&[
+                        file.as_str(),
+                        count.event.as_str(),
+                        count.dso.as_str(),
+                        count.symbol.as_str(),
+                        count.samples.to_string().as_str(),
+                        count.period.to_string().as_str(),
+                    ]);
+                }
+            }
+            Err(e) => warn!("Can't resolve the symbols of {:?}: {}", path.join(&file), e),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Machine topology from FreeBSD's sysctls, for machines without lscpu and
+//! numactl.
+//!
+//! The CPU groups of `kern.sched.topology_spec` tell us which CPUs share a core
+//! (the groups with the THREAD or SMT flag), an L2 and an L3 (`cache-level`),
+//! `dev.cpu.<n>.%domain` on which NUMA domain a CPU is. FreeBSD doesn't tell us
+//! the package of a CPU, we use its domain as the socket. Both are written in the
+//! format of `lscpu --parse=NODE,SOCKET,CORE,CPU,CACHE` and `numactl --hardware`,
+//! so `MachineTopology` (and everything that reads `lscpu.csv` and `numactl.dat`
+//! later on) doesn't have to care where a run was measured.
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::str::FromStr;
+
+use super::util::{Core, Cpu, Node, L2, L3};
+
+/// `sysctl -n <name>`, None if there is no such sysctl (or sysctl).
+pub fn sysctl(name: &str) -> Option<String> {
+    let out = Command::new("sysctl").arg("-n").arg(name).output().ok()?;
+    if out.status.success() {
+        Some(String::from(String::from_utf8_lossy(&out.stdout).trim()))
+    } else {
+        None
+    }
+}
+
+/// A `<group>` of `kern.sched.topology_spec`.
+#[derive(Debug, Default)]
+struct Group {
+    cache_level: u64,
+    /// Do the CPUs of this group share a core?
+    thread: bool,
+    cpus: Vec<Cpu>,
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.find(&format!("{}=\"", name))? + name.len() + 2;
+    tag[start..].find('"').map(|end| &tag[start..start + end])
+}
+
+/// All groups of the topology (nested groups come before their parents).
+fn parse_topology_spec(spec: &str) -> Vec<Group> {
+    let mut groups = Vec::new();
+    let mut open: Vec<Group> = Vec::new();
+    for element in spec.split('<') {
+        let (tag, text) = match element.find('>') {
+            Some(end) => (&element[..end], &element[end + 1..]),
+            None => continue,
+        };
+        if tag.starts_with("group") {
+            open.push(Group {
+                cache_level: attribute(tag, "cache-level")
+                    .and_then(|l| u64::from_str(l).ok())
+                    .unwrap_or(0),
+                ..Default::default()
+            });
+        } else if tag == "/group" {
+            groups.extend(open.pop());
+        } else if let Some(group) = open.last_mut() {
+            if tag.starts_with("cpu") {
+                group.cpus = text
+                    .split(',')
+                    .filter_map(|c| u64::from_str(c.trim()).ok())
+                    .collect();
+            } else if tag.starts_with("flag") {
+                let name = attribute(tag, "name").unwrap_or("");
+                group.thread |= name == "THREAD" || name == "SMT";
+            }
+        }
+    }
+    groups
+}
+
+/// The NUMA domain of every CPU.
+fn domains(cpus: &[Cpu]) -> BTreeMap<Cpu, Node> {
+    cpus.iter()
+        .map(|&cpu| {
+            let domain = sysctl(&format!("dev.cpu.{}.%domain", cpu))
+                .and_then(|d| u64::from_str(&d).ok())
+                .unwrap_or(0);
+            (cpu, domain)
+        })
+        .collect()
+}
+
+/// (core, L2, L3) of every CPU, the index of the group that contains it (or the
+/// next bigger thing if there is no such group).
+fn caches(
+    groups: &[Group],
+    cpus: &[Cpu],
+    domains: &BTreeMap<Cpu, Node>,
+) -> BTreeMap<Cpu, (Core, L2, L3)> {
+    let index = |cpu: Cpu, matches: &dyn Fn(&Group) -> bool| {
+        groups
+            .iter()
+            .filter(|g| matches(g))
+            .position(|g| g.cpus.contains(&cpu))
+            .map(|idx| idx as u64)
+    };
+    cpus.iter()
+        .map(|&cpu| {
+            let core = index(cpu, &|g| g.thread).unwrap_or(cpu);
+            let l2 = index(cpu, &|g| g.cache_level == 2).unwrap_or(core);
+            let l3 = index(cpu, &|g| g.cache_level == 3).unwrap_or(domains[&cpu]);
+            (cpu, (core, l2, l3))
+        })
+        .collect()
+}
+
+/// What `lscpu --parse=NODE,SOCKET,CORE,CPU,CACHE` would print.
+pub fn lscpu_parse() -> String {
+    let ncpu = sysctl("hw.ncpu")
+        .and_then(|n| u64::from_str(&n).ok())
+        .unwrap_or(1);
+    let cpus: Vec<Cpu> = (0..ncpu).collect();
+    let groups = parse_topology_spec(&sysctl("kern.sched.topology_spec").unwrap_or_default());
+    let domains = domains(&cpus);
+
+    let mut lscpu = String::from(
+        "# Generated from kern.sched.topology_spec and dev.cpu.<n>.%domain\n\
+         # Node,Socket,Core,CPU,L1d:L1i:L2:L3\n",
+    );
+    for (cpu, (core, l2, l3)) in caches(&groups, &cpus, &domains) {
+        let node = domains[&cpu];
+        lscpu.push_str(&format!(
+            "{},{},{},{},{}:{}:{}:{}\n",
+            node, node, core, cpu, core, core, l2, l3
+        ));
+    }
+    lscpu
+}
+
+/// The parts of `numactl --hardware` we read (the CPUs and size of every node).
+/// The memory is split up evenly between the domains.
+pub fn numactl_hardware() -> String {
+    let ncpu = sysctl("hw.ncpu")
+        .and_then(|n| u64::from_str(&n).ok())
+        .unwrap_or(1);
+    let cpus: Vec<Cpu> = (0..ncpu).collect();
+    let ndomain = sysctl("vm.ndomain")
+        .and_then(|n| u64::from_str(&n).ok())
+        .unwrap_or(1)
+        .max(1);
+    let memory = sysctl("hw.physmem")
+        .and_then(|m| u64::from_str(&m).ok())
+        .unwrap_or(0);
+    let domains = domains(&cpus);
+
+    let mut numactl = format!("available: {} nodes (0-{})\n", ndomain, ndomain - 1);
+    for node in 0..ndomain {
+        let on_node: Vec<String> = domains
+            .iter()
+            .filter(|&(_, d)| *d == node)
+            .map(|(cpu, _)| cpu.to_string())
+            .collect();
+        numactl.push_str(&format!("node {} cpus: {}\n", node, on_node.join(" ")));
+        numactl.push_str(&format!(
+            "node {} size: {} MB\n",
+            node,
+            memory / ndomain / (1024 * 1024)
+        ));
+    }
+    numactl
+}
@@ -0,0 +1,146 @@
+//! Keeps track of the threads a program spawns while we measure it, so per-thread
+//! counter values (`perf stat --per-thread`) can be attributed to the program's
+//! threads (and their names) later on.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use tracing::debug;
+
+/// How often we look for new threads in /proc.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// A thread that belonged to the measured program: (tid, pid, name).
+pub type ThreadInfo = (u64, u64, String);
+
+pub struct ThreadTracker {
+    stop: mpsc::Sender<()>,
+    tracker: thread::JoinHandle<BTreeMap<u64, ThreadInfo>>,
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|s| String::from(s.trim()))
+}
+
+/// All (transitive) child processes of `pid` (needs CONFIG_PROC_CHILDREN).
+pub fn descendants(pid: u64) -> Vec<u64> {
+    let mut found = Vec::new();
+    let mut todo = vec![pid];
+
+    while let Some(parent) = todo.pop() {
+        let tasks = match fs::read_dir(format!("/proc/{}/task", parent)) {
+            Ok(tasks) => tasks,
+            Err(_) => continue,
+        };
+        for task in tasks.filter_map(|t| t.ok()) {
+            let children = read_trimmed(&format!(
+                "/proc/{}/task/{}/children",
+                parent,
+                task.file_name().to_string_lossy()
+            ));
+            for child in children
+                .unwrap_or_default()
+                .split_whitespace()
+                .filter_map(|c| u64::from_str(c).ok())
+            {
+                found.push(child);
+                todo.push(child);
+            }
+        }
+    }
+
+    found
+}
+
+/// Records all threads of the processes spawned by `parent`.
+fn scan(parent: u64, threads: &mut BTreeMap<u64, ThreadInfo>) {
+    for pid in descendants(parent) {
+        let tasks = match fs::read_dir(format!("/proc/{}/task", pid)) {
+            Ok(tasks) => tasks,
+            Err(_) => continue,
+        };
+        for task in tasks.filter_map(|t| t.ok()) {
+            let tid = match u64::from_str(&task.file_name().to_string_lossy()) {
+                Ok(tid) => tid,
+                Err(_) => continue,
+            };
+            // Threads can rename themselves, so we always keep the latest name:
+            if let Some(name) = read_trimmed(&format!("/proc/{}/task/{}/comm", pid, tid)) {
+                threads.insert(tid, (tid, pid, name));
+            }
+        }
+    }
+}
+
+impl ThreadTracker {
+    /// Starts tracking the threads of all processes spawned by `parent` (i.e., perf).
+    pub fn start(parent: u32) -> ThreadTracker {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let tracker = thread::spawn(move || {
+            let mut threads = BTreeMap::new();
+            loop {
+                scan(parent as u64, &mut threads);
+                match stopped.recv_timeout(Duration::from_millis(POLL_INTERVAL_MS)) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            }
+            threads
+        });
+
+        ThreadTracker { stop, tracker }
+    }
+
+    /// Stops tracking and saves the threads we've seen to `save_to`.
+    pub fn finish(self, save_to: &Path) -> io::Result<Vec<ThreadInfo>> {
+        let _ = self.stop.send(());
+        let threads: Vec<ThreadInfo> = self
+            .tracker
+            .join()
+            .unwrap_or_default()
+            .into_values()
+            .collect();
+        debug!("Program used {} threads.", threads.len());
+
+        let mut wtr = csv::Writer::from_file(save_to).unwrap();
+        wtr.encode(("tid", "pid", "name")).unwrap();
+        for t in threads.iter() {
+            wtr.encode(t).unwrap();
+        }
+        wtr.flush().unwrap();
+
+        Ok(threads)
+    }
+}
+
+/// Reads the threads saved by `ThreadTracker::finish` (None if we didn't track threads).
+pub fn read_threads(path: &Path) -> Option<BTreeMap<u64, ThreadInfo>> {
+    if !path.exists() {
+        return None;
+    }
+
+    let mut rdr = csv::Reader::from_file(path).unwrap().has_headers(true);
+    let threads = rdr
+        .decode()
+        .collect::<csv::Result<Vec<ThreadInfo>>>()
+        .expect("Can't parse threads file");
+    Some(threads.into_iter().map(|t| (t.0, t)).collect())
+}
+
+/// Splits the `comm-tid` identifier perf uses for per-thread output.
+pub fn parse_perf_thread(thread: &str) -> Option<(String, u64)> {
+    let thread = thread.trim();
+    thread.rfind('-').and_then(|idx| {
+        let (comm, tid) = thread.split_at(idx);
+        u64::from_str(tid[1..].trim())
+            .ok()
+            .map(|tid| (String::from(comm.trim()), tid))
+    })
+}
@@ -0,0 +1,120 @@
+//! Detects events during a measurement that make the results hard to trust:
+//! System Management Interrupts (SMIs steal time from all CPUs without the OS
+//! noticing) and thermal throttling.
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::str::FromStr;
+
+use tracing::warn;
+
+/// Counts the SMIs since the last reset (Intel only).
+const MSR_SMI_COUNT: u64 = 0x34;
+
+/// SMI and thermal throttling counters at some point in time (or the difference
+/// between two points in time).
+#[derive(Debug, Default, Clone)]
+pub struct Throttling {
+    /// None if we can't read the MSR (needs the msr module and root).
+    pub smis: Option<u64>,
+    /// Summed over all CPUs.
+    pub core_throttles: u64,
+    /// Summed over the packages.
+    pub package_throttles: u64,
+    /// The counter of every package (by `physical_package_id`), it's the same for
+    /// all CPUs of a package, so we read it from the first one.
+    packages: BTreeMap<u64, u64>,
+}
+
+fn read_smi_count() -> Option<u64> {
+    let msr = File::open("/dev/cpu/0/msr").ok()?;
+    let mut buf = [0u8; 8];
+    msr.read_at(&mut buf, MSR_SMI_COUNT).ok()?;
+    // Only the lower 32 bits are the counter:
+    Some(u64::from_le_bytes(buf) & 0xffff_ffff)
+}
+
+fn read_counter(path: &str) -> Option<u64> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|v| u64::from_str(v.trim()).ok())
+}
+
+impl Throttling {
+    pub fn now() -> Throttling {
+        let mut throttling = Throttling {
+            smis: read_smi_count(),
+            ..Default::default()
+        };
+
+        let cpus = match fs::read_dir("/sys/devices/system/cpu") {
+            Ok(cpus) => cpus,
+            Err(_) => return throttling,
+        };
+        let mut cpus: Vec<(u64, String)> = cpus
+            .filter_map(|c| c.ok())
+            .filter_map(|c| {
+                let name = c.file_name().to_string_lossy().into_owned();
+                let cpu = u64::from_str(name.strip_prefix("cpu")?).ok()?;
+                Some((cpu, c.path().to_string_lossy().into_owned()))
+            })
+            .collect();
+        cpus.sort();
+        for (_, path) in cpus {
+            if let Some(count) =
+                read_counter(&format!("{}/thermal_throttle/core_throttle_count", path))
+            {
+                throttling.core_throttles += count;
+            }
+            let package = read_counter(&format!("{}/topology/physical_package_id", path));
+            if let Some(package) = package.filter(|p| !throttling.packages.contains_key(p)) {
+                if let Some(count) =
+                    read_counter(&format!("{}/thermal_throttle/package_throttle_count", path))
+                {
+                    throttling.packages.insert(package, count);
+                }
+            }
+        }
+        throttling.package_throttles = throttling.packages.values().sum();
+
+        throttling
+    }
+
+    /// What happened between `before` and now.
+    pub fn since(before: &Throttling) -> Throttling {
+        let now = Throttling::now();
+        let packages: BTreeMap<u64, u64> = now
+            .packages
+            .iter()
+            .map(|(package, count)| {
+                let before = before.packages.get(package).cloned().unwrap_or(*count);
+                (*package, count.saturating_sub(before))
+            })
+            .collect();
+        Throttling {
+            smis: match (now.smis, before.smis) {
+                (Some(n), Some(b)) => Some(n.wrapping_sub(b) & 0xffff_ffff),
+                _ => None,
+            },
+            core_throttles: now.core_throttles.saturating_sub(before.core_throttles),
+            package_throttles: packages.values().sum(),
+            packages,
+        }
+    }
+
+    /// Did something happen we should warn the user about?
+    pub fn is_suspicious(&self) -> bool {
+        self.smis.unwrap_or(0) > 0 || self.core_throttles > 0 || self.package_throttles > 0
+    }
+
+    pub fn warn(&self, what: &str) {
+        if self.is_suspicious() {
+            warn!(
+                "{}: {:?} SMIs, {} core and {} package thermal throttling events happened \
+                 during the measurement, be careful with these results.",
+                what, self.smis, self.core_throttles, self.package_throttles
+            );
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! Draws the topology of a machine (`autoperf topology`): its sockets, the L3
+//! domains on every socket, the cores of every L3 (and the L2s they share, if
+//! cores share them) with their SMT threads, and the NUMA nodes with their
+//! memory. Either as text (`ascii`) or as a Graphviz graph (`dot`, e.g. `|
+//! dot -Tsvg > topology.svg`), to check where a deployment puts a program
+//! before starting a long campaign.
+//!
+//! `--render json` captures the topology (with the cache sizes and the offline
+//! CPUs), and `autoperf topology diff` tells what changed between two captures
+//! (or result directories): the number of cores, cache sizes, the NUMA layout
+//! (e.g. after enabling sub-NUMA clustering in the BIOS) or offline CPUs.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use rustc_serialize::json::{self, Json};
+
+use super::bytesize::ByteSize;
+use super::util::{Core, Cpu, CpuInfo, MachineTopology, Socket, L2, L3, SCHEMA_VERSION};
+
+const OFFLINE_CPUS: &str = "/sys/devices/system/cpu/offline";
+
+/// `0-3,8,10-11`
+pub fn cpu_list(cpus: &[Cpu]) -> String {
+    let mut cpus = cpus.to_vec();
+    cpus.sort();
+    cpus.dedup();
+    let mut ranges: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < cpus.len() {
+        let mut j = i;
+        while j + 1 < cpus.len() && cpus[j + 1] == cpus[j] + 1 {
+            j += 1;
+        }
+        ranges.push(match j > i {
+            true => format!("{}-{}", cpus[i], cpus[j]),
+            false => cpus[i].to_string(),
+        });
+        i = j + 1;
+    }
+    ranges.join(",")
+}
+
+fn gigabytes(memory: Option<u64>, tier: &str) -> String {
+    match memory {
+        Some(bytes) => format!("{} {}", ByteSize(bytes), tier),
+        None => String::from("no memory"),
+    }
+}
+
+fn all_cpus(mt: &MachineTopology) -> Vec<&CpuInfo> {
+    mt.cpus().into_iter().filter_map(|c| mt.cpu(c)).collect()
+}
+
+/// Do some cores share their L2?
+fn shared_l2(cpus: &[&CpuInfo]) -> bool {
+    let cores: BTreeSet<(L2, Core)> = cpus.iter().map(|c| (c.l2, c.core)).collect();
+    let l2s: BTreeSet<L2> = cpus.iter().map(|c| c.l2).collect();
+    cores.len() > l2s.len()
+}
+
+/// The (sorted) values of `key` in `cpus`.
+fn ids<F: Fn(&CpuInfo) -> u64>(cpus: &[&CpuInfo], key: F) -> Vec<u64> {
+    let ids: BTreeSet<u64> = cpus.iter().map(|c| key(c)).collect();
+    ids.into_iter().collect()
+}
+
+fn on<'a, F: Fn(&CpuInfo) -> bool>(cpus: &[&'a CpuInfo], filter: F) -> Vec<&'a CpuInfo> {
+    cpus.iter().filter(|c| filter(c)).copied().collect()
+}
+
+fn threads(cpus: &[&CpuInfo]) -> String {
+    let threads: Vec<String> = ids(cpus, |c| c.cpu).iter().map(|c| c.to_string()).collect();
+    threads.join(" ")
+}
+
+fn nodes_of(cpus: &[&CpuInfo]) -> String {
+    let nodes: Vec<String> = ids(cpus, |c| c.node.node)
+        .iter()
+        .map(|n| n.to_string())
+        .collect();
+    match nodes.len() {
+        1 => format!("node {}", nodes[0]),
+        _ => format!("nodes {}", nodes.join(",")),
+    }
+}
+
+/// The machine as indented text.
+pub fn ascii(mt: &MachineTopology) -> String {
+    let cpus = all_cpus(mt);
+    let l2_level = shared_l2(&cpus);
+    let mut out = String::new();
+    for socket in mt.sockets() {
+        let on_socket = on(&cpus, |c| c.socket == socket);
+        let _ = writeln!(out, "Socket {} ({})", socket, nodes_of(&on_socket));
+        for l3 in ids(&on_socket, |c| c.l3) {
+            let on_l3 = on(&on_socket, |c| c.l3 == l3);
+            let _ = writeln!(
+                out,
+                "  L3 {} ({}, {} cores)",
+                l3,
+                nodes_of(&on_l3),
+                ids(&on_l3, |c| c.core).len()
+            );
+            for l2 in ids(&on_l3, |c| c.l2) {
+                let on_l2 = on(&on_l3, |c| c.l2 == l2);
+                let indent = if l2_level {
+                    let _ = writeln!(out, "    L2 {}", l2);
+                    "      "
+                } else {
+                    "    "
+                };
+                for core in ids(&on_l2, |c| c.core) {
+                    let on_core = on(&on_l2, |c| c.core == core);
+                    let _ = writeln!(out, "{}Core {}: CPU {}", indent, core, threads(&on_core));
+                }
+            }
+        }
+    }
+    for node in mt.all_nodes().iter() {
+        let on_node: Vec<Cpu> = on(&cpus, |c| c.node.node == node.node)
+            .iter()
+            .map(|c| c.cpu)
+            .collect();
+        let cpus = match on_node.is_empty() {
+            true => String::from("no CPUs"),
+            false => format!("CPUs {}", cpu_list(&on_node)),
+        };
+        let _ = writeln!(
+            out,
+            "Node {}: {}, {}",
+            node.node,
+            gigabytes(node.memory, &node.tier.to_string()),
+            cpus
+        );
+    }
+    out
+}
+
+/// The machine as a Graphviz graph: a cluster for every socket and L3 (and
+/// shared L2), a box for every core and an ellipse for every NUMA node.
+pub fn dot(mt: &MachineTopology) -> String {
+    let cpus = all_cpus(mt);
+    let l2_level = shared_l2(&cpus);
+    let mut out = String::from("graph topology {\n  compound=true;\n  node [shape=box];\n");
+    for socket in mt.sockets() {
+        let on_socket = on(&cpus, |c| c.socket == socket);
+        let _ = writeln!(out, "  subgraph cluster_socket{} {{", socket);
+        let _ = writeln!(out, "    label=\"Socket {}\";", socket);
+        for l3 in ids(&on_socket, |c| c.l3) {
+            let on_l3 = on(&on_socket, |c| c.l3 == l3);
+            let _ = writeln!(out, "    subgraph cluster_l3_{} {{", l3);
+            let _ = writeln!(out, "      label=\"L3 {}\";", l3);
+            for l2 in ids(&on_l3, |c| c.l2) {
+                let on_l2 = on(&on_l3, |c| c.l2 == l2);
+                if l2_level {
+                    let _ = writeln!(out, "      subgraph cluster_l2_{} {{", l2);
+                    let _ = writeln!(out, "        label=\"L2 {}\";", l2);
+                }
+                for core in ids(&on_l2, |c| c.core) {
+                    let on_core = on(&on_l2, |c| c.core == core);
+                    let _ = writeln!(
+                        out,
+                        "      core{} [label=\"Core {}\\nCPU {}\"];",
+                        core,
+                        core,
+                        threads(&on_core)
+                    );
+                }
+                if l2_level {
+                    let _ = writeln!(out, "      }}");
+                }
+            }
+            let _ = writeln!(out, "    }}");
+        }
+        let _ = writeln!(out, "  }}");
+    }
+    for node in mt.all_nodes().iter() {
+        let _ = writeln!(
+            out,
+            "  node{} [shape=ellipse, label=\"Node {}\\n{}\"];",
+            node.node,
+            node.node,
+            gigabytes(node.memory, &node.tier.to_string())
+        );
+        // One edge to every L3 with CPUs of the node:
+        let l3s: Vec<L3> = ids(&on(&cpus, |c| c.node.node == node.node), |c| c.l3);
+        for l3 in l3s {
+            let first = on(&cpus, |c| c.l3 == l3 && c.node.node == node.node)
+                .iter()
+                .map(|c| c.core)
+                .min();
+            if let Some(core) = first {
+                let _ = writeln!(
+                    out,
+                    "  node{} -- core{} [lhead=cluster_l3_{}];",
+                    node.node, core, l3
+                );
+            }
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct CapturedCpu {
+    pub cpu: Cpu,
+    pub core: Core,
+    pub socket: Socket,
+    pub node: u64,
+    pub l1: u64,
+    pub l2: L2,
+    pub l3: L3,
+}
+
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct CapturedNode {
+    pub node: u64,
+    /// In bytes, None if it has none.
+    pub memory: Option<u64>,
+    pub tier: String,
+}
+
+/// The topology of a machine at some point in time.
+#[derive(Debug, Clone, RustcEncodable)]
+pub struct CapturedTopology {
+    pub schema_version: u64,
+    pub cpus: Vec<CapturedCpu>,
+    pub nodes: Vec<CapturedNode>,
+    /// The cache sizes in bytes (None unless captured on the machine itself).
+    pub l1_size: Option<u64>,
+    pub l2_size: Option<u64>,
+    pub l3_size: Option<u64>,
+    /// None unless captured on the machine itself.
+    pub offline: Option<Vec<Cpu>>,
+}
+
+/// `0-3,8` as 0, 1, 2, 3, 8.
+fn parse_cpu_list(list: &str) -> Vec<Cpu> {
+    list.trim()
+        .split(',')
+        .filter(|r| !r.is_empty())
+        .flat_map(|range| {
+            let mut bounds = range.splitn(2, '-').map(|c| Cpu::from_str(c.trim()).ok());
+            match (bounds.next().and_then(|c| c), bounds.next()) {
+                (Some(from), Some(Some(to))) => (from..=to).collect(),
+                (Some(cpu), None) => vec![cpu],
+                _ => Vec::new(),
+            }
+        })
+        .collect()
+}
+
+impl CapturedTopology {
+    /// `mt` as it is now, with the cache sizes and offline CPUs of this machine
+    /// if it is `live`.
+    pub fn new(mt: &MachineTopology, live: bool) -> CapturedTopology {
+        CapturedTopology {
+            schema_version: SCHEMA_VERSION,
+            cpus: all_cpus(mt)
+                .iter()
+                .map(|c| CapturedCpu {
+                    cpu: c.cpu,
+                    core: c.core,
+                    socket: c.socket,
+                    node: c.node.node,
+                    l1: c.l1,
+                    l2: c.l2,
+                    l3: c.l3,
+                })
+                .collect(),
+            nodes: mt
+                .all_nodes()
+                .iter()
+                .map(|n| CapturedNode {
+                    node: n.node,
+                    memory: n.memory,
+                    tier: n.tier.to_string(),
+                })
+                .collect(),
+            l1_size: if live { mt.l1_size() } else { None },
+            l2_size: if live { mt.l2_size() } else { None },
+            l3_size: if live { mt.l3_size() } else { None },
+            offline: if live {
+                Some(parse_cpu_list(
+                    &fs::read_to_string(OFFLINE_CPUS).unwrap_or_default(),
+                ))
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{}\n", json::as_pretty_json(self))
+    }
+
+    fn from_json(topology: &Json) -> Option<CapturedTopology> {
+        let number = |v: &Json, key: &str| v.find(key).and_then(|v| v.as_u64());
+        let list = |key: &str| {
+            topology
+                .find(key)
+                .and_then(|v| v.as_array())
+                .map_or(Vec::new(), |l| l.clone())
+        };
+        Some(CapturedTopology {
+            schema_version: number(topology, "schema_version").unwrap_or(1),
+            cpus: list("cpus")
+                .iter()
+                .map(|c| {
+                    Some(CapturedCpu {
+                        cpu: number(c, "cpu")?,
+                        core: number(c, "core")?,
+                        socket: number(c, "socket")?,
+                        node: number(c, "node")?,
+                        l1: number(c, "l1")?,
+                        l2: number(c, "l2")?,
+                        l3: number(c, "l3")?,
+                    })
+                })
+                .collect::<Option<Vec<CapturedCpu>>>()?,
+            nodes: list("nodes")
+                .iter()
+                .map(|n| {
+                    Some(CapturedNode {
+                        node: number(n, "node")?,
+                        memory: number(n, "memory"),
+                        tier: n
+                            .find("tier")
+                            .and_then(|t| t.as_string())
+                            .map_or(String::new(), String::from),
+                    })
+                })
+                .collect::<Option<Vec<CapturedNode>>>()?,
+            l1_size: number(topology, "l1_size"),
+            l2_size: number(topology, "l2_size"),
+            l3_size: number(topology, "l3_size"),
+            offline: topology
+                .find("offline")
+                .and_then(|o| o.as_array())
+                .map(|o| o.iter().filter_map(|c| c.as_u64()).collect()),
+        })
+    }
+
+    /// A JSON file of `--render json`, or a directory (or shape) `--topology`
+    /// takes.
+    pub fn read(spec: &str) -> Result<CapturedTopology, String> {
+        if Path::new(spec).is_file() {
+            let content = fs::read_to_string(spec).map_err(|e| format!("{}: {}", spec, e))?;
+            let topology = Json::from_str(&content).map_err(|e| format!("{}: {}", spec, e))?;
+            return CapturedTopology::from_json(&topology).ok_or(format!(
+                "{} isn't a topology (autoperf topology --render json)",
+                spec
+            ));
+        }
+        MachineTopology::load(spec).map(|mt| CapturedTopology::new(&mt, false))
+    }
+
+    /// What we compare: (name, value), e.g. ("cores", "32").
+    fn shape(&self) -> Vec<(String, String)> {
+        let distinct = |id: &dyn Fn(&CapturedCpu) -> (u64, u64)| {
+            let ids: BTreeSet<(u64, u64)> = self.cpus.iter().map(id).collect();
+            ids.len().to_string()
+        };
+        let mut threads: BTreeMap<(Socket, Core), usize> = BTreeMap::new();
+        for c in self.cpus.iter() {
+            *threads.entry((c.socket, c.core)).or_insert(0) += 1;
+        }
+        let size = |s: Option<u64>| s.map_or(String::from("unknown"), |s| ByteSize(s).to_string());
+
+        let mut shape = vec![
+            (String::from("sockets"), distinct(&|c| (c.socket, 0))),
+            (String::from("cores"), distinct(&|c| (c.socket, c.core))),
+            (String::from("CPUs"), self.cpus.len().to_string()),
+            (
+                String::from("SMT threads per core"),
+                threads
+                    .values()
+                    .max()
+                    .map_or(String::from("0"), |t| t.to_string()),
+            ),
+            (String::from("L2 domains"), distinct(&|c| (c.l2, 0))),
+            (String::from("L3 domains"), distinct(&|c| (c.l3, 0))),
+            (String::from("L1 size"), size(self.l1_size)),
+            (String::from("L2 size"), size(self.l2_size)),
+            (String::from("L3 size"), size(self.l3_size)),
+            (String::from("NUMA nodes"), self.nodes.len().to_string()),
+        ];
+        for node in self.nodes.iter() {
+            let cpus: Vec<Cpu> = self
+                .cpus
+                .iter()
+                .filter(|c| c.node == node.node)
+                .map(|c| c.cpu)
+                .collect();
+            shape.push((
+                format!("node {} memory", node.node),
+                gigabytes(node.memory, &node.tier),
+            ));
+            shape.push((format!("node {} CPUs", node.node), cpu_list(&cpus)));
+        }
+        shape.push((
+            String::from("offline CPUs"),
+            self.offline
+                .as_ref()
+                .map_or(String::from("unknown"), |o| cpu_list(o)),
+        ));
+        shape
+    }
+}
+
+/// What differs between `a` and `b`: `cores: 32 -> 28`. Cache sizes and offline
+/// CPUs only count if both know them.
+pub fn diff(a: &CapturedTopology, b: &CapturedTopology) -> Vec<String> {
+    let (a, b) = (a.shape(), b.shape());
+    let value = |shape: &[(String, String)], key: &str| {
+        shape
+            .iter()
+            .find(|&(k, _)| k == key)
+            .map_or(String::from("-"), |(_, v)| v.clone())
+    };
+    // In the order of a, with what only b has last:
+    let mut keys: Vec<&String> = a.iter().map(|(k, _)| k).collect();
+    keys.extend(
+        b.iter()
+            .map(|(k, _)| k)
+            .filter(|k| a.iter().all(|(ka, _)| ka != *k)),
+    );
+    keys.into_iter()
+        .filter_map(|key| {
+            let (in_a, in_b) = (value(&a, key), value(&b, key));
+            if in_a == in_b || in_a == "unknown" || in_b == "unknown" {
+                return None;
+            }
+            Some(format!("{}: {} -> {}", key, in_a, in_b))
+        })
+        .collect()
+}
@@ -0,0 +1,103 @@
+//! Runs turbostat next to perf to find out at which frequency (and in which C-states)
+//! the CPUs were while we measured. Without this, IPC comparisons across runs are
+//! easily confounded by turbo boost or thermal limits.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+
+use tracing::debug;
+
+/// Columns of turbostat we keep (besides C-state residencies).
+const METRICS: [&str; 6] = [
+    "Avg_MHz", "Busy%", "Bzy_MHz", "TSC_MHz", "CoreTmp", "PkgTmp",
+];
+
+/// An average over the whole measurement: (cpu, metric, value).
+/// The cpu is "-" for turbostat's summary of all CPUs.
+pub type TurbostatValue = (String, String, f64);
+
+pub struct Turbostat {
+    child: Child,
+    output: PathBuf,
+}
+
+impl Turbostat {
+    /// Starts turbostat, it writes a line per CPU every second to `output`.
+    pub fn start(output: &Path) -> io::Result<Turbostat> {
+        let child = Command::new("turbostat")
+            .arg("--quiet")
+            .arg("--interval")
+            .arg("1")
+            .arg("--out")
+            .arg(output.as_os_str())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        Ok(Turbostat {
+            child,
+            output: output.to_path_buf(),
+        })
+    }
+
+    /// Stops turbostat and returns the averages of every CPU.
+    pub fn finish(mut self) -> io::Result<Vec<TurbostatValue>> {
+        unsafe {
+            libc::kill(self.child.id() as libc::pid_t, libc::SIGINT);
+        }
+        let _ = self.child.wait()?;
+
+        let output = fs::read_to_string(&self.output)?;
+        Ok(parse_turbostat(output.as_str()))
+    }
+}
+
+fn is_metric(column: &str) -> bool {
+    METRICS.contains(&column) || column.starts_with("CPU%c") || column.starts_with("Pkg%pc")
+}
+
+/// Averages all intervals in the turbostat output per CPU.
+fn parse_turbostat(output: &str) -> Vec<TurbostatValue> {
+    let mut header: Vec<&str> = Vec::new();
+    // (cpu, metric) -> (sum, samples)
+    let mut sums: BTreeMap<(String, String), (f64, u64)> = BTreeMap::new();
+
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        // turbostat repeats the header for every interval:
+        if fields.contains(&"CPU") {
+            header = fields;
+            continue;
+        }
+        let cpu_idx = match header.iter().position(|h| *h == "CPU") {
+            Some(idx) if idx < fields.len() => idx,
+            _ => continue,
+        };
+
+        // Some columns (e.g., PkgTmp) are only reported on the first CPU of a package:
+        for (column, value) in header.iter().zip(fields.iter()) {
+            if !is_metric(column) {
+                continue;
+            }
+            if let Ok(value) = f64::from_str(value) {
+                let entry = sums
+                    .entry((String::from(fields[cpu_idx]), String::from(*column)))
+                    .or_insert((0.0, 0));
+                entry.0 += value;
+                entry.1 += 1;
+            }
+        }
+    }
+
+    debug!("Parsed {} turbostat values.", sums.len());
+    sums.into_iter()
+        .map(|((cpu, metric), (sum, n))| (cpu, metric, sum / n as f64))
+        .collect()
+}
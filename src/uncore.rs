@@ -0,0 +1,96 @@
+//! The CBo/CHA (the uncore unit of an LLC slice) next to a core. Every PMU
+//! `uncore_cbox_<n>` or `uncore_cha_<n>` of the kernel is a box, and its
+//! `cpumask` has the CPU of every socket perf counts it on (so `uncore_cha_3`
+//! is the fourth box of every socket that's in the mask).
+//!
+//! Counting the box index as the core id modulo the cores of the socket goes
+//! wrong twice: core ids needn't be dense (they skip the cores of disabled
+//! tiles, or go on from socket to socket), and a Xeon with disabled cores keeps
+//! the LLC slices and CHAs of their tiles, so it has more boxes than cores.
+//! `uncore_boxes_for` therefore
+//!
+//! * takes the box at the place of the core among the cores of its socket (see
+//!   `CpuInfo::core_on_socket`) if the socket has a box for every core, and
+//! * all boxes of the socket otherwise: the kernel doesn't say which tiles are
+//!   disabled, and as the LLC hashes addresses over all slices, they are where
+//!   the lookups of the core end up anyway.
+//!
+//! The names it returns are PMUs of this machine that count on the socket of
+//! the CPU.
+use std::fs;
+use std::path::Path;
+
+use super::pci::parse_cpulist;
+use super::pmu::DEVICES;
+use super::util::{Cpu, MachineTopology};
+
+/// The prefixes of the box PMUs, the CHA of newer Xeons replaced the CBo.
+const BOX_PMUS: [&str; 2] = ["uncore_cha_", "uncore_cbox_"];
+
+/// A box PMU of the kernel.
+#[derive(Debug, Clone)]
+struct UncoreBox {
+    index: usize,
+    name: String,
+    /// The CPUs perf counts it on (all CPUs if the PMU doesn't have a mask).
+    cpumask: Option<Vec<Cpu>>,
+}
+
+/// The box PMUs in `devices` (usually `DEVICES`), by index.
+fn read_boxes(devices: &Path) -> Vec<UncoreBox> {
+    let mut boxes = Vec::new();
+    let entries = match fs::read_dir(devices) {
+        Ok(entries) => entries,
+        Err(_) => return boxes,
+    };
+    for device in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let name = device.file_name().unwrap().to_string_lossy().into_owned();
+        let index = BOX_PMUS
+            .iter()
+            .filter(|prefix| name.starts_with(*prefix))
+            .filter_map(|prefix| name[prefix.len()..].parse::<usize>().ok())
+            .next();
+        if let Some(index) = index {
+            boxes.push(UncoreBox {
+                index,
+                name,
+                cpumask: fs::read_to_string(device.join("cpumask"))
+                    .ok()
+                    .map(|mask| parse_cpulist(mask.trim())),
+            });
+        }
+    }
+    boxes.sort_by_key(|b| b.index);
+    boxes
+}
+
+/// The boxes of `boxes` next to `cpu`.
+fn boxes_for(boxes: &[UncoreBox], mt: &MachineTopology, cpu: Cpu) -> Result<Vec<String>, String> {
+    let info = mt
+        .cpu(cpu)
+        .ok_or(format!("CPU {} isn't a CPU of this machine", cpu))?;
+    let on_socket = |b: &&UncoreBox| {
+        b.cpumask.as_ref().is_none_or(|mask| {
+            mask.iter()
+                .any(|c| mt.cpu(*c).is_some_and(|c| c.socket == info.socket))
+        })
+    };
+    let socket_boxes: Vec<&UncoreBox> = boxes.iter().filter(on_socket).collect();
+    if socket_boxes.is_empty() {
+        return Err(format!(
+            "socket {} doesn't have CBo or CHA PMUs (is the uncore driver loaded?)",
+            info.socket
+        ));
+    }
+    let cores = mt.cores_on_socket(info.socket).len();
+    if socket_boxes.len() == cores {
+        Ok(vec![socket_boxes[info.core_on_socket(mt)].name.clone()])
+    } else {
+        Ok(socket_boxes.iter().map(|b| b.name.clone()).collect())
+    }
+}
+
+/// The CBo/CHA PMUs of this machine next to `cpu` (see the module).
+pub fn uncore_boxes_for(mt: &MachineTopology, cpu: Cpu) -> Result<Vec<String>, String> {
+    boxes_for(&read_boxes(Path::new(DEVICES)), mt, cpu)
+}
@@ -2,19 +2,29 @@
 
 use csv;
 use itertools::*;
-use log::error as lerror;
-use log::*;
-use nom::*;
+use schemars::JsonSchema;
+use std::collections::BTreeSet;
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::os::unix::fs::symlink;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::{Command, Output};
-use std::str::{from_utf8_unchecked, FromStr};
+use std::process::Command;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use toml;
+use tracing::{debug, warn};
 use x86::cpuid;
 
+use super::gpu::{self, GpuInfo};
+use super::memtier::{self, MemoryTier};
+use super::nic::{self, NicInfo};
+use super::numactl::{self, NumaNode};
+use super::sysctl;
+
 pub type Node = u64;
 pub type Socket = u64;
 pub type Core = u64;
@@ -25,52 +35,195 @@ pub type L3 = u64;
 pub type Online = u64;
 pub type MHz = u64;
 
+/// Version of the files autoperf writes, increase it whenever their layout changes.
+/// Version 1 are files written before we started to version them, version 3 has
+/// the `started` column in `perf.csv` and no `CORE` and `CPU` for uncore events.
+pub const SCHEMA_VERSION: u64 = 3;
+
+/// The memory of every node of `MachineTopology::synthetic`.
+pub const SYNTHETIC_NODE_MIB: u64 = 65536;
+
+/// The core types of the CPUs in a run directory (see `coretypes.rs`, which isn't
+/// part of the library).
+pub const CORE_TYPE_FILE: &str = "core_types.csv";
+
 pub fn mkdir(out_dir: &Path) {
     if !out_dir.exists() {
-        fs::create_dir(out_dir).expect("Can't create directory");
+        if let Err(e) = fs::create_dir_all(out_dir) {
+            panic!("Can't create directory {:?}: {}", out_dir, e);
+        }
     }
 }
 
-fn to_string(s: &[u8]) -> &str {
-    unsafe { from_utf8_unchecked(s) }
+/// What we do if an output directory already has something in it (`--on-existing`).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Collision {
+    /// Stop.
+    Error,
+    /// Continue the results in it (runs that completed are skipped), if it has
+    /// results of autoperf.
+    Resume,
+    /// Write to `<directory>-<timestamp>` instead.
+    Suffix,
 }
 
-fn to_u64(s: &str) -> u64 {
-    FromStr::from_str(s).unwrap()
+/// The names of the collision policies.
+pub const COLLISIONS: [&str; 3] = ["error", "resume", "suffix"];
+
+impl FromStr for Collision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Collision, String> {
+        match s {
+            "error" => Ok(Collision::Error),
+            "resume" => Ok(Collision::Resume),
+            "suffix" => Ok(Collision::Suffix),
+            _ => Err(format!(
+                "unknown collision policy '{}' (should be one of {})",
+                s,
+                COLLISIONS.join(", ")
+            )),
+        }
+    }
 }
 
-fn buf_to_u64(s: &[u8]) -> u64 {
-    to_u64(to_string(s))
+/// Files that only autoperf writes into an output directory (a run, or a campaign).
+const RESULT_MARKERS: [&str; 4] = ["summary.json", "perf.csv", "run.toml", "completed"];
+
+/// `time` as seconds since the epoch, to the microsecond (`started` in perf.csv).
+pub fn unix_time(time: SystemTime) -> String {
+    format!(
+        "{:.6}",
+        time.duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64())
+    )
 }
 
-named!(parse_numactl_size<&[u8], NodeInfo>,
-    chain!(
-        tag!("node") ~
-        take_while!(is_space) ~
-        node: take_while!(is_digit) ~
-        take_while!(is_space) ~
-        tag!("size:") ~
-        take_while!(is_space) ~
-        size: take_while!(is_digit) ~
-        take_while!(is_space) ~
-        tag!("MB"),
-        || NodeInfo { node: buf_to_u64(node), memory: buf_to_u64(size) * 1000000 }
+/// The local time as `20240611-142501`.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs()) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
     )
-);
-
-fn get_node_info(node: Node, numactl_output: &String) -> Option<NodeInfo> {
-    let find_prefix = format!("node {} size:", node);
-    for line in numactl_output.split('\n') {
-        if line.starts_with(find_prefix.as_str()) {
-            let res = parse_numactl_size(line.as_bytes());
-            return Some(res.unwrap().1);
+}
+
+/// Creates the output directory `dir` (and its parents) and returns where to
+/// write, which is somewhere else with `Collision::Suffix` if `dir` already has
+/// something in it.
+pub fn output_directory(dir: &Path, policy: Collision) -> Result<PathBuf, String> {
+    let used = fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some());
+    let dir = match (used, policy) {
+        (false, _) => dir.to_path_buf(),
+        (true, Collision::Error) => {
+            return Err(format!(
+                "{:?} isn't empty (--on-existing resume continues it, suffix writes next to it)",
+                dir
+            ))
+        }
+        (true, Collision::Resume) => {
+            if !RESULT_MARKERS.iter().any(|m| dir.join(m).exists()) {
+                return Err(format!(
+                    "{:?} isn't empty, but it has no results of autoperf to continue",
+                    dir
+                ));
+            }
+            dir.to_path_buf()
         }
+        (true, Collision::Suffix) => {
+            let name = format!(
+                "{}-{}",
+                dir.file_name()
+                    .map_or(String::from("out"), |n| n.to_string_lossy().into_owned()),
+                timestamp()
+            );
+            let mut candidate = dir.with_file_name(&name);
+            let mut nr = 2;
+            while candidate.exists() {
+                candidate = dir.with_file_name(format!("{}-{}", name, nr));
+                nr += 1;
+            }
+            debug!("{:?} isn't empty, writing to {:?}", dir, candidate);
+            candidate
+        }
+    };
+    fs::create_dir_all(&dir).map_err(|e| format!("Can't create {:?}: {}", dir, e))?;
+    Ok(dir)
+}
+
+/// The node as we keep it. Nodes without memory (`size: 0 MB`, or no `size:`
+/// line at all) have none.
+fn node_info(node: &NumaNode) -> NodeInfo {
+    NodeInfo {
+        node: node.node,
+        memory: node.size.filter(|size| *size > 0),
+        tier: MemoryTier::Dram,
     }
+}
+
+/// Node `node`, also if numactl doesn't list it (it then has no memory).
+fn get_node_info(node: Node, numa_nodes: &[NumaNode]) -> NodeInfo {
+    numa_nodes.iter().find(|n| n.node == node).map_or(
+        NodeInfo {
+            node,
+            memory: None,
+            tier: MemoryTier::Dram,
+        },
+        node_info,
+    )
+}
 
-    None
+/// All nodes with memory in the numactl output, also those without CPUs.
+fn get_node_infos(numa_nodes: &[NumaNode]) -> Vec<NodeInfo> {
+    numa_nodes
+        .iter()
+        .map(node_info)
+        .filter(|n| n.memory.is_some())
+        .collect()
 }
 
-#[derive(Debug, Eq, PartialEq, RustcEncodable)]
+/// A row of `lscpu --parse=NODE,SOCKET,CORE,CPU,CACHE`. lscpu leaves the node
+/// empty on machines without NUMA (we use node 0) and has fewer cache ids on
+/// machines without an L3 or L2: we then assume the L1 and L2 are private to
+/// the core and the L3 is shared by the socket.
+fn lscpu_row(line: &str) -> Option<(Node, Socket, Core, Cpu, L1, L2, L3)> {
+    let columns: Vec<&str> = line.split(',').map(|c| c.trim()).collect();
+    if columns.len() < 4 {
+        return None;
+    }
+    let id = |column: &str| u64::from_str(column).ok();
+    let node = if columns[0].is_empty() {
+        0
+    } else {
+        id(columns[0])?
+    };
+    let (socket, core, cpu) = (id(columns[1])?, id(columns[2])?, id(columns[3])?);
+    let caches: Vec<Option<u64>> = columns
+        .get(4)
+        .map_or(Vec::new(), |c| c.split(':').map(id).collect());
+    let cache =
+        |level: usize, default: u64| caches.get(level).cloned().flatten().unwrap_or(default);
+    Some((
+        node,
+        socket,
+        core,
+        cpu,
+        cache(0, core),
+        cache(2, core),
+        cache(3, socket),
+    ))
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, RustcEncodable)]
 pub struct CpuInfo {
     pub node: NodeInfo,
     pub socket: Socket,
@@ -82,86 +235,347 @@ pub struct CpuInfo {
 }
 
 impl CpuInfo {
+    /// Where the core is among the cores of its socket (by core id): core ids
+    /// needn't be dense (0, 1, 2, 8, 9, ... on some Xeons) and go on from socket
+    /// to socket on others.
+    pub fn core_on_socket(&self, mt: &MachineTopology) -> usize {
+        mt.cores_on_socket(self.socket)
+            .iter()
+            .position(|c| *c == self.core)
+            .unwrap_or(0)
+    }
+
+    /// The CBo of the core if every core has one, `uncore::uncore_boxes_for`
+    /// checks that with the PMUs of the machine.
     pub fn cbox(&self, mt: &MachineTopology) -> String {
-        let cbox = self.core % mt.cores_on_socket(self.socket).len() as u64;
-        format!("uncore_cbox_{}", cbox)
+        format!("uncore_cbox_{}", self.core_on_socket(mt))
+    }
+
+    /// The id of the `domain` the CPU is in.
+    pub fn domain(&self, domain: Domain) -> u64 {
+        match domain {
+            Domain::Core => self.core,
+            Domain::L1 => self.l1,
+            Domain::L2 => self.l2,
+            Domain::L3 => self.l3,
+            Domain::Node => self.node.node,
+            Domain::Socket => self.socket,
+            Domain::Machine => 0,
+        }
     }
 }
 
+/// What a group of CPUs shares.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Domain {
+    Core,
+    L1,
+    L2,
+    L3,
+    Node,
+    Socket,
+    Machine,
+}
+
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, RustcEncodable)]
 pub struct NodeInfo {
     pub node: Node,
-    pub memory: u64,
+    /// In bytes, None for nodes without memory (CPU-only nodes). We never bind
+    /// memory to them.
+    pub memory: Option<u64>,
+    /// What memory the node has (see `memtier.rs`).
+    pub tier: MemoryTier,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MachineTopology {
     data: Vec<CpuInfo>,
+    /// Every node with memory (`data` only has those with CPUs, with or
+    /// without memory).
+    memory: Vec<NodeInfo>,
+    gpus: Vec<GpuInfo>,
+    nics: Vec<NicInfo>,
+    /// The sizes of the L1, L2 and L3 the run recorded (None: ask cpuid).
+    cache_sizes: Option<[Option<u64>; 3]>,
 }
 
-fn save_file(
-    cmd: &'static str,
-    output_path: &Path,
-    file: &'static str,
-    out: Output,
-) -> io::Result<String> {
-    if out.status.success() {
-        // Save to result directory:
-        let mut out_file: PathBuf = output_path.to_path_buf();
-        out_file.push(file);
-        let mut f = File::create(out_file.as_path())?;
-        let content = String::from_utf8(out.stdout).unwrap_or(String::new());
-        f.write(content.as_bytes())?;
-        Ok(content)
-    } else {
-        lerror!(
-            "{} command: got unknown exit status was: {}",
-            cmd,
-            out.status
-        );
-        debug!(
-            "stderr:\n{}",
-            String::from_utf8(out.stderr).unwrap_or("Can't parse output".to_string())
-        );
-        unreachable!()
+/// How many cores `cpus` are on.
+fn distinct_cores(cpus: &[&CpuInfo]) -> u64 {
+    let cores: BTreeSet<(Socket, Core)> = cpus.iter().map(|c| (c.socket, c.core)).collect();
+    cores.len() as u64
+}
+
+/// The cache sizes of the machine a run was measured on (see `save_cache_sizes`).
+pub const CACHE_FILE: &str = "cache_sizes.csv";
+
+/// The size of the level `level` cache of `cache_type` of this machine (from
+/// cpuid).
+fn cpuid_cache_size(level: u8, cache_type: cpuid::CacheType) -> Option<u64> {
+    let cpuid = cpuid::CpuId::new();
+    cpuid.get_cache_parameters().map(|mut cparams| {
+        let cache = cparams
+            .find(|c| c.level() == level && c.cache_type() == cache_type)
+            .unwrap();
+        (cache.associativity()
+            * cache.physical_line_partitions()
+            * cache.coherency_line_size()
+            * cache.sets()) as u64
+    })
+}
+
+/// Saves the sizes of the L1 data cache, the L2 and the L3 of this machine in
+/// `CACHE_FILE`, cpuid on another machine would tell that machine's.
+pub fn save_cache_sizes(output_path: &Path) -> io::Result<()> {
+    let io_error = |e: csv::Error| io::Error::other(e);
+    let mut wtr = csv::Writer::from_file(output_path.join(CACHE_FILE)).map_err(io_error)?;
+    wtr.encode(("level", "bytes")).map_err(io_error)?;
+    let sizes = [
+        cpuid_cache_size(1, cpuid::CacheType::Data),
+        cpuid_cache_size(2, cpuid::CacheType::Unified),
+        cpuid_cache_size(3, cpuid::CacheType::Unified),
+    ];
+    for (level, size) in sizes.iter().enumerate() {
+        if let Some(bytes) = size {
+            wtr.encode((level + 1, bytes)).map_err(io_error)?;
+        }
     }
+    wtr.flush().map_err(io_error)
 }
 
-pub fn save_lstopo(output_path: &Path) -> io::Result<String> {
-    let out = Command::new("lstopo")
-        .arg("--of console")
-        .arg("--taskset")
-        .output()?;
-    save_file("lstopo", output_path, "lstopo.txt", out)
+/// The sizes of the L1, L2 and L3 in `CACHE_FILE` at `path` (None if there is
+/// no such file).
+fn read_cache_sizes(path: &Path) -> Option<[Option<u64>; 3]> {
+    let mut rdr = csv::Reader::from_file(path).ok()?.has_headers(true);
+    let mut sizes = [None; 3];
+    for row in rdr.decode() {
+        let row: csv::Result<(usize, u64)> = row;
+        if let Ok((level, bytes)) = row {
+            if (1..=3).contains(&level) {
+                sizes[level - 1] = Some(bytes);
+            }
+        }
+    }
+    Some(sizes)
 }
 
-pub fn save_cpuid(output_path: &Path) -> io::Result<String> {
-    let out = Command::new("cpuid").output()?;
-    save_file("cpuid", output_path, "cpuid.txt", out)
+/// The machine information files we store only once per campaign next to the
+/// captures of the collectors (see `dedup_captures`).
+const CAPTURES: [&str; 7] = [
+    memtier::TIER_FILE,
+    CORE_TYPE_FILE,
+    CACHE_FILE,
+    gpu::GPU_FILE,
+    nic::NIC_FILE,
+    "nvidia_topo.txt",
+    "rocm_topo.txt",
+];
+
+pub fn sha256sum(path: &Path) -> Option<String> {
+    Command::new("sha256sum")
+        .arg(path.as_os_str())
+        .output()
+        .ok()
+        .and_then(|out| {
+            String::from_utf8_lossy(&out.stdout)
+                .split_whitespace()
+                .next()
+                .map(String::from)
+        })
 }
 
-pub fn save_likwid_topology(output_path: &Path) -> io::Result<String> {
-    let out = Command::new("likwid-topology")
-        .arg("-g")
-        .arg("-c")
-        .output()?;
-    save_file("likwid-topology", output_path, "likwid_topology.txt", out)
+/// The path to `target` relative to the directory `base` (both have to exist).
+fn relative_path(target: &Path, base: &Path) -> io::Result<PathBuf> {
+    let target = fs::canonicalize(target)?;
+    let base = fs::canonicalize(base)?;
+    let common = target
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in base.components().skip(common) {
+        relative.push("..");
+    }
+    for c in target.components().skip(common) {
+        relative.push(c.as_os_str());
+    }
+    Ok(relative)
 }
 
-pub fn save_numa_topology(output_path: &Path) -> io::Result<String> {
-    let out = Command::new("numactl").arg("--hardware").output()?;
-    save_file("numactl", output_path, "numactl.dat", out)
+/// Moves the machine information files (`collected`, the files of the collectors,
+/// and `CAPTURES`) of `output_path` into the content-addressed `store` (as
+/// `<sha256>_<file>`) and replaces them with a symlink. Identical captures of a
+/// campaign are stored only once and a differing hash means the machine changed
+/// during the campaign.
+pub fn dedup_captures(output_path: &Path, store: &Path, collected: &[&str]) -> io::Result<()> {
+    mkdir(store);
+    let mut index_file = store.to_path_buf();
+    index_file.push("captures.csv");
+    let new_index = !index_file.exists();
+    let index = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&index_file)?;
+    let mut wtr = csv::Writer::from_writer(index);
+    if new_index {
+        wtr.encode(("run", "file", "sha256")).unwrap();
+    }
+
+    for file in collected.iter().chain(CAPTURES.iter()) {
+        let mut capture = output_path.to_path_buf();
+        capture.push(file);
+        if !capture.exists() || fs::symlink_metadata(&capture)?.file_type().is_symlink() {
+            continue;
+        }
+        let hash = match sha256sum(&capture) {
+            Some(hash) => hash,
+            None => continue,
+        };
+
+        let mut stored = store.to_path_buf();
+        stored.push(format!("{}_{}", hash, file));
+        if stored.exists() {
+            fs::remove_file(&capture)?;
+        } else {
+            let known = fs::read_dir(store)?
+                .filter_map(|e| e.ok())
+                .any(|e| e.file_name().to_string_lossy().ends_with(file));
+            if known {
+                warn!(
+                    "{} of {:?} differs from earlier runs, the machine changed?",
+                    file, output_path
+                );
+            }
+            if fs::rename(&capture, &stored).is_err() {
+                // Not on the same file-system:
+                fs::copy(&capture, &stored)?;
+                fs::remove_file(&capture)?;
+            }
+        }
+
+        symlink(relative_path(&stored, output_path)?, &capture)?;
+        wtr.encode((output_path.to_string_lossy(), file, &hash))
+            .unwrap();
+    }
+    wtr.flush().unwrap();
+
+    Ok(())
+}
+
+/// How time is measured on this machine.
+#[derive(Debug, RustcEncodable, JsonSchema)]
+pub struct ClockInfo {
+    pub schema_version: u64,
+    pub invariant_tsc: bool,
+    /// TSC ticks per second.
+    pub tsc_hz: u64,
+    /// Where `tsc_hz` comes from ("cpuid 0x15", "cpuid 0x16" or "calibrated").
+    pub tsc_hz_source: String,
+    /// The clock source the kernel currently uses (e.g., tsc or hpet).
+    pub clocksource: String,
+    pub available_clocksources: Vec<String>,
+}
+
+/// Measures the TSC frequency against the monotonic clock.
+fn calibrate_tsc() -> u64 {
+    let start = Instant::now();
+    let tsc_start = unsafe { std::arch::x86_64::_rdtsc() };
+    thread::sleep(Duration::from_millis(100));
+    let tsc_end = unsafe { std::arch::x86_64::_rdtsc() };
+    let elapsed = start.elapsed();
+
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    ((tsc_end - tsc_start) as f64 / seconds) as u64
+}
+
+fn read_clocksource(file: &str) -> String {
+    if cfg!(target_os = "freebsd") {
+        // The timecounters, e.g. `TSC-low(1000) ACPI-fast(900) i8254(0)`:
+        let sysctl = match file {
+            "current_clocksource" => "kern.timecounter.hardware",
+            _ => "kern.timecounter.choice",
+        };
+        return sysctl::sysctl(sysctl)
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|tc| tc.split('(').next().unwrap_or(tc))
+            .collect::<Vec<&str>>()
+            .join(" ");
+    }
+    fs::read_to_string(format!(
+        "/sys/devices/system/clocksource/clocksource0/{}",
+        file
+    ))
+    .map(|s| String::from(s.trim()))
+    .unwrap_or(String::new())
 }
 
-pub fn save_cpu_topology(output_path: &Path) -> io::Result<String> {
-    let out = Command::new("lscpu")
-        .arg("--parse=NODE,SOCKET,CORE,CPU,CACHE")
-        .output()?;
-    save_file("lscpu", output_path, "lscpu.csv", out)
+pub fn clock_info() -> ClockInfo {
+    let cpuid = cpuid::CpuId::new();
+    let invariant_tsc = cpuid
+        .get_advanced_power_mgmt_info()
+        .is_some_and(|apm| apm.has_invariant_tsc());
+
+    // The TSC runs at the base frequency if cpuid doesn't tell us the exact value:
+    let from_cpuid = cpuid
+        .get_tsc_info()
+        .and_then(|tsc| tsc.tsc_frequency())
+        .map(|hz| (hz, "cpuid 0x15"))
+        .or(cpuid
+            .get_processor_frequency_info()
+            .map(|f| f.processor_base_frequency() as u64 * 1_000_000)
+            .filter(|hz| *hz > 0)
+            .map(|hz| (hz, "cpuid 0x16")));
+    let (tsc_hz, tsc_hz_source) = from_cpuid.unwrap_or_else(|| (calibrate_tsc(), "calibrated"));
+
+    ClockInfo {
+        schema_version: SCHEMA_VERSION,
+        invariant_tsc,
+        tsc_hz,
+        tsc_hz_source: String::from(tsc_hz_source),
+        clocksource: read_clocksource("current_clocksource"),
+        available_clocksources: read_clocksource("available_clocksource")
+            .split_whitespace()
+            .map(String::from)
+            .collect(),
+    }
+}
+
+pub fn save_clock_info(output_path: &Path) -> io::Result<ClockInfo> {
+    let info = clock_info();
+    if !info.invariant_tsc {
+        warn!("This CPU has no invariant TSC, timestamps may drift with the frequency.");
+    }
+    // FreeBSD calls it TSC or TSC-low:
+    if !info.clocksource.to_lowercase().starts_with("tsc") {
+        warn!(
+            "The kernel uses '{}' as clock source, reading time is slow.",
+            info.clocksource
+        );
+    }
+
+    let mut out_file: PathBuf = output_path.to_path_buf();
+    out_file.push("clock.toml");
+    let mut f = File::create(out_file.as_path())?;
+    f.write_all(toml::encode_str(&info).as_bytes())?;
+    Ok(info)
+}
+
+impl Default for MachineTopology {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MachineTopology {
     pub fn new() -> MachineTopology {
+        if cfg!(target_os = "freebsd") {
+            return MachineTopology::from_strings(
+                sysctl::lscpu_parse(),
+                sysctl::numactl_hardware(),
+            );
+        }
         let lscpu_out = Command::new("lscpu")
             .arg("--parse=NODE,SOCKET,CORE,CPU,CACHE")
             .output()
@@ -171,9 +585,16 @@ impl MachineTopology {
         let numactl_out = Command::new("numactl").arg("--hardware").output().unwrap();
         let numactl_string = String::from_utf8(numactl_out.stdout).unwrap_or(String::new());
 
-        MachineTopology::from_strings(lscpu_string, numactl_string)
+        let mut mt = MachineTopology::from_strings(lscpu_string, numactl_string);
+        mt.set_tiers(&memtier::tiers());
+        mt.gpus = gpu::gpus();
+        mt.nics = nic::nics();
+        mt
     }
 
+    /// The topology in `lscpu.csv` and `numactl.dat`, and the memory tiers, GPUs,
+    /// NICs and cache sizes in `memory_tiers.csv`, `gpus.json`, `nics.json` and
+    /// `cache_sizes.csv` next to `lscpu.csv` (if the run has them).
     pub fn from_files(lcpu_path: &Path, numactl_path: &Path) -> MachineTopology {
         let mut file = File::open(lcpu_path).expect("lscpu.csv file does not exist?");
         let mut lscpu_string = String::new();
@@ -183,42 +604,152 @@ impl MachineTopology {
         let mut numactl_string = String::new();
         let _ = file.read_to_string(&mut numactl_string).unwrap();
 
-        MachineTopology::from_strings(lscpu_string, numactl_string)
+        let mut mt = MachineTopology::from_strings(lscpu_string, numactl_string);
+        if let Some(dir) = lcpu_path.parent() {
+            mt.set_tiers(&memtier::read_memory_tiers(&dir.join(memtier::TIER_FILE)));
+            mt.gpus = gpu::read_gpus(&dir.join(gpu::GPU_FILE));
+            mt.nics = nic::read_nics(&dir.join(nic::NIC_FILE));
+            mt.cache_sizes = read_cache_sizes(&dir.join(CACHE_FILE));
+        }
+        mt
     }
 
-    pub fn from_strings(lscpu_output: String, numactl_output: String) -> MachineTopology {
-        let no_comments: Vec<&str> = lscpu_output
-            .split('\n')
-            .filter(|s| s.trim().len() > 0 && !s.trim().starts_with("#"))
-            .collect();
+    /// A made-up machine with `sockets` of `cores_per_socket` cores, `smt` threads
+    /// per core and `nodes` NUMA nodes (of `SYNTHETIC_NODE_MIB` each) that split
+    /// the cores evenly. Every core has its own L1 and L2, every socket its own
+    /// L3, and the CPUs are numbered like Linux does it: the first thread of every
+    /// core, then the second thread of every core, and so on.
+    pub fn synthetic(
+        sockets: u64,
+        cores_per_socket: u64,
+        smt: u64,
+        nodes: u64,
+    ) -> Result<MachineTopology, String> {
+        let cores = sockets * cores_per_socket;
+        if cores == 0 || smt == 0 || nodes == 0 {
+            return Err(String::from(
+                "a machine needs sockets, cores, threads and nodes",
+            ));
+        }
+        if !cores.is_multiple_of(nodes) || (nodes >= sockets && !nodes.is_multiple_of(sockets)) {
+            return Err(format!(
+                "{} nodes can't split {} sockets with {} cores evenly",
+                nodes, sockets, cores_per_socket
+            ));
+        }
+        let cores_per_node = cores / nodes;
+
+        let mut lscpu = String::from("# Node,Socket,Core,CPU,L1d:L1i:L2:L3\n");
+        let mut node_cpus: Vec<Vec<Cpu>> = vec![Vec::new(); nodes as usize];
+        for thread in 0..smt {
+            for core in 0..cores {
+                let cpu = thread * cores + core;
+                let node = core / cores_per_node;
+                let socket = core / cores_per_socket;
+                node_cpus[node as usize].push(cpu);
+                lscpu.push_str(&format!(
+                    "{},{},{},{},{}:{}:{}:{}\n",
+                    node, socket, core, cpu, core, core, core, socket
+                ));
+            }
+        }
+        let mut numactl = format!("available: {} nodes (0-{})\n", nodes, nodes - 1);
+        for (node, cpus) in node_cpus.iter().enumerate() {
+            let cpus: Vec<String> = cpus.iter().map(|c| c.to_string()).collect();
+            numactl.push_str(&format!("node {} cpus: {}\n", node, cpus.join(" ")));
+            // numactl's MB are MiB:
+            numactl.push_str(&format!("node {} size: {} MB\n", node, SYNTHETIC_NODE_MIB));
+        }
+        Ok(MachineTopology::from_strings(lscpu, numactl))
+    }
+
+    /// The machine `--topology` names: `synthetic:<sockets>x<cores>x<smt>[x<nodes>]`
+    /// (one node per socket by default, see `synthetic`), or a directory with the
+    /// `lscpu.csv` and `numactl.dat` autoperf saved on another machine.
+    pub fn load(spec: &str) -> Result<MachineTopology, String> {
+        if let Some(shape) = spec.strip_prefix("synthetic:") {
+            let numbers: Vec<u64> = shape
+                .split('x')
+                .map(|n| u64::from_str(n.trim()))
+                .collect::<Result<Vec<u64>, _>>()
+                .map_err(|_| format!("'{}' isn't <sockets>x<cores>x<smt>[x<nodes>]", shape))?;
+            return match *numbers.as_slice() {
+                [sockets, cores, smt] => MachineTopology::synthetic(sockets, cores, smt, sockets),
+                [sockets, cores, smt, nodes] => {
+                    MachineTopology::synthetic(sockets, cores, smt, nodes)
+                }
+                _ => Err(format!(
+                    "'{}' isn't <sockets>x<cores>x<smt>[x<nodes>]",
+                    shape
+                )),
+            };
+        }
+        let dir = Path::new(spec);
+        let (lscpu, numactl) = (dir.join("lscpu.csv"), dir.join("numactl.dat"));
+        if !lscpu.exists() || !numactl.exists() {
+            return Err(format!("{} has no lscpu.csv and numactl.dat", spec));
+        }
+        Ok(MachineTopology::from_files(&lscpu, &numactl))
+    }
 
-        type Row = (Node, Socket, Core, Cpu, String); // Online MHz
-        let mut rdr = csv::Reader::from_string(no_comments.join("\n")).has_headers(false);
-        let rows = rdr.decode().collect::<csv::Result<Vec<Row>>>().unwrap();
-
-        let mut data: Vec<CpuInfo> = Vec::with_capacity(rows.len());
-        for row in rows {
-            let caches: Vec<u64> = row
-                .4
-                .split(":")
-                .map(|s| u64::from_str(s).unwrap())
-                .collect();
-            assert_eq!(caches.len(), 4);
-            let node: NodeInfo =
-                get_node_info(row.0, &numactl_output).expect("Can't find node in numactl output?");
-            let tuple: CpuInfo = CpuInfo {
-                node: node,
-                socket: row.1,
-                core: row.2,
-                cpu: row.3,
-                l1: caches[0],
-                l2: caches[2],
-                l3: caches[3],
+    pub fn from_strings(lscpu_output: String, numactl_output: String) -> MachineTopology {
+        let numa_nodes = numactl::parse(&numactl_output);
+        let mut data: Vec<CpuInfo> = Vec::new();
+        for line in lscpu_output.lines() {
+            if line.trim().is_empty() || line.trim().starts_with("#") {
+                continue;
+            }
+            let (node, socket, core, cpu, l1, l2, l3) = match lscpu_row(line) {
+                Some(row) => row,
+                None => {
+                    warn!("Skipping lscpu line we can't read: {}", line);
+                    continue;
+                }
             };
-            data.push(tuple);
+            let node: NodeInfo = get_node_info(node, &numa_nodes);
+            data.push(CpuInfo {
+                node,
+                socket,
+                core,
+                cpu,
+                l1,
+                l2,
+                l3,
+            });
         }
 
-        MachineTopology { data: data }
+        MachineTopology {
+            data,
+            memory: get_node_infos(&numa_nodes),
+            gpus: Vec::new(),
+            nics: Vec::new(),
+            cache_sizes: None,
+        }
+    }
+
+    /// The part of the machine on `cpus` (e.g. the cpuset we may use on a shared
+    /// node): the CPU groups (`same_*`, `physical_cores_in`, `cpu_sets`, ...) only
+    /// have these CPUs. The memory nodes, GPUs and NICs stay as they are. Use the
+    /// whole machine for `CpuInfo::cbox` and `uncore::uncore_boxes_for`, the uncore doesn't shrink with us.
+    pub fn restrict(&self, cpus: &[Cpu]) -> MachineTopology {
+        MachineTopology {
+            data: self
+                .data
+                .iter()
+                .filter(|t| cpus.contains(&t.cpu))
+                .cloned()
+                .collect(),
+            memory: self.memory.clone(),
+            gpus: self.gpus.clone(),
+            nics: self.nics.clone(),
+            cache_sizes: self.cache_sizes,
+        }
+    }
+
+    /// The part of the machine on `socket` (see `restrict`).
+    pub fn restrict_to_socket(&self, socket: Socket) -> MachineTopology {
+        let cpus: Vec<Cpu> = self.cpus_on_socket(socket).iter().map(|c| c.cpu).collect();
+        self.restrict(&cpus)
     }
 
     pub fn cpus(&self) -> Vec<Cpu> {
@@ -253,8 +784,53 @@ impl MachineTopology {
         nodes
     }
 
+    /// Every node with memory, also those without CPUs (CXL and PMem nodes
+    /// usually have none).
+    pub fn memory_nodes(&self) -> &[NodeInfo] {
+        &self.memory
+    }
+
+    /// Every node, with or without CPUs and memory.
+    pub fn all_nodes(&self) -> Vec<NodeInfo> {
+        let mut nodes = self.nodes();
+        nodes.extend(
+            self.memory
+                .iter()
+                .filter(|m| nodes.iter().all(|n| n.node != m.node))
+                .cloned()
+                .collect::<Vec<NodeInfo>>(),
+        );
+        nodes.sort();
+        nodes
+    }
+
+    /// The nodes with memory of `tier`.
+    pub fn nodes_of_tier(&self, tier: MemoryTier) -> Vec<NodeInfo> {
+        self.memory
+            .iter()
+            .filter(|n| n.tier == tier)
+            .cloned()
+            .collect()
+    }
+
+    /// Sets the tier of the nodes in `tiers` (the others are DRAM).
+    fn set_tiers(&mut self, tiers: &[(Node, MemoryTier)]) {
+        let tier_of = |node: Node| {
+            tiers
+                .iter()
+                .find(|t| t.0 == node)
+                .map_or(MemoryTier::Dram, |t| t.1)
+        };
+        for n in self.memory.iter_mut() {
+            n.tier = tier_of(n.node);
+        }
+        for t in self.data.iter_mut() {
+            t.node.tier = tier_of(t.node.node);
+        }
+    }
+
     pub fn max_memory(&self) -> u64 {
-        self.nodes().iter().map(|t| t.memory).sum()
+        self.nodes().iter().filter_map(|t| t.memory).sum()
     }
 
     pub fn l1(&self) -> Vec<L1> {
@@ -265,16 +841,10 @@ impl MachineTopology {
     }
 
     pub fn l1_size(&self) -> Option<u64> {
-        let cpuid = cpuid::CpuId::new();
-        cpuid.get_cache_parameters().map(|mut cparams| {
-            let cache = cparams
-                .find(|c| c.level() == 1 && c.cache_type() == cpuid::CacheType::Data)
-                .unwrap();
-            (cache.associativity()
-                * cache.physical_line_partitions()
-                * cache.coherency_line_size()
-                * cache.sets()) as u64
-        })
+        match self.cache_sizes {
+            Some(sizes) => sizes[0],
+            None => cpuid_cache_size(1, cpuid::CacheType::Data),
+        }
     }
 
     pub fn l2(&self) -> Vec<L2> {
@@ -285,16 +855,10 @@ impl MachineTopology {
     }
 
     pub fn l2_size(&self) -> Option<u64> {
-        let cpuid = cpuid::CpuId::new();
-        cpuid.get_cache_parameters().map(|mut cparams| {
-            let cache = cparams
-                .find(|c| c.level() == 2 && c.cache_type() == cpuid::CacheType::Unified)
-                .unwrap();
-            (cache.associativity()
-                * cache.physical_line_partitions()
-                * cache.coherency_line_size()
-                * cache.sets()) as u64
-        })
+        match self.cache_sizes {
+            Some(sizes) => sizes[1],
+            None => cpuid_cache_size(2, cpuid::CacheType::Unified),
+        }
     }
 
     pub fn l3(&self) -> Vec<L3> {
@@ -305,22 +869,117 @@ impl MachineTopology {
     }
 
     pub fn l3_size(&self) -> Option<u64> {
-        let cpuid = cpuid::CpuId::new();
-        cpuid.get_cache_parameters().map(|mut cparams| {
-            let cache = cparams
-                .find(|c| c.level() == 3 && c.cache_type() == cpuid::CacheType::Unified)
-                .unwrap();
-            (cache.associativity()
-                * cache.physical_line_partitions()
-                * cache.coherency_line_size()
-                * cache.sets()) as u64
-        })
+        match self.cache_sizes {
+            Some(sizes) => sizes[2],
+            None => cpuid_cache_size(3, cpuid::CacheType::Unified),
+        }
+    }
+
+    /// How many cores share the L2 (of the first core).
+    pub fn cores_per_l2(&self) -> Option<u64> {
+        let l2 = self.data.first()?.l2;
+        Some(distinct_cores(&self.cpus_on_l2(l2)))
+    }
+
+    /// How many cores share an L3 of `socket` (the first, a CPU with more L3s
+    /// per socket has the same number of cores on all of them).
+    pub fn cores_per_l3(&self, socket: Socket) -> Option<u64> {
+        let l3 = self.cpus_on_socket(socket).first()?.l3;
+        Some(distinct_cores(&self.cpus_on_l3(l3)))
+    }
+
+    /// The bytes of the L2 every core of it has on average.
+    pub fn l2_share_per_core(&self) -> Option<u64> {
+        Some(self.l2_size()? / self.cores_per_l2()?)
+    }
+
+    /// The bytes of its L3 every core of `socket` has on average.
+    pub fn l3_share_per_core(&self, socket: Socket) -> Option<u64> {
+        Some(self.l3_size()? / self.cores_per_l3(socket)?)
+    }
+
+    /// The bytes of its L3 every CPU (SMT thread) of `socket` has on average.
+    pub fn l3_share_per_cpu(&self, socket: Socket) -> Option<u64> {
+        let l3 = self.cpus_on_socket(socket).first()?.l3;
+        Some(self.l3_size()? / self.cpus_on_l3(l3).len() as u64)
     }
 
     pub fn cpus_on_node(&self, node: NodeInfo) -> Vec<&CpuInfo> {
         self.data.iter().filter(|t| t.node == node).collect()
     }
 
+    pub fn gpus(&self) -> &[GpuInfo] {
+        &self.gpus
+    }
+
+    /// The GPUs attached to `node` (those the machine doesn't place on a node
+    /// aren't on any).
+    pub fn gpus_on_node(&self, node: Node) -> Vec<&GpuInfo> {
+        self.gpus.iter().filter(|g| g.node == Some(node)).collect()
+    }
+
+    pub fn nics(&self) -> &[NicInfo] {
+        &self.nics
+    }
+
+    /// The NICs attached to `node`.
+    pub fn nics_on_node(&self, node: Node) -> Vec<&NicInfo> {
+        self.nics.iter().filter(|n| n.node == Some(node)).collect()
+    }
+
+    /// The node `device` (a NIC by name, or a GPU or NIC by PCI address) is
+    /// attached to.
+    pub fn device_node(&self, device: &str) -> Option<Node> {
+        let gpus = self
+            .gpus
+            .iter()
+            .filter(|g| g.pci_address == device)
+            .map(|g| g.node);
+        let nics = self
+            .nics
+            .iter()
+            .filter(|n| n.name == device || n.pci_address == device)
+            .map(|n| n.node);
+        gpus.chain(nics).next().and_then(|node| node)
+    }
+
+    /// The CPUs on the node of `device` (see `device_node`).
+    pub fn cpus_near_device(&self, device: &str) -> Vec<&CpuInfo> {
+        match self.device_node(device) {
+            Some(node) => self.data.iter().filter(|t| t.node.node == node).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The CPUs that aren't on the node of `device` (all of them if we don't know
+    /// where it is).
+    pub fn cpus_far_from_device(&self, device: &str) -> Vec<&CpuInfo> {
+        let node = self.device_node(device);
+        self.data
+            .iter()
+            .filter(|t| Some(t.node.node) != node)
+            .collect()
+    }
+
+    /// Do the devices `a` and `b` (PCI addresses) share a PCIe root complex?
+    pub fn same_pcie_root(&self, a: &str, b: &str) -> bool {
+        let roots: Vec<(&str, &str)> = self
+            .gpus
+            .iter()
+            .map(|g| (g.pci_address.as_str(), g.pcie_root.as_str()))
+            .chain(
+                self.nics
+                    .iter()
+                    .map(|n| (n.pci_address.as_str(), n.pcie_root.as_str())),
+            )
+            .collect();
+        let root = |device: &str| roots.iter().find(|r| r.0 == device).map(|r| r.1);
+        match (root(a), root(b)) {
+            (Some(a), Some(b)) => !a.is_empty() && a == b,
+            _ => false,
+        }
+    }
+
     pub fn cpus_on_l1(&self, l1: L1) -> Vec<&CpuInfo> {
         self.data.iter().filter(|t| t.l1 == l1).collect()
     }
@@ -341,7 +1000,7 @@ impl MachineTopology {
         self.data.iter().filter(|t| t.socket == socket).collect()
     }
 
-    fn cores_on_socket(&self, socket: Socket) -> Vec<Core> {
+    pub fn cores_on_socket(&self, socket: Socket) -> Vec<Core> {
         let mut cores: Vec<Core> = self
             .data
             .iter()
@@ -353,11 +1012,32 @@ impl MachineTopology {
         cores
     }
 
-    fn cores_on_l3(&self, l3: L3) -> Vec<&CpuInfo> {
-        let mut cpus: Vec<&CpuInfo> = self.data.iter().filter(|t| t.l3 == l3).collect();
-        cpus.sort_by_key(|c| c.core);
-        // TODO: implicit assumption that we have two HTs
-        cpus.into_iter().step(2).collect()
+    /// The SMT threads of the core `cpu` is on (including `cpu`).
+    pub fn siblings(&self, cpu: &CpuInfo) -> Vec<&CpuInfo> {
+        self.data
+            .iter()
+            .filter(|t| t.socket == cpu.socket && t.core == cpu.core)
+            .collect()
+    }
+
+    /// One CPU (the first SMT thread) of every physical core in each `domain`,
+    /// whatever the SMT width of the machine (or without SMT).
+    pub fn physical_cores_in(&self, domain: Domain) -> Vec<Vec<&CpuInfo>> {
+        let mut ids: Vec<u64> = self.data.iter().map(|t| t.domain(domain)).collect();
+        ids.sort();
+        ids.dedup();
+        ids.into_iter()
+            .map(|id| {
+                let mut cpus: Vec<&CpuInfo> = self
+                    .data
+                    .iter()
+                    .filter(|t| t.domain(domain) == id)
+                    .filter(|t| self.siblings(t).iter().all(|s| s.cpu >= t.cpu))
+                    .collect();
+                cpus.sort_by_key(|c| (c.core, c.cpu));
+                cpus
+            })
+            .collect()
     }
 
     pub fn same_socket(&self) -> Vec<Vec<&CpuInfo>> {
@@ -393,11 +1073,16 @@ impl MachineTopology {
         self.l3().into_iter().map(|c| self.cpus_on_l3(c)).collect()
     }
 
+    pub fn same_l2_cores(&self) -> Vec<Vec<&CpuInfo>> {
+        self.physical_cores_in(Domain::L2)
+    }
+
     pub fn same_l3_cores(&self) -> Vec<Vec<&CpuInfo>> {
-        self.l3()
-            .into_iter()
-            .map(|l3| self.cores_on_l3(l3))
-            .collect()
+        self.physical_cores_in(Domain::L3)
+    }
+
+    pub fn same_node_cores(&self) -> Vec<Vec<&CpuInfo>> {
+        self.physical_cores_in(Domain::Node)
     }
 
     pub fn whole_machine(&self) -> Vec<Vec<&CpuInfo>> {
@@ -405,10 +1090,99 @@ impl MachineTopology {
     }
 
     pub fn whole_machine_cores(&self) -> Vec<Vec<&CpuInfo>> {
-        let mut cpus: Vec<&CpuInfo> = self.data.iter().collect();
-        cpus.sort_by_key(|c| c.core);
-        // TODO: implicit assumption that we have two HTs
-        vec![cpus.into_iter().step(2).collect()]
+        self.physical_cores_in(Domain::Machine)
+    }
+
+    /// The CPU pairs that share `domain`, e.g. the SMT threads of different cores
+    /// on the same L2 with `pairs_sharing(Domain::L2).not_sharing(Domain::Core)`.
+    pub fn pairs_sharing(&self, domain: Domain) -> CpuQuery<'_> {
+        self.cpu_sets().sharing(domain)
+    }
+
+    /// The CPU pairs that don't share `domain`.
+    pub fn pairs_not_sharing(&self, domain: Domain) -> CpuQuery<'_> {
+        self.cpu_sets().not_sharing(domain)
+    }
+
+    /// All CPU pairs (or sets) of the machine, to narrow down with `sharing`
+    /// and `not_sharing`.
+    pub fn cpu_sets(&self) -> CpuQuery<'_> {
+        CpuQuery {
+            mt: self,
+            sharing: Vec::new(),
+            not_sharing: Vec::new(),
+            physical_cores: false,
+        }
+    }
+}
+
+/// CPUs that share some domains and not others (see `MachineTopology::cpu_sets`).
+/// Every constraint holds for every two CPUs of a set.
+#[derive(Debug, Clone)]
+pub struct CpuQuery<'a> {
+    mt: &'a MachineTopology,
+    sharing: Vec<Domain>,
+    not_sharing: Vec<Domain>,
+    physical_cores: bool,
+}
+
+impl<'a> CpuQuery<'a> {
+    pub fn sharing(mut self, domain: Domain) -> CpuQuery<'a> {
+        self.sharing.push(domain);
+        self
+    }
+
+    pub fn not_sharing(mut self, domain: Domain) -> CpuQuery<'a> {
+        self.not_sharing.push(domain);
+        self
+    }
+
+    /// Only one CPU (the first SMT thread) of every core.
+    pub fn physical_cores(mut self) -> CpuQuery<'a> {
+        self.physical_cores = true;
+        self
+    }
+
+    fn candidates(&self) -> Vec<&'a CpuInfo> {
+        let mut cpus: Vec<&'a CpuInfo> = if self.physical_cores {
+            self.mt.physical_cores_in(Domain::Machine).concat()
+        } else {
+            self.mt.data.iter().collect()
+        };
+        cpus.sort_by_key(|c| c.cpu);
+        cpus
+    }
+
+    fn fits(&self, a: &CpuInfo, b: &CpuInfo) -> bool {
+        self.sharing.iter().all(|d| a.domain(*d) == b.domain(*d))
+            && self
+                .not_sharing
+                .iter()
+                .all(|d| a.domain(*d) != b.domain(*d))
+    }
+
+    /// Every pair (the lower CPU first).
+    pub fn pairs(&self) -> Vec<(&'a CpuInfo, &'a CpuInfo)> {
+        let cpus = self.candidates();
+        cpus.iter()
+            .enumerate()
+            .flat_map(|(i, a)| cpus[i + 1..].iter().map(move |b| (*a, *b)))
+            .filter(|&(a, b)| self.fits(a, b))
+            .collect()
+    }
+
+    /// Every set of `n` CPUs (ordered by CPU). There are a lot of them on big
+    /// machines, narrow the query down first.
+    pub fn sets(&self, n: usize) -> Vec<Vec<&'a CpuInfo>> {
+        self.candidates()
+            .into_iter()
+            .combinations(n)
+            .filter(|set| {
+                set.iter()
+                    .enumerate()
+                    .all(|(i, a)| set[i + 1..].iter().all(|b| self.fits(a, b)))
+            })
+            .collect()
     }
 }
 
@@ -427,3 +1201,62 @@ pub fn socket_uncore_devices() -> Vec<&'static str> {
         "uncore_ubox",
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn fixture(lscpu: &str, numactl: &str) -> MachineTopology {
+        MachineTopology::from_strings(String::from(lscpu), String::from(numactl))
+    }
+
+    #[test]
+    fn lscpu_without_numa_and_l3() {
+        let mt = fixture(
+            include_str!("../tests/topology/debian12-vm/lscpu.csv"),
+            include_str!("../tests/topology/debian12-vm/numactl.dat"),
+        );
+        assert_eq!(mt.cpus(), vec![0, 1, 2, 3]);
+        for cpu in mt.cpus() {
+            let info = mt.cpu(cpu).unwrap();
+            assert_eq!(info.node.node, 0);
+            assert_eq!(info.node.memory, Some(7941 * MIB));
+            // Private L1 and L2, the L3 is the socket's:
+            assert_eq!(
+                (info.l1, info.l2, info.l3),
+                (info.core, info.core, info.socket)
+            );
+        }
+        assert_eq!(mt.sockets(), vec![0]);
+    }
+
+    #[test]
+    fn two_sockets() {
+        let mt = fixture(
+            include_str!("../tests/topology/ubuntu-2204-2s/lscpu.csv"),
+            include_str!("../tests/topology/ubuntu-2204-2s/numactl.dat"),
+        );
+        assert_eq!(mt.sockets(), vec![0, 1]);
+        let on_socket: Vec<Cpu> = mt.cpus_on_socket(1).iter().map(|c| c.cpu).collect();
+        assert_eq!(on_socket, vec![2, 3, 6, 7]);
+        let cpu = mt.cpu(6).unwrap();
+        assert_eq!((cpu.node.node, cpu.core, cpu.l3), (1, 2, 1));
+        assert_eq!(mt.memory_nodes().len(), 2);
+    }
+
+    #[test]
+    fn memoryless_nodes() {
+        let mt = fixture(
+            include_str!("../tests/topology/rhel8-memoryless/lscpu.csv"),
+            include_str!("../tests/topology/rhel8-memoryless/numactl.dat"),
+        );
+        // Node 2 has CPUs but no memory, node 3 memory but no CPUs:
+        assert_eq!(mt.cpu(2).unwrap().node.memory, None);
+        let with_memory: Vec<Node> = mt.memory_nodes().iter().map(|n| n.node).collect();
+        assert_eq!(with_memory, vec![0, 3]);
+        let all: Vec<Node> = mt.all_nodes().iter().map(|n| n.node).collect();
+        assert_eq!(all, vec![0, 2, 3]);
+    }
+}
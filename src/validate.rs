@@ -0,0 +1,288 @@
+//! Checks the measurements we aggregate for problems that make them unusable
+//! (missing runs, events that weren't counted, bad multiplexing, counters that
+//! can't be right) and writes them to `validation.txt` and `validation.json`, so
+//! they're caught before anyone starts analyzing the data.
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use rustc_serialize::json;
+use schemars::JsonSchema;
+use tracing::warn;
+
+use super::aggregate::read_perf_log;
+use super::errata::ERRATA_FILE;
+use super::index;
+use super::perfstat;
+use super::profile::Scope;
+use super::util::SCHEMA_VERSION;
+
+/// perf reports how long (in percent) an event was scheduled on a counter, below
+/// this we consider the event multiplexed.
+pub const MIN_COUNTER_PERCENT: f64 = 91.0;
+
+/// No x86 core we know of retires more instructions per cycle than this.
+pub const MAX_IPC: f64 = 8.0;
+
+#[derive(Debug, Clone, RustcEncodable, JsonSchema)]
+pub struct Issue {
+    /// The run (empty for a single profile).
+    pub run: String,
+    /// The measurement file (empty if it concerns the whole run).
+    pub file: String,
+    /// missing, incomplete, not-counted, multiplexed, zero-instructions, ipc,
+    /// rule (see `rules.rs`) or errata (see `errata.rs`)
+    pub kind: String,
+    pub event: String,
+    pub detail: String,
+}
+
+#[derive(Debug, RustcEncodable, JsonSchema)]
+pub struct Report {
+    pub schema_version: u64,
+    pub runs: usize,
+    pub issues: Vec<Issue>,
+}
+
+pub fn issue(run: &str, file: &str, kind: &str, event: &str, detail: String) -> Issue {
+    Issue {
+        run: String::from(run),
+        file: String::from(file),
+        kind: String::from(kind),
+        event: String::from(event),
+        detail,
+    }
+}
+
+/// Where the value is in the rows of a perf stat file with `scope`.
+/// Looks at the raw values of a perf stat file.
+fn check_stat_file(run: &str, file: &str, scope: Scope, path: &Path) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut not_counted: Vec<String> = Vec::new();
+    // event -> lowest percentage
+    let mut multiplexed: Vec<(String, f64)> = Vec::new();
+    let mut instructions: Option<u64> = None;
+    let mut cycles: Option<u64> = None;
+
+    let counts = match perfstat::counts(path, scope) {
+        Ok(counts) => counts,
+        Err(_) => {
+            issues.push(issue(
+                run,
+                file,
+                "missing",
+                "",
+                String::from("file not found"),
+            ));
+            return issues;
+        }
+    };
+    for count in counts {
+        let (event, percent) = (count.event.as_str(), count.percent);
+        let value = match count.count() {
+            Some(value) => value,
+            None => {
+                if !not_counted.iter().any(|e| e == event) {
+                    not_counted.push(String::from(event));
+                }
+                continue;
+            }
+        };
+        if percent < MIN_COUNTER_PERCENT {
+            match multiplexed.iter_mut().find(|m| m.0 == event) {
+                Some(m) => m.1 = m.1.min(percent),
+                None => multiplexed.push((String::from(event), percent)),
+            }
+        }
+
+        match event {
+            "instructions" | "INST_RETIRED.ANY" | "inst_retired.any" => {
+                *instructions.get_or_insert(0) += value
+            }
+            "cycles" | "cpu-cycles" | "CPU_CLK_UNHALTED.THREAD" | "cpu_clk_unhalted.thread" => {
+                *cycles.get_or_insert(0) += value
+            }
+            _ => {}
+        }
+    }
+
+    for event in not_counted {
+        issues.push(issue(
+            run,
+            file,
+            "not-counted",
+            &event,
+            String::from("not counted or not supported"),
+        ));
+    }
+    for (event, percent) in multiplexed {
+        issues.push(issue(
+            run,
+            file,
+            "multiplexed",
+            &event,
+            format!("only on a counter {:.1}% of the time", percent),
+        ));
+    }
+    match (instructions, cycles) {
+        (Some(0), _) => issues.push(issue(
+            run,
+            file,
+            "zero-instructions",
+            "instructions",
+            String::from("no instructions retired"),
+        )),
+        (Some(i), Some(c)) if c > 0 && i as f64 / c as f64 > MAX_IPC => issues.push(issue(
+            run,
+            file,
+            "ipc",
+            "instructions",
+            format!("IPC of {:.2} is more than {}", i as f64 / c as f64, MAX_IPC),
+        )),
+        _ => {}
+    }
+
+    issues
+}
+
+/// Checks all the measurements of a run (in `path`).
+pub fn check_run(run: &str, path: &Path) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    let mut completed = path.to_path_buf();
+    completed.push("completed");
+    if !completed.exists() {
+        issues.push(issue(
+            run,
+            "",
+            "incomplete",
+            "",
+            String::from("run did not complete"),
+        ));
+    }
+
+    let mut errata_csv = path.to_path_buf();
+    errata_csv.push(ERRATA_FILE);
+    if let Ok(rdr) = csv::Reader::from_file(&errata_csv) {
+        let mut rdr = rdr.has_headers(true);
+        for row in rdr.decode() {
+            let (event, errata, action, description): (String, String, String, String) = match row {
+                Ok(row) => row,
+                Err(e) => {
+                    warn!("Can't read {:?}: {}", errata_csv, e);
+                    break;
+                }
+            };
+            let detail = if !errata.is_empty() {
+                format!("{} ({}, {})", description, errata, action)
+            } else {
+                format!("{} ({})", description, action)
+            };
+            issues.push(issue(run, ERRATA_FILE, "errata", &event, detail));
+        }
+    }
+
+    let mut perf_csv = path.to_path_buf();
+    perf_csv.push("perf.csv");
+    if !perf_csv.exists() {
+        issues.push(issue(
+            run,
+            "perf.csv",
+            "missing",
+            "",
+            String::from("file not found"),
+        ));
+        return issues;
+    }
+    let (_, rows) = read_perf_log(&perf_csv);
+    for (_, _, _, _, file, perf_command) in rows {
+        let mut stat_file = path.to_path_buf();
+        stat_file.push(&file);
+        if !perfstat::is_stat_file(&file) {
+            // Sampled (perf record) data has no counter values to check
+            if !stat_file.exists() {
+                issues.push(issue(
+                    run,
+                    &file,
+                    "missing",
+                    "",
+                    String::from("file not found"),
+                ));
+            }
+            continue;
+        }
+        issues.extend(check_stat_file(
+            run,
+            &file,
+            Scope::from_perf_command(&perf_command),
+            &stat_file,
+        ));
+    }
+
+    issues
+}
+
+/// Directories below `path` that have a run.toml (i.e., `pair` started the run)
+/// but no measurements (the runs that didn't complete, if it has an index).
+pub fn missing_runs(path: &Path) -> Vec<PathBuf> {
+    if let Some(unfinished) = index::unfinished_runs(path) {
+        return unfinished;
+    }
+    let mut missing = Vec::new();
+    let mut entries: Vec<PathBuf> = match fs::read_dir(path) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => return missing,
+    };
+    entries.sort();
+    for entry in entries.into_iter().filter(|e| e.is_dir()) {
+        let mut run_toml = entry.clone();
+        run_toml.push("run.toml");
+        let mut perf_csv = entry.clone();
+        perf_csv.push("perf.csv");
+        if run_toml.exists() && !perf_csv.exists() {
+            missing.push(entry);
+        } else if !run_toml.exists() {
+            missing.extend(missing_runs(&entry));
+        }
+    }
+    missing
+}
+
+/// Writes `<save_to>.txt` and `<save_to>.json`.
+pub fn save_report(save_to: &Path, runs: usize, issues: Vec<Issue>) -> io::Result<()> {
+    let report = Report {
+        schema_version: SCHEMA_VERSION,
+        runs,
+        issues,
+    };
+    if !report.issues.is_empty() {
+        warn!(
+            "Found {} problems in the measurements, see {:?}.",
+            report.issues.len(),
+            save_to.with_extension("txt")
+        );
+    }
+
+    let mut f = File::create(save_to.with_extension("json"))?;
+    f.write_all(format!("{}\n", json::as_pretty_json(&report)).as_bytes())?;
+
+    let mut f = File::create(save_to.with_extension("txt"))?;
+    f.write_all(
+        format!(
+            "{} runs checked, {} problems found.\n",
+            report.runs,
+            report.issues.len()
+        )
+        .as_bytes(),
+    )?;
+    for i in report.issues.iter() {
+        let location: Vec<&str> = vec![i.run.as_str(), i.file.as_str(), i.event.as_str()]
+            .into_iter()
+            .filter(|l| !l.is_empty())
+            .collect();
+        f.write_all(format!("{}: {} ({})\n", i.kind, location.join(" "), i.detail).as_bytes())?;
+    }
+    Ok(())
+}
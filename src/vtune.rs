@@ -0,0 +1,220 @@
+//! Exchanges data with Intel VTune, for working with teams that standardized on
+//! it.
+//!
+//! `autoperf vtune` writes aggregated results as VTune's external data CSV, one
+//! file per run (`<run>-hostname-<host>.csv`, the name VTune expects), so they can
+//! be imported into a VTune result (`vtune -import <file> -r <result>`) and
+//! compared with its comparison views. Every interval of an event is a counter
+//! value (summed up over all locations) with its start and end time. The times
+//! are relative to the start of the measurement, so the imported data doesn't line
+//! up with a VTune collection of the same program.
+//!
+//! `profile --backend vtune` measures with `vtune -collect` instead of perf. The
+//! VTune result is kept as it is (`<output>/vtune`) next to its summary
+//! (`vtune_summary.csv`), autoperf doesn't aggregate it.
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use tracing::{debug, error, warn};
+
+use super::features::{read_intervals, Interval};
+use super::housekeeping;
+use super::profile::{create_out_directory, save_machine_information};
+use super::summary::{self, Failure};
+use super::workload::Workload;
+
+/// What VTune collects if there is no `--vtune-analysis`.
+pub const DEFAULT_ANALYSIS: &str = "uarch-exploration";
+
+fn to_io_error(e: csv::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// The file VTune imports the external data of `run` from.
+fn file_name(run: &str, hostname: &str) -> String {
+    let run: String = run
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let run = if !run.is_empty() {
+        run
+    } else {
+        String::from("autoperf")
+    };
+    format!("{}-hostname-{}.csv", run, hostname)
+}
+
+fn nanoseconds(seconds: f64) -> u64 {
+    (seconds.max(0.0) * 1e9).round() as u64
+}
+
+/// Writes the intervals of a run. An interval ends where the next one starts (the
+/// last one is as long as the one before it).
+fn save_run(save_to: &Path, event_names: &[String], intervals: &[&Interval]) -> io::Result<()> {
+    let mut wtr = csv::Writer::from_file(save_to).map_err(to_io_error)?;
+    wtr.encode((
+        "name",
+        "start_tsc.CLOCK_MONOTONIC_RAW",
+        "end_tsc",
+        "pid",
+        "tid",
+        "value",
+    ))
+    .map_err(to_io_error)?;
+
+    for (idx, interval) in intervals.iter().enumerate() {
+        let end = match (intervals.get(idx + 1), idx.checked_sub(1)) {
+            (Some(next), _) => next.time,
+            (None, Some(previous)) => interval.time + (interval.time - intervals[previous].time),
+            (None, None) => interval.time,
+        };
+        for (event, value) in interval.values.iter().enumerate() {
+            if let Some(value) = *value {
+                wtr.encode((
+                    event_names[event].as_str(),
+                    nanoseconds(interval.time),
+                    nanoseconds(end),
+                    0,
+                    0,
+                    value,
+                ))
+                .map_err(to_io_error)?;
+            }
+        }
+    }
+    wtr.flush().map_err(to_io_error)
+}
+
+/// Writes the aggregated `results` (long layout) as VTune external data files to
+/// `output_path`. Returns how many runs were exported.
+pub fn export(results: &Path, hostname: &str, output_path: &Path) -> io::Result<usize> {
+    let (event_names, intervals) = read_intervals(results)?;
+    let mut runs: Vec<&str> = intervals.keys().map(|k| k.0.as_str()).collect();
+    runs.dedup();
+
+    for run in runs.iter() {
+        let mut run_intervals: Vec<&Interval> = intervals
+            .iter()
+            .filter(|&(k, _)| k.0 == *run)
+            .map(|(_, i)| i)
+            .collect();
+        run_intervals.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+
+        let mut save_to = output_path.to_path_buf();
+        save_to.push(file_name(run, hostname));
+        debug!(
+            "Exporting {} intervals to {:?}",
+            run_intervals.len(),
+            save_to
+        );
+        save_run(&save_to, &event_names, &run_intervals)?;
+    }
+    Ok(runs.len())
+}
+
+pub fn check_for_vtune() {
+    if Command::new("vtune").arg("-version").output().is_err() {
+        error!(
+            "'vtune' does not seem to be executable? You may need to install VTune \
+             or source its vars.sh."
+        );
+        summary::fail(Failure::Environment);
+    }
+}
+
+/// Measures `cmd` with `vtune -collect <analysis>`, the result goes to
+/// `output_path/vtune`.
+pub fn profile(
+    output_path: &Path,
+    cmd_working_dir: &str,
+    cmd: Vec<String>,
+    analysis: &str,
+    dryrun: bool,
+) {
+    assert!(!cmd.is_empty());
+    let mut completed_file: PathBuf = output_path.to_path_buf();
+    completed_file.push("completed");
+    if completed_file.exists() {
+        warn!(
+            "Run {} already completed, skipping.",
+            output_path.to_string_lossy()
+        );
+        return;
+    }
+
+    let mut result_dir = output_path.to_path_buf();
+    result_dir.push("vtune");
+    let mut vtune = Command::new("vtune");
+    vtune.current_dir(cmd_working_dir);
+    housekeeping::release(&mut vtune);
+    vtune.arg("-collect").arg(analysis);
+    vtune.arg("-result-dir").arg(result_dir.as_os_str());
+    vtune.arg("--");
+    vtune.args(cmd.as_slice());
+    let vtune_cmd: String = format!("{:?}", vtune).replace("\"", "");
+    if dryrun {
+        warn!("Dry run mode -- would execute: {}", vtune_cmd);
+        return;
+    }
+
+    check_for_vtune();
+    create_out_directory(output_path);
+    save_machine_information(output_path, None);
+    let mut workload_file = output_path.to_path_buf();
+    workload_file.push("workload.toml");
+    let r = Workload::new(&cmd, cmd_working_dir, &Vec::new()).save(&workload_file);
+    if let Err(e) = r {
+        error!(
+            "Can't save workload information to {:?}: {}",
+            workload_file, e
+        );
+    }
+    // VTune refuses to collect into an existing result (e.g., of an aborted run):
+    let _ = fs::remove_dir_all(&result_dir);
+
+    match vtune.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            error!(
+                "vtune command: {} got unknown exit status was: {}",
+                vtune_cmd, status
+            );
+            summary::record(Failure::Workload);
+            return;
+        }
+        Err(e) => {
+            error!("Executing {} failed : {}", vtune_cmd, e);
+            return;
+        }
+    }
+
+    let mut summary = output_path.to_path_buf();
+    summary.push("vtune_summary.csv");
+    let r = Command::new("vtune")
+        .arg("-report")
+        .arg("summary")
+        .arg("-result-dir")
+        .arg(result_dir.as_os_str())
+        .arg("-format")
+        .arg("csv")
+        .arg("-csv-delimiter")
+        .arg("comma")
+        .arg("-report-output")
+        .arg(summary.as_os_str())
+        .status();
+    match r {
+        Ok(status) if status.success() => {}
+        Ok(status) => error!("vtune -report summary failed: {}", status),
+        Err(e) => error!("Can't run vtune -report summary: {}", e),
+    }
+
+    let _ = File::create(completed_file.as_path()).unwrap();
+}
@@ -0,0 +1,448 @@
+//! A terminal dashboard (`--watch`) for long measurements: it shows the IPC of
+//! every CPU and the event rates of the latest perf interval, the configuration
+//! we're running and how far along the campaign is (with an ETA).
+//!
+//! `profile` and `pair` report their progress here, the dashboard itself reads the
+//! perf stat file that is being written. While the dashboard is up, log messages
+//! end up in it (anything written to the terminal would mess up the screen), they
+//! are printed once it's closed.
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crossterm::execute;
+use crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
+use lazy_static::lazy_static;
+use ratatui::prelude::*;
+use ratatui::widgets::*;
+
+use super::normalize::{CYCLES, INSTRUCTIONS};
+use super::perfstat::{self, Count, Value};
+use super::profile::Scope;
+
+/// How many log messages we keep around.
+const MESSAGES: usize = 100;
+/// perf reports the counter values every 250 ms (see `get_perf_command`).
+pub const INTERVAL: f64 = 0.25;
+/// Only the end of a stat file is interesting.
+const TAIL: u64 = 512 * 1024;
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// How many of something are done (and when we started with the first one).
+#[derive(Debug, Clone)]
+struct Progress {
+    started: Instant,
+    done: usize,
+    total: usize,
+}
+
+impl Progress {
+    fn update(progress: &mut Option<Progress>, done: usize, total: usize) {
+        match progress {
+            Some(ref mut p) if p.total == total && done >= p.done => p.done = done,
+            _ => {
+                *progress = Some(Progress {
+                    started: Instant::now(),
+                    done,
+                    total,
+                })
+            }
+        }
+    }
+
+    /// Assumes the remaining ones take as long as the ones that are done.
+    fn eta(&self) -> Option<Duration> {
+        if self.done == 0 || self.done > self.total {
+            return None;
+        }
+        let average = self.started.elapsed().as_secs_f64() / self.done as f64;
+        Some(Duration::from_secs_f64(
+            average * (self.total - self.done) as f64,
+        ))
+    }
+}
+
+/// Where a `pair` campaign is (see `progress.rs`).
+#[derive(Debug, Clone)]
+pub struct Campaign {
+    pub started: Instant,
+    /// Runs that ended (completed, failed or skipped).
+    pub done: usize,
+    pub total: usize,
+    pub failed: usize,
+    /// When we expect to be done.
+    pub finish: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Status {
+    active: bool,
+    campaign: Option<Campaign>,
+    configuration: String,
+    /// Measurements (event groups) of the current run.
+    measurement: Option<Progress>,
+    stat_file: Option<PathBuf>,
+    /// When perf started writing `stat_file`.
+    stat_file_started: Option<SystemTime>,
+    scope: Scope,
+    /// Describe where the programs of a `pair` run are placed.
+    placement: Vec<(String, String)>,
+    messages: VecDeque<String>,
+}
+
+lazy_static! {
+    static ref STATUS: Mutex<Status> = Mutex::new(Default::default());
+}
+
+/// Is the dashboard showing?
+pub fn active() -> bool {
+    STATUS.lock().unwrap().active
+}
+
+/// The campaign changed, we're running `configuration` now.
+pub fn campaign(campaign: Campaign, configuration: &str) {
+    let mut status = STATUS.lock().unwrap();
+    status.campaign = Some(campaign);
+    status.configuration = String::from(configuration);
+}
+
+/// Where the programs of the `pair` run we're starting are placed (as key, value).
+pub fn placement(tags: Vec<(String, String)>) {
+    STATUS.lock().unwrap().placement = tags;
+}
+
+/// The perf stat file that is being written.
+pub struct StatFile {
+    pub path: PathBuf,
+    pub scope: Scope,
+    pub started: SystemTime,
+    pub configuration: String,
+    pub placement: Vec<(String, String)>,
+}
+
+pub fn stat_file() -> Option<StatFile> {
+    let status = STATUS.lock().unwrap();
+    Some(StatFile {
+        path: status.stat_file.clone()?,
+        scope: status.scope,
+        started: status.stat_file_started?,
+        configuration: status.configuration.clone(),
+        placement: status.placement.clone(),
+    })
+}
+
+/// We're starting measurement `done + 1` of `total`, perf writes to `stat_file`.
+pub fn measurement(done: usize, total: usize, stat_file: &Path, scope: Scope) {
+    let mut status = STATUS.lock().unwrap();
+    if done == 0 {
+        status.measurement = None;
+    }
+    Progress::update(&mut status.measurement, done, total);
+    status.stat_file = Some(stat_file.to_path_buf());
+    status.stat_file_started = Some(SystemTime::now());
+    status.scope = scope;
+    if status.campaign.is_none() {
+        status.configuration = stat_file
+            .parent()
+            .map_or(String::new(), |p| p.to_string_lossy().into_owned());
+    }
+}
+
+/// Sends log messages to the dashboard while it's showing (to stderr otherwise).
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut status = STATUS.lock().unwrap();
+        if !status.active {
+            drop(status);
+            io::stderr().write_all(buf)?;
+            return Ok(buf.len());
+        }
+        for line in String::from_utf8_lossy(buf).lines() {
+            if status.messages.len() == MESSAGES {
+                status.messages.pop_front();
+            }
+            status.messages.push_back(String::from(line));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    render: thread::JoinHandle<()>,
+}
+
+impl Dashboard {
+    /// Takes over the terminal until `stop` is called.
+    pub fn start() -> io::Result<Dashboard> {
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+        terminal.hide_cursor()?;
+        STATUS.lock().unwrap().active = true;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let render = thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                if let Err(e) = draw(&mut terminal) {
+                    STATUS
+                        .lock()
+                        .unwrap()
+                        .messages
+                        .push_back(format!("ERROR Can't draw dashboard: {}", e));
+                    break;
+                }
+                let next = Instant::now() + REFRESH;
+                while Instant::now() < next && !stopped.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(50));
+                }
+            }
+            let _ = terminal.show_cursor();
+        });
+
+        Ok(Dashboard { stop, render })
+    }
+
+    /// Gives the terminal back and prints the log messages we collected.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.render.join();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+
+        let mut status = STATUS.lock().unwrap();
+        status.active = false;
+        for message in status.messages.drain(..) {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+/// The counter values of the latest complete interval in a perf stat file.
+pub struct Interval {
+    /// Seconds since perf started.
+    pub time: f64,
+    /// location (CPU, core, ...) -> event -> value
+    pub values: BTreeMap<String, BTreeMap<String, f64>>,
+}
+
+/// Instructions per cycle from the `events` of a location (if we measured both).
+pub fn ipc(events: &BTreeMap<String, f64>) -> Option<f64> {
+    let is_one_of = |names: &[&str], event: &str| names.contains(&event);
+    let instructions = events.iter().find(|e| is_one_of(&INSTRUCTIONS, e.0))?;
+    let cycles = events.iter().find(|e| is_one_of(&CYCLES, e.0))?;
+    if *cycles.1 > 0.0 {
+        Some(instructions.1 / cycles.1)
+    } else {
+        None
+    }
+}
+
+/// What we know about the measurements that are running right now.
+pub struct Live {
+    pub configuration: String,
+    pub campaign: Option<Campaign>,
+    /// (done, total) measurements of the current run.
+    pub measurement: Option<(usize, usize)>,
+    pub interval: Option<Interval>,
+    /// When perf last wrote to the stat file.
+    pub updated: Option<SystemTime>,
+}
+
+pub fn live() -> Live {
+    let status = STATUS.lock().unwrap().clone();
+    let interval = status
+        .stat_file
+        .as_ref()
+        .and_then(|f| latest_interval(f, status.scope));
+    Live {
+        configuration: status.configuration,
+        campaign: status.campaign,
+        measurement: status.measurement.map(|m| (m.done, m.total)),
+        interval,
+        updated: status
+            .stat_file
+            .and_then(|f| f.metadata().ok())
+            .and_then(|m| m.modified().ok()),
+    }
+}
+
+fn latest_interval(path: &Path, scope: Scope) -> Option<Interval> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL))).ok()?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).ok()?;
+    let tail = String::from_utf8_lossy(&tail);
+
+    let counts: Vec<Count> = tail
+        .lines()
+        // We probably started reading in the middle of a line:
+        .skip(if len > TAIL { 1 } else { 0 })
+        .filter_map(|line| perfstat::parse_line(line, scope))
+        .collect();
+
+    // perf might still be writing the last interval, so we prefer the one before:
+    let mut times: Vec<f64> = counts.iter().map(|count| count.time).collect();
+    times.dedup();
+    let time = if times.len() > 1 {
+        times[times.len() - 2]
+    } else {
+        *times.last()?
+    };
+
+    let mut values: BTreeMap<String, BTreeMap<String, f64>> = BTreeMap::new();
+    for count in counts.iter().filter(|count| count.time == time) {
+        if let Value::Counted(value) = count.value {
+            *values
+                .entry(count.location.clone())
+                .or_default()
+                .entry(count.event.clone())
+                .or_insert(0.0) += value;
+        }
+    }
+    Some(Interval { time, values })
+}
+
+fn hms(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+fn si(value: f64) -> String {
+    let units = [(1e12, "T"), (1e9, "G"), (1e6, "M"), (1e3, "K")];
+    match units.iter().find(|&&(scale, _)| value.abs() >= scale) {
+        Some(&(scale, unit)) => format!("{:.2} {}", value / scale, unit),
+        None => format!("{:.0}", value),
+    }
+}
+
+fn progress_line(
+    what: &str,
+    (done, total): (usize, usize),
+    started: Instant,
+    eta: Option<Duration>,
+    detail: &str,
+) -> Line<'static> {
+    Line::from(format!(
+        "{} {}/{}{} (elapsed {}, ETA {})",
+        what,
+        (done + 1).min(total),
+        total,
+        detail,
+        hms(started.elapsed()),
+        eta.map_or(String::from("-"), hms)
+    ))
+}
+
+fn draw(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> io::Result<()> {
+    let status = STATUS.lock().unwrap().clone();
+    let interval = status
+        .stat_file
+        .as_ref()
+        .and_then(|f| latest_interval(f, status.scope));
+
+    let stat_file = status.stat_file.as_ref().map_or(String::new(), |f| {
+        f.file_name()
+            .map_or(String::new(), |n| format!(" ({})", n.to_string_lossy()))
+    });
+    let mut header = vec![Line::from(format!(
+        "Configuration: {}",
+        status.configuration
+    ))];
+    if let Some(ref m) = status.measurement {
+        header.push(progress_line(
+            "Measurement",
+            (m.done, m.total),
+            m.started,
+            m.eta(),
+            &stat_file,
+        ));
+    }
+    if let Some(ref c) = status.campaign {
+        header.push(progress_line(
+            "Run",
+            (c.done, c.total),
+            c.started,
+            c.finish
+                .map(|f| f.saturating_duration_since(Instant::now())),
+            &format!(", {} failed", c.failed),
+        ));
+    }
+    header.push(Line::from(match interval {
+        Some(ref i) => format!("Interval at {:.2} s", i.time),
+        None => String::from("Waiting for perf..."),
+    }));
+
+    let mut ipc_rows: Vec<Row> = Vec::new();
+    let mut rates: BTreeMap<&str, f64> = BTreeMap::new();
+    if let Some(ref interval) = interval {
+        for (location, events) in interval.values.iter() {
+            let ipc = ipc(events).map_or(String::from("-"), |ipc| format!("{:.2}", ipc));
+            ipc_rows.push(Row::new(vec![location.clone(), ipc]));
+            for (event, value) in events.iter() {
+                *rates.entry(event.as_str()).or_insert(0.0) += value / INTERVAL;
+            }
+        }
+    }
+    let rate_rows: Vec<Row> = rates
+        .iter()
+        .map(|(event, rate)| Row::new(vec![String::from(*event), format!("{}/s", si(*rate))]))
+        .collect();
+
+    terminal.draw(|f| {
+        let layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(header.len() as u16 + 2),
+                Constraint::Min(5),
+                Constraint::Length(8),
+            ])
+            .split(f.size());
+        let body = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(30), Constraint::Percentage(70)])
+            .split(layout[1]);
+
+        f.render_widget(
+            Paragraph::new(header).block(Block::default().borders(Borders::ALL).title("autoperf")),
+            layout[0],
+        );
+        f.render_widget(
+            Table::new(ipc_rows, [Constraint::Min(10), Constraint::Length(6)])
+                .header(Row::new(vec!["Location", "IPC"]).bold())
+                .block(Block::default().borders(Borders::ALL).title("IPC")),
+            body[0],
+        );
+        f.render_widget(
+            Table::new(rate_rows, [Constraint::Min(20), Constraint::Length(14)])
+                .header(Row::new(vec!["Event", "Rate"]).bold())
+                .block(Block::default().borders(Borders::ALL).title("Events")),
+            body[1],
+        );
+
+        let shown = layout[2].height.saturating_sub(2) as usize;
+        let messages: Vec<Line> = status
+            .messages
+            .iter()
+            .skip(status.messages.len().saturating_sub(shown))
+            .map(|m| Line::from(m.as_str()))
+            .collect();
+        f.render_widget(
+            Paragraph::new(messages).block(Block::default().borders(Borders::ALL).title("Log")),
+            layout[2],
+        );
+    })?;
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! Saves how a workload was started (command line, working directory, environment
+//! and resource limits), so differences between runs can be explained later on.
+//!
+//! Environment variables that look like they contain secrets are redacted, the
+//! list can be extended with a comma-separated list of variable names in
+//! `AUTOPERF_REDACT`.
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use schemars::JsonSchema;
+
+use super::util::SCHEMA_VERSION;
+
+/// Variables whose name contains one of these (ignoring case) are redacted.
+const REDACT: [&str; 8] = [
+    "PASSWORD",
+    "PASSWD",
+    "SECRET",
+    "TOKEN",
+    "KEY",
+    "CREDENTIAL",
+    "AUTH",
+    "COOKIE",
+];
+
+/// Extra variable names to redact (comma-separated).
+pub const REDACT_ENV: &str = "AUTOPERF_REDACT";
+
+#[derive(Debug, RustcEncodable, JsonSchema)]
+pub struct Workload {
+    pub schema_version: u64,
+    pub argv: Vec<String>,
+    pub working_dir: String,
+    pub env: BTreeMap<String, String>,
+    /// resource -> (soft limit, hard limit)
+    pub limits: BTreeMap<String, (String, String)>,
+}
+
+fn is_secret(name: &str, extra: &[String]) -> bool {
+    let upper = name.to_uppercase();
+    REDACT.iter().any(|r| upper.contains(r)) || extra.iter().any(|e| e == name)
+}
+
+/// The resource limits of our process (the workload inherits them).
+fn limits() -> BTreeMap<String, (String, String)> {
+    let mut limits = BTreeMap::new();
+    let content = fs::read_to_string("/proc/self/limits").unwrap_or_default();
+
+    // Limit  Soft Limit  Hard Limit  Units (columns are separated by spaces only)
+    for line in content.lines().skip(1) {
+        let columns: Vec<&str> = line
+            .split("  ")
+            .map(|c| c.trim())
+            .filter(|c| !c.is_empty())
+            .collect();
+        if columns.len() >= 3 {
+            limits.insert(
+                String::from(columns[0]),
+                (String::from(columns[1]), String::from(columns[2])),
+            );
+        }
+    }
+
+    limits
+}
+
+impl Workload {
+    /// `env` are the variables we set in addition to the ones the workload inherits from us.
+    pub fn new(argv: &[String], working_dir: &str, env: &[(String, String)]) -> Workload {
+        let extra_redact: Vec<String> = env::var(REDACT_ENV)
+            .unwrap_or_default()
+            .split(',')
+            .map(|v| String::from(v.trim()))
+            .filter(|v| !v.is_empty())
+            .collect();
+
+        // env::vars() panics on a variable that isn't valid unicode:
+        let mut vars: BTreeMap<String, String> = env::vars_os()
+            .map(|(key, value)| {
+                (
+                    key.to_string_lossy().into_owned(),
+                    value.to_string_lossy().into_owned(),
+                )
+            })
+            .collect();
+        for (key, value) in env.iter() {
+            vars.insert(key.clone(), value.clone());
+        }
+        for (key, value) in vars.iter_mut() {
+            if is_secret(key, &extra_redact) {
+                *value = String::from("<redacted>");
+            }
+        }
+
+        let working_dir = fs::canonicalize(working_dir)
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or(String::from(working_dir));
+
+        Workload {
+            schema_version: SCHEMA_VERSION,
+            argv: argv.to_vec(),
+            working_dir,
+            env: vars,
+            limits: limits(),
+        }
+    }
+
+    pub fn save(&self, save_to: &Path) -> io::Result<()> {
+        let mut f = File::create(save_to)?;
+        f.write_all(toml::encode_str(self).as_bytes())
+    }
+}